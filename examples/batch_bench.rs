@@ -0,0 +1,64 @@
+//! Compare evaluating one expression against many rows by parsing it every
+//! time, with `eval_with_bindings`, and by compiling it once.
+//!
+//! cargo run --release --example batch_bench
+use std::time::{Duration, Instant};
+use x7::cli::Options;
+use x7::stdlib::create_stdlib_symbol_table;
+use x7::{Expr, SymbolTable};
+
+const RULE: &str = "
+(if (and (> amount 100) (= region \"eu\"))
+    (* amount (+ 1 (/ rate 100)))
+    (- amount (% amount 7)))";
+
+const ROWS: i64 = 100_000;
+
+fn bindings(row: i64) -> [(&'static str, Expr); 3] {
+    let region = if row % 3 == 0 { "eu" } else { "us" };
+    [
+        ("amount", Expr::from(row % 500)),
+        ("region", Expr::from(region)),
+        ("rate", Expr::from(row % 20)),
+    ]
+}
+
+fn parse_each_time(syms: &SymbolTable) -> Duration {
+    let start = Instant::now();
+    for row in 0..ROWS {
+        syms.eval_with_bindings(RULE, &bindings(row)).unwrap();
+    }
+    start.elapsed()
+}
+
+fn compile_once(syms: &SymbolTable) -> Duration {
+    let start = Instant::now();
+    let compiled = syms.compile(RULE).unwrap();
+    for row in 0..ROWS {
+        compiled.eval_with(syms, &bindings(row)).unwrap();
+    }
+    start.elapsed()
+}
+
+fn main() {
+    for frozen_globals in &[false, true] {
+        let syms = create_stdlib_symbol_table(&Options {
+            frozen_globals: *frozen_globals,
+            ..Default::default()
+        });
+        let parsed = parse_each_time(&syms);
+        let compiled = compile_once(&syms);
+        println!(
+            "{} rows{}: {:?} parsing each time, {:?} compiled once ({:.2}x)",
+            ROWS,
+            if *frozen_globals {
+                ", frozen globals"
+            } else {
+                ""
+            },
+            parsed,
+            compiled,
+            parsed.as_secs_f64() / compiled.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,31 @@
+//! Define functions in x7 and call them from Rust.
+//!
+//! cargo run --example call_lisp
+use im::vector;
+use x7::cli::Options;
+use x7::stdlib::create_stdlib_symbol_table;
+use x7::{Expr, LispResult};
+
+fn main() -> LispResult<()> {
+    let interpreter = create_stdlib_symbol_table(&Options::default());
+    interpreter.eval_source(
+        "(defn area (w h) (* w h))
+         (defn total (l) (reduce + 0 l))",
+    )?;
+
+    let area = interpreter.call_function("area", vector![Expr::from(3), Expr::from(4)])?;
+    assert_eq!(area.get_usize()?, 12);
+    println!("(area 3 4) ; {}", area);
+
+    // Lists are passed as values, not evaluated as calls.
+    let items = Expr::List(vector![Expr::from(1), Expr::from(2), Expr::from(3)]);
+    let total = interpreter.call_function("total", vector![items])?;
+    assert_eq!(total.get_usize()?, 6);
+    println!("(total '(1 2 3)) ; {}", total);
+
+    // Bind Rust values for a single evaluation.
+    let res = interpreter.eval_with_bindings("(area w h)", &[("w", 5.into()), ("h", 6.into())])?;
+    assert_eq!(res.get_usize()?, 30);
+    println!("(area w h) ; {}", res);
+    Ok(())
+}
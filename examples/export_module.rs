@@ -0,0 +1,57 @@
+//! Expose a whole module of Rust functions to x7 at once.
+//!
+//! cargo run --example export_module
+use im::Vector;
+use x7::cli::Options;
+use x7::stdlib::create_stdlib_symbol_table;
+use x7::{Expr, LispResult, Num};
+
+const USERS: &[&str] = &["ada", "grace", "barbara"];
+
+fn user_count() -> i64 {
+    USERS.len() as i64
+}
+
+fn get_user(id: usize) -> Option<String> {
+    USERS.get(id).map(|name| name.to_string())
+}
+
+fn greet(name: String, excited: bool) -> String {
+    format!("hello {}{}", name, if excited { "!" } else { "" })
+}
+
+fn total(nums: Vector<Expr>) -> LispResult<Num> {
+    let mut sum = Num::from(0);
+    for n in nums.iter() {
+        sum += n.get_num()?;
+    }
+    Ok(sum)
+}
+
+fn main() -> LispResult<()> {
+    let interpreter = create_stdlib_symbol_table(&Options::default());
+    x7::export_module!(interpreter, "app", {
+        "user-count" => 0 => user_count,
+        /// The name of the user with `id`, or nil.
+        "get-user" => 1 => get_user,
+        "greet" => 2 => greet,
+        #[doc = "Sum a list of nums."]
+        "total" => 1 => total,
+    });
+
+    let res = interpreter.eval_source("(app/greet (app/get-user 1) true)")?;
+    assert_eq!(res, Expr::from("hello grace!"));
+    println!("(app/greet (app/get-user 1) true) ; {:?}", res);
+
+    let res = interpreter.eval_source("(app/get-user (app/user-count))")?;
+    assert_eq!(res, Expr::Nil);
+    println!("(app/get-user (app/user-count)) ; {:?}", res);
+
+    let res = interpreter.eval_source("(app/total (map inc (range 3)))")?;
+    println!("(app/total (map inc (range 3))) ; {:?}", res);
+
+    // Arguments that don't convert to the Rust type are x7 errors.
+    let err = interpreter.eval_source("(app/get-user -1)").unwrap_err();
+    println!("(app/get-user -1) ; {:#}", err);
+    Ok(())
+}
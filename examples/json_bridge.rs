@@ -0,0 +1,21 @@
+//! Pass JSON documents through x7 with serde_json.
+//!
+//! cargo run --example json_bridge --features json
+use serde_json::json;
+use x7::cli::Options;
+use x7::stdlib::create_stdlib_symbol_table;
+use x7::{Expr, LispResult};
+
+fn main() -> LispResult<()> {
+    let interpreter = create_stdlib_symbol_table(&Options::default());
+    let order = json!({"item": "tea", "quantity": 2, "price": 3.5});
+
+    let res = interpreter.eval_with_bindings(
+        "(assoc order \"total\" (* (get order \"quantity\") (get order \"price\")))",
+        &[("order", Expr::from_json(&order))],
+    )?;
+    let res = res.to_json()?;
+    assert_eq!(res["total"], json!(7));
+    println!("{}", res);
+    Ok(())
+}
@@ -0,0 +1,101 @@
+//! A custom record: a key-value store shared between Rust and x7.
+//!
+//! cargo run --example kv_store
+use anyhow::anyhow;
+use im::Vector;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use x7::cli::Options;
+use x7::stdlib::create_stdlib_symbol_table;
+use x7::{unknown_method, Expr, LispResult, Record, RecordType};
+
+#[derive(Clone, Default)]
+struct KvStore {
+    entries: Arc<Mutex<BTreeMap<String, Expr>>>,
+}
+
+impl KvStore {
+    fn arg(args: &Vector<Expr>, i: usize, method: &str) -> LispResult<Expr> {
+        args.get(i)
+            .cloned()
+            .ok_or_else(|| anyhow!("KvStore.{} needs {} args", method, i + 1))
+    }
+}
+
+impl Record for KvStore {
+    fn call_method(&self, sym: &str, args: Vector<Expr>) -> LispResult<Expr> {
+        match sym {
+            "get" => {
+                let key = KvStore::arg(&args, 0, sym)?.get_string()?;
+                Ok(self.entries.lock().get(&key).cloned().unwrap_or(Expr::Nil))
+            }
+            "set" => {
+                let key = KvStore::arg(&args, 0, sym)?.get_string()?;
+                let value = KvStore::arg(&args, 1, sym)?;
+                self.entries.lock().insert(key, value.clone());
+                Ok(value)
+            }
+            "keys" => Ok(Expr::List(
+                self.entries
+                    .lock()
+                    .keys()
+                    .cloned()
+                    .map(Expr::String)
+                    .collect(),
+            )),
+            _ => unknown_method!(self, sym),
+        }
+    }
+
+    fn id(&self) -> u64 {
+        Arc::as_ptr(&self.entries) as u64
+    }
+
+    fn display(&self) -> String {
+        format!("KvStore<{} entries>", self.entries.lock().len())
+    }
+
+    fn debug(&self) -> String {
+        self.display()
+    }
+
+    fn clone(&self) -> RecordType {
+        Box::new(Clone::clone(self))
+    }
+
+    fn methods(&self) -> Vec<&'static str> {
+        vec!["get", "set", "keys"]
+    }
+
+    fn type_name(&self) -> &'static str {
+        "KvStore"
+    }
+}
+
+fn main() -> LispResult<()> {
+    let interpreter = create_stdlib_symbol_table(&Options::default());
+    let store = KvStore::default();
+    interpreter.add_global("store", Expr::Record(Box::new(Clone::clone(&store))));
+
+    interpreter.eval_source("(.set store \"greeting\" \"hi\") (.set store \"count\" 2)")?;
+    let keys = interpreter.eval_source("(.keys store)")?;
+    println!("(.keys store) ; {:?}", keys);
+
+    // Both sides see the same entries.
+    assert_eq!(store.entries.lock().get("count"), Some(&Expr::from(2)));
+    store
+        .entries
+        .lock()
+        .insert("from-rust".into(), Expr::Bool(true));
+    assert_eq!(
+        interpreter.eval_source("(.get store \"from-rust\")")?,
+        Expr::Bool(true)
+    );
+
+    let err = interpreter
+        .eval_source("(.delete store \"count\")")
+        .unwrap_err();
+    println!("(.delete store \"count\") ; {}", err);
+    Ok(())
+}
@@ -0,0 +1,26 @@
+//! Serve modules from memory instead of the filesystem.
+//!
+//! cargo run --example module_resolver
+use std::collections::HashMap;
+use x7::cli::Options;
+use x7::stdlib::create_stdlib_symbol_table;
+use x7::{Expr, LispResult};
+
+fn main() -> LispResult<()> {
+    let mut modules = HashMap::new();
+    modules.insert("geometry", "(defn square (x) (* x x))");
+
+    let interpreter = create_stdlib_symbol_table(&Options::default());
+    interpreter.set_module_resolver(move |name| modules.get(name).map(|s| s.to_string()));
+    // Resolved modules don't touch the filesystem, so they work when sandboxed.
+    interpreter.set_sandboxed(true);
+
+    let res = interpreter.eval_source("(require geometry) (geometry::square 7)")?;
+    assert_eq!(res, Expr::from(49));
+    println!("(geometry::square 7) ; {}", res);
+
+    // Anything else falls back to the filesystem as usual.
+    let err = interpreter.eval_source("(require missing)").unwrap_err();
+    println!("(require missing) ; {}", err);
+    Ok(())
+}
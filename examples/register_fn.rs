@@ -0,0 +1,29 @@
+//! Expose Rust functions to x7.
+//!
+//! cargo run --example register_fn
+use x7::cli::Options;
+use x7::stdlib::create_stdlib_symbol_table;
+use x7::{Expr, LispResult};
+
+fn main() -> LispResult<()> {
+    let interpreter = create_stdlib_symbol_table(&Options::default());
+    interpreter.add_function(
+        "shout",
+        1,
+        "Uppercase a string.\nExample:\n(shout \"hi\") ; \"HI\"\n",
+        |args, _| Ok(Expr::String(args[0].get_string()?.to_uppercase())),
+    );
+
+    let res = interpreter.eval_source("(shout \"hello\")")?;
+    assert_eq!(res, Expr::from("HELLO"));
+    println!("(shout \"hello\") ; {:?}", res);
+
+    // Rust functions work anywhere lisp functions do.
+    let res = interpreter.eval_source("(map shout (list \"a\" \"b\"))")?;
+    println!("(map shout (list \"a\" \"b\")) ; {:?}", res);
+
+    // Bad arguments are ordinary x7 errors.
+    let err = interpreter.eval_source("(shout 1)").unwrap_err();
+    println!("(shout 1) ; {:?}", err);
+    Ok(())
+}
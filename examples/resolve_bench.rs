@@ -0,0 +1,38 @@
+//! Compare evaluating with and without frozen globals, which resolves
+//! calls to builtins before evaluating.
+//!
+//! cargo run --release --example resolve_bench
+use std::time::Duration;
+use x7::{run_source, RunOptions};
+
+const FIB: &str = "
+(defn slow-fib (n) (if (< n 2) n (+ (slow-fib (- n 1)) (slow-fib (- n 2)))))
+(slow-fib 20)";
+
+const PIPELINE: &str = "
+(reduce + 0 (filter (fn (x) (= 0 (% x 3))) (map (fn (x) (* x (+ 1 1))) (range 20000))))";
+
+fn time(source: &str, frozen_globals: bool) -> Duration {
+    let opts = RunOptions {
+        frozen_globals,
+        ..Default::default()
+    };
+    let outcome = run_source(source, opts);
+    assert!(outcome.success(), "{:?}", outcome.error);
+    outcome.duration
+}
+
+fn main() {
+    for (name, source) in &[("fib", FIB), ("pipeline", PIPELINE)] {
+        let n = 5;
+        let plain: Duration = (0..n).map(|_| time(source, false)).sum::<Duration>() / n;
+        let frozen: Duration = (0..n).map(|_| time(source, true)).sum::<Duration>() / n;
+        println!(
+            "{}: {:?} looked up, {:?} resolved ({:.2}x)",
+            name,
+            plain,
+            frozen,
+            plain.as_secs_f64() / frozen.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,31 @@
+//! Evaluate untrusted x7 without letting it touch files or run forever.
+//!
+//! cargo run --example sandbox
+use std::time::Duration;
+use x7::{run_source, RunOptions};
+
+fn untrusted(source: &str) -> RunOptions {
+    println!("running {}", source);
+    RunOptions {
+        sandbox: true,
+        timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    }
+}
+
+fn main() {
+    let source = "(+ 1 2)";
+    let outcome = run_source(source, untrusted(source));
+    assert_eq!(outcome.value.as_deref(), Some("3"));
+    println!("  value: {:?}", outcome.value);
+
+    let source = "(fs::open \"/etc/passwd\")";
+    let outcome = run_source(source, untrusted(source));
+    assert!(!outcome.success());
+    println!("  error: {:?}", outcome.error);
+
+    let source = "(foreach ident (range))";
+    let outcome = run_source(source, untrusted(source));
+    assert_eq!(outcome.error.as_ref().unwrap().kind, "Interrupted");
+    println!("  error: {:?}", outcome.error);
+}
@@ -0,0 +1,28 @@
+//! Measure how long it takes to make an interpreter. The first one builds
+//! the stdlib, as every interpreter used to, and the rest copy it.
+//!
+//! cargo run --release --example startup
+use std::time::Instant;
+use x7::cli::Options;
+use x7::stdlib::create_stdlib_symbol_table;
+
+fn main() {
+    let opts = Options::default();
+    let start = Instant::now();
+    let _ = create_stdlib_symbol_table(&opts);
+    println!(
+        "first interpreter, building the stdlib: {:?}",
+        start.elapsed()
+    );
+
+    let n = 1000;
+    let start = Instant::now();
+    for _ in 0..n {
+        let _ = create_stdlib_symbol_table(&opts);
+    }
+    println!(
+        "next {} interpreters, copying it: {:?} each",
+        n,
+        start.elapsed() / n
+    );
+}
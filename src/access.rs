@@ -0,0 +1,543 @@
+use crate::symbols::{LispResult, ProgramError, SymbolTable};
+#[cfg(feature = "shell")]
+use anyhow::ensure;
+use anyhow::{anyhow, bail, Context};
+#[cfg(feature = "shell")]
+use std::env::VarError;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+#[cfg(any(feature = "io", feature = "http"))]
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// File, network, and environment access for builtins. Everything here checks
+// the sandbox and the embedder's access hook first, and nothing else in the
+// crate should touch the filesystem or network on behalf of a program.
+
+/// The kind of access a program wants. More kinds may be added, so matches
+/// on it need a catch-all arm.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum IoKind {
+    /// Opening a file to read or write.
+    OpenFile,
+    /// Creating or truncating a file.
+    CreateFile,
+    /// Looking up whether a path exists, or what it resolves to.
+    Stat,
+    /// Listing a directory.
+    ListDir,
+    /// Connecting to a URL.
+    Network,
+    /// Running a shell command.
+    Exec,
+    /// Reading an environment variable.
+    ReadEnv,
+    /// Setting or unsetting an environment variable.
+    SetEnv,
+    /// Watching a file or directory for changes.
+    Watch,
+}
+
+impl fmt::Display for IoKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            IoKind::OpenFile => "open-file",
+            IoKind::CreateFile => "create-file",
+            IoKind::Stat => "stat",
+            IoKind::ListDir => "list-dir",
+            IoKind::Network => "network",
+            IoKind::Exec => "exec",
+            IoKind::ReadEnv => "read-env",
+            IoKind::SetEnv => "set-env",
+            IoKind::Watch => "watch",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// An access a program wants to make, e.g. opening `/etc/passwd`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IoOp {
+    pub kind: IoKind,
+    /// The path, URL, command, or environment variable.
+    pub target: String,
+}
+
+impl fmt::Display for IoOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:?}", self.kind, self.target)
+    }
+}
+
+/// What the access hook decided about an `IoOp`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    Allow,
+    /// Fail the builtin with a `Permission` error.
+    Deny,
+    /// Allow, and note the access on stderr.
+    AllowAndLog,
+}
+
+/// Decides whether programs may make an access.
+#[derive(Clone)]
+pub(crate) struct IoHook(pub(crate) Rc<dyn Fn(&IoOp) -> Decision>);
+
+impl fmt::Debug for IoHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "IoHook")
+    }
+}
+
+/// Check the sandbox and access hook. `what` names the builtin for sandbox errors.
+fn check_access(
+    symbol_table: &SymbolTable,
+    what: &str,
+    kind: IoKind,
+    target: &str,
+) -> LispResult<()> {
+    let op = IoOp {
+        kind,
+        target: target.into(),
+    };
+    let hook = {
+        let host = symbol_table.host();
+        if host.is_sandboxed() {
+            bail!("{} is not allowed in sandbox mode", what);
+        }
+        host.io_hook()
+    };
+    let decision = hook
+        .map(|IoHook(hook)| hook(&op))
+        .unwrap_or(Decision::Allow);
+    match decision {
+        Decision::Allow => Ok(()),
+        Decision::AllowAndLog => {
+            symbol_table
+                .host_mut()
+                .write_stderr(&format!("io: {} {}\n", what, op));
+            Ok(())
+        }
+        Decision::Deny => Err(anyhow!(ProgramError::Permission)).with_context(|| {
+            format!(
+                "Permission denied: {} was refused by the io access policy ({})",
+                op, what
+            )
+        }),
+    }
+}
+
+fn io_err(message: String) -> anyhow::Error {
+    anyhow!(ProgramError::Io).context(message)
+}
+
+pub(crate) fn open(
+    symbol_table: &SymbolTable,
+    what: &str,
+    path: &str,
+    options: &OpenOptions,
+) -> LispResult<File> {
+    check_access(symbol_table, what, IoKind::OpenFile, path)?;
+    options
+        .open(path)
+        .map_err(|e| io_err(format!("Could not open \"{}\", {}", path, e)))
+}
+
+pub(crate) fn open_read(symbol_table: &SymbolTable, what: &str, path: &str) -> LispResult<File> {
+    open(symbol_table, what, path, OpenOptions::new().read(true))
+}
+
+pub(crate) fn read_to_string(
+    symbol_table: &SymbolTable,
+    what: &str,
+    path: &str,
+) -> LispResult<String> {
+    let mut contents = String::new();
+    open_read(symbol_table, what, path)?
+        .read_to_string(&mut contents)
+        .map_err(|e| io_err(format!("Could not read \"{}\", {}", path, e)))?;
+    Ok(contents)
+}
+
+/// Whether `path` is a file that could be opened, like a module being looked for.
+pub(crate) fn is_file(symbol_table: &SymbolTable, what: &str, path: &str) -> LispResult<bool> {
+    check_access(symbol_table, what, IoKind::OpenFile, path)?;
+    Ok(Path::new(path).is_file())
+}
+
+#[cfg(any(feature = "io", feature = "compression"))]
+pub(crate) fn create(symbol_table: &SymbolTable, what: &str, path: &str) -> LispResult<File> {
+    check_access(symbol_table, what, IoKind::CreateFile, path)?;
+    File::create(path).map_err(|e| io_err(format!("Could not create \"{}\", {}", path, e)))
+}
+
+#[cfg(any(feature = "io", feature = "http"))]
+pub(crate) fn create_dir_all(
+    symbol_table: &SymbolTable,
+    what: &str,
+    path: &Path,
+) -> LispResult<()> {
+    check_access(
+        symbol_table,
+        what,
+        IoKind::CreateFile,
+        &path.to_string_lossy(),
+    )?;
+    std::fs::create_dir_all(path)
+        .map_err(|e| io_err(format!("Could not create the directory {:?}, {}", path, e)))
+}
+
+#[cfg(feature = "io")]
+pub(crate) fn write_all(file: &mut File, path: &str, contents: &str) -> LispResult<()> {
+    file.write_all(contents.as_bytes())
+        .and_then(|_| file.flush())
+        .map_err(|e| io_err(format!("Could not write to \"{}\", {}", path, e)))
+}
+
+/// Replace the file at `path` with `contents` by writing a temp file next
+/// to it and renaming it over `path`, so readers see the old or the new
+/// file but never part of one. The temp file is removed if any stage fails.
+#[cfg(any(feature = "io", feature = "http"))]
+pub(crate) fn write_atomic(
+    symbol_table: &SymbolTable,
+    what: &str,
+    path: &str,
+    contents: &[u8],
+    fsync: bool,
+) -> LispResult<()> {
+    check_access(symbol_table, what, IoKind::CreateFile, path)?;
+    let target = Path::new(path);
+    let file_name = target.file_name().ok_or_else(|| {
+        anyhow!(
+            "{} needs a path to a file, but was given \"{}\"",
+            what,
+            path
+        )
+    })?;
+    let dir = match target.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    let stage_err = |stage: &str, e: std::io::Error| {
+        io_err(format!("Could not {} for \"{}\", {}", stage, path, e))
+    };
+    let temp = dir.join(format!(
+        ".{}.{:x}.tmp",
+        file_name.to_string_lossy(),
+        rand::random::<u32>()
+    ));
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp)
+        .map_err(|e| stage_err("create a temp file", e))?;
+    // The replaced file keeps its permissions.
+    let permitted = match std::fs::metadata(target) {
+        Ok(metadata) => file
+            .set_permissions(metadata.permissions())
+            .map_err(|e| stage_err("copy permissions to the temp file", e)),
+        Err(_) => Ok(()),
+    };
+    let written = permitted
+        .and_then(|_| {
+            file.write_all(contents)
+                .map_err(|e| stage_err("write the temp file", e))
+        })
+        .and_then(|_| {
+            if fsync {
+                file.sync_all()
+                    .map_err(|e| stage_err("fsync the temp file", e))?;
+            }
+            Ok(())
+        });
+    drop(file);
+    let renamed = written.and_then(|_| {
+        std::fs::rename(&temp, target).map_err(|e| stage_err("rename the temp file", e))
+    });
+    if let Err(e) = renamed {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e);
+    }
+    // Make the rename itself durable. Only unix can open directories.
+    if fsync && cfg!(unix) {
+        File::open(dir)
+            .and_then(|d| d.sync_all())
+            .map_err(|e| stage_err("fsync the directory", e))?;
+    }
+    Ok(())
+}
+
+/// An OS lock on a lock file, released when dropped or when the process
+/// exits, so a crash never leaves a stale lock. The file itself stays, as
+/// removing it would let the next opener lock a new file while someone
+/// who opened the old one still could too.
+#[cfg(feature = "io")]
+pub(crate) struct LockFile {
+    // Only held, as closing it releases the lock.
+    _file: File,
+}
+
+/// Lock the lock file `path`, making it if needed, or return None if the
+/// lock is already held, by us or another process.
+#[cfg(feature = "io")]
+pub(crate) fn lock_file(
+    symbol_table: &SymbolTable,
+    what: &str,
+    path: &str,
+) -> LispResult<Option<LockFile>> {
+    use fs2::FileExt;
+    check_access(symbol_table, what, IoKind::CreateFile, path)?;
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| io_err(format!("Could not open the lock file \"{}\", {}", path, e)))?;
+    match file.try_lock_exclusive() {
+        Ok(()) => Ok(Some(LockFile { _file: file })),
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => Ok(None),
+        Err(e) => Err(io_err(format!(
+            "Could not lock the lock file \"{}\", {}",
+            path, e
+        ))),
+    }
+}
+
+/// Create a new, empty file or directory with a unique name under the
+/// system temp dir, ending with `suffix`.
+#[cfg(feature = "io")]
+pub(crate) fn create_temp(
+    symbol_table: &SymbolTable,
+    what: &str,
+    suffix: &str,
+    dir: bool,
+) -> LispResult<PathBuf> {
+    // Names are random, so collisions only come from other processes
+    // racing us, and a few tries is plenty.
+    for _ in 0..8 {
+        let path =
+            std::env::temp_dir().join(format!("x7-{:016x}{}", rand::random::<u64>(), suffix));
+        check_access(
+            symbol_table,
+            what,
+            IoKind::CreateFile,
+            &path.to_string_lossy(),
+        )?;
+        let created = if dir {
+            std::fs::create_dir(&path)
+        } else {
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .map(drop)
+        };
+        match created {
+            Ok(()) => return Ok(path),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                return Err(io_err(format!(
+                    "Could not create the temp path {:?}, {}",
+                    path, e
+                )))
+            }
+        }
+    }
+    Err(io_err(format!(
+        "{} could not find an unused temp path in {:?}",
+        what,
+        std::env::temp_dir()
+    )))
+}
+
+/// Remove a path made by `create_temp`, and everything under it. Paths
+/// which are already gone are fine.
+#[cfg(feature = "io")]
+pub(crate) fn remove_temp(path: &Path) -> LispResult<()> {
+    let removed = if path.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    };
+    match removed {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(io_err(format!(
+            "Could not remove the temp path {:?}, {}",
+            path, e
+        ))),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn exists(symbol_table: &SymbolTable, what: &str, path: &str) -> LispResult<bool> {
+    check_access(symbol_table, what, IoKind::Stat, path)?;
+    Ok(Path::new(path).exists())
+}
+
+pub(crate) fn canonicalize(
+    symbol_table: &SymbolTable,
+    what: &str,
+    path: &str,
+) -> LispResult<PathBuf> {
+    check_access(symbol_table, what, IoKind::Stat, path)?;
+    std::fs::canonicalize(path)
+        .map_err(|e| io_err(format!("Could not resolve \"{}\", {}", path, e)))
+}
+
+/// The paths matching `pattern`, sorted. Unreadable directories are errors.
+pub(crate) fn glob(
+    symbol_table: &SymbolTable,
+    what: &str,
+    pattern: &str,
+) -> LispResult<Vec<PathBuf>> {
+    check_access(symbol_table, what, IoKind::ListDir, pattern)?;
+    let paths = ::glob::glob(pattern)
+        .map_err(|e| anyhow!("Invalid glob pattern \"{}\", {}", pattern, e))?;
+    let mut res = paths
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| io_err(format!("Could not read {:?}, {}", e.path(), e.error())))?;
+    res.sort();
+    Ok(res)
+}
+
+/// Watch `path` for changes, sending them to `tx` once they've been quiet for `delay`.
+#[cfg(feature = "watch")]
+pub(crate) fn watch(
+    symbol_table: &SymbolTable,
+    what: &str,
+    path: &str,
+    tx: std::sync::mpsc::Sender<notify::DebouncedEvent>,
+    delay: std::time::Duration,
+) -> LispResult<notify::RecommendedWatcher> {
+    use notify::Watcher;
+    check_access(symbol_table, what, IoKind::Watch, path)?;
+    let mut watcher = notify::watcher(tx, delay)
+        .map_err(|e| io_err(format!("Could not watch \"{}\", {}", path, e)))?;
+    watcher
+        .watch(path, notify::RecursiveMode::Recursive)
+        .map_err(|e| io_err(format!("Could not watch \"{}\", {}", path, e)))?;
+    Ok(watcher)
+}
+
+/// The value of the environment variable `key`, or None if it isn't set.
+#[cfg(feature = "shell")]
+pub(crate) fn env_var(
+    symbol_table: &SymbolTable,
+    what: &str,
+    key: &str,
+) -> LispResult<Option<String>> {
+    check_access(symbol_table, what, IoKind::ReadEnv, key)?;
+    match std::env::var(key) {
+        Ok(value) => Ok(Some(value)),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(VarError::NotUnicode(_)) => {
+            bail!("The environment variable {} is not valid unicode", key)
+        }
+    }
+}
+
+/// Check that a program may set the environment variable `key` to `value`,
+/// or unset it when `value` is None. Callers then set it themselves, so
+/// builtins setting many variables can check them all before changing any.
+#[cfg(feature = "shell")]
+pub(crate) fn check_set_env(
+    symbol_table: &SymbolTable,
+    what: &str,
+    key: &str,
+    value: Option<&str>,
+) -> LispResult<()> {
+    // std::env::set_var panics on these.
+    ensure!(
+        !key.is_empty() && !key.contains(&['=', '\0'][..]),
+        "{} was given the invalid environment variable name {:?}",
+        what,
+        key
+    );
+    ensure!(
+        !value.is_some_and(|v| v.contains('\0')),
+        "{} was given a value for {} containing a NUL byte",
+        what,
+        key
+    );
+    check_access(symbol_table, what, IoKind::SetEnv, key)
+}
+
+#[cfg(feature = "http")]
+pub(crate) fn fetch_url(symbol_table: &SymbolTable, what: &str, url: &str) -> LispResult<String> {
+    check_access(symbol_table, what, IoKind::Network, url)?;
+    let resp = ureq::get(url).call();
+    if let Some(e) = resp.synthetic_error() {
+        bail!("Could not fetch {}, {}", url, e);
+    }
+    if !resp.ok() {
+        bail!("Could not fetch {}, server returned {}", url, resp.status());
+    }
+    resp.into_string()
+        .map_err(|e| anyhow!("Could not read the response from {}, {}", url, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Options;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use std::cell::RefCell;
+
+    #[test]
+    fn access_hook_sees_every_open() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let log = seen.clone();
+        syms.on_io_access(move |op| {
+            log.borrow_mut().push(op.clone());
+            Decision::Allow
+        });
+        let _ = read_to_string(&syms, "test", "Cargo.toml");
+        let _ = open_read(&syms, "test", "does-not-exist");
+        assert_eq!(
+            *seen.borrow(),
+            vec![
+                IoOp {
+                    kind: IoKind::OpenFile,
+                    target: "Cargo.toml".into()
+                },
+                IoOp {
+                    kind: IoKind::OpenFile,
+                    target: "does-not-exist".into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn denied_access_is_a_permission_error() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.on_io_access(|_| Decision::Deny);
+        let err = read_to_string(&syms, "test", "Cargo.toml").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ProgramError>(),
+            Some(&ProgramError::Permission)
+        );
+    }
+
+    #[test]
+    fn logged_access_goes_to_stderr() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.host_mut().capture_output();
+        syms.on_io_access(|_| Decision::AllowAndLog);
+        read_to_string(&syms, "test", "Cargo.toml").unwrap();
+        let (_, stderr) = syms.host_mut().take_captured_output();
+        assert_eq!(stderr, "io: test open-file \"Cargo.toml\"\n");
+    }
+
+    #[test]
+    fn sandbox_wins_over_hook() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.on_io_access(|_| Decision::Allow);
+        syms.set_sandboxed(true);
+        let err = read_to_string(&syms, "test", "Cargo.toml").unwrap_err();
+        assert_eq!(err.to_string(), "test is not allowed in sandbox mode");
+    }
+}
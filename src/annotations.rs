@@ -0,0 +1,151 @@
+use crate::symbols::{Expr, LispResult};
+use anyhow::{anyhow, bail, ensure};
+use im::Vector;
+use itertools::Itertools;
+
+// Optional type annotations on functions, e.g.
+//
+//   (defn add (x :num y :num) :num (+ x y))
+//
+// A keyword after a parameter annotates that parameter, and one after the
+// parameter list annotates the return value. They don't change how the
+// function runs. `doc` shows them, and `check-types` can enforce them.
+
+/// The types a function was annotated with. Unannotated parameters, and
+/// the `&` marker, are None.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Annotations {
+    params: Vec<Option<String>>,
+    ret: Option<String>,
+}
+
+/// The type keywords `check-types` knows, named like `(type x)`.
+const TYPES: &[&str] = &[
+    "any", "num", "str", "bool", "list", "tuple", "map", "func", "symbol", "nil", "iterator",
+    "record", "quote",
+];
+
+/// Split an annotated parameter list into the parameter names and their
+/// annotations.
+pub(crate) fn parse_params(
+    params: &Vector<Expr>,
+) -> LispResult<(Vector<Expr>, Vec<Option<String>>)> {
+    let mut names = Vector::new();
+    let mut types: Vec<Option<String>> = Vec::new();
+    for param in params.iter() {
+        if !param.is_keyword() {
+            names.push_back(param.clone());
+            types.push(None);
+            continue;
+        }
+        let name = param.get_symbol_string()?[1..].to_string();
+        match (names.last(), types.last_mut()) {
+            (Some(prev), Some(slot @ None)) if !prev.symbol_matches("&") => *slot = Some(name),
+            _ => bail!(
+                "Type annotation {} must follow a parameter name in {}",
+                param,
+                Expr::List(params.clone())
+            ),
+        }
+    }
+    Ok((names, types))
+}
+
+impl Annotations {
+    pub(crate) fn new(params: Vec<Option<String>>, ret: Option<String>) -> Self {
+        Annotations { params, ret }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.ret.is_none() && self.params.iter().all(Option::is_none)
+    }
+
+    /// e.g. `(num num) -> num`. Unannotated types show as `any`.
+    pub(crate) fn signature(&self, names: &[Expr]) -> String {
+        let params = names
+            .iter()
+            .zip(self.params.iter())
+            .map(|(name, ty)| match ty {
+                _ if name.symbol_matches("&") => "&",
+                Some(ty) => ty.as_str(),
+                None => "any",
+            })
+            .join(" ");
+        format!("({}) -> {}", params, self.ret.as_deref().unwrap_or("any"))
+    }
+
+    /// Make sure every annotation is a type `check-types` knows.
+    pub(crate) fn ensure_known(&self) -> LispResult<()> {
+        for ty in self
+            .params
+            .iter()
+            .chain(std::iter::once(&self.ret))
+            .flatten()
+        {
+            ensure!(
+                TYPES.contains(&ty.as_str()),
+                "Unknown type :{} in annotations, expected one of :{}",
+                ty,
+                TYPES.join(" :")
+            );
+        }
+        Ok(())
+    }
+
+    /// Check the arguments of a call to a function with parameters `names`.
+    /// A rest parameter is checked against the list of the remaining arguments.
+    pub(crate) fn check_args(
+        &self,
+        fn_name: &str,
+        names: &[Expr],
+        args: &Vector<Expr>,
+    ) -> LispResult<()> {
+        let mut rest = false;
+        let mut index = 0;
+        for (name, ty) in names.iter().zip(self.params.iter()) {
+            if name.symbol_matches("&") {
+                rest = true;
+                continue;
+            }
+            let value = if rest {
+                Expr::List(args.clone().slice(index.min(args.len())..))
+            } else {
+                match args.get(index) {
+                    Some(value) => value.clone(),
+                    None => break,
+                }
+            };
+            check(ty, &value).map_err(|got| {
+                anyhow!(
+                    "{} expected {} to be {}, but got {}",
+                    fn_name,
+                    name,
+                    ty.as_deref().unwrap_or("any"),
+                    got
+                )
+            })?;
+            index += 1;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_return(&self, fn_name: &str, value: &Expr) -> LispResult<()> {
+        check(&self.ret, value).map_err(|got| {
+            anyhow!(
+                "{} expected to return {}, but returned {}",
+                fn_name,
+                self.ret.as_deref().unwrap_or("any"),
+                got
+            )
+        })
+    }
+}
+
+/// Ok if `value` has type `ty`, otherwise a description of what it was.
+fn check(ty: &Option<String>, value: &Expr) -> Result<(), String> {
+    match ty.as_deref() {
+        None | Some("any") => Ok(()),
+        Some(ty) if ty == value.get_type_str() => Ok(()),
+        Some(_) => Err(format!("{} {:?}", value.get_type_str(), value)),
+    }
+}
@@ -0,0 +1,530 @@
+use crate::exact_len;
+use crate::symbols::{Dict, Expr, LispResult, Num, SymbolTable};
+use anyhow::{anyhow, bail, ensure};
+use im::Vector;
+use itertools::Itertools;
+
+// Command line parsing for scripts. A spec is a dict:
+//
+//   :name        the program name, for usage
+//   :flags       a dict of flag keyword -> options, where options has
+//                  :short        a one letter alias, e.g. "v" for -v
+//                  :bool         the flag is a switch, the default
+//                  :takes-value  the flag is followed by a value
+//                  :default      the value when the flag isn't given
+//                  :type         :string (the default), :int, or :num
+//                  :help         a description for usage
+//   :positional  a list of keywords naming the required positional arguments
+//
+// Values are given as --flag=value, --flag value, -f value, or -fvalue, and
+// switches can be combined, e.g. -vo out. Everything after -- is positional.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ValueType {
+    String,
+    Int,
+    Num,
+}
+
+struct FlagSpec {
+    key: Expr,
+    name: String,
+    short: Option<char>,
+    takes_value: bool,
+    default: Expr,
+    value_type: ValueType,
+    help: Option<String>,
+}
+
+struct ArgSpec {
+    name: Option<String>,
+    flags: Vec<FlagSpec>,
+    positional: Vec<Expr>,
+}
+
+fn get<'a>(dict: &'a Dict, key: &str) -> Option<&'a Expr> {
+    dict.get(&Expr::Symbol(key.into()))
+}
+
+/// Fail if `dict` has keys other than `known`.
+fn check_keys(what: &str, dict: &Dict, known: &[&str]) -> LispResult<()> {
+    for key in dict.keys() {
+        let name = key.get_symbol_string()?;
+        ensure!(
+            known.contains(&name.as_str()),
+            "{} has no option {}, it knows {}",
+            what,
+            name,
+            known.join(" ")
+        );
+    }
+    Ok(())
+}
+
+fn keyword_name(key: &Expr) -> LispResult<String> {
+    let name = key.get_symbol_string()?;
+    match name.strip_prefix(':') {
+        Some(name) if !name.is_empty() => Ok(name.into()),
+        _ => bail!(
+            "parse-args expects keywords to name flags and arguments, but was given {}",
+            key
+        ),
+    }
+}
+
+impl FlagSpec {
+    fn new(key: &Expr, options: &Expr) -> LispResult<Self> {
+        let name = keyword_name(key)?;
+        let what = format!("The flag --{}", name);
+        let options = options.get_dict()?;
+        check_keys(
+            &what,
+            &options,
+            &[
+                ":short",
+                ":bool",
+                ":takes-value",
+                ":default",
+                ":type",
+                ":help",
+            ],
+        )?;
+        let is_set = |option| get(&options, option).map_or(Ok(false), Expr::get_bool);
+        let takes_value = is_set(":takes-value")?;
+        ensure!(
+            !(takes_value && is_set(":bool")?),
+            "{} can't be both :bool and :takes-value",
+            what
+        );
+        let short = match get(&options, ":short") {
+            Some(short) => {
+                let short = short.get_string()?;
+                let mut chars = short.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if c != '-' => Some(c),
+                    _ => bail!(
+                        "{} needs a one letter :short, but was given {:?}",
+                        what,
+                        short
+                    ),
+                }
+            }
+            None => None,
+        };
+        let value_type = match get(&options, ":type") {
+            None => ValueType::String,
+            Some(t) => match t.get_symbol_string()?.as_str() {
+                ":string" => ValueType::String,
+                ":int" => ValueType::Int,
+                ":num" => ValueType::Num,
+                other => bail!(
+                    "{} has an unknown :type {}, try :string :int or :num",
+                    what,
+                    other
+                ),
+            },
+        };
+        ensure!(
+            takes_value || value_type == ValueType::String,
+            "{} needs :takes-value true to have a :type",
+            what
+        );
+        let default = match get(&options, ":default") {
+            Some(default) => default.clone(),
+            None if takes_value => Expr::Nil,
+            None => Expr::Bool(false),
+        };
+        let help = get(&options, ":help").map(Expr::get_string).transpose()?;
+        Ok(FlagSpec {
+            key: key.clone(),
+            name,
+            short,
+            takes_value,
+            default,
+            value_type,
+            help,
+        })
+    }
+
+    fn convert(&self, value: &str) -> LispResult<Expr> {
+        let expected = match self.value_type {
+            ValueType::String => return Ok(Expr::String(value.into())),
+            ValueType::Int => "an int",
+            ValueType::Num => "a number",
+        };
+        match value.parse::<Num>().map(Expr::Num) {
+            Ok(n) if self.value_type == ValueType::Num || n.is_int()? => Ok(n),
+            _ => bail!(
+                "--{} expects {}, but was given {:?}",
+                self.name,
+                expected,
+                value
+            ),
+        }
+    }
+
+    /// How usage shows the flag, e.g. "-o, --output <value>".
+    fn signature(&self) -> String {
+        let short = match self.short {
+            Some(c) => format!("-{}, ", c),
+            None => "    ".into(),
+        };
+        let value = match (self.takes_value, self.value_type) {
+            (false, _) => "",
+            (true, ValueType::String) => " <value>",
+            (true, ValueType::Int) => " <int>",
+            (true, ValueType::Num) => " <num>",
+        };
+        format!("{}--{}{}", short, self.name, value)
+    }
+}
+
+impl ArgSpec {
+    fn new(spec: &Expr) -> LispResult<Self> {
+        let spec = spec.get_dict()?;
+        check_keys(
+            "parse-args' spec",
+            &spec,
+            &[":name", ":flags", ":positional"],
+        )?;
+        let name = get(&spec, ":name").map(Expr::get_string).transpose()?;
+        let mut flags = match get(&spec, ":flags") {
+            Some(flags) => flags
+                .get_dict()?
+                .iter()
+                .map(|(key, options)| FlagSpec::new(key, options))
+                .collect::<LispResult<Vec<_>>>()?,
+            None => Vec::new(),
+        };
+        // Dicts are unordered, so list flags alphabetically.
+        flags.sort_by(|l, r| l.name.cmp(&r.name));
+        for (l, r) in flags.iter().tuple_combinations() {
+            ensure!(
+                l.short.is_none() || l.short != r.short,
+                "--{} and --{} have the same :short",
+                l.name,
+                r.name
+            );
+        }
+        let positional = match get(&spec, ":positional") {
+            Some(positional) => {
+                let positional = positional.get_list()?;
+                for p in positional.iter() {
+                    keyword_name(p)?;
+                }
+                positional.into_iter().collect()
+            }
+            None => Vec::new(),
+        };
+        Ok(ArgSpec {
+            name,
+            flags,
+            positional,
+        })
+    }
+
+    fn long(&self, name: &str) -> LispResult<&FlagSpec> {
+        self.flags
+            .iter()
+            .find(|f| f.name == name)
+            .ok_or_else(|| anyhow!("Unknown flag --{}", name))
+    }
+
+    fn short(&self, c: char) -> Option<&FlagSpec> {
+        self.flags.iter().find(|f| f.short == Some(c))
+    }
+
+    fn parse(&self, args: &[String]) -> LispResult<Dict> {
+        let mut parsed: Dict = self
+            .flags
+            .iter()
+            .map(|f| (f.key.clone(), f.default.clone()))
+            .collect();
+        let mut positionals = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                positionals.extend(args.by_ref().cloned());
+                break;
+            }
+            if let Some(long) = arg.strip_prefix("--") {
+                let (name, inline) = match long.find('=') {
+                    Some(i) => (&long[..i], Some(&long[i + 1..])),
+                    None => (long, None),
+                };
+                let flag = self.long(name)?;
+                let value = if flag.takes_value {
+                    match inline {
+                        Some(value) => flag.convert(value)?,
+                        None => match args.next() {
+                            Some(value) => flag.convert(value)?,
+                            None => bail!("--{} needs a value", name),
+                        },
+                    }
+                } else {
+                    ensure!(
+                        inline.is_none(),
+                        "--{} doesn't take a value, but was given {}",
+                        name,
+                        arg
+                    );
+                    Expr::Bool(true)
+                };
+                parsed.insert(flag.key.clone(), value);
+                continue;
+            }
+            let shorts: Vec<char> = arg.chars().skip(1).collect();
+            let is_flag = arg.starts_with('-') && !shorts.is_empty();
+            // Negative numbers are positional, unless there's a flag like -1.
+            if !is_flag || (shorts[0].is_ascii_digit() && self.short(shorts[0]).is_none()) {
+                positionals.push(arg.clone());
+                continue;
+            }
+            for (i, c) in shorts.iter().enumerate() {
+                let flag = self
+                    .short(*c)
+                    .ok_or_else(|| anyhow!("Unknown flag -{} in {}", c, arg))?;
+                if !flag.takes_value {
+                    parsed.insert(flag.key.clone(), Expr::Bool(true));
+                    continue;
+                }
+                // The rest of the argument is the value, as in -ofile, or the next one is.
+                let rest: String = shorts[i + 1..].iter().collect();
+                let value = if !rest.is_empty() {
+                    rest
+                } else {
+                    match args.next() {
+                        Some(value) => value.clone(),
+                        None => bail!("-{} needs a value", c),
+                    }
+                };
+                parsed.insert(flag.key.clone(), flag.convert(&value)?);
+                break;
+            }
+        }
+        if let Some(missing) = self.positional.get(positionals.len()) {
+            bail!("Missing the argument <{}>", keyword_name(missing)?);
+        }
+        let mut positionals = positionals.into_iter();
+        for key in self.positional.iter() {
+            // Checked above that there are enough.
+            parsed.insert(key.clone(), Expr::String(positionals.next().unwrap()));
+        }
+        let rest = positionals.map(Expr::String).collect();
+        parsed.insert(Expr::Symbol(":rest".into()), Expr::List(rest));
+        Ok(parsed)
+    }
+
+    fn usage(&self) -> LispResult<String> {
+        let mut line = vec!["Usage:".to_string()];
+        line.extend(self.name.clone());
+        if !self.flags.is_empty() {
+            line.push("[options]".into());
+        }
+        for p in self.positional.iter() {
+            line.push(format!("<{}>", keyword_name(p)?));
+        }
+        let mut usage = line.join(" ");
+        if self.flags.is_empty() {
+            return Ok(usage + "\n");
+        }
+        usage.push_str("\n\nOptions:\n");
+        let width = self.flags.iter().map(|f| f.signature().len()).max();
+        for flag in self.flags.iter() {
+            let mut help: Vec<String> = flag.help.iter().cloned().collect();
+            if flag.takes_value && flag.default != Expr::Nil {
+                help.push(format!("(default: {})", flag.default));
+            }
+            let line = format!(
+                "  {:width$}  {}",
+                flag.signature(),
+                help.join(" "),
+                width = width.unwrap_or(0)
+            );
+            usage.push_str(line.trim_end());
+            usage.push('\n');
+        }
+        Ok(usage)
+    }
+}
+
+/// (parse-args args spec)
+pub(crate) fn parse_args(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let args = exprs[0]
+        .get_list()?
+        .iter()
+        .map(Expr::get_string)
+        .collect::<LispResult<Vec<_>>>()?;
+    let spec = ArgSpec::new(&exprs[1])?;
+    spec.parse(&args).map(Expr::Dict)
+}
+
+/// (usage spec)
+pub(crate) fn usage(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    ArgSpec::new(&exprs[0])?.usage().map(Expr::String)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::Options;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use crate::symbols::{Expr, LispResult, SymbolTable};
+
+    const SPEC: &str = "(def spec (dict
+      :name \"convert\"
+      :flags (dict
+        :verbose (dict :short \"v\" :bool true :help \"Say more.\")
+        :output (dict :short \"o\" :takes-value true :default \"out.txt\" :help \"Where to write.\")
+        :count (dict :takes-value true :type :int)
+        :scale (dict :short \"s\" :takes-value true :type :num))
+      :positional '(:input)))";
+
+    fn syms() -> SymbolTable {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.eval_source(SPEC).unwrap();
+        syms
+    }
+
+    /// Parse `args`, returning the values of :verbose :output :count :scale :input :rest.
+    fn parse(args: &[&str]) -> LispResult<Vec<String>> {
+        let syms = syms();
+        let args = args.iter().map(|a| format!("{:?}", a)).collect::<Vec<_>>();
+        let prog = format!("(parse-args (list {}) spec)", args.join(" "));
+        let parsed = syms.eval_source(&prog)?.get_dict()?;
+        Ok(
+            [":verbose", ":output", ":count", ":scale", ":input", ":rest"]
+                .iter()
+                .map(|key| format!("{}", parsed[&Expr::Symbol((*key).into())]))
+                .collect(),
+        )
+    }
+
+    fn err(args: &[&str]) -> String {
+        parse(args).unwrap_err().root_cause().to_string()
+    }
+
+    #[test]
+    fn defaults_and_positionals() {
+        assert_eq!(
+            parse(&["in.txt"]).unwrap(),
+            ["false", "out.txt", "nil", "nil", "in.txt", "()"]
+        );
+        assert_eq!(
+            parse(&["in.txt", "more", "-"]).unwrap(),
+            [
+                "false",
+                "out.txt",
+                "nil",
+                "nil",
+                "in.txt",
+                "(\"more\" \"-\")"
+            ]
+        );
+    }
+
+    #[test]
+    fn long_flags() {
+        let expected = ["true", "x.txt", "3", "-1.5", "in.txt", "()"];
+        let equals = [
+            "--verbose",
+            "--output=x.txt",
+            "--count=3",
+            "--scale=-1.5",
+            "in.txt",
+        ];
+        assert_eq!(parse(&equals).unwrap(), expected);
+        let spaced = [
+            "in.txt",
+            "--output",
+            "x.txt",
+            "--count",
+            "3",
+            "--scale",
+            "-1.5",
+            "--verbose",
+        ];
+        assert_eq!(parse(&spaced).unwrap(), expected);
+        // The last one wins.
+        assert_eq!(parse(&["-o", "a", "--output=b", "in"]).unwrap()[1], "b");
+    }
+
+    #[test]
+    fn short_flags() {
+        let expected = ["true", "x.txt", "nil", "2", "in.txt", "()"];
+        assert_eq!(
+            parse(&["-v", "-o", "x.txt", "-s", "2", "in.txt"]).unwrap(),
+            expected
+        );
+        assert_eq!(parse(&["-vo", "x.txt", "-s2", "in.txt"]).unwrap(), expected);
+        assert_eq!(parse(&["-vox.txt", "in.txt", "-s", "2"]).unwrap(), expected);
+        // Negative numbers aren't flags.
+        assert_eq!(parse(&["-5"]).unwrap()[4], "-5");
+    }
+
+    #[test]
+    fn double_dash_stops_parsing() {
+        assert_eq!(
+            parse(&["-v", "--", "--output", "-v"]).unwrap(),
+            ["true", "out.txt", "nil", "nil", "--output", "(\"-v\")"]
+        );
+    }
+
+    #[test]
+    fn bad_arguments() {
+        assert_eq!(err(&["--nope", "in"]), "Unknown flag --nope");
+        assert_eq!(err(&["-vx", "in"]), "Unknown flag -x in -vx");
+        assert_eq!(err(&[]), "Missing the argument <input>");
+        assert_eq!(err(&["in", "--output"]), "--output needs a value");
+        assert_eq!(err(&["in", "-o"]), "-o needs a value");
+        assert_eq!(
+            err(&["in", "--count=2.5"]),
+            "--count expects an int, but was given \"2.5\""
+        );
+        assert_eq!(
+            err(&["in", "-s", "big"]),
+            "--scale expects a number, but was given \"big\""
+        );
+        assert_eq!(
+            err(&["in", "--verbose=yes"]),
+            "--verbose doesn't take a value, but was given --verbose=yes"
+        );
+    }
+
+    #[test]
+    fn bad_specs() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        for spec in &[
+            "(dict :flags (dict :v (dict :short \"vv\")))",
+            "(dict :flags (dict :v (dict :bool true :takes-value true)))",
+            "(dict :flags (dict :v (dict :type :int)))",
+            "(dict :flags (dict :v (dict :shrot \"v\")))",
+            "(dict :flags (dict :a (dict :short \"x\") :b (dict :short \"x\")))",
+            "(dict :positional '(input))",
+            "(dict :flag (dict))",
+        ] {
+            let prog = format!("(parse-args (list) {})", spec);
+            assert!(syms.eval_source(&prog).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn usage_lists_flags() {
+        let syms = syms();
+        assert_eq!(
+            syms.eval_source("(usage spec)").unwrap(),
+            Expr::String(
+                "Usage: convert [options] <input>
+
+Options:
+      --count <int>
+  -o, --output <value>  Where to write. (default: out.txt)
+  -s, --scale <num>
+  -v, --verbose         Say more.
+"
+                .into()
+            )
+        );
+    }
+}
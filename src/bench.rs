@@ -0,0 +1,309 @@
+use crate::exact_len;
+use crate::symbols::{Dict, Expr, LispResult, Num, SymbolTable};
+use crate::table;
+use anyhow::{anyhow, bail, ensure};
+use bigdecimal::ToPrimitive;
+use im::{vector, Vector};
+use std::time::{Duration, Instant};
+
+// `(benchmark options f)` calls f repeatedly and summarizes how long each
+// call took:
+//
+//   :warmup       calls made before timing starts, 3 by default
+//   :iterations   timed calls, 100 by default
+//   :max-seconds  stop early once this long has passed, warmup included,
+//                 after at least one timed call
+//
+// Times are wall clock seconds, measured with a monotonic clock. x7 doesn't
+// count allocations, so there's no allocation total.
+
+#[derive(Debug)]
+struct BenchOptions {
+    warmup: usize,
+    iterations: usize,
+    max_seconds: Option<Duration>,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        BenchOptions {
+            warmup: 3,
+            iterations: 100,
+            max_seconds: None,
+        }
+    }
+}
+
+impl BenchOptions {
+    fn from_dict(what: &str, options: &Dict) -> LispResult<Self> {
+        let mut bench = BenchOptions::default();
+        for (key, value) in options.iter() {
+            match key.get_symbol_string()?.as_str() {
+                ":warmup" => bench.warmup = value.get_usize()?,
+                ":iterations" => bench.iterations = value.get_usize()?,
+                ":max-seconds" => bench.max_seconds = Some(value.get_seconds(":max-seconds")?),
+                other => bail!(
+                    "{} has no option {}, it knows :warmup :iterations :max-seconds",
+                    what,
+                    other
+                ),
+            }
+        }
+        ensure!(bench.iterations > 0, "{} needs at least 1 iteration", what);
+        Ok(bench)
+    }
+}
+
+/// How long each timed call of `f` took.
+fn measure(
+    f: &Expr,
+    options: &BenchOptions,
+    symbol_table: &SymbolTable,
+) -> LispResult<Vec<Duration>> {
+    let start = Instant::now();
+    let out_of_time = || match options.max_seconds {
+        Some(max) => start.elapsed() >= max,
+        None => false,
+    };
+    for _ in 0..options.warmup {
+        if out_of_time() {
+            break;
+        }
+        f.call_fn(Vector::new(), symbol_table)?;
+    }
+    let mut times = Vec::with_capacity(options.iterations);
+    // Each result lives until the next call has been timed, so dropping it
+    // isn't part of any call's time, and the call can't be skipped.
+    let mut _kept = Expr::Nil;
+    while times.len() < options.iterations {
+        let before = Instant::now();
+        let res = f.call_fn(Vector::new(), symbol_table)?;
+        times.push(before.elapsed());
+        _kept = res;
+        if out_of_time() {
+            break;
+        }
+    }
+    Ok(times)
+}
+
+fn secs(d: Duration) -> Expr {
+    Expr::Num((Num::from(d.as_nanos() as i64) / Num::from(1_000_000_000)).with_scale(9))
+}
+
+/// A summary of `times`, which mustn't be empty.
+fn summarize(times: &mut [Duration], warmup: usize) -> Dict {
+    times.sort();
+    let n = times.len();
+    let total: Duration = times.iter().sum();
+    let median = if n.is_multiple_of(2) {
+        (times[n / 2 - 1] + times[n / 2]) / 2
+    } else {
+        times[n / 2]
+    };
+    // The nearest rank: the smallest time at least 95% of calls were within.
+    let p95 = times[(n * 95).div_ceil(100) - 1];
+    let mut summary = Dict::new();
+    let mut add = |key: &str, value| summary.insert(Expr::Symbol(key.into()), value);
+    add(":iterations", Expr::from(n as i64));
+    add(":warmup", Expr::from(warmup as i64));
+    add(":min", secs(times[0]));
+    add(":max", secs(times[n - 1]));
+    add(":mean", secs(total / n as u32));
+    add(":median", secs(median));
+    add(":p95", secs(p95));
+    add(":total", secs(total));
+    summary
+}
+
+fn run(
+    what: &str,
+    options: Option<&Expr>,
+    f: &Expr,
+    symbol_table: &SymbolTable,
+) -> LispResult<Dict> {
+    let options = match options {
+        Some(options) => BenchOptions::from_dict(what, &options.get_dict()?)?,
+        None => BenchOptions::default(),
+    };
+    f.get_function()?;
+    let mut times = measure(f, &options, symbol_table)?;
+    Ok(summarize(&mut times, options.warmup))
+}
+
+/// (benchmark [options] f)
+pub(crate) fn benchmark(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1, 2);
+    let (options, f) = match exprs.len() {
+        1 => (None, &exprs[0]),
+        _ => (Some(&exprs[0]), &exprs[1]),
+    };
+    run("benchmark", options, f, symbol_table).map(Expr::Dict)
+}
+
+/// A time short enough to read at a glance, like 1.25ms.
+fn human(d: Duration) -> String {
+    let secs = d.as_secs_f64();
+    let (value, unit) = if secs >= 1.0 {
+        (secs, "s")
+    } else if secs >= 1e-3 {
+        (secs * 1e3, "ms")
+    } else if secs >= 1e-6 {
+        (secs * 1e6, "µs")
+    } else {
+        (secs * 1e9, "ns")
+    };
+    format!("{:.2}{}", value, unit)
+}
+
+fn duration_of(summary: &Dict, key: &str) -> Duration {
+    summary
+        .get(&Expr::Symbol(key.into()))
+        .and_then(|n| n.get_num().ok())
+        .and_then(|n| n.to_f64())
+        .map_or(Duration::from_secs(0), Duration::from_secs_f64)
+}
+
+/// (benchmark-compare (dict :name f ...) [options])
+pub(crate) fn benchmark_compare(
+    exprs: Vector<Expr>,
+    symbol_table: &SymbolTable,
+) -> LispResult<Expr> {
+    exact_len!(exprs, 1, 2);
+    let mut candidates: Vec<(Expr, Expr)> = exprs[0].get_dict()?.into_iter().collect();
+    ensure!(
+        !candidates.is_empty(),
+        "benchmark-compare needs at least one function to time"
+    );
+    // Dicts are unordered, so run and list them by name.
+    candidates.sort_by_key(|(name, _)| format!("{}", name));
+    let mut results = Dict::new();
+    let mut summaries = Vec::new();
+    for (name, f) in candidates {
+        let summary = run("benchmark-compare", exprs.get(1), &f, symbol_table)?;
+        results.insert(name.clone(), Expr::Dict(summary.clone()));
+        summaries.push((name, summary));
+    }
+
+    let fastest = summaries
+        .iter()
+        .map(|(_, summary)| duration_of(summary, ":mean"))
+        .min()
+        .unwrap_or_default();
+    let rows = summaries
+        .iter()
+        .map(|(name, summary)| {
+            let mean = duration_of(summary, ":mean");
+            let relative = if fastest.as_nanos() == 0 {
+                1.0
+            } else {
+                mean.as_secs_f64() / fastest.as_secs_f64()
+            };
+            let mut row = Dict::new();
+            let mut add = |key: &str, value| row.insert(Expr::Symbol(key.into()), value);
+            add(":name", name.clone());
+            for key in [":mean", ":median", ":p95"].iter().copied() {
+                add(key, Expr::String(human(duration_of(summary, key))));
+            }
+            add(":relative", Expr::String(format!("{:.2}x", relative)));
+            Expr::Dict(row)
+        })
+        .collect();
+    let columns = [":name", ":mean", ":median", ":p95", ":relative"]
+        .iter()
+        .map(|key| Expr::Symbol((*key).into()))
+        .collect();
+    table::print_table(
+        vector![
+            Expr::List(rows),
+            Expr::Symbol(":columns".into()),
+            Expr::List(columns)
+        ],
+        symbol_table,
+    )?;
+    Ok(Expr::Dict(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::Options;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use crate::symbols::{Expr, SymbolTable};
+
+    fn syms() -> SymbolTable {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.eval_source(
+            "(def calls (atom 0)) (defn work () (do (.reset calls (inc (.deref calls))) (range 10)))",
+        )
+        .unwrap();
+        syms
+    }
+
+    fn get(syms: &SymbolTable, prog: &str) -> Expr {
+        syms.eval_source(prog).unwrap()
+    }
+
+    #[test]
+    fn summaries_have_every_statistic() {
+        let syms = syms();
+        syms.eval_source("(def result (benchmark (dict :warmup 2 :iterations 5) work))")
+            .unwrap();
+        let result = get(&syms, "result").get_dict().unwrap();
+        let stat = |key: &str| result[&Expr::Symbol(key.into())].clone();
+        assert_eq!(stat(":iterations"), Expr::from(5));
+        assert_eq!(stat(":warmup"), Expr::from(2));
+        assert_eq!(get(&syms, "(.deref calls)"), Expr::from(7));
+        let ordered = [":min", ":median", ":p95", ":max", ":total"];
+        for (smaller, larger) in ordered.iter().zip(ordered.iter().skip(1)) {
+            assert!(stat(smaller) <= stat(larger), "{} > {}", smaller, larger);
+        }
+        assert!(stat(":min") <= stat(":mean") && stat(":mean") <= stat(":max"));
+    }
+
+    #[test]
+    fn max_seconds_cuts_iterations_short() {
+        let syms = syms();
+        let result = get(
+            &syms,
+            "(benchmark (dict :iterations 1000 :max-seconds 0) work)",
+        );
+        assert_eq!(
+            result.get_dict().unwrap()[&Expr::Symbol(":iterations".into())],
+            Expr::from(1)
+        );
+        // Out of time before warming up.
+        assert_eq!(get(&syms, "(.deref calls)"), Expr::from(1));
+    }
+
+    #[test]
+    fn comparisons_print_a_table() {
+        let syms = syms();
+        syms.host_mut().capture_output();
+        let results = get(
+            &syms,
+            "(benchmark-compare (dict :b work :a (fn () 1)) (dict :warmup 0 :iterations 3))",
+        );
+        assert_eq!(get(&syms, "(.deref calls)"), Expr::from(3));
+        assert_eq!(results.get_dict().unwrap().len(), 2);
+        let out = syms.host_mut().take_captured_output().0;
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("name | mean"), "{}", out);
+        assert!(lines[2].starts_with(":a"), "{}", out);
+        assert!(lines[3].starts_with(":b"), "{}", out);
+    }
+
+    #[test]
+    fn options_are_checked() {
+        let syms = syms();
+        for prog in &[
+            "(benchmark (dict :iterations 0) work)",
+            "(benchmark (dict :runs 3) work)",
+            "(benchmark (dict :max-seconds -1) work)",
+            "(benchmark 3)",
+            "(benchmark-compare (dict))",
+        ] {
+            assert!(syms.eval_source(prog).is_err(), "{}", prog);
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use crate::symbols::{read_num, Expr, LispResult, Num};
+#[cfg(feature = "regex")]
+use anyhow::anyhow;
+#[cfg(feature = "regex")]
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Default number of entries kept by each interpreter cache.
+pub(crate) const DEFAULT_CACHE_CAPACITY: usize = 1024;
+
+/// Compiled regexes are much bigger than parsed nums, so fewer are kept.
+#[cfg(feature = "regex")]
+pub(crate) const REGEX_CACHE_CAPACITY: usize = 128;
+
+/// A small bounded least-recently-used cache.
+///
+/// Entries are stamped with a monotonically increasing tick on every access,
+/// so the oldest entry is always the first key in `recency`.
+#[derive(Debug, Clone)]
+pub(crate) struct LruCache<K, V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<K, (V, u64)>,
+    recency: BTreeMap<u64, K>,
+    hits: u64,
+    misses: u64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity,
+            tick: 0,
+            entries: HashMap::with_capacity(capacity),
+            recency: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// Look up a key, recording a hit or a miss.
+    pub(crate) fn get(&mut self, key: &K) -> Option<V> {
+        let tick = self.next_tick();
+        match self.entries.get_mut(key) {
+            Some((value, last_used)) => {
+                self.recency.remove(last_used);
+                *last_used = tick;
+                self.recency.insert(tick, key.clone());
+                self.hits += 1;
+                Some(value.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Insert a value, evicting the least recently used entry if full.
+    pub(crate) fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        let tick = self.next_tick();
+        if let Some((_, old_tick)) = self.entries.insert(key.clone(), (value, tick)) {
+            self.recency.remove(&old_tick);
+        }
+        self.recency.insert(tick, key);
+        while self.entries.len() > self.capacity {
+            let oldest = match self.recency.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            if let Some(key) = self.recency.remove(&oldest) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    /// Fetch a cached value, or compute and cache it with `f`.
+    /// Errors are never cached.
+    pub(crate) fn get_or_try_insert_with<F>(&mut self, key: &K, f: F) -> LispResult<V>
+    where
+        F: FnOnce() -> LispResult<V>,
+    {
+        if let Some(value) = self.get(key) {
+            return Ok(value);
+        }
+        let value = f()?;
+        self.insert(key.clone(), value.clone());
+        Ok(value)
+    }
+
+    /// Drop all entries and reset the hit / miss counters.
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+        self.hits = 0;
+        self.misses = 0;
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Summarize this cache as an x7 dict for `cache-stats`.
+    fn stats(&self) -> Expr {
+        let mut dict = im::HashMap::new();
+        dict.insert(Expr::String("hits".into()), Expr::Num(self.hits.into()));
+        dict.insert(Expr::String("misses".into()), Expr::Num(self.misses.into()));
+        dict.insert(
+            Expr::String("size".into()),
+            Expr::Num((self.len() as u64).into()),
+        );
+        dict.insert(
+            Expr::String("capacity".into()),
+            Expr::Num((self.capacity as u64).into()),
+        );
+        Expr::Dict(dict)
+    }
+}
+
+/// Per-interpreter memoization caches used by hot builtins.
+#[derive(Debug, Clone)]
+pub(crate) struct Caches {
+    enabled: bool,
+    num_parse: LruCache<String, Num>,
+    #[cfg(feature = "regex")]
+    regex: LruCache<String, Regex>,
+    // Calls through the inline caches of method call sites, which live in
+    // the parsed code rather than here.
+    method_hits: u64,
+    method_misses: u64,
+}
+
+impl Default for Caches {
+    fn default() -> Self {
+        Caches {
+            enabled: true,
+            num_parse: LruCache::new(DEFAULT_CACHE_CAPACITY),
+            #[cfg(feature = "regex")]
+            regex: LruCache::new(REGEX_CACHE_CAPACITY),
+            method_hits: 0,
+            method_misses: 0,
+        }
+    }
+}
+
+impl Caches {
+    pub(crate) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.clear();
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.num_parse.clear();
+        #[cfg(feature = "regex")]
+        self.regex.clear();
+        self.method_hits = 0;
+        self.method_misses = 0;
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Count a method call which did, or didn't, find its method in the
+    /// call site's inline cache.
+    pub(crate) fn count_method_lookup(&mut self, hit: bool) {
+        if hit {
+            self.method_hits += 1;
+        } else {
+            self.method_misses += 1;
+        }
+    }
+
+    /// Parse a string into a Num, memoizing successful parses.
+    pub(crate) fn parse_num(&mut self, s: &str) -> LispResult<Num> {
+        let parse = || read_num(s.trim());
+        if !self.enabled {
+            return parse();
+        }
+        self.num_parse.get_or_try_insert_with(&s.to_string(), parse)
+    }
+
+    /// Compile a regex, memoizing successful compiles by pattern.
+    #[cfg(feature = "regex")]
+    pub(crate) fn compile_regex(&mut self, pattern: &str) -> LispResult<Regex> {
+        let compile =
+            || Regex::new(pattern).map_err(|e| anyhow!("Invalid regex {:?}, {}", pattern, e));
+        if !self.enabled {
+            return compile();
+        }
+        self.regex
+            .get_or_try_insert_with(&pattern.to_string(), compile)
+    }
+
+    pub(crate) fn stats(&self) -> Expr {
+        let mut dict = im::HashMap::new();
+        dict.insert(Expr::String("enabled".into()), Expr::Bool(self.enabled));
+        dict.insert(Expr::String("num-parse".into()), self.num_parse.stats());
+        #[cfg(feature = "regex")]
+        dict.insert(Expr::String("regex".into()), self.regex.stats());
+        let mut methods = im::HashMap::new();
+        methods.insert(
+            Expr::String("hits".into()),
+            Expr::Num(self.method_hits.into()),
+        );
+        methods.insert(
+            Expr::String("misses".into()),
+            Expr::Num(self.method_misses.into()),
+        );
+        dict.insert(Expr::String("method-calls".into()), Expr::Dict(methods));
+        Expr::Dict(dict)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lru_evicts_least_recently_used() {
+        let mut cache = LruCache::new(2);
+        cache.insert(1, "one");
+        cache.insert(2, "two");
+        assert_eq!(cache.get(&1), Some("one"));
+        cache.insert(3, "three");
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some("one"));
+        assert_eq!(cache.get(&3), Some("three"));
+        assert_eq!((cache.hits, cache.misses), (3, 1));
+    }
+
+    #[test]
+    fn disabled_caches_do_not_record() {
+        let mut caches = Caches::default();
+        caches.set_enabled(false);
+        assert!(caches.parse_num("1.5").is_ok());
+        assert!(caches.parse_num("1.5").is_ok());
+        assert_eq!(caches.num_parse.len(), 0);
+        assert!(caches.parse_num("abc").is_err());
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn regexes_are_compiled_once_per_pattern() {
+        let mut caches = Caches::default();
+        let first = caches.compile_regex("a+b").unwrap();
+        let again = caches.compile_regex("a+b").unwrap();
+        assert_eq!(first.as_str(), again.as_str());
+        assert!(caches.compile_regex("(").is_err());
+        assert!(caches.compile_regex("(").is_err());
+        // Bad patterns are never cached, so they miss every time.
+        assert_eq!((caches.regex.hits, caches.regex.misses), (1, 3));
+        assert_eq!(caches.regex.len(), 1);
+
+        let mut small = LruCache::new(2);
+        for pattern in &["a", "b", "c"] {
+            small.insert(pattern.to_string(), Regex::new(pattern).unwrap());
+        }
+        assert_eq!(small.len(), 2);
+    }
+}
@@ -0,0 +1,174 @@
+use crate::exact_len;
+use crate::symbols::{
+    eval_spread_args, format_args, Dict, Expr, LispResult, ProgramError, SymbolTable,
+};
+use anyhow::{anyhow, bail, Context};
+use im::Vector;
+
+// Calling values other than functions, as in `(handlers :missing)`:
+//
+//   (dict key [default])     looks key up in the dict
+//   (:key dict [default])    looks the keyword up in the dict, or nil
+//   (record args...)         calls the record, if it implements Record::call
+//
+// Anything else is a NotAFunction error naming the value and its type.
+// Arguments are evaluated like a builtin's, spreads included.
+
+/// Call `value`, which isn't a function, with `args`.
+pub(crate) fn call_value(
+    value: &Expr,
+    args: Vector<Expr>,
+    symbol_table: &SymbolTable,
+) -> LispResult<Expr> {
+    if !is_callable(value) {
+        return Err(not_a_function(value, &args));
+    }
+    let args = eval_spread_args(value, args, symbol_table)?;
+    call_value_with_values(value, args, symbol_table)
+}
+
+/// Like `call_value`, with `args` which are already values.
+pub(crate) fn call_value_with_values(
+    value: &Expr,
+    args: Vector<Expr>,
+    symbol_table: &SymbolTable,
+) -> LispResult<Expr> {
+    match value.unmeta() {
+        Expr::Dict(dict) => lookup(dict, &args)
+            .with_context(|| format!("Error in dict call, with args {}", format_args(&args))),
+        Expr::Symbol(keyword) if value.is_keyword() => keyword_lookup(value, &args)
+            .with_context(|| format!("Error in {}, with args {}", keyword, format_args(&args))),
+        Expr::Record(record) => match record.call(args.clone(), symbol_table) {
+            Some(res) => res,
+            None => Err(not_a_function(value, &args)),
+        },
+        _ => Err(not_a_function(value, &args)),
+    }
+}
+
+/// Whether `value` might be callable, so its arguments are worth evaluating.
+fn is_callable(value: &Expr) -> bool {
+    match value.unmeta() {
+        Expr::Dict(_) | Expr::Record(_) => true,
+        _ => value.is_keyword(),
+    }
+}
+
+/// (dict key [default])
+fn lookup(dict: &Dict, args: &Vector<Expr>) -> LispResult<Expr> {
+    exact_len!(args, 1, 2);
+    Ok(dict
+        .get(&args[0])
+        .or_else(|| args.get(1))
+        .cloned()
+        .unwrap_or(Expr::Nil))
+}
+
+/// (:key dict [default])
+fn keyword_lookup(keyword: &Expr, args: &Vector<Expr>) -> LispResult<Expr> {
+    exact_len!(args, 1, 2);
+    match args[0].unmeta() {
+        Expr::Dict(dict) => lookup(dict, &args.clone().update(0, keyword.clone())),
+        Expr::Nil => Ok(args.get(1).cloned().unwrap_or(Expr::Nil)),
+        other => bail!(
+            "A keyword looks itself up in a dict, but was given the {} {:?}",
+            type_name(other),
+            other
+        ),
+    }
+}
+
+fn type_name(value: &Expr) -> &'static str {
+    match value.unmeta() {
+        Expr::Record(record) => record.type_name(),
+        other => other.get_type_str(),
+    }
+}
+
+/// The error for calling `value`, which can't be called, with `args`.
+fn not_a_function(value: &Expr, args: &Vector<Expr>) -> anyhow::Error {
+    anyhow!(ProgramError::NotAFunction(value.clone())).context(format!(
+        "{:?}, of type {}, is not a function, so it can't be called with args {}",
+        value,
+        type_name(value),
+        format_args(args)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::Options;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use crate::symbols::{error_kind, Expr, SymbolTable};
+
+    fn syms() -> SymbolTable {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.eval_source("(def handlers (dict :ok 1 :slow 2))")
+            .unwrap();
+        syms
+    }
+
+    fn error(prog: &str) -> (String, &'static str) {
+        let err = syms().eval_source(prog).unwrap_err();
+        (format!("{:#}", err), error_kind(&err))
+    }
+
+    #[test]
+    fn calling_non_functions_names_the_value() {
+        let (msg, kind) = error("((get handlers :missing) 1)");
+        assert_eq!(kind, "NotAFunction");
+        assert!(
+            msg.contains("nil, of type nil, is not a function"),
+            "{}",
+            msg
+        );
+        assert!(msg.contains("with args (1)"), "{}", msg);
+
+        let (msg, kind) = error("(3 (+ 1 2))");
+        assert_eq!(kind, "NotAFunction");
+        assert!(msg.contains("3, of type num, is not a function"), "{}", msg);
+
+        let (msg, _) = error("(with-location \"app.x7\" 7 3 (nil))");
+        assert!(msg.contains("at app.x7:7:3"), "{}", msg);
+
+        let (msg, kind) = error("((atom 1) 2)");
+        assert_eq!(kind, "NotAFunction");
+        assert!(
+            msg.contains("of type AtomRecord, is not a function"),
+            "{}",
+            msg
+        );
+    }
+
+    #[test]
+    fn dicts_and_keywords_look_up() {
+        let syms = syms();
+        let eval = |prog| syms.eval_source(prog).unwrap();
+        assert_eq!(eval("(handlers :ok)"), Expr::from(1));
+        assert_eq!(eval("(handlers :missing)"), Expr::Nil);
+        assert_eq!(eval("(handlers :missing 0)"), Expr::from(0));
+        assert_eq!(eval("(:slow handlers)"), Expr::from(2));
+        assert_eq!(eval("(:missing handlers 0)"), Expr::from(0));
+        assert_eq!(eval("(:missing nil)"), Expr::Nil);
+        assert_eq!(
+            eval("(map :ok (list handlers (dict :ok 3)))"),
+            eval("(list 1 3)")
+        );
+        assert_eq!(eval("(handlers @(list :ok))"), Expr::from(1));
+        assert!(syms.eval_source("(handlers)").is_err());
+        assert!(syms.eval_source("(:ok 1)").is_err());
+    }
+
+    #[test]
+    fn records_can_be_callable() {
+        let syms = syms();
+        let eval = |prog| syms.eval_source(prog).unwrap();
+        eval("(defrecord Adder (n) (defn call (self x) (+ (.n self) x)))");
+        assert_eq!(eval("((Adder 2) 3)"), Expr::from(5));
+        assert_eq!(eval("(map (Adder 1) (list 1 2))"), eval("(list 2 3)"));
+        #[cfg(feature = "time")]
+        assert_eq!(eval("((rate-limiter 2 1) + 1 2)"), Expr::from(3));
+        let err = syms.eval_source("((Adder 2))").unwrap_err();
+        assert!(format!("{:#}", err).contains("Too few args"), "{:#}", err);
+    }
+}
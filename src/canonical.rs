@@ -0,0 +1,256 @@
+use crate::exact_len;
+use crate::symbols::{normalized_num_string, Expr, LispResult, ProgramError, SymbolTable};
+#[cfg(any(feature = "io", test))]
+use crate::symbols::{read_num, Dict};
+use anyhow::anyhow;
+#[cfg(any(feature = "io", test))]
+use anyhow::{bail, ensure};
+use im::Vector;
+use sha2::{Digest, Sha256};
+
+// A byte encoding of values which depends only on what they are, so it can
+// key caches shared between processes. Equal values encode identically:
+// numbers go through the same normalization as hashing, so 1.50 and 1.5
+// agree, and dict entries are ordered by the encoding of their keys rather
+// than by where they sit in the map. Every value starts with a tag, and
+// strings and collections with their length, so no encoding is a prefix of
+// another and unequal values never share bytes.
+
+const NIL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const NUM: u8 = 3;
+const STRING: u8 = 4;
+const SYMBOL: u8 = 5;
+const LIST: u8 = 6;
+const QUOTE: u8 = 7;
+const TUPLE: u8 = 8;
+const DICT: u8 = 9;
+
+fn push_len(len: usize, out: &mut Vec<u8>) {
+    out.extend_from_slice(&(len as u64).to_be_bytes());
+}
+
+fn push_str(tag: u8, s: &str, out: &mut Vec<u8>) {
+    out.push(tag);
+    push_len(s.len(), out);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn encode(expr: &Expr, out: &mut Vec<u8>) -> LispResult<()> {
+    match expr {
+        Expr::Nil => out.push(NIL),
+        Expr::Bool(false) => out.push(FALSE),
+        Expr::Bool(true) => out.push(TRUE),
+        Expr::Num(n) => push_str(NUM, &normalized_num_string(n), out),
+        Expr::String(s) => push_str(STRING, s, out),
+        Expr::Symbol(s) => push_str(SYMBOL, s, out),
+        Expr::List(l) | Expr::Quote(l) | Expr::Tuple(l) => {
+            out.push(match expr {
+                Expr::List(_) => LIST,
+                Expr::Quote(_) => QUOTE,
+                _ => TUPLE,
+            });
+            push_len(l.len(), out);
+            for item in l.iter() {
+                encode(item, out)?;
+            }
+        }
+        Expr::Dict(d) => {
+            let mut entries = d
+                .iter()
+                .map(|(k, v)| Ok((k.canonical_bytes()?, v)))
+                .collect::<LispResult<Vec<_>>>()?;
+            entries.sort_by(|(l, _), (r, _)| l.cmp(r));
+            out.push(DICT);
+            push_len(entries.len(), out);
+            for (key, value) in entries {
+                out.extend_from_slice(&key);
+                encode(value, out)?;
+            }
+        }
+        Expr::WithMeta(inner, _) => encode(inner, out)?,
+        Expr::Values(_) => encode(expr.unmeta(), out)?,
+        Expr::Function(_) | Expr::LazyIter(_) | Expr::Record(_) => {
+            return Err(anyhow!(ProgramError::BadTypes).context(format!(
+                "{:?} can't be canonically encoded, as a {} is only equal to itself",
+                expr,
+                expr.get_type_str()
+            )))
+        }
+    }
+    Ok(())
+}
+
+/// Reads values back from their encoding, for stores like kv-open.
+#[cfg(any(feature = "io", test))]
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+#[cfg(any(feature = "io", test))]
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> LispResult<&'a [u8]> {
+        ensure!(
+            self.bytes.len() - self.at >= n,
+            "The canonical encoding ends in the middle of a value"
+        );
+        self.at += n;
+        Ok(&self.bytes[self.at - n..self.at])
+    }
+
+    fn len(&mut self) -> LispResult<usize> {
+        let mut len = [0; 8];
+        len.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(len) as usize)
+    }
+
+    fn string(&mut self) -> LispResult<String> {
+        let len = self.len()?;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| anyhow!("The canonical encoding has a string which isn't UTF-8"))
+    }
+
+    fn items(&mut self) -> LispResult<Vector<Expr>> {
+        let len = self.len()?;
+        (0..len).map(|_| self.expr()).collect()
+    }
+
+    fn expr(&mut self) -> LispResult<Expr> {
+        Ok(match self.take(1)?[0] {
+            NIL => Expr::Nil,
+            FALSE => Expr::Bool(false),
+            TRUE => Expr::Bool(true),
+            NUM => Expr::Num(read_num(&self.string()?)?),
+            STRING => Expr::String(self.string()?),
+            SYMBOL => Expr::Symbol(self.string()?),
+            LIST => Expr::List(self.items()?),
+            QUOTE => Expr::Quote(self.items()?),
+            TUPLE => Expr::Tuple(self.items()?),
+            DICT => {
+                let len = self.len()?;
+                let mut dict = Dict::new();
+                for _ in 0..len {
+                    let key = self.expr()?;
+                    dict.insert(key, self.expr()?);
+                }
+                Expr::Dict(dict)
+            }
+            tag => bail!("The canonical encoding has an unknown tag {}", tag),
+        })
+    }
+}
+
+/// The value `bytes` is the canonical encoding of.
+#[cfg(any(feature = "io", test))]
+pub(crate) fn decode(bytes: &[u8]) -> LispResult<Expr> {
+    let mut decoder = Decoder { bytes, at: 0 };
+    let expr = decoder.expr()?;
+    ensure!(
+        decoder.at == bytes.len(),
+        "The canonical encoding has {} bytes after its value",
+        bytes.len() - decoder.at
+    );
+    Ok(expr)
+}
+
+impl Expr {
+    /// A deterministic encoding of this value, the same for all values equal
+    /// to it and in every process. Fails for values with identity rather
+    /// than contents, like functions, lazy sequences, and records.
+    pub fn canonical_bytes(&self) -> LispResult<Vec<u8>> {
+        let mut out = Vec::new();
+        encode(self, &mut out)?;
+        Ok(out)
+    }
+}
+
+/// (canonical-hash x)
+pub(crate) fn canonical_hash(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let bytes = exprs[0].canonical_bytes()?;
+    Ok(Expr::String(format!("{:x}", Sha256::digest(&bytes))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Options;
+    use crate::stdlib::create_stdlib_symbol_table;
+
+    #[test]
+    fn equal_values_encode_alike() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let bytes = |prog: &str| syms.eval_source(prog).unwrap().canonical_bytes();
+        for (l, r) in &[
+            ("1.50", "1.5"),
+            ("(/ 4 4)", "1"),
+            ("(- 0 0.0)", "0"),
+            (
+                "(dict :a 1 :b '(1 2.0))",
+                "(assoc (dict :b (list 1 2)) :a 1.0)",
+            ),
+            ("(dict (dict 1 2) :x)", "(dict (dict 1.0 2.00) :x)"),
+        ] {
+            assert_eq!(bytes(l).unwrap(), bytes(r).unwrap(), "{} vs {}", l, r);
+        }
+        for (l, r) in &[
+            ("'(1 2)", "^(1 2)"),
+            ("\"a\"", "'a"),
+            ("(list \"ab\" \"c\")", "(list \"a\" \"bc\")"),
+            ("nil", "false"),
+            ("(dict :a 1)", "(dict :a \"1\")"),
+        ] {
+            assert_ne!(bytes(l).unwrap(), bytes(r).unwrap(), "{} vs {}", l, r);
+        }
+    }
+
+    #[test]
+    fn decoding_reads_values_back() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        for prog in &[
+            "nil",
+            "(list 1.5 -2 \"héllo\" :kw true false)",
+            "(dict :a '(1 2) (dict 1 2) (tuple 3 4))",
+        ] {
+            let value = syms.eval_source(prog).unwrap();
+            let bytes = value.canonical_bytes().unwrap();
+            assert_eq!(decode(&bytes).unwrap(), value, "{}", prog);
+            assert!(decode(&bytes[..bytes.len() - 1]).is_err(), "{}", prog);
+        }
+        assert!(decode(&[NIL, NIL]).is_err());
+        assert!(decode(&[42]).is_err());
+    }
+
+    #[test]
+    fn hashes_are_stable() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        // Fixed here so changes to the encoding, which invalidate caches
+        // written by older versions, don't go unnoticed.
+        assert_eq!(
+            Expr::from(1).canonical_bytes().unwrap(),
+            vec![3, 0, 0, 0, 0, 0, 0, 0, 1, b'1']
+        );
+        assert_eq!(
+            syms.eval_source("(canonical-hash (dict :b 2 :a 1))")
+                .unwrap(),
+            syms.eval_source("(canonical-hash (dict :a 1.0 :b 2))")
+                .unwrap()
+        );
+        for prog in &[
+            "(canonical-hash (fn (x) x))",
+            "(canonical-hash (list 1 (atom 2)))",
+            "(canonical-hash (dict :n (range)))",
+        ] {
+            let err = syms.eval_source(prog).unwrap_err();
+            let err = format!("{:#}", err);
+            assert!(
+                err.contains("can't be canonically encoded"),
+                "{}: {}",
+                prog,
+                err
+            );
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use crate::exact_len;
+use crate::symbols::{Expr, LispResult, SymbolTable};
+use anyhow::{anyhow, bail, ensure};
+use im::Vector;
+
+// Character classification for tokenizers written in x7. x7 has no char
+// type, so characters are one-character strings. The predicates follow
+// Rust's Unicode-aware char methods, so (digit? "٣") is true, while
+// char->digit and digit->char only know ASCII digits and letters.
+
+/// The character `expr` holds, which must be a string of exactly one.
+fn get_char(what: &str, expr: &Expr) -> LispResult<char> {
+    let s = expr.get_string()?;
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => bail!(
+            "{} expects a single character, but was given a string of length {}: {:?}",
+            what,
+            s.chars().count(),
+            s
+        ),
+    }
+}
+
+fn get_radix(what: &str, exprs: &Vector<Expr>) -> LispResult<u32> {
+    let radix = match exprs.get(1) {
+        Some(radix) => radix.get_usize()?,
+        None => 10,
+    };
+    ensure!(
+        (2..=36).contains(&radix),
+        "{} expects a radix from 2 to 36, but was given {}",
+        what,
+        radix
+    );
+    Ok(radix as u32)
+}
+
+macro_rules! char_predicates {
+    ($(($func:ident, $name:literal, $method:ident)),*) => {
+        $(
+            pub(crate) fn $func(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+                exact_len!(exprs, 1);
+                Ok(Expr::Bool(get_char($name, &exprs[0])?.$method()))
+            }
+        )*
+    };
+}
+
+char_predicates!(
+    (is_digit, "digit?", is_numeric),
+    (is_alpha, "alpha?", is_alphabetic),
+    (is_alphanumeric, "alphanumeric?", is_alphanumeric),
+    (is_whitespace, "whitespace?", is_whitespace),
+    (is_upper, "upper?", is_uppercase),
+    (is_lower, "lower?", is_lowercase)
+);
+
+/// (char->digit c [radix])
+pub(crate) fn char_to_digit(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1, 2);
+    let c = get_char("char->digit", &exprs[0])?;
+    let radix = get_radix("char->digit", &exprs)?;
+    Ok(match c.to_digit(radix) {
+        Some(digit) => Expr::from(digit as i64),
+        None => Expr::Nil,
+    })
+}
+
+/// (digit->char n [radix])
+pub(crate) fn digit_to_char(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1, 2);
+    let radix = get_radix("digit->char", &exprs)?;
+    let digit = exprs[0].get_usize()?;
+    let c = std::char::from_digit(digit as u32, radix)
+        .filter(|_| digit < radix as usize)
+        .ok_or_else(|| {
+            anyhow!(
+                "digit->char expects a digit below the radix {}, but was given {}",
+                radix,
+                digit
+            )
+        })?;
+    Ok(Expr::String(c.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::Options;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use crate::symbols::Expr;
+
+    const PREDICATES: [&str; 6] = [
+        "digit?",
+        "alpha?",
+        "alphanumeric?",
+        "whitespace?",
+        "upper?",
+        "lower?",
+    ];
+
+    #[test]
+    fn classification_matrix() {
+        // The expected result of each predicate, in the order of PREDICATES.
+        let matrix: &[(&str, [bool; 6])] = &[
+            ("a", [false, true, true, false, false, true]),
+            ("Z", [false, true, true, false, true, false]),
+            ("7", [true, false, true, false, false, false]),
+            ("_", [false, false, false, false, false, false]),
+            (" ", [false, false, false, true, false, false]),
+            ("é", [false, true, true, false, false, true]),
+            ("É", [false, true, true, false, true, false]),
+            ("ß", [false, true, true, false, false, true]),
+            ("東", [false, true, true, false, false, false]),
+            // Arabic-Indic three and Devanagari five.
+            ("٣", [true, false, true, false, false, false]),
+            ("५", [true, false, true, false, false, false]),
+            // The ideographic space.
+            ("\u{3000}", [false, false, false, true, false, false]),
+            ("😀", [false, false, false, false, false, false]),
+        ];
+        let syms = create_stdlib_symbol_table(&Options::default());
+        for (c, expected) in matrix.iter() {
+            for (predicate, expected) in PREDICATES.iter().zip(expected.iter()) {
+                let prog = format!("({} \"{}\")", predicate, c);
+                assert_eq!(
+                    syms.eval_source(&prog).unwrap(),
+                    Expr::Bool(*expected),
+                    "{}",
+                    prog
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn only_single_characters() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        // A flag is two code points.
+        for (arg, len) in &[("\"ab\"", 2), ("\"\"", 0), ("\"🇨🇦\"", 2)] {
+            for predicate in PREDICATES.iter() {
+                let prog = format!("({} {})", predicate, arg);
+                let err = syms.eval_source(&prog).unwrap_err();
+                let expected = format!(
+                    "{} expects a single character, but was given a string of length {}",
+                    predicate, len
+                );
+                assert!(
+                    err.root_cause().to_string().starts_with(&expected),
+                    "{}: {}",
+                    prog,
+                    err.root_cause()
+                );
+            }
+        }
+        assert!(syms.eval_source("(digit? 1)").is_err());
+    }
+
+    #[test]
+    fn digits_in_radixes() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let eval = |prog: &str| syms.eval_source(prog).unwrap();
+        assert_eq!(eval("(char->digit \"7\")"), Expr::from(7));
+        assert_eq!(eval("(char->digit \"F\" 16)"), Expr::from(15));
+        assert_eq!(eval("(char->digit \"z\" 36)"), Expr::from(35));
+        assert_eq!(eval("(char->digit \"8\" 8)"), Expr::Nil);
+        assert_eq!(eval("(char->digit \"٣\")"), Expr::Nil);
+        assert_eq!(eval("(digit->char 7)"), Expr::String("7".into()));
+        assert_eq!(eval("(digit->char 11 16)"), Expr::String("b".into()));
+        assert!(syms.eval_source("(digit->char 10)").is_err());
+        assert!(syms.eval_source("(digit->char 1 37)").is_err());
+        assert!(syms.eval_source("(char->digit \"1\" 1)").is_err());
+    }
+}
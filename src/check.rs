@@ -0,0 +1,260 @@
+use crate::docgen::source_files;
+use crate::lexer::{lex, Token, TokenKind};
+use crate::parser::read;
+use crate::symbols::LispResult;
+use std::fmt;
+use std::fs;
+
+// `x7 check <paths>` reads x7 files without evaluating them, reporting every
+// syntax error rather than stopping at the first.
+//
+// The parser gives up at its first error, so files are read with the lexer,
+// which never does. Tokens are grouped into top level forms by matching
+// parens, and each complete form without broken tokens is then handed to
+// the parser. After an error, checking picks up again at the next form.
+
+/// How bad a diagnostic is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    fn as_str(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+/// A problem found in a file, at a 1-based line and column (in chars).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub path: String,
+    pub line: usize,
+    pub col: usize,
+    pub message: String,
+    pub severity: Severity,
+}
+
+impl fmt::Display for Diagnostic {
+    /// Like gcc, `path:line:col: error: message`, which editors can jump to.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}: {}",
+            self.path,
+            self.line,
+            self.col,
+            self.severity.as_str(),
+            self.message
+        )
+    }
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl Diagnostic {
+    /// `{"path": ..., "line": ..., "col": ..., "message": ..., "severity": ...}`
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"path\": {}, \"line\": {}, \"col\": {}, \"message\": {}, \"severity\": {}}}",
+            json_string(&self.path),
+            self.line,
+            self.col,
+            json_string(&self.message),
+            json_string(self.severity.as_str())
+        )
+    }
+}
+
+/// `diagnostics` as a JSON array, one per line.
+pub fn diagnostics_json(diagnostics: &[Diagnostic]) -> String {
+    if diagnostics.is_empty() {
+        return "[]".into();
+    }
+    let items: Vec<String> = diagnostics
+        .iter()
+        .map(|d| format!("  {}", d.to_json()))
+        .collect();
+    format!("[\n{}\n]", items.join(",\n"))
+}
+
+fn broken_token(src: &str, token: &Token) -> String {
+    let text = token.text(src);
+    if text.starts_with('"') {
+        "unterminated string".into()
+    } else if text.starts_with('#') {
+        "unterminated tagged literal".into()
+    } else if text.len() > 1 {
+        format!("can't read `{}`, it starts like a number", text)
+    } else {
+        format!("unexpected character `{}`", text)
+    }
+}
+
+/// Every syntax error in `src`, in order, as (line, col, message).
+fn syntax_errors(src: &str) -> Vec<(usize, usize, String)> {
+    let mut errors = Vec::new();
+    let tokens: Vec<Token> = lex(src)
+        .into_iter()
+        .filter(|t| !matches!(t.kind, TokenKind::Whitespace | TokenKind::Comment))
+        .collect();
+    // The first token of the top level form being read, and its open parens.
+    let mut start: Option<&Token> = None;
+    let mut open: Vec<&Token> = Vec::new();
+    let mut broken = false;
+    let mut at = |t: &Token, message: String| errors.push((t.line, t.col, message));
+    for token in tokens.iter() {
+        match token.kind {
+            TokenKind::CloseParen if open.is_empty() => {
+                at(token, "unexpected `)`, there's no `(` to close".into());
+                continue;
+            }
+            TokenKind::CloseParen => {
+                open.pop();
+            }
+            TokenKind::OpenParen => open.push(token),
+            TokenKind::Error => {
+                at(token, broken_token(src, token));
+                broken = true;
+            }
+            _ => {}
+        }
+        let start_token = *start.get_or_insert(token);
+        let prefix = matches!(
+            token.kind,
+            TokenKind::QuoteMarker
+                | TokenKind::TupleMarker
+                | TokenKind::SpreadMarker
+                | TokenKind::UnquoteMarker
+        );
+        if !open.is_empty() || prefix {
+            continue;
+        }
+        // A whole top level form.
+        let text = &src[start_token.range.start..token.range.end];
+        // Tagged literals need an interpreter to read, so they're left be.
+        let forms = read(text).keeping_reader_tags();
+        if !broken && forms.collect::<LispResult<Vec<_>>>().is_err() {
+            at(start_token, "can't read this form".into());
+        }
+        start = None;
+        broken = false;
+    }
+    // An unterminated string runs to the end, so parens it swallowed are
+    // already accounted for.
+    if !broken {
+        for token in open {
+            at(token, "this `(` is never closed".into());
+        }
+        if let Some(marker) = start.filter(|t| t.kind != TokenKind::OpenParen) {
+            at(
+                marker,
+                format!("`{}` isn't followed by anything", marker.text(src)),
+            );
+        }
+    }
+    errors
+}
+
+/// Every syntax error in `src`, which came from `path`.
+pub fn check_source(path: &str, src: &str) -> Vec<Diagnostic> {
+    syntax_errors(src)
+        .into_iter()
+        .map(|(line, col, message)| Diagnostic {
+            path: path.into(),
+            line,
+            col,
+            message,
+            severity: Severity::Error,
+        })
+        .collect()
+}
+
+/// Every syntax error in the files under `paths`. Directories are searched
+/// for `.x7` files, and glob patterns are expanded, for shells which don't.
+pub fn check_paths(paths: &[String]) -> LispResult<Vec<Diagnostic>> {
+    let mut diagnostics = Vec::new();
+    for file in source_files(paths)? {
+        match fs::read_to_string(&file) {
+            Ok(src) => diagnostics.extend(check_source(&file, &src)),
+            Err(e) => diagnostics.push(Diagnostic {
+                path: file,
+                line: 1,
+                col: 1,
+                message: format!("can't read the file, {}", e),
+                severity: Severity::Error,
+            }),
+        }
+    }
+    Ok(diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(src: &str) -> Vec<String> {
+        check_source("f.x7", src)
+            .iter()
+            .map(|d| d.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn clean_sources_have_no_diagnostics() {
+        assert!(errors("(defn f (x) ; doc\n  ^(x '(1 2) @xs))\n\n1 \"s\" :k").is_empty());
+        assert!(errors("").is_empty());
+        assert!(errors("(def timeout #duration\"5m\")").is_empty());
+    }
+
+    #[test]
+    fn every_error_is_reported() {
+        assert_eq!(
+            errors("(f 1e)\n)\n(g (h)\n"),
+            [
+                "f.x7:1:4: error: can't read `1e`, it starts like a number",
+                "f.x7:2:1: error: unexpected `)`, there's no `(` to close",
+                "f.x7:3:1: error: this `(` is never closed",
+            ]
+        );
+        assert_eq!(
+            errors("(ok)\n  (f \"oops\n(g)"),
+            ["f.x7:2:6: error: unterminated string"]
+        );
+        assert_eq!(
+            errors("(def t #duration\"5m)"),
+            ["f.x7:1:8: error: unterminated tagged literal"]
+        );
+        assert_eq!(
+            errors("(ok) '"),
+            ["f.x7:1:6: error: `'` isn't followed by anything"]
+        );
+    }
+
+    #[test]
+    fn json_diagnostics() {
+        let diagnostics = check_source("dir/a \"b\".x7", "(f");
+        assert_eq!(
+            diagnostics_json(&diagnostics),
+            "[\n  {\"path\": \"dir/a \\\"b\\\".x7\", \"line\": 1, \"col\": 1, \"message\": \"this `(` is never closed\", \"severity\": \"error\"}\n]"
+        );
+        assert_eq!(diagnostics_json(&[]), "[]");
+    }
+}
@@ -1,19 +1,217 @@
 // use crate::repl::read;
 use crate::parser::read;
-use crate::symbols::SymbolTable;
+use crate::symbols::{Expr, ProgramError, SymbolTable};
 use rustyline::error::ReadlineError;
 use rustyline::{Config, Editor};
+use std::sync::atomic::Ordering;
+use std::time::Instant;
 use structopt::StructOpt;
 
-#[derive(Debug, StructOpt)]
+#[derive(Debug, Default, StructOpt)]
 #[structopt(name = "x7", about = "x7 Programming Language")]
 pub struct Options {
     #[structopt(short = "l", long)]
     pub show_loading_stdlib: bool,
+    /// Disable the interpreter's memoization caches.
+    #[structopt(long)]
+    pub no_caches: bool,
+    /// Elide REPL results whose printed form exceeds this many KB. 0 disables eliding.
+    #[structopt(long, default_value = "64")]
+    pub max_output_kb: usize,
+    /// Treat warnings as errors.
+    #[structopt(long)]
+    pub deny_warnings: bool,
+    /// Make calling a builtin by a deprecated name an error.
+    #[structopt(long)]
+    pub deny_deprecated: bool,
+    /// Make sequence builtins like `head`, `len`, and `map` error on nil
+    /// instead of treating it as an empty list.
+    #[structopt(long)]
+    pub strict_nil: bool,
+    /// Resolve calls to builtins ahead of time, making redefining a builtin an error.
+    #[structopt(long)]
+    pub frozen_globals: bool,
+    /// Make builtins like `repeat` and `str` error, rather than build values
+    /// bigger than about this many bytes.
+    #[structopt(long)]
+    pub max_value_bytes: Option<usize>,
     pub files: Vec<String>,
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Check x7 files for syntax errors without running them, exiting
+    /// nonzero if any are found. Directories are searched for `.x7` files.
+    Check {
+        /// `human` for gcc style `path:line:col: error: message` lines, or `json`.
+        #[structopt(long, default_value = "human", possible_values = &["human", "json"])]
+        format: String,
+        /// Files and directories to check; at least one is required.
+        #[structopt(required = true)]
+        paths: Vec<String>,
+    },
+    /// Look for likely mistakes, like an `if` without an else branch, exiting
+    /// nonzero if any are found. Directories are searched for `.x7` files.
+    /// A `;#allow(lint-name)` comment turns a lint off for the next form.
+    Lint {
+        /// `human` for gcc style lines, each followed by any suggested fix, or `json`.
+        #[structopt(long, default_value = "human", possible_values = &["human", "json"])]
+        format: String,
+        /// Apply the suggested fixes which can't change what the code does.
+        #[structopt(long)]
+        fix: bool,
+        /// Files and directories to lint; at least one is required.
+        #[structopt(required = true)]
+        paths: Vec<String>,
+    },
+    /// Print Markdown docs for the definitions in x7 files, grouped by file.
+    /// Directories are searched for `.x7` files.
+    Docgen {
+        /// Document the builtins instead, grouped by category.
+        #[structopt(long)]
+        builtins: bool,
+        /// Files and directories to document; required unless `--builtins` is given.
+        #[structopt(required_unless = "builtins")]
+        paths: Vec<String>,
+    },
+    /// Attach a REPL to a program serving one with `SymbolTable::serve_repl`, at a
+    /// localhost address like `127.0.0.1:7007`.
+    #[cfg(feature = "remote-repl")]
+    Attach {
+        /// The token the server printed. Read from X7_REPL_TOKEN if not given.
+        #[structopt(long, env = "X7_REPL_TOKEN", hide_env_values = true)]
+        token: String,
+        addr: String,
+    },
+}
+
+const ELIDED_HINT: &str = "use (pprint *1) to see all";
+
+/// Print `expr` like the REPL does, eliding whatever would take its printed
+/// form past `max_bytes`: the rest of long lists and dicts, and the end of
+/// long strings. The budget is checked as each element is written, so
+/// elided parts are never printed and this stays cheap for huge values.
+/// 0 disables eliding.
+pub(crate) fn bounded_repr(expr: &Expr, max_bytes: usize) -> String {
+    if max_bytes == 0 {
+        return format!("{:?}", expr);
+    }
+    write_bounded(expr, max_bytes).0
+}
+
+/// `expr` printed in about `budget` bytes, and whether any of it was elided.
+fn write_bounded(expr: &Expr, budget: usize) -> (String, bool) {
+    let (open, items, close) = match expr.unmeta() {
+        Expr::List(l) => ("(", l, ")"),
+        Expr::Tuple(l) => ("(tuple ", l, ")"),
+        Expr::Quote(l) => ("'(", l, ")"),
+        Expr::String(s) => return write_bounded_string(s, budget),
+        Expr::Dict(d) => {
+            let write_entry = |(k, v): (&Expr, &Expr), budget: usize| {
+                let (key, key_elided) = write_bounded(k, budget);
+                let (value, value_elided) = write_bounded(v, budget.saturating_sub(key.len() + 2));
+                (format!("{}: {}", key, value), key_elided || value_elided)
+            };
+            let parts = d.iter();
+            return write_elided(
+                ("{", "}"),
+                ", ",
+                "entries",
+                d.len(),
+                parts,
+                write_entry,
+                budget,
+            );
+        }
+        other => return (format!("{:?}", other), false),
+    };
+    write_elided(
+        (open, close),
+        " ",
+        "elements",
+        items.len(),
+        items.iter(),
+        write_bounded,
+        budget,
+    )
+}
+
+/// Write as many of `parts` between `open` and `close` as fit in `budget`,
+/// noting how many more there are. A part which doesn't fit is still
+/// written if it elided itself to fit what was left.
+fn write_elided<I>(
+    (open, close): (&str, &str),
+    sep: &str,
+    noun: &str,
+    total: usize,
+    parts: impl Iterator<Item = I>,
+    write_part: impl Fn(I, usize) -> (String, bool),
+    budget: usize,
+) -> (String, bool) {
+    let mut out = String::from(open);
+    let mut len = open.len() + close.len();
+    let mut shown = 0;
+    let mut elided = false;
+    for part in parts {
+        let sep_len = if shown == 0 { 0 } else { sep.len() };
+        let left = budget.saturating_sub(len + sep_len);
+        let (repr, part_elided) = write_part(part, left);
+        let fits = len + sep_len + repr.len() <= budget;
+        if !(fits || part_elided && left > 0) {
+            break;
+        }
+        if shown > 0 {
+            out.push_str(sep);
+        }
+        out.push_str(&repr);
+        len += sep_len + repr.len();
+        shown += 1;
+        elided |= part_elided;
+        if !fits {
+            break;
+        }
+    }
+    let hidden = total - shown;
+    if hidden > 0 {
+        out.push_str(&format!(" ... ({} more {}, {})", hidden, noun, ELIDED_HINT));
+    }
+    out.push_str(close);
+    (out, elided || hidden > 0)
+}
+
+fn write_bounded_string(s: &str, budget: usize) -> (String, bool) {
+    // The quotes count too.
+    if s.len() + 2 <= budget {
+        return (format!("\"{}\"", s), false);
+    }
+    let mut end = budget.saturating_sub(2).min(s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    let repr = format!(
+        "\"{}\"... ({} more bytes, {})",
+        &s[..end],
+        s.len() - end,
+        ELIDED_HINT
+    );
+    (repr, true)
+}
+
+fn is_interrupted(err: &anyhow::Error) -> bool {
+    err.chain()
+        .any(|e| e.downcast_ref::<ProgramError>() == Some(&ProgramError::Interrupted))
 }
 
-pub fn read_cli(sym_table: &SymbolTable) {
+pub fn read_cli(sym_table: &SymbolTable, opts: &Options) {
+    // Ctrl-C at the prompt is handled by rustyline, so this only
+    // fires while evaluating.
+    let interrupt = sym_table.interrupt_handle();
+    if let Err(e) = ctrlc::set_handler(move || interrupt.store(true, Ordering::SeqCst)) {
+        println!("Could not install Ctrl-C handler, {}", e);
+    }
+    let max_output_bytes = opts.max_output_kb * 1024;
     let conf = Config::builder().auto_add_history(true).build();
     // TODO: Auto-complete
     let mut rl = Editor::<()>::with_config(conf);
@@ -25,10 +223,15 @@ pub fn read_cli(sym_table: &SymbolTable) {
         let readline = rl.readline(">>> ");
         match readline {
             Ok(line) => {
-                if line == "" {
+                if line.is_empty() {
                     continue;
                 }
-                for expr in read(line.as_str()) {
+                // `:inspect expr` prints a report on each value instead.
+                let (source, inspecting) = match line.trim_start().strip_prefix(":inspect ") {
+                    Some(rest) => (rest, true),
+                    None => (line.as_str(), false),
+                };
+                for expr in read(source).with_reader_tags(sym_table) {
                     let prog = match expr {
                         Ok(prog) => prog,
                         Err(e) => {
@@ -36,10 +239,25 @@ pub fn read_cli(sym_table: &SymbolTable) {
                             continue;
                         }
                     };
-                    match prog.eval(sym_table) {
-                        Ok(p) => println!("{:?}", p),
+                    sym_table.reset_interrupt();
+                    sym_table.host_mut().reset_built_bytes();
+                    let start = Instant::now();
+                    match sym_table.eval_form(&prog) {
+                        Ok(p) if inspecting => {
+                            print!("{}", crate::inspect::inspect(&p, sym_table));
+                            let _ = sym_table.add_local(&Expr::Symbol("*1".into()), &p);
+                        }
+                        Ok(p) => {
+                            println!("{}", bounded_repr(&p, max_output_bytes));
+                            // Can't fail, *1 is a symbol.
+                            let _ = sym_table.add_local(&Expr::Symbol("*1".into()), &p);
+                        }
+                        Err(e) if is_interrupted(&e) => {
+                            println!("Interrupted after {:.2}s", start.elapsed().as_secs_f64());
+                            break;
+                        }
                         Err(e) => {
-                            report_error(&e);
+                            report_uncaught(&e, sym_table);
                             continue;
                         }
                     }
@@ -62,17 +280,78 @@ pub fn read_cli(sym_table: &SymbolTable) {
     rl.save_history("history.txt").unwrap();
 }
 
-pub fn report_error(err: &anyhow::Error) {
-    let first = err.chain().last().unwrap();
-    println!("Error: {}\n", first);
+/// The report printed for an error which reached the top level. Thrown
+/// values are rendered by `symbol_table`, if given.
+pub(crate) fn format_error(err: &anyhow::Error, symbol_table: Option<&SymbolTable>) -> String {
+    let mut out = format!("Error: {}\n\n", crate::throw::headline(err, symbol_table));
 
     let mut print_stackstace = true;
 
     for e in err.chain().rev().skip(1) {
         if print_stackstace {
-            println!("Stacktrace:");
+            out.push_str("Stacktrace:\n");
         }
         print_stackstace = false;
-        println!("  - {}", e)
+        out.push_str(&format!("  - {}\n", e));
+    }
+    out
+}
+
+pub fn report_error(err: &anyhow::Error) {
+    print!("{}", format_error(err, None));
+}
+
+/// Like `report_error`, rendering thrown values like `symbol_table` says to.
+pub fn report_uncaught(err: &anyhow::Error, symbol_table: &SymbolTable) {
+    print!("{}", format_error(err, Some(symbol_table)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::num;
+
+    #[test]
+    fn bounded_repr_elides_large_lists() {
+        let big = Expr::List((0..10_000).map(|n| num!(n)).collect());
+        let repr = bounded_repr(&big, 20);
+        assert_eq!(
+            repr,
+            "(0 1 2 3 4 5 6 7 8 ... (9991 more elements, use (pprint *1) to see all))"
+        );
+        let small = Expr::List((0..3).map(|n| num!(n)).collect());
+        assert_eq!(bounded_repr(&small, 20), "(0 1 2)");
+        assert_eq!(bounded_repr(&big, 0), format!("{:?}", big));
+    }
+
+    #[test]
+    fn bounded_repr_elides_strings_dicts_and_nested_values() {
+        let long = Expr::String("x".repeat(1 << 20));
+        assert_eq!(
+            bounded_repr(&long, 8),
+            "\"xxxxxx\"... (1048570 more bytes, use (pprint *1) to see all)"
+        );
+        assert_eq!(bounded_repr(&Expr::from("short"), 8), "\"short\"");
+
+        let dict: crate::symbols::Dict = (0..10_000).map(|n| (num!(n), num!(n))).collect();
+        let repr = bounded_repr(&Expr::Dict(dict), 30);
+        assert!(
+            repr.starts_with('{') && repr.ends_with("to see all)}"),
+            "{}",
+            repr
+        );
+        assert!(repr.contains(" more entries, "), "{}", repr);
+        assert!(repr.len() < 100, "{}", repr);
+        let small: crate::symbols::Dict = vec![(num!(1), num!(2))].into_iter().collect();
+        let small = Expr::Dict(small);
+        assert_eq!(bounded_repr(&small, 30), format!("{:?}", small));
+
+        // Nested values are bounded by what's left, rather than printed whole.
+        let big = Expr::List((0..10_000).map(|n| num!(n)).collect());
+        let nested = Expr::List(im::vector![num!(1), big, long]);
+        assert_eq!(
+            bounded_repr(&nested, 20),
+            "(1 (0 1 2 3 4 5 6 ... (9993 more elements, use (pprint *1) to see all)) ... (1 more elements, use (pprint *1) to see all))"
+        );
     }
 }
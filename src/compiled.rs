@@ -0,0 +1,168 @@
+use crate::parser::read_with_comments;
+use crate::symbols::{Expr, LispResult, SymbolTable};
+use anyhow::ensure;
+use std::sync::Arc;
+
+// Evaluating the same expression against many rows of data spends most of
+// its time parsing, when done with `eval_with_bindings`. A `CompiledExpr`
+// keeps the parsed (and, with frozen globals, resolved) forms, so each
+// evaluation only binds the row and evaluates.
+//
+// The forms are plain `Expr`s behind an `Arc`, so a `CompiledExpr` is cheap
+// to clone and can be sent to other threads. Interpreters can't, so each
+// worker thread evaluates it with its own.
+
+/// Source parsed once, from `SymbolTable::compile`, to evaluate many times.
+#[derive(Debug, Clone)]
+pub struct CompiledExpr {
+    source: Arc<str>,
+    forms: Arc<[Expr]>,
+    // Whether builtins were resolved into the forms, which bindings then
+    // can't shadow.
+    resolved: bool,
+}
+
+pub(crate) fn compile(source: &str, symbol_table: &SymbolTable) -> LispResult<CompiledExpr> {
+    symbol_table.check_program(source)?;
+    let resolved = symbol_table.host().is_frozen_globals();
+    let mut forms = Vec::new();
+    for form in read_with_comments(source).with_reader_tags(symbol_table) {
+        let form = form?;
+        forms.push(if resolved {
+            crate::resolve::resolve(&form, symbol_table)?
+        } else {
+            form
+        });
+    }
+    Ok(CompiledExpr {
+        source: source.into(),
+        forms: forms.into(),
+        resolved,
+    })
+}
+
+impl CompiledExpr {
+    /// The source this was compiled from.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Evaluate every form with `bindings` bound, returning the value of the
+    /// last one. Like `SymbolTable::eval_with_bindings`, the bindings, and
+    /// anything the forms `def`, are gone afterwards.
+    pub fn eval_with(
+        &self,
+        symbol_table: &SymbolTable,
+        bindings: &[(&str, Expr)],
+    ) -> LispResult<Expr> {
+        if self.resolved {
+            for (symbol, _) in bindings {
+                ensure!(
+                    symbol_table.builtin(symbol).is_none(),
+                    "Cannot bind the builtin {} while globals are frozen",
+                    symbol
+                );
+            }
+        }
+        let child = symbol_table.with_bindings(bindings);
+        // This source is the program being run, like `(deprecations)` says,
+        // until the evaluation ends.
+        let previous = child.host_mut().replace_program(&self.source);
+        let res = self.forms.iter().try_fold(Expr::Nil, |_, form| {
+            child.host_mut().reset_built_bytes();
+            form.eval(&child)
+        });
+        child.host_mut().restore_program(previous);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Options;
+    use crate::stdlib::create_stdlib_symbol_table;
+
+    fn syms(frozen_globals: bool) -> SymbolTable {
+        create_stdlib_symbol_table(&Options {
+            frozen_globals,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn compiled_exprs_evaluate_like_source() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<CompiledExpr>();
+
+        for frozen in &[false, true] {
+            let syms = syms(*frozen);
+            let compiled = syms
+                .compile("(def scale 10) (+ (* x scale) (len name))")
+                .unwrap();
+            for (x, name) in &[(1, "ab"), (2, "abc")] {
+                let bindings = [("x", Expr::from(*x)), ("name", Expr::from(*name))];
+                let from_source = syms
+                    .eval_with_bindings(compiled.source(), &bindings)
+                    .unwrap();
+                assert_eq!(compiled.eval_with(&syms, &bindings).unwrap(), from_source);
+            }
+        }
+    }
+
+    #[test]
+    fn bindings_do_not_leak_between_calls() {
+        let plain = syms(false);
+        let compiled = plain.compile("(def seen y) (+ x 1)").unwrap();
+        assert_eq!(
+            compiled
+                .eval_with(&plain, &[("x", Expr::from(1)), ("y", Expr::from(2))])
+                .unwrap(),
+            Expr::from(2)
+        );
+        // Neither y nor what was defined from it is left behind.
+        assert!(compiled.eval_with(&plain, &[("x", Expr::from(1))]).is_err());
+        assert!(plain.eval_source("seen").is_err());
+        assert!(plain.eval_source("x").is_err());
+
+        // Builtins were resolved into the forms, so can't be bound over.
+        let frozen = syms(true);
+        let compiled = frozen.compile("(inc x)").unwrap();
+        assert!(compiled
+            .eval_with(&frozen, &[("inc", Expr::from(1)), ("x", Expr::from(1))])
+            .is_err());
+    }
+
+    #[test]
+    fn the_program_is_only_replaced_while_evaluating() {
+        let syms = syms(false);
+        syms.host_mut().set_program("(+ 1 2)");
+        syms.host_mut().capture_output();
+        let compiled = syms.compile("(is-even? x) (len (deprecations))").unwrap();
+        let x = [("x", Expr::from(2))];
+        assert_eq!(compiled.eval_with(&syms, &x).unwrap(), Expr::from(1));
+        assert_eq!(syms.host().program(), Some("(+ 1 2)".into()));
+        let failing = syms.compile("(throw x)").unwrap();
+        assert!(failing.eval_with(&syms, &[("x", Expr::from(0))]).is_err());
+        assert_eq!(syms.host().program(), Some("(+ 1 2)".into()));
+    }
+
+    #[test]
+    fn compiled_exprs_go_to_other_threads() {
+        let compiled = syms(false).compile("(* x x)").unwrap();
+        let workers: Vec<_> = (0..4)
+            .map(|i| {
+                let compiled = compiled.clone();
+                std::thread::spawn(move || {
+                    let syms = syms(false);
+                    compiled.eval_with(&syms, &[("x", Expr::from(i))]).unwrap()
+                })
+            })
+            .collect();
+        let squares: Vec<Expr> = workers.into_iter().map(|w| w.join().unwrap()).collect();
+        assert_eq!(
+            squares,
+            (0..4).map(|i| Expr::from(i * i)).collect::<Vec<_>>()
+        );
+    }
+}
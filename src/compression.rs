@@ -0,0 +1,363 @@
+use crate::access;
+use crate::exact_len;
+use crate::iterators::{IterType, LazyIter};
+use crate::num;
+use crate::stdlib::register_builtins;
+use crate::symbols::{next_object_id, Expr, LispResult, ProgramError, SymbolTable};
+use anyhow::{anyhow, bail};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use im::Vector;
+use itertools::Itertools;
+use parking_lot::Mutex;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Lines, Read, Write};
+use std::sync::Arc;
+
+// gzip and zip support. Bytes are represented as lists of nums from 0 to 255.
+
+macro_rules! io_err {
+    ($($arg:tt)*) => {
+        anyhow!(ProgramError::Io).context(format!($($arg)*))
+    };
+}
+
+/// Bytes from either a string (its UTF-8 encoding) or a list of nums.
+fn get_bytes(expr: &Expr) -> LispResult<Vec<u8>> {
+    if let Ok(s) = expr.get_string() {
+        return Ok(s.into_bytes());
+    }
+    let mut bytes = Vec::new();
+    for (i, e) in expr.get_list()?.iter().enumerate() {
+        match e.get_usize() {
+            Ok(b) if b <= 255 => bytes.push(b as u8),
+            _ => bail!("Expected a byte (0 to 255) at index {}, but got {:?}", i, e),
+        }
+    }
+    Ok(bytes)
+}
+
+fn bytes_expr(bytes: Vec<u8>) -> Expr {
+    Expr::List(bytes.into_iter().map(|b| num!(b as usize)).collect())
+}
+
+fn open_zip(
+    path: &str,
+    symbol_table: &SymbolTable,
+    what: &str,
+) -> LispResult<zip::ZipArchive<File>> {
+    let file = access::open_read(symbol_table, what, path)?;
+    zip::ZipArchive::new(file)
+        .map_err(|e| io_err!("Could not read zip archive \"{}\", {}", path, e))
+}
+
+fn gzip_compress(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let bytes = get_bytes(&exprs[0])?;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(&bytes)
+        .and_then(|_| encoder.finish())
+        .map(bytes_expr)
+        .map_err(|e| io_err!("Could not gzip compress, {}", e))
+}
+
+fn gzip_decompress(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let bytes = get_bytes(&exprs[0])?;
+    let mut out = Vec::new();
+    GzDecoder::new(&bytes[..])
+        .read_to_end(&mut out)
+        .map_err(|e| io_err!("Could not gzip decompress, {}", e))?;
+    Ok(bytes_expr(out))
+}
+
+fn read_file_gz(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let path = exprs[0].get_string()?;
+    let file = access::open_read(symbol_table, "read-file-gz", &path)?;
+    let mut contents = String::new();
+    GzDecoder::new(file)
+        .read_to_string(&mut contents)
+        .map_err(|e| io_err!("Could not read gzipped file \"{}\", {}", path, e))?;
+    Ok(Expr::String(contents))
+}
+
+fn zip_entries(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let path = exprs[0].get_string()?;
+    let mut archive = open_zip(&path, symbol_table, "zip-entries")?;
+    let mut entries = Vector::new();
+    for i in 0..archive.len() {
+        let entry = archive
+            .by_index(i)
+            .map_err(|e| io_err!("Could not read entry {} of \"{}\", {}", i, path, e))?;
+        let mut dict = im::HashMap::new();
+        dict.insert(
+            Expr::String("name".into()),
+            Expr::String(entry.name().into()),
+        );
+        dict.insert(Expr::String("size".into()), Expr::Num(entry.size().into()));
+        entries.push_back(Expr::Dict(dict));
+    }
+    Ok(Expr::List(entries))
+}
+
+fn zip_read(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let path = exprs[0].get_string()?;
+    let name = exprs[1].get_string()?;
+    let mut archive = open_zip(&path, symbol_table, "zip-read")?;
+    let mut entry = archive
+        .by_name(&name)
+        .map_err(|e| io_err!("Could not find entry \"{}\" in \"{}\", {}", name, path, e))?;
+    let mut bytes = Vec::new();
+    entry
+        .read_to_end(&mut bytes)
+        .map_err(|e| io_err!("Could not read entry \"{}\" in \"{}\", {}", name, path, e))?;
+    Ok(bytes_expr(bytes))
+}
+
+fn zip_create(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let path = exprs[0].get_string()?;
+    // Sort the entries so archives are reproducible.
+    let entries: Vec<(String, Vec<u8>)> = exprs[1]
+        .get_dict()?
+        .iter()
+        .map(|(name, contents)| Ok((name.get_string()?, get_bytes(contents)?)))
+        .collect::<LispResult<Vec<_>>>()?
+        .into_iter()
+        .sorted_by(|l, r| l.0.cmp(&r.0))
+        .collect();
+    let file = access::create(symbol_table, "zip-create", &path)?;
+    let mut writer = zip::ZipWriter::new(file);
+    for (name, contents) in entries.iter() {
+        writer
+            .start_file(name.as_str(), zip::write::FileOptions::default())
+            .map_err(|e| io_err!("Could not add entry \"{}\" to \"{}\", {}", name, path, e))?;
+        writer
+            .write_all(contents)
+            .map_err(|e| io_err!("Could not write entry \"{}\" to \"{}\", {}", name, path, e))?;
+    }
+    writer
+        .finish()
+        .map_err(|e| io_err!("Could not finish writing \"{}\", {}", path, e))?;
+    Ok(num!(entries.len()))
+}
+
+fn string_to_bytes(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    Ok(bytes_expr(exprs[0].get_string()?.into_bytes()))
+}
+
+fn bytes_to_string(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    String::from_utf8(get_bytes(&exprs[0])?)
+        .map(Expr::String)
+        .map_err(|e| anyhow!("Bytes are not valid UTF-8, {}", e))
+}
+
+type GzReader = Lines<BufReader<GzDecoder<File>>>;
+
+/// Lazily read the lines of a gzipped file.
+pub(crate) struct GzLines {
+    state: Arc<Mutex<GzState>>,
+    path: String,
+    id: u64,
+}
+
+struct GzState {
+    // None until the first line is read, for clones.
+    lines: Option<GzReader>,
+    // How many lines have been read, which a clone skips past.
+    read: usize,
+}
+
+impl GzLines {
+    fn open(path: &str, symbol_table: &SymbolTable, skip: usize) -> LispResult<GzReader> {
+        let file = access::open_read(symbol_table, "lines-gz", path)?;
+        let mut lines = BufReader::new(GzDecoder::new(file)).lines();
+        for _ in 0..skip {
+            if let Some(Err(e)) = lines.next() {
+                return Err(io_err!("Could not read a line of \"{}\", {}", path, e));
+            }
+        }
+        Ok(lines)
+    }
+}
+
+impl LazyIter for GzLines {
+    fn next(&self, symbol_table: &SymbolTable) -> Option<LispResult<Expr>> {
+        let mut state = self.state.lock();
+        let line = match state.lines.as_mut() {
+            Some(lines) => lines.next()?,
+            None => match GzLines::open(&self.path, symbol_table, state.read) {
+                Ok(lines) => state.lines.get_or_insert(lines).next()?,
+                Err(e) => return Some(Err(e)),
+            },
+        };
+        state.read += 1;
+        Some(
+            line.map(Expr::String)
+                .map_err(|e| io_err!("Could not read a line of \"{}\", {}", self.path, e)),
+        )
+    }
+
+    fn name(&self) -> &'static str {
+        "GzLines"
+    }
+
+    // Clones read the file again from where this one is, as the
+    // decompressed stream can't be shared.
+    fn clone(&self) -> IterType {
+        Box::new(GzLines {
+            state: Arc::new(Mutex::new(GzState {
+                lines: None,
+                read: self.state.lock().read,
+            })),
+            path: self.path.clone(),
+            id: self.id,
+        })
+    }
+
+    fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl fmt::Debug for GzLines {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LazyIter<{}<{}>>", self.name(), self.path)
+    }
+}
+
+impl fmt::Display for GzLines {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+fn lines_gz(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let path = exprs[0].get_string()?;
+    let lines = GzLines::open(&path, symbol_table, 0)?;
+    Ok(Expr::LazyIter(Box::new(GzLines {
+        state: Arc::new(Mutex::new(GzState {
+            lines: Some(lines),
+            read: 0,
+        })),
+        path,
+        id: next_object_id(),
+    })))
+}
+
+pub(crate) fn register(syms: &SymbolTable) {
+    register_builtins(
+        syms,
+        &[
+            (
+                "gzip-compress",
+                "io",
+                1,
+                gzip_compress,
+                true,
+                "Gzip compress a string or list of bytes, returning bytes.
+Example:
+(gzip-decompress (gzip-compress \"hello\")) ; (104 101 108 108 111)
+",
+            ),
+            (
+                "gzip-decompress",
+                "io",
+                1,
+                gzip_decompress,
+                true,
+                "Decompress gzipped bytes.
+Example:
+(bytes->string (gzip-decompress (gzip-compress \"hello\"))) ; \"hello\"
+",
+            ),
+            (
+                "read-file-gz",
+                "io",
+                1,
+                read_file_gz,
+                true,
+                "Read a gzipped file into a string.
+Example:
+(read-file-gz \"log.txt.gz\")
+",
+            ),
+            (
+                "lines-gz",
+                "io",
+                1,
+                lines_gz,
+                true,
+                "Lazily read the lines of a gzipped file, without decompressing it all into memory.
+Example:
+(foreach println (take 10 (lines-gz \"log.txt.gz\"))) ; print the first ten lines
+",
+            ),
+            (
+                "zip-entries",
+                "io",
+                1,
+                zip_entries,
+                true,
+                "List the entries of a zip archive as dicts with a name and size.
+Example:
+(zip-entries \"bundle.zip\") ; ({\"name\": \"a.txt\", \"size\": 5})
+",
+            ),
+            (
+                "zip-read",
+                "io",
+                2,
+                zip_read,
+                true,
+                "Read an entry of a zip archive as bytes. Errors if the entry doesn't exist.
+Example:
+(bytes->string (zip-read \"bundle.zip\" \"a.txt\")) ; \"hello\"
+",
+            ),
+            (
+                "zip-create",
+                "io",
+                2,
+                zip_create,
+                true,
+                "Create a zip archive from a dict of entry names to strings or bytes.
+Returns the number of entries written.
+Example:
+(zip-create \"bundle.zip\" (dict \"a.txt\" \"hello\" \"b.txt\" \"world\")) ; 2
+",
+            ),
+            (
+                "string->bytes",
+                "strings",
+                1,
+                string_to_bytes,
+                true,
+                "Encode a string as a list of UTF-8 bytes.
+Example:
+(string->bytes \"hi\") ; (104 105)
+",
+            ),
+            (
+                "bytes->string",
+                "strings",
+                1,
+                bytes_to_string,
+                true,
+                "Decode a list of UTF-8 bytes as a string.
+Example:
+(bytes->string '(104 105)) ; \"hi\"
+",
+            ),
+        ],
+    );
+}
@@ -0,0 +1,153 @@
+use crate::cli::Options;
+use crate::exact_len;
+use crate::num;
+use crate::stdlib::create_stdlib_symbol_table;
+use crate::symbols::{Dict, Expr, LispResult, SymbolTable};
+use im::Vector;
+
+// An interpreter's settings as plain data, so a host can keep them in its
+// own config file and get the same interpreter every time. With the json
+// feature the config is serde (de)serializable, and fields it doesn't know
+// are errors, so a typo doesn't silently leave a limit off.
+
+/// How to set up an interpreter, with `SymbolTable::from_config`.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "json", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "json", serde(default, deny_unknown_fields))]
+pub struct InterpreterConfig {
+    /// Deny file access (`fs::open`, loading modules from files).
+    pub sandbox: bool,
+    /// Make warnings errors, like `--deny-warnings`.
+    pub deny_warnings: bool,
+    /// Make calling deprecated builtins errors, like `--deny-deprecated`.
+    pub deny_deprecated: bool,
+    /// Make sequence builtins error on nil, like `--strict-nil`.
+    pub strict_nil: bool,
+    /// Resolve calls to builtins ahead of time, like `--frozen-globals`.
+    pub frozen_globals: bool,
+    /// Disable the memoization caches, like `--no-caches`.
+    pub no_caches: bool,
+    /// Fail with a "Resource" error when a builtin would build a value
+    /// bigger than roughly this many bytes. Unlimited when `None`.
+    pub max_value_bytes: Option<usize>,
+    /// Keep this many of the latest top level forms, for
+    /// `SymbolTable::recent_evaluations`.
+    pub history: usize,
+}
+
+/// A stdlib interpreter set up as `config` says.
+pub(crate) fn build(config: &InterpreterConfig) -> SymbolTable {
+    let syms = create_stdlib_symbol_table(&Options {
+        no_caches: config.no_caches,
+        deny_warnings: config.deny_warnings,
+        deny_deprecated: config.deny_deprecated,
+        strict_nil: config.strict_nil,
+        frozen_globals: config.frozen_globals,
+        ..Default::default()
+    });
+    syms.set_sandboxed(config.sandbox);
+    syms.set_max_value_bytes(config.max_value_bytes);
+    syms.set_history_size(config.history);
+    syms
+}
+
+/// The settings `symbol_table` has now.
+pub(crate) fn effective(symbol_table: &SymbolTable) -> InterpreterConfig {
+    let host = symbol_table.host();
+    InterpreterConfig {
+        sandbox: host.is_sandboxed(),
+        deny_warnings: host.is_deny_warnings(),
+        deny_deprecated: host.is_deny_deprecated(),
+        strict_nil: host.is_strict_nil(),
+        frozen_globals: host.is_frozen_globals(),
+        no_caches: !symbol_table.caches_enabled(),
+        max_value_bytes: host.max_value_bytes(),
+        history: host.history().capacity(),
+    }
+}
+
+/// (interpreter-config)
+pub(crate) fn interpreter_config(
+    exprs: Vector<Expr>,
+    symbol_table: &SymbolTable,
+) -> LispResult<Expr> {
+    exact_len!(exprs, 0);
+    let config = symbol_table.config();
+    let entries = vec![
+        ("sandbox", Expr::Bool(config.sandbox)),
+        ("deny-warnings", Expr::Bool(config.deny_warnings)),
+        ("deny-deprecated", Expr::Bool(config.deny_deprecated)),
+        ("strict-nil", Expr::Bool(config.strict_nil)),
+        ("frozen-globals", Expr::Bool(config.frozen_globals)),
+        ("no-caches", Expr::Bool(config.no_caches)),
+        (
+            "max-value-bytes",
+            config.max_value_bytes.map_or(Expr::Nil, |n| num!(n)),
+        ),
+        ("history", num!(config.history)),
+    ];
+    let dict: Dict = entries
+        .into_iter()
+        .map(|(key, value)| (Expr::Symbol(format!(":{}", key)), value))
+        .collect();
+    Ok(Expr::Dict(dict))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbols::error_kind;
+
+    #[test]
+    fn built_interpreters_report_their_config() {
+        let config = InterpreterConfig {
+            strict_nil: true,
+            max_value_bytes: Some(1024),
+            history: 3,
+            ..Default::default()
+        };
+        let syms = SymbolTable::from_config(&config);
+        assert_eq!(syms.config(), config);
+        assert_eq!(
+            syms.eval_source("(:max-value-bytes (interpreter-config))")
+                .unwrap(),
+            num!(1024)
+        );
+        assert_eq!(
+            syms.eval_source("(:sandbox (interpreter-config))").unwrap(),
+            Expr::Bool(false)
+        );
+
+        // The limits it sets are enforced.
+        let too_big = syms.eval_source("(* \"a\" 2000)").unwrap_err();
+        assert_eq!(error_kind(&too_big), "Resource");
+        assert!(syms.eval_source("(* \"a\" 100)").is_ok());
+        assert!(syms.eval_source("(len (head '()))").is_err());
+        let lenient = SymbolTable::from_config(&InterpreterConfig::default());
+        assert_eq!(lenient.eval_source("(len (head '()))").unwrap(), num!(0));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn configs_round_trip_through_json() {
+        let config = InterpreterConfig {
+            strict_nil: true,
+            max_value_bytes: Some(1 << 16),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&config).unwrap();
+        assert_eq!(
+            serde_json::from_str::<InterpreterConfig>(&json).unwrap(),
+            config
+        );
+        // Fields left out take their defaults, and unknown ones are errors.
+        let partial = r#"{"strict_nil": true, "max_value_bytes": 65536}"#;
+        let config: InterpreterConfig = serde_json::from_str(partial).unwrap();
+        assert_eq!(config.max_value_bytes, Some(1 << 16));
+        let typo = serde_json::from_str::<InterpreterConfig>(r#"{"strict_nill": true}"#)
+            .unwrap_err()
+            .to_string();
+        assert!(typo.contains("unknown field `strict_nill`"), "{}", typo);
+        assert_eq!(SymbolTable::from_config(&config).config(), config);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::symbols::{Expr, LispResult};
+use anyhow::{anyhow, bail};
+use bigdecimal::BigDecimal;
+use itertools::Itertools;
+
+// Schemas for `conform` are plain data:
+//
+//   :any :string :bool :keyword    the value must have this type
+//   :int :num                      numbers, or strings which parse as one
+//   (:list schema)                 a list whose items all conform to schema
+//   (:optional schema default)     in a dict, a key which may be missing.
+//                                  The default is used if given.
+//   (dict :key schema ...)         a dict with these keys. Other keys are kept.
+//
+// Violations don't stop the walk, so every one of them can be reported.
+
+/// Where a violation happened, e.g. `:db :port` or `:tags 1`.
+pub(crate) fn describe(path: &[Expr]) -> String {
+    if path.is_empty() {
+        "top level".into()
+    } else {
+        path.iter().map(|p| format!("{:?}", p)).join(" ")
+    }
+}
+
+#[derive(Default)]
+struct Conformer {
+    violations: Vec<String>,
+}
+
+impl Conformer {
+    fn violation(&mut self, path: &[Expr], message: String) -> Option<Expr> {
+        self.violations
+            .push(format!("{}: {}", describe(path), message));
+        None
+    }
+
+    /// Conform `value` to `schema`, returning None if there were violations.
+    /// Errors are reserved for malformed schemas.
+    fn conform(
+        &mut self,
+        schema: &Expr,
+        value: &Expr,
+        path: &mut Vec<Expr>,
+    ) -> LispResult<Option<Expr>> {
+        match schema {
+            Expr::Dict(fields) => self.conform_dict(fields, value, path),
+            Expr::Symbol(kind) => self.conform_scalar(kind, value, path),
+            Expr::List(items) | Expr::Quote(items) => {
+                let head = items.front().and_then(|h| h.get_symbol_string().ok());
+                match (head.as_deref(), items.len()) {
+                    (Some(":list"), 2) => self.conform_list(&items[1], value, path),
+                    (Some(":optional"), 2) | (Some(":optional"), 3) => {
+                        self.conform(&items[1], value, path)
+                    }
+                    _ => bail!("Unknown schema {:?}", schema),
+                }
+            }
+            _ => bail!("Unknown schema {:?}", schema),
+        }
+    }
+
+    fn conform_scalar(
+        &mut self,
+        kind: &str,
+        value: &Expr,
+        path: &[Expr],
+    ) -> LispResult<Option<Expr>> {
+        let ok = match (kind, value) {
+            (":any", _) => true,
+            (":string", Expr::String(_)) => true,
+            (":bool", Expr::Bool(_)) => true,
+            (":keyword", Expr::Symbol(s)) => s.starts_with(':'),
+            (":num", Expr::Num(_)) => true,
+            (":int", Expr::Num(_)) => value.is_int()?,
+            (":int", Expr::String(s)) | (":num", Expr::String(s)) => {
+                let parsed = s.trim().parse::<BigDecimal>().ok().map(Expr::Num);
+                let converted = match parsed {
+                    Some(n) if kind == ":num" || n.is_int()? => Some(n),
+                    _ => None,
+                };
+                return Ok(converted.or_else(|| {
+                    self.violation(
+                        path,
+                        format!("expected {}, but could not convert {:?}", &kind[1..], s),
+                    )
+                }));
+            }
+            (":string", _) | (":bool", _) | (":keyword", _) | (":num", _) | (":int", _) => false,
+            _ => bail!("Unknown schema type {}", kind),
+        };
+        if ok {
+            Ok(Some(value.clone()))
+        } else {
+            Ok(self.violation(
+                path,
+                format!(
+                    "expected {}, but got {} {:?}",
+                    &kind[1..],
+                    value.get_type_str(),
+                    value
+                ),
+            ))
+        }
+    }
+
+    fn conform_list(
+        &mut self,
+        schema: &Expr,
+        value: &Expr,
+        path: &mut Vec<Expr>,
+    ) -> LispResult<Option<Expr>> {
+        let items = match value.unmeta() {
+            Expr::List(items) => items,
+            _ => {
+                return Ok(self.violation(
+                    path,
+                    format!(
+                        "expected list, but got {} {:?}",
+                        value.get_type_str(),
+                        value
+                    ),
+                ))
+            }
+        };
+        let mut res = im::Vector::new();
+        let mut ok = true;
+        for (i, item) in items.iter().enumerate() {
+            path.push(Expr::from(i as i64));
+            match self.conform(schema, item, path)? {
+                Some(item) => res.push_back(item),
+                None => ok = false,
+            }
+            path.pop();
+        }
+        Ok(if ok { Some(Expr::List(res)) } else { None })
+    }
+
+    fn conform_dict(
+        &mut self,
+        fields: &im::HashMap<Expr, Expr>,
+        value: &Expr,
+        path: &mut Vec<Expr>,
+    ) -> LispResult<Option<Expr>> {
+        let dict = match value.unmeta() {
+            Expr::Dict(dict) => dict,
+            _ => {
+                return Ok(self.violation(
+                    path,
+                    format!(
+                        "expected dict, but got {} {:?}",
+                        value.get_type_str(),
+                        value
+                    ),
+                ))
+            }
+        };
+        let mut res = dict.clone();
+        let mut ok = true;
+        // Sorted so violations are reported in a stable order. Keys are
+        // usually keywords, which Expr's Ord doesn't order, so go by name.
+        for (key, schema) in fields.iter().sorted_by_key(|(key, _)| format!("{:?}", key)) {
+            path.push(key.clone());
+            let conformed = match (dict.get(key), optional(schema)) {
+                (Some(value), _) => self.conform(schema, value, path)?,
+                (None, Some(Some(default))) => Some(default.clone()),
+                (None, Some(None)) => {
+                    path.pop();
+                    continue;
+                }
+                (None, None) => self.violation(path, "missing required key".into()),
+            };
+            match conformed {
+                Some(value) => {
+                    res.insert(key.clone(), value);
+                }
+                None => ok = false,
+            }
+            path.pop();
+        }
+        Ok(if ok { Some(Expr::Dict(res)) } else { None })
+    }
+}
+
+/// For an `(:optional schema default)` schema, the default if there is one.
+fn optional(schema: &Expr) -> Option<Option<&Expr>> {
+    match schema {
+        Expr::List(items) | Expr::Quote(items) if items.front()?.symbol_matches(":optional") => {
+            Some(items.get(2))
+        }
+        _ => None,
+    }
+}
+
+/// Validate `data` against `schema`, applying defaults and coercions.
+/// Errors list every violation with its path.
+pub(crate) fn conform(data: &Expr, schema: &Expr) -> LispResult<Expr> {
+    let mut conformer = Conformer::default();
+    let res = conformer.conform(schema, data, &mut Vec::new())?;
+    match res {
+        Some(res) if conformer.violations.is_empty() => Ok(res),
+        _ => Err(anyhow!(
+            "Data does not conform to the schema, {} violation(s):\n{}",
+            conformer.violations.len(),
+            conformer.violations.join("\n")
+        )),
+    }
+}
@@ -0,0 +1,284 @@
+use crate::exact_len;
+use crate::host::Warning;
+use crate::metrics::analyze;
+use crate::stdlib::emptiness;
+use crate::symbols::{Expr, Function, LispResult, ProgramError, SymbolTable};
+use anyhow::{anyhow, ensure};
+use bigdecimal::{BigDecimal, Zero};
+use im::Vector;
+use std::sync::Arc;
+
+// Old names of builtins. Each still works by calling its replacement, or
+// the builtin as it was if the replacement behaves differently, but the
+// first call under the old name warns, pointing at the new one. With
+// --deny-deprecated every call is an error instead, so scripts can be
+// checked before the old names go away.
+
+/// A builtin as it behaved before it was renamed, taking evaluated arguments.
+pub(crate) type OldBehaviour = fn(Vector<Expr>, &SymbolTable) -> LispResult<Expr>;
+
+/// When a builtin was renamed, and what to call instead.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Deprecation {
+    pub(crate) since: &'static str,
+    pub(crate) replacement: &'static str,
+    /// How the replacement behaves differently, and the old behaviour the
+    /// old name keeps, if it does.
+    pub(crate) change: Option<(&'static str, OldBehaviour)>,
+}
+
+pub(crate) const fn deprecated_since(
+    since: &'static str,
+    replacement: &'static str,
+) -> Deprecation {
+    Deprecation {
+        since,
+        replacement,
+        change: None,
+    }
+}
+
+impl Deprecation {
+    /// Keep the old name behaving as it did, as the replacement differs in
+    /// the way `change` says, for the old name's docs.
+    pub(crate) const fn keeping(self, change: &'static str, old: OldBehaviour) -> Deprecation {
+        Deprecation {
+            change: Some((change, old)),
+            ..self
+        }
+    }
+}
+
+/// Renaming a builtin is a line here, from its old name.
+const DEPRECATED: &[(&str, Deprecation)] = &[
+    (
+        "is-even?",
+        deprecated_since("0.1.0", "even?").keeping(
+            "Unlike even?, it calls fractional numbers odd rather than erroring.",
+            old_is_even,
+        ),
+    ),
+    (
+        "non-empty?",
+        deprecated_since("0.1.0", "not-empty?").keeping(
+            "Unlike not-empty?, it calls values that aren't collections, like \
+             numbers, non-empty rather than erroring.",
+            old_non_empty,
+        ),
+    ),
+];
+
+/// is-even?, which called fractional numbers odd.
+fn old_is_even(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let n = exprs[0].get_num()?;
+    Ok(Expr::Bool((n % BigDecimal::from(2)).is_zero()))
+}
+
+/// non-empty?, which called values that aren't collections non-empty.
+fn old_non_empty(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    Ok(Expr::Bool(emptiness(&exprs[0]) != Some(true)))
+}
+
+/// Whether `name` is the old name of a builtin.
+pub(crate) fn deprecation(name: &str) -> Option<Deprecation> {
+    DEPRECATED
+        .iter()
+        .find(|(old, _)| *old == name)
+        .map(|(_, deprecation)| *deprecation)
+}
+
+/// Warn about calling `name`, the first time it's called, or fail if
+/// deprecated builtins are denied.
+fn warn(
+    name: &'static str,
+    deprecation: Deprecation,
+    symbol_table: &SymbolTable,
+) -> LispResult<()> {
+    let message = format!(
+        "{} is deprecated since x7 {}, use {} instead",
+        name, deprecation.since, deprecation.replacement
+    );
+    {
+        let mut host = symbol_table.host_mut();
+        if host.is_deny_deprecated() {
+            return Err(anyhow!(ProgramError::DeniedWarning)
+                .context(format!("{} (deprecated builtins are denied)", message)));
+        }
+        if !host.first_deprecated_call(name) {
+            return Ok(());
+        }
+    }
+    symbol_table.warn(Warning {
+        kind: ":deprecated".into(),
+        message,
+        span: None,
+    })
+}
+
+/// Define each old name as an alias of its replacement, or as the builtin
+/// it used to be.
+pub(crate) fn register(syms: &SymbolTable) {
+    for (name, deprecation) in DEPRECATED.iter().copied() {
+        let replacement = syms
+            .lookup(&Expr::Symbol(deprecation.replacement.into()))
+            .and_then(|f| f.get_function())
+            .unwrap_or_else(|_| panic!("{} is deprecated for a missing builtin", name));
+        let f = match deprecation.change {
+            Some((_, old)) => Function::new(
+                name.into(),
+                1,
+                Arc::new(move |args: Vector<Expr>, symbol_table: &SymbolTable| {
+                    warn(name, deprecation, symbol_table)?;
+                    old(args, symbol_table)
+                }),
+                true,
+            ),
+            // Arguments are passed on as written, for the replacement to evaluate.
+            None => Function::new(
+                name.into(),
+                0,
+                Arc::new(move |args: Vector<Expr>, symbol_table: &SymbolTable| {
+                    warn(name, deprecation, symbol_table)?;
+                    replacement.call_fn(args, symbol_table)
+                }),
+                false,
+            ),
+        };
+        syms.add_global(name, Expr::Function(f));
+        let mut doc = format!(
+            "Deprecated since x7 {}, use {} instead.",
+            deprecation.since, deprecation.replacement
+        );
+        if let Some((change, _)) = deprecation.change {
+            doc = format!("{} {}", doc, change);
+        }
+        syms.add_doc_item(name.into(), doc);
+        if let Some(category) = syms.get_category(deprecation.replacement) {
+            syms.set_category(name, category);
+        }
+    }
+}
+
+/// (deprecations [source])
+pub(crate) fn deprecations(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    ensure!(
+        exprs.len() <= 1,
+        "deprecations takes the source to look at, or nothing for the program being run"
+    );
+    let source = match exprs.get(0) {
+        Some(source) => source.get_string()?,
+        None => symbol_table.host().program().unwrap_or_default(),
+    };
+    let found = analyze(&source)?
+        .deprecated
+        .into_iter()
+        .map(|name| {
+            let deprecation = deprecation(&name).unwrap();
+            let mut dict = im::HashMap::new();
+            dict.insert(Expr::Symbol(":name".into()), Expr::String(name));
+            dict.insert(
+                Expr::Symbol(":since".into()),
+                Expr::String(deprecation.since.into()),
+            );
+            dict.insert(
+                Expr::Symbol(":replacement".into()),
+                Expr::String(deprecation.replacement.into()),
+            );
+            Expr::Dict(dict)
+        })
+        .collect();
+    Ok(Expr::List(found))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::Options;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use crate::symbols::Expr;
+    use crate::{run_source, RunOptions};
+
+    #[test]
+    fn old_names_warn_once() {
+        let outcome = run_source(
+            "(list (is-even? 2) (is-even? 3) (non-empty? '()) (is-even? (+ 1 1)))",
+            RunOptions::default(),
+        );
+        assert_eq!(outcome.value.as_deref(), Some("(true false false true)"));
+        let warnings: Vec<String> = outcome.warnings.iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            warnings,
+            [
+                "Warning[:deprecated]: is-even? is deprecated since x7 0.1.0, use even? instead",
+                "Warning[:deprecated]: non-empty? is deprecated since x7 0.1.0, use not-empty? instead",
+            ]
+        );
+    }
+
+    #[test]
+    fn old_names_document_how_they_changed() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let doc = syms.eval_source("(doc is-even?)").unwrap();
+        let doc = doc.get_string().unwrap();
+        assert!(
+            doc.contains("use even? instead. Unlike even?, it calls fractional numbers odd"),
+            "{}",
+            doc
+        );
+    }
+
+    #[test]
+    fn denying_deprecated_builtins() {
+        let syms = create_stdlib_symbol_table(&Options {
+            deny_deprecated: true,
+            ..Default::default()
+        });
+        for _ in 0..2 {
+            let err = syms.eval_source("(is-even? 2)").unwrap_err();
+            assert!(format!("{:#}", err).contains("(deprecated builtins are denied)"));
+        }
+        assert_eq!(syms.eval_source("(even? 2)").unwrap(), Expr::Bool(true));
+
+        let outcome = run_source(
+            "(non-empty? '(1))",
+            RunOptions {
+                deny_deprecated: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(outcome.error.unwrap().kind, "DeniedWarning");
+    }
+
+    #[test]
+    fn programs_list_the_old_names_they_use() {
+        let outcome = run_source(
+            "(defn evens (l) (filter is-even? l))
+             (map (fn (d) (get d :name)) (deprecations))",
+            RunOptions::default(),
+        );
+        assert_eq!(outcome.value.as_deref(), Some("(\"is-even?\")"));
+        // Nothing was called, so nothing warned.
+        assert!(outcome.warnings.is_empty());
+
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let prog = "(deprecations \"(non-empty? (filter is-even? (list 2)))\")";
+        let found = syms.eval_source(prog).unwrap().get_list().unwrap();
+        assert_eq!(found.len(), 2);
+        let prog = "(= (head (deprecations \"(is-even? 2)\"))
+                       (dict :name \"is-even?\" :since \"0.1.0\" :replacement \"even?\"))";
+        assert_eq!(syms.eval_source(prog).unwrap(), Expr::Bool(true));
+    }
+
+    #[test]
+    fn evaluating_more_source_keeps_the_program() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.host_mut().set_program("(is-even? 2)");
+        let found = syms
+            .eval_source("(deprecations)")
+            .unwrap()
+            .get_list()
+            .unwrap();
+        assert_eq!(found.len(), 1);
+    }
+}
@@ -0,0 +1,303 @@
+use crate::exact_len;
+use crate::records::Record;
+use crate::symbols::{sort_order, Expr, LispResult, ProgramError, SymbolTable};
+use anyhow::{anyhow, bail, ensure};
+use im::Vector;
+
+// Building dicts in bulk from other data. Keys must be plain data, as
+// functions, lazy iterators, and records are only equal to themselves, so
+// a key extracted from an element which is one of those is an error citing
+// the element's index.
+
+/// Error unless `key`, from the element at `index`, can key a dict.
+fn check_key(what: &str, key: &Expr, index: usize) -> LispResult<()> {
+    let bad = match key.unmeta() {
+        Expr::Function(_) | Expr::LazyIter(_) | Expr::Record(_) => Some(key),
+        Expr::List(l) | Expr::Quote(l) | Expr::Tuple(l) => {
+            return l.iter().try_for_each(|k| check_key(what, k, index))
+        }
+        Expr::Dict(d) => {
+            return d
+                .iter()
+                .try_for_each(|(k, v)| check_key(what, k, index).and(check_key(what, v, index)))
+        }
+        _ => None,
+    };
+    match bad {
+        Some(bad) => Err(anyhow!(ProgramError::BadTypes).context(format!(
+            "{} can't key a dict with {:?}, from the element at index {}, as a {} is only equal to itself",
+            what,
+            bad,
+            index,
+            bad.get_type_str()
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// The value of a trailing `:option value` pair, if `exprs` ends with one.
+fn trailing_option(
+    exprs: &mut Vector<Expr>,
+    min_args: usize,
+    option: &'static str,
+) -> Option<Expr> {
+    if exprs.len() >= min_args + 2 && exprs[exprs.len() - 2].symbol_matches(option) {
+        let value = exprs.pop_back();
+        exprs.pop_back();
+        value
+    } else {
+        None
+    }
+}
+
+/// The keyword value of `option`, which must be one of `choices`.
+fn choice(what: &str, option: &str, value: Option<Expr>, choices: &[&str]) -> LispResult<String> {
+    let value = match value {
+        Some(value) => value.get_symbol_string()?,
+        None => return Ok(choices[0].into()),
+    };
+    ensure!(
+        choices.contains(&value.as_str()),
+        "{}'s {} is one of {}, but was given {}",
+        what,
+        option,
+        choices.join(" "),
+        value
+    );
+    Ok(value)
+}
+
+/// (dict-from-pairs pairs [:on-duplicate :last|:error])
+pub(crate) fn dict_from_pairs(
+    mut exprs: Vector<Expr>,
+    _symbol_table: &SymbolTable,
+) -> LispResult<Expr> {
+    let on_duplicate = trailing_option(&mut exprs, 1, ":on-duplicate");
+    let on_duplicate = choice(
+        "dict-from-pairs",
+        ":on-duplicate",
+        on_duplicate,
+        &[":last", ":error"],
+    )?;
+    exact_len!(exprs, 1);
+    let mut dict = im::HashMap::new();
+    // Where each key was first seen, to report duplicates.
+    let mut first_seen = im::HashMap::new();
+    for (index, pair) in exprs[0].get_list()?.into_iter().enumerate() {
+        let (key, value) = match &pair {
+            Expr::List(l) | Expr::Quote(l) | Expr::Tuple(l) if l.len() == 2 => {
+                (l[0].clone(), l[1].clone())
+            }
+            _ => bail!(
+                "dict-from-pairs expects pairs like (key value), but the element at index {} is {:?}",
+                index,
+                pair
+            ),
+        };
+        check_key("dict-from-pairs", &key, index)?;
+        if let Some(first) = first_seen.get(&key) {
+            ensure!(
+                on_duplicate == ":last",
+                "dict-from-pairs found the key {:?} at index {}, and first at index {}",
+                key,
+                index,
+                first
+            );
+        } else {
+            first_seen.insert(key.clone(), index);
+        }
+        dict.insert(key, value);
+    }
+    Ok(Expr::Dict(dict))
+}
+
+/// (zipmap keys vals [:on-mismatch :error|:truncate])
+pub(crate) fn zipmap(mut exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let on_mismatch = trailing_option(&mut exprs, 2, ":on-mismatch");
+    let on_mismatch = choice(
+        "zipmap",
+        ":on-mismatch",
+        on_mismatch,
+        &[":error", ":truncate"],
+    )?;
+    exact_len!(exprs, 2);
+    let (keys, values) = (exprs[0].get_list()?, exprs[1].get_list()?);
+    ensure!(
+        keys.len() == values.len() || on_mismatch == ":truncate",
+        "zipmap was given {} keys and {} values, pass :on-mismatch :truncate to drop the extras",
+        keys.len(),
+        values.len()
+    );
+    let mut dict = im::HashMap::new();
+    for (index, (key, value)) in keys.into_iter().zip(values).enumerate() {
+        check_key("zipmap", &key, index)?;
+        dict.insert(key, value);
+    }
+    Ok(Expr::Dict(dict))
+}
+
+/// (invert dict [:collect true])
+pub(crate) fn invert(mut exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let collect = match trailing_option(&mut exprs, 1, ":collect") {
+        Some(collect) => collect.get_bool()?,
+        None => false,
+    };
+    exact_len!(exprs, 1);
+    let dict = exprs[0].dict_entries()?;
+    // Sorted, so collected keys and errors don't depend on hashing.
+    let mut entries: Vec<(Expr, Expr)> = dict.into_iter().collect();
+    entries.sort_by(|(l, _), (r, _)| sort_order(l, r));
+    let mut inverted: im::HashMap<Expr, Vector<Expr>> = im::HashMap::new();
+    for (index, (key, value)) in entries.into_iter().enumerate() {
+        check_key("invert", &value, index)?;
+        inverted.entry(value).or_default().push_back(key);
+    }
+    if collect {
+        return Ok(Expr::Dict(
+            inverted
+                .into_iter()
+                .map(|(value, keys)| (value, Expr::List(keys)))
+                .collect(),
+        ));
+    }
+    let mut collisions: Vec<(&Expr, &Vector<Expr>)> =
+        inverted.iter().filter(|(_, keys)| keys.len() > 1).collect();
+    collisions.sort_by(|(l, _), (r, _)| sort_order(l, r));
+    if let Some((value, keys)) = collisions.first() {
+        bail!(
+            "invert found {} keys with the value {:?}: {}, pass :collect true to gather them in lists",
+            keys.len(),
+            value,
+            keys.iter().map(|k| format!("{:?}", k)).collect::<Vec<_>>().join(", ")
+        );
+    }
+    Ok(Expr::Dict(
+        inverted
+            .into_iter()
+            .map(|(value, mut keys)| (value, keys.pop_back().unwrap()))
+            .collect(),
+    ))
+}
+
+/// The key of `item` from `key_fn`, a function or a keyword naming a
+/// dict entry or record field.
+fn extract_key(key_fn: &Expr, item: &Expr, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    match (key_fn, item) {
+        (Expr::Symbol(k), Expr::Dict(d)) if k.starts_with(':') => {
+            Ok(d.get(key_fn).cloned().unwrap_or(Expr::Nil))
+        }
+        (Expr::Symbol(k), Expr::Record(r)) if k.starts_with(':') => {
+            r.call_method_in(&k[1..], Vector::new(), symbol_table)
+        }
+        (Expr::Symbol(k), _) if k.starts_with(':') => bail!(
+            "index-by can only look up {} in dicts and records, but was given {:?}",
+            k,
+            item
+        ),
+        _ => key_fn.call_with_values(Vector::unit(item.clone()), symbol_table),
+    }
+}
+
+/// (index-by key-fn coll)
+pub(crate) fn index_by(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let key_fn = &exprs[0];
+    let mut dict = im::HashMap::new();
+    for (index, item) in exprs[1].get_list()?.into_iter().enumerate() {
+        let key = extract_key(key_fn, &item, symbol_table)?;
+        check_key("index-by", &key, index)?;
+        dict.insert(key, item);
+    }
+    Ok(Expr::Dict(dict))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::Options;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use crate::symbols::Expr;
+
+    fn check(progs: &[(&str, &str)]) {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        for (prog, expected) in progs {
+            let prog = format!("(= {} {})", prog, expected);
+            assert_eq!(
+                syms.eval_source(&prog).unwrap(),
+                Expr::Bool(true),
+                "{}",
+                prog
+            );
+        }
+    }
+
+    fn error(prog: &str) -> String {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        format!("{:#}", syms.eval_source(prog).unwrap_err())
+    }
+
+    #[test]
+    fn pairs_and_zips() {
+        check(&[
+            ("(dict-from-pairs '((:a 1) (:b 2)))", "(dict :a 1 :b 2)"),
+            ("(dict-from-pairs (list ^(:a 1) ^(:a 2)))", "(dict :a 2)"),
+            ("(dict-from-pairs '())", "(dict)"),
+            ("(zipmap '(:a :b) '(1 2))", "(dict :a 1 :b 2)"),
+            (
+                "(zipmap '(:a :b :c) '(1 2) :on-mismatch :truncate)",
+                "(dict :a 1 :b 2)",
+            ),
+        ]);
+        let err = error("(dict-from-pairs '((:a 1) (:b 2) (:a 3)) :on-duplicate :error)");
+        assert!(err.contains("found the key :a at index 2, and first at index 0"));
+        assert!(error("(dict-from-pairs '((:a 1) (:b)))").contains("the element at index 1"));
+        assert!(error("(dict-from-pairs '() :on-duplicate :first)").contains(":last :error"));
+        assert!(error("(zipmap '(:a :b) '(1))").contains("2 keys and 1 values"));
+    }
+
+    #[test]
+    fn inverting() {
+        check(&[
+            ("(invert (dict :a 1 :b 2))", "(dict 1 :a 2 :b)"),
+            (
+                "(invert (dict :a 1 :b 2 :c 1) :collect true)",
+                "(dict 1 '(:a :c) 2 '(:b))",
+            ),
+        ]);
+        let err = error("(invert (dict :c 1 :b 2 :a 1))");
+        assert!(
+            err.contains("found 2 keys with the value 1: :a, :c"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn indexing() {
+        check(&[
+            (
+                "(index-by :id (list (dict :id 1 :n \"a\") (dict :id 2 :n \"b\")))",
+                "(dict 1 (dict :id 1 :n \"a\") 2 (dict :id 2 :n \"b\"))",
+            ),
+            (
+                "(index-by len '(\"a\" \"bb\" \"cc\"))",
+                "(dict 1 \"a\" 2 \"cc\")",
+            ),
+            (
+                "(index-by first '((:a 1) (:b 2)))",
+                "(dict :a '(:a 1) :b '(:b 2))",
+            ),
+            (
+                "(do (defrecord Point (x y)) (.y (get (index-by :x (list (Point 1 2))) 1)))",
+                "2",
+            ),
+        ]);
+        let err = error("(index-by (fn (x) (if (= x 3) inc x)) '(1 2 3))");
+        assert!(err.contains("from the element at index 2"), "{}", err);
+        let err = error("(zipmap (list 1 (list inc)) '(1 2))");
+        assert!(err.contains("from the element at index 1"), "{}", err);
+        assert!(error("(invert (dict :f inc))").contains("index 0"));
+        let err = error("(index-by (fn (x) (data (a ~(with-meta inc (dict))))) '(1))");
+        assert!(err.contains("from the element at index 0"), "{}", err);
+        assert!(error("(index-by :id '(1))").contains("dicts and records"));
+    }
+}
@@ -0,0 +1,239 @@
+use crate::conform::describe;
+use crate::symbols::{Expr, LispResult, SymbolTable};
+use itertools::Itertools;
+
+// Failure reports for the test builtins.
+//
+// `assert-eq` reports the subpaths where expected and actual differ, instead
+// of printing both values in full. Only the first MAX_DIFFS differences are
+// shown, followed by a count of the rest.
+//
+// `assert-matches` checks a value against an unevaluated pattern:
+//
+//   _                      matches anything
+//   (dict :key pattern ...) a dict with at least these keys. Other keys are ignored.
+//   (list pattern ...)     a list with exactly these items
+//   '(pattern ...)         the same, written as a quote
+//   (tuple pattern ...)    a tuple with exactly these items
+//   anything else          evaluated, and compared with =
+//
+// The first sub-pattern which fails is reported with its path.
+
+/// How many differences `assert-eq` shows.
+const MAX_DIFFS: usize = 5;
+
+#[derive(Default)]
+struct Differ {
+    shown: Vec<String>,
+    total: usize,
+}
+
+impl Differ {
+    fn difference(&mut self, path: &[Expr], message: String) {
+        self.total += 1;
+        if self.shown.len() < MAX_DIFFS {
+            self.shown.push(format!("{}: {}", describe(path), message));
+        }
+    }
+
+    fn diff(&mut self, expected: &Expr, actual: &Expr, path: &mut Vec<Expr>) {
+        if expected == actual {
+            return;
+        }
+        match (expected, actual) {
+            (Expr::Dict(l), Expr::Dict(r)) => {
+                // Sorted so differences are reported in a stable order.
+                let keys = l
+                    .keys()
+                    .chain(r.keys().filter(|k| !l.contains_key(*k)))
+                    .sorted_by_key(|k| format!("{:?}", k));
+                for key in keys {
+                    path.push(key.clone());
+                    match (l.get(key), r.get(key)) {
+                        (Some(l), Some(r)) => self.diff(l, r, path),
+                        (Some(l), None) => {
+                            self.difference(path, format!("missing, expected {:?}", l))
+                        }
+                        (None, Some(r)) => self.difference(path, format!("unexpected {:?}", r)),
+                        (None, None) => {}
+                    }
+                    path.pop();
+                }
+            }
+            (Expr::List(l), Expr::List(r))
+            | (Expr::Tuple(l), Expr::Tuple(r))
+            | (Expr::Quote(l), Expr::Quote(r)) => {
+                for i in 0..l.len().max(r.len()) {
+                    path.push(Expr::from(i as i64));
+                    match (l.get(i), r.get(i)) {
+                        (Some(l), Some(r)) => self.diff(l, r, path),
+                        (Some(l), None) => {
+                            self.difference(path, format!("missing, expected {:?}", l))
+                        }
+                        (None, Some(r)) => self.difference(path, format!("unexpected {:?}", r)),
+                        (None, None) => {}
+                    }
+                    path.pop();
+                }
+            }
+            _ => self.difference(path, format!("expected {:?}, got {:?}", expected, actual)),
+        }
+    }
+}
+
+/// Describe where `expected` and `actual` differ, one difference per line.
+pub(crate) fn diff(expected: &Expr, actual: &Expr) -> String {
+    let mut differ = Differ::default();
+    differ.diff(expected, actual, &mut Vec::new());
+    let mut lines = vec![format!(
+        "expected and actual differ in {} place(s):",
+        differ.total
+    )];
+    lines.extend(differ.shown.iter().map(|d| format!("  {}", d)));
+    if differ.total > differ.shown.len() {
+        lines.push(format!(
+            "  ... and {} more",
+            differ.total - differ.shown.len()
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Check `value` against `pattern`, returning why it failed to match, if it did.
+pub(crate) fn match_pattern(
+    pattern: &Expr,
+    value: &Expr,
+    symbol_table: &SymbolTable,
+) -> LispResult<Option<String>> {
+    let mut path = Vec::new();
+    let failure = matches(pattern, value, &mut path, symbol_table)?;
+    Ok(failure.map(|message| format!("{}: {}", describe(&path), message)))
+}
+
+fn found(value: &Expr) -> String {
+    format!("found {} {:?}", value.get_type_str(), value)
+}
+
+/// On failure, `path` is left pointing at the sub-pattern which failed.
+fn matches(
+    pattern: &Expr,
+    value: &Expr,
+    path: &mut Vec<Expr>,
+    symbol_table: &SymbolTable,
+) -> LispResult<Option<String>> {
+    if pattern.is_symbol_underscore() {
+        return Ok(None);
+    }
+    let (kind, items) = match pattern {
+        Expr::Quote(items) => ("list", items.clone()),
+        Expr::List(l) if l.front().is_some_and(|h| h.symbol_matches("dict")) => {
+            return matches_dict(pattern, value, path, symbol_table)
+        }
+        Expr::List(l) if l.front().is_some_and(|h| h.symbol_matches("list")) => {
+            ("list", l.clone().slice(1..))
+        }
+        Expr::List(l) if l.front().is_some_and(|h| h.symbol_matches("tuple")) => {
+            ("tuple", l.clone().slice(1..))
+        }
+        _ => {
+            let expected = pattern.eval(symbol_table)?;
+            if &expected == value {
+                return Ok(None);
+            }
+            return Ok(Some(format!("expected {:?}, {}", expected, found(value))));
+        }
+    };
+    let values = match (kind, value.unmeta()) {
+        ("list", Expr::List(l)) | ("tuple", Expr::Tuple(l)) => l,
+        _ => return Ok(Some(format!("expected a {}, {}", kind, found(value)))),
+    };
+    if values.len() != items.len() {
+        return Ok(Some(format!(
+            "expected a {} of {} items, {}",
+            kind,
+            items.len(),
+            found(value)
+        )));
+    }
+    for (i, (item, value)) in items.iter().zip(values.iter()).enumerate() {
+        path.push(Expr::from(i as i64));
+        if let Some(failure) = matches(item, value, path, symbol_table)? {
+            return Ok(Some(failure));
+        }
+        path.pop();
+    }
+    Ok(None)
+}
+
+fn matches_dict(
+    pattern: &Expr,
+    value: &Expr,
+    path: &mut Vec<Expr>,
+    symbol_table: &SymbolTable,
+) -> LispResult<Option<String>> {
+    let fields = pattern.get_list()?.slice(1..);
+    anyhow::ensure!(
+        fields.len() % 2 == 0,
+        "Dict patterns need a pattern for every key, but was given {:?}",
+        pattern
+    );
+    let dict = match value.unmeta() {
+        Expr::Dict(dict) => dict,
+        _ => return Ok(Some(format!("expected a dict, {}", found(value)))),
+    };
+    for (key, field) in fields.iter().tuples() {
+        let key = key.eval(symbol_table)?;
+        path.push(key.clone());
+        let failure = match dict.get(&key) {
+            Some(value) => matches(field, value, path, symbol_table)?,
+            None => Some(format!("missing key, found {:?}", value)),
+        };
+        if failure.is_some() {
+            return Ok(failure);
+        }
+        path.pop();
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Options;
+    use crate::parser::read;
+    use crate::stdlib::create_stdlib_symbol_table;
+
+    fn eval(prog: &str) -> Expr {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        read(prog).next().unwrap().unwrap().eval(&syms).unwrap()
+    }
+
+    #[test]
+    fn diff_names_subpaths() {
+        let expected = eval("(dict :status 200 :body (dict :items '(1 2 3) :next 0))");
+        let actual = eval("(dict :status 404 :body (dict :items '(1 5) :extra true))");
+        assert_eq!(
+            diff(&expected, &actual),
+            "expected and actual differ in 5 place(s):
+  :body :extra: unexpected true
+  :body :items 1: expected 2, got 5
+  :body :items 2: missing, expected 3
+  :body :next: missing, expected 0
+  :status: expected 200, got 404"
+        );
+        assert_eq!(
+            diff(&eval("1"), &eval("\"1\"")),
+            "expected and actual differ in 1 place(s):\n  top level: expected 1, got \"1\""
+        );
+    }
+
+    #[test]
+    fn diff_is_budgeted() {
+        let report = diff(&eval("(range 100)"), &eval("(map inc (range 100))"));
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "expected and actual differ in 100 place(s):");
+        assert_eq!(lines[1], "  0: expected 0, got 1");
+        assert_eq!(lines.len(), MAX_DIFFS + 2);
+        assert_eq!(lines[MAX_DIFFS + 1], "  ... and 95 more");
+    }
+}
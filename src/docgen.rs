@@ -0,0 +1,228 @@
+use crate::annotations::{parse_params, Annotations};
+use crate::parser::read_with_comments;
+use crate::stdlib::CATEGORIES;
+use crate::symbols::{Expr, LispResult, SymbolTable};
+use anyhow::Context;
+use glob::glob;
+use std::fs;
+use std::path::Path;
+
+// `x7 docgen <paths>` renders Markdown docs for the top level `defn`s in
+// x7 source files.
+//
+// Docs come from a definition's docstring, or else from the `;;` comment
+// block directly above it (see `parser::read_with_comments`). Definitions
+// whose names start with `_` are private and left out.
+//
+// `x7 docgen --builtins` instead renders the builtins of the stdlib, grouped
+// by the category each was registered with.
+
+/// A documented top level definition.
+#[derive(Debug, PartialEq)]
+struct Definition {
+    name: String,
+    /// The parameter list as written, annotations included.
+    params: String,
+    /// The return type keyword, if annotated.
+    ret: Option<String>,
+    /// e.g. `(num num) -> num`, for annotated definitions.
+    signature: Option<String>,
+    doc: Option<String>,
+}
+
+/// The definition `form` makes, if it is a public `defn`.
+fn definition(form: &Expr) -> LispResult<Option<Definition>> {
+    let list = match form {
+        Expr::List(l) if !l.is_empty() => l,
+        _ => return Ok(None),
+    };
+    // Forms after a `;#line` directive are wrapped to carry their location.
+    if list[0].symbol_matches("with-location") && list.len() == 5 {
+        return definition(&list[4]);
+    }
+    if !list[0].symbol_matches("defn") || list.len() < 4 {
+        return Ok(None);
+    }
+    let name = list[1].get_symbol_string()?;
+    if name.starts_with('_') {
+        return Ok(None);
+    }
+    let mut rest = list.clone().slice(2..);
+    let doc = match rest.front() {
+        Some(Expr::String(doc)) => {
+            let doc = doc.clone();
+            rest.pop_front();
+            Some(doc)
+        }
+        _ => None,
+    };
+    let params = rest.pop_front().unwrap();
+    let ret = match (rest.len(), rest.front()) {
+        (2, Some(ret)) if ret.is_keyword() => Some(ret.get_symbol_string()?),
+        _ => None,
+    };
+    let (names, types) = parse_params(&params.get_list()?)
+        .with_context(|| format!("Bad parameters for {}", name))?;
+    let annotations = Annotations::new(types, ret.as_ref().map(|ret| ret[1..].to_string()));
+    let signature = if annotations.is_empty() {
+        None
+    } else {
+        let names: Vec<Expr> = names.into_iter().collect();
+        Some(annotations.signature(&names))
+    };
+    Ok(Some(Definition {
+        name,
+        params: format!("{:?}", params),
+        ret,
+        signature,
+        doc,
+    }))
+}
+
+fn definitions(source: &str) -> LispResult<Vec<Definition>> {
+    let mut defs = Vec::new();
+    for form in read_with_comments(source).keeping_reader_tags() {
+        if let Some(def) = definition(&form?)? {
+            defs.push(def);
+        }
+    }
+    Ok(defs)
+}
+
+/// Render the definitions in one file as a Markdown section.
+fn render_file(file: &str, defs: &[Definition]) -> String {
+    let mut out = format!("## {}\n", file);
+    for def in defs {
+        out.push_str(&format!("\n### `{}`\n\n", def.name));
+        let ret = def.ret.as_ref().map(|ret| format!(" {}", ret));
+        out.push_str(&format!(
+            "`(defn {} {}{})`\n",
+            def.name,
+            def.params,
+            ret.unwrap_or_default()
+        ));
+        if let Some(signature) = &def.signature {
+            out.push_str(&format!("\nType: `{}`\n", signature));
+        }
+        if let Some(doc) = &def.doc {
+            out.push_str(&format!("\n{}\n", doc));
+        }
+    }
+    out
+}
+
+/// The `.x7` files under `paths`, sorted. Files are taken as given, and
+/// glob patterns are expanded, as Windows shells leave that to programs.
+pub(crate) fn source_files(paths: &[String]) -> LispResult<Vec<String>> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.contains(['*', '?', '[']) {
+            let expanded = glob(path)?
+                .map(|entry| entry.map(|p| p.to_string_lossy().into_owned()))
+                .collect::<Result<Vec<_>, _>>()?;
+            files.extend(source_files(&expanded)?);
+        } else if Path::new(path).is_dir() {
+            let pattern = format!("{}/**/*.x7", path.trim_end_matches('/'));
+            for entry in glob(&pattern)? {
+                files.push(entry?.to_string_lossy().into_owned());
+            }
+        } else {
+            files.push(path.clone());
+        }
+    }
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// Markdown docs for the public definitions in `paths`, grouped by file.
+/// Directories are searched for `.x7` files.
+pub fn docgen(paths: &[String]) -> LispResult<String> {
+    let mut sections = vec!["# API\n".to_string()];
+    for file in source_files(paths)? {
+        let source =
+            fs::read_to_string(&file).with_context(|| format!("Could not read {}", file))?;
+        let defs = definitions(&source).with_context(|| format!("In {}", file))?;
+        if !defs.is_empty() {
+            sections.push(render_file(&file, &defs));
+        }
+    }
+    Ok(sections.join("\n"))
+}
+
+/// Render one builtin, with its examples as a code block.
+fn render_builtin(name: &str, minargs: usize, doc: &str) -> String {
+    let mut out = format!("\n### `{}`\n\n", name);
+    let plural = if minargs == 1 { "" } else { "s" };
+    out.push_str(&format!("Takes at least {} argument{}.\n", minargs, plural));
+    let (text, example) = match doc.find("Example:") {
+        Some(i) => (&doc[..i], Some(&doc[i + "Example:".len()..])),
+        None => (doc, None),
+    };
+    if !text.trim().is_empty() {
+        out.push_str(&format!("\n{}\n", text.trim()));
+    }
+    if let Some(example) = example {
+        out.push_str(&format!("\n```\n{}\n```\n", example.trim()));
+    }
+    out
+}
+
+/// Markdown docs for the builtins in `syms`, grouped by category.
+pub fn builtins_reference(syms: &SymbolTable) -> String {
+    let mut sections = vec!["# Builtins\n".to_string()];
+    let names = syms.get_canonical_doc_order();
+    for category in CATEGORIES {
+        let mut out = format!("## {}\n", category);
+        for name in names.iter() {
+            if syms.get_category(name) != Some(*category) {
+                continue;
+            }
+            let minargs = syms
+                .lookup(&Expr::Symbol(name.clone()))
+                .and_then(|f| f.get_function())
+                .map_or(0, |f| f.minimum_args());
+            let doc = syms.get_doc_item(name).unwrap_or_default();
+            out.push_str(&render_builtin(name, minargs, &doc));
+        }
+        sections.push(out);
+    }
+    sections.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_public_definitions() {
+        let defs = definitions(
+            ";; Doubles x.
+(defn double (x :num) :num (* 2 x))
+(defn _helper (x) x)
+(defn plain \"Has a docstring.\" (a & rest) a)
+(def not-a-fn 1)
+(def timeout #duration\"5m\")",
+        )
+        .unwrap();
+        assert_eq!(
+            defs,
+            vec![
+                Definition {
+                    name: "double".into(),
+                    params: "(x :num)".into(),
+                    ret: Some(":num".into()),
+                    signature: Some("(num) -> num".into()),
+                    doc: Some("Doubles x.".into()),
+                },
+                Definition {
+                    name: "plain".into(),
+                    params: "(a & rest)".into(),
+                    ret: None,
+                    signature: None,
+                    doc: Some("Has a docstring.".into()),
+                },
+            ]
+        );
+    }
+}
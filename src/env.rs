@@ -0,0 +1,388 @@
+use crate::access;
+use crate::stdlib::register_builtins;
+use crate::symbols::{Dict, Expr, LispResult, SymbolTable};
+use anyhow::{anyhow, bail, ensure, Context};
+use im::Vector;
+use std::ffi::OsString;
+
+// Builtins for the process environment. Variables are shared by the whole
+// process, so setting one is seen by everything else running in it, and is
+// checked against the sandbox and access hook like file access.
+
+/// Parse the value after `KEY=`. Double quoted values take `\n`, `\t`, `\"`,
+/// and `\\` escapes, single quoted ones are taken as written, and unquoted
+/// ones end at a `#` comment.
+fn dotenv_value(raw: &str) -> LispResult<String> {
+    let raw = raw.trim();
+    let quote = match raw.chars().next() {
+        Some(q @ '"') | Some(q @ '\'') => q,
+        _ => {
+            let end = raw.find(" #").or_else(|| raw.find("\t#"));
+            return Ok(raw[..end.unwrap_or(raw.len())].trim_end().into());
+        }
+    };
+    let mut value = String::new();
+    let mut chars = raw[1..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            c if c == quote => {
+                let rest = raw[i + 2..].trim_start();
+                ensure!(
+                    rest.is_empty() || rest.starts_with('#'),
+                    "unexpected {} after the closing quote",
+                    rest
+                );
+                return Ok(value);
+            }
+            '\\' if quote == '"' => match chars.next().map(|(_, c)| c) {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some(c @ '"') | Some(c @ '\\') => value.push(c),
+                Some(c) => {
+                    value.push('\\');
+                    value.push(c);
+                }
+                None => break,
+            },
+            c => value.push(c),
+        }
+    }
+    bail!("missing the closing {}", quote)
+}
+
+/// The KEY=VALUE pairs of a .env file, in order. Blank lines and `#`
+/// comments are skipped, and keys may be prefixed with `export`.
+pub(crate) fn parse_dotenv(source: &str) -> LispResult<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+    for (n, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = match line.find('=') {
+            Some(i) => (line[..i].trim(), &line[i + 1..]),
+            None => bail!("line {}: expected KEY=VALUE, got {}", n + 1, line),
+        };
+        let valid_key = key
+            .chars()
+            .enumerate()
+            .all(|(i, c)| c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()));
+        ensure!(
+            !key.is_empty() && valid_key,
+            "line {}: {:?} is not a valid variable name",
+            n + 1,
+            key
+        );
+        let value = dotenv_value(value).with_context(|| format!("line {}", n + 1))?;
+        vars.push((key.to_string(), value));
+    }
+    Ok(vars)
+}
+
+/// (load-dotenv path [:override true])
+pub(crate) fn load_dotenv(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let override_existing = match exprs.len() {
+        1 => false,
+        3 if exprs[1].symbol_matches(":override") => exprs[2].get_bool()?,
+        _ => bail!(
+            "load-dotenv takes a path, and optionally :override true, but was given {}",
+            Expr::List(exprs)
+        ),
+    };
+    let path = exprs[0].get_string()?;
+    let source = access::read_to_string(symbol_table, "load-dotenv", &path)?;
+    let vars = parse_dotenv(&source).with_context(|| format!("Could not parse {}", path))?;
+    // Check everything first, so a denied variable leaves the rest untouched.
+    let mut set: Vec<(String, String)> = Vec::new();
+    for (key, value) in vars {
+        let chosen = set.iter().position(|(k, _)| *k == key);
+        let exists =
+            chosen.is_some() || access::env_var(symbol_table, "load-dotenv", &key)?.is_some();
+        if exists && !override_existing {
+            continue;
+        }
+        access::check_set_env(symbol_table, "load-dotenv", &key, Some(&value))?;
+        match chosen {
+            Some(i) => set[i].1 = value,
+            None => set.push((key, value)),
+        }
+    }
+    for (key, value) in set.iter() {
+        std::env::set_var(key, value);
+    }
+    Ok(Expr::Dict(
+        set.into_iter()
+            .map(|(key, value)| (Expr::String(key), Expr::String(value)))
+            .collect(),
+    ))
+}
+
+/// (env-or key default)
+pub(crate) fn env_or(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    crate::exact_len!(exprs, 2);
+    let key = exprs[0].get_string()?;
+    Ok(match access::env_var(symbol_table, "env-or", &key)? {
+        Some(value) => Expr::String(value),
+        None => exprs[1].clone(),
+    })
+}
+
+/// The variables for with-env: string keys, and string values or nil to unset.
+fn env_changes(vars: &Dict) -> LispResult<Vec<(String, Option<String>)>> {
+    let mut changes = Vec::new();
+    for (key, value) in vars.iter() {
+        let key = match key {
+            Expr::String(key) => key.clone(),
+            _ => bail!("with-env expects string keys, but was given {}", key),
+        };
+        let value = match value {
+            Expr::String(value) => Some(value.clone()),
+            Expr::Nil => None,
+            _ => bail!(
+                "with-env expects strings, or nil to unset a variable, but {} was given {}",
+                key,
+                value
+            ),
+        };
+        changes.push((key, value));
+    }
+    // Dicts are unordered, so sort to change them in a predictable order.
+    changes.sort();
+    Ok(changes)
+}
+
+fn set_or_remove(key: &str, value: Option<OsString>) {
+    match value {
+        Some(value) => std::env::set_var(key, value),
+        None => std::env::remove_var(key),
+    }
+}
+
+/// (with-env vars body...)
+pub(crate) fn with_env(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    ensure!(
+        exprs.len() >= 2,
+        "with-env expects a dict of variables and a body, like (with-env (dict \"KEY\" \"value\") (run))"
+    );
+    let vars = exprs[0].eval(symbol_table)?;
+    let vars = vars.get_dict().map_err(|_| {
+        anyhow!(
+            "with-env expects a dict of variables, but was given {}",
+            vars
+        )
+    })?;
+    let changes = env_changes(&vars)?;
+    // Check everything first, so a denied variable leaves the rest untouched.
+    for (key, value) in changes.iter() {
+        access::check_set_env(symbol_table, "with-env", key, value.as_deref())?;
+    }
+    let prior: Vec<(String, Option<OsString>)> = changes
+        .iter()
+        .map(|(key, _)| (key.clone(), std::env::var_os(key)))
+        .collect();
+    for (key, value) in changes {
+        set_or_remove(&key, value.map(OsString::from));
+    }
+    let res = exprs
+        .iter()
+        .skip(1)
+        .try_fold(Expr::Nil, |_, expr| expr.eval(symbol_table));
+    // Restore even if the body failed. Nested with-envs restore innermost
+    // first, each back to what it saw on entry.
+    for (key, value) in prior.into_iter().rev() {
+        set_or_remove(&key, value);
+    }
+    res
+}
+
+pub(crate) fn register(syms: &SymbolTable) {
+    register_builtins(
+        syms,
+        &[
+            (
+                "load-dotenv",
+                "io",
+                1,
+                load_dotenv,
+                true,
+                "Set environment variables from a .env file of KEY=VALUE lines, returning a dict of what was set.
+Values may be quoted, lines may start with `export`, and `#` starts a comment.
+Variables which are already set are kept, unless given `:override true`.
+Example:
+(load-dotenv \".env\") ; {\"API_KEY\": \"abc123\"}
+(load-dotenv \".env.test\" :override true)
+",
+            ),
+            (
+                "env-or",
+                "io",
+                2,
+                env_or,
+                true,
+                "Get an environment variable, or the default if it isn't set.
+Example:
+(env-or \"PORT\" \"8080\") ; \"8080\"
+",
+            ),
+            (
+                "with-env",
+                "io",
+                2,
+                with_env,
+                false,
+                "Evaluate a body with environment variables set, restoring their old values afterwards, even on error.
+Variables set to nil are unset for the body, and ones which weren't set before are unset again.
+Example:
+(with-env (dict \"API_KEY\" \"test\") (env-or \"API_KEY\" nil)) ; \"test\"
+",
+            ),
+        ],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::Decision;
+    use crate::cli::Options;
+    use crate::parser::read;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use crate::symbols::ProgramError;
+
+    fn eval(prog: &str, syms: &SymbolTable) -> LispResult<Expr> {
+        let mut res = Expr::Nil;
+        for expr in read(prog) {
+            res = expr?.eval(syms)?;
+        }
+        Ok(res)
+    }
+
+    fn string(s: &str) -> Expr {
+        Expr::String(s.into())
+    }
+
+    #[test]
+    fn dotenv_parsing() {
+        let source = "# settings
+export API_KEY=abc123
+PLAIN = some value # a comment
+DOUBLE=\"two\\nlines \\\"quoted\\\"\" # trailing
+SINGLE='as $written\\n'
+EMPTY=
+HASH=a#b
+";
+        assert_eq!(
+            parse_dotenv(source).unwrap(),
+            vec![
+                ("API_KEY".into(), "abc123".into()),
+                ("PLAIN".into(), "some value".into()),
+                ("DOUBLE".into(), "two\nlines \"quoted\"".into()),
+                ("SINGLE".into(), "as $written\\n".into()),
+                ("EMPTY".into(), "".into()),
+                ("HASH".into(), "a#b".into()),
+            ]
+        );
+        let err = parse_dotenv("A=1\nnot a pair").unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+        assert!(parse_dotenv("1ABC=x").is_err());
+        assert!(parse_dotenv("A=\"unterminated").is_err());
+        assert!(parse_dotenv("A=\"x\" y").is_err());
+    }
+
+    #[test]
+    fn loading_dotenv_files() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let path = std::env::temp_dir().join(format!("x7-{}.env", rand::random::<u64>()));
+        std::fs::write(
+            &path,
+            "X7_DOTENV_TEST_NEW=fresh\nX7_DOTENV_TEST_KEPT=from-file\n",
+        )
+        .unwrap();
+        std::env::set_var("X7_DOTENV_TEST_KEPT", "original");
+        let path = path.to_string_lossy().into_owned();
+
+        let set = eval(&format!("(load-dotenv \"{}\")", path), &syms).unwrap();
+        let expected: Dict = vec![(string("X7_DOTENV_TEST_NEW"), string("fresh"))]
+            .into_iter()
+            .collect();
+        assert_eq!(set, Expr::Dict(expected));
+        assert_eq!(std::env::var("X7_DOTENV_TEST_KEPT").unwrap(), "original");
+        let res = eval(&format!("(load-dotenv \"{}\" :override true)", path), &syms);
+        assert_eq!(res.unwrap().get_dict().unwrap().len(), 2);
+        assert_eq!(std::env::var("X7_DOTENV_TEST_KEPT").unwrap(), "from-file");
+        assert_eq!(
+            eval("(env-or \"X7_DOTENV_TEST_NEW\" nil)", &syms).unwrap(),
+            string("fresh")
+        );
+        assert_eq!(
+            eval("(env-or \"X7_DOTENV_TEST_MISSING\" 8080)", &syms).unwrap(),
+            Expr::from(8080)
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn with_env_restores_variables() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        std::env::set_var("X7_WITH_ENV_TEST_SET", "outer");
+        std::env::remove_var("X7_WITH_ENV_TEST_NEW");
+        let prog = "(with-env (dict \"X7_WITH_ENV_TEST_SET\" \"a\" \"X7_WITH_ENV_TEST_NEW\" \"b\")
+                      (list (env-or \"X7_WITH_ENV_TEST_SET\" nil)
+                            (with-env (dict \"X7_WITH_ENV_TEST_SET\" \"c\" \"X7_WITH_ENV_TEST_NEW\" nil)
+                              (list (env-or \"X7_WITH_ENV_TEST_SET\" nil)
+                                    (env-or \"X7_WITH_ENV_TEST_NEW\" :unset)))
+                            (env-or \"X7_WITH_ENV_TEST_NEW\" nil)))";
+        assert_eq!(
+            format!("{:?}", eval(prog, &syms).unwrap()),
+            "(\"a\" (\"c\" :unset) \"b\")"
+        );
+        assert_eq!(std::env::var("X7_WITH_ENV_TEST_SET").unwrap(), "outer");
+        assert!(std::env::var_os("X7_WITH_ENV_TEST_NEW").is_none());
+
+        // Errors in the body still restore.
+        let prog = "(with-env (dict \"X7_WITH_ENV_TEST_SET\" \"a\") (err \"boom\"))";
+        assert!(eval(prog, &syms).is_err());
+        assert_eq!(std::env::var("X7_WITH_ENV_TEST_SET").unwrap(), "outer");
+
+        assert!(eval("(with-env (dict \"A=B\" \"x\") 1)", &syms).is_err());
+        assert!(eval("(with-env (dict :key \"x\") 1)", &syms).is_err());
+        assert!(eval("(with-env (dict \"X7_WITH_ENV_TEST_SET\" 1) 1)", &syms).is_err());
+    }
+
+    #[test]
+    fn env_access_is_checked() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        std::env::set_var("X7_ENV_ACCESS_TEST", "before");
+        syms.on_io_access(|op| {
+            if op.kind == access::IoKind::SetEnv && op.target == "X7_ENV_ACCESS_TEST_DENIED" {
+                Decision::Deny
+            } else {
+                Decision::Allow
+            }
+        });
+        let prog = "(with-env (dict \"X7_ENV_ACCESS_TEST\" \"after\" \"X7_ENV_ACCESS_TEST_DENIED\" \"x\") 1)";
+        let err = eval(prog, &syms).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ProgramError>(),
+            Some(&ProgramError::Permission)
+        );
+        // Nothing was set, even the allowed variable.
+        assert_eq!(std::env::var("X7_ENV_ACCESS_TEST").unwrap(), "before");
+
+        // The same goes for load-dotenv.
+        let path = std::env::temp_dir().join(format!("x7-{}.env", rand::random::<u64>()));
+        std::fs::write(
+            &path,
+            "X7_ENV_ACCESS_TEST=after\nX7_ENV_ACCESS_TEST_DENIED=x\n",
+        )
+        .unwrap();
+        let prog = format!("(load-dotenv {:?} :override true)", path.to_string_lossy());
+        assert!(eval(&prog, &syms).is_err());
+        assert_eq!(std::env::var("X7_ENV_ACCESS_TEST").unwrap(), "before");
+        let _ = std::fs::remove_file(&path);
+
+        syms.set_sandboxed(true);
+        assert!(eval("(env-or \"X7_ENV_ACCESS_TEST\" nil)", &syms).is_err());
+    }
+}
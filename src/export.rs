@@ -0,0 +1,261 @@
+//! Registering a module of Rust functions at once, with [`export_module!`].
+//!
+//! Each function's arguments are converted from x7 values with `TryFrom<&Expr>`,
+//! and its result back with `Into<Expr>`, so the Rust signature is all that
+//! says what a function takes and returns. A signature with an argument or
+//! result that can't be converted, or that doesn't take as many arguments as
+//! declared, fails to compile.
+//!
+//! [`export_module!`]: crate::export_module
+use crate::symbols::{Dict, Expr, LispResult, Num, ProgramError, SymbolTable};
+use anyhow::{anyhow, bail, ensure, Context};
+use bigdecimal::ToPrimitive;
+use im::Vector;
+use std::convert::TryFrom;
+
+/// Register Rust functions in `interpreter`, each named `module/name`.
+///
+/// Each function is given as `"name" => arity => path`, with any doc
+/// comments or `#[doc = "..."]` attributes before it as its docstring:
+///
+/// ```ignore
+/// x7::export_module!(interpreter, "app", {
+///     /// Look up a user by id.
+///     "get-user" => 1 => get_user,
+///     "save!" => 2 => save_user,
+/// });
+/// ```
+#[macro_export]
+macro_rules! export_module {
+    ($interpreter:expr, $module:literal, {
+        $($(#[doc = $doc:expr])* $name:literal => $arity:literal => $f:path),* $(,)?
+    }) => {{
+        let interpreter: &$crate::SymbolTable = &$interpreter;
+        $(
+            let doc: &[&str] = &[$($doc),*];
+            $crate::export::export_fn::<$arity, _, _>(
+                interpreter,
+                concat!($module, "/", $name),
+                doc,
+                $f,
+            );
+        )*
+    }};
+}
+
+/// A Rust function taking `N` arguments converted from x7 values.
+pub trait ExportFn<const N: usize, Args> {
+    fn call_with(&self, args: &Vector<Expr>) -> LispResult<Expr>;
+}
+
+/// What exported functions may return: a value, or a result with one.
+pub trait ExportResult {
+    fn into_result(self) -> LispResult<Expr>;
+}
+
+impl<T: Into<Expr>> ExportResult for T {
+    fn into_result(self) -> LispResult<Expr> {
+        Ok(self.into())
+    }
+}
+
+impl<T: Into<Expr>> ExportResult for LispResult<T> {
+    fn into_result(self) -> LispResult<Expr> {
+        self.map(Into::into)
+    }
+}
+
+/// Convert argument `index` of `args`, counting from 1 in errors.
+fn arg<A>(args: &Vector<Expr>, index: usize) -> LispResult<A>
+where
+    A: for<'a> TryFrom<&'a Expr, Error = anyhow::Error>,
+{
+    A::try_from(&args[index]).with_context(|| format!("argument {}", index + 1))
+}
+
+macro_rules! export_fn_impl {
+    ($n:literal; $($arg:ident $index:literal),*) => {
+        impl<F, $($arg,)* R> ExportFn<$n, ($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R,
+            $($arg: for<'a> TryFrom<&'a Expr, Error = anyhow::Error>,)*
+            R: ExportResult,
+        {
+            fn call_with(&self, args: &Vector<Expr>) -> LispResult<Expr> {
+                ensure!(args.len() == $n, ProgramError::WrongNumberOfArgs($n));
+                self($(arg::<$arg>(args, $index)?),*).into_result()
+            }
+        }
+    };
+}
+
+export_fn_impl!(0;);
+export_fn_impl!(1; A 0);
+export_fn_impl!(2; A 0, B 1);
+export_fn_impl!(3; A 0, B 1, C 2);
+export_fn_impl!(4; A 0, B 1, C 2, D 3);
+export_fn_impl!(5; A 0, B 1, C 2, D 3, E 4);
+
+/// Register `f` as `name`, with the lines of `doc` as its docstring.
+#[doc(hidden)]
+pub fn export_fn<const N: usize, Args, F>(
+    interpreter: &SymbolTable,
+    name: &'static str,
+    doc: &[&str],
+    f: F,
+) where
+    F: ExportFn<N, Args> + Sync + Send + 'static,
+{
+    // Doc comments keep the space after `///`.
+    let doc = doc
+        .iter()
+        .map(|line| line.strip_prefix(' ').unwrap_or(line))
+        .collect::<Vec<_>>()
+        .join("\n");
+    interpreter.add_function(name, N, &doc, move |args, _| {
+        f.call_with(&args).with_context(|| format!("in {}", name))
+    });
+}
+
+impl TryFrom<&Expr> for Expr {
+    type Error = anyhow::Error;
+
+    fn try_from(expr: &Expr) -> LispResult<Expr> {
+        Ok(expr.clone())
+    }
+}
+
+impl TryFrom<&Expr> for Num {
+    type Error = anyhow::Error;
+
+    fn try_from(expr: &Expr) -> LispResult<Num> {
+        expr.get_num()
+    }
+}
+
+impl TryFrom<&Expr> for i64 {
+    type Error = anyhow::Error;
+
+    fn try_from(expr: &Expr) -> LispResult<i64> {
+        let n = expr.get_num()?;
+        if !expr.is_int()? {
+            bail!("Expected an integer, but was given {}", n);
+        }
+        n.to_i64()
+            .ok_or_else(|| anyhow!("Cannot represent {} as it needs to fit in an i64", n))
+    }
+}
+
+impl TryFrom<&Expr> for usize {
+    type Error = anyhow::Error;
+
+    fn try_from(expr: &Expr) -> LispResult<usize> {
+        expr.get_usize()
+    }
+}
+
+impl TryFrom<&Expr> for f64 {
+    type Error = anyhow::Error;
+
+    fn try_from(expr: &Expr) -> LispResult<f64> {
+        let n = expr.get_num()?;
+        n.to_f64()
+            .ok_or_else(|| anyhow!("Cannot represent {} as an f64", n))
+    }
+}
+
+impl TryFrom<&Expr> for bool {
+    type Error = anyhow::Error;
+
+    fn try_from(expr: &Expr) -> LispResult<bool> {
+        expr.get_bool()
+    }
+}
+
+impl TryFrom<&Expr> for String {
+    type Error = anyhow::Error;
+
+    fn try_from(expr: &Expr) -> LispResult<String> {
+        expr.get_string()
+    }
+}
+
+impl TryFrom<&Expr> for Vector<Expr> {
+    type Error = anyhow::Error;
+
+    fn try_from(expr: &Expr) -> LispResult<Vector<Expr>> {
+        expr.get_list()
+    }
+}
+
+impl TryFrom<&Expr> for Dict {
+    type Error = anyhow::Error;
+
+    fn try_from(expr: &Expr) -> LispResult<Dict> {
+        expr.get_dict()
+    }
+}
+
+impl<T> TryFrom<&Expr> for Option<T>
+where
+    T: for<'a> TryFrom<&'a Expr, Error = anyhow::Error>,
+{
+    type Error = anyhow::Error;
+
+    fn try_from(expr: &Expr) -> LispResult<Option<T>> {
+        match expr.unmeta() {
+            Expr::Nil => Ok(None),
+            other => T::try_from(other).map(Some),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::Options;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use crate::symbols::{Expr, LispResult, Num};
+
+    fn area(w: i64, h: i64) -> i64 {
+        w * h
+    }
+
+    fn greet(name: Option<String>) -> String {
+        format!("hello {}", name.as_deref().unwrap_or("you"))
+    }
+
+    fn halve(n: Num) -> LispResult<Num> {
+        anyhow::ensure!(n >= Num::from(0), "can't halve {}", n);
+        Ok(n / Num::from(2))
+    }
+
+    #[test]
+    fn arguments_convert_from_the_signature() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        crate::export_module!(syms, "geo", {
+            /// Area of a rectangle.
+            "area" => 2 => area,
+            "greet" => 1 => greet,
+            "halve" => 1 => halve,
+        });
+        assert_eq!(syms.eval_source("(geo/area 3 4)").unwrap(), Expr::from(12));
+        assert_eq!(
+            syms.eval_source("(geo/greet nil)").unwrap(),
+            Expr::from("hello you")
+        );
+        assert_eq!(
+            syms.eval_source("(geo/halve 3)").unwrap(),
+            syms.eval_source("1.5").unwrap()
+        );
+        assert_eq!(
+            syms.get_doc_item("geo/area").unwrap(),
+            "Area of a rectangle."
+        );
+
+        let err = |prog| format!("{:#}", syms.eval_source(prog).unwrap_err());
+        assert!(err("(geo/area 3 4.5)").contains("in geo/area: argument 2"));
+        assert!(err("(geo/area 3 \"4\")").contains("argument 2"));
+        assert!(err("(geo/area 3 4 5)").contains("WrongNumberOfArgs"));
+        assert!(err("(geo/halve -1)").contains("can't halve -1"));
+    }
+}
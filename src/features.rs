@@ -0,0 +1,206 @@
+use crate::exact_len;
+use crate::symbols::{Expr, LispResult, SymbolTable};
+use anyhow::{bail, ensure};
+use im::Vector;
+
+// The cargo features x7 was built with, for library code which adapts to
+// them. `cond-expand` picks code by feature before evaluating it, so code for
+// missing features can name builtins which don't exist in this build.
+
+/// Every optional cargo feature, and whether this build has it.
+const FEATURES: &[(&str, bool)] = &[
+    ("io", cfg!(feature = "io")),
+    ("regex", cfg!(feature = "regex")),
+    ("time", cfg!(feature = "time")),
+    ("shell", cfg!(feature = "shell")),
+    ("http", cfg!(feature = "http")),
+    ("compression", cfg!(feature = "compression")),
+    ("json", cfg!(feature = "json")),
+    ("toml", cfg!(feature = "toml")),
+    ("yaml", cfg!(feature = "yaml")),
+    ("watch", cfg!(feature = "watch")),
+    ("remote-repl", cfg!(feature = "remote-repl")),
+];
+
+/// Whether this build has the feature named by `keyword`, like `:json`.
+fn has_feature(what: &str, keyword: &Expr) -> LispResult<bool> {
+    let name = keyword.get_symbol_string()?;
+    match FEATURES
+        .iter()
+        .find(|(f, _)| name.strip_prefix(':') == Some(*f))
+    {
+        Some((_, enabled)) => Ok(*enabled),
+        None => bail!(
+            "{} doesn't know the feature {}, it knows {}",
+            what,
+            name,
+            FEATURES
+                .iter()
+                .map(|(f, _)| format!(":{}", f))
+                .collect::<Vec<_>>()
+                .join(" ")
+        ),
+    }
+}
+
+/// (feature? :json)
+pub(crate) fn is_feature(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    has_feature("feature?", &exprs[0]).map(Expr::Bool)
+}
+
+/// (features)
+pub(crate) fn features(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 0);
+    Ok(Expr::List(
+        FEATURES
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(f, _)| Expr::Symbol(format!(":{}", f)))
+            .collect(),
+    ))
+}
+
+/// Whether a cond-expand requirement, like `(and :json (not :http))`, holds.
+fn satisfied(requirement: &Expr) -> LispResult<bool> {
+    let list = match requirement {
+        Expr::Symbol(s) if s == ":else" => return Ok(true),
+        Expr::Symbol(_) => return has_feature("cond-expand", requirement),
+        Expr::List(l) if !l.is_empty() => l,
+        other => bail!(
+            "cond-expand expects a feature like :json, or (and ...) (or ...) (not ...), but was given {}",
+            other
+        ),
+    };
+    let args = list.clone().slice(1..);
+    match list[0].get_symbol_string()?.as_str() {
+        "and" => args
+            .iter()
+            .try_fold(true, |all, r| Ok(all && satisfied(r)?)),
+        "or" => args
+            .iter()
+            .try_fold(false, |any, r| Ok(any || satisfied(r)?)),
+        "not" => {
+            ensure!(
+                args.len() == 1,
+                "cond-expand's not takes one requirement, but was given {}",
+                requirement
+            );
+            Ok(!satisfied(&args[0])?)
+        }
+        other => bail!(
+            "cond-expand can combine features with and, or, and not, but was given {}",
+            other
+        ),
+    }
+}
+
+/// (cond-expand (requirement body...) ...)
+pub(crate) fn cond_expand(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    // Check every requirement first, so typos in later clauses aren't hidden
+    // by builds which happen to pick an earlier one.
+    let mut chosen = None;
+    for clause in exprs.iter() {
+        let clause = match clause {
+            Expr::List(l) if !l.is_empty() => l,
+            other => bail!(
+                "cond-expand expects clauses like (:json body...), but was given {}",
+                other
+            ),
+        };
+        if satisfied(&clause[0])? && chosen.is_none() {
+            chosen = Some(clause.clone().slice(1..));
+        }
+    }
+    match chosen {
+        Some(body) => body
+            .iter()
+            .try_fold(Expr::Nil, |_, expr| expr.eval(symbol_table)),
+        None => bail!("cond-expand has no clause for this build's features, add an :else clause"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::Options;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use crate::symbols::Expr;
+
+    #[test]
+    fn features_match_the_build() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let json = cfg!(feature = "json");
+        assert_eq!(
+            syms.eval_source("(feature? :json)").unwrap(),
+            Expr::Bool(json)
+        );
+        let features = syms.eval_source("(features)").unwrap().get_list().unwrap();
+        assert_eq!(features.contains(&Expr::Symbol(":json".into())), json);
+        assert!(syms.eval_source("(feature? :jsno)").is_err());
+
+        // Builtins behind a feature only exist, and are documented, in builds with it.
+        for (feature, builtin) in &[
+            ("io", "write-file"),
+            ("regex", "regex"),
+            ("time", "with-retries"),
+            ("shell", "env-or"),
+        ] {
+            let enabled = syms
+                .eval_source(&format!("(feature? :{})", feature))
+                .unwrap();
+            assert_eq!(enabled, Expr::Bool(syms.get_doc_item(builtin).is_some()));
+            assert_eq!(
+                syms.eval_source(builtin).is_ok(),
+                syms.get_doc_item(builtin).is_some()
+            );
+        }
+        assert_eq!(
+            syms.eval_source("(feature? :io)").unwrap(),
+            Expr::Bool(cfg!(feature = "io"))
+        );
+    }
+
+    #[test]
+    fn only_the_chosen_clause_runs() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        // The other clause calls a builtin this build may not have.
+        let prog = "(cond-expand
+          (:json (defn encode (x) (json-serialize x)))
+          (:else (defn encode (x) \"no json\")))
+          (encode 1)";
+        let encoded = syms.eval_source(prog).unwrap();
+        if !cfg!(feature = "json") {
+            assert_eq!(encoded, Expr::from("no json"));
+        }
+
+        let prog = "(cond-expand
+          ((and :json (not :watch)) 1)
+          ((or :json :watch) 2)
+          (:else (undefined-function)))";
+        let expected = match (cfg!(feature = "json"), cfg!(feature = "watch")) {
+            (true, false) => Ok(Expr::from(1)),
+            (_, true) => Ok(Expr::from(2)),
+            (false, false) => Err(()),
+        };
+        assert_eq!(syms.eval_source(prog).map_err(drop), expected);
+    }
+
+    #[test]
+    fn clauses_are_checked() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        for prog in &[
+            "(cond-expand (:htpp 1) (:else 2))",
+            "(cond-expand (:else 1) ((nand :json) 2))",
+            "(cond-expand ((not :json :http) 1))",
+            "(cond-expand :else)",
+            "(cond-expand ((and :json) 1) ((not :json) 2) ((not (or :http :http)) 3) (:nope 4))",
+        ] {
+            assert!(syms.eval_source(prog).is_err(), "{}", prog);
+        }
+        assert_eq!(
+            syms.eval_source("(cond-expand ((or :json (not :json)) 1))")
+                .unwrap(),
+            Expr::from(1)
+        );
+    }
+}
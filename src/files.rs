@@ -0,0 +1,281 @@
+use crate::access;
+use crate::records::{FileRecord, KvRecord};
+use crate::stdlib::register_builtins;
+use crate::symbols::{Expr, LispResult, SymbolTable};
+use anyhow::{bail, ensure};
+use im::Vector;
+use itertools::Itertools;
+use std::fs::OpenOptions;
+use std::path::Path;
+
+// Builtins which write whole files. Each takes a path and a string, then
+// optional `:flag true` pairs. Failures are `Io` errors naming the stage
+// which failed and the path.
+
+/// The `:flag bool` pairs after `what`'s first two arguments. Returns the
+/// value of each of `known`, false when not given.
+fn flags(what: &str, args: &Vector<Expr>, known: &[&str]) -> LispResult<Vec<bool>> {
+    let rest = args.clone().slice(2..);
+    ensure!(
+        rest.len().is_multiple_of(2),
+        "{} takes options as :flag true pairs, but was given {}",
+        what,
+        Expr::List(rest)
+    );
+    let mut values = vec![false; known.len()];
+    for (flag, value) in rest.iter().tuples() {
+        let name = flag.get_symbol_string()?;
+        match known.iter().position(|k| *k == name) {
+            Some(i) => values[i] = value.get_bool()?,
+            None => bail!(
+                "{} has no option {}, it knows {}",
+                what,
+                name,
+                known.join(" ")
+            ),
+        }
+    }
+    Ok(values)
+}
+
+/// (write-file path s [:create-dirs true])
+pub(crate) fn write_file(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let create_dirs = flags("write-file", &exprs, &[":create-dirs"])?[0];
+    let path = exprs[0].get_string()?;
+    let contents = exprs[1].get_string()?;
+    if create_dirs {
+        if let Some(dir) = Path::new(&path).parent() {
+            access::create_dir_all(symbol_table, "write-file", dir)?;
+        }
+    }
+    let mut file = access::create(symbol_table, "write-file", &path)?;
+    access::write_all(&mut file, &path, &contents)?;
+    Ok(Expr::from(contents.len() as i64))
+}
+
+/// (write-file-atomic path s [:fsync true])
+pub(crate) fn write_file_atomic(
+    exprs: Vector<Expr>,
+    symbol_table: &SymbolTable,
+) -> LispResult<Expr> {
+    let fsync = flags("write-file-atomic", &exprs, &[":fsync"])?[0];
+    let path = exprs[0].get_string()?;
+    let contents = exprs[1].get_string()?;
+    access::write_atomic(
+        symbol_table,
+        "write-file-atomic",
+        &path,
+        contents.as_bytes(),
+        fsync,
+    )?;
+    Ok(Expr::from(contents.len() as i64))
+}
+
+/// (append-file path s [:create true])
+pub(crate) fn append_file(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let create = flags("append-file", &exprs, &[":create"])?[0];
+    let path = exprs[0].get_string()?;
+    let contents = exprs[1].get_string()?;
+    let mut options = OpenOptions::new();
+    options.append(true).create(create);
+    let mut file = access::open(symbol_table, "append-file", &path, &options)?;
+    access::write_all(&mut file, &path, &contents)?;
+    Ok(Expr::from(contents.len() as i64))
+}
+
+pub(crate) fn register(syms: &SymbolTable) {
+    register_builtins(
+        syms,
+        &[
+            (
+                "write-file",
+                "io",
+                2,
+                write_file,
+                true,
+                "Write a string to a file, replacing its contents. Returns the bytes written.
+With `:create-dirs true`, missing parent directories are created.
+Example:
+(write-file \"out/report.txt\" \"done\" :create-dirs true) ; 4
+",
+            ),
+            (
+                "write-file-atomic",
+                "io",
+                2,
+                write_file_atomic,
+                true,
+                "Replace a file's contents without readers ever seeing a partial file.
+The string is written to a temp file in the same directory, which is renamed over the file.
+With `:fsync true`, the data is flushed to disk before the rename, and the rename after it.
+Example:
+(write-file-atomic \"config.x7\" \"(def port 8080)\" :fsync true) ; 15
+",
+            ),
+            (
+                "append-file",
+                "io",
+                2,
+                append_file,
+                true,
+                "Append a string to a file. Returns the bytes written.
+The file must exist, unless given `:create true`.
+Example:
+(append-file \"log.txt\" \"started\" :create true) ; 7
+",
+            ),
+            (
+                "fs::open",
+                "io",
+                1,
+                FileRecord::from_x7,
+                true,
+                "Open a file. Under construction.",
+            ),
+            (
+                "kv-open",
+                "io",
+                1,
+                KvRecord::from_x7,
+                true,
+                "Open a key-value store kept in a file, creating it if needed, with .get, .put,
+.delete, .keys, .contains?, and .close. Keys are strings, and values anything canonical-hash takes.
+Changes are on disk once the method making them returns, and a store can only be open once at a time.
+Example:
+(def store (kv-open \"state.db\"))
+(.put store \"runs\" (inc (.get store \"runs\" 0)))
+(.close store)
+",
+            ),
+        ],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Options;
+    use crate::parser::read;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use crate::symbols::ProgramError;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn eval(prog: &str) -> LispResult<Expr> {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let mut res = Expr::Nil;
+        for expr in read(prog) {
+            res = expr?.eval(&syms)?;
+        }
+        Ok(res)
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("x7-files-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn listing(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn assert_io_error(err: anyhow::Error, stage: &str) {
+        assert_eq!(err.downcast_ref::<ProgramError>(), Some(&ProgramError::Io));
+        assert!(format!("{:?}", err).contains(stage), "{:?}", err);
+    }
+
+    #[test]
+    fn writing_and_appending() {
+        let dir = temp_dir();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let prog = format!(
+            "(write-file \"{0}\" \"one\")
+             (append-file \"{0}\" \" two\")
+             (write-file \"{1}\" \"nested\" :create-dirs true)
+             (append-file \"{2}\" \"made\" :create true)",
+            path("a.txt"),
+            path("sub/dir/b.txt"),
+            path("c.txt")
+        );
+        eval(&prog).unwrap();
+        assert_eq!(fs::read_to_string(path("a.txt")).unwrap(), "one two");
+        assert_eq!(fs::read_to_string(path("sub/dir/b.txt")).unwrap(), "nested");
+        assert_eq!(fs::read_to_string(path("c.txt")).unwrap(), "made");
+
+        let err = eval(&format!("(append-file \"{}\" \"x\")", path("missing.txt")));
+        assert_io_error(err.unwrap_err(), "missing.txt");
+        let err = eval(&format!(
+            "(write-file \"{}\" \"x\")",
+            path("no/such/dir.txt")
+        ));
+        assert_io_error(err.unwrap_err(), "Could not create");
+        assert!(eval(&format!(
+            "(write-file \"{}\" \"x\" :bogus true)",
+            path("a.txt")
+        ))
+        .is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn atomic_writes_replace_whole_files() {
+        let dir = temp_dir();
+        let target = dir.join("config.x7").to_string_lossy().into_owned();
+        fs::write(&target, "old contents which are longer").unwrap();
+        let res = eval(&format!(
+            "(write-file-atomic \"{}\" \"new\" :fsync true)",
+            target
+        ));
+        assert_eq!(res.unwrap(), Expr::from(3));
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+        assert_eq!(listing(&dir), vec!["config.x7"]);
+
+        // Renaming over a directory fails after the temp file was written.
+        fs::create_dir(dir.join("taken")).unwrap();
+        fs::write(dir.join("taken").join("keep"), "").unwrap();
+        let taken = dir.join("taken").to_string_lossy().into_owned();
+        let err = eval(&format!("(write-file-atomic \"{}\" \"new\")", taken)).unwrap_err();
+        assert_io_error(err, "rename the temp file");
+        assert_eq!(listing(&dir), vec!["config.x7", "taken"]);
+
+        // A missing directory fails before anything is written.
+        let missing = dir.join("missing").join("x").to_string_lossy().into_owned();
+        let err = eval(&format!("(write-file-atomic \"{}\" \"new\")", missing)).unwrap_err();
+        assert_io_error(err, "create a temp file");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let read_only = dir.join("read-only");
+            fs::create_dir(&read_only).unwrap();
+            let locked = read_only.join("locked.txt");
+            fs::write(&locked, "original").unwrap();
+            fs::set_permissions(&read_only, fs::Permissions::from_mode(0o555)).unwrap();
+            // Permissions don't apply to root.
+            let enforced = fs::write(read_only.join("probe"), "").is_err();
+            if enforced {
+                let prog = format!(
+                    "(write-file-atomic \"{}\" \"new\")",
+                    locked.to_string_lossy()
+                );
+                assert_io_error(eval(&prog).unwrap_err(), "create a temp file");
+                assert_eq!(fs::read_to_string(&locked).unwrap(), "original");
+                assert_eq!(listing(&read_only), vec!["locked.txt"]);
+            }
+            fs::set_permissions(&read_only, fs::Permissions::from_mode(0o755)).unwrap();
+
+            // The replaced file keeps its permissions.
+            fs::set_permissions(&target, fs::Permissions::from_mode(0o640)).unwrap();
+            eval(&format!("(write-file-atomic \"{}\" \"newer\")", target)).unwrap();
+            let mode = fs::metadata(&target).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o640);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
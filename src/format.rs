@@ -0,0 +1,383 @@
+use crate::symbols::{Expr, LispResult, Num};
+use anyhow::{anyhow, bail, ensure};
+use bigdecimal::{BigDecimal, Signed, Zero};
+use im::Vector;
+
+// The `format` mini-language. Placeholders look like `{}` or `{:spec}`,
+// and `{{` / `}}` are literal braces. A spec is, in order and all optional:
+//
+//   +          always show the sign
+//   0          pad with zeros after the sign instead of spaces before it
+//   width      minimum width in characters
+//   ,          separate thousands with commas
+//   .precision digits after the decimal point (rounded half away from zero)
+//   type       % (multiply by 100 and append %), x, b, or o (integer radix)
+//
+// e.g. {:,.2} -> 1,234.50   {:%} -> 12.5%   {:+08.3} -> +001.500   {:x} -> ff
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Default,
+    Percent,
+    Radix(u32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct FormatSpec {
+    plus: bool,
+    zero: bool,
+    width: Option<usize>,
+    thousands: bool,
+    precision: Option<usize>,
+    kind: Kind,
+}
+
+fn eat(c: char, rest: &mut &str) -> bool {
+    if rest.starts_with(c) {
+        *rest = &rest[1..];
+        true
+    } else {
+        false
+    }
+}
+
+fn take_digits(s: &str) -> (Option<usize>, &str) {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    (s[..end].parse().ok(), &s[end..])
+}
+
+impl FormatSpec {
+    pub(crate) fn parse(spec: &str) -> LispResult<FormatSpec> {
+        let err = |rest: &str, why: &str| {
+            anyhow!("Invalid format spec \"{}\": {} at \"{}\"", spec, why, rest)
+        };
+        let mut rest = spec;
+        let plus = eat('+', &mut rest);
+        let zero = eat('0', &mut rest);
+        let (width, after) = take_digits(rest);
+        rest = after;
+        let thousands = eat(',', &mut rest);
+        let precision = if eat('.', &mut rest) {
+            let (precision, after) = take_digits(rest);
+            let precision = precision.ok_or_else(|| err(rest, "expected digits after ."))?;
+            rest = after;
+            Some(precision)
+        } else {
+            None
+        };
+        let kind = match rest.chars().next() {
+            None => Kind::Default,
+            Some('%') => Kind::Percent,
+            Some('x') => Kind::Radix(16),
+            Some('b') => Kind::Radix(2),
+            Some('o') => Kind::Radix(8),
+            Some(_) => return Err(err(rest, "unexpected characters")),
+        };
+        if kind != Kind::Default {
+            rest = &rest[1..];
+        }
+        if !rest.is_empty() {
+            return Err(err(rest, "unexpected characters after the type"));
+        }
+        if let Kind::Radix(_) = kind {
+            ensure!(
+                !thousands && precision.is_none(),
+                "Invalid format spec \"{}\": x, b, and o can't be combined with , or .precision",
+                spec
+            );
+        }
+        ensure!(
+            !zero || width.is_some(),
+            "Invalid format spec \"{}\": 0 padding needs a width",
+            spec
+        );
+        Ok(FormatSpec {
+            plus,
+            zero,
+            width,
+            thousands,
+            precision,
+            kind,
+        })
+    }
+
+    fn is_numeric(&self) -> bool {
+        self.plus
+            || self.zero
+            || self.thousands
+            || self.precision.is_some()
+            || self.kind != Kind::Default
+    }
+
+    pub(crate) fn apply(&self, expr: &Expr, spec: &str) -> LispResult<String> {
+        let n = match expr.unmeta() {
+            Expr::Num(n) => n.clone(),
+            _ if self.is_numeric() => bail!(
+                "Format spec \"{}\" needs a num, but was given {:?}",
+                spec,
+                expr
+            ),
+            // Only a width: pad anything else on the right.
+            _ => {
+                let s = expr.to_string();
+                let pad = self.width.unwrap_or(0).saturating_sub(s.chars().count());
+                return Ok(format!("{}{}", s, " ".repeat(pad)));
+            }
+        };
+        let (negative, digits) = self.digits(n, spec)?;
+        let sign = if negative {
+            "-"
+        } else if self.plus {
+            "+"
+        } else {
+            ""
+        };
+        let suffix = if self.kind == Kind::Percent { "%" } else { "" };
+        let (int, frac) = match digits.find('.') {
+            Some(dot) => digits.split_at(dot),
+            None => (digits.as_str(), ""),
+        };
+        let mut int = int.to_string();
+        let width = self.width.unwrap_or(0);
+        let len = |body: &str| sign.len() + body.chars().count() + suffix.len();
+        let mut body = format!("{}{}", self.group(&int), frac);
+        if self.zero {
+            // Pad before grouping, so the zeros are grouped too: 0,001,234.
+            while len(&body) < width {
+                int.insert(0, '0');
+                body = format!("{}{}", self.group(&int), frac);
+            }
+            return Ok(format!("{}{}{}", sign, body, suffix));
+        }
+        let pad = width.saturating_sub(len(&body));
+        Ok(format!("{}{}{}{}", " ".repeat(pad), sign, body, suffix))
+    }
+
+    /// The integer digits `int`, with commas between thousands if asked for.
+    fn group(&self, int: &str) -> String {
+        if self.thousands {
+            group_thousands(int)
+        } else {
+            int.to_string()
+        }
+    }
+
+    /// Whether the formatted num is negative, and its digits without a sign
+    /// or grouping.
+    fn digits(&self, n: Num, spec: &str) -> LispResult<(bool, String)> {
+        let n = match self.kind {
+            Kind::Percent => n * BigDecimal::from(100),
+            _ => n,
+        };
+        if let Kind::Radix(radix) = self.kind {
+            ensure!(
+                n.with_scale(0) == n,
+                "Format spec \"{}\" needs an integer, but was given {}",
+                spec,
+                n
+            );
+            let (int, _) = n.abs().with_scale(0).as_bigint_and_exponent();
+            return Ok((n.is_negative(), int.to_str_radix(radix)));
+        }
+        let n = match self.precision {
+            Some(precision) => round(&n, precision),
+            None => n,
+        };
+        let negative = n.is_negative() && !n.is_zero();
+        let mut digits = n.abs().to_string();
+        if self.kind == Kind::Percent && self.precision.is_none() && digits.contains('.') {
+            // Scaling by 100 leaves trailing zeros, e.g. 0.125 -> 12.500
+            digits = digits.trim_end_matches('0').trim_end_matches('.').into();
+        }
+        Ok((negative, digits))
+    }
+}
+
+/// Round half away from zero to `precision` decimal places.
+pub(crate) fn round(n: &Num, precision: usize) -> Num {
+    let half = BigDecimal::new(5.into(), precision as i64 + 1);
+    let nudged = if n.is_negative() {
+        n.clone() - half
+    } else {
+        n.clone() + half
+    };
+    // with_scale truncates towards zero.
+    nudged.with_scale(precision as i64)
+}
+
+fn group_thousands(int: &str) -> String {
+    let mut res = String::new();
+    for (i, c) in int.chars().enumerate() {
+        if i > 0 && (int.len() - i).is_multiple_of(3) {
+            res.push(',');
+        }
+        res.push(c);
+    }
+    res
+}
+
+/// Fill the placeholders of `template` with `args`, in order.
+pub(crate) fn format(template: &str, args: &Vector<Expr>) -> LispResult<String> {
+    let mut res = String::new();
+    let mut args = args.iter();
+    let mut chars = template.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '{' if chars.peek().map(|(_, c)| *c) == Some('{') => {
+                chars.next();
+                res.push('{');
+            }
+            '{' => {
+                let end = template[i..]
+                    .find('}')
+                    .map(|end| i + end)
+                    .ok_or_else(|| anyhow!("Unclosed {{ in format string \"{}\"", template))?;
+                let placeholder = &template[i + 1..end];
+                let spec = match placeholder {
+                    "" => "",
+                    _ if placeholder.starts_with(':') => &placeholder[1..],
+                    _ => bail!(
+                        "Invalid placeholder \"{{{}}}\", expected {{}} or {{:spec}}",
+                        placeholder
+                    ),
+                };
+                let arg = args.next().ok_or_else(|| {
+                    anyhow!("Not enough arguments for format string \"{}\"", template)
+                })?;
+                res.push_str(&FormatSpec::parse(spec)?.apply(arg, spec)?);
+                while chars.peek().is_some_and(|(j, _)| *j <= end) {
+                    chars.next();
+                }
+            }
+            '}' if chars.peek().map(|(_, c)| *c) == Some('}') => {
+                chars.next();
+                res.push('}');
+            }
+            '}' => bail!("Unmatched }} in format string \"{}\"", template),
+            c => res.push(c),
+        }
+    }
+    let extra = args.count();
+    ensure!(
+        extra == 0,
+        "Too many arguments for format string \"{}\", {} left over",
+        template,
+        extra
+    );
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use im::vector;
+
+    fn num(s: &str) -> Expr {
+        Expr::Num(s.parse().unwrap())
+    }
+
+    fn fmt(spec: &str, n: &str) -> String {
+        format(&format!("{{:{}}}", spec), &vector![num(n)]).unwrap()
+    }
+
+    fn fmt_err(spec: &str, n: &str) -> String {
+        let err = format(&format!("{{:{}}}", spec), &vector![num(n)]).unwrap_err();
+        format!("{:?}", err)
+    }
+
+    #[test]
+    fn placeholders() {
+        let args = vector![num("1"), Expr::String("two".into())];
+        assert_eq!(format("{} and {}", &args).unwrap(), "1 and two");
+        assert_eq!(format("{{}} {}", &vector![num("1")]).unwrap(), "{} 1");
+        assert_eq!(format("no args", &Vector::new()).unwrap(), "no args");
+        assert!(format("{}", &Vector::new()).is_err());
+        assert!(format("{}", &args).is_err());
+        assert!(format("{", &vector![num("1")]).is_err());
+        assert!(format("}", &Vector::new()).is_err());
+        assert!(format("{0}", &vector![num("1")]).is_err());
+    }
+
+    #[test]
+    fn sign() {
+        assert_eq!(fmt("+", "5"), "+5");
+        assert_eq!(fmt("+", "-5"), "-5");
+        assert_eq!(fmt("+", "0"), "+0");
+        assert_eq!(fmt("", "-5"), "-5");
+    }
+
+    #[test]
+    fn width_and_zero_padding() {
+        assert_eq!(fmt("5", "42"), "   42");
+        assert_eq!(fmt("05", "42"), "00042");
+        assert_eq!(fmt("05", "-42"), "-0042");
+        assert_eq!(fmt("+05", "42"), "+0042");
+        assert_eq!(fmt("2", "12345"), "12345");
+        assert_eq!(
+            format("[{:6}]", &vector![Expr::String("ab".into())]).unwrap(),
+            "[ab    ]"
+        );
+    }
+
+    #[test]
+    fn thousands() {
+        assert_eq!(fmt(",", "1234567"), "1,234,567");
+        assert_eq!(fmt(",", "123"), "123");
+        assert_eq!(fmt(",", "-1234.5"), "-1,234.5");
+        assert_eq!(fmt(",", "123456"), "123,456");
+        assert_eq!(fmt("+010,.2", "1234.5"), "+01,234.50");
+        assert_eq!(fmt("011,.2", "1234.5"), "0,001,234.50");
+        assert_eq!(fmt("10,", "1234"), "     1,234");
+    }
+
+    #[test]
+    fn precision() {
+        assert_eq!(fmt(".2", "3.14159"), "3.14");
+        assert_eq!(fmt(".2", "2.675"), "2.68");
+        assert_eq!(fmt(".2", "-2.675"), "-2.68");
+        assert_eq!(fmt(".0", "2.5"), "3");
+        assert_eq!(fmt(".3", "1"), "1.000");
+        assert_eq!(fmt(".1", "-0.01"), "0.0");
+    }
+
+    #[test]
+    fn percent() {
+        assert_eq!(fmt("%", "0.125"), "12.5%");
+        assert_eq!(fmt(".1%", "0.12345"), "12.3%");
+        assert_eq!(fmt("+%", "1"), "+100%");
+    }
+
+    #[test]
+    fn radix() {
+        assert_eq!(fmt("x", "255"), "ff");
+        assert_eq!(fmt("b", "5"), "101");
+        assert_eq!(fmt("o", "8"), "10");
+        assert_eq!(fmt("x", "-255"), "-ff");
+        assert_eq!(fmt("08b", "5"), "00000101");
+        assert_eq!(fmt("x", "255.0"), "ff");
+        assert!(fmt_err("x", "1.5").contains("needs an integer"));
+    }
+
+    #[test]
+    fn combinations() {
+        assert_eq!(fmt(",.2", "1234.5"), "1,234.50");
+        assert_eq!(fmt("08.3", "3.14159"), "0003.142");
+        assert_eq!(fmt("+,.2", "-1234567.891"), "-1,234,567.89");
+        assert_eq!(fmt("+012,.2", "1234.5"), "+0,001,234.50");
+        assert_eq!(fmt("10.1%", "0.5"), "     50.0%");
+    }
+
+    #[test]
+    fn invalid_specs_quote_the_problem() {
+        assert!(
+            fmt_err("q", "1").contains("Invalid format spec \"q\": unexpected characters at \"q\"")
+        );
+        assert!(fmt_err(",.2q", "1").contains("at \"q\""));
+        assert!(fmt_err("x2", "1").contains("at \"2\""));
+        assert!(fmt_err(".", "1").contains("expected digits after ."));
+        assert!(fmt_err(",x", "1").contains("can't be combined"));
+        assert!(fmt_err("0", "1").contains("0 padding needs a width"));
+        let err = format("{:.2}", &vector![Expr::String("a".into())]).unwrap_err();
+        assert!(format!("{:?}", err).contains("needs a num"));
+    }
+}
@@ -0,0 +1,431 @@
+use crate::exact_len;
+use crate::host::Warning;
+use crate::iterators::{IterType, LazyIter};
+use crate::symbols::{next_object_id, Expr, LispResult, ProgramError, SymbolTable};
+use anyhow::{anyhow, bail};
+use im::Vector;
+use parking_lot::Mutex;
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// `(generator body...)` makes an iterator of the values the body passes
+// to `(yield v)`.
+//
+// The body runs on its own thread, in a copy of the scope the generator was
+// made in, and only while the consumer waits for an item: asking for an
+// item resumes the body, and `yield` hands the value over and suspends it
+// again. The body's output and warnings are sent along with each item, so
+// they're printed (or captured) by the consumer's interpreter in order.
+//
+// Once every handle to a generator is dropped, the body is interrupted: the
+// pending `yield` fails, and so does every function call after it, even if
+// something catches that first error. This unwinds the body and ends the
+// thread. Interrupting the consumer while it waits interrupts the body too,
+// and so does shutting down the consumer's interpreter.
+
+/// Most generator threads alive at once.
+const MAX_LIVE_GENERATORS: usize = 256;
+
+static LIVE_GENERATORS: AtomicUsize = AtomicUsize::new(0);
+
+/// What the body sends each time it suspends or finishes.
+struct Step {
+    stdout: String,
+    stderr: String,
+    warnings: Vec<Warning>,
+    /// The yielded value, an error from the body, or None once it finished.
+    item: Option<LispResult<Expr>>,
+}
+
+// On a generator's thread, the channels `yield` talks to the consumer with,
+// and the body's interrupt flag.
+type Yielder = (Receiver<()>, Sender<Step>, Arc<AtomicBool>);
+
+thread_local! {
+    static YIELDER: RefCell<Option<Yielder>> = const { RefCell::new(None) };
+}
+
+fn send_step(
+    steps: &Sender<Step>,
+    symbol_table: &SymbolTable,
+    item: Option<LispResult<Expr>>,
+) -> LispResult<()> {
+    let (stdout, stderr, warnings) = {
+        let mut host = symbol_table.host_mut();
+        let (stdout, stderr) = host.take_captured_output();
+        let warnings = host.finish_collecting_warnings();
+        host.start_collecting_warnings();
+        (stdout, stderr, warnings)
+    };
+    steps
+        .send(Step {
+            stdout,
+            stderr,
+            warnings,
+            item,
+        })
+        .map_err(|_| dropped())
+}
+
+fn dropped() -> anyhow::Error {
+    anyhow!(ProgramError::Interrupted).context("The generator was dropped")
+}
+
+/// Wait for the consumer to ask for an item. False if it never will, as the
+/// generator was dropped, or the body was interrupted, e.g. by shutdown.
+fn wait_for_resume(resume: &Receiver<()>, stop: &AtomicBool) -> bool {
+    loop {
+        match resume.recv_timeout(Duration::from_millis(50)) {
+            Ok(()) => return true,
+            Err(RecvTimeoutError::Timeout) if !stop.load(Ordering::SeqCst) => {}
+            Err(_) => return false,
+        }
+    }
+}
+
+struct Channels {
+    resume: Sender<()>,
+    steps: Receiver<Step>,
+    // The body's interrupt flag.
+    stop: Arc<AtomicBool>,
+    done: bool,
+}
+
+impl Channels {
+    /// Wait for the body to suspend or finish, passing on interrupts.
+    fn wait(&self, symbol_table: &SymbolTable) -> Option<Step> {
+        loop {
+            match self.steps.recv_timeout(Duration::from_millis(50)) {
+                Ok(step) => return Some(step),
+                Err(RecvTimeoutError::Timeout) => {
+                    if symbol_table.is_interrupted() {
+                        self.stop.store(true, Ordering::SeqCst);
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for Channels {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Clones share the same body, so items are handed out once between them.
+#[derive(Clone)]
+pub(crate) struct Generator {
+    channels: Arc<Mutex<Channels>>,
+    id: u64,
+}
+
+impl LazyIter for Generator {
+    fn next(&self, symbol_table: &SymbolTable) -> Option<LispResult<Expr>> {
+        let mut channels = self.channels.lock();
+        if channels.done {
+            return None;
+        }
+        let step = channels
+            .resume
+            .send(())
+            .ok()
+            .and_then(|_| channels.wait(symbol_table));
+        let step = match step {
+            Some(step) => step,
+            None => {
+                channels.done = true;
+                return Some(Err(anyhow!("The generator's body stopped unexpectedly")));
+            }
+        };
+        {
+            let mut host = symbol_table.host_mut();
+            host.write_stdout(&step.stdout);
+            host.write_stderr(&step.stderr);
+        }
+        for warning in step.warnings {
+            if let Err(e) = symbol_table.warn(warning) {
+                channels.done = true;
+                return Some(Err(e));
+            }
+        }
+        match step.item {
+            Some(Ok(item)) => Some(Ok(item)),
+            rest => {
+                channels.done = true;
+                rest
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "Generator"
+    }
+
+    fn clone(&self) -> IterType {
+        Box::new(Clone::clone(self))
+    }
+
+    fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl fmt::Debug for Generator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LazyIter<{}>", self.name())
+    }
+}
+
+impl fmt::Display for Generator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LazyIter<{}>", self.name())
+    }
+}
+
+/// (generator body...)
+pub(crate) fn generator(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    if LIVE_GENERATORS.fetch_add(1, Ordering::SeqCst) >= MAX_LIVE_GENERATORS {
+        LIVE_GENERATORS.fetch_sub(1, Ordering::SeqCst);
+        bail!(
+            "Cannot make a generator, {} are already running. Finish or drop some first",
+            MAX_LIVE_GENERATORS
+        );
+    }
+    let (resume_tx, resume_rx) = channel();
+    let (steps_tx, steps_rx) = channel();
+    let scope = symbol_table.detach();
+    let stop: Arc<AtomicBool> = Arc::default();
+    let body_stop = stop.clone();
+    let spawned = thread::Builder::new()
+        .name("x7-generator".into())
+        .spawn(move || {
+            let mut symbol_table = scope.attach();
+            symbol_table.set_interrupt_handle(body_stop.clone());
+            // Don't start until the first item is asked for.
+            if wait_for_resume(&resume_rx, &body_stop) {
+                YIELDER.with(|yielder| {
+                    *yielder.borrow_mut() = Some((resume_rx, steps_tx.clone(), body_stop))
+                });
+                let res = exprs
+                    .iter()
+                    .try_fold(Expr::Nil, |_, expr| expr.eval(&symbol_table));
+                let _ = send_step(&steps_tx, &symbol_table, res.err().map(Err));
+            }
+            LIVE_GENERATORS.fetch_sub(1, Ordering::SeqCst);
+        });
+    let handle = match spawned {
+        Ok(handle) => handle,
+        Err(e) => {
+            LIVE_GENERATORS.fetch_sub(1, Ordering::SeqCst);
+            bail!("Could not start a generator, {}", e);
+        }
+    };
+    symbol_table
+        .resources_mut()
+        .register_worker("generator".into(), stop.clone(), handle);
+    Ok(Expr::LazyIter(Box::new(Generator {
+        channels: Arc::new(Mutex::new(Channels {
+            resume: resume_tx,
+            steps: steps_rx,
+            stop,
+            done: false,
+        })),
+        id: next_object_id(),
+    })))
+}
+
+/// (yield value)
+pub(crate) fn yield_value(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    YIELDER.with(|yielder| {
+        let yielder = yielder.borrow();
+        let (resume, steps, stop) = match yielder.as_ref() {
+            Some(channels) => channels,
+            None => bail!("yield can only be used inside a generator"),
+        };
+        send_step(steps, symbol_table, Some(Ok(exprs[0].clone())))?;
+        if !wait_for_resume(resume, stop) {
+            return Err(dropped());
+        }
+        Ok(Expr::Nil)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::Decision;
+    use crate::cli::Options;
+    use crate::parser::read;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use std::time::{Duration, Instant};
+
+    fn eval(prog: &str, syms: &SymbolTable) -> LispResult<Expr> {
+        let mut res = Expr::Nil;
+        for expr in read(prog) {
+            res = expr?.eval(syms)?;
+        }
+        Ok(res)
+    }
+
+    fn wait_for_no_live_generators() -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if LIVE_GENERATORS.load(Ordering::SeqCst) == 0 {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    // One test, as it counts the generator threads left running.
+    #[test]
+    fn generators() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let evens = "(def coll (range 10))
+                     (doall (generator (foreach (fn (x) (if (even? x) (yield x) ())) coll)))";
+        assert_eq!(
+            eval(evens, &syms).unwrap(),
+            eval("(list 0 2 4 6 8)", &syms).unwrap()
+        );
+
+        // Only as much of the body runs as is asked for.
+        let prog = "(def counter (atom 0))
+                    (def naturals (generator (dotimes (i 1000000000) (.reset counter i) (yield i))))
+                    (doall (take 3 naturals))";
+        assert_eq!(
+            eval(prog, &syms).unwrap(),
+            eval("(list 0 1 2)", &syms).unwrap()
+        );
+        assert_eq!(eval("(.deref counter)", &syms).unwrap(), Expr::from(2));
+
+        // Errors in the body surface where the items are consumed.
+        let err = eval("(doall (generator (yield 1) (err \"boom\")))", &syms).unwrap_err();
+        assert!(format!("{:?}", err).contains("boom"), "{:?}", err);
+        let err = eval("(yield 1)", &syms).unwrap_err();
+        assert!(format!("{:?}", err).contains("yield can only be used inside a generator"));
+
+        // Output from the body is printed in order with the consumer's.
+        syms.host_mut().capture_output();
+        let prog = "(foreach (fn (x) (println \"got \" x))
+                             (generator (println \"a\") (yield 1) (println \"b\") (yield 2)))";
+        eval(prog, &syms).unwrap();
+        let (stdout, _) = syms.host_mut().take_captured_output();
+        assert_eq!(stdout, "a\ngot 1\nb\ngot 2\n");
+
+        // The body can't get around the sandbox or the access hook.
+        for sandboxed in &[true, false] {
+            let restricted = create_stdlib_symbol_table(&Options::default());
+            if *sandboxed {
+                restricted.set_sandboxed(true);
+            } else {
+                restricted.on_io_access(|_| Decision::Deny);
+            }
+            let prog = "(doall (generator (yield (path-exists? \"Cargo.toml\"))))";
+            let err = format!("{:?}", eval(prog, &restricted).unwrap_err());
+            let expected = if *sandboxed {
+                "path-exists? is not allowed in sandbox mode"
+            } else {
+                "Permission denied"
+            };
+            assert!(err.contains(expected), "{}", err);
+        }
+
+        // The body shares stdin with the consumer, and its warnings go to
+        // the consumer's handler.
+        let host = create_stdlib_symbol_table(&Options::default());
+        host.host_mut().set_stdin("input".into());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        host.on_warning(move |w| sink.lock().push(w.message.clone()));
+        let prog = "(list (doall (generator (warn :custom \"inside\") (yield (read-stdin))))
+                          (read-stdin))";
+        assert_eq!(
+            eval(prog, &host).unwrap(),
+            eval("(list (list \"input\") \"\")", &host).unwrap()
+        );
+        assert_eq!(*seen.lock(), vec!["inside".to_string()]);
+
+        // Seeding the consumer seeds the body.
+        let prog = "(random-seed 7) (doall (generator (yield (shuffle (range 20)))))";
+        assert_eq!(eval(prog, &host).unwrap(), eval(prog, &host).unwrap());
+
+        // Records defined by the consumer can be upgraded in the body.
+        let prog = "(defrecord P (x)) (def p (P 1)) (defrecord P (x (y 2)))
+                    (doall (generator (yield (.y (upgrade-record p)))))";
+        assert_eq!(eval(prog, &host).unwrap(), eval("(list 2)", &host).unwrap());
+
+        // Builtins stay frozen in the body.
+        host.host_mut().set_frozen_globals(true);
+        let err = eval("(doall (generator (yield (def inc 1))))", &host).unwrap_err();
+        assert!(format!("{:?}", err).contains("Cannot redefine the builtin inc"));
+        host.host_mut().set_frozen_globals(false);
+
+        // The body draws for the consumer's terminal.
+        let mut tty = crate::terminal::TerminalInfo::detect();
+        tty.stdout_tty = true;
+        tty.stderr_tty = true;
+        host.set_terminal_info(tty);
+        let prog = "(= (list (terminal-info)) (doall (generator (yield (terminal-info)))))";
+        assert_eq!(eval(prog, &host).unwrap(), Expr::Bool(true));
+
+        // The body knows the program it's part of, and deprecated builtins
+        // stay denied.
+        host.host_mut().set_program("(is-even? 2)");
+        host.host_mut().set_deny_deprecated(true);
+        let prog = "(doall (generator (yield (len (deprecations)))))";
+        assert_eq!(eval(prog, &host).unwrap(), eval("(list 1)", &host).unwrap());
+        let err = eval("(doall (generator (yield (is-even? 2))))", &host).unwrap_err();
+        assert_eq!(crate::symbols::error_kind(&err), "DeniedWarning");
+
+        // Interrupting the consumer while it waits interrupts the body.
+        let busy = create_stdlib_symbol_table(&Options::default());
+        let interrupt = busy.interrupt_handle();
+        let interrupter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            interrupt.store(true, Ordering::SeqCst);
+        });
+        let err = eval(
+            "(doall (generator (yield 1) (dotimes (i 1000000000) (inc i))))",
+            &busy,
+        )
+        .unwrap_err();
+        interrupter.join().unwrap();
+        assert_eq!(crate::symbols::error_kind(&err), "Interrupted");
+
+        // Catching the failed yield of an abandoned body doesn't keep it going.
+        #[cfg(feature = "time")]
+        {
+            let prog = "(def retried
+                          (generator (with-retries (dict :attempts 1000000000 :backoff 0) (yield 1))))
+                        (doall (take 1 retried))
+                        (def retried ())";
+            eval(prog, &syms).unwrap();
+        }
+
+        // Shutting down stops the bodies of generators still bound, and
+        // waits for their threads to exit.
+        let held = create_stdlib_symbol_table(&Options::default());
+        let prog = "(def started (generator (yield 1) (yield 2)))
+                    (doall (take 1 started))
+                    (def waiting (generator (yield 1)))";
+        eval(prog, &held).unwrap();
+        let running = vec!["generator".to_string(), "generator".to_string()];
+        assert_eq!(held.resource_report().running_tasks, running);
+        assert_eq!(held.shutdown().running_tasks, running);
+        assert!(held.resource_report().running_tasks.is_empty());
+
+        // Abandoned generators don't leave their threads behind.
+        eval("(def naturals ())", &syms).unwrap();
+        drop(syms);
+        assert!(wait_for_no_live_generators());
+    }
+}
@@ -0,0 +1,148 @@
+use crate::symbols::Expr;
+use std::collections::VecDeque;
+use std::fmt::{self, Write};
+use std::time::Duration;
+
+// The last few top level forms an interpreter evaluated, for working out what
+// led up to an error in production. Nothing is kept unless an embedder asks
+// for it. Printed forms are cut to MAX_SOURCE_BYTES as they're recorded, so
+// the ring's memory is bounded however big the forms are.
+
+/// The most of a printed form kept, in bytes, before "..." is added.
+const MAX_SOURCE_BYTES: usize = 256;
+
+/// A top level form the interpreter evaluated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Evaluation {
+    /// The form as printed, cut short with "..." after 256 bytes.
+    pub source: String,
+    /// The wall time the form took to evaluate.
+    pub duration: Duration,
+    /// Whether the form evaluated without error.
+    pub success: bool,
+}
+
+/// A ring of the latest evaluations, oldest first.
+#[derive(Debug, Default)]
+pub(crate) struct History {
+    capacity: usize,
+    entries: VecDeque<Evaluation>,
+}
+
+impl History {
+    /// Keep the last `capacity` evaluations, dropping older ones now if
+    /// there are too many. 0 keeps none.
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub(crate) fn record(&mut self, form: &Expr, duration: Duration, success: bool) {
+        if !self.is_enabled() {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Evaluation {
+            source: truncated(form),
+            duration,
+            success,
+        });
+    }
+
+    pub(crate) fn entries(&self) -> Vec<Evaluation> {
+        self.entries.iter().cloned().collect()
+    }
+}
+
+/// A string which takes the first MAX_SOURCE_BYTES written to it, and
+/// refuses the rest.
+#[derive(Default)]
+struct Bounded {
+    buf: String,
+    full: bool,
+}
+
+impl Write for Bounded {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = MAX_SOURCE_BYTES - self.buf.len();
+        if !self.full && s.len() <= room {
+            self.buf.push_str(s);
+            return Ok(());
+        }
+        if !self.full {
+            let end = (0..=room)
+                .rev()
+                .find(|i| s.is_char_boundary(*i))
+                .unwrap_or(0);
+            self.buf.push_str(&s[..end]);
+            self.full = true;
+        }
+        Err(fmt::Error)
+    }
+}
+
+/// `form` as printed, cut short after MAX_SOURCE_BYTES.
+fn truncated(form: &Expr) -> String {
+    // Forms after a `;#line` directive are wrapped to carry their location.
+    let form = match form {
+        Expr::List(l) if l.len() == 5 && l[0].symbol_matches("with-location") => &l[4],
+        form => form,
+    };
+    let mut out = Bounded::default();
+    let _ = write!(out, "{:?}", form);
+    if out.full {
+        out.buf.push_str("...");
+    }
+    out.buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::read;
+
+    fn form(source: &str) -> Expr {
+        read(source).next().unwrap().unwrap()
+    }
+
+    #[test]
+    fn the_ring_is_bounded() {
+        let mut history = History::default();
+        history.record(&form("(+ 1 2)"), Duration::from_millis(1), true);
+        assert!(history.entries().is_empty());
+
+        history.set_capacity(2);
+        for (i, source) in ["(def a 1)", "(inc a)", "(err \"boom\")"]
+            .iter()
+            .enumerate()
+        {
+            history.record(&form(source), Duration::from_millis(1), i != 2);
+        }
+        let entries = history.entries();
+        let sources: Vec<_> = entries.iter().map(|e| e.source.as_str()).collect();
+        assert_eq!(sources, vec!["(inc a)", "(err \"boom\")"]);
+        assert!(entries[0].success && !entries[1].success);
+
+        // Huge forms are cut short when recorded, even inside multibyte chars.
+        let huge = format!("(list \"{}\")", "é".repeat(10_000));
+        history.record(&form(&huge), Duration::from_millis(1), true);
+        let source = &history.entries()[1].source;
+        assert!(source.len() <= MAX_SOURCE_BYTES + 3, "{}", source.len());
+        assert!(source.starts_with("(list \"éé") && source.ends_with("..."));
+
+        history.set_capacity(1);
+        assert_eq!(history.entries().len(), 1);
+    }
+}
@@ -0,0 +1,452 @@
+use crate::access::{Decision, IoHook, IoOp};
+use crate::history::History;
+use crate::metrics::ProgramLimits;
+use crate::records::RecordDef;
+use crate::symbols::{Expr, LispResult, ProgramError};
+use crate::terminal::TerminalInfo;
+use anyhow::anyhow;
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+#[cfg(test)]
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io::Read;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Something worth telling the user about that isn't an error.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    /// A keyword naming the kind of warning, e.g. ":shadow" or ":custom".
+    pub kind: String,
+    pub message: String,
+    /// Where the warning came from, e.g. a module path, if known.
+    pub span: Option<String>,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Warning[{}]: {}", self.kind, self.message)?;
+        if let Some(span) = &self.span {
+            write!(f, " ({})", span)?;
+        }
+        Ok(())
+    }
+}
+
+/// A warning on its way to the embedder's handler.
+pub(crate) struct PendingWarning(WarningHandler, Warning);
+
+impl PendingWarning {
+    /// Call the handler. The handler may use the interpreter, so the host
+    /// mustn't be borrowed.
+    pub(crate) fn deliver(self) {
+        let PendingWarning(WarningHandler(handler), warning) = self;
+        handler(&warning);
+    }
+}
+
+/// Receives warnings instead of stderr.
+#[derive(Clone)]
+pub(crate) struct WarningHandler(Rc<dyn Fn(&Warning)>);
+
+impl fmt::Debug for WarningHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WarningHandler")
+    }
+}
+
+/// The outside world as seen by the interpreter.
+///
+/// Builtins go through here instead of touching stdout / stdin directly,
+/// so embedders can capture output, feed input, and sandbox scripts.
+#[derive(Debug, Default)]
+pub(crate) struct Host {
+    // When set, output is captured here instead of going to the real stdout / stderr.
+    captured_stdout: Option<String>,
+    captured_stderr: Option<String>,
+    // What stdout / stderr are when not captured. Not a terminal by default.
+    terminal: TerminalInfo,
+    // Output is only captured to be passed on to the host of another
+    // thread, and ends up on the terminal after all.
+    passes_output_on: bool,
+    // When set, `read-stdin` reads from here instead of the real stdin.
+    // Shared with generator threads, so it's only read once between them.
+    stdin: Option<Arc<Mutex<String>>>,
+    sandboxed: bool,
+    io_hook: Option<IoHook>,
+    on_warning: Option<WarningHandler>,
+    deny_warnings: bool,
+    deny_deprecated: bool,
+    // Deprecated builtins already warned about, as that's done once per name.
+    deprecated_called: HashSet<&'static str>,
+    // The source of the program being run, if known.
+    program: Option<Rc<str>>,
+    // Sequence builtins error on nil instead of treating it as empty.
+    strict_nil: bool,
+    // Calls to builtins are resolved before evaluating, and builtins can't be redefined.
+    frozen_globals: bool,
+    // One buffer per enclosing `with-warnings-collected`, innermost last.
+    collected_warnings: Vec<Vec<Warning>>,
+    // Seeded from the OS when first used, unless `random-seed` was called.
+    rng: Option<StdRng>,
+    // The largest value builtins may build in one call, if limited.
+    max_value_bytes: Option<usize>,
+    // What builtins have built during the current top level form, which is
+    // also held to max_value_bytes.
+    built_bytes: usize,
+    // Source evaluated is rejected before evaluating it when over these.
+    max_program_metrics: Option<ProgramLimits>,
+    // Renders thrown values which go uncaught, from `set-error-renderer!`.
+    error_renderer: Option<Expr>,
+    // The latest version of each record type made with `defrecord`.
+    record_defs: HashMap<&'static str, Arc<RecordDef>>,
+    // The last top level forms evaluated, if an embedder asked to keep them.
+    history: History,
+    // Tag -> the function reading `#tag"literal"`, from `set-reader-tag!`.
+    reader_tags: BTreeMap<String, Expr>,
+    // How many values were evaluated, when tests give every other one metadata.
+    #[cfg(test)]
+    wrapped_values: Option<Cell<usize>>,
+}
+
+impl Host {
+    /// Capture everything printed from now on.
+    pub(crate) fn capture_output(&mut self) {
+        self.captured_stdout = Some(String::new());
+        self.captured_stderr = Some(String::new());
+    }
+
+    /// Return the captured (stdout, stderr), leaving the buffers empty.
+    pub(crate) fn take_captured_output(&mut self) -> (String, String) {
+        let stdout = self.captured_stdout.as_mut().map(std::mem::take);
+        let stderr = self.captured_stderr.as_mut().map(std::mem::take);
+        (stdout.unwrap_or_default(), stderr.unwrap_or_default())
+    }
+
+    /// Whether output is being captured rather than printed.
+    pub(crate) fn captures_output(&self) -> bool {
+        self.captured_stderr.is_some()
+    }
+
+    pub(crate) fn write_stdout(&mut self, s: &str) {
+        match self.captured_stdout.as_mut() {
+            Some(buf) => buf.push_str(s),
+            None => print!("{}", s),
+        }
+    }
+
+    pub(crate) fn write_stderr(&mut self, s: &str) {
+        match self.captured_stderr.as_mut() {
+            Some(buf) => buf.push_str(s),
+            None => eprint!("{}", s),
+        }
+    }
+
+    /// Give every other value metadata as it's evaluated, to check that
+    /// metadata doesn't change what a program does.
+    #[cfg(test)]
+    pub(crate) fn set_wraps_values(&mut self, wraps: bool) {
+        self.wrapped_values = if wraps { Some(Cell::new(0)) } else { None };
+    }
+
+    #[cfg(test)]
+    pub(crate) fn wraps_next_value(&self) -> bool {
+        self.wrapped_values.as_ref().is_some_and(|count| {
+            count.set(count.get() + 1);
+            count.get() % 2 == 0
+        })
+    }
+
+    pub(crate) fn set_terminal(&mut self, terminal: TerminalInfo) {
+        self.terminal = terminal;
+    }
+
+    /// What output is printed to, which is never a terminal when captured.
+    pub(crate) fn terminal(&mut self) -> TerminalInfo {
+        self.terminal.refresh_width();
+        if self.captures_output() && !self.passes_output_on {
+            self.terminal.captured()
+        } else {
+            self.terminal.clone()
+        }
+    }
+
+    pub(crate) fn set_stdin(&mut self, stdin: String) {
+        self.stdin = Some(Arc::new(Mutex::new(stdin)));
+    }
+
+    /// Read the rest of stdin.
+    pub(crate) fn read_stdin(&mut self) -> LispResult<String> {
+        if let Some(stdin) = self.stdin.as_ref() {
+            return Ok(std::mem::take(&mut *stdin.lock()));
+        }
+        let mut buf = String::new();
+        std::io::stdin()
+            .read_to_string(&mut buf)
+            .map_err(|e| anyhow!("Failed to read stdin, {}", e))?;
+        Ok(buf)
+    }
+
+    pub(crate) fn set_sandboxed(&mut self, sandboxed: bool) {
+        self.sandboxed = sandboxed;
+    }
+
+    pub(crate) fn is_sandboxed(&self) -> bool {
+        self.sandboxed
+    }
+
+    /// Ask `hook` before every file and network access.
+    pub(crate) fn set_io_hook<F: Fn(&IoOp) -> Decision + 'static>(&mut self, hook: F) {
+        self.io_hook = Some(IoHook(Rc::new(hook)));
+    }
+
+    pub(crate) fn io_hook(&self) -> Option<IoHook> {
+        self.io_hook.clone()
+    }
+
+    /// Send warnings to `handler` instead of stderr.
+    pub(crate) fn on_warning<F: Fn(&Warning) + 'static>(&mut self, handler: F) {
+        self.on_warning = Some(WarningHandler(Rc::new(handler)));
+    }
+
+    /// Make every warning an error.
+    pub(crate) fn set_deny_warnings(&mut self, deny: bool) {
+        self.deny_warnings = deny;
+    }
+
+    pub(crate) fn is_deny_warnings(&self) -> bool {
+        self.deny_warnings
+    }
+
+    /// Make calling builtins by deprecated names an error.
+    pub(crate) fn set_deny_deprecated(&mut self, deny: bool) {
+        self.deny_deprecated = deny;
+    }
+
+    pub(crate) fn is_deny_deprecated(&self) -> bool {
+        self.deny_deprecated
+    }
+
+    /// Whether this is the first call to the deprecated builtin `name`.
+    pub(crate) fn first_deprecated_call(&mut self, name: &'static str) -> bool {
+        self.deprecated_called.insert(name)
+    }
+
+    pub(crate) fn set_program(&mut self, source: &str) {
+        self.program = Some(source.into());
+    }
+
+    /// Make `source` the program being run for a while, returning the one
+    /// to put back with `restore_program`.
+    pub(crate) fn replace_program(&mut self, source: &str) -> Option<Rc<str>> {
+        self.program.replace(source.into())
+    }
+
+    pub(crate) fn restore_program(&mut self, program: Option<Rc<str>>) {
+        self.program = program;
+    }
+
+    pub(crate) fn program(&self) -> Option<String> {
+        self.program.as_deref().map(String::from)
+    }
+
+    pub(crate) fn set_strict_nil(&mut self, strict: bool) {
+        self.strict_nil = strict;
+    }
+
+    pub(crate) fn is_strict_nil(&self) -> bool {
+        self.strict_nil
+    }
+
+    pub(crate) fn set_frozen_globals(&mut self, frozen: bool) {
+        self.frozen_globals = frozen;
+    }
+
+    pub(crate) fn is_frozen_globals(&self) -> bool {
+        self.frozen_globals
+    }
+
+    pub(crate) fn set_max_value_bytes(&mut self, max: Option<usize>) {
+        self.max_value_bytes = max;
+    }
+
+    pub(crate) fn max_value_bytes(&self) -> Option<usize> {
+        self.max_value_bytes
+    }
+
+    /// Add `bytes` to what's been built during this top level form,
+    /// returning the new total.
+    pub(crate) fn add_built_bytes(&mut self, bytes: usize) -> usize {
+        self.built_bytes = self.built_bytes.saturating_add(bytes);
+        self.built_bytes
+    }
+
+    pub(crate) fn reset_built_bytes(&mut self) {
+        self.built_bytes = 0;
+    }
+
+    pub(crate) fn set_max_program_metrics(&mut self, limits: Option<ProgramLimits>) {
+        self.max_program_metrics = limits;
+    }
+
+    pub(crate) fn max_program_metrics(&self) -> Option<&ProgramLimits> {
+        self.max_program_metrics.as_ref()
+    }
+
+    pub(crate) fn set_error_renderer(&mut self, renderer: Option<Expr>) {
+        self.error_renderer = renderer;
+    }
+
+    pub(crate) fn error_renderer(&self) -> Option<Expr> {
+        self.error_renderer.clone()
+    }
+
+    pub(crate) fn record_def(&self, name: &str) -> Option<Arc<RecordDef>> {
+        self.record_defs.get(name).cloned()
+    }
+
+    pub(crate) fn add_record_def(&mut self, def: Arc<RecordDef>) {
+        self.record_defs.insert(def.name(), def);
+    }
+
+    pub(crate) fn history(&self) -> &History {
+        &self.history
+    }
+
+    pub(crate) fn history_mut(&mut self) -> &mut History {
+        &mut self.history
+    }
+
+    /// Read `#tag"literal"` with `reader`, or stop reading it if `None`.
+    pub(crate) fn set_reader_tag(&mut self, tag: String, reader: Option<Expr>) {
+        match reader {
+            Some(reader) => self.reader_tags.insert(tag, reader),
+            None => self.reader_tags.remove(&tag),
+        };
+    }
+
+    pub(crate) fn reader_tag(&self, tag: &str) -> Option<Expr> {
+        self.reader_tags.get(tag).cloned()
+    }
+
+    /// The registered tags, in order.
+    pub(crate) fn reader_tags(&self) -> Vec<String> {
+        self.reader_tags.keys().cloned().collect()
+    }
+
+    /// Report a warning. Errors instead if warnings are denied. A warning
+    /// for the embedder's handler is given back, to deliver once the host
+    /// is no longer borrowed. See `SymbolTable::warn`.
+    pub(crate) fn warn(&mut self, warning: Warning) -> LispResult<Option<PendingWarning>> {
+        if self.deny_warnings {
+            return Err(anyhow!(ProgramError::DeniedWarning)
+                .context(format!("{} (warnings are denied)", warning)));
+        }
+        if let Some(collected) = self.collected_warnings.last_mut() {
+            collected.push(warning);
+        } else if let Some(handler) = self.on_warning.clone() {
+            return Ok(Some(PendingWarning(handler, warning)));
+        } else {
+            self.write_stderr(&format!("{}\n", warning));
+        }
+        Ok(None)
+    }
+
+    /// Collect warnings from now on, until the matching `finish_collecting_warnings`.
+    pub(crate) fn start_collecting_warnings(&mut self) {
+        self.collected_warnings.push(Vec::new());
+    }
+
+    pub(crate) fn finish_collecting_warnings(&mut self) -> Vec<Warning> {
+        self.collected_warnings.pop().unwrap_or_default()
+    }
+
+    /// The random number generator builtins like `shuffle` draw from.
+    pub(crate) fn rng(&mut self) -> &mut StdRng {
+        self.rng.get_or_insert_with(StdRng::from_entropy)
+    }
+
+    /// Make everything drawn from `rng` reproducible.
+    pub(crate) fn seed_rng(&mut self, seed: u64) {
+        self.rng = Some(StdRng::seed_from_u64(seed));
+    }
+}
+
+/// A host's settings, to carry over to an interpreter on another thread,
+/// from `Host::detach`. The access hook and the warning handler can't go
+/// along: what the hook would have been asked is denied instead, and
+/// warnings are handed back to be reported by the original host.
+pub(crate) struct DetachedHost {
+    stdin: Option<Arc<Mutex<String>>>,
+    sandboxed: bool,
+    io_hooked: bool,
+    deny_warnings: bool,
+    strict_nil: bool,
+    // Drawn from this host's, so a seed makes the other thread's choices repeat too.
+    rng: Option<StdRng>,
+    max_value_bytes: Option<usize>,
+    record_defs: HashMap<&'static str, Arc<RecordDef>>,
+    frozen_globals: bool,
+    terminal: TerminalInfo,
+    deny_deprecated: bool,
+    deprecated_called: HashSet<&'static str>,
+    program: Option<String>,
+    error_renderer: Option<Expr>,
+    reader_tags: BTreeMap<String, Expr>,
+}
+
+impl Host {
+    pub(crate) fn detach(&mut self) -> DetachedHost {
+        DetachedHost {
+            stdin: self.stdin.clone(),
+            sandboxed: self.sandboxed,
+            io_hooked: self.io_hook.is_some(),
+            deny_warnings: self.deny_warnings,
+            strict_nil: self.strict_nil,
+            rng: StdRng::from_rng(self.rng()).ok(),
+            max_value_bytes: self.max_value_bytes,
+            record_defs: self.record_defs.clone(),
+            frozen_globals: self.frozen_globals,
+            terminal: self.terminal(),
+            deny_deprecated: self.deny_deprecated,
+            deprecated_called: self.deprecated_called.clone(),
+            program: self.program(),
+            error_renderer: self.error_renderer.clone(),
+            reader_tags: self.reader_tags.clone(),
+        }
+    }
+}
+
+impl DetachedHost {
+    /// A host with the settings, which captures its output and warnings
+    /// to hand back.
+    pub(crate) fn attach(self) -> Host {
+        let mut host = Host {
+            stdin: self.stdin,
+            sandboxed: self.sandboxed,
+            deny_warnings: self.deny_warnings,
+            strict_nil: self.strict_nil,
+            rng: self.rng,
+            max_value_bytes: self.max_value_bytes,
+            record_defs: self.record_defs,
+            frozen_globals: self.frozen_globals,
+            terminal: self.terminal,
+            passes_output_on: true,
+            deny_deprecated: self.deny_deprecated,
+            deprecated_called: self.deprecated_called,
+            program: self.program.map(Rc::from),
+            error_renderer: self.error_renderer,
+            reader_tags: self.reader_tags,
+            ..Default::default()
+        };
+        host.capture_output();
+        if self.io_hooked {
+            host.set_io_hook(|_| Decision::Deny);
+        }
+        host.start_collecting_warnings();
+        host
+    }
+}
@@ -0,0 +1,274 @@
+use crate::symbols::{Expr, SymbolTable};
+use itertools::Itertools;
+use std::fmt::Write;
+
+// The report printed by `(inspect x)` and the REPL's `:inspect expr`.
+//
+// The first line names the type and size. Collections then list their
+// elements as a tree, which stops at MAX_DEPTH and after MAX_CHILDREN
+// elements, saying how much was left out.
+
+/// How many levels of nested collections are expanded.
+const MAX_DEPTH: usize = 3;
+/// How many elements of each collection are shown.
+const MAX_CHILDREN: usize = 5;
+/// How many characters of a string are shown.
+const MAX_PREVIEW: usize = 40;
+
+pub(crate) fn plural(n: usize, one: &str, many: &str) -> String {
+    format!("{} {}", n, if n == 1 { one } else { many })
+}
+
+fn preview(s: &str) -> String {
+    if s.chars().count() <= MAX_PREVIEW {
+        format!("\"{}\"", s)
+    } else {
+        let shown: String = s.chars().take(MAX_PREVIEW).collect();
+        format!("\"{}\"...", shown)
+    }
+}
+
+fn items(expr: &Expr) -> Option<&im::Vector<Expr>> {
+    match expr {
+        Expr::List(l) | Expr::Tuple(l) | Expr::Quote(l) => Some(l),
+        _ => None,
+    }
+}
+
+/// One line describing `expr`, without its elements.
+fn summary(expr: &Expr) -> String {
+    match expr {
+        Expr::List(l) | Expr::Tuple(l) | Expr::Quote(l) => {
+            format!(
+                "{}, {}",
+                expr.get_type_str(),
+                plural(l.len(), "element", "elements")
+            )
+        }
+        Expr::Dict(d) => format!("map, {}", plural(d.len(), "entry", "entries")),
+        Expr::String(s) => format!(
+            "str, {}, {}: {}",
+            plural(s.chars().count(), "char", "chars"),
+            plural(s.len(), "byte", "bytes"),
+            preview(s)
+        ),
+        Expr::Symbol(s) if s.starts_with(':') => format!("keyword {}", s),
+        Expr::Record(r) => format!("record {}", r.type_name()),
+        Expr::Function(f) => format!("func {}", f.name()),
+        Expr::LazyIter(_) => "iterator (not consumed)".into(),
+        Expr::Nil => "nil".into(),
+        rest => format!("{} {:?}", rest.get_type_str(), rest),
+    }
+}
+
+/// The elements of a collection with their labels, or None for anything else.
+fn children(expr: &Expr) -> Option<Vec<(String, &Expr)>> {
+    match expr {
+        Expr::Dict(d) => Some(
+            d.iter()
+                .sorted_by_key(|(k, _)| format!("{:?}", k))
+                .map(|(k, v)| (format!("{:?}", k), v))
+                .collect(),
+        ),
+        _ => items(expr).map(|l| {
+            l.iter()
+                .enumerate()
+                .map(|(i, e)| (i.to_string(), e))
+                .collect()
+        }),
+    }
+}
+
+fn tree(out: &mut String, label: &str, expr: &Expr, depth: usize) {
+    let indent = "  ".repeat(depth);
+    match children(expr) {
+        None => {
+            let repr = match expr {
+                Expr::String(s) => preview(s),
+                _ => format!("{:?}", expr),
+            };
+            let _ = writeln!(out, "{}{}{}", indent, label, repr);
+        }
+        Some(elements) if depth >= MAX_DEPTH && !elements.is_empty() => {
+            let _ = writeln!(out, "{}{}{} (elided)", indent, label, summary(expr));
+        }
+        Some(elements) => {
+            let _ = writeln!(out, "{}{}{}", indent, label, summary(expr));
+            tree_elements(out, &elements, depth + 1);
+        }
+    }
+}
+
+fn tree_elements(out: &mut String, elements: &[(String, &Expr)], depth: usize) {
+    for (label, element) in elements.iter().take(MAX_CHILDREN) {
+        tree(out, &format!("{}: ", label), element, depth);
+    }
+    if elements.len() > MAX_CHILDREN {
+        let _ = writeln!(
+            out,
+            "{}... {} more",
+            "  ".repeat(depth),
+            elements.len() - MAX_CHILDREN
+        );
+    }
+}
+
+/// A multi-line report on `expr`, for people rather than programs.
+pub(crate) fn inspect(expr: &Expr, symbol_table: &SymbolTable) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{}", summary(expr));
+    match expr {
+        Expr::Record(r) => {
+            let _ = writeln!(out, "value: {}", r.display());
+            let _ = writeln!(out, "methods: {}", r.methods().iter().sorted().join(", "));
+        }
+        Expr::Function(f) => {
+            let _ = writeln!(out, "minimum args: {}", f.minimum_args());
+            if !f.named_args().is_empty() {
+                let _ = writeln!(out, "args: {}", f.named_args().iter().join(" "));
+            }
+            let doc = symbol_table.get_doc_item(f.name());
+            let doc = doc.as_deref().and_then(|d| d.lines().next());
+            let _ = writeln!(out, "doc: {}", doc.unwrap_or("none"));
+        }
+        _ => {
+            if let Some(elements) = children(expr) {
+                if !elements.is_empty() {
+                    let types = elements
+                        .iter()
+                        .take(MAX_CHILDREN)
+                        .map(|(_, e)| e.get_type_str())
+                        .join(", ");
+                    let more = if elements.len() > MAX_CHILDREN {
+                        ", ..."
+                    } else {
+                        ""
+                    };
+                    let _ = writeln!(out, "element types: {}{}", types, more);
+                }
+                tree_elements(&mut out, &elements, 1);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Options;
+    use crate::parser::read;
+    use crate::stdlib::create_stdlib_symbol_table;
+
+    fn report(prog: &str) -> String {
+        let sym_table = create_stdlib_symbol_table(&Options::default());
+        let mut res = Expr::Nil;
+        for expr in read(prog) {
+            res = expr.unwrap().eval(&sym_table).unwrap();
+        }
+        inspect(&res, &sym_table)
+    }
+
+    #[test]
+    fn inspect_scalars() {
+        assert_eq!(report("42"), "num 42\n");
+        assert_eq!(report("true"), "bool true\n");
+        assert_eq!(report(":port"), "keyword :port\n");
+    }
+
+    #[test]
+    fn inspect_strings() {
+        assert_eq!(report("\"héllo\""), "str, 5 chars, 6 bytes: \"héllo\"\n");
+        assert_eq!(
+            report(&format!("\"{}\"", "abcdefghij".repeat(5))),
+            format!(
+                "str, 50 chars, 50 bytes: \"{}\"...\n",
+                "abcdefghij".repeat(4)
+            )
+        );
+    }
+
+    #[test]
+    fn inspect_lists() {
+        assert_eq!(
+            report("(list 1 \"a\" (list 2 3))"),
+            "list, 3 elements
+element types: num, str, list
+  0: 1
+  1: \"a\"
+  2: list, 2 elements
+    0: 2
+    1: 3
+"
+        );
+        assert_eq!(report("(list)"), "list, 0 elements\n");
+    }
+
+    #[test]
+    fn inspect_elides_wide_and_deep_data() {
+        assert_eq!(
+            report("(list 1 2 3 4 5 6 7)"),
+            "list, 7 elements
+element types: num, num, num, num, num, ...
+  0: 1
+  1: 2
+  2: 3
+  3: 4
+  4: 5
+  ... 2 more
+"
+        );
+        assert_eq!(
+            report("(list (list (list (list 1 2))))"),
+            "list, 1 element
+element types: list
+  0: list, 1 element
+    0: list, 1 element
+      0: list, 2 elements (elided)
+"
+        );
+    }
+
+    #[test]
+    fn inspect_dicts() {
+        assert_eq!(
+            report("(dict :b 2 :a (list 1))"),
+            "map, 2 entries
+element types: list, num
+  :a: list, 1 element
+    0: 1
+  :b: 2
+"
+        );
+    }
+
+    #[test]
+    fn inspect_records() {
+        assert_eq!(
+            report("(atom 1)"),
+            "record AtomRecord
+value: Atom<1>
+methods: deref, reset
+"
+        );
+    }
+
+    #[test]
+    fn inspect_functions() {
+        assert_eq!(
+            report("(defn add \"Add two numbers.\" (x y) (+ x y)) add"),
+            "func add
+minimum args: 2
+args: x y
+doc: Add two numbers.
+"
+        );
+        assert_eq!(
+            report("type"),
+            "func type
+minimum args: 1
+doc: Return the type of the argument as a string.
+"
+        );
+    }
+}
@@ -1,22 +1,24 @@
-use crate::symbols::{Expr, Function, LispResult, SymbolTable};
+use crate::resources::ValueBudget;
+use crate::symbols::{next_object_id, Expr, Function, LispResult, SymbolTable};
 use im::Vector;
 use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::ops::Deref;
 
-use rand::random;
+pub type IterType = Box<dyn LazyIter>;
 
-pub(crate) type IterType = Box<dyn LazyIter>;
-
-pub(crate) trait LazyIter: fmt::Display + fmt::Debug + Sync + Send {
+pub trait LazyIter: fmt::Display + fmt::Debug + Sync + Send {
     fn next(&self, symbol_table: &SymbolTable) -> Option<LispResult<Expr>>;
     fn name(&self) -> &'static str;
     fn clone(&self) -> Box<dyn LazyIter>;
     fn id(&self) -> u64;
     fn eval(&self, symbol_table: &SymbolTable) -> LispResult<Expr> {
+        let mut budget = ValueBudget::new("Realizing a lazy sequence", symbol_table);
         let mut res = Vector::new();
         while let Some(ee) = self.next(symbol_table) {
-            res.push_back(ee?)
+            let ee = ee?;
+            budget.add_expr(&ee)?;
+            res.push_back(ee)
         }
         Ok(Expr::List(res))
     }
@@ -64,9 +66,9 @@ pub(crate) struct LazyMap {
 
 impl LazyIter for LazyMap {
     fn next(&self, symbol_table: &SymbolTable) -> Option<LispResult<Expr>> {
-        self.inner
-            .next(symbol_table)
-            .map(|lispres| lispres.and_then(|e| self.f.call_fn(Vector::unit(e), symbol_table)))
+        self.inner.next(symbol_table).map(|lispres| {
+            lispres.and_then(|e| self.f.call_with_values(Vector::unit(e), symbol_table))
+        })
     }
     fn name(&self) -> &'static str {
         "Map"
@@ -85,7 +87,7 @@ impl LazyMap {
         Ok(Expr::LazyIter(Box::new(LazyMap {
             inner,
             f,
-            id: random(),
+            id: next_object_id(),
         })))
     }
 }
@@ -110,7 +112,7 @@ impl NaturalNumbers {
     pub(crate) fn lisp_res() -> LispResult<Expr> {
         Ok(Expr::LazyIter(Box::new(NaturalNumbers {
             counter: AtomicUsize::new(0),
-            id: random(),
+            id: next_object_id(),
         })))
     }
 }
@@ -146,7 +148,7 @@ impl Take {
         Ok(Expr::LazyIter(Box::new(Take {
             amount: AtomicUsize::new(amount),
             inner,
-            id: random(),
+            id: next_object_id(),
         })))
     }
 }
@@ -181,6 +183,63 @@ impl LazyIter for Take {
     }
 }
 
+/// Windows of `size` items over a list, each starting `step` after the last.
+/// Windows are slices sharing the list's structure, so making one takes
+/// O(log n) however big it is. Only whole windows are made.
+pub(crate) struct Window {
+    list: Vector<Expr>,
+    size: usize,
+    step: usize,
+    start: AtomicUsize,
+    id: u64,
+}
+
+impl Window {
+    pub(crate) fn lisp_res(size: usize, step: usize, list: Vector<Expr>) -> LispResult<Expr> {
+        Ok(Expr::LazyIter(Box::new(Window {
+            list,
+            size,
+            step,
+            start: AtomicUsize::new(0),
+            id: next_object_id(),
+        })))
+    }
+}
+
+impl Clone for Window {
+    fn clone(&self) -> Window {
+        Window {
+            list: self.list.clone(),
+            size: self.size,
+            step: self.step,
+            start: AtomicUsize::new(self.start.load(Ordering::SeqCst)),
+            id: self.id,
+        }
+    }
+}
+
+impl LazyIter for Window {
+    fn next(&self, _symbol_table: &SymbolTable) -> Option<LispResult<Expr>> {
+        let start = self.start.load(Ordering::SeqCst);
+        let end = start.checked_add(self.size)?;
+        if end > self.list.len() {
+            return None;
+        }
+        self.start
+            .store(start.saturating_add(self.step), Ordering::SeqCst);
+        Some(Ok(Expr::List(self.list.clone().slice(start..end))))
+    }
+    fn name(&self) -> &'static str {
+        "Window"
+    }
+    fn clone(&self) -> IterType {
+        Box::new(Clone::clone(self))
+    }
+    fn id(&self) -> u64 {
+        self.id
+    }
+}
+
 macro_rules! impl_dbg_inner {
 	  ($($t:ident),*) => {
 		    $(
@@ -217,4 +276,4 @@ macro_rules! impl_dbg {
 }
 
 impl_dbg_inner!(LazyMap, Take);
-impl_dbg!(NaturalNumbers);
+impl_dbg!(NaturalNumbers, Window);
@@ -0,0 +1,480 @@
+use crate::conform::describe;
+use crate::records::{AtomRecord, DictRecord, Record, RecordType, Walk};
+use crate::symbols::{next_object_id, Expr, LispResult, ProgramError};
+use crate::unknown_method;
+use anyhow::{anyhow, bail};
+use bigdecimal::BigDecimal;
+use im::Vector;
+use parking_lot::Mutex;
+use serde_json::{Map, Number, Value};
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// Conversions between x7 values and serde_json values.
+//
+// Objects become dicts with string keys. Going the other way, dict keys
+// must be strings or keywords, and keywords lose their leading colon.
+// Atoms are written as what they hold, unless they contain themselves.
+//
+// TOML and YAML values are converted through JSON values too, so every
+// format shares these rules.
+
+impl Expr {
+    /// Convert a JSON value into an x7 value.
+    pub fn from_json(value: &Value) -> Expr {
+        match value {
+            Value::Null => Expr::Nil,
+            Value::Bool(b) => Expr::Bool(*b),
+            // serde_json prints numbers losslessly, so go through the string.
+            Value::Number(n) => n
+                .to_string()
+                .parse::<BigDecimal>()
+                .map(Expr::Num)
+                .unwrap_or(Expr::Nil),
+            Value::String(s) => Expr::String(s.clone()),
+            Value::Array(items) => Expr::List(items.iter().map(Expr::from_json).collect()),
+            Value::Object(map) => Expr::Dict(
+                map.iter()
+                    .map(|(k, v)| (Expr::String(k.clone()), Expr::from_json(v)))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// A read-only view of a JSON value which converts it as it's read.
+    /// Objects become records that `get`, `keys`, `contains?`, `get-in`, and
+    /// `reduce-kv` take like dicts, converting and keeping each value the
+    /// first time it's asked for, so parts of a large document which are
+    /// never read are never converted. Builtins which read a whole dict, like
+    /// `merge` and `map-vals`, read them too. `deep-copy` makes plain dicts
+    /// of them, for `assoc` and anything else which changes a dict.
+    ///
+    /// Arrays aren't lazy: reading one converts it to a list of its items,
+    /// though objects among them stay lazy records.
+    pub fn wrap_json_lazy(value: Arc<Value>) -> Expr {
+        let converted = Arc::new(AtomicUsize::new(0));
+        let root = value.clone();
+        lazy_json(&root, &value, Vec::new(), &converted)
+    }
+
+    /// Convert this value into JSON. Functions, iterators, and records have
+    /// no JSON representation and are errors.
+    pub fn to_json(&self) -> LispResult<Value> {
+        to_serde(self, &JSON, &mut Vec::new())
+    }
+}
+
+/// A serde format whose values go through the JSON data model.
+pub(crate) struct SerdeFormat {
+    pub(crate) name: &'static str,
+    /// Whether the format can represent nil.
+    pub(crate) has_null: bool,
+}
+
+pub(crate) const JSON: SerdeFormat = SerdeFormat {
+    name: "JSON",
+    has_null: true,
+};
+
+/// Convert `expr` for `format`, naming the path to anything it can't represent.
+pub(crate) fn to_serde(
+    expr: &Expr,
+    format: &SerdeFormat,
+    path: &mut Vec<Expr>,
+) -> LispResult<Value> {
+    if let Some(atom) = AtomRecord::from_expr(expr) {
+        return atom
+            .visit(Walk::Serialize, |value| to_serde(value, format, path))
+            .unwrap_or_else(|| {
+                Err(anyhow!(ProgramError::Cyclic).context(format!(
+                    "Cannot convert an atom which contains itself to {}, at {}",
+                    format.name,
+                    describe(path)
+                )))
+            });
+    }
+    let value = match expr.unmeta() {
+        Expr::Nil if format.has_null => Value::Null,
+        Expr::Bool(b) => Value::Bool(*b),
+        Expr::Num(n) => Value::Number(json_number(n, format, path)?),
+        Expr::String(s) => Value::String(s.clone()),
+        Expr::List(l) | Expr::Tuple(l) | Expr::Quote(l) => {
+            let mut items = Vec::with_capacity(l.len());
+            for (i, item) in l.iter().enumerate() {
+                path.push(Expr::from(i as i64));
+                items.push(to_serde(item, format, path)?);
+                path.pop();
+            }
+            Value::Array(items)
+        }
+        Expr::Dict(d) => {
+            let mut map = Map::new();
+            for (k, v) in d.iter() {
+                path.push(k.clone());
+                let key = json_key(k, format, path)?;
+                map.insert(key, to_serde(v, format, path)?);
+                path.pop();
+            }
+            Value::Object(map)
+        }
+        rest => bail!(
+            "Cannot convert {} of type {} to {}, at {}",
+            rest,
+            rest.get_type_str(),
+            format.name,
+            describe(path)
+        ),
+    };
+    Ok(value)
+}
+
+fn json_number(n: &BigDecimal, format: &SerdeFormat, path: &[Expr]) -> LispResult<Number> {
+    // Whole numbers are written without a fraction, even with a scale.
+    let n = if n.with_scale(0) == *n {
+        n.with_scale(0)
+    } else {
+        n.clone()
+    };
+    // Parse the decimal digits, so fractions round once, to the nearest f64.
+    serde_json::from_str::<Number>(&n.to_string())
+        .ok()
+        .filter(|number| number.as_f64().is_some_and(f64::is_finite))
+        .ok_or_else(|| {
+            anyhow!(
+                "Cannot represent {} as a {} number, at {}",
+                n,
+                format.name,
+                describe(path)
+            )
+        })
+}
+
+fn json_key(key: &Expr, format: &SerdeFormat, path: &[Expr]) -> LispResult<String> {
+    match key {
+        Expr::String(s) => Ok(s.clone()),
+        Expr::Symbol(s) if s.starts_with(':') => Ok(s[1..].into()),
+        rest => bail!(
+            "{} keys must be strings or keywords, but got {:?}, at {}",
+            format.name,
+            rest,
+            describe(path)
+        ),
+    }
+}
+
+/// A step from a JSON value to one inside it.
+#[derive(Clone)]
+enum Step {
+    Key(String),
+    Index(usize),
+}
+
+/// `value`, at `path` in `root`, as a lazily converted x7 value.
+fn lazy_json(
+    root: &Arc<Value>,
+    value: &Value,
+    path: Vec<Step>,
+    converted: &Arc<AtomicUsize>,
+) -> Expr {
+    converted.fetch_add(1, Ordering::Relaxed);
+    match value {
+        Value::Object(_) => Expr::Record(Box::new(LazyJson {
+            root: root.clone(),
+            path,
+            children: Arc::new(Mutex::new(HashMap::new())),
+            converted: converted.clone(),
+            id: next_object_id(),
+        })),
+        Value::Array(items) => Expr::List(
+            items
+                .iter()
+                .enumerate()
+                .map(|(i, item)| {
+                    let mut path = path.clone();
+                    path.push(Step::Index(i));
+                    lazy_json(root, item, path, converted)
+                })
+                .collect(),
+        ),
+        scalar => Expr::from_json(scalar),
+    }
+}
+
+/// A JSON object from `Expr::wrap_json_lazy`.
+#[derive(Clone)]
+pub(crate) struct LazyJson {
+    root: Arc<Value>,
+    // Where the object is in `root`.
+    path: Vec<Step>,
+    // Its values converted so far, shared by clones.
+    children: Arc<Mutex<HashMap<String, Expr>>>,
+    // How many JSON values every view of `root` has converted.
+    converted: Arc<AtomicUsize>,
+    id: u64,
+}
+
+impl LazyJson {
+    fn object(&self) -> &Map<String, Value> {
+        let mut node = &*self.root;
+        for step in &self.path {
+            node = match step {
+                Step::Key(key) => &node[key.as_str()],
+                Step::Index(i) => &node[*i],
+            };
+        }
+        match node {
+            Value::Object(map) => map,
+            _ => unreachable!("LazyJson paths only lead to objects"),
+        }
+    }
+
+    /// How many JSON values have been converted, from any part of the
+    /// document this object came from.
+    #[cfg(test)]
+    fn converted(&self) -> usize {
+        self.converted.load(Ordering::Relaxed)
+    }
+}
+
+impl DictRecord for LazyJson {
+    fn get(&self, key: &Expr) -> LispResult<Option<Expr>> {
+        // Like `to_json`, keywords stand for the string without the colon.
+        let key = match key.unmeta() {
+            Expr::String(s) => s.as_str(),
+            Expr::Symbol(s) if s.starts_with(':') => &s[1..],
+            _ => return Ok(None),
+        };
+        if let Some(child) = self.children.lock().get(key) {
+            return Ok(Some(child.clone()));
+        }
+        let value = match self.object().get(key) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let mut path = self.path.clone();
+        path.push(Step::Key(key.into()));
+        let child = lazy_json(&self.root, value, path, &self.converted);
+        self.children.lock().insert(key.into(), child.clone());
+        Ok(Some(child))
+    }
+
+    fn keys(&self) -> LispResult<Vec<Expr>> {
+        Ok(self.object().keys().cloned().map(Expr::String).collect())
+    }
+
+    fn deep_copy(&self) -> LispResult<Expr> {
+        let map = self.object().iter();
+        Ok(Expr::Dict(
+            map.map(|(k, v)| (Expr::String(k.clone()), Expr::from_json(v)))
+                .collect(),
+        ))
+    }
+}
+
+impl Record for LazyJson {
+    fn call_method(&self, sym: &str, _args: Vector<Expr>) -> LispResult<Expr> {
+        unknown_method!(self, sym)
+    }
+
+    fn display(&self) -> String {
+        format!("{{JSON object with {} keys}}", self.object().len())
+    }
+
+    fn debug(&self) -> String {
+        self.display()
+    }
+
+    fn clone(&self) -> RecordType {
+        Box::new(Clone::clone(self))
+    }
+
+    fn methods(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+
+    fn type_name(&self) -> &'static str {
+        "LazyJson"
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
+
+    fn as_dict(&self) -> Option<&dyn DictRecord> {
+        Some(self)
+    }
+
+    fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn json_round_trip() {
+        let value = json!({
+            "name": "x7",
+            "tags": ["lisp", "rust"],
+            "version": 1,
+            "ratio": 0.5,
+            "stable": false,
+            "parent": null
+        });
+        let expr = Expr::from_json(&value);
+        assert_eq!(expr.to_json().unwrap(), value);
+    }
+
+    #[test]
+    fn json_numbers_round_once() {
+        let num = |s: &str| Expr::Num(s.parse().unwrap());
+        assert_eq!(num("1.000").to_json().unwrap(), json!(1));
+        assert_eq!(num("-12").to_json().unwrap(), json!(-12));
+        assert_eq!(
+            num("18446744073709551615").to_json().unwrap(),
+            json!(u64::MAX)
+        );
+        assert_eq!(num("0.1").to_json().unwrap(), json!(0.1));
+        assert_eq!(
+            num("123456789.123456789").to_json().unwrap(),
+            json!("123456789.123456789".parse::<f64>().unwrap())
+        );
+        assert!(num("1e400").to_json().is_err());
+    }
+
+    #[test]
+    fn json_keywords_and_errors() {
+        let mut dict = im::HashMap::new();
+        dict.insert(Expr::Symbol(":a".into()), Expr::from(1));
+        assert_eq!(Expr::Dict(dict).to_json().unwrap(), json!({"a": 1}));
+
+        let mut dict = im::HashMap::new();
+        dict.insert(Expr::from(1), Expr::from(1));
+        assert!(Expr::Dict(dict).to_json().is_err());
+
+        let mut inner = im::HashMap::new();
+        inner.insert(Expr::Symbol(":f".into()), Expr::Symbol("f".into()));
+        let mut dict = im::HashMap::new();
+        dict.insert(
+            Expr::Symbol(":a".into()),
+            Expr::List(im::vector![Expr::from(1), Expr::Dict(inner)]),
+        );
+        let err = Expr::Dict(dict).to_json().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot convert f of type symbol to JSON, at :a 1 :f"
+        );
+    }
+
+    #[test]
+    fn atoms_serialize_as_their_value_unless_cyclic() {
+        use crate::cli::Options;
+        use crate::stdlib::create_stdlib_symbol_table;
+        let syms = create_stdlib_symbol_table(&Options::default());
+        assert_eq!(
+            syms.eval_source("(json-serialize (dict :n (atom (list 1 (atom 2)))))")
+                .unwrap(),
+            Expr::from("{\"n\":[1,2]}")
+        );
+        let err = syms
+            .eval_source("(def a (atom 1)) (.reset a (dict :self a)) (json-serialize a)")
+            .unwrap_err();
+        assert_eq!(crate::symbols::error_kind(&err), "Cyclic");
+        let cyclic = "Cannot convert an atom which contains itself to JSON, at :self";
+        assert!(
+            err.chain().any(|cause| cause.to_string() == cyclic),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn lazy_json_reads_like_the_eager_conversion() {
+        use crate::cli::Options;
+        use crate::stdlib::create_stdlib_symbol_table;
+        let value = json!({
+            "name": "x7",
+            "deps": [{"name": "im", "features": ["serde"]}, {"name": "nom"}],
+            "untouched": {"a": [1, 2, {"b": 3}], "c": {"d": null}}
+        });
+        let eager = Expr::from_json(&value);
+        let lazy = Expr::wrap_json_lazy(Arc::new(value));
+        let record = lazy.get_record().unwrap();
+        let converted = || {
+            let any = record.as_any().unwrap();
+            any.downcast_ref::<LazyJson>().unwrap().converted()
+        };
+        assert_eq!(converted(), 1);
+
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.add_global("eager", eager);
+        syms.add_global("lazy", lazy.clone());
+        for read in &[
+            "(get {} \"name\")",
+            "(keys {})",
+            "(contains? {} \"deps\")",
+            "(contains? {} \"nope\")",
+            "(get-in {} '(\"deps\" 0 \"features\" 0))",
+            "(get-in {} '(\"deps\" 1 \"name\"))",
+            "(get-in {} '(\"deps\" 5 \"name\") :missing)",
+            "(reduce-kv (fn (acc k v) (+ acc (len k))) 0 (get-in {} '(\"deps\" 0)))",
+        ] {
+            let from_eager = syms.eval_source(&read.replace("{}", "eager"));
+            let from_lazy = syms.eval_source(&read.replace("{}", "lazy"));
+            assert_eq!(from_lazy.unwrap(), from_eager.unwrap(), "{}", read);
+        }
+        // The root, name, deps, its two objects, their names, and the
+        // features list and its item, but nothing under "untouched".
+        assert_eq!(converted(), 9);
+        // Values are converted once, then kept, and keywords read as strings.
+        assert_eq!(
+            syms.eval_source("(get-in lazy '(:deps 0 :features 0))")
+                .unwrap(),
+            Expr::String("serde".into())
+        );
+        assert_eq!(converted(), 9);
+
+        // Reading an array converts all of it, but not the objects inside.
+        assert_eq!(
+            syms.eval_source("(len (get-in lazy '(\"untouched\" \"a\")))")
+                .unwrap(),
+            Expr::from(3)
+        );
+        // "untouched", "a", and its three items.
+        assert_eq!(converted(), 14);
+
+        // Builtins reading whole dicts take them like dicts too.
+        for read in &[
+            "(len {})",
+            "(get (merge {} (dict \"extra\" 1)) \"name\")",
+            "(get (merge-with + (dict \"n\" 1) (dict \"n\" 2) {}) \"n\")",
+            "(keys (map-vals (fn (v) 1) {}))",
+            "(keys (map-keys (fn (k) (+ k \"!\")) {}))",
+            "(keys (filter-kv (fn (k v) (= k \"name\")) {}))",
+            "(select-keys {} '(\"name\" \"nope\"))",
+            "(get (invert (select-keys {} '(\"name\"))) \"x7\")",
+            "(get-in (deep-merge {} (dict \"untouched\" (dict \"e\" 1))) '(\"untouched\" \"e\"))",
+        ] {
+            let from_eager = syms.eval_source(&read.replace("{}", "eager"));
+            let from_lazy = syms.eval_source(&read.replace("{}", "lazy"));
+            assert_eq!(from_lazy.unwrap(), from_eager.unwrap(), "{}", read);
+        }
+
+        let err = syms.eval_source("(assoc lazy :a 1)").unwrap_err();
+        assert!(format!("{:?}", err).contains("deep-copy"), "{:?}", err);
+        assert_eq!(
+            syms.eval_source("(deep-copy lazy)").unwrap(),
+            syms.eval_source("eager").unwrap()
+        );
+        assert_eq!(
+            syms.eval_source("(get (assoc (deep-copy lazy) \"name\" 1) \"name\")")
+                .unwrap(),
+            Expr::from(1)
+        );
+    }
+}
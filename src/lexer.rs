@@ -0,0 +1,331 @@
+use crate::parser::{is_symbol_char, parse_bool, parse_num, parse_string};
+use std::ops::Range;
+
+// A one-pass tokenizer for editors and syntax highlighters. Unlike the
+// parser it never gives up: every byte of the input lands in some token,
+// and input the parser would reject becomes `Error` tokens.
+//
+// Atoms are recognized by the parser's own functions (`parse_num`,
+// `is_symbol_char`, ...), so the two agree on where a number ends or
+// what counts as a symbol.
+
+/// What a token is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TokenKind {
+    OpenParen,
+    CloseParen,
+    String,
+    Number,
+    Bool,
+    Symbol,
+    /// A symbol starting with `:`, like `:foo`.
+    Keyword,
+    /// A symbol starting with `.`, like `.deref`.
+    Method,
+    /// The `'` in `'(1 2)`.
+    QuoteMarker,
+    /// The `^` in `^(1 2)`.
+    TupleMarker,
+    /// The `@` in `@rest`.
+    SpreadMarker,
+    /// The `~` in `~port`.
+    UnquoteMarker,
+    /// A tag and the string it reads, like `#duration"5m"`.
+    TaggedLiteral,
+    Comment,
+    Whitespace,
+    /// Input the parser can't read, like an unterminated string.
+    Error,
+}
+
+/// A token, located by byte range and by 1-based line and column.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub range: Range<usize>,
+    pub line: usize,
+    /// Counted in chars.
+    pub col: usize,
+}
+
+impl Token {
+    /// The source text of this token.
+    pub fn text<'a>(&self, src: &'a str) -> &'a str {
+        &src[self.range.clone()]
+    }
+}
+
+/// The length of the symbol characters at the start of `s`.
+fn symbol_len(s: &str) -> usize {
+    s.find(|c| !is_symbol_char(c)).unwrap_or(s.len())
+}
+
+/// The kind and length of the token at the start of `rest`, which is not empty.
+fn next_token(rest: &str) -> (TokenKind, usize) {
+    let c = rest.chars().next().unwrap();
+    let consumed = |after: &str| rest.len() - after.len();
+    match c {
+        '(' => (TokenKind::OpenParen, 1),
+        ')' => (TokenKind::CloseParen, 1),
+        '\'' => (TokenKind::QuoteMarker, 1),
+        '^' => (TokenKind::TupleMarker, 1),
+        '@' => (TokenKind::SpreadMarker, 1),
+        '~' => (TokenKind::UnquoteMarker, 1),
+        ';' => (TokenKind::Comment, rest.find('\n').unwrap_or(rest.len())),
+        c if c.is_whitespace() => (
+            TokenKind::Whitespace,
+            rest.find(|c: char| !c.is_whitespace())
+                .unwrap_or(rest.len()),
+        ),
+        // An unterminated string runs to the end of the input, as far as
+        // the parser is concerned.
+        '"' => match parse_string(rest) {
+            Ok((after, _)) => (TokenKind::String, consumed(after)),
+            Err(_) => (TokenKind::Error, rest.len()),
+        },
+        // Without a string right after it, `#tag` is just a symbol.
+        '#' if symbol_len(rest) > 1 && rest[symbol_len(rest)..].starts_with('"') => {
+            match parse_string(&rest[symbol_len(rest)..]) {
+                Ok((after, _)) => (TokenKind::TaggedLiteral, consumed(after)),
+                Err(_) => (TokenKind::Error, rest.len()),
+            }
+        }
+        _ => match parse_num(rest) {
+            Ok((after, _)) => (TokenKind::Number, consumed(after)),
+            // Like `1e`, which starts a number but can't finish it.
+            Err(nom::Err::Failure(_)) => (TokenKind::Error, symbol_len(rest).max(1)),
+            Err(_) => match parse_bool(rest) {
+                Ok((after, _)) => (TokenKind::Bool, consumed(after)),
+                Err(_) => match symbol_len(rest) {
+                    0 => (TokenKind::Error, c.len_utf8()),
+                    len if rest.starts_with(':') => (TokenKind::Keyword, len),
+                    len if rest.starts_with('.') => (TokenKind::Method, len),
+                    len => (TokenKind::Symbol, len),
+                },
+            },
+        },
+    }
+}
+
+/// Split `src` into tokens covering all of it, in order.
+pub fn lex(src: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let (mut pos, mut line, mut col) = (0, 1, 1);
+    while pos < src.len() {
+        let (kind, len) = next_token(&src[pos..]);
+        let range = pos..pos + len;
+        tokens.push(Token {
+            kind,
+            range: range.clone(),
+            line,
+            col,
+        });
+        for c in src[range].chars() {
+            if c == '\n' {
+                line += 1;
+                col = 1;
+            } else {
+                col += 1;
+            }
+        }
+        pos += len;
+    }
+    tokens
+}
+
+/// Whether `src` stops partway through a form: inside a string or a tagged
+/// literal, with a `(` left open, or after a `'`, `^`, or `@`. A REPL keeps reading lines until
+/// it isn't. Extra `)`s don't make it incomplete, they're an error to report.
+pub fn is_incomplete(src: &str) -> bool {
+    let mut depth = 0;
+    let mut last = None;
+    for token in lex(src) {
+        match token.kind {
+            TokenKind::Whitespace | TokenKind::Comment => continue,
+            TokenKind::OpenParen => depth += 1,
+            TokenKind::CloseParen => depth -= 1,
+            TokenKind::Error if token.text(src).starts_with(&['"', '#'][..]) => return true,
+            _ => {}
+        }
+        last = Some(token.kind);
+    }
+    depth > 0
+        || matches!(
+            last,
+            Some(TokenKind::QuoteMarker)
+                | Some(TokenKind::TupleMarker)
+                | Some(TokenKind::SpreadMarker)
+                | Some(TokenKind::UnquoteMarker)
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{read, READER_TAG, SPREAD_FORM};
+    use crate::symbols::{Expr, Num};
+    use TokenKind::*;
+
+    fn kinds(src: &str) -> Vec<(TokenKind, &str)> {
+        lex(src)
+            .into_iter()
+            .map(|t| (t.kind, t.text(src)))
+            .filter(|(k, _)| *k != Whitespace)
+            .collect()
+    }
+
+    #[test]
+    fn lex_tokens() {
+        let src = "(defn f (x) ; doc\n  (.m x :k 1.5 \"s\" true ^(1) '(a) @xs))";
+        assert_eq!(
+            kinds(src),
+            vec![
+                (OpenParen, "("),
+                (Symbol, "defn"),
+                (Symbol, "f"),
+                (OpenParen, "("),
+                (Symbol, "x"),
+                (CloseParen, ")"),
+                (Comment, "; doc"),
+                (OpenParen, "("),
+                (Method, ".m"),
+                (Symbol, "x"),
+                (Keyword, ":k"),
+                (Number, "1.5"),
+                (String, "\"s\""),
+                (Bool, "true"),
+                (TupleMarker, "^"),
+                (OpenParen, "("),
+                (Number, "1"),
+                (CloseParen, ")"),
+                (QuoteMarker, "'"),
+                (OpenParen, "("),
+                (Symbol, "a"),
+                (CloseParen, ")"),
+                (SpreadMarker, "@"),
+                (Symbol, "xs"),
+                (CloseParen, ")"),
+                (CloseParen, ")"),
+            ]
+        );
+        let tokens = lex(src);
+        let method = tokens.iter().find(|t| t.kind == Method).unwrap();
+        assert_eq!(
+            (method.range.clone(), method.line, method.col),
+            (21..23, 2, 4)
+        );
+    }
+
+    #[test]
+    fn lex_broken_input() {
+        assert_eq!(
+            kinds("(f 1e \"oops\n(g)"),
+            vec![
+                (OpenParen, "("),
+                (Symbol, "f"),
+                (Error, "1e"),
+                (Error, "\"oops\n(g)"),
+            ]
+        );
+        assert_eq!(
+            kinds("(#re\"a+\" #path\"/tmp)"),
+            vec![
+                (OpenParen, "("),
+                (TaggedLiteral, "#re\"a+\""),
+                (Error, "#path\"/tmp)"),
+            ]
+        );
+        assert_eq!(kinds("a;b"), vec![(Symbol, "a"), (Comment, ";b")]);
+        assert!(lex("").is_empty());
+    }
+
+    #[test]
+    fn incomplete_input() {
+        for src in &["(f 1", "(f \"a)\nb", "'", "(f) ^", "(f ; )", "#re\"a"] {
+            assert!(is_incomplete(src), "{}", src);
+        }
+        for src in &[
+            "",
+            "(f 1)",
+            "(f))",
+            "\"a(\"",
+            "x ; (",
+            "'(a)",
+            "#re\"a(\"",
+            "#re",
+        ] {
+            assert!(!is_incomplete(src), "{}", src);
+        }
+    }
+
+    /// The atoms of `expr` in source order, as the lexer would describe them.
+    fn parsed_atoms(expr: &Expr, out: &mut Vec<(TokenKind, std::string::String)>) {
+        match expr {
+            Expr::List(l) if l.len() == 3 && l[0].symbol_matches(READER_TAG) => {
+                let text = format!("#{}{:?}", l[1].get_string().unwrap(), l[2]);
+                out.push((TaggedLiteral, text));
+            }
+            Expr::List(l) => l.iter().for_each(|e| parsed_atoms(e, out)),
+            Expr::Quote(l) => {
+                out.push((QuoteMarker, "'".into()));
+                l.iter().for_each(|e| parsed_atoms(e, out));
+            }
+            Expr::String(s) => out.push((String, s.clone())),
+            Expr::Num(n) => out.push((Number, n.to_string())),
+            Expr::Bool(b) => out.push((Bool, b.to_string())),
+            Expr::Symbol(s) if s.starts_with(':') => out.push((Keyword, s.clone())),
+            Expr::Symbol(s) => out.push((Symbol, s.clone())),
+            Expr::Function(f) => out.push((Method, f.name().into())),
+            other => panic!("the parser made {:?}", other),
+        }
+    }
+
+    /// The atoms of `tokens`, with the symbols the parser adds for `^`, `@`, and `~`.
+    fn lexed_atoms(src: &str, tokens: &[Token]) -> Vec<(TokenKind, std::string::String)> {
+        tokens
+            .iter()
+            .filter_map(|t| {
+                let text = t.text(src);
+                Some(match t.kind {
+                    String => (String, text[1..text.len() - 1].into()),
+                    Number => (Number, text.parse::<Num>().unwrap().to_string()),
+                    Method => (Method, format!("method_call<{}>", &text[1..])),
+                    TupleMarker => (Symbol, "tuple".into()),
+                    SpreadMarker => (Symbol, SPREAD_FORM.into()),
+                    UnquoteMarker => (Symbol, "unquote".into()),
+                    OpenParen | CloseParen | Comment | Whitespace => return None,
+                    kind => (kind, text.into()),
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lexer_agrees_with_parser() {
+        let corpus = [
+            include_str!("../stdlib/base.x7"),
+            include_str!("../stdlib/test.x7"),
+            include_str!("../tests/fixtures/reference.x7"),
+            include_str!("../tests/fixtures/docgen/math.x7"),
+            "(f ^(1 -2.5 .5) '(a '(b)) @(list :x) (.m r) truex 1.5.2 a@b -x)",
+            "(data (a ~b ~(f c) d~e))",
+            "(f #re\"a+\" '(#path\"/tmp\") #\"x\" #tag x#y\"z\")",
+        ];
+        for src in corpus.iter() {
+            let tokens = lex(src);
+            let mut end = 0;
+            for t in &tokens {
+                assert_eq!(t.range.start, end, "tokens must cover the input");
+                end = t.range.end;
+            }
+            assert_eq!(end, src.len());
+            assert!(tokens.iter().all(|t| t.kind != Error));
+
+            let mut expected = Vec::new();
+            for expr in read(src).keeping_reader_tags() {
+                parsed_atoms(&expr.unwrap(), &mut expected);
+            }
+            assert_eq!(lexed_atoms(src, &tokens), expected);
+        }
+    }
+}
@@ -1,7 +1,83 @@
+mod access;
+mod annotations;
+mod args;
+mod bench;
+mod cache;
+mod callable;
+mod canonical;
+mod chars;
+pub mod check;
 pub mod cli;
+mod compiled;
+#[cfg(feature = "compression")]
+mod compression;
+mod config;
+mod conform;
+mod deprecated;
+mod dicts;
+mod diff;
+pub mod docgen;
+#[cfg(feature = "shell")]
+mod env;
+pub mod export;
+mod features;
+#[cfg(feature = "io")]
+mod files;
+mod format;
+mod generator;
+mod history;
+mod host;
+mod inspect;
 mod iterators;
+#[cfg(feature = "json")]
+mod json;
+mod lexer;
+pub mod lint;
+mod meta;
+mod metrics;
 pub mod modules;
 mod parser;
+mod paths;
+mod pretty;
+mod property;
 mod records;
+#[cfg(feature = "remote-repl")]
+mod remote;
+mod resolve;
+pub mod resources;
+#[cfg(feature = "time")]
+mod retry;
+pub mod runner;
+#[cfg(feature = "json")]
+mod serde_formats;
+mod snapshot;
 pub mod stdlib;
 mod symbols;
+mod table;
+#[cfg(feature = "io")]
+mod tempfiles;
+mod template;
+mod terminal;
+mod text;
+mod throw;
+mod units;
+#[cfg(feature = "watch")]
+mod watch;
+
+pub use access::{Decision, IoKind, IoOp};
+pub use compiled::CompiledExpr;
+pub use config::InterpreterConfig;
+pub use history::Evaluation;
+pub use host::Warning;
+pub use lexer::{is_incomplete, lex, Token, TokenKind};
+pub use metrics::{analyze, ProgramLimits, SourceMetrics};
+pub use records::{DictRecord, MethodHandle, Record, RecordType};
+#[cfg(feature = "remote-repl")]
+pub use remote::{attach, ReplClient, ReplServer};
+pub use resources::ResourceReport;
+pub use runner::{
+    run_script, run_source, run_source_async, RunError, RunFuture, RunOptions, RunOutcome,
+};
+pub use snapshot::{diff_globals, GlobalsDiff, GlobalsSnapshot};
+pub use symbols::{Dict, Expr, Function, LispResult, Num, Span, SymbolTable};
+pub use terminal::{ColorDepth, TerminalInfo};
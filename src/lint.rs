@@ -0,0 +1,692 @@
+use crate::check::{check_source, json_string, Diagnostic, Severity};
+use crate::cli::Options;
+use crate::docgen::source_files;
+use crate::lexer::{lex, Token, TokenKind};
+use crate::stdlib::create_stdlib_symbol_table;
+use crate::symbols::{Expr, LispResult};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::iter::Peekable;
+use std::mem;
+use std::ops::Range;
+use std::slice::Iter;
+
+// `x7 lint <paths>` reports code which reads fine but is probably a mistake,
+// like an `if` without an else branch or a `bind` whose binding is never
+// used. Files with syntax errors get those reported instead, as by `check`.
+//
+// Forms are read from the lexer's tokens rather than the parser's values, so
+// every form keeps where it is in the source. Where the fix is mechanical a
+// lint suggests replacement text for the whole form, and `--fix` applies the
+// suggestions marked safe, the ones which can't change what the code does.
+//
+// A `;#allow(lint-name)` comment turns a lint off for the form after it,
+// everything inside that form included. Several names can be given,
+// separated by commas.
+//
+// The parser's `attach_comment` isn't used for `;#allow`, as it only gives
+// a `;;` comment to a `defn` as its docstring, and the parser's values have
+// neither the source range of each form nor a place for other comments.
+// Fixes aren't written out by `pretty` for the same reason: it prints
+// values, so rewriting a form through it would drop the comments and the
+// layout around the fix. Replacing the form's text leaves the rest of the
+// file as it was.
+
+/// A fix for a lint: replace `range` of the source with `replacement`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fix {
+    pub range: Range<usize>,
+    pub replacement: String,
+    /// Whether `--fix` applies it. Unsafe fixes are only suggested.
+    pub safe: bool,
+}
+
+/// A probable mistake found in a file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lint {
+    /// The lint's name, as given to `;#allow(...)`.
+    pub name: &'static str,
+    pub diagnostic: Diagnostic,
+    pub fix: Option<Fix>,
+}
+
+impl fmt::Display for Lint {
+    /// A `check` style line, then the suggestion, if any, on the next.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} [{}]", self.diagnostic, self.name)?;
+        if let Some(fix) = &self.fix {
+            write!(f, "\n  suggestion: {}", fix.replacement)?;
+        }
+        Ok(())
+    }
+}
+
+impl Lint {
+    /// Like `Diagnostic::to_json`, with `"lint"` and `"suggestion"` too.
+    pub fn to_json(&self) -> String {
+        let diagnostic = self.diagnostic.to_json();
+        let suggestion = self
+            .fix
+            .as_ref()
+            .map_or("null".into(), |fix| json_string(&fix.replacement));
+        format!(
+            "{}, \"lint\": {}, \"suggestion\": {}}}",
+            &diagnostic[..diagnostic.len() - 1],
+            json_string(self.name),
+            suggestion
+        )
+    }
+}
+
+/// `lints` as a JSON array, one per line.
+pub fn lints_json(lints: &[Lint]) -> String {
+    if lints.is_empty() {
+        return "[]".into();
+    }
+    let items: Vec<String> = lints.iter().map(|l| format!("  {}", l.to_json())).collect();
+    format!("[\n{}\n]", items.join(",\n"))
+}
+
+/// A form as written.
+struct Node {
+    kind: NodeKind,
+    /// From the start of its first token, prefix markers included, to the end of its last.
+    range: Range<usize>,
+    line: usize,
+    col: usize,
+    /// Lints turned off for this form by `;#allow(...)` comments before it.
+    allowed: Vec<String>,
+}
+
+enum NodeKind {
+    List(Vec<Node>),
+    Atom(TokenKind),
+    /// A form after a `'`, `^`, or `@`.
+    Prefixed(TokenKind, Box<Node>),
+}
+
+type Tokens<'a> = Peekable<Iter<'a, Token>>;
+
+/// The lint names in an `;#allow(a, b)` comment.
+fn allowed_in(comment: &str) -> Vec<String> {
+    comment
+        .trim_end()
+        .strip_prefix(";#allow(")
+        .and_then(|rest| rest.strip_suffix(')'))
+        .map_or_else(Vec::new, |names| {
+            names
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect()
+        })
+}
+
+/// The forms up to the next unmatched `)` or the end.
+fn read_forms(src: &str, tokens: &mut Tokens) -> Vec<Node> {
+    let mut forms = Vec::new();
+    let mut allowed = Vec::new();
+    while let Some(token) = tokens.peek() {
+        match token.kind {
+            TokenKind::CloseParen => break,
+            TokenKind::Comment => {
+                allowed.extend(allowed_in(token.text(src)));
+                tokens.next();
+            }
+            _ => {
+                if let Some(mut form) = read_form(src, tokens) {
+                    form.allowed = mem::take(&mut allowed);
+                    forms.push(form);
+                }
+            }
+        }
+    }
+    forms
+}
+
+fn read_form(src: &str, tokens: &mut Tokens) -> Option<Node> {
+    let token = tokens.next()?;
+    let (kind, end) = match token.kind {
+        TokenKind::OpenParen => {
+            let items = read_forms(src, tokens);
+            (NodeKind::List(items), tokens.next()?.range.end)
+        }
+        TokenKind::QuoteMarker
+        | TokenKind::TupleMarker
+        | TokenKind::SpreadMarker
+        | TokenKind::UnquoteMarker => {
+            while tokens.peek()?.kind == TokenKind::Comment {
+                tokens.next();
+            }
+            let inner = read_form(src, tokens)?;
+            let end = inner.range.end;
+            (NodeKind::Prefixed(token.kind, Box::new(inner)), end)
+        }
+        kind => (NodeKind::Atom(kind), token.range.end),
+    };
+    Some(Node {
+        kind,
+        range: token.range.start..end,
+        line: token.line,
+        col: token.col,
+        allowed: Vec::new(),
+    })
+}
+
+impl Node {
+    fn text<'a>(&self, src: &'a str) -> &'a str {
+        &src[self.range.clone()]
+    }
+
+    /// The name, if this is a plain symbol.
+    fn symbol<'a>(&self, src: &'a str) -> Option<&'a str> {
+        match self.kind {
+            NodeKind::Atom(TokenKind::Symbol) => Some(self.text(src)),
+            _ => None,
+        }
+    }
+
+    /// The name of the symbol this list starts with, if any.
+    fn head<'a>(&self, src: &'a str) -> Option<&'a str> {
+        self.items()?.first()?.symbol(src)
+    }
+
+    fn items(&self) -> Option<&[Node]> {
+        match &self.kind {
+            NodeKind::List(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    fn is_quoted(&self, src: &str) -> bool {
+        match &self.kind {
+            NodeKind::Prefixed(TokenKind::QuoteMarker, _) => true,
+            NodeKind::List(_) => self.head(src) == Some("quote"),
+            _ => false,
+        }
+    }
+
+    /// Whether the symbol `name` appears anywhere in this form.
+    fn mentions(&self, src: &str, name: &str) -> bool {
+        match &self.kind {
+            NodeKind::List(items) => items.iter().any(|item| item.mentions(src, name)),
+            NodeKind::Prefixed(_, inner) => inner.mentions(src, name),
+            NodeKind::Atom(_) => self.symbol(src) == Some(name),
+        }
+    }
+}
+
+/// Parameter names in a parameter list, leaving out `&` and type annotations.
+fn param_names<'a>(src: &'a str, params: &'a Node) -> Vec<&'a Node> {
+    params.items().map_or_else(Vec::new, |items| {
+        items
+            .iter()
+            .filter(|p| p.symbol(src).is_some_and(|s| s != "&"))
+            .collect()
+    })
+}
+
+struct Linter<'a> {
+    path: &'a str,
+    src: &'a str,
+    builtins: &'a HashSet<String>,
+    /// Lints turned off for the form being visited.
+    allowed: Vec<String>,
+    lints: Vec<Lint>,
+}
+
+impl<'a> Linter<'a> {
+    fn report(&mut self, node: &Node, name: &'static str, message: String, fix: Option<Fix>) {
+        if self.allowed.iter().any(|allowed| allowed == name) {
+            return;
+        }
+        self.lints.push(Lint {
+            name,
+            diagnostic: Diagnostic {
+                path: self.path.into(),
+                line: node.line,
+                col: node.col,
+                message,
+                severity: Severity::Warning,
+            },
+            fix,
+        });
+    }
+
+    fn visit(&mut self, node: &Node) {
+        let depth = self.allowed.len();
+        self.allowed.extend(node.allowed.iter().cloned());
+        match &node.kind {
+            // Only the unquoted parts of data are code.
+            NodeKind::List(items) if node.head(self.src) == Some("data") => items
+                .iter()
+                .skip(1)
+                .for_each(|item| self.visit_unquoted(item)),
+            NodeKind::List(items) => {
+                if !node.is_quoted(self.src) {
+                    self.check_call(node, items);
+                    items.iter().for_each(|item| self.visit(item));
+                } else if let Some(quoted) = items.get(1) {
+                    self.check_quote(node, quoted);
+                }
+            }
+            // Quoted forms are data, not code.
+            NodeKind::Prefixed(TokenKind::QuoteMarker, quoted) => self.check_quote(node, quoted),
+            NodeKind::Prefixed(_, inner) => self.visit(inner),
+            NodeKind::Atom(_) => {}
+        }
+        self.allowed.truncate(depth);
+    }
+
+    fn visit_unquoted(&mut self, node: &Node) {
+        match &node.kind {
+            NodeKind::Prefixed(TokenKind::UnquoteMarker, inner) => self.visit(inner),
+            NodeKind::List(items) if node.head(self.src) == Some("unquote") => {
+                items.iter().skip(1).for_each(|item| self.visit(item))
+            }
+            NodeKind::List(items) => items.iter().for_each(|item| self.visit_unquoted(item)),
+            NodeKind::Prefixed(_, inner) => self.visit_unquoted(inner),
+            NodeKind::Atom(_) => {}
+        }
+    }
+
+    fn check_quote(&mut self, node: &Node, quoted: &Node) {
+        if !quoted.is_quoted(self.src) {
+            return;
+        }
+        let innermost = match &quoted.kind {
+            NodeKind::Prefixed(_, inner) => inner.text(self.src),
+            _ => quoted
+                .items()
+                .and_then(|l| l.get(1))
+                .map_or("", |n| n.text(self.src)),
+        };
+        self.report(
+            node,
+            "double-quote",
+            format!(
+                "`{}` is quoted twice, so its value is still quoted",
+                node.text(self.src)
+            ),
+            Some(Fix {
+                range: node.range.clone(),
+                replacement: format!("'{}", innermost),
+                safe: false,
+            }),
+        );
+    }
+
+    fn check_call(&mut self, node: &Node, items: &[Node]) {
+        let head = match items.first().and_then(|h| h.symbol(self.src)) {
+            Some(head) => head,
+            None => return,
+        };
+        let args = &items[1..];
+        match head {
+            "if" => self.check_if(node, args),
+            "=" if args.len() == 1 => self.report(
+                node,
+                "single-arg-equals",
+                format!(
+                    "`{}` compares {} with nothing, so it's always true",
+                    node.text(self.src),
+                    args[0].text(self.src)
+                ),
+                None,
+            ),
+            "def" => self.check_names(args.iter().take(1)),
+            "defn" => {
+                self.check_names(args.iter().take(1));
+                // (defn name ["doc"] (params) [:ret] body)
+                let rest = match args.get(1) {
+                    Some(doc) if matches!(doc.kind, NodeKind::Atom(TokenKind::String)) => {
+                        &args[2..]
+                    }
+                    _ => args.get(1..).unwrap_or(&[]),
+                };
+                self.check_function(node, "defn", rest);
+            }
+            "fn" => self.check_function(node, "fn", args),
+            "do" if args.is_empty() => self.report_empty(node, "do"),
+            "bind" => self.check_bind(node, args),
+            "let-values" => self.check_let_values(node, args),
+            _ => {}
+        }
+    }
+
+    fn check_if(&mut self, node: &Node, args: &[Node]) {
+        let src = self.src;
+        if args.len() == 2 {
+            let text = node.text(src);
+            self.report(
+                node,
+                "missing-else",
+                "`if` has no else branch, which it needs when it runs".into(),
+                // Unsafe, as it turns the error `if` gives now into nil.
+                Some(Fix {
+                    range: node.range.clone(),
+                    replacement: format!("{} nil)", &text[..text.len() - 1]),
+                    safe: false,
+                }),
+            );
+        }
+        let condition = match args.first() {
+            Some(condition) => condition,
+            None => return,
+        };
+        match (&condition.kind, condition.text(src)) {
+            (NodeKind::Atom(TokenKind::Bool), value) => {
+                // Only the branch taken is kept, or nil if it's a missing else.
+                let taken = if value == "true" {
+                    args.get(1)
+                } else {
+                    args.get(2)
+                };
+                let fix = if args.len() == 3 {
+                    taken.map(|branch| Fix {
+                        range: node.range.clone(),
+                        replacement: branch.text(src).into(),
+                        safe: true,
+                    })
+                } else {
+                    None
+                };
+                self.report(
+                    node,
+                    "literal-condition",
+                    format!("the condition is always {}", value),
+                    fix,
+                );
+            }
+            // A tagged literal reads as whatever its tag makes of it.
+            (NodeKind::Atom(kind), value)
+                if !matches!(kind, TokenKind::Symbol | TokenKind::TaggedLiteral)
+                    || value == "nil" =>
+            {
+                self.report(
+                    node,
+                    "literal-condition",
+                    format!(
+                        "the condition {} isn't a bool, so this `if` always fails",
+                        value
+                    ),
+                    None,
+                );
+            }
+            _ => {}
+        }
+    }
+
+    fn report_empty(&mut self, node: &Node, form: &str) {
+        self.report(
+            node,
+            "empty-body",
+            format!("this `{}` has an empty body", form),
+            None,
+        );
+    }
+
+    /// Report names which hide builtins.
+    fn check_names<'n>(&mut self, names: impl Iterator<Item = &'n Node>) {
+        for name in names {
+            if let Some(symbol) = name.symbol(self.src) {
+                if self.builtins.contains(symbol) {
+                    self.report(
+                        name,
+                        "shadowed-builtin",
+                        format!("`{}` hides the builtin of the same name", symbol),
+                        None,
+                    );
+                }
+            }
+        }
+    }
+
+    /// (fn (params) [:ret] body)
+    fn check_function(&mut self, node: &Node, form: &str, rest: &[Node]) {
+        let params = match rest.first() {
+            Some(params) => params,
+            None => return,
+        };
+        self.check_names(param_names(self.src, params).into_iter());
+        let body = match rest.get(1) {
+            Some(ret) if matches!(ret.kind, NodeKind::Atom(TokenKind::Keyword)) => &rest[2..],
+            _ => &rest[1..],
+        };
+        if body.is_empty() {
+            self.report_empty(node, form);
+        }
+    }
+
+    /// Report the names of `bindings` which nothing after them mentions.
+    fn check_unused(&mut self, bindings: &[(Vec<&Node>, &Node)], body: &[Node]) {
+        for (i, (names, _)) in bindings.iter().enumerate() {
+            for name in names {
+                let symbol = match name.symbol(self.src) {
+                    Some(symbol) if !symbol.starts_with('_') => symbol,
+                    _ => continue,
+                };
+                let later_values = bindings[i + 1..].iter().map(|(_, value)| *value);
+                let used = later_values
+                    .chain(body.iter())
+                    .any(|n| n.mentions(self.src, symbol));
+                if !used {
+                    self.report(
+                        name,
+                        "unused-binding",
+                        format!("`{}` is bound but never used", symbol),
+                        None,
+                    );
+                }
+            }
+        }
+    }
+
+    /// (bind (name value ...) body)
+    fn check_bind(&mut self, node: &Node, args: &[Node]) {
+        let pairs = match args.first().and_then(Node::items) {
+            Some(pairs) => pairs,
+            None => return,
+        };
+        let bindings: Vec<(Vec<&Node>, &Node)> = pairs
+            .chunks(2)
+            .filter(|pair| pair.len() == 2)
+            .map(|pair| (vec![&pair[0]], &pair[1]))
+            .collect();
+        self.check_names(bindings.iter().map(|(names, _)| names[0]));
+        self.check_unused(&bindings, &args[1..]);
+        if args.len() == 1 {
+            self.report_empty(node, "bind");
+        }
+    }
+
+    /// (let-values (((names ...) expr) ...) body)
+    fn check_let_values(&mut self, node: &Node, args: &[Node]) {
+        let clauses = match args.first().and_then(Node::items) {
+            Some(clauses) => clauses,
+            None => return,
+        };
+        let bindings: Vec<(Vec<&Node>, &Node)> = clauses
+            .iter()
+            .filter_map(|clause| match clause.items() {
+                Some([names, value]) => Some((param_names(self.src, names), value)),
+                _ => None,
+            })
+            .collect();
+        self.check_names(bindings.iter().flat_map(|(names, _)| names.iter().copied()));
+        self.check_unused(&bindings, &args[1..]);
+        if args.len() == 1 {
+            self.report_empty(node, "let-values");
+        }
+    }
+}
+
+/// The names of the builtins, which definitions shouldn't hide.
+pub fn builtin_names() -> HashSet<String> {
+    let syms = create_stdlib_symbol_table(&Options::default());
+    let (globals, _) = syms.globals_and_docs();
+    globals
+        .into_iter()
+        .filter(|(_, value)| matches!(value, Expr::Function(_)))
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// The lints in `src`, which came from `path`, or its syntax errors if it has any.
+pub fn lint_source(path: &str, src: &str, builtins: &HashSet<String>) -> Vec<Lint> {
+    let errors = check_source(path, src);
+    if !errors.is_empty() {
+        return errors
+            .into_iter()
+            .map(|diagnostic| Lint {
+                name: "syntax",
+                diagnostic,
+                fix: None,
+            })
+            .collect();
+    }
+    let tokens: Vec<Token> = lex(src)
+        .into_iter()
+        .filter(|t| t.kind != TokenKind::Whitespace)
+        .collect();
+    let forms = read_forms(src, &mut tokens.iter().peekable());
+    let mut linter = Linter {
+        path,
+        src,
+        builtins,
+        allowed: Vec::new(),
+        lints: Vec::new(),
+    };
+    forms.iter().for_each(|form| linter.visit(form));
+    linter.lints
+}
+
+/// `src` with the safe fixes of `lints` applied. Fixes overlapping one
+/// already applied are left for another pass.
+pub fn apply_fixes(src: &str, lints: &[Lint]) -> String {
+    let mut fixes: Vec<&Fix> = lints
+        .iter()
+        .filter_map(|lint| lint.fix.as_ref())
+        .filter(|fix| fix.safe)
+        .collect();
+    fixes.sort_by_key(|fix| (fix.range.start, fix.range.end));
+    let mut out = String::new();
+    let mut at = 0;
+    for fix in fixes {
+        if fix.range.start < at {
+            continue;
+        }
+        out.push_str(&src[at..fix.range.start]);
+        out.push_str(&fix.replacement);
+        at = fix.range.end;
+    }
+    out.push_str(&src[at..]);
+    out
+}
+
+/// The lints in the files under `paths`, found like `check_paths` finds
+/// files. With `fix`, safe fixes are applied to the files first, and the
+/// lints left afterwards are returned.
+pub fn lint_paths(paths: &[String], fix: bool) -> LispResult<Vec<Lint>> {
+    let builtins = builtin_names();
+    let mut lints = Vec::new();
+    for file in source_files(paths)? {
+        let original = match fs::read_to_string(&file) {
+            Ok(src) => src,
+            Err(e) => {
+                lints.push(Lint {
+                    name: "syntax",
+                    diagnostic: Diagnostic {
+                        path: file,
+                        line: 1,
+                        col: 1,
+                        message: format!("can't read the file, {}", e),
+                        severity: Severity::Error,
+                    },
+                    fix: None,
+                });
+                continue;
+            }
+        };
+        let mut src = original.clone();
+        let mut found = lint_source(&file, &src, &builtins);
+        // Fixing a form can leave another fix inside it for the next pass.
+        if fix {
+            loop {
+                let fixed = apply_fixes(&src, &found);
+                if fixed == src {
+                    break;
+                }
+                src = fixed;
+                found = lint_source(&file, &src, &builtins);
+            }
+        }
+        if src != original {
+            fs::write(&file, &src)?;
+        }
+        lints.extend(found);
+    }
+    Ok(lints)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lints(src: &str) -> Vec<String> {
+        let builtins = ["list", "map"].iter().map(|s| s.to_string()).collect();
+        lint_source("f.x7", src, &builtins)
+            .iter()
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn forms_keep_their_place() {
+        assert_eq!(
+            lints("(defn f (x)\n  (if (= x) x))"),
+            [
+                "f.x7:2:3: warning: `if` has no else branch, which it needs when it runs [missing-else]\n  suggestion: (if (= x) x nil)",
+                "f.x7:2:7: warning: `(= x)` compares x with nothing, so it's always true [single-arg-equals]",
+            ]
+        );
+        assert!(lints("(defn f (x) (if (= x 1) x nil))\n'(if true)").is_empty());
+        // Data is only code where it's unquoted.
+        assert_eq!(
+            lints("(data ((if true) (= x) ~(if y 1)))"),
+            ["f.x7:1:25: warning: `if` has no else branch, which it needs when it runs [missing-else]\n  suggestion: (if y 1 nil)"]
+        );
+    }
+
+    #[test]
+    fn allow_comments_cover_the_next_form() {
+        let src = ";#allow(shadowed-builtin, empty-body)\n(defn list (map))\n(def list 1)";
+        assert_eq!(
+            lints(src),
+            ["f.x7:3:6: warning: `list` hides the builtin of the same name [shadowed-builtin]"]
+        );
+    }
+
+    #[test]
+    fn safe_fixes_apply_until_none_are_left() {
+        let builtins = HashSet::new();
+        let mut src = String::from("(if true (if false 1 2) 3)");
+        for _ in 0..3 {
+            src = apply_fixes(&src, &lint_source("f.x7", &src, &builtins));
+        }
+        assert_eq!(src, "2");
+        // Unsafe suggestions are left alone.
+        let src = "''x";
+        assert_eq!(apply_fixes(src, &lint_source("f.x7", src, &builtins)), src);
+    }
+
+    #[test]
+    fn lints_as_json() {
+        let lints = lint_source("f.x7", "(do)", &HashSet::new());
+        assert_eq!(
+            lints_json(&lints),
+            "[\n  {\"path\": \"f.x7\", \"line\": 1, \"col\": 1, \"message\": \"this `do` has an empty body\", \"severity\": \"warning\", \"lint\": \"empty-body\", \"suggestion\": null}\n]"
+        );
+    }
+}
@@ -1,17 +1,81 @@
 use crate::cli::report_error;
 use structopt::StructOpt;
 
-use x7::{cli, modules, stdlib};
+use x7::{check, cli, docgen, lint, modules, stdlib};
 
 fn main() -> Result<(), i32> {
     let opt = cli::Options::from_args();
+    if let Some(cli::Command::Docgen { builtins, paths }) = &opt.cmd {
+        let markdown = if *builtins {
+            Ok(docgen::builtins_reference(
+                &stdlib::create_stdlib_symbol_table(&opt),
+            ))
+        } else {
+            docgen::docgen(paths)
+        };
+        return match markdown {
+            Ok(markdown) => {
+                print!("{}", markdown);
+                Ok(())
+            }
+            Err(e) => {
+                report_error(&e);
+                Err(1)
+            }
+        };
+    }
+    if let Some(cli::Command::Check { format, paths }) = &opt.cmd {
+        let diagnostics = match check::check_paths(paths) {
+            Ok(diagnostics) => diagnostics,
+            Err(e) => {
+                report_error(&e);
+                return Err(1);
+            }
+        };
+        if format == "json" {
+            println!("{}", check::diagnostics_json(&diagnostics));
+        } else {
+            for diagnostic in diagnostics.iter() {
+                println!("{}", diagnostic);
+            }
+        }
+        return if diagnostics.is_empty() {
+            Ok(())
+        } else {
+            Err(1)
+        };
+    }
+    if let Some(cli::Command::Lint { format, fix, paths }) = &opt.cmd {
+        let lints = match lint::lint_paths(paths, *fix) {
+            Ok(lints) => lints,
+            Err(e) => {
+                report_error(&e);
+                return Err(1);
+            }
+        };
+        if format == "json" {
+            println!("{}", lint::lints_json(&lints));
+        } else {
+            for lint in lints.iter() {
+                println!("{}", lint);
+            }
+        }
+        return if lints.is_empty() { Ok(()) } else { Err(1) };
+    }
+    #[cfg(feature = "remote-repl")]
+    if let Some(cli::Command::Attach { token, addr }) = &opt.cmd {
+        return x7::attach(addr, token).map_err(|e| {
+            report_error(&e);
+            1
+        });
+    }
     let sym_table = stdlib::create_stdlib_symbol_table(&opt);
     if opt.files.is_empty() {
-        cli::read_cli(&sym_table);
+        cli::read_cli(&sym_table, &opt);
     } else {
         for f in opt.files {
             if let Err(e) = modules::run_file(&f, &sym_table) {
-                report_error(&e);
+                cli::report_uncaught(&e, &sym_table);
                 return Err(1);
             }
         }
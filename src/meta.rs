@@ -0,0 +1,249 @@
+use crate::exact_len;
+use crate::symbols::{Dict, Expr, LispResult, SymbolTable};
+use anyhow::bail;
+use im::Vector;
+
+// Metadata on values, from (with-meta value dict). A value with metadata is
+// still the value: it's equal to it, hashes and prints the same, and the
+// accessors builtins use (get-num, get-list, ...) see straight through it.
+//
+// Builtins are called with the metadata of their arguments removed, so
+// their results, like sums and strings, have none. That includes builtins
+// building collections, like list and dict, whose elements lose it. The
+// exceptions are the builtins below which see metadata, and those making an
+// updated copy of a collection, like assoc, whose result keeps the
+// collection's metadata.
+//
+// In tests, a host can give every other value metadata as it's evaluated
+// (see `wrap_evaluated`), to check that what a program does never depends
+// on whether a value has it.
+
+/// Builtins given their arguments with metadata.
+const SEES_META: &[&str] = &["meta", "with-meta"];
+
+/// Builtins updating a collection, with the index of the collection.
+const UPDATES: &[(&str, usize)] = &[
+    ("assoc", 0),
+    ("merge", 0),
+    ("merge-with", 1),
+    ("deep-merge", 0),
+    ("cons", 1),
+];
+
+impl Expr {
+    /// This value, without any metadata. Multiple values are their first.
+    pub(crate) fn unmeta(&self) -> &Expr {
+        static NIL: Expr = Expr::Nil;
+        match self {
+            Expr::WithMeta(inner, _) => inner,
+            Expr::Values(values) => values.front().map_or(&NIL, Expr::unmeta),
+            other => other,
+        }
+    }
+
+    pub(crate) fn without_meta(self) -> Expr {
+        match self.first_value() {
+            Expr::WithMeta(inner, _) => *inner,
+            other => other,
+        }
+    }
+
+    /// Several values, as `values` returns them. One value is just itself.
+    pub(crate) fn values(values: Vector<Expr>) -> Expr {
+        let mut values: Vector<Expr> = values.into_iter().map(Expr::first_value).collect();
+        match values.len() {
+            1 => values.pop_front().unwrap(),
+            _ => Expr::Values(values),
+        }
+    }
+
+    /// The first of multiple values, or else this value.
+    pub(crate) fn first_value(self) -> Expr {
+        match self {
+            Expr::Values(values) => values.front().cloned().unwrap_or(Expr::Nil),
+            other => other,
+        }
+    }
+
+    pub(crate) fn meta(&self) -> Option<&Dict> {
+        match self {
+            Expr::WithMeta(_, meta) => Some(meta),
+            _ => None,
+        }
+    }
+
+    /// This value with `meta`, replacing any it had.
+    pub(crate) fn with_meta(self, meta: Dict) -> Expr {
+        Expr::WithMeta(Box::new(self.without_meta()), meta)
+    }
+}
+
+/// Call the builtin `name` with `args`, removing and restoring metadata.
+pub(crate) fn call_builtin(
+    name: &str,
+    args: Vector<Expr>,
+    call: impl FnOnce(Vector<Expr>) -> LispResult<Expr>,
+) -> LispResult<Expr> {
+    if SEES_META.contains(&name) || args.iter().all(|arg| arg.meta().is_none()) {
+        return call(args);
+    }
+    let kept = UPDATES
+        .iter()
+        .find(|(update, _)| *update == name)
+        .and_then(|(_, index)| args.get(*index))
+        .and_then(Expr::meta)
+        .cloned();
+    let result = call(args.into_iter().map(Expr::without_meta).collect())?;
+    Ok(match kept {
+        Some(meta) => result.with_meta(meta),
+        None => result,
+    })
+}
+
+/// (with-meta value dict)
+pub(crate) fn with_meta(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let value = exprs[0].clone();
+    match exprs[1].unmeta() {
+        Expr::Nil => Ok(value.without_meta()),
+        Expr::Dict(meta) => Ok(value.with_meta(meta.clone())),
+        other => bail!(
+            "with-meta expects a dict, or nil to remove metadata, but was given {:?}",
+            other
+        ),
+    }
+}
+
+/// (meta value)
+pub(crate) fn meta(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let meta = exprs[0].meta();
+    #[cfg(test)]
+    let meta = meta.filter(|meta| **meta != test_meta());
+    Ok(meta.cloned().map_or(Expr::Nil, Expr::Dict))
+}
+
+/// `value`, which was just evaluated, with metadata `meta` can't see, if
+/// the host gives every other value some. See `Host::set_wraps_values`.
+#[cfg(test)]
+pub(crate) fn wrap_evaluated(value: Expr, symbol_table: &SymbolTable) -> Expr {
+    // Multiple values would be cut down to their first.
+    if matches!(value, Expr::WithMeta(..) | Expr::Values(_)) || !symbol_table.wraps_next_value() {
+        return value;
+    }
+    value.with_meta(test_meta())
+}
+
+#[cfg(not(test))]
+#[inline]
+pub(crate) fn wrap_evaluated(value: Expr, _symbol_table: &SymbolTable) -> Expr {
+    value
+}
+
+/// The metadata `wrap_evaluated` gives values.
+#[cfg(test)]
+fn test_meta() -> Dict {
+    let mut meta = Dict::new();
+    meta.insert(Expr::Symbol(":x7/wrapped".into()), Expr::Bool(true));
+    meta
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::Options;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use crate::symbols::{error_kind, Expr};
+    use std::fs;
+
+    fn check(progs: &[(&str, &str)]) {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.eval_source("(def v (with-meta (dict :a 1) (dict :line 12)))")
+            .unwrap();
+        for (prog, expected) in progs {
+            let prog = format!("(= {} {})", prog, expected);
+            assert_eq!(
+                syms.eval_source(&prog).unwrap(),
+                Expr::Bool(true),
+                "{}",
+                prog
+            );
+        }
+    }
+
+    #[test]
+    fn values_with_metadata_are_the_value() {
+        check(&[
+            ("v", "(dict :a 1)"),
+            ("(meta v)", "(dict :line 12)"),
+            ("(meta (dict :a 1))", "nil"),
+            ("(get (dict v 1) (dict :a 1))", "1"),
+            ("(str v)", "(str (dict :a 1))"),
+            ("(type v)", "(type (dict))"),
+            ("(+ (with-meta 1 (dict)) 2)", "3"),
+            ("((with-meta inc (dict :pure true)) 1)", "2"),
+            ("(map inc (with-meta '(1 2) (dict)))", "'(2 3)"),
+            ("(sort (list 3 (with-meta 1 (dict)) 2))", "'(1 2 3)"),
+            (
+                "(remove (with-meta (dict :a 1 :b 2) (dict)) :a)",
+                "(dict :b 2)",
+            ),
+            (
+                "(apply remove (list (with-meta (dict :a 1 :b 2) (dict)) :a))",
+                "(dict :b 2)",
+            ),
+            ("(meta (with-meta v nil))", "nil"),
+            ("(meta (with-meta v (dict :line 13)))", "(dict :line 13)"),
+        ]);
+    }
+
+    #[test]
+    fn updates_keep_metadata() {
+        check(&[
+            ("(meta (assoc v :b 2))", "(dict :line 12)"),
+            ("(assoc v :b 2)", "(dict :a 1 :b 2)"),
+            ("(meta (merge v (dict :b 2)))", "(dict :line 12)"),
+            (
+                "(meta (cons 0 (with-meta '(1) (dict :n 1))))",
+                "(dict :n 1)",
+            ),
+            // Everything else makes new values, without metadata.
+            ("(meta (get v :a))", "nil"),
+            ("(meta (str v))", "nil"),
+            ("(meta (merge (dict :b 2) v))", "nil"),
+        ]);
+        let syms = create_stdlib_symbol_table(&Options::default());
+        assert!(syms.eval_source("(with-meta 1 '(:a 1))").is_err());
+    }
+
+    /// The script fixtures give the same output and errors when every
+    /// other value they evaluate has metadata.
+    #[test]
+    fn fixture_scripts_run_the_same_with_metadata() {
+        let mut ran = 0;
+        for entry in fs::read_dir("tests/fixtures/scripts").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|ext| ext != "x7") {
+                continue;
+            }
+            let syms = create_stdlib_symbol_table(&Options::default());
+            syms.host_mut().capture_output();
+            syms.host_mut().set_wraps_values(true);
+            let source = fs::read_to_string(&path).unwrap();
+            let res = syms.eval_script(&source, &path.to_string_lossy());
+            let (stdout, _) = syms.host_mut().take_captured_output();
+            let expected = fs::read_to_string(path.with_extension("out")).unwrap();
+            assert_eq!(stdout, expected, "stdout of {}", path.display());
+            match fs::read_to_string(path.with_extension("err")) {
+                Ok(kind) => assert_eq!(
+                    error_kind(&res.unwrap_err()),
+                    kind.trim(),
+                    "error of {}",
+                    path.display()
+                ),
+                Err(_) => assert!(res.is_ok(), "{}: {:?}", path.display(), res),
+            }
+            ran += 1;
+        }
+        assert!(ran > 0);
+    }
+}
@@ -0,0 +1,349 @@
+use crate::deprecated::deprecation;
+use crate::lexer::{lex, TokenKind};
+use crate::parser::read;
+use crate::stdlib::builtin_category;
+use crate::symbols::{Expr, LispResult, ProgramError};
+use anyhow::anyhow;
+use itertools::Itertools;
+use std::collections::HashSet;
+
+// Sizing up a program from its parse, so embedders can turn away absurd
+// untrusted input before spending any time evaluating it. The walk keeps an
+// explicit stack, as the programs worth rejecting are the deeply nested ones.
+// The parser itself recurses once per level though, so how deep a program
+// nests is first bounded from its tokens, and programs too deep to parse
+// safely, or deeper than a limit, are turned away without parsing them.
+
+/// The deepest nesting `analyze` parses. The parser takes several KB of
+/// stack a level in debug builds, and this leaves room on a 2MB thread.
+const MAX_READ_DEPTH: usize = 128;
+
+/// What a program is made of, from `analyze`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceMetrics {
+    /// Every form, nested ones included.
+    pub expressions: usize,
+    /// The deepest nesting of lists, quotes, tuples, and dicts. 0 for a
+    /// program of atoms.
+    pub max_depth: usize,
+    /// Forms which aren't collections, like numbers, strings, and symbols.
+    pub atoms: usize,
+    /// Different symbols referenced, not counting keywords.
+    pub distinct_symbols: usize,
+    /// Bytes of string literals, docstrings included.
+    pub string_bytes: usize,
+    /// Whether a builtin in the "io" category is named anywhere, even in quoted code.
+    pub uses_io: bool,
+    /// Deprecated names of builtins referenced, sorted.
+    pub deprecated: Vec<String>,
+}
+
+/// Upper bounds on `SourceMetrics`, for `RunOptions::max_program_metrics`.
+/// Each is unlimited when `None`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProgramLimits {
+    pub max_expressions: Option<usize>,
+    pub max_depth: Option<usize>,
+    pub max_atoms: Option<usize>,
+    pub max_distinct_symbols: Option<usize>,
+    pub max_string_bytes: Option<usize>,
+    /// Reject programs which name io builtins.
+    pub deny_io: bool,
+}
+
+fn is_collection(expr: &Expr) -> bool {
+    matches!(
+        expr,
+        Expr::List(_) | Expr::Quote(_) | Expr::Tuple(_) | Expr::Dict(_)
+    )
+}
+
+/// The least and most levels `source` can nest, from its tokens. Each pair
+/// of parentheses is a level, and the quote, tuple, spread and unquote
+/// markers before a form may add a level each.
+fn nesting_bounds(source: &str) -> (usize, usize) {
+    // The levels each open parenthesis added, with its markers.
+    let mut open: Vec<usize> = Vec::new();
+    let (mut level, mut markers) = (0, 0);
+    let (mut least, mut most) = (0, 0);
+    for token in lex(source) {
+        match token.kind {
+            TokenKind::Whitespace | TokenKind::Comment => continue,
+            TokenKind::QuoteMarker
+            | TokenKind::TupleMarker
+            | TokenKind::SpreadMarker
+            | TokenKind::UnquoteMarker => {
+                markers += 1;
+                continue;
+            }
+            TokenKind::OpenParen => {
+                let added = markers.max(1);
+                open.push(added);
+                level += added;
+                least = least.max(open.len());
+                most = most.max(level);
+            }
+            TokenKind::CloseParen => level -= open.pop().unwrap_or(0),
+            _ => most = most.max(level + markers),
+        }
+        markers = 0;
+    }
+    (least, most.max(level + markers))
+}
+
+/// Measure the x7 program `source` without evaluating it. Programs nesting
+/// too deeply to parse safely, hundreds of levels, are Resource errors.
+pub fn analyze(source: &str) -> LispResult<SourceMetrics> {
+    let (_, most) = nesting_bounds(source);
+    if most > MAX_READ_DEPTH {
+        return Err(anyhow!(ProgramError::Resource).context(format!(
+            "The program may nest {} levels deep, over the {} which can be read",
+            most, MAX_READ_DEPTH
+        )));
+    }
+    let mut metrics = SourceMetrics::default();
+    let mut symbols = HashSet::new();
+    for form in read(source) {
+        let form = form?;
+        // Each form with how many collections enclose it.
+        let mut stack = vec![(&form, 0)];
+        while let Some((expr, depth)) = stack.pop() {
+            metrics.expressions += 1;
+            if is_collection(expr) {
+                metrics.max_depth = metrics.max_depth.max(depth + 1);
+            } else {
+                metrics.atoms += 1;
+            }
+            match expr {
+                Expr::List(l) | Expr::Quote(l) | Expr::Tuple(l) => {
+                    stack.extend(l.iter().map(|child| (child, depth + 1)))
+                }
+                Expr::Dict(d) => {
+                    for (key, value) in d.iter() {
+                        stack.push((key, depth + 1));
+                        stack.push((value, depth + 1));
+                    }
+                }
+                Expr::Symbol(s) if !s.starts_with(':') && !symbols.contains(s) => {
+                    symbols.insert(s.clone());
+                }
+                Expr::String(s) => metrics.string_bytes += s.len(),
+                _ => {}
+            }
+        }
+    }
+    metrics.distinct_symbols = symbols.len();
+    metrics.uses_io = symbols.iter().any(|s| builtin_category(s) == Some("io"));
+    metrics.deprecated = symbols
+        .into_iter()
+        .filter(|s| deprecation(s).is_some())
+        .sorted()
+        .collect();
+    Ok(metrics)
+}
+
+/// Fail with a Resource error if `source` is over any of `limits`, before
+/// parsing it if its tokens show it nests too deeply.
+pub(crate) fn check_source(source: &str, limits: &ProgramLimits) -> LispResult<()> {
+    if let Some(max) = limits.max_depth {
+        let (least, _) = nesting_bounds(source);
+        if least > max {
+            return Err(anyhow!(ProgramError::Resource).context(format!(
+                "The program has at least {} levels of nesting, over the limit of {}",
+                least, max
+            )));
+        }
+    }
+    analyze(source)?.check(limits)
+}
+
+impl SourceMetrics {
+    /// Fail with a Resource error if these metrics are over any of `limits`.
+    pub(crate) fn check(&self, limits: &ProgramLimits) -> LispResult<()> {
+        let measured = [
+            ("expressions", self.expressions, limits.max_expressions),
+            ("levels of nesting", self.max_depth, limits.max_depth),
+            ("atoms", self.atoms, limits.max_atoms),
+            (
+                "distinct symbols",
+                self.distinct_symbols,
+                limits.max_distinct_symbols,
+            ),
+            (
+                "bytes of strings",
+                self.string_bytes,
+                limits.max_string_bytes,
+            ),
+        ];
+        for (what, value, max) in measured.iter() {
+            match max {
+                Some(max) if value > max => {
+                    return Err(anyhow!(ProgramError::Resource).context(format!(
+                        "The program has {} {}, over the limit of {}",
+                        value, what, max
+                    )))
+                }
+                _ => {}
+            }
+        }
+        if limits.deny_io && self.uses_io {
+            return Err(anyhow!(ProgramError::Resource)
+                .context("The program uses io builtins, which aren't allowed"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Options;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use crate::symbols::error_kind;
+
+    /// `n` calls nested in each other, like (f (f (f 1))).
+    fn nested(n: usize) -> String {
+        format!("{}1{}", "(f ".repeat(n), ")".repeat(n))
+    }
+
+    #[test]
+    fn counts_every_form() {
+        let metrics =
+            analyze("(defn greet (name) \"Say hi.\" (str \"hi \" name)) (greet :bob) 3").unwrap();
+        assert_eq!(
+            metrics,
+            SourceMetrics {
+                // The defn's 3 lists and 7 atoms, the call and its 2 atoms, and 3.
+                expressions: 14,
+                max_depth: 2,
+                atoms: 10,
+                // defn greet name str
+                distinct_symbols: 4,
+                string_bytes: 10,
+                uses_io: false,
+                deprecated: vec![],
+            }
+        );
+        assert!(analyze("(defn log (x) (println x))").unwrap().uses_io);
+        assert!(analyze("'(println \"quoted\")").unwrap().uses_io);
+        assert!(analyze("(1 2").is_err());
+        let metrics = analyze("(non-empty? (filter is-even? l)) '(is-even? 4)").unwrap();
+        assert_eq!(metrics.deprecated, ["is-even?", "non-empty?"]);
+    }
+
+    #[test]
+    fn deep_nesting_is_measured() {
+        let metrics = analyze(&nested(100)).unwrap();
+        assert_eq!(metrics.max_depth, 100);
+        assert_eq!(metrics.expressions, 201);
+        assert_eq!(metrics.distinct_symbols, 1);
+    }
+
+    #[test]
+    fn nesting_is_bounded_from_tokens() {
+        assert_eq!(nesting_bounds("1 (a (b)) c"), (2, 2));
+        assert_eq!(nesting_bounds("'(1 '(2)) ; ((("), (2, 2));
+        assert_eq!(nesting_bounds("(f ''x ^(a @b))"), (2, 3));
+        assert_eq!(nesting_bounds("(((\"(\""), (3, 3));
+    }
+
+    #[test]
+    fn too_deep_to_read_is_refused_without_parsing() {
+        let err = analyze(&nested(100_000)).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "The program may nest 100000 levels deep, over the 128 which can be read: Resource"
+        );
+        assert!(analyze(&format!("{}x", "'".repeat(100_000))).is_err());
+        let limits = ProgramLimits {
+            max_depth: Some(10),
+            ..Default::default()
+        };
+        let err = check_source(&nested(100_000), &limits).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "The program has at least 100000 levels of nesting, over the limit of 10: Resource"
+        );
+    }
+
+    #[test]
+    fn limits_allow_programs_at_them() {
+        let check = |source: &str, limits: ProgramLimits| {
+            analyze(source)
+                .unwrap()
+                .check(&limits)
+                .map_err(|e| format!("{:#}", e))
+        };
+        let depth = |n| ProgramLimits {
+            max_depth: Some(n),
+            ..Default::default()
+        };
+        assert!(check(&nested(10), depth(10)).is_ok());
+        assert_eq!(
+            check(&nested(11), depth(10)).unwrap_err(),
+            "The program has 11 levels of nesting, over the limit of 10: Resource"
+        );
+
+        let exprs = |n| ProgramLimits {
+            max_expressions: Some(n),
+            ..Default::default()
+        };
+        let program = "1 ".repeat(50);
+        assert!(check(&program, exprs(50)).is_ok());
+        assert!(check(&program, exprs(49)).is_err());
+
+        let atoms = |n| ProgramLimits {
+            max_atoms: Some(n),
+            ..Default::default()
+        };
+        assert!(check("(+ 1 2)", atoms(3)).is_ok());
+        assert!(check("(+ 1 2)", atoms(2)).is_err());
+
+        let symbols = |n| ProgramLimits {
+            max_distinct_symbols: Some(n),
+            ..Default::default()
+        };
+        let program = (0..20)
+            .map(|i| format!("(def s{} 1) ", i))
+            .collect::<String>();
+        // def and the 20 names.
+        assert!(check(&program, symbols(21)).is_ok());
+        assert!(check(&program, symbols(20)).is_err());
+
+        let strings = |n| ProgramLimits {
+            max_string_bytes: Some(n),
+            ..Default::default()
+        };
+        let program = format!("{:?}", "é".repeat(8));
+        assert!(check(&program, strings(16)).is_ok());
+        assert!(check(&program, strings(15)).is_err());
+
+        let no_io = ProgramLimits {
+            deny_io: true,
+            ..Default::default()
+        };
+        assert!(check("(+ 1 2)", no_io.clone()).is_ok());
+        assert!(check("(print-table (list))", no_io).is_err());
+    }
+
+    #[test]
+    fn interpreters_reject_programs_before_evaluating_them() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.set_max_program_metrics(Some(ProgramLimits {
+            max_depth: Some(2),
+            ..Default::default()
+        }));
+        assert_eq!(
+            syms.eval_source("(def x 1) (+ x (* 2 3))").unwrap(),
+            Expr::from(7)
+        );
+        let err = syms
+            .eval_source("(def y 1) (+ y (* 2 (- 3 1)))")
+            .unwrap_err();
+        assert_eq!(error_kind(&err), "Resource");
+        assert!(syms.eval_source("y").is_err());
+        assert!(syms.compile(&nested(3)).is_err());
+        syms.set_max_program_metrics(None);
+        assert!(syms.compile(&nested(3)).is_ok());
+    }
+}
@@ -1,10 +1,17 @@
+use crate::access;
 use crate::cli::Options;
-use crate::parser::read;
-use crate::symbols::SymbolTable;
+use crate::host::Warning;
+use crate::parser::{read, read_with_comments};
+use crate::symbols::{Expr, LispResult, SymbolTable};
+use anyhow::{anyhow, bail, Context};
+use im::Vector;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::fmt;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
+use std::rc::Rc;
 
 // TODO: Figure out best way to have the stdlib available
 // $X7_PATH?
@@ -50,9 +57,427 @@ pub(crate) fn load_x7_stdlib(
 pub fn run_file(file_name: &str, symbol_table: &SymbolTable) -> Result<i32, anyhow::Error> {
     let mut strbuf = String::new();
     File::open(file_name)?.read_to_string(&mut strbuf)?;
-    for expr in read(strbuf.as_str()) {
+    symbol_table.host_mut().set_program(&strbuf);
+    let mut forms = read_with_comments(strbuf.as_str()).with_reader_tags(symbol_table);
+    while let Some(expr) = forms.next() {
         let prog = expr?;
-        prog.eval(symbol_table)?;
+        symbol_table.host_mut().reset_built_bytes();
+        let value = symbol_table.eval_form(&prog)?;
+        symbol_table.warn_if_discarded(&prog, &value, &forms.last_span(file_name))?;
     }
     Ok(0)
 }
+
+/// A module loaded with `require` or `import`.
+#[derive(Debug, Clone)]
+pub(crate) struct Module {
+    /// Where the module was loaded from.
+    pub(crate) source: String,
+    /// Top level names defined by the module, unprefixed.
+    pub(crate) exports: Vec<String>,
+}
+
+type ResolveFn = dyn Fn(&str) -> Option<String>;
+
+/// Supplies module source by name, before modules are looked for on disk.
+#[derive(Clone)]
+pub(crate) struct ModuleResolver(Rc<ResolveFn>);
+
+impl fmt::Debug for ModuleResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ModuleResolver")
+    }
+}
+
+/// Book-keeping for loaded modules and the bindings imported from them.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Imports {
+    modules: HashMap<String, Module>,
+    // Bound symbol -> source of the module it was imported from.
+    provenance: HashMap<String, String>,
+    resolver: Option<ModuleResolver>,
+}
+
+impl Imports {
+    pub(crate) fn get_module(&self, name: &str) -> Option<Module> {
+        self.modules.get(name).cloned()
+    }
+
+    pub(crate) fn add_module(&mut self, name: String, module: Module) {
+        self.modules.insert(name, module);
+    }
+
+    pub(crate) fn provenance(&self, symbol: &str) -> Option<String> {
+        self.provenance.get(symbol).cloned()
+    }
+
+    pub(crate) fn set_provenance(&mut self, symbol: String, source: String) {
+        self.provenance.insert(symbol, source);
+    }
+
+    pub(crate) fn set_resolver<F: Fn(&str) -> Option<String> + 'static>(&mut self, resolver: F) {
+        self.resolver = Some(ModuleResolver(Rc::new(resolver)));
+    }
+
+    /// Ask the embedder's resolver, if any, for the source of module `name`.
+    fn resolve(&self, name: &str) -> Option<String> {
+        self.resolver
+            .as_ref()
+            .and_then(|resolver| (resolver.0)(name))
+    }
+}
+
+/// Find the module a `require` / `import` refers to.
+/// Symbols are looked up as `<name>.x7` in the current directory, then the stdlib directory.
+/// Strings are treated as paths, and the module is named after the file stem.
+fn resolve_module(spec: &Expr, symbol_table: &SymbolTable) -> LispResult<(String, String)> {
+    if let Ok(path) = spec.get_string() {
+        let name = std::path::Path::new(&path)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| anyhow!("Cannot determine a module name for \"{}\"", path))?
+            .to_string();
+        return Ok((name, path));
+    }
+    let name = spec.get_symbol_string()?;
+    let dirs = [".", stdlib_dir()?];
+    for dir in dirs.iter() {
+        let path = format!("{}/{}.x7", dir, name);
+        if access::is_file(symbol_table, "Loading modules from files", &path)? {
+            return Ok((name, path));
+        }
+    }
+    bail!(
+        "Could not find module {}, looked for {}.x7 in {}",
+        name,
+        name,
+        dirs.join(", ")
+    )
+}
+
+/// Prefix every reference to one of the module's top level names, so
+/// module functions keep working no matter how they are imported.
+/// Quoted data is left alone, as its symbols aren't references.
+fn namespace_symbols(expr: &Expr, names: &HashSet<String>, module: &str) -> Expr {
+    let rewrite = |l: &Vector<Expr>| -> Vector<Expr> {
+        l.iter()
+            .map(|e| namespace_symbols(e, names, module))
+            .collect()
+    };
+    match expr {
+        Expr::Symbol(s) if names.contains(s) => Expr::Symbol(format!("{}::{}", module, s)),
+        Expr::List(l) if l.front().is_some_and(is_quote) => expr.clone(),
+        Expr::List(l) => Expr::List(rewrite(l)),
+        Expr::Tuple(l) => Expr::Tuple(rewrite(l)),
+        rest => rest.clone(),
+    }
+}
+
+/// Whether `head` starts a form that gives back its arguments as data.
+fn is_quote(head: &Expr) -> bool {
+    head.symbol_matches("quote") || head.symbol_matches(crate::parser::QUOTE_FORM)
+}
+
+/// Evaluate module source text, defining each top level name as `<name>::<sym>`.
+pub(crate) fn load_module_source(
+    name: &str,
+    source: &str,
+    contents: &str,
+    symbol_table: &SymbolTable,
+) -> LispResult<Module> {
+    let forms: Vec<Expr> = read_with_comments(contents)
+        .with_reader_tags(symbol_table)
+        .collect::<LispResult<_>>()?;
+    let mut exports = Vec::new();
+    for form in forms.iter() {
+        let form = match form {
+            // Forms after a `;#line` directive are wrapped to carry their location.
+            Expr::List(l) if l.len() == 5 && l[0].symbol_matches("with-location") => &l[4],
+            form => form,
+        };
+        if let Ok(list) = form.get_list() {
+            let is_def = list
+                .front()
+                .map(|head| head.symbol_matches("def") || head.symbol_matches("defn"))
+                .unwrap_or(false);
+            if let (true, Some(Ok(sym))) = (is_def, list.get(1).map(Expr::get_symbol_string)) {
+                if !exports.contains(&sym) {
+                    exports.push(sym);
+                }
+            }
+        }
+    }
+    let names: HashSet<String> = exports.iter().cloned().collect();
+    for form in forms.iter() {
+        symbol_table
+            .eval_form(&namespace_symbols(form, &names, name))
+            .with_context(|| format!("Error while loading module {} from {}", name, source))?;
+    }
+    for export in exports.iter() {
+        symbol_table
+            .imports_mut()
+            .set_provenance(format!("{}::{}", name, export), source.into());
+    }
+    Ok(Module {
+        source: source.into(),
+        exports,
+    })
+}
+
+/// Load module source which has no file, caching it by `name`.
+fn load_named_module(
+    name: &str,
+    source: &str,
+    contents: &str,
+    symbol_table: &SymbolTable,
+) -> LispResult<Module> {
+    let module = load_module_source(name, source, contents, symbol_table)?;
+    symbol_table
+        .imports_mut()
+        .add_module(name.into(), module.clone());
+    Ok(module)
+}
+
+fn load_module(spec: &Expr, symbol_table: &SymbolTable) -> LispResult<(String, Module)> {
+    // Modules registered with require-string don't exist on disk.
+    if let Ok(name) = spec.get_symbol_string() {
+        if let Some(module) = symbol_table.imports().get_module(&name) {
+            return Ok((name, module));
+        }
+        // Release the borrow first, as loading the module updates imports.
+        let resolved = symbol_table.imports().resolve(&name);
+        if let Some(contents) = resolved {
+            let source = format!("<resolver>/{}", name);
+            let module = load_named_module(&name, &source, &contents, symbol_table)?;
+            return Ok((name, module));
+        }
+    }
+    let (name, path) = resolve_module(spec, symbol_table)?;
+    // Files are cached by where they are, and not by name, as modules in
+    // different directories can share a name. Paths that can't be resolved
+    // are left for reading to report.
+    let key = access::canonicalize(symbol_table, "Loading modules from files", &path)
+        .map(|canonical| canonical.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| path.clone());
+    if let Some(module) = symbol_table.imports().get_module(&key) {
+        return Ok((name, module));
+    }
+    let strbuf = access::read_to_string(symbol_table, "Loading modules from files", &path)
+        .with_context(|| format!("Could not read module {} from \"{}\"", name, path))?;
+    let module = load_module_source(&name, &path, &strbuf, symbol_table)?;
+    symbol_table.imports_mut().add_module(key, module.clone());
+    Ok((name, module))
+}
+
+/// The module's definitions as they are bound by `require`.
+fn module_symbols(name: &str, module: &Module) -> Expr {
+    Expr::List(
+        module
+            .exports
+            .iter()
+            .map(|export| Expr::Symbol(format!("{}::{}", name, export)))
+            .collect(),
+    )
+}
+
+/// (require-string "name" "(defn helper ...)")
+pub(crate) fn require_string(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    crate::exact_len!(exprs, 2);
+    let name = exprs[0].get_string()?;
+    let contents = exprs[1].get_string()?;
+    let source = format!("<string {}>", name);
+    let module = load_named_module(&name, &source, &contents, symbol_table)?;
+    Ok(module_symbols(&name, &module))
+}
+
+/// Where modules fetched with require-url are cached, keyed by their sha256.
+#[cfg(feature = "http")]
+fn url_cache_dir() -> std::path::PathBuf {
+    match std::env::var_os("X7_MODULE_CACHE") {
+        Some(dir) => dir.into(),
+        None => std::env::temp_dir().join("x7-modules"),
+    }
+}
+
+#[cfg(feature = "http")]
+fn sha256_hex(contents: &str) -> String {
+    use sha2::{Digest, Sha256};
+    format!("{:x}", Sha256::digest(contents.as_bytes()))
+}
+
+/// (require-url "https://example.com/lib.x7" :sha256 "...")
+#[cfg(feature = "http")]
+pub(crate) fn require_url(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    crate::exact_len!(exprs, 3);
+    let url = exprs[0].eval(symbol_table)?.get_string()?;
+    if !exprs[1].symbol_matches(":sha256") {
+        bail!(
+            "require-url expects :sha256 after the url, but was given {}",
+            exprs[1]
+        );
+    }
+    let expected = exprs[2].eval(symbol_table)?.get_string()?.to_lowercase();
+    // The digest names the cached file, so it must be nothing but a digest.
+    if expected.len() != 64 || !expected.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!(
+            "require-url expects a sha256 digest of 64 hex digits, but was given \"{}\"",
+            expected
+        );
+    }
+    let name = url
+        .rsplit('/')
+        .next()
+        .map(|file| file.trim_end_matches(".x7"))
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| anyhow!("Cannot determine a module name for \"{}\"", url))?
+        .to_string();
+
+    let cache_dir = url_cache_dir();
+    let cached = cache_dir.join(format!("{}.x7", expected));
+    let cached = cached.to_string_lossy();
+    let contents = match access::read_to_string(symbol_table, "require-url", &cached) {
+        Ok(contents) if sha256_hex(&contents) == expected => contents,
+        _ => {
+            let contents = access::fetch_url(symbol_table, "require-url", &url)?;
+            let actual = sha256_hex(&contents);
+            if actual != expected {
+                bail!(
+                    "Refusing to load {}, its sha256 digest is {} but {} was expected",
+                    url,
+                    actual,
+                    expected
+                );
+            }
+            // Failing to cache is fine, we'll just fetch it again next time.
+            let _ =
+                access::create_dir_all(symbol_table, "require-url", &cache_dir).and_then(|_| {
+                    let bytes = contents.as_bytes();
+                    access::write_atomic(symbol_table, "require-url", &cached, bytes, false)
+                });
+            contents
+        }
+    };
+    let module = load_named_module(&name, &url, &contents, symbol_table)?;
+    Ok(module_symbols(&name, &module))
+}
+
+#[cfg(not(feature = "http"))]
+pub(crate) fn require_url(_exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    bail!("require-url is unavailable, x7 was built without the http feature")
+}
+
+/// Bind `bound` to the module's `export`, refusing to clobber a binding
+/// imported from a different module.
+fn bind_import(
+    bound: String,
+    export: &str,
+    name: &str,
+    module: &Module,
+    warn_shadowing: bool,
+    symbol_table: &SymbolTable,
+) -> LispResult<()> {
+    if !module.exports.iter().any(|e| e == export) {
+        bail!(
+            "Module {} ({}) does not define {}. It defines: {}",
+            name,
+            module.source,
+            export,
+            module.exports.join(" ")
+        );
+    }
+    let existing = symbol_table.imports().provenance(&bound);
+    match existing {
+        Some(other) if other != module.source => bail!(
+            "Cannot import {} from {}, it was already imported from {}",
+            bound,
+            module.source,
+            other
+        ),
+        None if warn_shadowing && symbol_table.lookup(&Expr::Symbol(bound.clone())).is_ok() => {
+            symbol_table.warn(Warning {
+                kind: ":shadow".into(),
+                message: format!("importing {} shadows an existing definition", bound),
+                span: Some(module.source.clone()),
+            })?;
+        }
+        _ => {}
+    }
+    let value = symbol_table.lookup(&Expr::Symbol(format!("{}::{}", name, export)))?;
+    symbol_table.add_local(&Expr::Symbol(bound.clone()), &value)?;
+    symbol_table
+        .imports_mut()
+        .set_provenance(bound, module.source.clone());
+    Ok(())
+}
+
+/// (import utils :as u :only (helper) :rename ((helper h)) :all)
+pub(crate) fn import(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let (name, module) = load_module(&exprs[0], symbol_table)?;
+    let mut bound = Vec::new();
+    let mut options = exprs.iter().skip(1);
+    while let Some(option) = options.next() {
+        let option = option.get_symbol_string()?;
+        let mut arg = || {
+            options
+                .next()
+                .ok_or_else(|| anyhow!("import option {} requires an argument", option))
+        };
+        match option.as_str() {
+            ":as" => {
+                let alias = arg()?.get_symbol_string()?;
+                for export in module.exports.iter() {
+                    let sym = format!("{}::{}", alias, export);
+                    bind_import(sym.clone(), export, &name, &module, false, symbol_table)?;
+                    bound.push(sym);
+                }
+            }
+            ":only" => {
+                for export in arg()?.get_list()? {
+                    let export = export.get_symbol_string()?;
+                    bind_import(export.clone(), &export, &name, &module, false, symbol_table)?;
+                    bound.push(export);
+                }
+            }
+            ":rename" => {
+                for pair in arg()?.get_list()? {
+                    let pair = pair.get_list()?;
+                    if pair.len() != 2 {
+                        bail!(
+                            "import :rename expects pairs like ((helper h)), but was given {}",
+                            Expr::List(pair)
+                        );
+                    }
+                    let export = pair[0].get_symbol_string()?;
+                    let sym = pair[1].get_symbol_string()?;
+                    bind_import(sym.clone(), &export, &name, &module, false, symbol_table)?;
+                    bound.push(sym);
+                }
+            }
+            ":all" => {
+                for export in module.exports.iter() {
+                    bind_import(export.clone(), export, &name, &module, true, symbol_table)?;
+                    bound.push(export.clone());
+                }
+            }
+            _ => bail!(
+                "Unknown import option {}, expected one of :as :only :rename :all",
+                option
+            ),
+        }
+    }
+    if bound.is_empty() {
+        return Ok(module_symbols(&name, &module));
+    }
+    Ok(Expr::List(bound.into_iter().map(Expr::Symbol).collect()))
+}
+
+/// Return where an imported symbol came from, or nil.
+pub(crate) fn source(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    crate::exact_len!(exprs, 1);
+    let sym = exprs[0].get_symbol_string()?;
+    Ok(symbol_table
+        .imports()
+        .provenance(&sym)
+        .map(Expr::String)
+        .unwrap_or(Expr::Nil))
+}
@@ -1,4 +1,5 @@
-use crate::symbols::{Expr, LispResult, Num, ProgramError};
+use crate::symbols::{read_num, Expr, LispResult, ProgramError, Span};
+use anyhow::Context;
 
 // s-expression parser using nom.
 // Supports the usual constructs (quotes, numbers, strings, comments)
@@ -13,16 +14,16 @@ use nom::{
     bytes::complete::tag,
     bytes::complete::{take_till, take_while1},
     character::complete::{char, multispace0, none_of},
-    combinator::{cut, map, map_res},
-    error::{context, VerboseError},
+    combinator::{cut, map},
+    error::{context, VerboseError, VerboseErrorKind},
     multi::many0,
     number::complete::recognize_float,
-    sequence::{delimited, preceded},
+    sequence::{delimited, pair, preceded},
     IResult, Parser,
 };
 
 #[inline]
-fn is_symbol_char(c: char) -> bool {
+pub(crate) fn is_symbol_char(c: char) -> bool {
     match c {
         '(' | ')' => false,
         '"' => false,
@@ -37,16 +38,41 @@ use crate::symbols::SymbolTable;
 use im::Vector;
 
 fn method_call(method: String) -> Expr {
+    use crate::records::{MethodHandle, Record};
     use crate::symbols::Function;
+    use parking_lot::Mutex;
     use std::sync::Arc;
     let method_clone = method.clone();
-    let method_fn = move |args: Vector<Expr>, _sym: &SymbolTable| {
+    // A monomorphic inline cache: the type of the last record called here,
+    // and what the method resolved to on it. Records check the handle is
+    // theirs, so alternating types just miss.
+    let cache: Mutex<Option<(&'static str, MethodHandle)>> = Mutex::new(None);
+    let method_fn = move |args: Vector<Expr>, sym: &SymbolTable| {
         let rec = match args[0].get_record() {
             Ok(rec) => rec,
             Err(e) => return Err(e),
         };
-        use crate::records::Record;
-        rec.call_method(&method_clone, args.clone().slice(1..))
+        let args = args.clone().slice(1..);
+        if sym.caches_enabled() {
+            // Cloned out, so recursive calls from this call site don't deadlock.
+            let cached = cache.lock().clone();
+            if let Some((type_name, handle)) = cached {
+                if type_name == rec.type_name() {
+                    if let Some(res) = rec.call_resolved(&handle, args.clone(), sym) {
+                        sym.count_method_lookup(true);
+                        return res;
+                    }
+                }
+            }
+            if let Some(handle) = rec.resolve_method(&method_clone) {
+                sym.count_method_lookup(false);
+                *cache.lock() = Some((rec.type_name(), handle.clone()));
+                if let Some(res) = rec.call_resolved(&handle, args.clone(), sym) {
+                    return res;
+                }
+            }
+        }
+        rec.call_method_in(&method_clone, args, sym)
     };
     let f = Function::new(
         format!("method_call<{}>", method),
@@ -57,17 +83,43 @@ fn method_call(method: String) -> Expr {
     Expr::Function(f)
 }
 
-fn parse_symbol<'a>(i: &'a str) -> IResult<&'a str, Expr, VerboseError<&'a str>> {
+fn parse_symbol(i: &str) -> IResult<&str, Expr, VerboseError<&str>> {
     map(take_while1(is_symbol_char), |sym: &str| {
-        if sym.starts_with('.') {
-            method_call(sym[1..].into())
-        } else {
-            Expr::Symbol(sym.into())
+        match sym.strip_prefix('.') {
+            Some(method) => method_call(method.into()),
+            None => Expr::Symbol(sym.into()),
         }
     })(i)
 }
 
-fn parse_string<'a>(i: &'a str) -> IResult<&'a str, Expr, VerboseError<&'a str>> {
+/// The special form `'expr` reads as, unless `expr` is a list.
+pub(crate) const QUOTE_FORM: &str = "quote-form";
+
+/// The head of what `@expr` reads as. `@` before a name always reads as a
+/// spread, so no program can write this symbol, and a list starting with it
+/// came from `@`.
+pub(crate) const SPREAD_FORM: &str = "@spread";
+
+// What `#tag"literal"` reads as until the function registered for the tag
+// replaces it, which needs an interpreter. See `ExprIterator::with_reader_tags`.
+pub(crate) const READER_TAG: &str = "#reader-tag";
+
+/// A tagged literal, `#duration"5m"`. Without a string right after it,
+/// `#duration` is just a symbol.
+fn parse_tagged(i: &str) -> IResult<&str, Expr, VerboseError<&str>> {
+    map(
+        preceded(char('#'), pair(take_while1(is_symbol_char), parse_string)),
+        |(tag, literal): (&str, Expr)| {
+            Expr::List(im::vector![
+                Expr::Symbol(READER_TAG.into()),
+                Expr::String(tag.into()),
+                literal
+            ])
+        },
+    )(i)
+}
+
+pub(crate) fn parse_string(i: &str) -> IResult<&str, Expr, VerboseError<&str>> {
     let esc = escaped(none_of("\\\""), '\\', tag("\""));
     let esc_or_empty = alt((esc, tag("")));
 
@@ -76,14 +128,14 @@ fn parse_string<'a>(i: &'a str) -> IResult<&'a str, Expr, VerboseError<&'a str>>
     })(i)
 }
 
-fn parse_bool<'a>(i: &'a str) -> IResult<&'a str, Expr, VerboseError<&'a str>> {
+pub(crate) fn parse_bool(i: &str) -> IResult<&str, Expr, VerboseError<&str>> {
     alt((
         map(tag("true"), |_| Expr::Bool(true)),
         map(tag("false"), |_| Expr::Bool(false)),
     ))(i)
 }
 
-fn ignored_input<'a>(i: &'a str) -> IResult<&'a str, &'a str, VerboseError<&'a str>> {
+fn ignored_input(i: &str) -> IResult<&str, &str, VerboseError<&str>> {
     let comment_parse = delimited(
         preceded(multispace0, tag(";")),
         take_till(|c| c == '\n'),
@@ -92,29 +144,89 @@ fn ignored_input<'a>(i: &'a str) -> IResult<&'a str, &'a str, VerboseError<&'a s
     alt((comment_parse, multispace0))(i)
 }
 
-fn parse_tuple<'a>(i: &'a str) -> IResult<&'a str, Expr, VerboseError<&'a str>> {
-    let make_tuple = |exprs: Vec<_>| {
+/// What a `'` or `^` prefix applies to.
+enum Prefixed {
+    /// The contents of the list right after it.
+    List(Vector<Expr>),
+    /// Any other one expression after it.
+    One(Expr),
+}
+
+/// What the `marker` prefix applies to: the list right after it, or else
+/// the one expression after it. Prefixes compose in the order they're
+/// written, so `'^(1 2)` quotes the tuple form `^(1 2)` and `^'(1 2)` is a
+/// tuple holding the quote `'(1 2)`.
+fn prefixed<'a>(
+    marker: &'static str,
+    missing: &'static str,
+) -> impl FnMut(&'a str) -> IResult<&'a str, Prefixed, VerboseError<&'a str>> {
+    move |i: &'a str| {
+        let (rest, _) = tag::<_, _, VerboseError<&'a str>>(marker)(i)?;
+        if rest.starts_with('(') {
+            return map(s_exp(many0(parse_expr)), |l| Prefixed::List(l.into()))(rest);
+        }
+        match parse_form(rest) {
+            Ok((rest, expr)) => Ok((rest, Prefixed::One(expr))),
+            Err(nom::Err::Error(_)) => Err(nom::Err::Failure(VerboseError {
+                errors: vec![(i, VerboseErrorKind::Context(missing))],
+            })),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn parse_tuple(i: &str) -> IResult<&str, Expr, VerboseError<&str>> {
+    let make_tuple = |prefixed: Prefixed| {
         let mut tuple_list = im::vector![Expr::Symbol("tuple".into())];
-        tuple_list.append(exprs.into());
+        match prefixed {
+            Prefixed::List(exprs) => tuple_list.append(exprs),
+            Prefixed::One(expr) => tuple_list.push_back(expr),
+        }
         Expr::List(tuple_list)
     };
+    map(prefixed("^", "expected expression after ^"), make_tuple)(i)
+}
+
+/// Spread syntax, `@expr`, which splices a list into the argument
+/// positions of a call. `@rest` becomes `(@spread rest)`, see `SPREAD_FORM`.
+fn parse_spread(i: &str) -> IResult<&str, Expr, VerboseError<&str>> {
     map(
-        context("quote", preceded(tag("^"), cut(s_exp(many0(parse_expr))))),
-        make_tuple,
+        context("spread", preceded(tag("@"), cut(parse_expr))),
+        |expr| Expr::List(im::vector![Expr::Symbol(SPREAD_FORM.into()), expr]),
     )(i)
 }
 
-fn parse_quote<'a>(i: &'a str) -> IResult<&'a str, Expr, VerboseError<&'a str>> {
+/// Unquote syntax, `~expr`, which evaluates `expr` inside `data`.
+/// `~port` becomes `(unquote port)`.
+fn parse_unquote(i: &str) -> IResult<&str, Expr, VerboseError<&str>> {
     map(
-        context("quote", preceded(tag("'"), cut(s_exp(many0(parse_expr))))),
-        |exprs| Expr::Quote(exprs.into()),
+        context("unquote", preceded(tag("~"), cut(parse_expr))),
+        |expr| Expr::List(im::vector![Expr::Symbol("unquote".into()), expr]),
     )(i)
 }
 
-fn parse_num<'a>(i: &'a str) -> IResult<&'a str, Expr, VerboseError<&'a str>> {
-    map_res(recognize_float, |digit_str: &str| {
-        digit_str.parse::<Num>().map(Expr::Num)
-    })(i)
+/// `'(1 2)` reads as a quote, which evaluates to the list. `'` before
+/// anything else, like `'x` or `''(1 2)`, reads as `(quote-form x)`, which
+/// evaluates to the expression unevaluated, so `'x` is the symbol `x`.
+fn parse_quote(i: &str) -> IResult<&str, Expr, VerboseError<&str>> {
+    map(
+        prefixed("'", "expected expression after '"),
+        |prefixed| match prefixed {
+            Prefixed::List(exprs) => Expr::Quote(exprs),
+            Prefixed::One(expr) => Expr::List(im::vector![Expr::Symbol(QUOTE_FORM.into()), expr]),
+        },
+    )(i)
+}
+
+pub(crate) fn parse_num(i: &str) -> IResult<&str, Expr, VerboseError<&str>> {
+    let (rest, digits) = recognize_float(i)?;
+    match read_num(digits) {
+        Ok(num) => Ok((rest, Expr::Num(num))),
+        // Like an exponent out of range, which shouldn't read as a symbol.
+        Err(_) => Err(nom::Err::Failure(VerboseError {
+            errors: vec![(i, VerboseErrorKind::Context("num in range"))],
+        })),
+    }
 }
 
 fn s_exp<'a, O1, F>(inner: F) -> impl FnMut(&'a str) -> IResult<&'a str, O1, VerboseError<&'a str>>
@@ -128,64 +240,346 @@ where
     )
 }
 
-fn parse_list<'a>(i: &'a str) -> IResult<&'a str, Expr, VerboseError<&'a str>> {
+fn parse_list(i: &str) -> IResult<&str, Expr, VerboseError<&str>> {
     let application_inner = map(many0(parse_expr), |l| Expr::List(l.into()));
     // finally, we wrap it in an s-expression
     s_exp(application_inner)(i)
 }
 
-fn parse_expr<'a>(i: &'a str) -> IResult<&'a str, Expr, VerboseError<&'a str>> {
-    delimited(
-        ignored_input,
-        alt((
-            parse_list,
-            parse_quote,
-            parse_tuple,
-            parse_string,
-            parse_num,
-            parse_bool,
-            parse_symbol,
-        )),
-        ignored_input,
-    )(i)
+/// A single expression, without the whitespace or comments around it.
+fn parse_form(i: &str) -> IResult<&str, Expr, VerboseError<&str>> {
+    alt((
+        parse_list,
+        parse_quote,
+        parse_tuple,
+        parse_spread,
+        parse_unquote,
+        parse_string,
+        parse_num,
+        parse_bool,
+        parse_tagged,
+        parse_symbol,
+    ))(i)
+}
+
+fn parse_expr(i: &str) -> IResult<&str, Expr, VerboseError<&str>> {
+    delimited(ignored_input, parse_form, ignored_input)(i)
+}
+
+/// An active `;#line 12 "original.dsl"` directive.
+struct LineDirective {
+    file: String,
+    // The line the directive says comes next, and the real line it applies to.
+    line: usize,
+    physical_line: usize,
 }
 
 pub(crate) struct ExprIterator<'a> {
     input: &'a str,
     done: bool,
+    // Position of `input` in the source, 1-based.
+    line: usize,
+    col: usize,
+    directive: Option<LineDirective>,
+    // Attach `;;` comment blocks to the definitions they precede.
+    keep_comments: bool,
+    // The lines of the `;;` comment block right before `input`, if any.
+    comment: Vec<String>,
+    // Where the form last returned started, and its span if a directive is active.
+    form_start: (usize, usize),
+    form_span: Option<Span>,
+    // Where reader tags are registered, if anywhere.
+    symbol_table: Option<&'a SymbolTable>,
+    // Leave tagged literals as placeholders rather than reading them.
+    keep_reader_tags: bool,
 }
 
 impl<'a> ExprIterator<'a> {
     pub(crate) fn new(input: &'a str) -> Self {
-        Self { input, done: false }
+        Self {
+            input,
+            done: false,
+            line: 1,
+            col: 1,
+            directive: None,
+            keep_comments: false,
+            comment: Vec::new(),
+            form_start: (1, 1),
+            form_span: None,
+            symbol_table: None,
+            keep_reader_tags: false,
+        }
+    }
+
+    /// Leave tagged literals as placeholders instead of reading them, for
+    /// checking syntax where the tags aren't registered.
+    pub(crate) fn keeping_reader_tags(mut self) -> Self {
+        self.keep_reader_tags = true;
+        self
+    }
+
+    /// Read tagged literals, like `#duration"5m"`, with the functions
+    /// registered in `symbol_table` by `set-reader-tag!`. Otherwise every
+    /// tagged literal is an error.
+    pub(crate) fn with_reader_tags(mut self, symbol_table: &'a SymbolTable) -> Self {
+        self.symbol_table = Some(symbol_table);
+        self
     }
+
+    /// Where the form last returned started. Cites `file`, unless a
+    /// `;#line` directive says otherwise.
+    pub(crate) fn last_span(&self, file: &str) -> Span {
+        self.form_span.clone().unwrap_or_else(|| Span {
+            file: file.into(),
+            line: self.form_start.0,
+            col: self.form_start.1,
+        })
+    }
+
+    fn advance(&mut self, n: usize) {
+        let (consumed, rest) = self.input.split_at(n);
+        match consumed.rfind('\n') {
+            Some(last) => {
+                self.line += consumed.matches('\n').count();
+                self.col = consumed[last + 1..].chars().count() + 1;
+            }
+            None => self.col += consumed.chars().count(),
+        }
+        self.input = rest;
+    }
+
+    /// Skip whitespace and comments, applying any `;#line` directives.
+    fn skip_ignored(&mut self) {
+        loop {
+            let trimmed = self.input.trim_start();
+            let skipped = &self.input[..self.input.len() - trimmed.len()];
+            // A comment on its own line, rather than after some code.
+            let own_line = skipped.contains('\n') || self.col == 1;
+            if skipped.matches('\n').count() > 1 {
+                // Blank lines separate a comment block from what follows.
+                self.comment.clear();
+            }
+            self.advance(skipped.len());
+            if !self.input.starts_with(';') {
+                return;
+            }
+            let end = self.input.find('\n').unwrap_or(self.input.len());
+            let directive = parse_line_directive(&self.input[..end]);
+            match doc_comment_line(&self.input[..end]) {
+                Some(line) if own_line => self.comment.push(line.into()),
+                _ => self.comment.clear(),
+            }
+            self.advance(end);
+            if let Some((line, file)) = directive {
+                self.directive = Some(LineDirective {
+                    file,
+                    line,
+                    physical_line: self.line + 1,
+                });
+            }
+        }
+    }
+
+    /// The line `rest`, which is what's left of the input somewhere in the
+    /// next form, is on. Follows `;#line` directives.
+    fn line_of(&self, rest: &str) -> usize {
+        let consumed = &self.input[..self.input.len() - rest.len()];
+        let line = self.line + consumed.matches('\n').count();
+        match &self.directive {
+            Some(d) => d.line + line - d.physical_line,
+            None => line,
+        }
+    }
+
+    /// Where the next form starts, if a `;#line` directive is active.
+    fn span(&self) -> Option<Span> {
+        self.directive.as_ref().map(|d| Span {
+            file: d.file.clone(),
+            line: d.line + self.line - d.physical_line,
+            col: self.col,
+        })
+    }
+}
+
+/// `;; Some text` -> `Some text`. Plain `;` comments aren't documentation.
+fn doc_comment_line(comment: &str) -> Option<&str> {
+    let text = comment.strip_prefix(";;")?.trim_start_matches(';');
+    Some(text.strip_prefix(' ').unwrap_or(text).trim_end())
+}
+
+/// Give `form`, if it is a `defn` without a docstring, `comment` as its docstring.
+fn attach_comment(form: Expr, comment: Vec<String>) -> Expr {
+    if comment.is_empty() {
+        return form;
+    }
+    match form {
+        Expr::List(mut l)
+            if l.len() > 3
+                && l[0].symbol_matches("defn")
+                && l[1].is_symbol()
+                && !matches!(l[2], Expr::String(_)) =>
+        {
+            l.insert(2, Expr::String(comment.join("\n")));
+            Expr::List(l)
+        }
+        rest => rest,
+    }
+}
+
+/// `;#line 12 "original.dsl"` -> (12, "original.dsl")
+fn parse_line_directive(comment: &str) -> Option<(usize, String)> {
+    let rest = comment.strip_prefix(";#line")?.trim();
+    let (line, file) = rest.split_at(rest.find(char::is_whitespace)?);
+    let file = file.trim().strip_prefix('"')?.strip_suffix('"')?;
+    Some((line.parse().ok()?, file.into()))
 }
 
 impl<'a> Iterator for ExprIterator<'a> {
     type Item = LispResult<Expr>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.done || self.input.is_empty() {
+        if self.done {
+            return None;
+        }
+        self.skip_ignored();
+        if self.input.is_empty() {
             return None;
         }
-        let (rest, res) = match parse_expr(self.input) {
+        let span = self.span();
+        self.form_start = (self.line, self.col);
+        self.form_span = span.clone();
+        // Comments after the form are left for `skip_ignored`.
+        let (rest, res) = match parse_form(self.input) {
             Ok(r) => r,
             Err(e) => {
                 self.done = true;
+                let message = match &e {
+                    nom::Err::Failure(VerboseError { errors }) => match errors.first() {
+                        Some((rest, VerboseErrorKind::Context(what)))
+                            if what.starts_with("expected expression") =>
+                        {
+                            format!("{} (line {})", what, self.line_of(rest))
+                        }
+                        _ => e.to_string(),
+                    },
+                    _ => e.to_string(),
+                };
                 return Some(Err(anyhow::Error::new(ProgramError::FailedToParse(
-                    e.to_string(),
+                    message,
                 ))));
             }
         };
-        self.input = rest;
-        Some(Ok(res))
+        let input = self.input;
+        let consumed = &input[..input.len() - rest.len()];
+        let res = if consumed.contains('#') && !self.keep_reader_tags {
+            match apply_reader_tags(res, false, self.symbol_table) {
+                Ok(res) => res,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        } else {
+            res
+        };
+        self.advance(consumed.len());
+        let comment = std::mem::take(&mut self.comment);
+        let res = if self.keep_comments {
+            attach_comment(res, comment)
+        } else {
+            res
+        };
+        Some(Ok(match span {
+            Some(span) => res.with_span(&span),
+            None => res,
+        }))
+    }
+}
+
+/// `expr` with each tagged literal replaced by what its reader tag makes of
+/// it. Tags in quoted data apply too. In code, values which would be
+/// evaluated, like lists and symbols, are quoted so they stay values.
+fn apply_reader_tags(
+    expr: Expr,
+    quoted: bool,
+    symbol_table: Option<&SymbolTable>,
+) -> LispResult<Expr> {
+    let apply_all = |items: Vector<Expr>, quoted| {
+        items
+            .into_iter()
+            .map(|e| apply_reader_tags(e, quoted, symbol_table))
+            .collect::<LispResult<Vector<Expr>>>()
+    };
+    match expr {
+        Expr::List(l) if l.len() == 3 && l[0].symbol_matches(READER_TAG) => {
+            let value = read_tagged(&l[1].get_string()?, l[2].clone(), symbol_table)?;
+            Ok(if quoted { value } else { quote_value(value) })
+        }
+        Expr::List(l) if !l.is_empty() && l[0].symbol_matches(QUOTE_FORM) => {
+            Ok(Expr::List(apply_all(l, true)?))
+        }
+        Expr::List(l) => Ok(Expr::List(apply_all(l, quoted)?)),
+        Expr::Quote(l) => Ok(Expr::Quote(apply_all(l, true)?)),
+        other => Ok(other),
+    }
+}
+
+/// An expression which evaluates to `value`. Values which evaluate to
+/// themselves are left as they are.
+pub(crate) fn quote_value(value: Expr) -> Expr {
+    match value {
+        Expr::List(l) => Expr::Quote(l),
+        Expr::Quote(_) => Expr::List(im::vector![Expr::Symbol(QUOTE_FORM.into()), value]),
+        Expr::Symbol(_) if !value.is_keyword() => {
+            Expr::List(im::vector![Expr::Symbol(QUOTE_FORM.into()), value])
+        }
+        rest => rest,
+    }
+}
+
+/// `#tag"literal"`, read by the function registered for `tag`.
+fn read_tagged(tag: &str, literal: Expr, symbol_table: Option<&SymbolTable>) -> LispResult<Expr> {
+    let symbol_table = match symbol_table {
+        Some(symbol_table) => symbol_table,
+        None => return Err(unknown_reader_tag(tag, &[])),
+    };
+    // Don't hold the host while the reader runs, it may register tags too.
+    let reader = symbol_table.host().reader_tag(tag);
+    match reader {
+        Some(reader) => reader
+            .call_with_values(im::vector![literal.clone()], symbol_table)
+            .with_context(|| format!("The reader tag #{} failed to read {:?}", tag, literal)),
+        None => Err(unknown_reader_tag(tag, &symbol_table.host().reader_tags())),
     }
 }
 
-pub(crate) fn read(s: &str) -> ExprIterator {
+fn unknown_reader_tag(tag: &str, known: &[String]) -> anyhow::Error {
+    let message = if known.is_empty() {
+        format!("unknown reader tag #{}, none are registered", tag)
+    } else {
+        let known: Vec<String> = known.iter().map(|t| format!("#{}", t)).collect();
+        format!(
+            "unknown reader tag #{}, the known tags are {}",
+            tag,
+            known.join(" ")
+        )
+    };
+    anyhow::Error::new(ProgramError::FailedToParse(message))
+}
+
+pub(crate) fn read(s: &str) -> ExprIterator<'_> {
     ExprIterator::new(s)
 }
 
+/// Like `read`, but a block of `;;` comment lines directly above a
+/// top level `defn` without a docstring becomes its docstring. A blank
+/// line, a plain `;` comment, or code between them breaks the association.
+pub(crate) fn read_with_comments(s: &str) -> ExprIterator<'_> {
+    let mut iter = ExprIterator::new(s);
+    iter.keep_comments = true;
+    iter
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -206,6 +600,19 @@ mod tests {
         assert_eq!(parse_num("-0.1").unwrap(), ("", num_f!(-0.1)));
     }
 
+    #[test]
+    fn parse_exponents_and_leading_zeros() {
+        assert_eq!(parse_num("007.50").unwrap(), ("", num_f!(7.5)));
+        assert_eq!(parse_num("1.5e3").unwrap(), ("", num_f!(1500.0)));
+        assert_eq!(parse_num("+25E-2").unwrap(), ("", num_f!(0.25)));
+        assert_eq!(format!("{:?}", parse_num("-0.0e5").unwrap().1), "0");
+        // Too big to print, and not a symbol either.
+        assert!(matches!(
+            parse_num("1e9223372036854775807"),
+            Err(nom::Err::Failure(_))
+        ));
+    }
+
     macro_rules! test_symbol {
         ($($sym:literal),*) => {
             $(
@@ -293,6 +700,230 @@ mod tests {
         )
     }
 
+    #[test]
+    fn line_directives() {
+        let prog = "(a)\n;#line 12 \"original.dsl\"\n(b)\n\n  (c) ; trailing\n(d";
+        let forms: Vec<String> = read(prog)
+            .map(|e| {
+                e.map(|e| format!("{:?}", e))
+                    .unwrap_or_else(|_| "error".into())
+            })
+            .collect();
+        assert_eq!(
+            forms,
+            vec![
+                "(a)",
+                "(with-location \"original.dsl\" 12 1 (b))",
+                "(with-location \"original.dsl\" 14 3 (c))",
+                "error",
+            ]
+        );
+        assert_eq!(
+            parse_line_directive(";#line 3 \"f.dsl\""),
+            Some((3, "f.dsl".into()))
+        );
+        assert_eq!(parse_line_directive(";#line x \"f.dsl\""), None);
+        assert_eq!(parse_line_directive("; just a comment"), None);
+    }
+
+    #[test]
+    fn comments_attach_to_definitions() {
+        let prog = ";; Adds one.
+;;; Really.
+(defn inc1 (x) (+ x 1))
+
+;; Separated by a blank line.
+
+(defn blank (x) x)
+;; Has its own docstring.
+(defn documented \"Explicit.\" (x) x)
+(def a 1) ;; trailing, not a doc comment
+(defn trailing (x) x)
+;; Broken by a plain comment.
+; plain
+(defn plain (x) x)
+;; Not a defn.
+(def b 2)
+(defn after-def (x) x)
+  ;;   Indented comments work too.
+  (defn indented (x :num) :num x)";
+        let docs: Vec<String> = read_with_comments(prog)
+            .map(|e| match e.unwrap() {
+                Expr::List(l) if l[0].symbol_matches("defn") => format!("{:?}", l[2]),
+                other => format!("{:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            docs,
+            vec![
+                "\"Adds one.\nReally.\"",
+                "(x)",
+                "\"Explicit.\"",
+                "(def a 1)",
+                "(x)",
+                "(x)",
+                "(def b 2)",
+                "(x)",
+                "\"  Indented comments work too.\"",
+            ]
+        );
+        // Plain read leaves definitions alone.
+        let plain = read(";; Adds one.\n(defn inc1 (x) (+ x 1))").next();
+        assert_eq!(
+            format!("{:?}", plain.unwrap().unwrap()),
+            "(defn inc1 (x) (+ x 1))"
+        );
+    }
+
+    #[test]
+    fn parse_spread_arg() {
+        use im::vector;
+        assert_eq!(
+            parse_expr("(f 1 @rest)").unwrap(),
+            (
+                "",
+                Expr::List(vector![
+                    Expr::Symbol("f".into()),
+                    num_f!(1.0),
+                    Expr::List(vector![
+                        Expr::Symbol(SPREAD_FORM.into()),
+                        Expr::Symbol("rest".into())
+                    ])
+                ])
+            )
+        );
+        // The marker can't be written, even by spelling it out.
+        let (_, spelled) = parse_expr(SPREAD_FORM).unwrap();
+        assert_ne!(spelled, Expr::Symbol(SPREAD_FORM.into()));
+    }
+
+    #[test]
+    fn parse_unquote_arg() {
+        use im::vector;
+        let unquote = |e| Expr::List(vector![Expr::Symbol("unquote".into()), e]);
+        assert_eq!(
+            parse_expr("(a ~b ~(f))").unwrap(),
+            (
+                "",
+                Expr::List(vector![
+                    Expr::Symbol("a".into()),
+                    unquote(Expr::Symbol("b".into())),
+                    unquote(Expr::List(vector![Expr::Symbol("f".into())]))
+                ])
+            )
+        );
+        // Only a leading ~ unquotes.
+        assert_eq!(parse_expr("a~b").unwrap(), ("", Expr::Symbol("a~b".into())));
+    }
+
+    #[test]
+    fn prefixes_stack_in_written_order() {
+        use crate::pretty::{pretty, PrettyOptions};
+        let cases = [
+            ("'^(1 2)", "(quote-form (tuple 1 2))"),
+            ("^'(1 2)", "(tuple '(1 2))"),
+            ("''(1 2)", "(quote-form '(1 2))"),
+            ("^^(1 2)", "(tuple (tuple 1 2))"),
+            ("'x", "(quote-form x)"),
+            ("^x", "(tuple x)"),
+            ("'^'x", "(quote-form (tuple (quote-form x)))"),
+        ];
+        for (src, expected) in cases.iter() {
+            let expr = read(src).next().unwrap().unwrap();
+            assert_eq!(format!("{:?}", expr), *expected, "{}", src);
+            let printed = pretty(&expr, &PrettyOptions::default());
+            assert_eq!(read(&printed).next().unwrap().unwrap(), expr, "{}", src);
+        }
+    }
+
+    #[test]
+    fn quoting_one_expression_gives_it_back() {
+        use crate::cli::Options;
+        use crate::stdlib::create_stdlib_symbol_table;
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let eval = |prog: &str| syms.eval_source(prog).unwrap();
+        assert_eq!(eval("'x"), Expr::Symbol("x".into()));
+        assert_eq!(eval("'5"), Expr::from(5));
+        assert_eq!(eval("''(1 2)"), read("'(1 2)").next().unwrap().unwrap());
+        assert_eq!(
+            eval("'^(1 2)"),
+            read("(tuple 1 2)").next().unwrap().unwrap()
+        );
+        assert_eq!(eval("(list 'a 'b)"), eval("'(a b)"));
+    }
+
+    #[test]
+    fn dangling_prefixes() {
+        let error = |src: &str| read(src).find_map(Result::err).unwrap().to_string();
+        let expected = |after: &str, line: usize| {
+            format!(
+                "FailedToParse(\"expected expression after {} (line {})\")",
+                after, line
+            )
+        };
+        assert_eq!(error("'"), expected("'", 1));
+        assert_eq!(error("''"), expected("'", 1));
+        assert_eq!(error("'^"), expected("^", 1));
+        assert_eq!(error("^ (1 2)"), expected("^", 1));
+        assert_eq!(error("(a)\n(list 1\n  2 ')"), expected("'", 3));
+        assert_eq!(error(";#line 40 \"f.dsl\"\n(a\n ^)"), expected("^", 41));
+    }
+
+    #[test]
+    fn reader_tags() {
+        use crate::cli::Options;
+        use crate::stdlib::create_stdlib_symbol_table;
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let prog = r#"
+(set-reader-tag! "twice" (fn (s) (str s s)))
+(set-reader-tag! "pair" (fn (s) (tuple s s)))
+(dict "name" #twice"ab" "parts" (list #pair"x" '(#twice"y" #pair"z")))"#;
+        let expected = r#"(dict "name" "abab" "parts" (list ^("x" "x") (list "yy" ^("z" "z"))))"#;
+        assert_eq!(
+            syms.eval_source(prog).unwrap(),
+            syms.eval_source(expected).unwrap()
+        );
+
+        let error = |prog: &str| format!("{:#}", syms.eval_source(prog).unwrap_err());
+        assert_eq!(
+            error("(list 1 #nope\"x\")"),
+            "FailedToParse(\"unknown reader tag #nope, the known tags are #pair #twice\")"
+        );
+        syms.eval_source("(set-reader-tag! \"bad\" (fn (s) (err \"no\")))")
+            .unwrap();
+        assert!(error("#bad\"x\"").starts_with("The reader tag #bad failed to read \"x\""));
+        assert!(syms.eval_source("(set-reader-tag! \"a b\" str)").is_err());
+
+        // Readers may give lists and symbols, which are values in code too.
+        syms.eval_source("(set-reader-tag! \"parts\" (fn (s) (list s s)))")
+            .unwrap();
+        syms.eval_source("(set-reader-tag! \"name\" (fn (s) 'undefined))")
+            .unwrap();
+        assert_eq!(
+            syms.eval_source("(list #parts\"a\" '(#parts\"b\") #name\"c\" '#name\"d\")")
+                .unwrap(),
+            syms.eval_source("(list '(\"a\" \"a\") '((\"b\" \"b\")) 'undefined 'undefined)")
+                .unwrap()
+        );
+
+        // Without an interpreter, there are no tags.
+        assert_eq!(
+            read("#twice\"x\"").next().unwrap().unwrap_err().to_string(),
+            "FailedToParse(\"unknown reader tag #twice, none are registered\")"
+        );
+        // Checking syntax leaves tags unread.
+        assert!(read("(f #twice\"x\")")
+            .keeping_reader_tags()
+            .next()
+            .unwrap()
+            .is_ok());
+        // Nor without a string right after.
+        assert_eq!(
+            read("#twice \"x\"").next().unwrap().unwrap(),
+            Expr::Symbol("#twice".into())
+        );
+    }
+
     #[test]
     fn parse_ignored_input() {
         assert_eq!(ignored_input("; hello\n"), Ok(("", " hello")));
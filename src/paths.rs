@@ -0,0 +1,174 @@
+use crate::access;
+use crate::exact_len;
+use crate::symbols::{Expr, LispResult, SymbolTable};
+use anyhow::{anyhow, bail, ensure};
+use im::Vector;
+use std::path::{Component, Path, PathBuf};
+
+// Path builtins. Paths are x7 strings, but are always taken apart and put
+// together with std::path, so separators and Windows drive letters are
+// handled by the platform rather than by string surgery.
+
+/// The path as an x7 string. Paths which aren't valid UTF-8 are errors.
+fn path_expr(path: &Path) -> LispResult<Expr> {
+    path.to_str()
+        .map(|s| Expr::String(s.into()))
+        .ok_or_else(|| anyhow!("The path {:?} is not valid UTF-8", path))
+}
+
+fn optional_path_expr(path: Option<&Path>) -> LispResult<Expr> {
+    path.map(path_expr).unwrap_or(Ok(Expr::Nil))
+}
+
+/// The path from `base` to `path`, worked out from their components alone.
+fn relative(base: &Path, path: &Path) -> LispResult<PathBuf> {
+    let no_relative_path = || anyhow!("There is no relative path from {:?} to {:?}", base, path);
+    ensure!(
+        base.is_absolute() == path.is_absolute(),
+        "path-relative needs two absolute or two relative paths, but was given {:?} and {:?}",
+        base,
+        path
+    );
+    let base: Vec<Component> = base.components().collect();
+    let path: Vec<Component> = path.components().collect();
+    let common = base
+        .iter()
+        .zip(path.iter())
+        .take_while(|(b, p)| b == p)
+        .count();
+    // Different drives, or climbing out of a `..` we can't see past.
+    let starts_differently = |c: &Component| matches!(c, Component::Prefix(_) | Component::RootDir);
+    if base[common..]
+        .iter()
+        .any(|c| starts_differently(c) || *c == Component::ParentDir)
+        || path[common..].iter().any(starts_differently)
+    {
+        return Err(no_relative_path());
+    }
+    let mut res = PathBuf::new();
+    for _ in common..base.len() {
+        res.push("..");
+    }
+    for component in path[common..].iter() {
+        res.push(component.as_os_str());
+    }
+    if res.as_os_str().is_empty() {
+        res.push(".");
+    }
+    Ok(res)
+}
+
+pub(crate) fn path_join(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let mut path = PathBuf::new();
+    for part in exprs.iter() {
+        path.push(part.get_string()?);
+    }
+    path_expr(&path)
+}
+
+pub(crate) fn path_parent(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    optional_path_expr(Path::new(&exprs[0].get_string()?).parent())
+}
+
+pub(crate) fn path_filename(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let path = exprs[0].get_string()?;
+    optional_path_expr(Path::new(&path).file_name().map(Path::new))
+}
+
+pub(crate) fn path_extension(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let path = exprs[0].get_string()?;
+    optional_path_expr(Path::new(&path).extension().map(Path::new))
+}
+
+pub(crate) fn path_absolute(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let path = PathBuf::from(exprs[0].get_string()?);
+    if path.is_absolute() {
+        return path_expr(&path);
+    }
+    let cwd = std::env::current_dir()
+        .map_err(|e| anyhow!("Could not find the current directory, {}", e))?;
+    path_expr(&cwd.join(path))
+}
+
+pub(crate) fn path_canonical(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let path = exprs[0].get_string()?;
+    path_expr(&access::canonicalize(
+        symbol_table,
+        "path-canonical",
+        &path,
+    )?)
+}
+
+pub(crate) fn path_relative(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let base = exprs[0].get_string()?;
+    let path = exprs[1].get_string()?;
+    path_expr(&relative(Path::new(&base), Path::new(&path))?)
+}
+
+pub(crate) fn path_exists(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let path = exprs[0].get_string()?;
+    access::exists(symbol_table, "path-exists?", &path).map(Expr::Bool)
+}
+
+pub(crate) fn glob(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let pattern = exprs[0].get_string()?;
+    access::glob(symbol_table, "glob", &pattern)?
+        .iter()
+        .map(|path| path_expr(path))
+        .collect::<LispResult<_>>()
+        .map(Expr::List)
+}
+
+pub(crate) fn home_dir(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 0);
+    match dirs_next::home_dir() {
+        Some(home) => path_expr(&home),
+        None => bail!("Could not find the home directory"),
+    }
+}
+
+pub(crate) fn temp_dir(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 0);
+    path_expr(&std::env::temp_dir())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_paths() {
+        // Absolute on every platform, whatever its separators and drive.
+        let root = std::env::temp_dir();
+        let base = root.join("srv").join("app");
+        let rel = |p: &Path| relative(&base, p).unwrap();
+        assert_eq!(
+            rel(&base.join("lib").join("a.x7")),
+            Path::new("lib").join("a.x7")
+        );
+        assert_eq!(
+            rel(&root.join("srv").join("data")),
+            Path::new("..").join("data")
+        );
+        assert_eq!(rel(&base), Path::new("."));
+        assert!(relative(&base, Path::new("lib")).is_err());
+        assert!(relative(&Path::new("..").join("a"), Path::new("b")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_paths_are_errors() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(OsStr::from_bytes(b"bad\xff.x7"));
+        assert!(path_expr(path).is_err());
+    }
+}
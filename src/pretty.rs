@@ -0,0 +1,205 @@
+use crate::inspect::plural;
+use crate::symbols::{sort_order, Expr, LispResult};
+use anyhow::{bail, ensure};
+use im::Vector;
+use itertools::Itertools;
+
+// Printing values deterministically and within bounds, for snapshot tests
+// and big data, by `pprint` and `json-serialize`:
+//
+//   :sort-keys  write dict entries in key order, not the dict's own order
+//   :max-depth  write collections nested deeper than this as `…` and how
+//               many elements they hold
+//   :max-seq    write sequences longer than this as their first and last
+//               elements, with how many were left out between
+//   :pretty     indent JSON, one entry per line
+//
+// The options are passed down with each value printed, so nothing printing
+// one value can change how another is printed.
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct PrettyOptions {
+    pub(crate) sort_keys: bool,
+    pub(crate) max_depth: Option<usize>,
+    pub(crate) max_seq: Option<usize>,
+    /// Only JSON is indented.
+    #[cfg(feature = "json")]
+    pub(crate) pretty: bool,
+}
+
+impl PrettyOptions {
+    /// Options from `:option value` pairs given to `what`, which knows `known`.
+    pub(crate) fn parse(what: &str, args: &Vector<Expr>, known: &[&str]) -> LispResult<Self> {
+        ensure!(
+            args.len().is_multiple_of(2),
+            "{} takes options as :option value pairs, but was given {}",
+            what,
+            Expr::List(args.clone())
+        );
+        let mut options = PrettyOptions::default();
+        for (key, value) in args.iter().tuples() {
+            let key = key.get_symbol_string()?;
+            if !known.contains(&key.as_str()) {
+                bail!(
+                    "{} has no option {}, it knows {}",
+                    what,
+                    key,
+                    known.join(" ")
+                );
+            }
+            match key.as_str() {
+                ":sort-keys" => options.sort_keys = value.get_bool()?,
+                ":max-depth" => options.max_depth = Some(value.get_usize()?),
+                ":max-seq" => options.max_seq = Some(value.get_usize()?),
+                #[cfg(feature = "json")]
+                ":pretty" => options.pretty = value.get_bool()?,
+                _ => unreachable!(),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// `expr` printed like its usual form, within `options`.
+pub(crate) fn pretty(expr: &Expr, options: &PrettyOptions) -> String {
+    let mut out = String::new();
+    write_expr(&mut out, expr, options, 0);
+    out
+}
+
+fn write_expr(out: &mut String, expr: &Expr, options: &PrettyOptions, depth: usize) {
+    match expr.unmeta() {
+        Expr::List(l) => write_seq(out, ("(", ")"), l, options, depth),
+        Expr::Quote(l) => write_seq(out, ("'(", ")"), l, options, depth),
+        Expr::Tuple(l) => write_seq(out, ("(tuple ", ")"), l, options, depth),
+        Expr::Dict(d) => {
+            if too_deep(options, depth, d.len()) {
+                out.push_str(&format!("{{… {}}}", plural(d.len(), "entry", "entries")));
+                return;
+            }
+            let mut entries: Vec<(&Expr, &Expr)> = d.iter().collect();
+            if options.sort_keys {
+                entries.sort_by(|(l, _), (r, _)| sort_order(l, r));
+            }
+            out.push('{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_expr(out, key, options, depth + 1);
+                out.push_str(": ");
+                write_expr(out, value, options, depth + 1);
+            }
+            out.push('}');
+        }
+        other => out.push_str(&format!("{:?}", other)),
+    }
+}
+
+fn too_deep(options: &PrettyOptions, depth: usize, len: usize) -> bool {
+    len > 0 && options.max_depth.is_some_and(|max| depth >= max)
+}
+
+fn write_seq(
+    out: &mut String,
+    (open, close): (&str, &str),
+    items: &Vector<Expr>,
+    options: &PrettyOptions,
+    depth: usize,
+) {
+    out.push_str(open);
+    if too_deep(options, depth, items.len()) {
+        out.push_str(&format!("… {}", plural(items.len(), "item", "items")));
+        out.push_str(close);
+        return;
+    }
+    // The first half of what's shown, rounding up, then the last half.
+    let (head, tail) = match options.max_seq {
+        Some(max) if items.len() > max => (max.div_ceil(2), max / 2),
+        _ => (items.len(), 0),
+    };
+    let mut parts = Vec::new();
+    for item in items.iter().take(head) {
+        let mut part = String::new();
+        write_expr(&mut part, item, options, depth + 1);
+        parts.push(part);
+    }
+    if head + tail < items.len() {
+        parts.push(format!("… {} more …", items.len() - head - tail));
+    }
+    for item in items.iter().skip(items.len() - tail) {
+        let mut part = String::new();
+        write_expr(&mut part, item, options, depth + 1);
+        parts.push(part);
+    }
+    out.push_str(&parts.join(" "));
+    out.push_str(close);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::Options;
+    use crate::stdlib::create_stdlib_symbol_table;
+
+    fn pprint(prog: &str) -> String {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.host_mut().capture_output();
+        syms.eval_source(prog).unwrap();
+        let (stdout, _) = syms.host_mut().take_captured_output();
+        stdout.trim_end().into()
+    }
+
+    #[test]
+    fn sorting_keys() {
+        let dict = "(dict :b 1 :a (dict 3 :x 1 :y 2 :w) :c '(1 2))";
+        assert_eq!(
+            pprint(&format!("(pprint {} :sort-keys true)", dict)),
+            "{:a: {1: :y, 2: :w, 3: :x}, :b: 1, :c: (1 2)}"
+        );
+        // Without it, dicts print as they always do.
+        let printed = pprint(&format!("(def d {}) (pprint d) (println (str d))", dict));
+        let lines: Vec<&str> = printed.lines().collect();
+        assert_eq!(lines[0], lines[1]);
+    }
+
+    #[test]
+    fn limiting_depth() {
+        let nested = "(list 1 (list 2 (list 3 (list 4))) (dict :a (list 5 6)) '())";
+        assert_eq!(
+            pprint(&format!("(pprint {} :max-depth 2)", nested)),
+            "(1 (2 (… 2 items)) {:a: (… 2 items)} ())"
+        );
+        assert_eq!(
+            pprint(&format!("(pprint {} :max-depth 1)", nested)),
+            "(1 (… 2 items) {… 1 entry} ())"
+        );
+        assert_eq!(
+            pprint("(pprint (tuple 1 2) :max-depth 0)"),
+            "(tuple … 2 items)"
+        );
+    }
+
+    #[test]
+    fn eliding_long_sequences() {
+        assert_eq!(
+            pprint("(pprint (range 100) :max-seq 5)"),
+            "(0 1 2 … 95 more … 98 99)"
+        );
+        assert_eq!(pprint("(pprint (range 4) :max-seq 4)"), "(0 1 2 3)");
+        assert_eq!(pprint("(pprint (range 3) :max-seq 0)"), "(… 3 more …)");
+    }
+
+    #[test]
+    fn options_combine() {
+        let prog = "(pprint (dict :z (range 10) :a (list (list 1)) :m 0)
+                      :sort-keys true :max-depth 2 :max-seq 4)";
+        assert_eq!(
+            pprint(prog),
+            "{:a: ((… 1 item)), :m: 0, :z: (0 1 … 6 more … 8 9)}"
+        );
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let err = syms.eval_source("(pprint 1 :pretty true)").unwrap_err();
+        assert!(format!("{:#}", err).contains("it knows :sort-keys :max-depth :max-seq"));
+        assert!(syms.eval_source("(pprint 1 :max-seq)").is_err());
+    }
+}
@@ -0,0 +1,551 @@
+use crate::exact_len;
+use crate::records::{Record, RecordDoc, RecordType};
+use crate::symbols::{error_kind, next_object_id, Expr, LispResult, ProgramError, SymbolTable};
+use crate::{record, unknown_method};
+use anyhow::{anyhow, bail, ensure};
+use bigdecimal::ToPrimitive;
+use im::Vector;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+// Property testing for scripts.
+//
+// `gen-int`, `gen-string`, `gen-list`, `gen-dict`, and `gen-one-of` make
+// generators, and `(for-all (g1 g2) n f)` calls `f` with n cases drawn from
+// them. A case fails if `f` returns false or errors. The first failing case
+// is shrunk by trying simpler values one argument at a time (smaller numbers,
+// shorter strings and collections, simpler elements) and keeping any which
+// still fail, until none do.
+//
+// Each run draws its cases from a seed, taken from the interpreter's random
+// number generator (see `random-seed`) unless given with `:seed`. Failures
+// report the seed so the run can be replayed.
+
+/// Most shrinking steps taken before settling for the case found so far.
+const MAX_SHRINKS: usize = 1000;
+
+/// Dicts are made with up to this many entries.
+const MAX_DICT_LEN: usize = 8;
+
+#[derive(Clone, Debug)]
+enum Gen {
+    Int(i64, i64),
+    String {
+        min: usize,
+        max: usize,
+        charset: Vec<char>,
+    },
+    List {
+        item: Box<Gen>,
+        min: usize,
+        max: usize,
+    },
+    Dict {
+        key: Box<Gen>,
+        value: Box<Gen>,
+    },
+    OneOf(Vec<Gen>),
+    Const(Expr),
+}
+
+fn get_i64(expr: &Expr) -> LispResult<i64> {
+    expr.get_num()?
+        .to_i64()
+        .filter(|_| expr.is_int().unwrap_or(false))
+        .ok_or_else(|| anyhow!("Expected an integer, but was given {}", expr))
+}
+
+/// A `(lo hi)` length range.
+fn len_range(what: &str, expr: &Expr) -> LispResult<(usize, usize)> {
+    let range = expr.get_list()?;
+    ensure!(
+        range.len() == 2,
+        "{} needs a length range like '(0 10), but was given {}",
+        what,
+        expr
+    );
+    let (min, max) = (range[0].get_usize()?, range[1].get_usize()?);
+    ensure!(
+        min <= max,
+        "{} was given an empty length range {}",
+        what,
+        expr
+    );
+    Ok((min, max))
+}
+
+fn gen_of(expr: &Expr) -> LispResult<Gen> {
+    let record = expr.get_record()?;
+    match record
+        .as_any()
+        .and_then(|any| any.downcast_ref::<GenRecord>())
+    {
+        Some(gen) => Ok(gen.gen.clone()),
+        None => bail!("Expected a generator, but was given {}", expr),
+    }
+}
+
+/// `items` with the one at `i` dropped.
+fn without<T: Clone>(items: &Vector<T>, i: usize) -> Vector<T> {
+    let mut items = items.clone();
+    items.remove(i);
+    items
+}
+
+/// Shorter versions of `items`, no shorter than `min`, shortest first.
+fn shorter<T: Clone>(items: &Vector<T>, min: usize) -> Vec<Vector<T>> {
+    let mut res = Vec::new();
+    if items.len() <= min {
+        return res;
+    }
+    res.push(items.clone().slice(..min));
+    let half = items.len() / 2;
+    if half > min {
+        res.push(items.clone().slice(..half));
+    }
+    for i in (0..items.len()).rev() {
+        res.push(without(items, i));
+    }
+    res
+}
+
+impl Gen {
+    fn generate(&self, rng: &mut StdRng) -> Expr {
+        match self {
+            Gen::Int(lo, hi) => Expr::from(rng.gen_range(*lo as i128, *hi as i128 + 1) as i64),
+            Gen::String { min, max, charset } => {
+                let len = rng.gen_range(*min, max + 1);
+                let s = (0..len)
+                    .map(|_| charset[rng.gen_range(0, charset.len())])
+                    .collect();
+                Expr::String(s)
+            }
+            Gen::List { item, min, max } => {
+                let len = rng.gen_range(*min, max + 1);
+                Expr::List((0..len).map(|_| item.generate(rng)).collect())
+            }
+            Gen::Dict { key, value } => {
+                let len = rng.gen_range(0, MAX_DICT_LEN + 1);
+                Expr::Dict(
+                    (0..len)
+                        .map(|_| (key.generate(rng), value.generate(rng)))
+                        .collect(),
+                )
+            }
+            Gen::OneOf(gens) => gens[rng.gen_range(0, gens.len())].generate(rng),
+            Gen::Const(value) => value.clone(),
+        }
+    }
+
+    /// Whether `value` is something this generator could have made.
+    fn fits(&self, value: &Expr) -> bool {
+        match (self, value) {
+            (Gen::Int(lo, hi), _) => get_i64(value).is_ok_and(|n| *lo <= n && n <= *hi),
+            (Gen::String { min, max, charset }, Expr::String(s)) => {
+                let len = s.chars().count();
+                *min <= len && len <= *max && s.chars().all(|c| charset.contains(&c))
+            }
+            (Gen::List { item, min, max }, Expr::List(l)) => {
+                *min <= l.len() && l.len() <= *max && l.iter().all(|v| item.fits(v))
+            }
+            (Gen::Dict { key, value }, Expr::Dict(d)) => {
+                d.iter().all(|(k, v)| key.fits(k) && value.fits(v))
+            }
+            (Gen::OneOf(gens), _) => gens.iter().any(|g| g.fits(value)),
+            (Gen::Const(c), _) => c == value,
+            _ => false,
+        }
+    }
+
+    /// Simpler values than `value`, simplest first.
+    fn shrink(&self, value: &Expr) -> Vec<Expr> {
+        match (self, value) {
+            (Gen::Int(lo, hi), _) => {
+                let n = match get_i64(value) {
+                    Ok(n) => n as i128,
+                    Err(_) => return Vec::new(),
+                };
+                // Head toward the value closest to 0, halving the distance.
+                let target = 0.max(*lo as i128).min(*hi as i128);
+                let mut res = Vec::new();
+                let mut distance = n - target;
+                while distance != 0 {
+                    res.push(Expr::from((n - distance) as i64));
+                    distance /= 2;
+                }
+                res
+            }
+            (Gen::String { min, charset, .. }, Expr::String(s)) => {
+                let chars: Vector<char> = s.chars().collect();
+                let mut res: Vec<Expr> = shorter(&chars, *min)
+                    .into_iter()
+                    .map(|c| Expr::String(c.iter().collect()))
+                    .collect();
+                if let Some(i) = chars.iter().position(|c| *c != charset[0]) {
+                    let mut simpler = chars;
+                    simpler.set(i, charset[0]);
+                    res.push(Expr::String(simpler.iter().collect()));
+                }
+                res
+            }
+            (Gen::List { item, min, .. }, Expr::List(l)) => {
+                let mut res: Vec<Expr> = shorter(l, *min).into_iter().map(Expr::List).collect();
+                for (i, v) in l.iter().enumerate() {
+                    for simpler in item.shrink(v) {
+                        res.push(Expr::List(l.update(i, simpler)));
+                    }
+                }
+                res
+            }
+            (Gen::Dict { value: gen, .. }, Expr::Dict(d)) => {
+                let mut res: Vec<Expr> = d.keys().map(|k| Expr::Dict(d.without(k))).collect();
+                for (k, v) in d.iter() {
+                    for simpler in gen.shrink(v) {
+                        res.push(Expr::Dict(d.update(k.clone(), simpler)));
+                    }
+                }
+                res
+            }
+            (Gen::OneOf(gens), _) => {
+                // Earlier choices are simpler.
+                let mut res: Vec<Expr> = gens
+                    .iter()
+                    .take_while(|g| !g.fits(value))
+                    .filter_map(|g| match g {
+                        Gen::Const(c) => Some(c.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                if let Some(gen) = gens.iter().find(|g| g.fits(value)) {
+                    res.extend(gen.shrink(value));
+                }
+                res
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct GenRecord {
+    gen: Gen,
+    id: u64,
+}
+
+fn gen_record(gen: Gen) -> LispResult<Expr> {
+    record!(GenRecord {
+        gen,
+        id: next_object_id()
+    })
+}
+
+impl Record for GenRecord {
+    fn call_method(&self, sym: &str, _args: Vector<Expr>) -> LispResult<Expr> {
+        unknown_method!(self, sym)
+    }
+
+    fn type_name(&self) -> &'static str {
+        "Gen"
+    }
+
+    fn display(&self) -> String {
+        let kind = match &self.gen {
+            Gen::Int(lo, hi) => format!("int {} {}", lo, hi),
+            Gen::String { min, max, .. } => format!("string {} {}", min, max),
+            Gen::List { min, max, .. } => format!("list {} {}", min, max),
+            Gen::Dict { .. } => "dict".into(),
+            Gen::OneOf(gens) => format!("one-of {}", gens.len()),
+            Gen::Const(value) => format!("{:?}", value),
+        };
+        format!("Gen<{}>", kind)
+    }
+
+    fn debug(&self) -> String {
+        self.display()
+    }
+
+    fn clone(&self) -> RecordType {
+        Box::new(Clone::clone(self))
+    }
+
+    fn methods(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn as_any(&self) -> Option<&dyn std::any::Any> {
+        Some(self)
+    }
+}
+
+impl RecordDoc for GenRecord {
+    fn name() -> &'static str {
+        "Gen"
+    }
+
+    fn type_doc() -> &'static str {
+        "Makes random values for for-all. See gen-int, gen-string, gen-list, gen-dict, and gen-one-of."
+    }
+
+    fn method_doc() -> &'static [(&'static str, &'static str)] {
+        &[]
+    }
+}
+
+/// (gen-int lo hi)
+pub(crate) fn gen_int(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let (lo, hi) = (get_i64(&exprs[0])?, get_i64(&exprs[1])?);
+    ensure!(
+        lo <= hi,
+        "gen-int needs lo <= hi, but was given {} {}",
+        lo,
+        hi
+    );
+    gen_record(Gen::Int(lo, hi))
+}
+
+/// (gen-string len-range charset)
+pub(crate) fn gen_string(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let (min, max) = len_range("gen-string", &exprs[0])?;
+    let charset: Vec<char> = exprs[1].get_string()?.chars().collect();
+    ensure!(
+        !charset.is_empty(),
+        "gen-string needs at least one character"
+    );
+    gen_record(Gen::String { min, max, charset })
+}
+
+/// (gen-list gen len-range)
+pub(crate) fn gen_list(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let item = Box::new(gen_of(&exprs[0])?);
+    let (min, max) = len_range("gen-list", &exprs[1])?;
+    gen_record(Gen::List { item, min, max })
+}
+
+/// (gen-dict keygen valgen)
+pub(crate) fn gen_dict(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    gen_record(Gen::Dict {
+        key: Box::new(gen_of(&exprs[0])?),
+        value: Box::new(gen_of(&exprs[1])?),
+    })
+}
+
+/// (gen-one-of gens). Values which aren't generators are picked as they are.
+pub(crate) fn gen_one_of(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let gens = exprs[0]
+        .get_list()?
+        .iter()
+        .map(|e| gen_of(e).or_else(|_| Ok(Gen::Const(e.clone()))))
+        .collect::<LispResult<Vec<_>>>()?;
+    ensure!(!gens.is_empty(), "gen-one-of needs at least one choice");
+    gen_record(Gen::OneOf(gens))
+}
+
+/// Why `args` fail the property, or None if they pass. Interrupts and
+/// running out of resources aren't failures of the property, so they stop
+/// the run.
+fn check(f: &Expr, args: &Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Option<String>> {
+    match symbol_table.call_function_value(f, args.clone()) {
+        Ok(Expr::Bool(true)) => Ok(None),
+        Ok(Expr::Bool(false)) => Ok(Some("returned false".into())),
+        Ok(other) => bail!(
+            "for-all's property must return a bool, but returned {} for {}",
+            other,
+            Expr::List(args.clone())
+        ),
+        Err(e) if stops_run(&e) => Err(e),
+        Err(e) => Ok(Some(format!("errored, {}", e.root_cause()))),
+    }
+}
+
+fn stops_run(err: &anyhow::Error) -> bool {
+    let kind = error_kind(err);
+    kind == ProgramError::Interrupted.kind() || kind == ProgramError::Resource.kind()
+}
+
+/// The simplest failing case reachable from `args`, and why it fails.
+fn shrink(
+    gens: &[Gen],
+    mut args: Vector<Expr>,
+    mut failure: String,
+    f: &Expr,
+    symbol_table: &SymbolTable,
+) -> LispResult<(Vector<Expr>, String)> {
+    let mut steps = 0;
+    'search: while steps < MAX_SHRINKS {
+        for (i, gen) in gens.iter().enumerate() {
+            for candidate in gen.shrink(&args[i]) {
+                let simpler = args.update(i, candidate);
+                if let Some(why) = check(f, &simpler, symbol_table)? {
+                    args = simpler;
+                    failure = why;
+                    steps += 1;
+                    continue 'search;
+                }
+            }
+        }
+        break;
+    }
+    Ok((args, failure))
+}
+
+/// (for-all (gens...) n f [:seed s])
+pub(crate) fn for_all(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 3, 5);
+    let gens = match &exprs[0] {
+        Expr::List(gens) => gens
+            .iter()
+            .map(|g| gen_of(&g.eval(symbol_table)?))
+            .collect::<LispResult<Vec<_>>>()?,
+        other => bail!(
+            "for-all needs a list of generators like (g1 g2), but was given {}",
+            other
+        ),
+    };
+    let cases = exprs[1].eval(symbol_table)?.get_usize()?;
+    let f = exprs[2].eval(symbol_table)?;
+    let seed = if exprs.len() == 5 {
+        ensure!(
+            exprs[3].symbol_matches(":seed"),
+            "for-all's only option is :seed, but was given {}",
+            exprs[3]
+        );
+        exprs[4]
+            .eval(symbol_table)?
+            .get_num()?
+            .to_u64()
+            .ok_or_else(|| anyhow!("for-all's :seed must be a non-negative integer"))?
+    } else {
+        symbol_table.host_mut().rng().gen()
+    };
+    let mut rng = StdRng::seed_from_u64(seed);
+    for case in 0..cases {
+        let args: Vector<Expr> = gens.iter().map(|g| g.generate(&mut rng)).collect();
+        if let Some(failure) = check(&f, &args, symbol_table)? {
+            let (smallest, failure) = shrink(&gens, args, failure, &f, symbol_table)?;
+            bail!(
+                "for-all failed after {} passing case(s). Smallest failing case: {}, which {}. Replay with :seed {}",
+                case,
+                Expr::List(smallest),
+                failure,
+                seed
+            );
+        }
+    }
+    Ok(Expr::Bool(true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Options;
+    use crate::parser::read;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use std::sync::atomic::Ordering;
+
+    fn eval(prog: &str) -> LispResult<Expr> {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let mut res = Expr::Nil;
+        for expr in read(prog) {
+            res = expr?.eval(&syms)?;
+        }
+        Ok(res)
+    }
+
+    fn counterexample(prog: &str) -> String {
+        let err = eval(prog).unwrap_err();
+        let message = err.root_cause().to_string();
+        let start = message.find("case: ").unwrap() + "case: ".len();
+        let end = message.find(", which").unwrap();
+        message[start..end].to_string()
+    }
+
+    #[test]
+    fn passing_properties() {
+        let prog = "(for-all ((gen-int -5 5) (gen-int -5 5)) 100 (fn (a b) (= (+ a b) (+ b a))))";
+        assert_eq!(eval(prog).unwrap(), Expr::Bool(true));
+        let prog = "(for-all ((gen-string '(0 5) \"ab\")) 50 (fn (s) (<= (len s) 5)))";
+        assert_eq!(eval(prog).unwrap(), Expr::Bool(true));
+        let prog = "(for-all ((gen-dict (gen-one-of '(:a :b :c)) (gen-int 0 9))) 50
+                      (fn (d) (<= (len d) 3)))";
+        assert_eq!(eval(prog).unwrap(), Expr::Bool(true));
+    }
+
+    #[test]
+    fn failures_shrink_deterministically() {
+        // The same seed finds and shrinks to the same case every time.
+        let prog = "(random-seed 7)
+                    (for-all ((gen-int 0 1000)) 100 (fn (x) (< x 50)))";
+        assert_eq!(counterexample(prog), "(50)");
+        assert_eq!(counterexample(prog), "(50)");
+
+        let prog = "(random-seed 7)
+                    (for-all ((gen-list (gen-int 0 100) '(0 10))) 100 (fn (l) (< (len l) 3)))";
+        assert_eq!(counterexample(prog), "((0 0 0))");
+
+        let prog = "(random-seed 7)
+                    (for-all ((gen-string '(1 10) \"abc\")) 100 (fn (s) (< (len s) 4)))";
+        assert_eq!(counterexample(prog), "(\"aaaa\")");
+
+        // Errors are failures too.
+        let prog = "(random-seed 7)
+                    (for-all ((gen-int -100 100) (gen-int 1 100)) 100 (fn (a b) (if (> a b) (err \"too big\") true)))";
+        let err = eval(prog).unwrap_err().root_cause().to_string();
+        assert!(
+            err.contains("case: (2 1), which errored, too big"),
+            "{}",
+            err
+        );
+
+        // Generated lists are passed as values, not called.
+        let prog = "(for-all ((gen-list (gen-int 0 9) '(1 3))) 20 (fn (l) (int? (first l))))";
+        assert_eq!(eval(prog).unwrap(), Expr::Bool(true));
+
+        // Failures can be replayed from their seed.
+        let err = eval("(for-all ((gen-int 0 1000)) 100 (fn (x) (< x 50)))").unwrap_err();
+        let message = err.root_cause().to_string();
+        let seed = message.rsplit(' ').next().unwrap();
+        let replay = format!(
+            "(for-all ((gen-int 0 1000)) 100 (fn (x) (< x 50)) :seed {})",
+            seed
+        );
+        assert_eq!(eval(&replay).unwrap_err().root_cause().to_string(), message);
+    }
+
+    #[test]
+    fn interrupts_stop_the_run() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.add_function("interrupt", 0, "Interrupt the interpreter.", |_, syms| {
+            syms.interrupt_handle().store(true, Ordering::SeqCst);
+            Ok(Expr::Bool(true))
+        });
+        let err = syms
+            .eval_source("(for-all ((gen-int 0 9)) 10 (fn (x) (interrupt)))")
+            .unwrap_err();
+        assert_eq!(error_kind(&err), "Interrupted");
+        assert!(
+            !format!("{:#}", err).contains("for-all failed"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn resource_errors_stop_the_run() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.set_max_value_bytes(Some(1 << 10));
+        let err = syms
+            .eval_source("(for-all ((gen-int 0 9)) 10 (fn (x) (= (* \"a\" 2000) \"\")))")
+            .unwrap_err();
+        assert_eq!(error_kind(&err), "Resource");
+        assert!(!format!("{:#}", err).contains("for-all failed"));
+    }
+}
@@ -0,0 +1,197 @@
+use crate::exact_len;
+use crate::records::{Record, RecordDoc, RecordType};
+use crate::symbols::{next_object_id, Expr, LispResult, ProgramError, SymbolTable};
+use crate::{record, unknown_method};
+use anyhow::anyhow;
+use core::cell::RefCell;
+use im::Vector;
+use parking_lot::Mutex;
+use std::any::Any;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+thread_local! {
+    // Atoms this thread is inside of, by what it's doing with them. Meeting
+    // one again means it (indirectly) contains itself: printing shows it as
+    // #<cycle>, and comparing, copying and serializing are :cyclic errors.
+    static VISITING: RefCell<HashSet<(Walk, u64)>> = RefCell::new(HashSet::new());
+}
+
+/// What's walking into an atom's value. Each is its own traversal, so the
+/// two sides of a comparison are too: an atom met on both sides isn't a cycle.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Walk {
+    Print,
+    CompareLeft,
+    CompareRight,
+    Copy,
+    #[cfg(feature = "json")]
+    Serialize,
+}
+
+/// Marks the walk as out of the atom when dropped, even by a panic, so a
+/// caught panic doesn't leave the atom looking like a cycle.
+struct Leaving((Walk, u64));
+
+impl Drop for Leaving {
+    fn drop(&mut self) {
+        VISITING.with(|visiting| visiting.borrow_mut().remove(&self.0));
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct AtomRecord {
+    value: Arc<Mutex<Expr>>,
+    id: u64,
+}
+
+impl AtomRecord {
+    pub(crate) fn from_x7(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+        exact_len!(exprs, 1);
+        let value = Arc::new(Mutex::new(exprs[0].clone()));
+        symbol_table.resources_mut().register_atom(&value);
+        record!(AtomRecord {
+            value,
+            id: next_object_id()
+        })
+    }
+
+    pub(crate) fn from_expr(expr: &Expr) -> Option<&AtomRecord> {
+        match expr.unmeta() {
+            Expr::Record(r) => r.as_any()?.downcast_ref(),
+            _ => None,
+        }
+    }
+
+    /// Call `f` with the atom's value, or return None if `walk` is already
+    /// inside this atom.
+    pub(crate) fn visit<T>(&self, walk: Walk, f: impl FnOnce(&Expr) -> T) -> Option<T> {
+        let first_visit = VISITING.with(|visiting| visiting.borrow_mut().insert((walk, self.id)));
+        if !first_visit {
+            return None;
+        }
+        let _leaving = Leaving((walk, self.id));
+        // Clone first so we don't hold the lock while visiting ourselves.
+        let value = self.value.lock().clone();
+        Some(f(&value))
+    }
+
+    /// Like `visit`, but a cycle is a :cyclic error saying what `doing` failed.
+    pub(crate) fn visit_acyclic<T>(
+        &self,
+        walk: Walk,
+        doing: &str,
+        f: impl FnOnce(&Expr) -> LispResult<T>,
+    ) -> LispResult<T> {
+        self.visit(walk, f).unwrap_or_else(|| {
+            Err(anyhow!(ProgramError::Cyclic)
+                .context(format!("Cannot {} an atom which contains itself", doing)))
+        })
+    }
+
+    fn deref(&self, args: Vector<Expr>) -> LispResult<Expr> {
+        exact_len!(args, 0);
+        Ok(self.value.lock().clone())
+    }
+
+    fn reset(&self, args: Vector<Expr>) -> LispResult<Expr> {
+        exact_len!(args, 1);
+        *self.value.lock() = args[0].clone();
+        Ok(args[0].clone())
+    }
+}
+
+impl Record for AtomRecord {
+    fn call_method(&self, sym: &str, args: Vector<Expr>) -> LispResult<Expr> {
+        match sym {
+            "deref" => self.deref(args),
+            "reset" => self.reset(args),
+            _ => unknown_method!(self, sym),
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        "AtomRecord"
+    }
+
+    fn display(&self) -> String {
+        self.visit(Walk::Print, |value| format!("Atom<{:?}>", value))
+            .unwrap_or_else(|| "#<cycle>".into())
+    }
+
+    fn debug(&self) -> String {
+        self.display()
+    }
+
+    fn clone(&self) -> RecordType {
+        Box::new(Clone::clone(self))
+    }
+
+    fn methods(&self) -> Vec<&'static str> {
+        AtomRecord::method_doc().iter().map(|(l, _)| *l).collect()
+    }
+
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
+}
+
+impl RecordDoc for AtomRecord {
+    fn name() -> &'static str {
+        "AtomRecord"
+    }
+
+    fn type_doc() -> &'static str {
+        "A mutable reference to a value.
+Example:
+(def counter (atom 0))
+(.reset counter (inc (.deref counter)))
+(.deref counter) ; 1
+"
+    }
+
+    fn method_doc() -> &'static [(&'static str, &'static str)] {
+        &[
+            (
+                "deref",
+                "Get the value inside the atom.
+Example:
+(def a (atom 1))
+(.deref a) ; 1
+",
+            ),
+            (
+                "reset",
+                "Replace the value inside the atom, returning the new value.
+Example:
+(def a (atom 1))
+(.reset a 2) ; 2
+",
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panics_while_visiting_leave_the_atom() {
+        let atom = AtomRecord {
+            value: Arc::new(Mutex::new(Expr::Nil)),
+            id: next_object_id(),
+        };
+        let visit = || atom.visit(Walk::Print, |_| panic!("boom"));
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(visit));
+        assert!(res.is_err());
+        assert_eq!(
+            atom.visit(Walk::Print, |value| value.clone()),
+            Some(Expr::Nil)
+        );
+    }
+}
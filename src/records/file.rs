@@ -1,10 +1,11 @@
+use crate::access;
 use crate::exact_len;
-use crate::records::{Record, RecordDoc, RecordType};
-use crate::symbols::{Expr, LispResult, SymbolTable};
+use crate::records::{closed_error, Record, RecordDoc, RecordType};
+use crate::symbols::{next_object_id, Expr, LispResult, ProgramError, SymbolTable};
 use crate::{num, record, unknown_method};
 use anyhow::anyhow;
 use im::Vector;
-use parking_lot::Mutex;
+use parking_lot::{MappedMutexGuard, Mutex, MutexGuard};
 use std::fs;
 use std::fs::OpenOptions;
 use std::io::{Read, Seek, Write};
@@ -26,57 +27,100 @@ macro_rules! write_file {
     };
 }
 
+/// A file handle, shared by clones of the record. `None` once closed.
+pub(crate) type FileHandle = Arc<Mutex<Option<fs::File>>>;
+
 #[derive(Clone, Debug)]
 pub(crate) struct FileRecord {
     path: String,
-    file: Arc<Mutex<std::fs::File>>,
+    file: FileHandle,
+    // Each open file handle is its own object, even when paths are shared.
+    id: u64,
+    // The interpreter's max_value_bytes when the file was opened.
+    max_read_bytes: Option<usize>,
 }
 
 impl FileRecord {
-    pub(crate) fn from_x7(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    pub(crate) fn from_x7(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
         exact_len!(exprs, 1);
         let path = exprs[0].get_string()?;
-        FileRecord::open_file(path)
+        let file = FileRecord::open_file(path, symbol_table)?;
+        symbol_table
+            .resources_mut()
+            .register(file.path.clone(), file.downgrade());
+        record!(file)
     }
 
-    fn new(f: fs::File, path: String) -> FileRecord {
+    fn new(f: fs::File, path: String, max_read_bytes: Option<usize>) -> FileRecord {
         FileRecord {
-            file: Arc::new(Mutex::new(f)),
+            file: Arc::new(Mutex::new(Some(f))),
             path,
+            id: next_object_id(),
+            max_read_bytes,
         }
     }
 
-    pub(crate) fn open_file(path: String) -> LispResult<Expr> {
+    fn open_file(path: String, symbol_table: &SymbolTable) -> LispResult<FileRecord> {
         // TODO: Allow access to OpenOptions in x7.
         // Open the file with liberal permissions.
-        let f = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .read(true)
-            .open(path.clone())
-            .map_err(|e| anyhow!("Could not open file \"{}\" because {}", &path, e))?;
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).read(true);
+        let f = access::open(symbol_table, "fs::open", &path, &options)?;
         // Make the path pretty.
         let abs_path = fs::canonicalize(path)
             .map_err(|e| anyhow!("Could not canonicalize path! {}", e))?
             .to_str()
             .ok_or_else(|| anyhow!("Could not represent path as UTF-8 string"))?
             .into();
-        record!(FileRecord::new(f, abs_path))
+        let max_read_bytes = symbol_table.host().max_value_bytes();
+        Ok(FileRecord::new(f, abs_path, max_read_bytes))
     }
 
-    fn read_all(&self) -> LispResult<String> {
-        let mut buf = String::new();
-        let mut guard = self.file.lock();
-        guard
-            .read_to_string(&mut buf)
+    /// The record, for as long as the file handle is alive, without keeping it alive.
+    fn downgrade(&self) -> impl Fn() -> Option<RecordType> + Send + Sync {
+        let file = Arc::downgrade(&self.file);
+        let (path, id, max_read_bytes) = (self.path.clone(), self.id, self.max_read_bytes);
+        move || {
+            let file = file.upgrade()?;
+            Some(Box::new(FileRecord {
+                path: path.clone(),
+                file,
+                id,
+                max_read_bytes,
+            }) as RecordType)
+        }
+    }
+
+    fn lock(&self, method: &str) -> LispResult<MappedMutexGuard<'_, fs::File>> {
+        MutexGuard::try_map(self.file.lock(), |f| f.as_mut())
+            .map_err(|_| closed_error(self, method))
+    }
+
+    fn read_all(&self, method: &str) -> LispResult<String> {
+        let mut buf = Vec::new();
+        let mut guard = self.lock(method)?;
+        // Read one byte past the limit, rather than trusting the file's
+        // length, which devices and pipes don't have.
+        let limit = self.max_read_bytes.map_or(u64::MAX, |max| max as u64 + 1);
+        (&mut *guard)
+            .take(limit)
+            .read_to_end(&mut buf)
             .map_err(|e| anyhow!("Failed to read to string {}", e))?;
         rewind_file!(guard);
-        Ok(buf)
+        if let Some(max) = self.max_read_bytes {
+            if buf.len() > max {
+                return Err(anyhow!(ProgramError::Resource).context(format!(
+                    "Reading {} would make a value of over {} bytes, the limit",
+                    self.path, max
+                )));
+            }
+        }
+        String::from_utf8(buf).map_err(|e| anyhow!("Failed to read to string {}", e))
     }
 
     fn read_to_string(&self, args: Vector<Expr>) -> LispResult<Expr> {
         exact_len!(args, 0);
-        self.read_all().map(Expr::String)
+        self.read_all("read_to_string").map(Expr::String)
     }
 
     fn try_shrink(&self, file: &mut std::fs::File) -> LispResult<()> {
@@ -95,7 +139,7 @@ impl FileRecord {
         exact_len!(args, 1);
         let content = args[0].get_string()?;
         let content_len = num!(content.len());
-        let mut guard = self.file.lock();
+        let mut guard = self.lock("write")?;
         // Set the length to 0.
         self.try_shrink(&mut guard)?;
         // Write the string
@@ -110,7 +154,7 @@ impl FileRecord {
     }
 
     fn read_lines(&self) -> LispResult<Expr> {
-        let contents = self.read_all()?;
+        let contents = self.read_all("read_lines")?;
         let split: im::Vector<Expr> = contents
             .split('\n')
             .map(|s| Expr::String(s.into()))
@@ -118,9 +162,9 @@ impl FileRecord {
         Ok(Expr::List(split))
     }
 
-    fn append(&self, content: &str) -> LispResult<Expr> {
+    fn append(&self, method: &str, content: &str) -> LispResult<Expr> {
         let content_len = num!(content.len());
-        let mut guard = self.file.lock();
+        let mut guard = self.lock(method)?;
 
         guard
             .seek(std::io::SeekFrom::End(0))
@@ -133,24 +177,13 @@ impl FileRecord {
     fn append_to_file(&self, args: Vector<Expr>) -> LispResult<Expr> {
         exact_len!(args, 1);
         let content = args[0].get_string()?;
-        self.append(&content)
+        self.append("append_to_file", &content)
     }
 
     fn append_line(&self, args: Vector<Expr>) -> LispResult<Expr> {
         exact_len!(args, 1);
         let content = args[0].get_string()?;
-        self.append(&format!("\n{}", content))
-    }
-}
-
-macro_rules! try_call_method {
-    ($self:expr, $sym:expr, $args:expr, $($method_name:ident),*) => {
-        match $sym {
-            $(
-                stringify!($method_name) => $self.$method_name($args),
-            )*
-            _ => unknown_method!($self, $sym)
-        }
+        self.append("append_line", &format!("\n{}", content))
     }
 }
 
@@ -162,6 +195,14 @@ impl Record for FileRecord {
             "write" => self.write(args),
             "append_to_file" => self.append_to_file(args),
             "append_line" => self.append_line(args),
+            "close" => {
+                exact_len!(args, 0);
+                self.close()
+            }
+            "closed?" => {
+                exact_len!(args, 0);
+                Ok(Expr::Bool(self.is_closed()))
+            }
             _ => unknown_method!(self, sym),
         }
     }
@@ -187,11 +228,15 @@ impl Record for FileRecord {
     }
 
     fn id(&self) -> u64 {
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
-        let mut h = DefaultHasher::new();
-        self.path.hash(&mut h);
-        h.finish()
+        self.id
+    }
+
+    fn close(&self) -> LispResult<Expr> {
+        Ok(Expr::Bool(self.file.lock().take().is_some()))
+    }
+
+    fn is_closed(&self) -> bool {
+        self.file.lock().is_none()
     }
 }
 
@@ -254,6 +299,23 @@ Example:
 Example:
 (def new-file (fs::open \"new_file.txt\"))
 (.append_line \"Hello world!\") ; file contains '...old-contents...Hello world!\n'
+",
+            ),
+            (
+                "close",
+                "Close the file. Returns false if it was already closed.
+Other methods fail with a :closed error once the file is closed.
+Example:
+(def my-file (fs::open \"my_file.txt\"))
+(.close my-file) ; true
+",
+            ),
+            (
+                "closed?",
+                "Whether the file has been closed.
+Example:
+(def my-file (fs::open \"my_file.txt\"))
+(.closed? my-file) ; false
 ",
             ),
         ]
@@ -0,0 +1,590 @@
+use crate::access;
+use crate::canonical::decode;
+use crate::exact_len;
+use crate::records::{closed_error, Record, RecordDoc, RecordType};
+use crate::symbols::{next_object_id, Expr, LispResult, ProgramError, SymbolTable};
+use crate::unknown_method;
+use anyhow::{anyhow, bail, ensure, Context};
+use im::Vector;
+use parking_lot::{MappedMutexGuard, Mutex, MutexGuard};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+// A key-value store kept in a file, from (kv-open path).
+//
+// The file is a log of changes, each a frame of
+//
+//   [payload length: u64 BE][first 8 bytes of the payload's sha256][payload]
+//
+// whose payload is the canonical encoding of (key value) for a put, or
+// (key) for a delete. Changes are appended and synced before they take
+// effect, so a crash mid-write leaves at most one partial frame at the end,
+// which opening ignores. Opening also compacts the log down to one put per
+// key, replacing the file atomically.
+//
+// While a store is open it holds an OS lock on a `.lock` file next to it, so
+// opening the same store twice, from this process or another, is an error.
+// The lock goes with the process, so a crash doesn't leave the store locked.
+//
+// A write which fails part way is cut off again, so later changes aren't
+// appended after a damaged frame. If even that fails, the store refuses
+// further changes until it's reopened.
+
+const HEADER_LEN: usize = 16;
+
+fn checksum(payload: &[u8]) -> [u8; 8] {
+    let mut sum = [0; 8];
+    sum.copy_from_slice(&Sha256::digest(payload)[..8]);
+    sum
+}
+
+fn push_frame(payload: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    out.extend_from_slice(&checksum(payload));
+    out.extend_from_slice(payload);
+}
+
+/// Replay the frames of `log` into entries. A partial or damaged frame is
+/// only allowed at the end, where a crash mid-write leaves one.
+fn replay(path: &str, log: &[u8]) -> LispResult<BTreeMap<String, Expr>> {
+    let mut entries = BTreeMap::new();
+    let mut at = 0;
+    while log.len() - at >= HEADER_LEN {
+        let mut len = [0; 8];
+        len.copy_from_slice(&log[at..at + 8]);
+        let len = u64::from_be_bytes(len) as usize;
+        let body = at + HEADER_LEN;
+        if log.len() - body < len {
+            break;
+        }
+        let payload = &log[body..body + len];
+        if checksum(payload) != log[at + 8..body] {
+            if body + len == log.len() {
+                break;
+            }
+            bail!(
+                "The key-value store {} is damaged at byte {}, before its last change",
+                path,
+                at
+            );
+        }
+        let change = decode(payload)
+            .and_then(|change| change.get_list())
+            .with_context(|| {
+                format!(
+                    "The key-value store {} has a bad change at byte {}",
+                    path, at
+                )
+            })?;
+        ensure!(
+            change.len() == 1 || change.len() == 2,
+            "The key-value store {} has a bad change at byte {}: {:?}",
+            path,
+            at,
+            change
+        );
+        let key = change[0].get_string()?;
+        match change.get(1) {
+            Some(value) => entries.insert(key, value.clone()),
+            None => entries.remove(&key),
+        };
+        at = body + len;
+    }
+    Ok(entries)
+}
+
+fn io_err(message: String, e: std::io::Error) -> anyhow::Error {
+    anyhow!(ProgramError::Io).context(format!("{}, {}", message, e))
+}
+
+/// Lock the `.lock` file of the store at `path`, until the store closes.
+fn lock_store(path: &str, symbol_table: &SymbolTable) -> LispResult<access::LockFile> {
+    let lock_path = format!("{}.lock", path);
+    access::lock_file(symbol_table, "kv-open", &lock_path)?.ok_or_else(|| {
+        anyhow!(ProgramError::Io).context(format!(
+            "The key-value store {} is already open, here or in another process",
+            path
+        ))
+    })
+}
+
+struct Store {
+    log: File,
+    entries: BTreeMap<String, Expr>,
+    // Set when a failed write couldn't be cut off again.
+    damaged: bool,
+    _lock: access::LockFile,
+}
+
+#[derive(Clone)]
+pub(crate) struct KvRecord {
+    path: String,
+    // Shared by clones of the record. `None` once closed.
+    store: Arc<Mutex<Option<Store>>>,
+    id: u64,
+}
+
+impl KvRecord {
+    pub(crate) fn from_x7(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+        exact_len!(exprs, 1);
+        let path = exprs[0].get_string()?;
+        let kv = KvRecord::open(&path, symbol_table)?;
+        symbol_table
+            .resources_mut()
+            .register(kv.path.clone(), kv.downgrade());
+        Ok(Expr::Record(Box::new(kv)))
+    }
+
+    fn open(path: &str, symbol_table: &SymbolTable) -> LispResult<KvRecord> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true);
+        let mut file = access::open(symbol_table, "kv-open", path, &options)?;
+        let abs_path = access::canonicalize(symbol_table, "kv-open", path)?
+            .to_str()
+            .ok_or_else(|| anyhow!("Could not represent path as UTF-8 string"))?
+            .to_string();
+
+        let lock = lock_store(&abs_path, symbol_table)?;
+        let mut log = Vec::new();
+        file.read_to_end(&mut log)
+            .map_err(|e| io_err(format!("Could not read {}", abs_path), e))?;
+        drop(file);
+        let entries = replay(&abs_path, &log)?;
+
+        let mut compacted = Vec::new();
+        for (key, value) in entries.iter() {
+            let change = Expr::List(im::vector![Expr::String(key.clone()), value.clone()]);
+            push_frame(&change.canonical_bytes()?, &mut compacted);
+        }
+        if compacted.len() < log.len() {
+            access::write_atomic(symbol_table, "kv-open", &abs_path, &compacted, true)?;
+        }
+        let log = access::open(
+            symbol_table,
+            "kv-open",
+            &abs_path,
+            OpenOptions::new().append(true),
+        )?;
+        let store = Store {
+            log,
+            entries,
+            damaged: false,
+            _lock: lock,
+        };
+
+        Ok(KvRecord {
+            path: abs_path,
+            store: Arc::new(Mutex::new(Some(store))),
+            id: next_object_id(),
+        })
+    }
+
+    /// The record, for as long as the store is alive, without keeping it alive.
+    fn downgrade(&self) -> impl Fn() -> Option<RecordType> + Send + Sync {
+        let store = Arc::downgrade(&self.store);
+        let (path, id) = (self.path.clone(), self.id);
+        move || {
+            Some(Box::new(KvRecord {
+                path: path.clone(),
+                store: store.upgrade()?,
+                id,
+            }) as RecordType)
+        }
+    }
+
+    fn lock(&self, method: &str) -> LispResult<MappedMutexGuard<'_, Store>> {
+        MutexGuard::try_map(self.store.lock(), |store| store.as_mut())
+            .map_err(|_| closed_error(self, method))
+    }
+
+    /// Append `change` to the log, and sync it, before it takes effect.
+    fn append(&self, store: &mut Store, change: Vector<Expr>) -> LispResult<()> {
+        ensure!(
+            !store.damaged,
+            "The key-value store {} has a partial change which couldn't be removed, reopen it",
+            self.path
+        );
+        let mut frame = Vec::new();
+        push_frame(&Expr::List(change).canonical_bytes()?, &mut frame);
+        let len = store
+            .log
+            .metadata()
+            .map_err(|e| io_err(format!("Could not read {}", self.path), e))?
+            .len();
+        let written = store
+            .log
+            .write_all(&frame)
+            .and_then(|_| store.log.sync_data());
+        if let Err(e) = written {
+            if store.log.set_len(len).is_err() {
+                store.damaged = true;
+            }
+            return Err(io_err(format!("Could not write to {}", self.path), e));
+        }
+        Ok(())
+    }
+
+    fn get(&self, args: Vector<Expr>) -> LispResult<Expr> {
+        exact_len!(args, 1, 2);
+        let key = args[0].get_string()?;
+        let store = self.lock("get")?;
+        Ok(store
+            .entries
+            .get(&key)
+            .or_else(|| args.get(1))
+            .cloned()
+            .unwrap_or(Expr::Nil))
+    }
+
+    fn put(&self, args: Vector<Expr>) -> LispResult<Expr> {
+        exact_len!(args, 2);
+        let key = args[0].get_string()?;
+        let value = args[1].clone().without_meta();
+        let mut store = self.lock("put")?;
+        self.append(
+            &mut store,
+            im::vector![Expr::String(key.clone()), value.clone()],
+        )?;
+        store.entries.insert(key, value.clone());
+        Ok(value)
+    }
+
+    fn delete(&self, args: Vector<Expr>) -> LispResult<Expr> {
+        exact_len!(args, 1);
+        let key = args[0].get_string()?;
+        let mut store = self.lock("delete")?;
+        if !store.entries.contains_key(&key) {
+            return Ok(Expr::Bool(false));
+        }
+        self.append(&mut store, im::vector![Expr::String(key.clone())])?;
+        store.entries.remove(&key);
+        Ok(Expr::Bool(true))
+    }
+
+    fn keys(&self, args: Vector<Expr>) -> LispResult<Expr> {
+        exact_len!(args, 0);
+        let store = self.lock("keys")?;
+        Ok(Expr::List(
+            store.entries.keys().cloned().map(Expr::String).collect(),
+        ))
+    }
+
+    fn contains(&self, args: Vector<Expr>) -> LispResult<Expr> {
+        exact_len!(args, 1);
+        let key = args[0].get_string()?;
+        Ok(Expr::Bool(
+            self.lock("contains?")?.entries.contains_key(&key),
+        ))
+    }
+}
+
+impl Record for KvRecord {
+    fn call_method(&self, sym: &str, args: Vector<Expr>) -> LispResult<Expr> {
+        match sym {
+            "get" => self.get(args),
+            "put" => self.put(args),
+            "delete" => self.delete(args),
+            "keys" => self.keys(args),
+            "contains?" => self.contains(args),
+            "close" => {
+                exact_len!(args, 0);
+                self.close()
+            }
+            "closed?" => {
+                exact_len!(args, 0);
+                Ok(Expr::Bool(self.is_closed()))
+            }
+            _ => unknown_method!(self, sym),
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        "KvRecord"
+    }
+
+    fn display(&self) -> String {
+        format!("KvStore<{}>", self.path)
+    }
+
+    fn debug(&self) -> String {
+        self.display()
+    }
+
+    fn clone(&self) -> RecordType {
+        Box::new(Clone::clone(self))
+    }
+
+    fn methods(&self) -> Vec<&'static str> {
+        KvRecord::method_doc().iter().map(|(l, _)| *l).collect()
+    }
+
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn close(&self) -> LispResult<Expr> {
+        Ok(Expr::Bool(self.store.lock().take().is_some()))
+    }
+
+    fn is_closed(&self) -> bool {
+        self.store.lock().is_none()
+    }
+}
+
+impl RecordDoc for KvRecord {
+    fn name() -> &'static str {
+        "KvRecord"
+    }
+
+    fn type_doc() -> &'static str {
+        "A key-value store kept in a file, made with (kv-open path).
+Keys are strings, and values anything canonical-hash takes. Changes are on disk
+before the method making them returns.
+Example:
+(def store (kv-open \"state.db\"))
+(.put store \"runs\" (inc (.get store \"runs\" 0)))
+(.close store)
+"
+    }
+
+    fn method_doc() -> &'static [(&'static str, &'static str)] {
+        &[
+            (
+                "get",
+                "Get the value of a key, or the default (nil if not given) if it has none.
+Example:
+(def store (kv-open \"state.db\"))
+(.get store \"runs\" 0) ; 0
+",
+            ),
+            (
+                "put",
+                "Set the value of a key, returning the value.
+Example:
+(def store (kv-open \"state.db\"))
+(.put store \"user\" (dict :name \"ada\")) ; {:name: \"ada\"}
+",
+            ),
+            (
+                "delete",
+                "Remove a key, returning whether it had a value.
+Example:
+(def store (kv-open \"state.db\"))
+(.delete store \"user\") ; true
+",
+            ),
+            (
+                "keys",
+                "The keys with values, in order.
+Example:
+(def store (kv-open \"state.db\"))
+(.keys store) ; (\"runs\" \"user\")
+",
+            ),
+            (
+                "contains?",
+                "Whether a key has a value.
+Example:
+(def store (kv-open \"state.db\"))
+(.contains? store \"runs\") ; true
+",
+            ),
+            (
+                "close",
+                "Close the store, letting it be opened again. Returns false if it was already closed.
+Example:
+(def store (kv-open \"state.db\"))
+(.close store) ; true
+",
+            ),
+            (
+                "closed?",
+                "Whether the store has been closed.
+Example:
+(def store (kv-open \"state.db\"))
+(.closed? store) ; false
+",
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Options;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use std::io::{Seek, SeekFrom};
+
+    struct Db {
+        path: String,
+        syms: SymbolTable,
+    }
+
+    impl Db {
+        fn new() -> Db {
+            let path = std::env::temp_dir().join(format!("x7-kv-{:x}.db", rand::random::<u64>()));
+            Db {
+                path: path.to_str().unwrap().into(),
+                syms: create_stdlib_symbol_table(&Options::default()),
+            }
+        }
+
+        fn eval(&self, prog: &str) -> Expr {
+            let prog = prog.replace("PATH", &format!("{:?}", self.path));
+            self.syms.eval_source(&prog).unwrap()
+        }
+
+        fn len(&self) -> u64 {
+            std::fs::metadata(&self.path).unwrap().len()
+        }
+    }
+
+    impl Drop for Db {
+        fn drop(&mut self) {
+            if let Ok(path) = std::fs::canonicalize(&self.path) {
+                let _ = std::fs::remove_file(format!("{}.lock", path.display()));
+            }
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    #[test]
+    fn values_survive_reopening() {
+        let db = Db::new();
+        db.eval(
+            "(def s (kv-open PATH))
+             (.put s \"user\" (dict :name \"ada\" :tags '(1 2.5)))
+             (.put s \"runs\" 1)
+             (.put s \"gone\" true)
+             (.delete s \"gone\")
+             (.close s)",
+        );
+        db.eval("(def s (kv-open PATH))");
+        assert_eq!(
+            db.eval("(.get s \"user\")"),
+            db.eval("(dict :name \"ada\" :tags '(1 2.5))")
+        );
+        assert_eq!(db.eval("(.keys s)"), db.eval("(list \"runs\" \"user\")"));
+        assert_eq!(db.eval("(.contains? s \"gone\")"), Expr::Bool(false));
+        assert_eq!(db.eval("(.get s \"gone\" 0)"), Expr::from(0));
+        assert_eq!(db.eval("(.delete s \"gone\")"), Expr::Bool(false));
+        assert!(db.syms.eval_source("(.put s \"f\" inc)").is_err());
+        assert!(db.syms.eval_source("(.put s 1 2)").is_err());
+    }
+
+    #[test]
+    fn a_partial_last_change_is_ignored() {
+        let db = Db::new();
+        db.eval("(def s (kv-open PATH)) (.put s \"a\" 1) (.put s \"b\" 2) (.close s)");
+        let full = db.len();
+        db.eval("(def s (kv-open PATH)) (.put s \"c\" 3) (.close s)");
+        // Cut the last change short, as a crash mid-write would.
+        let file = OpenOptions::new().write(true).open(&db.path).unwrap();
+        file.set_len(db.len() - 3).unwrap();
+        db.eval("(def s (kv-open PATH))");
+        assert_eq!(db.eval("(.keys s)"), db.eval("(list \"a\" \"b\")"));
+        assert_eq!(db.len(), full);
+        db.eval("(.close s)");
+
+        // Damage anywhere else is an error rather than lost changes.
+        let mut file = OpenOptions::new().write(true).open(&db.path).unwrap();
+        file.seek(SeekFrom::Start(HEADER_LEN as u64)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+        let err = db
+            .syms
+            .eval_source(&format!("(kv-open {:?})", db.path))
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("is damaged at byte 0"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn opening_compacts_the_log() {
+        let db = Db::new();
+        db.eval("(def s (kv-open PATH)) (dotimes (i 20) (.put s \"n\" i)) (.close s)");
+        let before = db.len();
+        db.eval("(def s (kv-open PATH))");
+        assert!(db.len() * 10 < before, "{} vs {}", db.len(), before);
+        assert_eq!(db.eval("(.get s \"n\")"), Expr::from(19));
+    }
+
+    #[test]
+    fn a_store_is_only_open_once() {
+        let db = Db::new();
+        db.eval("(def s (kv-open PATH))");
+        let err = db
+            .syms
+            .eval_source(&format!("(kv-open {:?})", db.path))
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("is already open"),
+            "{:#}",
+            err
+        );
+        db.eval("(.close s) (def s (kv-open PATH)) (.close s)");
+        // Dropping a store without closing it releases it too.
+        drop(KvRecord::open(&db.path, &db.syms).unwrap());
+        drop(KvRecord::open(&db.path, &db.syms).unwrap());
+        // The lock file stays behind, but it's the OS lock which counts, so
+        // one left by a crash doesn't keep the store locked.
+        let lock = format!(
+            "{}.lock",
+            std::fs::canonicalize(&db.path).unwrap().display()
+        );
+        assert!(std::path::Path::new(&lock).exists());
+        db.eval("(def t (kv-open PATH)) (.close t)");
+        let closed = db.syms.eval_source("(.get s \"a\")").unwrap_err();
+        assert!(
+            format!("{:#}", closed).contains("is closed"),
+            "{:#}",
+            closed
+        );
+    }
+
+    #[test]
+    fn locks_go_through_the_access_hook() {
+        use crate::access::{Decision, IoKind};
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        let db = Db::new();
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let log = seen.clone();
+        let lock = format!(
+            "{}.lock",
+            std::env::temp_dir()
+                .canonicalize()
+                .unwrap()
+                .join(std::path::Path::new(&db.path).file_name().unwrap())
+                .display()
+        );
+        let denied = lock.clone();
+        db.syms.on_io_access(move |op| {
+            log.borrow_mut().push(op.clone());
+            if op.target == denied {
+                Decision::Deny
+            } else {
+                Decision::Allow
+            }
+        });
+        let err = db
+            .syms
+            .eval_source(&format!("(kv-open {:?})", db.path))
+            .unwrap_err();
+        assert_eq!(crate::symbols::error_kind(&err), "Permission");
+        assert!(!std::path::Path::new(&lock).exists());
+        let kinds: Vec<_> = seen
+            .borrow()
+            .iter()
+            .filter(|op| op.target == lock)
+            .map(|op| op.kind)
+            .collect();
+        assert_eq!(kinds, vec![IoKind::CreateFile]);
+    }
+}
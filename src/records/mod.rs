@@ -1,5 +1,22 @@
+pub mod atom;
+#[cfg(feature = "io")]
 pub mod file;
+#[cfg(feature = "io")]
+pub mod kv;
+pub mod progress;
+#[cfg(feature = "time")]
+pub mod rate_limiter;
 pub mod record;
+pub mod user_record;
 
+pub(crate) use self::atom::{AtomRecord, Walk};
+#[cfg(feature = "io")]
 pub(crate) use self::file::FileRecord;
-pub(crate) use self::record::{Record, RecordDoc, RecordType};
+#[cfg(feature = "io")]
+pub(crate) use self::kv::KvRecord;
+pub(crate) use self::progress::ProgressRecord;
+#[cfg(feature = "time")]
+pub(crate) use self::rate_limiter::RateLimiterRecord;
+pub(crate) use self::record::{closed_error, RecordDoc};
+pub use self::record::{DictRecord, MethodHandle, Record, RecordType};
+pub(crate) use self::user_record::RecordDef;
@@ -0,0 +1,492 @@
+use crate::exact_len;
+use crate::records::{closed_error, Record, RecordDoc, RecordType};
+use crate::symbols::{next_object_id, Expr, LispResult, SymbolTable};
+use crate::terminal::TerminalInfo;
+use crate::{num, record, unknown_method};
+use anyhow::{anyhow, ensure};
+use im::Vector;
+use parking_lot::Mutex;
+#[cfg(test)]
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use unicode_width::UnicodeWidthStr;
+
+/// Most characters in a terminal progress bar.
+const BAR_WIDTH: usize = 30;
+/// Narrower than this, the bar is left out and only the counts drawn.
+const MIN_BAR_WIDTH: usize = 5;
+/// Least time between redraws on a terminal, so ticking in a tight loop
+/// doesn't spend its time drawing.
+const TTY_REDRAW: Duration = Duration::from_millis(100);
+/// Least time between log lines when stderr isn't a terminal.
+const LOG_EVERY: Duration = Duration::from_secs(1);
+
+/// The current time. Tests stand in their own, so redraws don't depend
+/// on how fast they run.
+type Clock = Arc<dyn Fn() -> Instant + Send + Sync>;
+
+/// Where a progress bar draws.
+pub(crate) enum Sink {
+    /// The interpreter's stderr. Terminals get one line redrawn in place,
+    /// anything else gets a log line now and then.
+    Stderr(TerminalInfo),
+    /// A buffer tests read back, which is never a terminal.
+    #[cfg(test)]
+    Writer(Box<dyn Write + Send>),
+}
+
+struct Progress {
+    total: usize,
+    position: usize,
+    message: String,
+    started: Instant,
+    last_drawn: Option<Instant>,
+    finished: bool,
+    sink: Sink,
+    clock: Clock,
+}
+
+impl Progress {
+    fn tty(&self) -> bool {
+        matches!(&self.sink, Sink::Stderr(terminal) if terminal.stderr_tty)
+    }
+
+    fn emit(&mut self, s: &str, symbol_table: Option<&SymbolTable>) {
+        match &mut self.sink {
+            Sink::Stderr(_) => match symbol_table {
+                Some(symbol_table) => symbol_table.host_mut().write_stderr(s),
+                None => eprint!("{}", s),
+            },
+            // Progress is best effort, so failing to draw isn't an error.
+            #[cfg(test)]
+            Sink::Writer(w) => {
+                let _ = w.write_all(s.as_bytes()).and_then(|_| w.flush());
+            }
+        }
+    }
+
+    fn prefix(&self) -> String {
+        if self.message.is_empty() {
+            String::new()
+        } else {
+            format!("{} ", self.message)
+        }
+    }
+
+    fn percent(&self) -> usize {
+        self.position
+            .saturating_mul(100)
+            .checked_div(self.total)
+            .map_or(100, |percent| percent.min(100))
+    }
+
+    /// How wide a bar fits beside the message and `counts` on one line,
+    /// if one is drawn at all.
+    fn bar_width(&mut self, counts: &str) -> Option<usize> {
+        let terminal = match &mut self.sink {
+            Sink::Stderr(terminal) if terminal.stderr_tty => terminal,
+            _ => return None,
+        };
+        terminal.refresh_width();
+        let width = match terminal.width {
+            // Leave the last column, so the line never wraps.
+            Some(width) => width.saturating_sub(1),
+            None => return Some(BAR_WIDTH),
+        };
+        let used = UnicodeWidthStr::width(self.prefix().as_str()) + counts.len() + "[] ".len();
+        Some(width.saturating_sub(used).min(BAR_WIDTH)).filter(|w| *w >= MIN_BAR_WIDTH)
+    }
+
+    /// Draw the current state, unless it was drawn too recently.
+    fn draw(&mut self, symbol_table: Option<&SymbolTable>) {
+        let every = if self.tty() { TTY_REDRAW } else { LOG_EVERY };
+        let now = (self.clock)();
+        if self.finished || self.last_drawn.is_some_and(|t| now - t < every) {
+            return;
+        }
+        self.last_drawn = Some(now);
+        let counts = format!("{}/{} ({}%)", self.position, self.total, self.percent());
+        let line = if let Some(bar_width) = self.bar_width(&counts) {
+            let filled = self.percent() * bar_width / 100;
+            format!(
+                "\r\x1b[2K{}[{}{}] {}",
+                self.prefix(),
+                "#".repeat(filled),
+                " ".repeat(bar_width - filled),
+                counts
+            )
+        } else if self.tty() {
+            format!("\r\x1b[2K{}{}", self.prefix(), counts)
+        } else {
+            format!("{}{}\n", self.prefix(), counts)
+        };
+        self.emit(&line, symbol_table);
+    }
+
+    fn finish(&mut self, symbol_table: Option<&SymbolTable>) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        let clear = if self.tty() { "\r\x1b[2K" } else { "" };
+        let summary = format!(
+            "{}{}done: {}/{} in {:.1}s\n",
+            clear,
+            self.prefix(),
+            self.position,
+            self.total,
+            ((self.clock)() - self.started).as_secs_f64()
+        );
+        self.emit(&summary, symbol_table);
+    }
+}
+
+/// A progress bar, made with (progress-bar total).
+#[derive(Clone)]
+pub(crate) struct ProgressRecord {
+    progress: Arc<Mutex<Progress>>,
+    id: u64,
+}
+
+impl ProgressRecord {
+    pub(crate) fn new(total: usize, sink: Sink) -> ProgressRecord {
+        ProgressRecord::with_clock(total, sink, Arc::new(Instant::now))
+    }
+
+    fn with_clock(total: usize, sink: Sink, clock: Clock) -> ProgressRecord {
+        ProgressRecord {
+            progress: Arc::new(Mutex::new(Progress {
+                total,
+                position: 0,
+                message: String::new(),
+                started: clock(),
+                last_drawn: None,
+                finished: false,
+                sink,
+                clock,
+            })),
+            id: next_object_id(),
+        }
+    }
+
+    pub(crate) fn from_x7(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+        exact_len!(exprs, 1);
+        let total = exprs[0].get_usize()?;
+        let terminal = symbol_table.host_mut().terminal();
+        record!(ProgressRecord::new(total, Sink::Stderr(terminal)))
+    }
+
+    fn call(
+        &self,
+        sym: &str,
+        args: Vector<Expr>,
+        symbol_table: Option<&SymbolTable>,
+    ) -> LispResult<Expr> {
+        let mut progress = self.progress.lock();
+        if progress.finished && ["tick", "inc", "set-message"].contains(&sym) {
+            // Describing the bar takes the lock.
+            drop(progress);
+            return Err(closed_error(self, sym));
+        }
+        match sym {
+            "tick" | "inc" => {
+                let n = if sym == "tick" {
+                    exact_len!(args, 0);
+                    1
+                } else {
+                    exact_len!(args, 1);
+                    args[0].get_usize()?
+                };
+                progress.position = progress.position.saturating_add(n);
+                progress.draw(symbol_table);
+                Ok(num!(progress.position))
+            }
+            "set-message" => {
+                exact_len!(args, 1);
+                progress.message = args[0].get_string()?;
+                progress.draw(symbol_table);
+                Ok(Expr::Nil)
+            }
+            "finish" => {
+                exact_len!(args, 0);
+                progress.finish(symbol_table);
+                Ok(Expr::Nil)
+            }
+            "close" => {
+                exact_len!(args, 0);
+                let closing = !progress.finished;
+                progress.finish(symbol_table);
+                Ok(Expr::Bool(closing))
+            }
+            "closed?" => {
+                exact_len!(args, 0);
+                Ok(Expr::Bool(progress.finished))
+            }
+            _ => {
+                drop(progress);
+                unknown_method!(self, sym)
+            }
+        }
+    }
+}
+
+impl Record for ProgressRecord {
+    fn call_method(&self, sym: &str, args: Vector<Expr>) -> LispResult<Expr> {
+        self.call(sym, args, None)
+    }
+
+    fn call_method_in(
+        &self,
+        sym: &str,
+        args: Vector<Expr>,
+        symbol_table: &SymbolTable,
+    ) -> LispResult<Expr> {
+        self.call(sym, args, Some(symbol_table))
+    }
+
+    fn type_name(&self) -> &'static str {
+        "ProgressRecord"
+    }
+
+    fn display(&self) -> String {
+        let progress = self.progress.lock();
+        format!("Progress<{}/{}>", progress.position, progress.total)
+    }
+
+    fn debug(&self) -> String {
+        self.display()
+    }
+
+    fn clone(&self) -> RecordType {
+        Box::new(Clone::clone(self))
+    }
+
+    fn methods(&self) -> Vec<&'static str> {
+        ProgressRecord::method_doc()
+            .iter()
+            .map(|(l, _)| *l)
+            .collect()
+    }
+
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn close(&self) -> LispResult<Expr> {
+        self.call("close", Vector::new(), None)
+    }
+
+    fn close_in(&self, symbol_table: &SymbolTable) -> LispResult<Expr> {
+        self.call("close", Vector::new(), Some(symbol_table))
+    }
+
+    fn is_closed(&self) -> bool {
+        self.progress.lock().finished
+    }
+}
+
+impl RecordDoc for ProgressRecord {
+    fn name() -> &'static str {
+        "ProgressRecord"
+    }
+
+    fn type_doc() -> &'static str {
+        "Reports progress on stderr, made with (progress-bar total).
+Terminals get a bar redrawn at most 10 times a second, and anything else a log line every second.
+Example:
+(def p (progress-bar (len files)))
+(foreach (fn (f) (process f) (.tick p)) files)
+(.finish p) ; done: 120/120 in 3.2s
+"
+    }
+
+    fn method_doc() -> &'static [(&'static str, &'static str)] {
+        &[
+            (
+                "tick",
+                "Advance by one, returning the new position.
+Example: (.tick p) ; 1
+",
+            ),
+            (
+                "inc",
+                "Advance by n, returning the new position.
+Example: (.inc p 10) ; 11
+",
+            ),
+            (
+                "set-message",
+                "Show a message before the bar.
+Example: (.set-message p \"downloading\")
+",
+            ),
+            (
+                "finish",
+                "Print a summary of how far it got and how long it took. Only the first call prints.
+Other methods fail with a :closed error once the bar is finished.
+Example: (.finish p) ; done: 11/120 in 0.4s
+",
+            ),
+            (
+                "close",
+                "Finish the bar, returning false if it was already finished.
+Example: (.close p) ; true
+",
+            ),
+            (
+                "closed?",
+                "Whether the bar has been finished.
+Example: (.closed? p) ; false
+",
+            ),
+        ]
+    }
+}
+
+/// (with-progress (p total) body...)
+pub(crate) fn with_progress(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let binding = exprs[0].get_list()?;
+    ensure!(
+        binding.len() == 2 && binding[0].is_symbol(),
+        "with-progress expects a binding like (p 100), but was given {}",
+        exprs[0]
+    );
+    let total = binding[1].eval(symbol_table)?;
+    let bar = ProgressRecord::from_x7(Vector::unit(total), symbol_table)?;
+    let scope = symbol_table.with_locals(&[binding[0].clone()], Vector::unit(bar.clone()))?;
+    let res = exprs
+        .iter()
+        .skip(1)
+        .try_fold(Expr::Nil, |_, expr| expr.eval(&scope));
+    // Finish even if the body failed, so the terminal isn't left mid-line.
+    if let Expr::Record(bar) = &bar {
+        bar.close_in(symbol_table)?;
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Options;
+    use crate::parser::read;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use crate::terminal::TerminalInfo;
+
+    /// A writer the test can read back.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuf {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.lock().clone())
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect()
+        }
+    }
+
+    #[test]
+    fn ticking_is_rate_limited() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let buf = SharedBuf::default();
+        let start = Instant::now();
+        let now = Arc::new(Mutex::new(start));
+        let clock = now.clone();
+        let bar = ProgressRecord::with_clock(
+            10_000,
+            Sink::Writer(Box::new(buf.clone())),
+            Arc::new(move || *clock.lock()),
+        );
+        let call = |sym: &str, args: Vector<Expr>| bar.call_method_in(sym, args, &syms);
+
+        call("set-message", Vector::unit(Expr::String("copying".into()))).unwrap();
+        for _ in 0..5_000 {
+            call("tick", Vector::new()).unwrap();
+        }
+        // Only once a second has passed is there another line.
+        *now.lock() = start + LOG_EVERY / 2;
+        call("tick", Vector::new()).unwrap();
+        *now.lock() = start + LOG_EVERY;
+        call("tick", Vector::new()).unwrap();
+        for _ in 0..4_988 {
+            call("tick", Vector::new()).unwrap();
+        }
+        assert_eq!(
+            call("inc", Vector::unit(Expr::from(10))).unwrap(),
+            Expr::from(10_000)
+        );
+        *now.lock() = start + LOG_EVERY * 2;
+        call("finish", Vector::new()).unwrap();
+        call("finish", Vector::new()).unwrap();
+
+        let lines = buf.lines();
+        assert_eq!(
+            lines,
+            vec![
+                "copying 0/10000 (0%)",
+                "copying 5002/10000 (50%)",
+                "copying done: 10000/10000 in 2.0s"
+            ]
+        );
+        assert!(call("rewind", Vector::new()).is_err());
+    }
+
+    #[test]
+    fn bars_fit_the_terminal() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.host_mut().capture_output();
+        let drawn = |width: Option<usize>| {
+            let terminal = TerminalInfo {
+                stderr_tty: true,
+                width,
+                ..TerminalInfo::default()
+            };
+            let bar = ProgressRecord::new(4, Sink::Stderr(terminal));
+            bar.call_method_in("tick", Vector::new(), &syms).unwrap();
+            syms.host_mut().take_captured_output().1
+        };
+        let bar = |width| {
+            format!(
+                "\r\x1b[2K[{}] 1/4 (25%)",
+                "#".repeat(width / 4) + &" ".repeat(width - width / 4)
+            )
+        };
+        assert_eq!(drawn(None), bar(BAR_WIDTH));
+        assert_eq!(drawn(Some(200)), bar(BAR_WIDTH));
+        assert_eq!(drawn(Some(20)), bar(7));
+        // Too narrow for a bar.
+        assert_eq!(drawn(Some(15)), "\r\x1b[2K1/4 (25%)");
+    }
+
+    #[test]
+    fn with_progress_finishes_on_error() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.host_mut().capture_output();
+        let prog = "(with-progress (p 3) (.tick p) (err \"boom\"))";
+        let mut res = Ok(Expr::Nil);
+        for expr in read(prog) {
+            res = expr.unwrap().eval(&syms);
+        }
+        assert!(res.is_err());
+        let (stdout, stderr) = syms.host_mut().take_captured_output();
+        assert_eq!(stdout, "");
+        let lines: Vec<&str> = stderr.lines().collect();
+        assert_eq!(lines[0], "1/3 (33%)");
+        assert!(lines[1].starts_with("done: 1/3 in "), "{}", stderr);
+        assert_eq!(lines.len(), 2);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::exact_len;
+use crate::records::{Record, RecordDoc, RecordType};
+use crate::symbols::{next_object_id, Expr, LispResult, SymbolTable};
+use crate::{record, unknown_method};
+use anyhow::{anyhow, ensure};
+use im::Vector;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Hands out at most `limit` permits in any window of `per`.
+#[derive(Clone)]
+pub(crate) struct RateLimiterRecord {
+    limit: usize,
+    per: Duration,
+    // When the last `limit` permits were taken, or are reserved for, oldest first.
+    recent: Arc<Mutex<VecDeque<Instant>>>,
+    id: u64,
+}
+
+impl RateLimiterRecord {
+    pub(crate) fn from_x7(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+        exact_len!(exprs, 2);
+        let limit = exprs[0].get_usize()?;
+        ensure!(limit > 0, "rate-limiter needs to allow at least 1 call");
+        let per = exprs[1].get_seconds("rate-limiter's period")?;
+        ensure!(
+            per > Duration::from_secs(0),
+            "rate-limiter's period must be positive"
+        );
+        record!(RateLimiterRecord {
+            limit,
+            per,
+            recent: Arc::new(Mutex::new(VecDeque::with_capacity(limit))),
+            id: next_object_id(),
+        })
+    }
+
+    /// Take the next permit, waiting for it outside the lock. Waiting stops
+    /// with an error when `symbol_table` is interrupted.
+    fn acquire(&self, args: Vector<Expr>, symbol_table: Option<&SymbolTable>) -> LispResult<Expr> {
+        exact_len!(args, 0);
+        // Permits are reserved in order, each for when it frees up, so
+        // callers are let through in order without holding the lock.
+        let free_at = {
+            let mut recent = self.recent.lock();
+            let now = Instant::now();
+            let free_at = if recent.len() == self.limit {
+                (recent.pop_front().unwrap() + self.per).max(now)
+            } else {
+                now
+            };
+            recent.push_back(free_at);
+            free_at
+        };
+        let wait = free_at.saturating_duration_since(Instant::now());
+        match symbol_table {
+            Some(symbol_table) => crate::retry::sleep(symbol_table, wait)?,
+            None => thread::sleep(wait),
+        }
+        Ok(Expr::Nil)
+    }
+
+    /// (limiter f args...)
+    fn call_limited(&self, args: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+        ensure!(
+            !args.is_empty(),
+            "A rate limiter is called with a function to call, and its args"
+        );
+        self.acquire(Vector::new(), Some(symbol_table))?;
+        args[0].call_with_values(args.clone().slice(1..), symbol_table)
+    }
+}
+
+impl Record for RateLimiterRecord {
+    fn call_method(&self, sym: &str, args: Vector<Expr>) -> LispResult<Expr> {
+        match sym {
+            "acquire" => self.acquire(args, None),
+            _ => unknown_method!(self, sym),
+        }
+    }
+
+    fn call_method_in(
+        &self,
+        sym: &str,
+        args: Vector<Expr>,
+        symbol_table: &SymbolTable,
+    ) -> LispResult<Expr> {
+        match sym {
+            "acquire" => self.acquire(args, Some(symbol_table)),
+            _ => self.call_method(sym, args),
+        }
+    }
+
+    fn call(&self, args: Vector<Expr>, symbol_table: &SymbolTable) -> Option<LispResult<Expr>> {
+        Some(self.call_limited(args, symbol_table))
+    }
+
+    fn type_name(&self) -> &'static str {
+        "RateLimiterRecord"
+    }
+
+    fn display(&self) -> String {
+        format!(
+            "RateLimiter<{} per {}s>",
+            self.limit,
+            self.per.as_secs_f64()
+        )
+    }
+
+    fn debug(&self) -> String {
+        self.display()
+    }
+
+    fn clone(&self) -> RecordType {
+        Box::new(Clone::clone(self))
+    }
+
+    fn methods(&self) -> Vec<&'static str> {
+        RateLimiterRecord::method_doc()
+            .iter()
+            .map(|(l, _)| *l)
+            .collect()
+    }
+
+    fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl RecordDoc for RateLimiterRecord {
+    fn name() -> &'static str {
+        "RateLimiterRecord"
+    }
+
+    fn type_doc() -> &'static str {
+        "Keeps callers under a rate, made with (rate-limiter n per-seconds).
+Calling it, (limiter f args...), waits for a call to be allowed and then calls f.
+Example:
+(def limiter (rate-limiter 5 1))
+(foreach (fn (url) (limiter fetch url)) urls) ; at most 5 fetches a second
+"
+    }
+
+    fn method_doc() -> &'static [(&'static str, &'static str)] {
+        &[(
+            "acquire",
+            "Wait until another call is allowed under the rate, then take it.
+Example:
+(def limiter (rate-limiter 2 1))
+(.acquire limiter) ; returns at once
+(.acquire limiter) ; returns at once
+(.acquire limiter) ; returns a second after the first
+",
+        )]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Options;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn acquire_keeps_under_the_rate() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let limiter = RateLimiterRecord::from_x7(
+            im::vector![Expr::from(2), Expr::Num("0.2".parse().unwrap())],
+            &syms,
+        )
+        .unwrap()
+        .get_record()
+        .unwrap();
+        let start = Instant::now();
+        limiter.call_method("acquire", Vector::new()).unwrap();
+        limiter.call_method("acquire", Vector::new()).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(150));
+        limiter.call_method("acquire", Vector::new()).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        let bad = |n: i64, per: i64| {
+            RateLimiterRecord::from_x7(im::vector![Expr::from(n), Expr::from(per)], &syms)
+        };
+        assert!(bad(0, 1).is_err());
+        assert!(bad(1, 0).is_err());
+        assert!(bad(1, -1).is_err());
+    }
+
+    #[test]
+    fn waiting_can_be_interrupted() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let limiter =
+            RateLimiterRecord::from_x7(im::vector![Expr::from(1), Expr::from(3600)], &syms)
+                .unwrap()
+                .get_record()
+                .unwrap();
+        limiter
+            .call_method_in("acquire", Vector::new(), &syms)
+            .unwrap();
+        syms.interrupt_handle().store(true, Ordering::SeqCst);
+        let err = limiter
+            .call_method_in("acquire", Vector::new(), &syms)
+            .unwrap_err();
+        assert_eq!(crate::symbols::error_kind(&err), "Interrupted");
+        syms.reset_interrupt();
+    }
+
+    #[test]
+    fn calls_pass_values_on() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let prog = "(def limiter (rate-limiter 5 1)) (limiter list '(1 2) 'x)";
+        assert_eq!(
+            syms.eval_source(prog).unwrap(),
+            syms.eval_source("(list '(1 2) 'x)").unwrap()
+        );
+    }
+}
@@ -1,11 +1,20 @@
-use crate::symbols::{Expr, LispResult};
+use crate::symbols::{Expr, LispResult, ProgramError, SymbolTable};
+use anyhow::anyhow;
 use core::hash::Hash;
 use core::hash::Hasher;
 use im::Vector;
+use std::any::Any;
 use std::fmt;
 use std::ops::Deref;
+use std::sync::Arc;
 
-pub(crate) type RecordType = Box<dyn Record>;
+pub type RecordType = Box<dyn Record>;
+
+/// What a method name resolved to on some record, from `Record::resolve_method`.
+pub type MethodHandle = Arc<dyn Any + Send + Sync>;
+
+/// Longest record summary shown when printing a record.
+const MAX_RECORD_SUMMARY: usize = 60;
 
 /// Document Records. Used in the document_records! macro
 /// to properly document your record type.
@@ -24,12 +33,27 @@ pub(crate) trait RecordDoc {
 /// Records allow x7 to represent a variety of internally mutable types
 /// while not expanding the Expr enum too much. These types are responsible for
 /// implementing RecordDoc if they want to have documentation.
-pub(crate) trait Record: Sync + Send {
+pub trait Record: Sync + Send {
     /// Call a method on this record.
     /// (.method_name <rec> arg1 arg2 arg3)
     /// Becomes:
     /// (&self: <rec>, sym: "method_name", args: vector![arg1, arg2, arg3])
     fn call_method(&self, sym: &str, args: Vector<Expr>) -> LispResult<Expr>;
+    /// Like `call_method`, for records whose methods run x7 code, like
+    /// records made with `defrecord`. Method call syntax goes through here.
+    fn call_method_in(
+        &self,
+        sym: &str,
+        args: Vector<Expr>,
+        _symbol_table: &SymbolTable,
+    ) -> LispResult<Expr> {
+        self.call_method(sym, args)
+    }
+    /// Call the record itself, as in `(rec arg1 arg2)`, with evaluated `args`.
+    /// Records which can't be called return `None`.
+    fn call(&self, _args: Vector<Expr>, _symbol_table: &SymbolTable) -> Option<LispResult<Expr>> {
+        None
+    }
     fn id(&self) -> u64 {
         0
     }
@@ -43,6 +67,69 @@ pub(crate) trait Record: Sync + Send {
     fn methods(&self) -> Vec<&'static str>;
     /// Return the type name for nice help messages
     fn type_name(&self) -> &'static str;
+    /// The record as `Any`, for builtins which take their own record types.
+    fn as_any(&self) -> Option<&dyn Any> {
+        None
+    }
+    /// The record as a read-only dict, for records which read like one.
+    fn as_dict(&self) -> Option<&dyn DictRecord> {
+        None
+    }
+    /// Release what the record holds, like a file handle. Records holding
+    /// nothing can't be closed.
+    ///
+    /// Closing is shared by clones of the record and closing twice does
+    /// nothing, so this returns whether this call closed it. Once closed,
+    /// other methods fail with `closed_error`.
+    fn close(&self) -> LispResult<Expr> {
+        Err(anyhow!("{} has nothing to close", self.type_name()))
+    }
+    /// Like `close`, for records which report to the interpreter on closing.
+    fn close_in(&self, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+        self.close()
+    }
+    fn is_closed(&self) -> bool {
+        false
+    }
+    /// Look up the method `sym` ahead of calling it, for records where that
+    /// takes real work. Method call syntax keeps the handle per call site,
+    /// and calls it with `call_resolved` while records of the same type come by.
+    fn resolve_method(&self, _sym: &str) -> Option<MethodHandle> {
+        None
+    }
+    /// Call a method found by `resolve_method`. `None` when the handle isn't
+    /// for this record, e.g. it came from another version of its type, in
+    /// which case the caller looks the method up again.
+    fn call_resolved(
+        &self,
+        _handle: &MethodHandle,
+        _args: Vector<Expr>,
+        _symbol_table: &SymbolTable,
+    ) -> Option<LispResult<Expr>> {
+        None
+    }
+}
+
+/// Records which read like dicts. `get`, `keys`, `contains?`, `get-in`, and
+/// `reduce-kv` take them, but they can't be changed: `deep-copy` makes a
+/// plain dict out of one.
+pub trait DictRecord {
+    /// The value of `key`, or `None` if there isn't one.
+    fn get(&self, key: &Expr) -> LispResult<Option<Expr>>;
+    /// Every key, in no particular order.
+    fn keys(&self) -> LispResult<Vec<Expr>>;
+    /// The whole record as plain values, with no records left inside.
+    fn deep_copy(&self) -> LispResult<Expr>;
+}
+
+/// The error for calling `method` on `record` once it's closed.
+pub(crate) fn closed_error(record: &dyn Record, method: &str) -> anyhow::Error {
+    anyhow!(ProgramError::Closed).context(format!(
+        "The {} {} is closed, so .{} can't be called on it",
+        record.type_name(),
+        record.display(),
+        method
+    ))
 }
 
 impl fmt::Display for RecordType {
@@ -53,7 +140,13 @@ impl fmt::Display for RecordType {
 
 impl fmt::Debug for RecordType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.debug())
+        let summary = self.display();
+        if summary.chars().count() > MAX_RECORD_SUMMARY {
+            let truncated: String = summary.chars().take(MAX_RECORD_SUMMARY).collect();
+            write!(f, "#<Record {} {}...>", self.type_name(), truncated)
+        } else {
+            write!(f, "#<Record {} {}>", self.type_name(), summary)
+        }
     }
 }
 
@@ -62,6 +155,15 @@ impl Record for RecordType {
         self.deref().call_method(sym, args)
     }
 
+    fn call_method_in(
+        &self,
+        sym: &str,
+        args: Vector<Expr>,
+        symbol_table: &SymbolTable,
+    ) -> LispResult<Expr> {
+        self.deref().call_method_in(sym, args, symbol_table)
+    }
+
     fn debug(&self) -> String {
         self.deref().debug()
     }
@@ -82,6 +184,32 @@ impl Record for RecordType {
     fn type_name(&self) -> &'static str {
         self.deref().type_name()
     }
+    fn as_any(&self) -> Option<&dyn Any> {
+        self.deref().as_any()
+    }
+    fn as_dict(&self) -> Option<&dyn DictRecord> {
+        self.deref().as_dict()
+    }
+    fn close(&self) -> LispResult<Expr> {
+        self.deref().close()
+    }
+    fn close_in(&self, symbol_table: &SymbolTable) -> LispResult<Expr> {
+        self.deref().close_in(symbol_table)
+    }
+    fn is_closed(&self) -> bool {
+        self.deref().is_closed()
+    }
+    fn resolve_method(&self, sym: &str) -> Option<MethodHandle> {
+        self.deref().resolve_method(sym)
+    }
+    fn call_resolved(
+        &self,
+        handle: &MethodHandle,
+        args: Vector<Expr>,
+        symbol_table: &SymbolTable,
+    ) -> Option<LispResult<Expr>> {
+        self.deref().call_resolved(handle, args, symbol_table)
+    }
 }
 
 impl Hash for RecordType {
@@ -90,9 +218,10 @@ impl Hash for RecordType {
     }
 }
 
+// Records are mutable, so they are equal only to themselves.
 impl PartialEq for RecordType {
-    fn eq(&self, _other: &RecordType) -> bool {
-        false
+    fn eq(&self, other: &RecordType) -> bool {
+        self.id() == other.id()
     }
 }
 
@@ -126,3 +255,43 @@ macro_rules! unknown_method {
         ))
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone)]
+    struct Dummy(String);
+
+    impl Record for Dummy {
+        fn call_method(&self, _sym: &str, _args: Vector<Expr>) -> LispResult<Expr> {
+            Ok(Expr::Nil)
+        }
+        fn display(&self) -> String {
+            format!("Dummy<{}>", self.0)
+        }
+        fn debug(&self) -> String {
+            self.display()
+        }
+        fn clone(&self) -> RecordType {
+            Box::new(Clone::clone(self))
+        }
+        fn methods(&self) -> Vec<&'static str> {
+            Vec::new()
+        }
+        fn type_name(&self) -> &'static str {
+            "Dummy"
+        }
+    }
+
+    #[test]
+    fn record_printing_is_stable_and_bounded() {
+        let short = Expr::Record(Box::new(Dummy("short".into())));
+        assert_eq!(format!("{:?}", short), "#<Record Dummy Dummy<short>>");
+        assert_eq!(format!("{}", short), "#<Record Dummy Dummy<short>>");
+
+        let long = Expr::Record(Box::new(Dummy("x".repeat(200))));
+        let expected = format!("#<Record Dummy Dummy<{}...>", "x".repeat(54));
+        assert_eq!(format!("{:?}", long), expected);
+    }
+}
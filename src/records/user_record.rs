@@ -0,0 +1,486 @@
+use crate::exact_len;
+use crate::records::{MethodHandle, Record, RecordType};
+use crate::stdlib::func;
+use crate::symbols::{next_object_id, Expr, Function, LispResult, SymbolTable};
+use anyhow::{anyhow, bail, ensure};
+use im::Vector;
+use itertools::Itertools;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+// Records defined in x7 with `defrecord`:
+//
+//   (defrecord Point (x y (z 0))
+//     (defn norm (self) (+ (.x self) (.y self))))
+//
+// binds the constructor `Point`, the predicate `Point?`, and gives
+// instances a method per field and per `defn`. Redefining a record makes a
+// new version of it. Instances keep dispatching against the version they
+// were made with until `upgrade-record` moves them to the latest one.
+
+/// Names of record types, fields, and methods, which `Record` wants as
+/// `&'static str`. Each distinct name is leaked once.
+fn intern(name: &str) -> &'static str {
+    static NAMES: Lazy<Mutex<HashSet<&'static str>>> = Lazy::new(Default::default);
+    let mut names = NAMES.lock();
+    match names.get(name) {
+        Some(name) => name,
+        None => {
+            let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+            names.insert(name);
+            name
+        }
+    }
+}
+
+/// One version of a record type.
+#[derive(Debug)]
+pub(crate) struct RecordDef {
+    name: &'static str,
+    version: usize,
+    // In constructor order, with their defaults.
+    fields: Vec<(&'static str, Expr)>,
+    methods: HashMap<&'static str, Expr>,
+}
+
+impl RecordDef {
+    pub(crate) fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn field_index(&self, field: &str) -> Option<usize> {
+        self.fields.iter().position(|(name, _)| *name == field)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct UserRecord {
+    def: Arc<RecordDef>,
+    values: Vector<Expr>,
+    id: u64,
+}
+
+impl UserRecord {
+    fn new_expr(def: Arc<RecordDef>, values: Vector<Expr>) -> Expr {
+        Expr::Record(Box::new(UserRecord {
+            def,
+            values,
+            id: next_object_id(),
+        }))
+    }
+
+    fn from_expr(expr: &Expr) -> Option<&UserRecord> {
+        match expr.unmeta() {
+            Expr::Record(r) => r.as_any()?.downcast_ref(),
+            _ => None,
+        }
+    }
+
+    fn field(&self, sym: &str, args: &Vector<Expr>) -> Option<LispResult<Expr>> {
+        let index = self.def.field_index(sym)?;
+        Some(self.field_at(index, args))
+    }
+
+    fn field_at(&self, index: usize, args: &Vector<Expr>) -> LispResult<Expr> {
+        if args.is_empty() {
+            Ok(self.values[index].clone())
+        } else {
+            Err(anyhow!(
+                "Field `{}` of {} takes no arguments, but was given {}",
+                self.def.fields[index].0,
+                self.def.name,
+                args.len()
+            ))
+        }
+    }
+
+    fn call_x7_method(
+        &self,
+        method: &Expr,
+        mut args: Vector<Expr>,
+        symbol_table: &SymbolTable,
+    ) -> LispResult<Expr> {
+        args.push_front(Expr::Record(Box::new(Clone::clone(self))));
+        method.call_with_values(args, symbol_table)
+    }
+}
+
+/// A field or method of one version of a record type.
+enum Member {
+    Field(usize),
+    Method(Expr),
+}
+
+struct ResolvedMember {
+    // Held so the version can't be dropped, and another take its address,
+    // while a call site caches this.
+    def: Arc<RecordDef>,
+    member: Member,
+}
+
+impl Record for UserRecord {
+    fn call_method(&self, sym: &str, args: Vector<Expr>) -> LispResult<Expr> {
+        match self.field(sym, &args) {
+            Some(res) => res,
+            None if self.def.methods.contains_key(sym) => bail!(
+                "Method `{}` of {} is written in x7, and needs an interpreter to call it",
+                sym,
+                self.def.name
+            ),
+            None => unknown_method(self, sym),
+        }
+    }
+
+    fn call_method_in(
+        &self,
+        sym: &str,
+        args: Vector<Expr>,
+        symbol_table: &SymbolTable,
+    ) -> LispResult<Expr> {
+        if let Some(res) = self.field(sym, &args) {
+            return res;
+        }
+        match self.def.methods.get(sym) {
+            Some(method) => self.call_x7_method(method, args, symbol_table),
+            None => unknown_method(self, sym),
+        }
+    }
+
+    fn call(&self, args: Vector<Expr>, symbol_table: &SymbolTable) -> Option<LispResult<Expr>> {
+        let method = self.def.methods.get("call")?;
+        Some(self.call_x7_method(method, args, symbol_table))
+    }
+
+    fn resolve_method(&self, sym: &str) -> Option<MethodHandle> {
+        let member = match self.def.field_index(sym) {
+            Some(index) => Member::Field(index),
+            None => Member::Method(self.def.methods.get(sym)?.clone()),
+        };
+        Some(Arc::new(ResolvedMember {
+            def: self.def.clone(),
+            member,
+        }))
+    }
+
+    fn call_resolved(
+        &self,
+        handle: &MethodHandle,
+        args: Vector<Expr>,
+        symbol_table: &SymbolTable,
+    ) -> Option<LispResult<Expr>> {
+        let resolved = handle.downcast_ref::<ResolvedMember>()?;
+        if !Arc::ptr_eq(&resolved.def, &self.def) {
+            return None;
+        }
+        Some(match &resolved.member {
+            Member::Field(index) => self.field_at(*index, &args),
+            Member::Method(method) => self.call_x7_method(method, args, symbol_table),
+        })
+    }
+
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn display(&self) -> String {
+        let fields = self
+            .def
+            .fields
+            .iter()
+            .zip(self.values.iter())
+            .map(|((name, _), value)| format!("{}: {:?}", name, value))
+            .join(", ");
+        format!("{}<{}>", self.def.name, fields)
+    }
+
+    fn debug(&self) -> String {
+        format!("{} (version {})", self.display(), self.def.version)
+    }
+
+    fn clone(&self) -> RecordType {
+        Box::new(Clone::clone(self))
+    }
+
+    fn methods(&self) -> Vec<&'static str> {
+        self.def
+            .fields
+            .iter()
+            .map(|(name, _)| *name)
+            .chain(self.def.methods.keys().copied().sorted())
+            .collect()
+    }
+
+    fn type_name(&self) -> &'static str {
+        self.def.name
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
+}
+
+fn unknown_method(rec: &UserRecord, sym: &str) -> LispResult<Expr> {
+    crate::unknown_method!(rec, sym)
+}
+
+/// `(z 0)` -> ("z", 0), and `z` -> ("z", nil).
+fn parse_field(field: &Expr, symbol_table: &SymbolTable) -> LispResult<(&'static str, Expr)> {
+    if let Ok(name) = field.get_symbol_string() {
+        return Ok((intern(&name), Expr::Nil));
+    }
+    let pair = field.get_list()?;
+    ensure!(
+        pair.len() == 2 && pair[0].is_symbol(),
+        "defrecord expects fields like x or (x default), but was given {}",
+        field
+    );
+    Ok((
+        intern(&pair[0].get_symbol_string()?),
+        pair[1].eval(symbol_table)?,
+    ))
+}
+
+/// `(defn name (self args...) body)` -> (name, the method).
+fn parse_method(
+    record: &str,
+    method: &Expr,
+    symbol_table: &SymbolTable,
+) -> LispResult<(&'static str, Expr)> {
+    let mut form = method.get_list()?;
+    ensure!(
+        form.len() > 3 && form[0].symbol_matches("defn") && form[1].is_symbol(),
+        "defrecord expects methods like (defn name (self) body), but was given {}",
+        method
+    );
+    form.pop_front();
+    let name = form.pop_front().unwrap().get_symbol_string()?;
+    let f = func(form, symbol_table)?.rename_function(format!("{}.{}", record, name))?;
+    ensure!(
+        f.get_function()?.minimum_args() > 0,
+        "Method {} of {} needs a parameter for the record itself, like (self)",
+        name,
+        record
+    );
+    Ok((intern(&name), f))
+}
+
+/// (defrecord Name (fields...) methods...)
+pub(crate) fn defrecord(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    ensure!(
+        exprs.len() >= 2,
+        "defrecord expects a name and a list of fields, like (defrecord Point (x y))"
+    );
+    let name = intern(&exprs[0].get_symbol_string()?);
+    let fields = exprs[1]
+        .get_list()?
+        .iter()
+        .map(|field| parse_field(field, symbol_table))
+        .collect::<LispResult<Vec<_>>>()?;
+    let mut methods = HashMap::new();
+    for method in exprs.iter().skip(2) {
+        let (method_name, f) = parse_method(name, method, symbol_table)?;
+        ensure!(
+            !fields.iter().any(|(field, _)| *field == method_name),
+            "{} has both a field and a method named {}",
+            name,
+            method_name
+        );
+        methods.insert(method_name, f);
+    }
+    let version = symbol_table
+        .host()
+        .record_def(name)
+        .map_or(1, |def| def.version + 1);
+    let def = Arc::new(RecordDef {
+        name,
+        version,
+        fields,
+        methods,
+    });
+    symbol_table.host_mut().add_record_def(def.clone());
+
+    let constructor = move |args: Vector<Expr>, _symbol_table: &SymbolTable| {
+        ensure!(
+            args.len() <= def.fields.len(),
+            "{} has {} fields, but was given {} values",
+            def.name,
+            def.fields.len(),
+            args.len()
+        );
+        let mut values = args;
+        for (_, default) in def.fields.iter().skip(values.len()) {
+            values.push_back(default.clone());
+        }
+        Ok(UserRecord::new_expr(def.clone(), values))
+    };
+    let predicate = move |args: Vector<Expr>, _symbol_table: &SymbolTable| {
+        exact_len!(args, 1);
+        let is_instance = UserRecord::from_expr(&args[0]).is_some_and(|r| r.def.name == name);
+        Ok(Expr::Bool(is_instance))
+    };
+    symbol_table.add_local(
+        &exprs[0],
+        &Expr::Function(Function::new(name.into(), 0, Arc::new(constructor), true)),
+    )?;
+    let predicate_name = format!("{}?", name);
+    symbol_table.add_local(
+        &Expr::Symbol(predicate_name.clone()),
+        &Expr::Function(Function::new(predicate_name, 1, Arc::new(predicate), true)),
+    )?;
+    Ok(Expr::Nil)
+}
+
+/// (upgrade-record instance)
+pub(crate) fn upgrade_record(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let old = UserRecord::from_expr(&exprs[0]).ok_or_else(|| {
+        anyhow!(
+            "upgrade-record expects a record made with defrecord, but was given {}",
+            exprs[0]
+        )
+    })?;
+    let latest = symbol_table
+        .host()
+        .record_def(old.def.name)
+        .ok_or_else(|| anyhow!("{} is not defined here", old.def.name))?;
+    if Arc::ptr_eq(&latest, &old.def) {
+        return Ok(exprs[0].clone());
+    }
+    // Fields are matched by name. New fields get their defaults, and removed ones are dropped.
+    let values = latest
+        .fields
+        .iter()
+        .map(|(field, default)| match old.def.field_index(field) {
+            Some(index) => old.values[index].clone(),
+            None => default.clone(),
+        })
+        .collect();
+    Ok(UserRecord::new_expr(latest, values))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::Options;
+    use crate::parser::read;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use crate::symbols::{Expr, LispResult, SymbolTable};
+
+    fn eval(prog: &str, syms: &SymbolTable) -> LispResult<Expr> {
+        let mut res = Expr::Nil;
+        for expr in read(prog) {
+            res = expr?.eval(syms)?;
+        }
+        Ok(res)
+    }
+
+    fn show(prog: &str, syms: &SymbolTable) -> String {
+        format!("{:?}", eval(prog, syms).unwrap())
+    }
+
+    #[test]
+    fn redefined_records_keep_old_instances_working() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        eval(
+            "(defrecord Point (x y)
+               (defn norm (self) (+ (.x self) (.y self))))
+             (def p (Point 1 2))",
+            &syms,
+        )
+        .unwrap();
+        assert_eq!(show("(.norm p)", &syms), "3");
+        assert_eq!(show("(.y p)", &syms), "2");
+
+        eval(
+            "(defrecord Point (x y (z 10))
+               (defn norm (self) (+ (.x self) (.y self) (.z self)))
+               (defn scale (self k) (Point (* k (.x self)) (* k (.y self)) (.z self))))",
+            &syms,
+        )
+        .unwrap();
+        // The old instance still uses the definition it was made with.
+        assert_eq!(show("(.norm p)", &syms), "3");
+        assert!(eval("(.z p)", &syms).is_err());
+        assert!(eval("(.scale p 2)", &syms).is_err());
+        assert_eq!(show("(Point? p)", &syms), "true");
+        assert_eq!(show("(Point? (Point 1 2))", &syms), "true");
+        assert_eq!(show("(Point? 1)", &syms), "false");
+
+        eval("(def q (upgrade-record p))", &syms).unwrap();
+        assert_eq!(show("(.z q)", &syms), "10");
+        assert_eq!(show("(.norm q)", &syms), "13");
+        assert_eq!(show("(.norm (.scale q 2))", &syms), "16");
+        assert_eq!(show("(Point? q)", &syms), "true");
+        assert_eq!(show("p", &syms), "#<Record Point Point<x: 1, y: 2>>");
+
+        eval("(defrecord Other (x))", &syms).unwrap();
+        assert_eq!(show("(Point? (Other 1))", &syms), "false");
+        assert_eq!(show("(.x (Other))", &syms), "nil");
+        assert!(eval("(Other 1 2)", &syms).is_err());
+        assert!(eval("(upgrade-record 1)", &syms).is_err());
+        assert!(eval("(defrecord Bad (x) (defn x (self) 1))", &syms).is_err());
+        assert!(eval("(defrecord Bad (x) (defn f () 1))", &syms).is_err());
+    }
+
+    #[test]
+    fn method_call_sites_cache_per_record_type() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        eval(
+            "(defrecord Square (side) (defn area (self) (* (.side self) (.side self))))
+             (defrecord Rect (w h) (defn area (self) (* (.w self) (.h self))))
+             (def old (Square 3))
+             (defrecord Square (pad side) (defn area (self) (* 10 (.side self))))
+             (defn area-of (s) (.area s))
+             (defn side-of (s) (.side s))",
+            &syms,
+        )
+        .unwrap();
+        // Each call site sees types, and versions with the field in another
+        // place, alternate.
+        assert_eq!(
+            show(
+                "(map area-of (list (Square 0 2) (Rect 2 5) old (Rect 1 1) (Square 0 2) old))",
+                &syms
+            ),
+            "(20 10 9 1 20 9)"
+        );
+        assert_eq!(
+            show(
+                "(map side-of (list (Square 0 2) old (Square 0 5) old))",
+                &syms
+            ),
+            "(2 3 5 3)"
+        );
+        assert!(eval("(side-of (Rect 1 2))", &syms).is_err());
+        assert_eq!(show("(side-of old)", &syms), "3");
+
+        // Runs of the same type hit the cache after the first call.
+        eval(
+            "(defn w-of (r) (.w r))
+             (def rects (map (fn (i) (Rect i 1)) (range 10)))
+             (clear-caches!)",
+            &syms,
+        )
+        .unwrap();
+        assert_eq!(show("(reduce + 0 (map w-of rects))", &syms), "45");
+        assert_eq!(
+            show(
+                "(= (get (cache-stats) \"method-calls\") (dict \"hits\" 9 \"misses\" 1))",
+                &syms
+            ),
+            "true"
+        );
+
+        syms.set_caches_enabled(false);
+        assert_eq!(
+            show("(map side-of (list old (Square 0 5)))", &syms),
+            "(3 5)"
+        );
+        assert_eq!(
+            show("(get (get (cache-stats) \"method-calls\") \"hits\")", &syms),
+            "0"
+        );
+    }
+}
@@ -0,0 +1,431 @@
+use crate::cli::{bounded_repr, format_error};
+use crate::lexer::is_incomplete;
+use crate::symbols::{LispResult, SymbolTable};
+use anyhow::{anyhow, bail, ensure, Context};
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+// A REPL a running program can open on itself, for `x7 attach` to connect
+// to, behind the remote-repl feature.
+//
+// The server only listens on loopback addresses and takes one client at a
+// time, which has to send the server's token first, within AUTH_TIMEOUT,
+// or is dropped to free the slot. Each connection has a thread reading the
+// client's lines until they make complete forms, which are queued for the
+// interpreter. They're only evaluated when the program
+// calls `ReplServer::serve_pending` or `serve_for` on its own thread, so
+// they never run concurrently with it.
+//
+// The protocol is lines of text. The client sends `auth <token>`, then
+// forms, which can span lines. Each is answered by the printed result or
+// error, then a line with just `.`. Reply lines starting with `.` get another
+// `.` in front, which the client strips.
+
+/// Largest result sent back in full, like the REPL's `--max-output-kb`.
+const MAX_REPLY_BYTES: usize = 64 * 1024;
+
+/// How long a client has to authenticate, and how much it can send doing
+/// so, before it's dropped. Until then it holds the only slot.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_AUTH_BYTES: u64 = 256;
+
+/// A form from a client, and where to send what it evaluated to.
+struct Request {
+    source: String,
+    reply: mpsc::Sender<String>,
+}
+
+/// A REPL served over a loopback socket, from `SymbolTable::serve_repl`.
+/// Dropping it stops taking connections.
+pub struct ReplServer {
+    addr: SocketAddr,
+    token: String,
+    requests: mpsc::Receiver<Request>,
+    stop: Arc<AtomicBool>,
+}
+
+/// Listen on `addr`, which must be a loopback address. Port 0 picks a free one.
+pub(crate) fn serve(addr: &str) -> LispResult<ReplServer> {
+    let addrs: Vec<SocketAddr> = addr
+        .to_socket_addrs()
+        .with_context(|| format!("Can't serve a REPL on {}", addr))?
+        .collect();
+    ensure!(
+        !addrs.is_empty() && addrs.iter().all(|a| a.ip().is_loopback()),
+        "A REPL can only be served on localhost, but was given {}",
+        addr
+    );
+    let listener = TcpListener::bind(&addrs[..])?;
+    let addr = listener.local_addr()?;
+    let token = format!("{:032x}", rand::random::<u128>());
+    let (sender, requests) = mpsc::channel();
+    let stop = Arc::new(AtomicBool::new(false));
+    let (accept_token, accept_stop) = (token.clone(), stop.clone());
+    thread::spawn(move || accept(listener, accept_token, sender, accept_stop));
+    Ok(ReplServer {
+        addr,
+        token,
+        requests,
+        stop,
+    })
+}
+
+fn accept(
+    listener: TcpListener,
+    token: String,
+    requests: mpsc::Sender<Request>,
+    stop: Arc<AtomicBool>,
+) {
+    let attached = Arc::new(AtomicBool::new(false));
+    for stream in listener.incoming() {
+        if stop.load(Ordering::SeqCst) {
+            return;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        if attached.swap(true, Ordering::SeqCst) {
+            thread::spawn(move || {
+                let _ = refuse(stream, "error: another client is attached");
+            });
+            continue;
+        }
+        let (token, requests, slot) = (token.clone(), requests.clone(), Slot(attached.clone()));
+        thread::spawn(move || {
+            let _ = serve_client(stream, &token, &requests, slot);
+        });
+    }
+}
+
+/// Turn a client away once it has sent its auth line. Closing with the line
+/// unread would reset the connection, which can lose the reply.
+fn refuse(mut stream: TcpStream, reply: &str) -> io::Result<()> {
+    stream.set_read_timeout(Some(AUTH_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(stream.try_clone()?)
+        .take(MAX_AUTH_BYTES)
+        .read_line(&mut line)?;
+    write_reply(&mut stream, reply)
+}
+
+/// The one client's place, freed when dropped.
+struct Slot(Arc<AtomicBool>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Compare tokens in time independent of where they differ.
+fn same_token(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn serve_client(
+    stream: TcpStream,
+    token: &str,
+    requests: &mpsc::Sender<Request>,
+    slot: Slot,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(AUTH_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    // Dropped before the stream, so the next client never finds the slot
+    // taken by a connection which has already closed.
+    let held = slot;
+    let mut line = String::new();
+    (&mut reader).take(MAX_AUTH_BYTES).read_line(&mut line)?;
+    let given = line.trim_end().strip_prefix("auth ").unwrap_or("");
+    if !same_token(given, token) {
+        drop(held);
+        return write_reply(&mut writer, "error: bad token");
+    }
+    // The clones share the socket, so this clears the reader's timeout too.
+    writer.set_read_timeout(None)?;
+    write_reply(&mut writer, "ok")?;
+    let mut source = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        source.push_str(&line);
+        if is_incomplete(&source) {
+            continue;
+        }
+        if source.trim().is_empty() {
+            source.clear();
+            continue;
+        }
+        let (reply, replied) = mpsc::channel();
+        let request = Request {
+            source: mem::take(&mut source),
+            reply,
+        };
+        // Either fails once the server is dropped.
+        if requests.send(request).is_err() {
+            return Ok(());
+        }
+        match replied.recv() {
+            Ok(reply) => write_reply(&mut writer, &reply)?,
+            Err(_) => return Ok(()),
+        }
+    }
+}
+
+fn write_reply(writer: &mut impl Write, body: &str) -> io::Result<()> {
+    let mut out = String::new();
+    for line in body.lines() {
+        if line.starts_with('.') {
+            out.push('.');
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push_str(".\n");
+    writer.write_all(out.as_bytes())?;
+    writer.flush()
+}
+
+fn read_reply(reader: &mut impl BufRead) -> LispResult<String> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        ensure!(
+            reader.read_line(&mut line)? > 0,
+            "The REPL server closed the connection"
+        );
+        let line = line.trim_end_matches('\n');
+        match line {
+            "." => return Ok(lines.join("\n")),
+            line => lines.push(line.strip_prefix('.').unwrap_or(line).to_string()),
+        }
+    }
+}
+
+/// What a client sees for `source`.
+fn evaluate(source: &str, symbol_table: &SymbolTable) -> String {
+    match symbol_table.eval_source(source) {
+        Ok(value) => bounded_repr(&value, MAX_REPLY_BYTES),
+        Err(e) => format_error(&e, Some(symbol_table)).trim_end().to_string(),
+    }
+}
+
+impl ReplServer {
+    /// Where the server is listening, with the port it picked for port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The token clients have to give, for `x7 attach --token`.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Evaluate the forms clients have sent, in order, without waiting for
+    /// more. Returns how many were evaluated.
+    pub fn serve_pending(&self, symbol_table: &SymbolTable) -> usize {
+        let mut served = 0;
+        while let Ok(request) = self.requests.try_recv() {
+            self.answer(request, symbol_table);
+            served += 1;
+        }
+        served
+    }
+
+    /// Like `serve_pending`, first waiting up to `timeout` for a form if
+    /// none has been sent.
+    pub fn serve_for(&self, symbol_table: &SymbolTable, timeout: Duration) -> usize {
+        match self.requests.recv_timeout(timeout) {
+            Ok(request) => {
+                self.answer(request, symbol_table);
+                1 + self.serve_pending(symbol_table)
+            }
+            Err(_) => 0,
+        }
+    }
+
+    fn answer(&self, request: Request, symbol_table: &SymbolTable) {
+        // The client may have gone, which is no reason to stop serving.
+        let _ = request.reply.send(evaluate(&request.source, symbol_table));
+    }
+}
+
+impl Drop for ReplServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake the accepting thread so it sees it's stopped.
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+/// A connection to a served REPL.
+pub struct ReplClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl ReplClient {
+    /// Connect to the REPL at `addr`, giving `token`.
+    pub fn connect(addr: &str, token: &str) -> LispResult<ReplClient> {
+        let writer =
+            TcpStream::connect(addr).with_context(|| format!("Can't connect to {}", addr))?;
+        let mut client = ReplClient {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+        };
+        writeln!(client.writer, "auth {}", token)?;
+        match client.read()?.as_str() {
+            "ok" => Ok(client),
+            error => Err(anyhow!("{} refused the connection, {}", addr, error)),
+        }
+    }
+
+    /// Send one complete form, returning the printed result or error.
+    pub fn eval(&mut self, source: &str) -> LispResult<String> {
+        if is_incomplete(source) {
+            bail!("{:?} isn't a complete form", source);
+        }
+        writeln!(self.writer, "{}", source)?;
+        self.read()
+    }
+
+    fn read(&mut self) -> LispResult<String> {
+        read_reply(&mut self.reader)
+    }
+}
+
+/// `x7 attach`: a REPL on the terminal for the interpreter served at `addr`.
+pub fn attach(addr: &str, token: &str) -> LispResult<()> {
+    let mut client = ReplClient::connect(addr, token)?;
+    println!("Attached to {}", addr);
+    let mut rl = Editor::<()>::new();
+    let mut source = String::new();
+    loop {
+        let prompt = if source.is_empty() { ">>> " } else { "... " };
+        match rl.readline(prompt) {
+            Ok(line) => {
+                source.push_str(&line);
+                source.push('\n');
+                if is_incomplete(&source) {
+                    continue;
+                }
+                let form = mem::take(&mut source);
+                if form.trim().is_empty() {
+                    continue;
+                }
+                rl.add_history_entry(form.trim_end());
+                println!("{}", client.eval(form.trim_end())?);
+            }
+            Err(ReadlineError::Interrupted) if !source.is_empty() => source.clear(),
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Options;
+    use crate::stdlib::create_stdlib_symbol_table;
+
+    #[test]
+    fn clients_evaluate_on_the_interpreter_thread() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.eval_source("(def requests-seen 41)").unwrap();
+        let server = syms.serve_repl("127.0.0.1:0").unwrap();
+        let (addr, token) = (server.local_addr().to_string(), server.token().to_string());
+        let client = thread::spawn(move || {
+            let mut client = ReplClient::connect(&addr, &token).unwrap();
+            // Only one client at a time.
+            let busy = ReplClient::connect(&addr, &token)
+                .err()
+                .unwrap()
+                .to_string();
+            let replies = vec![
+                client.eval("(+ requests-seen\n   1)").unwrap(),
+                client.eval("(def attached true)").unwrap(),
+                client.eval("(head)").unwrap(),
+            ];
+            (busy, replies)
+        });
+        let mut served = 0;
+        while served < 3 {
+            served += server.serve_for(&syms, Duration::from_millis(50));
+        }
+        let (busy, replies) = client.join().unwrap();
+        assert!(busy.contains("another client is attached"), "{}", busy);
+        assert_eq!(replies[0], "42");
+        assert!(replies[2].starts_with("Error: "), "{}", replies[2]);
+        // Definitions land in the live interpreter.
+        assert_eq!(syms.eval_source("attached").unwrap(), true.into());
+    }
+
+    #[test]
+    fn bad_tokens_and_addresses_are_refused() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let server = syms.serve_repl("localhost:0").unwrap();
+        let addr = server.local_addr().to_string();
+        let err = ReplClient::connect(&addr, "guess")
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("bad token"), "{}", err);
+        // The refused client didn't keep the slot.
+        let mut client = ReplClient::connect(&addr, server.token()).unwrap();
+        let reply = thread::spawn(move || client.eval("(+ 1 1)").unwrap());
+        while server.serve_for(&syms, Duration::from_millis(50)) == 0 {}
+        assert_eq!(reply.join().unwrap(), "2");
+
+        assert!(syms.serve_repl("0.0.0.0:0").is_err());
+    }
+
+    #[test]
+    fn clients_which_never_authenticate_lose_the_slot() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let server = syms.serve_repl("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().to_string();
+
+        // An endless auth line is cut off and refused.
+        let mut flood = TcpStream::connect(&addr).unwrap();
+        flood
+            .write_all(format!("auth {}", "a".repeat(1000)).as_bytes())
+            .unwrap();
+        let reply = read_reply(&mut BufReader::new(&flood)).unwrap();
+        assert_eq!(reply, "error: bad token");
+        drop(flood);
+
+        // A silent one is dropped once the timeout passes.
+        let silent = TcpStream::connect(&addr).unwrap();
+        let err = read_reply(&mut BufReader::new(&silent)).unwrap_err();
+        assert!(err.to_string().contains("closed the connection"), "{}", err);
+
+        let mut client = ReplClient::connect(&addr, server.token()).unwrap();
+        let reply = thread::spawn(move || client.eval("(+ 1 1)").unwrap());
+        while server.serve_for(&syms, Duration::from_millis(50)) == 0 {}
+        assert_eq!(reply.join().unwrap(), "2");
+    }
+
+    #[test]
+    fn replies_keep_lines_starting_with_dots() {
+        let mut out = Vec::new();
+        write_reply(&mut out, "a\n.b\n..c").unwrap();
+        assert_eq!(String::from_utf8(out.clone()).unwrap(), "a\n..b\n...c\n.\n");
+        assert_eq!(read_reply(&mut &out[..]).unwrap(), "a\n.b\n..c");
+    }
+}
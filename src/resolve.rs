@@ -0,0 +1,471 @@
+use crate::stdlib::{has_unquote, to_data};
+use crate::symbols::{Expr, Function, LispResult, SymbolTable};
+use im::Vector;
+
+// With frozen globals, each top level form is resolved before it's evaluated:
+//
+//   - symbols in call position bound to builtins are replaced by the builtin,
+//     so `Expr::eval` calls it without a lookup
+//   - calls to arithmetic and comparison builtins with literal arguments are
+//     folded into their value
+//   - calls with too few arguments are errors now, instead of when reached
+//   - `data` forms without unquotes are replaced by the data
+//
+// Arguments of special forms (`eval_args` false) are only resolved for the
+// forms below, where we know which arguments are code and what they bind.
+// Anything bound by them, like function parameters, hides builtins of the
+// same name within its body.
+
+/// Builtins which always return the same literal for the same literal arguments.
+const FOLDABLE: &[&str] = &[
+    "+", "-", "*", "/", "%", "=", "<", "<=", ">", ">=", "inc", "not",
+];
+
+/// Resolve `form` against the builtins visible in `symbol_table`.
+pub(crate) fn resolve(form: &Expr, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    Resolver {
+        symbol_table,
+        bound: Vec::new(),
+    }
+    .resolve(form)
+}
+
+struct Resolver<'a> {
+    symbol_table: &'a SymbolTable,
+    // Names bound by enclosing forms, like function parameters.
+    bound: Vec<String>,
+}
+
+fn is_literal(expr: &Expr) -> bool {
+    matches!(expr, Expr::Num(_) | Expr::String(_) | Expr::Bool(_))
+}
+
+/// The symbols in a parameter or binding list.
+fn symbols_in(expr: &Expr) -> Vec<String> {
+    match expr {
+        Expr::List(l) => l
+            .iter()
+            .filter_map(|e| e.get_symbol_string().ok())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+impl Resolver<'_> {
+    fn builtin(&self, head: &Expr) -> Option<Function> {
+        match head {
+            Expr::Symbol(s) if !self.bound.contains(s) => self.symbol_table.builtin(s),
+            _ => None,
+        }
+    }
+
+    fn resolve(&mut self, form: &Expr) -> LispResult<Expr> {
+        let list = match form {
+            Expr::List(l) if !l.is_empty() => l,
+            _ => return Ok(form.clone()),
+        };
+        let head = &list[0];
+        let args = list.clone().slice(1..);
+        let f = match (self.builtin(head), head) {
+            (Some(f), _) => f,
+            // Like method calls, which the parser already resolved.
+            (None, Expr::Function(f)) => f.clone(),
+            (None, Expr::Symbol(s)) => {
+                // Functions defined by the program, or not defined yet, evaluate their
+                // arguments, unless they're a special form under another name.
+                let special = match self.symbol_table.lookup(head) {
+                    Ok(Expr::Function(f)) => !f.eval_args() && !self.bound.contains(s),
+                    _ => false,
+                };
+                if special {
+                    return Ok(form.clone());
+                }
+                return self.call(head.clone(), args);
+            }
+            (None, _) => {
+                let head = self.resolve(head)?;
+                return self.call(head, args);
+            }
+        };
+        let name = match head {
+            Expr::Symbol(s) => s.as_str(),
+            _ => "",
+        };
+        let static_arity = !f.eval_args() || args.iter().all(|a| a.get_spread_target().is_none());
+        if static_arity {
+            f.check_arity(&args)?;
+        }
+        if name == "data" && args.len() == 1 {
+            return self.data(f, &args[0]);
+        }
+        let args = if f.eval_args() {
+            self.all(&args)?
+        } else {
+            self.special_form_args(name, args)?
+        };
+        if FOLDABLE.contains(&name) && args.iter().all(is_literal) {
+            // Errors like dividing by zero are left for when the call is reached.
+            if let Ok(value) = f.call_with_values(args.clone(), self.symbol_table) {
+                if is_literal(&value) {
+                    return Ok(value);
+                }
+            }
+        }
+        let mut resolved = args;
+        resolved.push_front(Expr::Function(f));
+        Ok(Expr::List(resolved))
+    }
+
+    fn call(&mut self, head: Expr, args: Vector<Expr>) -> LispResult<Expr> {
+        let mut resolved = self.all(&args)?;
+        resolved.push_front(head);
+        Ok(Expr::List(resolved))
+    }
+
+    /// `(data form)`, which is constant unless it has unquotes. Otherwise
+    /// only the unquoted code is resolved.
+    fn data(&mut self, f: Function, form: &Expr) -> LispResult<Expr> {
+        if !has_unquote(form) {
+            // Errors, like odd dicts, are left for when the form is reached.
+            match to_data(form, self.symbol_table) {
+                // Evaluating a list would call it, but a quote gives it back.
+                Ok(Expr::List(l)) => return Ok(Expr::Quote(l)),
+                Ok(Expr::Symbol(_)) | Err(_) => {}
+                Ok(value) => return Ok(value),
+            }
+        }
+        let form = self.resolve_unquotes(form)?;
+        Ok(Expr::List(im::vector![Expr::Function(f), form]))
+    }
+
+    fn resolve_unquotes(&mut self, form: &Expr) -> LispResult<Expr> {
+        match form {
+            Expr::List(l) if l.len() == 2 && l[0] == Expr::Symbol("unquote".into()) => {
+                Ok(Expr::List(im::vector![l[0].clone(), self.resolve(&l[1])?]))
+            }
+            Expr::List(l) => Ok(Expr::List(
+                l.iter()
+                    .map(|e| self.resolve_unquotes(e))
+                    .collect::<LispResult<_>>()?,
+            )),
+            Expr::Quote(l) => Ok(Expr::Quote(
+                l.iter()
+                    .map(|e| self.resolve_unquotes(e))
+                    .collect::<LispResult<_>>()?,
+            )),
+            _ => Ok(form.clone()),
+        }
+    }
+
+    fn all(&mut self, forms: &Vector<Expr>) -> LispResult<Vector<Expr>> {
+        forms.iter().map(|form| self.resolve(form)).collect()
+    }
+
+    /// Resolve `form` with `names` bound.
+    fn resolve_binding(&mut self, names: Vec<String>, form: &Expr) -> LispResult<Expr> {
+        let depth = self.bound.len();
+        self.bound.extend(names);
+        let res = self.resolve(form);
+        self.bound.truncate(depth);
+        res
+    }
+
+    fn special_form_args(
+        &mut self,
+        name: &str,
+        mut args: Vector<Expr>,
+    ) -> LispResult<Vector<Expr>> {
+        // Malformed forms are left for the special form to complain about.
+        let last = args.len().saturating_sub(1);
+        match name {
+            "do" | "if" | "cond" => return self.all(&args),
+            "def" if args.len() == 2 => args[last] = self.resolve(&args[last])?,
+            // (with-location file line col form)
+            "with-location" if args.len() == 4 => args[last] = self.resolve(&args[last])?,
+            // (fn (params) [return-type] body)
+            "fn" if args.len() >= 2 => {
+                args[last] = self.resolve_binding(symbols_in(&args[0]), &args[last])?
+            }
+            // (defn name [doc] (params) [return-type] body)
+            "defn" if args.len() >= 3 => {
+                let params = match args.get(1) {
+                    Some(Expr::String(_)) => 2,
+                    _ => 1,
+                };
+                let names = args.get(params).map(symbols_in).unwrap_or_default();
+                args[last] = self.resolve_binding(names, &args[last])?;
+            }
+            // (dotimes (i n) body...)
+            "dotimes" => {
+                let mut binding = match args.front() {
+                    Some(Expr::List(l)) if l.len() == 2 => l.clone(),
+                    _ => return Ok(args),
+                };
+                binding[1] = self.resolve(&binding[1])?;
+                args[0] = Expr::List(binding.clone());
+                let names = symbols_in(&Expr::List(binding.take(1)));
+                for body in args.iter_mut().skip(1) {
+                    *body = self.resolve_binding(names.clone(), body)?;
+                }
+            }
+            // (bind (a 1 b (+ a 1)) body)
+            "bind" if args.len() == 2 => {
+                let mut bindings = match &args[0] {
+                    Expr::List(l) => l.clone(),
+                    _ => return Ok(args),
+                };
+                let names: Vec<String> = bindings
+                    .iter()
+                    .step_by(2)
+                    .filter_map(|e| e.get_symbol_string().ok())
+                    .collect();
+                for value in bindings.iter_mut().skip(1).step_by(2) {
+                    *value = self.resolve_binding(names.clone(), value)?;
+                }
+                args[0] = Expr::List(bindings);
+                args[last] = self.resolve_binding(names, &args[last])?;
+            }
+            // (let-values (((q r) (div-mod 17 5)) ((x & rest) (values 1 2 3))) body...)
+            "let-values" => {
+                let mut bindings = match args.front() {
+                    Some(Expr::List(l)) => l.clone(),
+                    _ => return Ok(args),
+                };
+                let names: Vec<String> = bindings
+                    .iter()
+                    .flat_map(|binding| match binding {
+                        Expr::List(pair) if pair.len() == 2 => symbols_in(&pair[0]),
+                        _ => Vec::new(),
+                    })
+                    .collect();
+                for binding in bindings.iter_mut() {
+                    if let Expr::List(pair) = binding {
+                        if pair.len() == 2 {
+                            pair[1] = self.resolve_binding(names.clone(), &pair[1])?;
+                        }
+                    }
+                }
+                args[0] = Expr::List(bindings);
+                for body in args.iter_mut().skip(1) {
+                    *body = self.resolve_binding(names.clone(), body)?;
+                }
+            }
+            // (with-open (f (fs::open "a") g (fs::open "b")) body...)
+            "with-open" => {
+                let mut bindings = match args.front() {
+                    Some(Expr::List(l)) => l.clone(),
+                    _ => return Ok(args),
+                };
+                let names: Vec<String> = bindings
+                    .iter()
+                    .step_by(2)
+                    .filter_map(|e| e.get_symbol_string().ok())
+                    .collect();
+                for value in bindings.iter_mut().skip(1).step_by(2) {
+                    *value = self.resolve_binding(names.clone(), value)?;
+                }
+                args[0] = Expr::List(bindings);
+                for body in args.iter_mut().skip(1) {
+                    *body = self.resolve_binding(names.clone(), body)?;
+                }
+            }
+            // (with-temp-file (f [:suffix s] [:keep true]) body...)
+            "with-temp-file" | "with-temp-dir" => {
+                let mut binding = match args.front() {
+                    Some(Expr::List(l)) if !l.is_empty() => l.clone(),
+                    _ => return Ok(args),
+                };
+                // Options are evaluated outside the binding.
+                for option in binding.iter_mut().skip(1) {
+                    *option = self.resolve(option)?;
+                }
+                args[0] = Expr::List(binding.clone());
+                let names = symbols_in(&Expr::List(binding.take(1)));
+                for body in args.iter_mut().skip(1) {
+                    *body = self.resolve_binding(names.clone(), body)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Options;
+    use crate::parser::read;
+    use crate::stdlib::create_stdlib_symbol_table;
+
+    fn frozen() -> SymbolTable {
+        create_stdlib_symbol_table(&Options {
+            frozen_globals: true,
+            ..Default::default()
+        })
+    }
+
+    fn resolved(prog: &str, syms: &SymbolTable) -> Expr {
+        resolve(&read(prog).next().unwrap().unwrap(), syms).unwrap()
+    }
+
+    #[test]
+    fn calls_hold_builtins() {
+        let syms = frozen();
+        let form = resolved("(map inc (range n))", &syms).get_list().unwrap();
+        assert!(matches!(&form[0], Expr::Function(f) if f.name() == "map"));
+        assert!(matches!(&form[1], Expr::Symbol(s) if s == "inc"));
+        let range = form[2].get_list().unwrap();
+        assert!(matches!(&range[0], Expr::Function(f) if f.name() == "range"));
+
+        // Parameters hide builtins, but only in their function's body.
+        let form = resolved("(fn (list) (list (len list)))", &syms)
+            .get_list()
+            .unwrap();
+        let body = form[2].get_list().unwrap();
+        assert_eq!(body[0], Expr::Symbol("list".into()));
+        assert!(matches!(&body[1].get_list().unwrap()[0], Expr::Function(_)));
+
+        // Quoted code is data.
+        let form = resolved("(eval '(len x))", &syms).get_list().unwrap();
+        assert_eq!(
+            form[1],
+            Expr::Quote(im::vector![
+                Expr::Symbol("len".into()),
+                Expr::Symbol("x".into())
+            ])
+        );
+    }
+
+    #[test]
+    fn constants_fold() {
+        let syms = frozen();
+        assert_eq!(resolved("(+ 1 (* 2 3))", &syms), Expr::from(7));
+        assert_eq!(resolved("(< 1 2)", &syms), Expr::Bool(true));
+        // Errors wait until the call is reached.
+        let form = resolved("(if false (/ 1 0) 2)", &syms).get_list().unwrap();
+        assert!(matches!(&form[2], Expr::List(_)));
+        assert_eq!(
+            syms.eval_source("(if false (/ 1 0) 2)").unwrap(),
+            Expr::from(2)
+        );
+    }
+
+    #[test]
+    fn arity_and_redefinition_are_checked() {
+        let syms = frozen();
+        let form = read("(defn f (x) (nth x))").next().unwrap().unwrap();
+        let err = resolve(&form, &syms).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Too few args supplied for #<fn nth"));
+        let err = syms.eval_source("(def map 3)").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot redefine the builtin map while globals are frozen"
+        );
+        // Programs can still shadow builtins with parameters.
+        assert_eq!(
+            syms.eval_source("((fn (map) (+ map 1)) 2)").unwrap(),
+            Expr::from(3)
+        );
+    }
+
+    /// The first call in the body of `prog`, a special form binding `len`.
+    fn body_call(prog: &str, syms: &SymbolTable) -> Vector<Expr> {
+        let form = resolved(prog, syms).get_list().unwrap();
+        form[2].get_list().unwrap()
+    }
+
+    #[test]
+    fn let_values_binds_its_formals() {
+        let syms = frozen();
+        let body = body_call("(let-values (((len n) (values 1 2))) (len n))", &syms);
+        assert_eq!(body[0], Expr::Symbol("len".into()));
+        let body = body_call("(let-values (((n) 1)) (len n))", &syms);
+        assert!(matches!(&body[0], Expr::Function(f) if f.name() == "len"));
+    }
+
+    #[test]
+    fn with_open_binds_its_names() {
+        let syms = frozen();
+        let prog = "(with-open (len (get files 0)) (len 1))";
+        assert_eq!(body_call(prog, &syms)[0], Expr::Symbol("len".into()));
+        // What's opened is resolved too.
+        let form = resolved(prog, &syms).get_list().unwrap();
+        let binding = form[1].get_list().unwrap();
+        assert!(matches!(
+            &binding[1].get_list().unwrap()[0],
+            Expr::Function(_)
+        ));
+    }
+
+    #[cfg(feature = "io")]
+    #[test]
+    fn with_temp_file_binds_its_name() {
+        let syms = frozen();
+        let prog = "(with-temp-file (len :suffix (str \".\" \"txt\")) (len 1))";
+        assert_eq!(body_call(prog, &syms)[0], Expr::Symbol("len".into()));
+        let form = resolved(prog, &syms).get_list().unwrap();
+        let binding = form[1].get_list().unwrap();
+        assert!(
+            matches!(&binding[2].get_list().unwrap()[0], Expr::Function(f) if f.name() == "str")
+        );
+    }
+
+    #[test]
+    fn data_without_unquotes_is_constant() {
+        let syms = frozen();
+        let prog = "(data ((port 8080) ^(a b) (dict \"k\" (x))))";
+        let form = resolved(prog, &syms);
+        assert!(matches!(&form, Expr::Quote(_)));
+        let plain = create_stdlib_symbol_table(&Options::default());
+        assert_eq!(form.eval(&syms).unwrap(), plain.eval_source(prog).unwrap());
+
+        // Unquotes are code, resolved like any other.
+        let form = resolved("(data (a ~(inc n)))", &syms).get_list().unwrap();
+        assert!(matches!(&form[0], Expr::Function(f) if f.name() == "data"));
+        let unquote = form[1].get_list().unwrap()[1].get_list().unwrap();
+        assert!(matches!(
+            &unquote[1].get_list().unwrap()[0],
+            Expr::Function(_)
+        ));
+    }
+
+    #[test]
+    fn same_results_either_way() {
+        let progs = [
+            "(defn fibo (n) (if (< n 2) n (+ (fibo (- n 1)) (fibo (- n 2))))) (fibo 15)",
+            "(reduce + 0 (filter even? (map (fn (x) (* x x)) (range 100))))",
+            "(bind (a 2 b (+ a 1)) (list a b (inc 1)))",
+            "(do (def total 0) (dotimes (i 4) (def total (+ total i))) total)",
+            "(cond false 1 (= 1 1) (str \"a\" 1))",
+            "(bind (n 2) (data (a ~(inc n) (data ^(b ~n)) (dict :c (d)))))",
+            "(let-values (((inc) (fn (x) (* x 10)))) (inc 2))",
+            // Local bindings hide builtins in their body, and only there.
+            "(list (bind (list 1 inc (+ list 1)) (+ list inc)) (list 3))",
+            "(list (let-values (((a & list) (values 1 2))) list) (list 3))",
+            "(do (defn f (& list) list) (list (f 1 2) (list 3)))",
+        ];
+        for prog in progs.iter() {
+            let plain = create_stdlib_symbol_table(&Options::default());
+            assert_eq!(
+                frozen().eval_source(prog).unwrap(),
+                plain.eval_source(prog).unwrap(),
+                "{}",
+                prog
+            );
+        }
+    }
+
+    #[cfg(feature = "io")]
+    #[test]
+    fn io_bindings_hide_builtins_either_way() {
+        let prog = "(list (with-temp-dir (list) (with-open (inc (fs::open (str list \"/f\"))) (.closed? inc))) (inc 1))";
+        let plain = create_stdlib_symbol_table(&Options::default());
+        assert_eq!(
+            frozen().eval_source(prog).unwrap(),
+            plain.eval_source(prog).unwrap()
+        );
+    }
+}
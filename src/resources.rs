@@ -0,0 +1,531 @@
+use crate::records::{Record, RecordType};
+use crate::symbols::{Expr, LispResult, ProgramError, SymbolTable};
+use anyhow::{anyhow, ensure};
+use im::Vector;
+use parking_lot::Mutex;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Resources a script still holds, e.g. after running untrusted code.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResourceReport {
+    /// Paths of files opened with `fs::open` and not yet closed.
+    pub open_files: Vec<String>,
+    /// Number of atoms still reachable from somewhere.
+    pub live_atoms: usize,
+    /// Threads the program started which are still running, like
+    /// "generator" or "watch-path notes.txt".
+    pub running_tasks: Vec<String>,
+}
+
+impl ResourceReport {
+    pub fn is_empty(&self) -> bool {
+        self.open_files.is_empty() && self.live_atoms == 0 && self.running_tasks.is_empty()
+    }
+}
+
+/// Gets a record back from its weak references, unless it was dropped.
+type Revive = Box<dyn Fn() -> Option<RecordType> + Send + Sync>;
+
+/// A thread working for the program, and the flag which asks it to stop.
+struct Worker {
+    description: String,
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+/// Weak references to resources created by an interpreter.
+///
+/// Records register here when they are made. Entries whose records were
+/// dropped are pruned as more register, and when reporting, so nothing here
+/// keeps a resource alive. Worker threads are held until they finish.
+#[derive(Default)]
+pub(crate) struct Resources {
+    // What each record holds, like a file's path, and how to reach it.
+    closeable: Vec<(String, Revive)>,
+    atoms: Vec<Weak<Mutex<Expr>>>,
+    workers: Vec<Worker>,
+    // How many entries there can be before registering prunes again.
+    prune_at: usize,
+}
+
+/// Registering prunes no more often than once per this many entries.
+const MIN_PRUNE_AT: usize = 64;
+
+/// How long `shutdown` waits for worker threads to stop.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl fmt::Debug for Resources {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Resources({} closeable, {} atoms, {} workers)",
+            self.closeable.len(),
+            self.atoms.len(),
+            self.workers.len()
+        )
+    }
+}
+
+impl Resources {
+    /// Track a record which holds something until it's closed.
+    #[cfg(feature = "io")]
+    pub(crate) fn register(
+        &mut self,
+        description: String,
+        revive: impl Fn() -> Option<RecordType> + Send + Sync + 'static,
+    ) {
+        self.closeable.push((description, Box::new(revive)));
+        self.prune_if_grown();
+    }
+
+    pub(crate) fn register_atom(&mut self, atom: &Arc<Mutex<Expr>>) {
+        self.atoms.push(Arc::downgrade(atom));
+        self.prune_if_grown();
+    }
+
+    /// Track a thread started for the program, which stops once `stop` is set.
+    pub(crate) fn register_worker(
+        &mut self,
+        description: String,
+        stop: Arc<AtomicBool>,
+        handle: JoinHandle<()>,
+    ) {
+        self.workers.push(Worker {
+            description,
+            stop,
+            handle,
+        });
+        self.prune_if_grown();
+    }
+
+    fn len(&self) -> usize {
+        self.closeable.len() + self.atoms.len() + self.workers.len()
+    }
+
+    /// Drop entries for records that are gone once the entries have doubled
+    /// since the last time, so registering stays cheap on average.
+    /// Closed records that are still alive are left for `report`, as checking
+    /// them takes their locks, which whoever is registering may hold.
+    fn prune_if_grown(&mut self) {
+        if self.len() < self.prune_at.max(MIN_PRUNE_AT) {
+            return;
+        }
+        self.closeable.retain(|(_, revive)| revive().is_some());
+        self.atoms.retain(|atom| atom.strong_count() > 0);
+        self.workers.retain(|worker| !worker.handle.is_finished());
+        self.prune_at = 2 * self.len();
+    }
+
+    fn prune(&mut self) {
+        self.closeable
+            .retain(|(_, revive)| revive().is_some_and(|r| !r.is_closed()));
+        self.atoms.retain(|atom| atom.strong_count() > 0);
+        self.workers.retain(|worker| !worker.handle.is_finished());
+    }
+
+    pub(crate) fn report(&mut self) -> ResourceReport {
+        self.prune();
+        ResourceReport {
+            open_files: self
+                .closeable
+                .iter()
+                .map(|(path, _)| path.clone())
+                .collect(),
+            live_atoms: self.atoms.len(),
+            running_tasks: self
+                .workers
+                .iter()
+                .map(|worker| worker.description.clone())
+                .collect(),
+        }
+    }
+
+    /// Close every open record and stop every worker, returning what was
+    /// still held beforehand. Waits up to `SHUTDOWN_TIMEOUT` for the workers
+    /// to finish; any still running after that stay in later reports.
+    pub(crate) fn shutdown(&mut self) -> ResourceReport {
+        let report = self.report();
+        for (_, revive) in self.closeable.drain(..) {
+            if let Some(record) = revive() {
+                // Shutting down closes what it can.
+                let _ = record.close();
+            }
+        }
+        for worker in &self.workers {
+            worker.stop.store(true, Ordering::SeqCst);
+        }
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        while self.workers.iter().any(|w| !w.handle.is_finished()) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let (finished, running): (Vec<Worker>, Vec<Worker>) = self
+            .workers
+            .drain(..)
+            .partition(|worker| worker.handle.is_finished());
+        self.workers = running;
+        for worker in finished {
+            // Workers report their own errors; there's nothing left to collect.
+            let _ = worker.handle.join();
+        }
+        report
+    }
+}
+
+/// (with-open (name resource ...) body...)
+pub(crate) fn with_open(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let bindings = exprs[0].get_list()?;
+    ensure!(
+        bindings.len() % 2 == 0 && bindings.iter().step_by(2).all(Expr::is_symbol),
+        "with-open expects bindings like (f (fs::open \"notes.txt\")), but was given {}",
+        exprs[0]
+    );
+    let mut opened: Vec<RecordType> = Vec::new();
+    let res = open_all(&bindings, &exprs, symbol_table, &mut opened);
+    // Close in reverse, even if opening or the body failed. The first error wins.
+    let closed = opened
+        .iter()
+        .rev()
+        .map(|record| record.close_in(symbol_table))
+        .fold(Ok(Expr::Nil), Result::and);
+    let value = res?;
+    closed?;
+    Ok(value)
+}
+
+fn open_all(
+    bindings: &Vector<Expr>,
+    exprs: &Vector<Expr>,
+    symbol_table: &SymbolTable,
+    opened: &mut Vec<RecordType>,
+) -> LispResult<Expr> {
+    let mut scope = symbol_table.clone();
+    for pair in bindings.clone().into_iter().collect::<Vec<_>>().chunks(2) {
+        let (name, value) = (&pair[0], pair[1].eval(&scope)?);
+        match value.unmeta() {
+            Expr::Record(record) if !record.is_closed() => opened.push(Clone::clone(record)),
+            other => {
+                return Err(anyhow!(
+                    "with-open can only bind open records, but {} is {}",
+                    name,
+                    other
+                ))
+            }
+        }
+        scope = scope.with_locals(std::slice::from_ref(name), Vector::unit(value))?;
+    }
+    exprs
+        .iter()
+        .skip(1)
+        .try_fold(Expr::Nil, |_, expr| expr.eval(&scope))
+}
+
+/// Roughly how many bytes `expr` takes up, not counting what its elements hold.
+pub(crate) fn shallow_bytes(expr: &Expr) -> usize {
+    let size = std::mem::size_of::<Expr>();
+    match expr {
+        Expr::String(s) => size + s.len(),
+        Expr::List(l) | Expr::Tuple(l) | Expr::Quote(l) => size + l.len() * size,
+        Expr::Dict(d) => size + d.len() * 2 * size,
+        _ => size,
+    }
+}
+
+/// Tallies the bytes of a value as a builtin builds it, erroring once they
+/// pass the interpreter's `max_value_bytes`. The bytes also count towards
+/// a running total for the top level form, held to the same limit, so many
+/// calls can't get around it either. The count is coarse: it is only meant
+/// to stop a program from exhausting memory.
+pub(crate) struct ValueBudget<'a> {
+    what: &'static str,
+    max: Option<usize>,
+    used: usize,
+    symbol_table: &'a SymbolTable,
+}
+
+impl<'a> ValueBudget<'a> {
+    pub(crate) fn new(what: &'static str, symbol_table: &'a SymbolTable) -> Self {
+        ValueBudget {
+            what,
+            max: symbol_table.host().max_value_bytes(),
+            used: 0,
+            symbol_table,
+        }
+    }
+
+    pub(crate) fn add(&mut self, bytes: usize) -> LispResult<()> {
+        let max = match self.max {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        self.used = self.used.saturating_add(bytes);
+        if self.used > max {
+            return Err(anyhow!(ProgramError::Resource).context(format!(
+                "{} would make a value of at least {} bytes, over the limit of {} bytes",
+                self.what, self.used, max
+            )));
+        }
+        let total = self.symbol_table.host_mut().add_built_bytes(bytes);
+        if total > max {
+            return Err(anyhow!(ProgramError::Resource).context(format!(
+                "{} would bring the values built by this form to at least {} bytes, \
+                 over the limit of {} bytes",
+                self.what, total, max
+            )));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn add_expr(&mut self, expr: &Expr) -> LispResult<()> {
+        self.add(shallow_bytes(expr))
+    }
+}
+
+/// Check before building it that a value of `bytes` bytes is allowed.
+pub(crate) fn check_value_bytes(
+    what: &'static str,
+    bytes: usize,
+    symbol_table: &SymbolTable,
+) -> LispResult<()> {
+    ValueBudget::new(what, symbol_table).add(bytes)
+}
+
+// The resources these tests leak and close are mostly files from fs::open.
+#[cfg(all(test, feature = "io"))]
+mod tests {
+    use crate::cli::Options;
+    use crate::parser::read;
+    use crate::records::Record;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use crate::symbols::{error_kind, Expr, SymbolTable};
+
+    fn eval(prog: &str, syms: &SymbolTable) {
+        for expr in read(prog) {
+            expr.unwrap().eval(syms).unwrap();
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn open_fds_to(path: &std::path::Path) -> usize {
+        std::fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|fd| std::fs::read_link(fd.ok()?.path()).ok())
+            .filter(|target| target == path)
+            .count()
+    }
+
+    #[test]
+    fn shutdown_closes_leaked_files() {
+        let path = std::env::temp_dir().join(format!("x7-leak-{}.txt", rand::random::<u64>()));
+        std::fs::write(&path, "leaked").unwrap();
+        let path = path.canonicalize().unwrap();
+        let path_str = path.to_str().unwrap();
+
+        let syms = create_stdlib_symbol_table(&Options::default());
+        eval(
+            &format!(
+                "(def f (fs::open \"{}\")) (def a (atom 1)) (atom 2) (.close (fs::open \"{}\"))",
+                path_str, path_str
+            ),
+            &syms,
+        );
+        let report = syms.resource_report();
+        assert_eq!(report.open_files, vec![path_str.to_string()]);
+        assert_eq!(report.live_atoms, 1);
+        #[cfg(target_os = "linux")]
+        assert_eq!(open_fds_to(&path), 1);
+
+        assert_eq!(syms.shutdown().open_files, vec![path_str.to_string()]);
+        assert_eq!(syms.resource_report().open_files, Vec::<String>::new());
+        // The record is still bound, but its handle is gone.
+        #[cfg(target_os = "linux")]
+        assert_eq!(open_fds_to(&path), 0);
+        let closed = read("(.read_to_string f)")
+            .next()
+            .unwrap()
+            .unwrap()
+            .eval(&syms);
+        assert!(format!("{:?}", closed.unwrap_err()).contains("is closed"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dropped_resources_are_pruned_as_more_register() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        eval("(def kept (atom 0))", &syms);
+        for _ in 0..1000 {
+            eval("(atom 1)", &syms);
+        }
+        assert!(syms.resources_mut().atoms.len() < 200);
+        assert_eq!(syms.resource_report().live_atoms, 1);
+    }
+
+    #[test]
+    fn max_value_bytes_guards_big_values() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.set_max_value_bytes(Some(1 << 20));
+        let eval = |prog: &str| syms.eval_source(prog);
+        let path = std::env::temp_dir().join(format!("x7-big-{}.txt", rand::random::<u64>()));
+        std::fs::write(&path, "x".repeat(2 << 20)).unwrap();
+        let small = std::env::temp_dir().join(format!("x7-small-{}.txt", rand::random::<u64>()));
+        std::fs::write(&small, "small").unwrap();
+        let read_big = format!("(.read_to_string (fs::open {:?}))", path);
+        let read_small = format!("(.read_to_string (fs::open {:?}))", small);
+
+        let too_big: [&str; 9] = [
+            "(* \"a\" 2000000)",
+            "(repeat-v 1000000000 \"a\")",
+            "(repeat 100 (* \"a\" 20000))",
+            "(times 100 (fn (i) (* \"a\" 20000)))",
+            "(range 0 1e9)",
+            "(doall (take 1000000000 (range)))",
+            "(str (* \"a\" 600000) (* \"a\" 600000))",
+            "(+ (* \"a\" 600000) (* \"a\" 600000))",
+            &read_big,
+        ];
+        for prog in too_big.iter() {
+            let err = eval(prog).unwrap_err();
+            assert_eq!(crate::symbols::error_kind(&err), "Resource", "{}", prog);
+        }
+        let fine: [&str; 9] = [
+            "(* \"a\" 3)",
+            "(repeat-v 3 \"a\")",
+            "(repeat 3 1)",
+            "(times 3 inc)",
+            "(range 0 10)",
+            "(doall (take 10 (range)))",
+            "(str \"a\" 1)",
+            "(+ \"a\" \"b\")",
+            &read_small,
+        ];
+        for prog in fine.iter() {
+            assert!(eval(prog).is_ok(), "{}", prog);
+        }
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&small).unwrap();
+
+        // Files without a length are cut off at the limit too.
+        #[cfg(target_os = "linux")]
+        {
+            let err = eval("(.read_to_string (fs::open \"/dev/zero\"))").unwrap_err();
+            assert_eq!(crate::symbols::error_kind(&err), "Resource");
+        }
+
+        // Values built over many calls count towards one total per top level
+        // form, as they can all be held at once.
+        let part = "(* \"a\" 300000)";
+        let err = eval(&format!("(def parts (list {0} {0} {0} {0}))", part)).unwrap_err();
+        assert_eq!(crate::symbols::error_kind(&err), "Resource");
+        assert!(eval("(def a (* \"a\" 600000)) (def b (* \"a\" 600000))").is_ok());
+    }
+
+    /// A new file in the temp dir, as an x7 string literal.
+    fn temp_file(name: &str) -> (std::path::PathBuf, String) {
+        let path = std::env::temp_dir().join(format!("x7-{}-{}.txt", name, rand::random::<u64>()));
+        std::fs::write(&path, "contents").unwrap();
+        let literal = format!("{:?}", path.to_str().unwrap());
+        (path, literal)
+    }
+
+    #[test]
+    fn resource_records_close_alike() {
+        let (path, literal) = temp_file("close");
+        // Each resource record, and a method which needs it open.
+        let resources = [
+            (
+                format!("(fs::open {})", literal),
+                "FileRecord",
+                "read_to_string",
+            ),
+            ("(progress-bar 3)".to_string(), "ProgressRecord", "tick"),
+        ];
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.host_mut().capture_output();
+        let eval = |prog: &str| {
+            let mut res = Ok(Expr::Nil);
+            for expr in read(prog) {
+                res = expr.unwrap().eval(&syms);
+            }
+            res
+        };
+        for (make, type_name, method) in resources.iter() {
+            eval(&format!("(def r {}) (def copy r)", make)).unwrap();
+            assert_eq!(eval("(.closed? r)").unwrap(), Expr::Bool(false), "{}", make);
+            assert!(eval(&format!("(.{} r)", method)).is_ok(), "{}", make);
+            assert_eq!(eval("(.close r)").unwrap(), Expr::Bool(true), "{}", make);
+            // Closing is shared with copies, and closing again does nothing.
+            assert_eq!(
+                eval("(.closed? copy)").unwrap(),
+                Expr::Bool(true),
+                "{}",
+                make
+            );
+            assert_eq!(
+                eval("(.close copy)").unwrap(),
+                Expr::Bool(false),
+                "{}",
+                make
+            );
+            match eval("r").unwrap() {
+                Expr::Record(r) => {
+                    assert!(r.is_closed());
+                    assert_eq!(r.close().unwrap(), Expr::Bool(false));
+                }
+                other => panic!("{} made {}", make, other),
+            }
+            let err = eval(&format!("(.{} r)", method)).unwrap_err();
+            assert_eq!(error_kind(&err), "Closed", "{}", make);
+            let message = format!("{:#}", err);
+            assert!(
+                message.contains(type_name) && message.contains(&format!(".{} ", method)),
+                "{}",
+                message
+            );
+        }
+        // Records which hold nothing can't be closed.
+        assert!(eval("(.close (atom 1))").is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn with_open_closes_on_the_way_out() {
+        let (path, literal) = temp_file("with-open");
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.host_mut().capture_output();
+        let eval = |prog: &str| {
+            let mut res = Ok(Expr::Nil);
+            for expr in read(prog) {
+                res = expr.unwrap().eval(&syms);
+            }
+            res
+        };
+        let prog = format!(
+            "(def kept (with-open (f (fs::open {}) p (progress-bar 1)) (list f p (.read_to_string f))))",
+            literal
+        );
+        eval(&prog).unwrap();
+        assert_eq!(
+            eval("(list (.closed? (nth 0 kept)) (.closed? (nth 1 kept)) (nth 2 kept))").unwrap(),
+            Expr::List(im::vector![
+                Expr::Bool(true),
+                Expr::Bool(true),
+                Expr::from("contents")
+            ])
+        );
+
+        let prog = format!(
+            "(def kept (atom nil)) (with-open (f (fs::open {})) (.reset kept f) (err \"boom\"))",
+            literal
+        );
+        assert!(eval(&prog).is_err());
+        assert_eq!(eval("(.closed? (.deref kept))").unwrap(), Expr::Bool(true));
+        assert!(syms.resource_report().open_files.is_empty());
+
+        assert!(eval("(with-open (x 1) x)").is_err());
+        assert!(eval("(with-open (f) f)").is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
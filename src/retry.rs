@@ -0,0 +1,313 @@
+use crate::exact_len;
+use crate::records::RateLimiterRecord;
+use crate::stdlib::register_builtins;
+use crate::symbols::{error_kind, Dict, Expr, LispResult, ProgramError, SymbolTable};
+use anyhow::{bail, ensure};
+use im::Vector;
+use itertools::Itertools;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// `(with-retries options expr)` evaluates `expr` until it succeeds, waiting
+// longer after each failure:
+//
+//   :attempts     how many times to evaluate expr, 3 by default
+//   :backoff      seconds to wait after the first failure, doubling after each, 0.1 by default
+//   :max-backoff  the longest wait, 10 seconds by default
+//   :jitter       wait a random 50-100% of each delay, so clients don't retry in lockstep
+//   :retry-on     the error kinds to retry, e.g. '(:io :error), which must be known kinds.
+//                 Every kind by default
+//
+// Error kinds are written in kebab case, so `:bad-types` matches BadTypes
+// errors, and `:error` matches errors made with `err`. Interrupts are never
+// retried.
+
+/// Sleep for `duration`, stopping early with an error if interrupted.
+pub(crate) fn sleep(symbol_table: &SymbolTable, duration: Duration) -> LispResult<()> {
+    let deadline = Instant::now() + duration;
+    loop {
+        if symbol_table.is_interrupted() {
+            bail!(ProgramError::Interrupted);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(());
+        }
+        thread::sleep((deadline - now).min(Duration::from_millis(50)));
+    }
+}
+
+/// The kinds errors can have, including Error for those made with `err`.
+fn error_kinds() -> impl Iterator<Item = &'static str> {
+    ProgramError::KINDS
+        .iter()
+        .copied()
+        .chain(std::iter::once("Error"))
+}
+
+/// The keyword naming the error kind `kind`, like `:bad-types` for BadTypes.
+fn kind_keyword(kind: &str) -> String {
+    let mut keyword = String::from(":");
+    for (i, c) in kind.chars().enumerate() {
+        if i > 0 && c.is_uppercase() {
+            keyword.push('-');
+        }
+        keyword.extend(c.to_lowercase());
+    }
+    keyword
+}
+
+/// Whether `keyword`, like `:bad-types`, names the error kind `kind`, like BadTypes.
+fn kind_matches(keyword: &str, kind: &str) -> bool {
+    keyword
+        .trim_start_matches(':')
+        .replace('-', "")
+        .eq_ignore_ascii_case(kind)
+}
+
+#[derive(Debug)]
+struct RetryPolicy {
+    attempts: usize,
+    backoff: Duration,
+    max_backoff: Duration,
+    jitter: bool,
+    retry_on: Option<Vec<String>>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            jitter: false,
+            retry_on: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn from_dict(options: &Dict) -> LispResult<Self> {
+        let mut policy = RetryPolicy::default();
+        for (key, value) in options.iter() {
+            match key.get_symbol_string()?.as_str() {
+                ":attempts" => policy.attempts = value.get_usize()?,
+                ":backoff" => policy.backoff = value.get_seconds(":backoff")?,
+                ":max-backoff" => policy.max_backoff = value.get_seconds(":max-backoff")?,
+                ":jitter" => policy.jitter = value.get_bool()?,
+                ":retry-on" => {
+                    let kinds: Vec<String> = value
+                        .get_list()?
+                        .iter()
+                        .map(Expr::get_symbol_string)
+                        .collect::<LispResult<_>>()?;
+                    for kind in kinds.iter() {
+                        ensure!(
+                            error_kinds().any(|known| kind_matches(kind, known)),
+                            "with-retries can't retry {}, the error kinds are {}",
+                            kind,
+                            error_kinds().map(kind_keyword).join(" ")
+                        );
+                    }
+                    policy.retry_on = Some(kinds);
+                }
+                other => bail!(
+                    "with-retries has no option {}, it knows :attempts :backoff :max-backoff :jitter :retry-on",
+                    other
+                ),
+            }
+        }
+        ensure!(policy.attempts > 0, "with-retries needs at least 1 attempt");
+        Ok(policy)
+    }
+
+    fn should_retry(&self, err: &anyhow::Error) -> bool {
+        let kind = error_kind(err);
+        if kind == ProgramError::Interrupted.kind() {
+            return false;
+        }
+        match &self.retry_on {
+            Some(kinds) => kinds.iter().any(|k| kind_matches(k, kind)),
+            None => true,
+        }
+    }
+
+    /// How long to wait after the `failures`th failure.
+    fn delay(&self, failures: usize) -> Duration {
+        // Past 2^31 doublings the cap has long since been reached.
+        let doublings = failures.saturating_sub(1).min(31) as u32;
+        let delay = self
+            .backoff
+            .checked_mul(1 << doublings)
+            .map_or(self.max_backoff, |delay| delay.min(self.max_backoff));
+        if self.jitter {
+            delay.mul_f64(0.5 + rand::random::<f64>() / 2.0)
+        } else {
+            delay
+        }
+    }
+}
+
+/// (with-retries options expr)
+pub(crate) fn with_retries(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let policy = RetryPolicy::from_dict(&exprs[0].eval(symbol_table)?.get_dict()?)?;
+    let mut failures = 0;
+    loop {
+        let err = match exprs[1].eval(symbol_table) {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+        failures += 1;
+        if !policy.should_retry(&err) {
+            return Err(err);
+        }
+        if failures == policy.attempts {
+            return Err(err.context(format!("with-retries gave up after {} attempts", failures)));
+        }
+        sleep(symbol_table, policy.delay(failures))?;
+    }
+}
+
+pub(crate) fn register(syms: &SymbolTable) {
+    register_builtins(
+        syms,
+        &[
+            (
+                "rate-limiter",
+                "records",
+                2,
+                RateLimiterRecord::from_x7,
+                true,
+                "Make a record whose .acquire waits as needed to allow at most n calls every per-seconds.
+Calling the record, (limiter f args...), does the same and then calls f.
+Example:
+(def limiter (rate-limiter 5 1))
+(.acquire limiter) ; at most 5 of these a second
+(limiter + 1 2) ; 3
+",
+            ),
+            (
+                "with-retries",
+                "control",
+                2,
+                with_retries,
+                false,
+                "Evaluate an expression, evaluating it again if it fails, waiting longer after each failure.
+Options are :attempts (3), :backoff seconds (0.1) doubling after each failure up to :max-backoff (10),
+:jitter to wait a random 50-100% of each delay, and :retry-on, a list of error kinds to retry.
+Kinds are kebab case, like :io or :bad-types, and errors from `err` are :error. Naming a kind no error has is an error.
+Every kind but interrupts is retried by default.
+Once out of attempts, the last error is raised.
+Example:
+(with-retries (dict :attempts 5 :backoff 0.2 :max-backoff 5 :jitter true :retry-on '(:io))
+  (read-config \"settings.x7\"))
+",
+            ),
+        ],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Options;
+    use crate::parser::read;
+    use crate::stdlib::create_stdlib_symbol_table;
+
+    fn eval(prog: &str, syms: &SymbolTable) -> LispResult<Expr> {
+        let mut res = Expr::Nil;
+        for expr in read(prog) {
+            res = expr?.eval(syms)?;
+        }
+        Ok(res)
+    }
+
+    fn gave_up(err: &anyhow::Error) -> bool {
+        err.chain()
+            .any(|e| e.to_string().starts_with("with-retries gave up"))
+    }
+
+    #[test]
+    fn retries_matching_errors() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let flaky = "(def calls (atom 0))
+                     (defn flaky (fails)
+                       (do (.reset calls (inc (.deref calls)))
+                           (if (<= (.deref calls) fails) (err \"flaky\") (.deref calls))))";
+        eval(flaky, &syms).unwrap();
+        let calls = |syms: &SymbolTable| eval("(.deref calls)", syms).unwrap();
+
+        let res = eval(
+            "(with-retries (dict :attempts 5 :backoff 0) (flaky 2))",
+            &syms,
+        );
+        assert_eq!(res.unwrap(), Expr::from(3));
+        assert_eq!(calls(&syms), Expr::from(3));
+
+        eval("(.reset calls 0)", &syms).unwrap();
+        let err = eval(
+            "(with-retries (dict :attempts 2 :backoff 0 :retry-on '(:error)) (flaky 10))",
+            &syms,
+        )
+        .unwrap_err();
+        assert_eq!(err.root_cause().to_string(), "flaky");
+        assert!(gave_up(&err));
+        assert_eq!(calls(&syms), Expr::from(2));
+
+        // Other kinds of errors are raised straight away.
+        eval("(.reset calls 0)", &syms).unwrap();
+        let err = eval(
+            "(with-retries (dict :attempts 5 :backoff 0 :retry-on '(:io)) (flaky 10))",
+            &syms,
+        )
+        .unwrap_err();
+        assert_eq!(err.root_cause().to_string(), "flaky");
+        assert!(!gave_up(&err));
+        assert_eq!(calls(&syms), Expr::from(1));
+
+        let io = "(.reset calls 0)
+                  (with-retries (dict :attempts 3 :backoff 0 :retry-on '(:io :permission))
+                    (do (flaky 0) (append-file \"/no/such/dir/x7-retry.txt\" \"x\")))";
+        let err = eval(io, &syms).unwrap_err();
+        assert_eq!(error_kind(&err), "Io");
+        assert!(gave_up(&err));
+        assert_eq!(calls(&syms), Expr::from(3));
+
+        assert!(eval("(with-retries (dict :attempts 0) 1)", &syms).is_err());
+        assert!(eval("(with-retries (dict :bogus 1) 1)", &syms).is_err());
+        assert!(eval("(with-retries (dict :backoff -1) 1)", &syms).is_err());
+        let err = eval("(with-retries (dict :retry-on '(:timeout)) 1)", &syms).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("can't retry :timeout, the error kinds are :bad-types"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let policy = RetryPolicy {
+            backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(1),
+            ..RetryPolicy::default()
+        };
+        let delays: Vec<u128> = (1..=5).map(|n| policy.delay(n).as_millis()).collect();
+        assert_eq!(delays, vec![200, 400, 800, 1000, 1000]);
+        assert_eq!(policy.delay(usize::MAX), Duration::from_secs(1));
+
+        let jittered = RetryPolicy {
+            jitter: true,
+            ..policy
+        };
+        for _ in 0..100 {
+            let delay = jittered.delay(2);
+            assert!(delay >= Duration::from_millis(200) && delay <= Duration::from_millis(400));
+        }
+        assert!(kind_matches(":bad-types", "BadTypes"));
+        assert!(!kind_matches(":io", "Error"));
+        for kind in error_kinds() {
+            assert!(kind_matches(&kind_keyword(kind), kind), "{}", kind);
+        }
+    }
+}
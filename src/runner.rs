@@ -0,0 +1,285 @@
+use crate::cli::Options;
+use crate::history::Evaluation;
+use crate::host::Warning;
+use crate::metrics::ProgramLimits;
+use crate::resources::ResourceReport;
+use crate::stdlib::create_stdlib_symbol_table;
+use crate::symbols::{error_kind, Expr, LispResult, SymbolTable};
+use crate::throw::headline;
+use im::Vector;
+use parking_lot::Mutex;
+use std::cell::RefCell;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How to run a script with `run_script`.
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// Bound to `script-args` as a list of strings.
+    pub args: Vec<String>,
+    /// What `read-stdin` returns. The real stdin is used when `None`.
+    pub stdin: Option<String>,
+    /// Interrupt the script if it runs longer than this.
+    pub timeout: Option<Duration>,
+    /// Deny file access (`fs::open`, loading modules from files).
+    pub sandbox: bool,
+    /// Make warnings errors, like `--deny-warnings`.
+    pub deny_warnings: bool,
+    /// Make calling deprecated builtins errors, like `--deny-deprecated`.
+    pub deny_deprecated: bool,
+    /// Make sequence builtins error on nil, like `--strict-nil`.
+    pub strict_nil: bool,
+    /// Resolve calls to builtins ahead of time, like `--frozen-globals`.
+    pub frozen_globals: bool,
+    /// Fail with a "Resource" error when a builtin would build a value
+    /// bigger than roughly this many bytes. Unlimited when `None`.
+    pub max_value_bytes: Option<usize>,
+    /// Fail with a "Resource" error, before evaluating anything, when the
+    /// source is over any of these limits. See `analyze`.
+    pub max_program_metrics: Option<ProgramLimits>,
+    /// Keep this many of the latest top level forms, for `RunError::recent`.
+    /// None are kept by default.
+    pub history: usize,
+}
+
+/// A structured error from a failed script.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunError {
+    /// The kind of error, e.g. "BadTypes", "Interrupted", or "Error" for custom errors.
+    pub kind: String,
+    /// The innermost error message.
+    pub message: String,
+    /// The remaining error context, innermost first.
+    pub stacktrace: Vec<String>,
+    /// The top level forms evaluated before the error, oldest first, ending
+    /// with the one which failed. Empty unless `RunOptions::history` is set.
+    pub recent: Vec<Evaluation>,
+}
+
+/// Everything observable about a finished script.
+#[derive(Debug, Clone)]
+pub struct RunOutcome {
+    /// The debug representation of the last form evaluated, if the script succeeded.
+    pub value: Option<String>,
+    pub stdout: String,
+    pub stderr: String,
+    /// Warnings emitted by the script. These are not also written to `stderr`.
+    pub warnings: Vec<Warning>,
+    /// Resources the script left open. They are closed before `run_script` returns.
+    pub leaked: ResourceReport,
+    pub error: Option<RunError>,
+    pub duration: Duration,
+}
+
+impl RunOutcome {
+    pub fn success(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// The exit status the `x7` binary would use for this outcome.
+    pub fn exit_status(&self) -> i32 {
+        if self.success() {
+            0
+        } else {
+            1
+        }
+    }
+}
+
+impl RunError {
+    fn from_error(err: &anyhow::Error, symbol_table: &SymbolTable) -> RunError {
+        let kind = error_kind(err);
+        RunError {
+            kind: kind.into(),
+            message: headline(err, Some(symbol_table)),
+            stacktrace: err.chain().rev().skip(1).map(|e| e.to_string()).collect(),
+            recent: symbol_table.recent_evaluations(),
+        }
+    }
+}
+
+/// Run an x7 script in a fresh interpreter, capturing its output.
+///
+/// Each call builds its own interpreter, so scripts can't observe each other
+/// and it is safe to call this from many threads at once.
+pub fn run_script<P: AsRef<Path>>(path: P, opts: RunOptions) -> RunOutcome {
+    let source = std::fs::read_to_string(path.as_ref())
+        .map_err(|e| anyhow::anyhow!("Could not read {}, {}", path.as_ref().display(), e));
+    let file = path.as_ref().display().to_string();
+    run(source, &file, opts, Arc::default())
+}
+
+/// Like `run_script`, but for x7 source in a string. Warnings cite it as
+/// `<source>`.
+pub fn run_source(source: &str, opts: RunOptions) -> RunOutcome {
+    run(Ok(source.into()), "<source>", opts, Arc::default())
+}
+
+/// Like `run_source`, but evaluated on a dedicated thread, as a `Future`
+/// which async hosts can await without blocking their executor.
+///
+/// Dropping the future, e.g. when a timeout around it expires, interrupts
+/// the script and waits for its thread to exit. Scripts notice interrupts
+/// at their next function call, so one stuck in a long builtin is waited on
+/// for `DROP_TIMEOUT` at most, after which its thread is left to finish.
+pub fn run_source_async(source: &str, opts: RunOptions) -> RunFuture {
+    let shared: Arc<Mutex<FutureState>> = Arc::default();
+    let interrupt: Arc<AtomicBool> = Arc::default();
+    let worker = {
+        let (source, shared, interrupt) = (source.to_string(), shared.clone(), interrupt.clone());
+        thread::spawn(move || {
+            let outcome = run(Ok(source), "<source>", opts, interrupt);
+            let mut state = shared.lock();
+            state.outcome = Some(outcome);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        })
+    };
+    RunFuture {
+        shared,
+        interrupt,
+        worker: Some(worker),
+    }
+}
+
+/// Longest dropping a `RunFuture` waits for its script to stop.
+const DROP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct FutureState {
+    outcome: Option<RunOutcome>,
+    waker: Option<Waker>,
+}
+
+/// The outcome of a script running on its own thread, made with `run_source_async`.
+pub struct RunFuture {
+    shared: Arc<Mutex<FutureState>>,
+    interrupt: Arc<AtomicBool>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl Future for RunFuture {
+    type Output = RunOutcome;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<RunOutcome> {
+        let mut state = self.shared.lock();
+        match state.outcome.take() {
+            Some(outcome) => Poll::Ready(outcome),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for RunFuture {
+    fn drop(&mut self) {
+        // Harmless if the script already finished.
+        self.interrupt.store(true, Ordering::SeqCst);
+        let worker = match self.worker.take() {
+            Some(worker) => worker,
+            None => return,
+        };
+        let deadline = Instant::now() + DROP_TIMEOUT;
+        while !worker.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        if worker.is_finished() {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn run(
+    source: LispResult<String>,
+    file: &str,
+    opts: RunOptions,
+    interrupt: Arc<AtomicBool>,
+) -> RunOutcome {
+    let start = Instant::now();
+    let mut symbol_table = create_stdlib_symbol_table(&Options::default());
+    symbol_table.set_interrupt_handle(interrupt);
+    let warnings = Rc::new(RefCell::new(Vec::new()));
+    symbol_table.set_sandboxed(opts.sandbox);
+    symbol_table.set_deny_warnings(opts.deny_warnings);
+    symbol_table.set_deny_deprecated(opts.deny_deprecated);
+    symbol_table.set_strict_nil(opts.strict_nil);
+    symbol_table.set_frozen_globals(opts.frozen_globals);
+    symbol_table.set_max_value_bytes(opts.max_value_bytes);
+    symbol_table.set_max_program_metrics(opts.max_program_metrics);
+    symbol_table.set_history_size(opts.history);
+    let sink = warnings.clone();
+    symbol_table.on_warning(move |w| sink.borrow_mut().push(w.clone()));
+    {
+        let mut host = symbol_table.host_mut();
+        host.capture_output();
+        if let Some(stdin) = opts.stdin {
+            host.set_stdin(stdin);
+        }
+    }
+    let args: Vector<Expr> = opts.args.into_iter().map(Expr::String).collect();
+    // Can't fail, script-args is a symbol.
+    let _ = symbol_table.add_local(&Expr::Symbol("script-args".into()), &Expr::List(args));
+
+    // The watchdog interrupts the script unless we finish first and hang up.
+    let (done, done_rx) = mpsc::channel::<()>();
+    let watchdog = opts.timeout.map(|timeout| {
+        let interrupt = symbol_table.interrupt_handle();
+        thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = done_rx.recv_timeout(timeout) {
+                interrupt.store(true, Ordering::SeqCst);
+            }
+        })
+    });
+
+    let result = source.and_then(|source| {
+        symbol_table.host_mut().set_program(&source);
+        symbol_table.eval_script(&source, file)
+    });
+
+    drop(done);
+    if let Some(watchdog) = watchdog {
+        let _ = watchdog.join();
+    }
+
+    let leaked = symbol_table.shutdown();
+    let (stdout, stderr) = symbol_table.host_mut().take_captured_output();
+    let (value, error) = match result {
+        Ok(value) => (Some(format!("{:?}", value)), None),
+        Err(e) => (None, Some(RunError::from_error(&e, &symbol_table))),
+    };
+    let warnings = warnings.take();
+    RunOutcome {
+        value,
+        stdout,
+        stderr,
+        warnings,
+        leaked,
+        error,
+        duration: start.elapsed(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dropping_the_future_ends_its_thread() {
+        let slow = run_source_async("(foreach ident (range))", RunOptions::default());
+        // The thread holds the shared state until it exits.
+        let shared = Arc::downgrade(&slow.shared);
+        drop(slow);
+        assert!(shared.upgrade().is_none());
+    }
+}
@@ -0,0 +1,416 @@
+use crate::exact_len;
+use crate::json::{to_serde, JSON};
+use crate::pretty::PrettyOptions;
+use crate::stdlib::register_builtins;
+use crate::symbols::{Expr, LispResult, SymbolTable};
+use anyhow::anyhow;
+use im::Vector;
+use serde_json::Value;
+
+#[cfg(any(feature = "toml", feature = "yaml"))]
+use crate::json::SerdeFormat;
+#[cfg(any(feature = "toml", feature = "yaml"))]
+use serde_json::Number;
+
+// Parsing and serializing JSON, TOML, and YAML. Every format is converted
+// through serde_json values, so they share the rules in json.rs: maps become
+// dicts keyed by strings, and numbers become nums exactly when the format
+// writes them exactly.
+
+/// (json-parse s)
+fn json_parse(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let value: Value = serde_json::from_str(&exprs[0].get_string()?)
+        .map_err(|e| anyhow!("Could not parse JSON, {}", e))?;
+    Ok(Expr::from_json(&value))
+}
+
+/// `value` with the entries of its objects in key order.
+fn sort_keys(value: Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<(String, Value)> = map.into_iter().collect();
+            entries.sort_by(|(l, _), (r, _)| l.cmp(r));
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (k, sort_keys(v)))
+                    .collect(),
+            )
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(sort_keys).collect()),
+        other => other,
+    }
+}
+
+/// (json-serialize v [:pretty bool] [:sort-keys bool])
+fn json_serialize(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let options = PrettyOptions::parse(
+        "json-serialize",
+        &exprs.clone().slice(1..),
+        &[":pretty", ":sort-keys"],
+    )?;
+    let mut value = to_serde(&exprs[0], &JSON, &mut Vec::new())?;
+    if options.sort_keys {
+        value = sort_keys(value);
+    }
+    if options.pretty {
+        let pretty = serde_json::to_string_pretty(&value)
+            .map_err(|e| anyhow!("Could not write JSON, {}", e))?;
+        return Ok(Expr::String(pretty));
+    }
+    Ok(Expr::String(value.to_string()))
+}
+
+#[cfg(any(feature = "toml", feature = "yaml"))]
+fn float(f: f64, format: &SerdeFormat) -> LispResult<Value> {
+    Number::from_f64(f)
+        .map(Value::Number)
+        .ok_or_else(|| anyhow!("Cannot represent the {} number {} in x7", format.name, f))
+}
+
+#[cfg(feature = "toml")]
+const TOML: SerdeFormat = SerdeFormat {
+    name: "TOML",
+    has_null: false,
+};
+
+#[cfg(feature = "toml")]
+fn toml_to_json(value: toml_crate::Value) -> LispResult<Value> {
+    use toml_crate::Value as Toml;
+    let value = match value {
+        Toml::String(s) => Value::String(s),
+        Toml::Integer(i) => Value::from(i),
+        Toml::Float(f) => float(f, &TOML)?,
+        Toml::Boolean(b) => Value::Bool(b),
+        // x7 has no dates, so they stay as written.
+        Toml::Datetime(d) => Value::String(d.to_string()),
+        Toml::Array(items) => Value::Array(
+            items
+                .into_iter()
+                .map(toml_to_json)
+                .collect::<LispResult<_>>()?,
+        ),
+        Toml::Table(table) => Value::Object(
+            table
+                .into_iter()
+                .map(|(k, v)| Ok((k, toml_to_json(v)?)))
+                .collect::<LispResult<_>>()?,
+        ),
+    };
+    Ok(value)
+}
+
+/// (toml-parse s)
+#[cfg(feature = "toml")]
+fn toml_parse(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let value: toml_crate::Value = toml_crate::from_str(&exprs[0].get_string()?)
+        .map_err(|e| anyhow!("Could not parse TOML, {}", e))?;
+    Ok(Expr::from_json(&toml_to_json(value)?))
+}
+
+/// (toml-serialize v)
+#[cfg(feature = "toml")]
+fn toml_serialize(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    anyhow::ensure!(
+        matches!(exprs[0], Expr::Dict(_)),
+        "toml-serialize needs a dict, but was given {}",
+        exprs[0].get_type_str()
+    );
+    let value = to_serde(&exprs[0], &TOML, &mut Vec::new())?;
+    // Going through toml's own values puts plain keys before tables, as TOML requires.
+    let toml = toml_crate::Value::try_from(value)
+        .and_then(|v| toml_crate::to_string(&v))
+        .map_err(|e| anyhow!("Could not serialize TOML, {}", e))?;
+    Ok(Expr::String(toml))
+}
+
+#[cfg(feature = "yaml")]
+const YAML: SerdeFormat = SerdeFormat {
+    name: "YAML",
+    has_null: true,
+};
+
+#[cfg(feature = "yaml")]
+fn yaml_key(key: serde_yaml::Value) -> LispResult<String> {
+    use serde_yaml::Value as Yaml;
+    match key {
+        Yaml::String(s) => Ok(s),
+        Yaml::Number(n) => Ok(n.to_string()),
+        Yaml::Bool(b) => Ok(b.to_string()),
+        Yaml::Null => Ok("null".into()),
+        rest => anyhow::bail!(
+            "YAML keys must be scalars to become dict keys, but got {:?}",
+            rest
+        ),
+    }
+}
+
+/// Anchors and aliases were already expanded by serde_yaml.
+#[cfg(feature = "yaml")]
+fn yaml_to_json(value: serde_yaml::Value) -> LispResult<Value> {
+    use serde_yaml::Value as Yaml;
+    let value = match value {
+        Yaml::Null => Value::Null,
+        Yaml::Bool(b) => Value::Bool(b),
+        Yaml::Number(n) => match (n.as_i64(), n.as_u64(), n.as_f64()) {
+            (Some(i), _, _) => Value::from(i),
+            (_, Some(u), _) => Value::from(u),
+            (_, _, Some(f)) => float(f, &YAML)?,
+            _ => unreachable!("YAML numbers are i64, u64, or f64"),
+        },
+        Yaml::String(s) => Value::String(s),
+        Yaml::Sequence(items) => Value::Array(
+            items
+                .into_iter()
+                .map(yaml_to_json)
+                .collect::<LispResult<_>>()?,
+        ),
+        Yaml::Mapping(map) => Value::Object(
+            map.into_iter()
+                .map(|(k, v)| Ok((yaml_key(k)?, yaml_to_json(v)?)))
+                .collect::<LispResult<_>>()?,
+        ),
+    };
+    Ok(value)
+}
+
+/// (yaml-parse s)
+#[cfg(feature = "yaml")]
+fn yaml_parse(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let value: serde_yaml::Value = serde_yaml::from_str(&exprs[0].get_string()?)
+        .map_err(|e| anyhow!("Could not parse YAML, {}", e))?;
+    Ok(Expr::from_json(&yaml_to_json(value)?))
+}
+
+/// (yaml-serialize v)
+#[cfg(feature = "yaml")]
+fn yaml_serialize(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let value = to_serde(&exprs[0], &YAML, &mut Vec::new())?;
+    serde_yaml::to_string(&value)
+        .map(Expr::String)
+        .map_err(|e| anyhow!("Could not serialize YAML, {}", e))
+}
+
+pub(crate) fn register(syms: &SymbolTable) {
+    register_builtins(
+        syms,
+        &[
+            (
+                "json-parse",
+                "data",
+                1,
+                json_parse,
+                true,
+                "Parse a JSON string. Objects become dicts with string keys, and null becomes nil.
+Example:
+(json-parse \"[1, 2.5, null]\") ; (1 2.5 nil)
+(get (json-parse (json-serialize (dict :port 8080))) \"port\") ; 8080
+",
+            ),
+            (
+                "json-serialize",
+                "data",
+                1,
+                json_serialize,
+                true,
+                "Write a value as a JSON string. Dict keys must be strings or keywords.
+`:pretty true` indents the JSON, and `:sort-keys true` writes object keys in order,
+so the output is the same for equal values.
+Example:
+(json-serialize (dict :port 8080)) ; \"{\\\"port\\\":8080}\"
+(json-serialize (dict :b 1 :a 2) :sort-keys true) ; \"{\\\"a\\\":2,\\\"b\\\":1}\"
+",
+            ),
+        ],
+    );
+    #[cfg(feature = "toml")]
+    register_builtins(
+        syms,
+        &[
+            (
+                "toml-parse",
+                "data",
+                1,
+                toml_parse,
+                true,
+                "Parse a TOML string into a dict with string keys. Dates are kept as strings.
+Example:
+(get (toml-parse \"port = 8080\") \"port\") ; 8080
+",
+            ),
+            (
+                "toml-serialize",
+                "data",
+                1,
+                toml_serialize,
+                true,
+                "Write a dict as a TOML string. TOML has no nil, so nils are errors.
+Example:
+(toml-serialize (dict :package (dict :name \"x7\"))) ; \"[package]\\nname = \\\"x7\\\"\\n\"
+",
+            ),
+        ],
+    );
+    #[cfg(feature = "yaml")]
+    register_builtins(
+        syms,
+        &[
+            (
+                "yaml-parse",
+                "data",
+                1,
+                yaml_parse,
+                true,
+                "Parse a YAML string. Mappings become dicts with string keys, and aliases are expanded.
+Example:
+(get (yaml-parse \"jobs: [build, test]\") \"jobs\") ; (\"build\" \"test\")
+",
+            ),
+            (
+                "yaml-serialize",
+                "data",
+                1,
+                yaml_serialize,
+                true,
+                "Write a value as a YAML string.
+Example:
+(yaml-serialize (dict :jobs (list \"build\" \"test\"))) ; \"---\\njobs:\\n  - build\\n  - test\\n\"
+",
+            ),
+        ],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::Options;
+    use crate::parser::read;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use crate::symbols::{Expr, LispResult};
+
+    /// Evaluate `prog` with `doc` bound to the given document.
+    fn eval(doc: &str, prog: &str) -> LispResult<Expr> {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.add_global("doc", Expr::String(doc.into()));
+        let mut res = Expr::Nil;
+        for expr in read(prog) {
+            res = expr?.eval(&syms)?;
+        }
+        Ok(res)
+    }
+
+    fn error(prog: &str) -> String {
+        format!("{:?}", eval("", prog).unwrap_err())
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let doc = r#"{"name":"x7","ratio":0.1,"tags":["lisp",null],"version":1}"#;
+        let res = eval(doc, "(json-serialize (json-parse doc))").unwrap();
+        assert_eq!(res, Expr::String(doc.into()));
+        let err = error("(json-serialize (dict :f inc))");
+        assert!(err.contains("to JSON, at :f"), "{}", err);
+    }
+
+    #[test]
+    fn json_pretty_and_sorted() {
+        let value = "(dict :b (list 1 (dict :z 1 :y 2)) :a nil)";
+        let json = |options: &str| {
+            eval("", &format!("(json-serialize {} {})", value, options))
+                .unwrap()
+                .get_string()
+                .unwrap()
+        };
+        assert_eq!(
+            json(":sort-keys true"),
+            r#"{"a":null,"b":[1,{"y":2,"z":1}]}"#
+        );
+        assert_eq!(
+            json(":pretty true :sort-keys true"),
+            r#"{
+  "a": null,
+  "b": [
+    1,
+    {
+      "y": 2,
+      "z": 1
+    }
+  ]
+}"#
+        );
+        assert_eq!(json(":pretty true"), json(":sort-keys true :pretty true"));
+        let err = error("(json-serialize 1 :max-depth 2)");
+        assert!(err.contains("it knows :pretty :sort-keys"), "{}", err);
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn toml_round_trip() {
+        let doc = r#"title = "x7"
+ratio = 0.1
+big = 9007199254740993
+
+[package]
+name = "x7"
+authors = ["a", "b"]
+"#;
+        let parsed = eval(doc, "(toml-parse doc)").unwrap();
+        let again = eval(doc, "(toml-parse (toml-serialize (toml-parse doc)))").unwrap();
+        assert_eq!(parsed, again);
+        // Numbers are exact.
+        let big = eval(doc, "(get (toml-parse doc) \"big\")").unwrap();
+        assert_eq!(big, Expr::Num("9007199254740993".parse().unwrap()));
+        let ratio = eval(doc, "(get (toml-parse doc) \"ratio\")").unwrap();
+        assert_eq!(ratio, Expr::Num("0.1".parse().unwrap()));
+
+        let err = error("(toml-serialize (dict :a (list 1 nil)))");
+        assert!(
+            err.contains("Cannot convert nil of type nil to TOML, at :a 1"),
+            "{}",
+            err
+        );
+        assert!(eval("", "(toml-serialize 1)").is_err());
+        assert!(eval("a = ", "(toml-parse doc)").is_err());
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn yaml_round_trip() {
+        let doc = "defaults: &defaults
+  image: rust
+  retries: 2
+build:
+  script: [cargo build]
+test: *defaults
+1: one
+";
+        let parsed = eval(doc, "(yaml-parse doc)").unwrap();
+        let again = eval(doc, "(yaml-parse (yaml-serialize (yaml-parse doc)))").unwrap();
+        assert_eq!(parsed, again);
+        // Aliases are expanded, and keys become strings.
+        let prog =
+            "(def ci (yaml-parse doc)) (list (get (get ci \"test\") \"image\") (get ci \"1\"))";
+        assert_eq!(
+            format!("{:?}", eval(doc, prog).unwrap()),
+            "(\"rust\" \"one\")"
+        );
+
+        let err = error("(yaml-serialize (list 1 (dict :f (fn (x) x))))");
+        assert!(err.contains("to YAML, at 1 :f"), "{}", err);
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn toml_to_json() {
+        let doc = "[package]\nname = 'x7'\nversion = 1\n";
+        assert_eq!(
+            eval(doc, "(json-serialize (toml-parse doc))").unwrap(),
+            Expr::String(r#"{"package":{"name":"x7","version":1}}"#.into())
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use crate::cli::bounded_repr;
+use crate::exact_len;
+use crate::symbols::{Dict, Expr, LispResult, SymbolLookup, SymbolTable};
+use im::Vector;
+use std::collections::{BTreeMap, BTreeSet};
+
+// What a cell of a notebook defined: snapshot the top level bindings before
+// and after evaluating it, and compare. Scopes are persistent maps, so a
+// snapshot is two cheap clones, and nothing is copied until the interpreter
+// changes a binding.
+
+/// How much of a value `GlobalsSnapshot::summaries` shows, in bytes.
+const MAX_SUMMARY_BYTES: usize = 80;
+
+/// The top level bindings of an interpreter at one point, builtins included.
+/// Made with `SymbolTable::globals_snapshot`.
+#[derive(Debug, Clone)]
+pub struct GlobalsSnapshot {
+    globals: SymbolLookup,
+    locals: SymbolLookup,
+}
+
+impl GlobalsSnapshot {
+    pub(crate) fn new(globals: SymbolLookup, locals: SymbolLookup) -> Self {
+        GlobalsSnapshot { globals, locals }
+    }
+
+    /// The value `name` was bound to.
+    pub fn get(&self, name: &str) -> Option<&Expr> {
+        self.locals.get(name).or_else(|| self.globals.get(name))
+    }
+
+    fn names(&self) -> BTreeSet<&String> {
+        self.locals.keys().chain(self.globals.keys()).collect()
+    }
+
+    /// Every binding, ordered by name, with its value printed like the REPL
+    /// does and cut short if long.
+    pub fn summaries(&self) -> BTreeMap<String, String> {
+        self.names()
+            .into_iter()
+            .filter_map(|name| {
+                let value = self.get(name)?;
+                Some((name.clone(), bounded_repr(value, MAX_SUMMARY_BYTES)))
+            })
+            .collect()
+    }
+}
+
+/// The names bound differently in two snapshots, each sorted.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GlobalsDiff {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl GlobalsDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Whether a binding still holds the same value. Functions and records are
+/// compared by identity, so redefining a function counts as a change even
+/// if the new one prints the same.
+fn same_value(before: &Expr, after: &Expr) -> bool {
+    match (before.unmeta(), after.unmeta()) {
+        // Lazy sequences are never equal, but one bound twice is the same one.
+        (Expr::LazyIter(l), Expr::LazyIter(r)) => l.id() == r.id(),
+        _ => before == after,
+    }
+}
+
+/// What changed from `before` to `after`.
+pub fn diff_globals(before: &GlobalsSnapshot, after: &GlobalsSnapshot) -> GlobalsDiff {
+    let mut diff = GlobalsDiff::default();
+    for name in after.names() {
+        match (before.get(name), after.get(name)) {
+            (None, Some(_)) => diff.added.push(name.clone()),
+            (Some(old), Some(new)) if !same_value(old, new) => diff.changed.push(name.clone()),
+            _ => {}
+        }
+    }
+    for name in before.names() {
+        if after.get(name).is_none() {
+            diff.removed.push(name.clone());
+        }
+    }
+    diff
+}
+
+/// (env-diff expr)
+pub(crate) fn env_diff(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let before = symbol_table.globals_snapshot();
+    exprs[0].eval(symbol_table)?;
+    let after = symbol_table.globals_snapshot();
+    let diff = diff_globals(&before, &after);
+    let mut bindings = Dict::new();
+    for name in diff.added.into_iter().chain(diff.changed) {
+        if let Some(value) = after.get(&name) {
+            bindings.insert(Expr::String(name), value.clone());
+        }
+    }
+    Ok(Expr::Dict(bindings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Options;
+    use crate::stdlib::create_stdlib_symbol_table;
+
+    #[test]
+    fn cells_report_what_they_bound() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.eval_source("(def total 1) (defn double (x) (* 2 x)) (def kept 3)")
+            .unwrap();
+        let before = syms.globals_snapshot();
+        let cell = "(do (def total (+ total 1)) (def rate 5) (defn triple (x) (* 3 x))
+                        (defn double (x) (* 2 x)) (def kept 3))";
+        syms.eval_source(cell).unwrap();
+        let after = syms.globals_snapshot();
+        assert_eq!(
+            diff_globals(&before, &after),
+            GlobalsDiff {
+                added: vec!["rate".into(), "triple".into()],
+                changed: vec!["double".into(), "total".into()],
+                removed: vec![],
+            }
+        );
+        assert!(diff_globals(&after, &after).is_empty());
+        assert_eq!(after.summaries()["total"], "2");
+        assert_eq!(before.summaries()["total"], "1");
+
+        let diff = syms
+            .eval_source("(env-diff (do (def total 10) (def fresh \"new\") (def kept 3)))")
+            .unwrap();
+        assert_eq!(
+            diff,
+            syms.eval_source("(dict \"total\" 10 \"fresh\" \"new\")")
+                .unwrap()
+        );
+        assert_eq!(
+            syms.eval_source("(env-diff (+ 1 2))").unwrap(),
+            Expr::Dict(Dict::new())
+        );
+        assert_eq!(
+            syms.eval_source("(env-diff (do (def r (range)) r))")
+                .unwrap()
+                .get_dict()
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(
+            syms.eval_source("(env-diff (def r r))").unwrap(),
+            Expr::Dict(Dict::new())
+        );
+    }
+}
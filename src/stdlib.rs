@@ -1,12 +1,39 @@
+use crate::annotations::{parse_params, Annotations};
+use crate::args;
+use crate::bench;
+use crate::canonical;
+use crate::chars;
 use crate::cli::Options;
-use crate::iterators::{LazyMap, NaturalNumbers, Take};
-use crate::modules::load_x7_stdlib;
-use crate::records::FileRecord;
-use crate::symbols::{Expr, Function, LispResult, ProgramError, SymbolTable};
-use anyhow::{anyhow, bail, ensure};
-use bigdecimal::{BigDecimal, One};
+use crate::config;
+use crate::deprecated;
+use crate::dicts;
+use crate::features;
+use crate::generator;
+use crate::host::Warning;
+use crate::iterators::{LazyMap, NaturalNumbers, Take, Window};
+use crate::meta;
+use crate::modules::{import, load_x7_stdlib, require_string, require_url, source};
+use crate::parser::{is_symbol_char, read};
+use crate::paths;
+use crate::pretty::{pretty, PrettyOptions};
+use crate::property::{self, GenRecord};
+use crate::records::user_record;
+use crate::records::{progress, AtomRecord, ProgressRecord, Walk};
+use crate::resources::{check_value_bytes, shallow_bytes, with_open, ValueBudget};
+use crate::snapshot;
+use crate::symbols::{
+    sort_order, Dict, Doc, Expr, Function, LispResult, ProgramError, SymbolLookup, SymbolTable,
+};
+use crate::table;
+use crate::terminal::{self, TerminalInfo};
+use crate::text;
+use crate::throw;
+use crate::units;
+use anyhow::{anyhow, bail, ensure, Context};
+use bigdecimal::{BigDecimal, One, ToPrimitive, Zero};
 use im::{vector, Vector};
 use itertools::Itertools;
+use once_cell::sync::Lazy;
 
 /// Macro to check if we have the right number of args,
 /// and throw a nice error if we don't.
@@ -14,7 +41,7 @@ use itertools::Itertools;
 macro_rules! exact_len {
     ($args:expr, $len:literal) => {
         use anyhow::ensure;
-        use crate::symbols::ProgramError;
+        use $crate::symbols::ProgramError;
         ensure!($args.len() == $len, ProgramError::WrongNumberOfArgs($len))
     };
     ($args:expr, $($len:literal),*) => {
@@ -105,9 +132,17 @@ fn eq_exprs(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr
     Ok(Expr::Bool(all_eq))
 }
 
-fn add_exprs(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+fn add_exprs(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
     let init = exprs[0].clone();
-    exprs.iter().skip(1).try_fold(init, |acc, x| acc + x)
+    exprs.iter().skip(1).try_fold(init, |acc, x| {
+        let bytes = match (&acc, x) {
+            (Expr::String(l), Expr::String(r)) => l.len() + r.len(),
+            (Expr::List(l), Expr::List(r)) => (l.len() + r.len()) * std::mem::size_of::<Expr>(),
+            _ => 0,
+        };
+        check_value_bytes("+", bytes, symbol_table)?;
+        acc + x
+    })
 }
 
 fn sub_exprs(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
@@ -118,9 +153,15 @@ fn sub_exprs(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Exp
     exprs.iter().skip(1).try_fold(init, |acc, x| acc - x)
 }
 
-fn mult_exprs(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+fn mult_exprs(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
     let init = exprs[0].clone();
-    exprs.iter().skip(1).try_fold(init, |acc, x| acc * x)
+    exprs.iter().skip(1).try_fold(init, |acc, x| {
+        if let (Expr::String(l), Expr::Num(times)) = (&acc, x) {
+            let times = times.to_usize().unwrap_or(0);
+            check_value_bytes("*", l.len().saturating_mul(times), symbol_table)?;
+        }
+        acc * x
+    })
 }
 
 fn div_exprs(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
@@ -128,6 +169,25 @@ fn div_exprs(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Exp
     exprs.iter().skip(1).try_fold(init, |acc, x| acc / x)
 }
 
+fn div_mod(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let l = exprs[0].get_num()?;
+    let r = exprs[1].get_num()?;
+    ensure!(!r.is_zero(), ProgramError::DivisionByZero);
+    // Floored division, so the remainder takes the sign of the divisor.
+    let mut quotient = (&l / &r).with_scale(0);
+    let product = &quotient * &r;
+    let mut remainder = &l - &product;
+    if !remainder.is_zero() && (remainder < BigDecimal::zero()) != (r < BigDecimal::zero()) {
+        quotient -= BigDecimal::one();
+        remainder += r;
+    }
+    values(
+        vector![Expr::Num(quotient), Expr::Num(remainder)],
+        symbol_table,
+    )
+}
+
 fn inc_exprs(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
     exact_len!(exprs, 1);
     let n = &exprs[0].get_num()?;
@@ -148,6 +208,207 @@ fn int(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
     Ok(Expr::Num(num.round(0)))
 }
 
+// AGGREGATION
+// These all return nil when given an empty collection.
+
+/// Collect the nums in a list, naming the first item that isn't one.
+fn num_list(expr: &Expr, fn_name: &str) -> LispResult<Vec<BigDecimal>> {
+    expr.get_list()?
+        .iter()
+        .enumerate()
+        .map(|(i, e)| {
+            e.get_num().with_context(|| {
+                format!(
+                    "{} requires a collection of nums, but item {} is {:?}",
+                    fn_name, i, e
+                )
+            })
+        })
+        .collect()
+}
+
+fn mean_of(nums: &[BigDecimal]) -> BigDecimal {
+    let mut sum = BigDecimal::zero();
+    for n in nums {
+        sum += n.clone();
+    }
+    sum / BigDecimal::from(nums.len() as u64)
+}
+
+fn variance_of(nums: &[BigDecimal]) -> BigDecimal {
+    let mean = mean_of(nums);
+    let squares: Vec<BigDecimal> = nums
+        .iter()
+        .map(|n| {
+            let deviation = n - &mean;
+            &deviation * &deviation
+        })
+        .collect();
+    mean_of(&squares)
+}
+
+fn min_of(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let nums = num_list(&exprs[0], "min-of")?;
+    Ok(nums.into_iter().min().map(Expr::Num).unwrap_or(Expr::Nil))
+}
+
+fn max_of(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let nums = num_list(&exprs[0], "max-of")?;
+    Ok(nums.into_iter().max().map(Expr::Num).unwrap_or(Expr::Nil))
+}
+
+fn extent(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let nums = num_list(&exprs[0], "extent")?;
+    match (nums.iter().min(), nums.iter().max()) {
+        (Some(min), Some(max)) => Ok(Expr::Tuple(vector![
+            Expr::Num(min.clone()),
+            Expr::Num(max.clone())
+        ])),
+        _ => Ok(Expr::Nil),
+    }
+}
+
+fn mean(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let nums = num_list(&exprs[0], "mean")?;
+    if nums.is_empty() {
+        return Ok(Expr::Nil);
+    }
+    Ok(Expr::Num(mean_of(&nums)))
+}
+
+fn median(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let mut nums = num_list(&exprs[0], "median")?;
+    if nums.is_empty() {
+        return Ok(Expr::Nil);
+    }
+    nums.sort();
+    let mid = nums.len() / 2;
+    if nums.len() % 2 == 1 {
+        Ok(Expr::Num(nums[mid].clone()))
+    } else {
+        Ok(Expr::Num(mean_of(&nums[mid - 1..=mid])))
+    }
+}
+
+fn variance(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let nums = num_list(&exprs[0], "variance")?;
+    if nums.is_empty() {
+        return Ok(Expr::Nil);
+    }
+    Ok(Expr::Num(variance_of(&nums)))
+}
+
+fn stddev(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let nums = num_list(&exprs[0], "stddev")?;
+    if nums.is_empty() {
+        return Ok(Expr::Nil);
+    }
+    variance_of(&nums)
+        .sqrt()
+        .map(Expr::Num)
+        .ok_or_else(|| anyhow!("Cannot take the square root of a negative variance!"))
+}
+
+fn clamp(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 3);
+    let x = exprs[0].get_num()?;
+    let lo = exprs[1].get_num()?;
+    let hi = exprs[2].get_num()?;
+    ensure!(
+        lo <= hi,
+        "clamp requires lo <= hi, but was given lo {} and hi {}",
+        lo,
+        hi
+    );
+    Ok(Expr::Num(x.max(lo).min(hi)))
+}
+
+fn parse_num(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let s = exprs[0].get_string()?;
+    symbol_table.parse_num(&s).map(Expr::Num)
+}
+
+fn is_int(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    exprs[0].is_int().map(Expr::Bool)
+}
+
+fn is_zero(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    Ok(Expr::Bool(exprs[0].get_num()?.is_zero()))
+}
+
+fn is_pos(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    Ok(Expr::Bool(exprs[0].get_num()? > BigDecimal::zero()))
+}
+
+fn is_neg(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    Ok(Expr::Bool(exprs[0].get_num()? < BigDecimal::zero()))
+}
+
+/// Whether `expr`, which must be an integer, is even.
+fn is_even_int(expr: &Expr, func: &str) -> LispResult<bool> {
+    ensure!(
+        expr.is_int()?,
+        "{} expects an integer, but was given {}",
+        func,
+        expr
+    );
+    Ok((&expr.get_num()? % &BigDecimal::from(2)).is_zero())
+}
+
+fn is_even(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    is_even_int(&exprs[0], "even?").map(Expr::Bool)
+}
+
+fn is_odd(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    is_even_int(&exprs[0], "odd?").map(|even| Expr::Bool(!even))
+}
+
+/// Whether `expr` is an empty collection, or `None` if it isn't a collection.
+pub(crate) fn emptiness(expr: &Expr) -> Option<bool> {
+    match expr.unmeta() {
+        Expr::Nil => Some(true),
+        coll => coll.len().ok().map(|len| len == 0),
+    }
+}
+
+// empty? is older than the other predicates, and kept calling values that
+// aren't collections non-empty when it became a builtin.
+fn is_empty(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    Ok(Expr::Bool(emptiness(&exprs[0]) == Some(true)))
+}
+
+fn is_not_empty(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    match emptiness(&exprs[0]) {
+        Some(empty) => Ok(Expr::Bool(!empty)),
+        None => bail!(
+            "not-empty? expects a collection, but was given {}",
+            exprs[0]
+        ),
+    }
+}
+
+fn is_between(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 3);
+    let (x, lo, hi) = (&exprs[0], &exprs[1], &exprs[2]);
+    Ok(Expr::Bool(lo <= x && x <= hi))
+}
+
 // MISC
 
 fn ident(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
@@ -159,14 +420,95 @@ fn quote(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
     Ok(Expr::Quote(exprs))
 }
 
+/// (quote-form expr)
+fn quote_form(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    Ok(exprs[0].clone())
+}
+
+/// (data form)
+fn data(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    to_data(&exprs[0], symbol_table)
+}
+
+/// `form` read as data: lists are lists rather than calls, except that
+/// `(tuple ...)` and `(dict ...)` make tuples and dicts, and `~expr`
+/// evaluates `expr` in `symbol_table`.
+pub(crate) fn to_data(form: &Expr, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let list = match form {
+        Expr::List(l) => l,
+        // The quote is redundant in data.
+        Expr::Quote(l) => return Ok(Expr::List(data_items(l, symbol_table)?)),
+        _ => return Ok(form.clone()),
+    };
+    let head = list.front().and_then(|head| head.get_symbol_string().ok());
+    let rest = list.clone().slice(1..);
+    match (head.as_deref(), list.len()) {
+        (Some("unquote"), 2) => list[1].eval(symbol_table),
+        // Data within data is already data.
+        (Some("data"), 2) => to_data(&list[1], symbol_table),
+        (Some("tuple"), _) => Ok(Expr::Tuple(data_items(&rest, symbol_table)?)),
+        (Some("dict"), _) => make_dict(data_items(&rest, symbol_table)?, symbol_table),
+        _ => Ok(Expr::List(data_items(list, symbol_table)?)),
+    }
+}
+
+fn data_items(items: &Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Vector<Expr>> {
+    items
+        .iter()
+        .map(|item| to_data(item, symbol_table))
+        .collect()
+}
+
+/// Whether `form` has anything `data` would evaluate.
+pub(crate) fn has_unquote(form: &Expr) -> bool {
+    match form {
+        Expr::List(l) => {
+            matches!(l.front(), Some(Expr::Symbol(s)) if s == "unquote")
+                || l.iter().any(has_unquote)
+        }
+        Expr::Quote(l) => l.iter().any(has_unquote),
+        _ => false,
+    }
+}
+
+fn unquote(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    bail!(anyhow!(
+        "Cannot unquote {} here, unquote (~) is only valid inside data",
+        exprs.iter().join(" ")
+    ))
+}
+
 fn eval(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
     exact_len!(exprs, 1);
     exprs[0].eval(symbol_table)
 }
 
+/// (set-reader-tag! "tag" f)
+fn set_reader_tag(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let tag = exprs[0].get_string()?;
+    ensure!(
+        !tag.is_empty() && tag.chars().all(is_symbol_char),
+        "set-reader-tag! expects a tag which could follow # in #tag\"literal\", but was given {:?}",
+        tag
+    );
+    let reader = match &exprs[1] {
+        Expr::Nil => None,
+        Expr::Function(_) => Some(exprs[1].clone()),
+        other => bail!(
+            "set-reader-tag! expects a function, or nil to remove the tag, but was given {}",
+            other
+        ),
+    };
+    symbol_table.host_mut().set_reader_tag(tag, reader);
+    Ok(Expr::Nil)
+}
+
 fn apply(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
     exact_len!(exprs, 2);
-    exprs[0].call_fn(exprs[1].get_list()?, symbol_table)
+    exprs[0].call_with_values(exprs[1].get_list()?, symbol_table)
 }
 
 fn err(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
@@ -174,12 +516,28 @@ fn err(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
     Err(anyhow!(msg))
 }
 
+fn x7_version(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 0);
+    Ok(Expr::String(env!("CARGO_PKG_VERSION").into()))
+}
+
 fn all_symbols(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
     exact_len!(exprs, 0);
     let all_syms = symbol_table.get_canonical_doc_order();
     Ok(Expr::List(all_syms.into_iter().map(Expr::Symbol).collect()))
 }
 
+fn cache_stats(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 0);
+    Ok(symbol_table.cache_stats())
+}
+
+fn clear_caches(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 0);
+    symbol_table.clear_caches();
+    Ok(Expr::Nil)
+}
+
 fn doc(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
     exact_len!(exprs, 1);
     let sym = exprs[0].get_symbol_string()?;
@@ -189,6 +547,66 @@ fn doc(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
     Ok(Expr::String(doc))
 }
 
+/// The source of the examples in a builtin's docs, everything after its first
+/// `Example:`. REPL transcripts (lines starting with `>>>`) keep only the input.
+pub(crate) fn example_source(doc: &str) -> Option<String> {
+    let text = &doc[doc.find("Example:")?..];
+    let transcript = text.contains(">>>");
+    let mut lines = text.lines().filter_map(|line| {
+        let line = line.trim_start();
+        let line = line.strip_prefix("Example:").unwrap_or(line);
+        if transcript {
+            line.trim_start().strip_prefix(">>>")
+        } else {
+            Some(line)
+        }
+    });
+    Some(lines.join("\n"))
+}
+
+fn examples(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let sym = exprs[0].get_symbol_string()?;
+    let doc = symbol_table
+        .get_doc_item(&sym)
+        .ok_or_else(|| anyhow!("No documentation for {}", sym))?;
+    let forms = match example_source(&doc) {
+        Some(source) => read(&source)
+            .collect::<LispResult<Vector<Expr>>>()
+            .with_context(|| format!("The examples of {} don't parse", sym))?,
+        None => Vector::new(),
+    };
+    Ok(Expr::List(forms))
+}
+
+fn symbols(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let category = match exprs.len() {
+        0 => None,
+        2 if exprs[0].symbol_matches(":category") => {
+            let keyword = exprs[1].get_symbol_string()?;
+            let category = keyword.trim_start_matches(':');
+            ensure!(
+                CATEGORIES.contains(&category),
+                "Unknown category {}, expected one of {}",
+                keyword,
+                CATEGORIES.iter().map(|c| format!(":{}", c)).join(" ")
+            );
+            Some(category.to_string())
+        }
+        _ => bail!("symbols expects no arguments, or :category and a category like :math"),
+    };
+    let syms = symbol_table
+        .get_canonical_doc_order()
+        .into_iter()
+        .filter(|sym| match &category {
+            Some(category) => symbol_table.get_category(sym) == Some(category.as_str()),
+            None => true,
+        })
+        .map(Expr::Symbol)
+        .collect();
+    Ok(Expr::List(syms))
+}
+
 // XXX: Closure lifetime resolution is some magic shit.
 //      For some reason it compiles now no idea why  ¯\_(ツ)_/¯
 // #[inline(always)]
@@ -199,12 +617,26 @@ fn doc(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
 //     f
 // }
 
+fn partial(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let f = exprs[0].get_function()?;
+    let bound = exprs.clone().slice(1..);
+    let name = format!("partial({})", f.name());
+    let min_args = f.minimum_args().saturating_sub(bound.len());
+    let partial = move |args: Vector<Expr>, sym: &SymbolTable| {
+        let mut all_args = bound.clone();
+        all_args.append(args);
+        f.call_with_values(all_args, sym)
+    };
+    let f = Function::new(name, min_args, Arc::new(partial), true);
+    Ok(Expr::Function(f))
+}
+
 // TODO: Make this work.
-fn comp<'c>(exprs: Vector<Expr>, _symbol_table: &'c SymbolTable) -> LispResult<Expr> {
+fn comp(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
     let compose = move |es, sym: &SymbolTable| {
         let mut res: Vector<Expr> = es;
         for func in exprs.iter().rev() {
-            let fn_call = func.call_fn(res, sym);
+            let fn_call = func.call_with_values(res, sym);
             res = match fn_call {
                 Ok(e) => Vector::unit(e),
                 // Ok(l) => match l.get_list() {
@@ -239,33 +671,192 @@ fn panic(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
     } else {
         format!("{}", exprs[0])
     };
-    panic!(msg);
+    panic!("{}", msg);
 }
 
 // PRINT
 
-fn print(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+fn print(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let mut host = symbol_table.host_mut();
     for expr in &exprs {
-        print!("{}", expr);
+        host.write_stdout(&format!("{}", expr));
     }
     Ok(num!(exprs.len()))
 }
 
-fn println(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+fn println(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
     let item = exprs.iter().join("");
-    println!("{}", item);
+    symbol_table.host_mut().write_stdout(&format!("{}\n", item));
+    Ok(Expr::Nil)
+}
+
+/// (pprint value [:sort-keys bool] [:max-depth n] [:max-seq n])
+fn pprint(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let options = PrettyOptions::parse(
+        "pprint",
+        &exprs.clone().slice(1..),
+        &[":sort-keys", ":max-depth", ":max-seq"],
+    )?;
+    let printed = pretty(&exprs[0], &options);
+    symbol_table
+        .host_mut()
+        .write_stdout(&format!("{}\n", printed));
+    Ok(Expr::Nil)
+}
+
+fn inspect(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let report = crate::inspect::inspect(&exprs[0], symbol_table);
+    symbol_table.host_mut().write_stdout(&report);
+    Ok(Expr::Nil)
+}
+
+/// (with-location "original.dsl" 12 3 expr)
+fn with_location(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 4);
+    let file = exprs[0].get_string()?;
+    let line = exprs[1].get_usize()?;
+    let col = exprs[2].get_usize()?;
+    exprs[3]
+        .eval(symbol_table)
+        .with_context(|| format!("at {}:{}:{}", file, line, col))
+}
+
+fn format_exprs(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let template = exprs[0].get_string()?;
+    crate::format::format(&template, &exprs.clone().slice(1..)).map(Expr::String)
+}
+
+fn str_exprs(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let mut budget = ValueBudget::new("str", symbol_table);
+    let mut res = String::new();
+    for expr in exprs.iter() {
+        let piece = expr.to_string();
+        budget.add(piece.len())?;
+        res.push_str(&piece);
+    }
+    Ok(Expr::String(res))
+}
+
+fn read_stdin(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 0);
+    symbol_table.host_mut().read_stdin().map(Expr::String)
+}
+
+fn warn(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let kind = exprs[0].get_symbol_string()?;
+    ensure!(
+        exprs[0].is_keyword(),
+        "warn expects a keyword kind like :custom, but was given {}",
+        kind
+    );
+    let message = exprs[1].get_string()?;
+    symbol_table.warn(Warning {
+        kind,
+        message,
+        span: None,
+    })?;
     Ok(Expr::Nil)
 }
 
+fn warning_to_dict(warning: Warning) -> Expr {
+    let mut dict = im::HashMap::new();
+    dict.insert(Expr::String("kind".into()), Expr::Symbol(warning.kind));
+    dict.insert(
+        Expr::String("message".into()),
+        Expr::String(warning.message),
+    );
+    dict.insert(
+        Expr::String("span".into()),
+        warning.span.map(Expr::String).unwrap_or(Expr::Nil),
+    );
+    Expr::Dict(dict)
+}
+
+fn with_warnings_collected(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    symbol_table.host_mut().start_collecting_warnings();
+    let res = exprs[0].eval(symbol_table);
+    // Stop collecting even if evaluation failed.
+    let warnings = symbol_table.host_mut().finish_collecting_warnings();
+    let warnings = warnings.into_iter().map(warning_to_dict).collect();
+    Ok(Expr::List(vector![res?, Expr::List(warnings)]))
+}
+
 fn type_of(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
     exact_len!(exprs, 1);
     Ok(Expr::String(exprs[0].get_type_str().into()))
 }
 
+fn identical(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    Ok(Expr::Bool(exprs[0].is_identical(&exprs[1])))
+}
+
+fn deep_eq_exprs(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let first = &exprs[0];
+    for x in exprs.iter().skip(1) {
+        if !deep_eq(first, x)? {
+            return Ok(Expr::Bool(false));
+        }
+    }
+    Ok(Expr::Bool(true))
+}
+
+/// Like `=`, but atoms are equal when what they hold is.
+fn deep_eq(l: &Expr, r: &Expr) -> LispResult<bool> {
+    if l.is_identical(r) {
+        return Ok(true);
+    }
+    let all_eq = |l: &Vector<Expr>, r: &Vector<Expr>| -> LispResult<bool> {
+        if l.len() != r.len() {
+            return Ok(false);
+        }
+        for (l, r) in l.iter().zip(r.iter()) {
+            if !deep_eq(l, r)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    };
+    if let (Some(la), Some(ra)) = (AtomRecord::from_expr(l), AtomRecord::from_expr(r)) {
+        return la.visit_acyclic(Walk::CompareLeft, "compare", |l| {
+            ra.visit_acyclic(Walk::CompareRight, "compare", |r| deep_eq(l, r))
+        });
+    }
+    match (l.unmeta(), r.unmeta()) {
+        (Expr::List(l), Expr::List(r))
+        | (Expr::Tuple(l), Expr::Tuple(r))
+        | (Expr::Quote(l), Expr::Quote(r)) => all_eq(l, r),
+        (Expr::Dict(l), Expr::Dict(r)) => {
+            if l.len() != r.len() {
+                return Ok(false);
+            }
+            for (k, lv) in l.iter() {
+                match r.get(k) {
+                    Some(rv) if deep_eq(lv, rv)? => {}
+                    _ => return Ok(false),
+                }
+            }
+            Ok(true)
+        }
+        _ => Ok(l == r),
+    }
+}
+
+fn object_id(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    Ok(Expr::Num(exprs[0].object_id().into()))
+}
+
 // FUNC
 
 fn cond(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
-    ensure!(exprs.len() % 2 == 0, ProgramError::CondBadConditionNotEven);
+    ensure!(
+        exprs.len().is_multiple_of(2),
+        ProgramError::CondBadConditionNotEven
+    );
     let mut iter = exprs.iter();
     while let Some(pred) = iter.next() {
         let body = iter.next().unwrap();
@@ -280,7 +871,7 @@ fn expr_match(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Exp
     let item = exprs[0].eval(symbol_table)?;
     let mut iter = exprs.iter().skip(1);
     ensure!(
-        (exprs.len() - 1) % 2 == 0,
+        (exprs.len() - 1).is_multiple_of(2),
         anyhow!("Match requires an even list of then")
     );
     while let Some(lhs) = iter.next() {
@@ -305,8 +896,23 @@ fn if_gate(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr>
     }
 }
 
+/// Called when `form` is given nil where it expects a list. nil is treated
+/// as an empty list, unless `--strict-nil` is on.
+fn nil_as_empty(form: &str, symbol_table: &SymbolTable) -> LispResult<()> {
+    ensure!(
+        !symbol_table.host().is_strict_nil(),
+        "{} expects a list, but was given nil (nil is not an empty list with --strict-nil)",
+        form
+    );
+    Ok(())
+}
+
 fn map(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
     exact_len!(exprs, 2);
+    if let Expr::Nil = exprs[1] {
+        nil_as_empty("map", symbol_table)?;
+        return Ok(Expr::Nil);
+    }
     let f = &exprs[0];
     if let Ok(iter) = exprs[1].get_iterator() {
         return LazyMap::lisp_res(iter, f.get_function()?);
@@ -314,22 +920,101 @@ fn map(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
     let mut l = exprs[1].get_list()?;
     for expr in l.iter_mut() {
         let old = expr.clone();
-        *expr = f.call_fn(Vector::unit(old), symbol_table)?;
+        *expr = f.call_with_values(Vector::unit(old), symbol_table)?;
     }
     Ok(Expr::List(l))
 }
 
+/// The repetition count for `form`, which must be a non-negative integer.
+fn repeat_count(expr: &Expr, form: &str) -> LispResult<usize> {
+    let is_count = expr.is_int().unwrap_or(false) && expr.get_num()? >= BigDecimal::from(0);
+    ensure!(
+        is_count,
+        "{} expects a non-negative integer count, but was given {}",
+        form,
+        expr
+    );
+    expr.get_usize()
+}
+
+/// (dotimes (i 10) body...)
+fn dotimes(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let binding = exprs[0].get_list()?;
+    ensure!(
+        binding.len() == 2 && binding[0].is_symbol(),
+        "dotimes expects a binding like (i 10), but was given {}",
+        exprs[0]
+    );
+    let count = repeat_count(&binding[1].eval(symbol_table)?, "dotimes")?;
+    let index = [binding[0].clone()];
+    let body = exprs.clone().slice(1..);
+    for i in 0..count {
+        let scope = symbol_table.with_locals(&index, Vector::unit(num!(i)))?;
+        for expr in body.iter() {
+            expr.eval(&scope)?;
+        }
+    }
+    Ok(Expr::Nil)
+}
+
+fn repeat(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let count = repeat_count(&exprs[0].eval(symbol_table)?, "repeat")?;
+    let spine = count.saturating_mul(std::mem::size_of::<Expr>());
+    check_value_bytes("repeat", spine, symbol_table)?;
+    let mut budget = ValueBudget::new("repeat", symbol_table);
+    (0..count)
+        .map(|_| {
+            let value = exprs[1].eval(symbol_table)?;
+            budget.add_expr(&value)?;
+            Ok(value)
+        })
+        .collect::<LispResult<_>>()
+        .map(Expr::List)
+}
+
+fn repeat_value(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let count = repeat_count(&exprs[0], "repeat-v")?;
+    let bytes = count.saturating_mul(shallow_bytes(&exprs[1]));
+    check_value_bytes("repeat-v", bytes, symbol_table)?;
+    Ok(Expr::List(
+        std::iter::repeat_n(exprs[1].clone(), count).collect(),
+    ))
+}
+
+fn times(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let count = repeat_count(&exprs[0], "times")?;
+    let f = &exprs[1];
+    let spine = count.saturating_mul(std::mem::size_of::<Expr>());
+    check_value_bytes("times", spine, symbol_table)?;
+    let mut budget = ValueBudget::new("times", symbol_table);
+    (0..count)
+        .map(|i| {
+            let value = f.call_with_values(Vector::unit(num!(i)), symbol_table)?;
+            budget.add_expr(&value)?;
+            Ok(value)
+        })
+        .collect::<LispResult<_>>()
+        .map(Expr::List)
+}
+
 // Like map, but doesn't produce a list.
 fn foreach(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
     exact_len!(exprs, 2);
+    if let Expr::Nil = exprs[1] {
+        nil_as_empty("foreach", symbol_table)?;
+        return Ok(Expr::Nil);
+    }
     let f = &exprs[0];
     if let Ok(iter) = exprs[1].get_iterator() {
         while let Some(x) = iter.next(symbol_table) {
-            f.call_fn(Vector::unit(x?), symbol_table)?;
+            f.call_with_values(Vector::unit(x?), symbol_table)?;
         }
     } else if let Ok(list) = exprs[1].get_list() {
         for x in list.iter() {
-            f.call_fn(Vector::unit(x.clone()), symbol_table)?;
+            f.call_with_values(Vector::unit(x.clone()), symbol_table)?;
         }
     } else {
         bail!(ProgramError::BadTypes)
@@ -344,11 +1029,15 @@ fn filter(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
         return Ok(Expr::Nil);
     }
     exact_len!(exprs, 2);
+    if let Expr::Nil = exprs[1] {
+        nil_as_empty("filter", symbol_table)?;
+        return Ok(Expr::Nil);
+    }
     let f = &exprs[0];
     let l = exprs[1].get_list()?;
     let mut res = Vector::new();
     for expr in l {
-        if f.call_fn(Vector::unit(expr.clone()), symbol_table)?
+        if f.call_with_values(Vector::unit(expr.clone()), symbol_table)?
             .get_bool()?
         {
             res.push_back(expr);
@@ -357,53 +1046,210 @@ fn filter(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
     Ok(Expr::List(res))
 }
 
-/// reduce
-/// (f init coll)
-fn reduce(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
-    exact_len!(exprs, 2, 3);
-    let (mut init, list) = if exprs.len() == 2 {
-        let list = exprs[1].get_list()?;
-        ensure!(
-            !list.is_empty(),
-            "Attempted to reduce without initial argument using an empty list"
-        );
-        let (mut head, tail) = list.split_at(1);
-        (head.pop_front().unwrap(), tail)
-    } else {
-        (exprs[1].clone(), exprs[2].get_list()?)
+/// (remove pred coll), the complement of filter.
+fn remove_matching(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    if let Expr::Nil = exprs[1] {
+        nil_as_empty("remove", symbol_table)?;
+        return Ok(Expr::Nil);
+    }
+    let f = &exprs[0];
+    let mut res = Vector::new();
+    for expr in exprs[1].get_list()? {
+        if !f
+            .call_with_values(Vector::unit(expr.clone()), symbol_table)?
+            .get_bool()?
+        {
+            res.push_back(expr);
+        }
+    }
+    Ok(Expr::List(res))
+}
+
+/// Map, dropping nil results.
+fn keep(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    if let Expr::Nil = exprs[1] {
+        nil_as_empty("keep", symbol_table)?;
+        return Ok(Expr::Nil);
+    }
+    let f = &exprs[0];
+    let mut res = Vector::new();
+    for expr in exprs[1].get_list()? {
+        match f
+            .call_with_values(Vector::unit(expr), symbol_table)?
+            .unmeta()
+        {
+            Expr::Nil => {}
+            value => res.push_back(value.clone()),
+        }
+    }
+    Ok(Expr::List(res))
+}
+
+/// The first result of f which isn't nil or false.
+fn some(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    if let Expr::Nil = exprs[1] {
+        nil_as_empty("some", symbol_table)?;
+        return Ok(Expr::Nil);
+    }
+    let f = &exprs[0];
+    for expr in exprs[1].get_list()? {
+        match f
+            .call_with_values(Vector::unit(expr), symbol_table)?
+            .unmeta()
+        {
+            Expr::Nil | Expr::Bool(false) => {}
+            value => return Ok(value.clone()),
+        }
+    }
+    Ok(Expr::Nil)
+}
+
+fn filter_nil(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    if let Expr::Nil = exprs[0] {
+        nil_as_empty("filter-nil", symbol_table)?;
+        return Ok(Expr::Nil);
+    }
+    let list = exprs[0].get_list()?;
+    Ok(Expr::List(
+        list.into_iter()
+            .filter(|e| !matches!(e, Expr::Nil))
+            .collect(),
+    ))
+}
+
+fn partition(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let f = &exprs[0];
+    let mut matching = Vector::new();
+    let mut rest = Vector::new();
+    for expr in exprs[1].get_list()? {
+        if f.call_with_values(Vector::unit(expr.clone()), symbol_table)?
+            .get_bool()?
+        {
+            matching.push_back(expr);
+        } else {
+            rest.push_back(expr);
+        }
+    }
+    values(
+        vector![Expr::List(matching), Expr::List(rest)],
+        symbol_table,
+    )
+}
+
+/// reduce
+/// (f init coll)
+fn reduce(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2, 3);
+    let (mut init, list) = if exprs.len() == 2 {
+        let list = exprs[1].get_list()?;
+        ensure!(
+            !list.is_empty(),
+            "Attempted to reduce without initial argument using an empty list"
+        );
+        let (mut head, tail) = list.split_at(1);
+        (head.pop_front().unwrap(), tail)
+    } else {
+        (exprs[1].clone(), exprs[2].get_list()?)
     };
     let f = &exprs[0];
     for item in list {
-        init = f.call_fn(vector![init, item], symbol_table)?;
+        init = f.call_with_values(vector![init, item], symbol_table)?;
     }
     Ok(init)
 }
 
 fn bind(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
     let symbols = &exprs[0];
-    let sym_copy = symbol_table.clone();
     let list = symbols.get_list()?;
     ensure!(
         list.len() % 2 == 0,
         anyhow!("Error: bind requires an even list of expressions, but was given a list of length {}. List given was: {}", list.len(), symbols)
     );
 
+    // The names are only bound in here, like function parameters, so they
+    // may hide builtins even while globals are frozen.
+    let mut scope = symbol_table.clone();
     let mut iter = list.iter();
     while let Some(l) = iter.next() {
         let r = iter.next().unwrap();
-        sym_copy.add_local(l, &r.eval(&sym_copy)?)?;
+        let value = r.eval(&scope)?.first_value();
+        scope = scope.with_locals(std::slice::from_ref(l), Vector::unit(value))?;
     }
-    exprs[1].eval(&sym_copy)
+    exprs[1].eval(&scope)
 }
 
-fn func(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
-    exact_len!(exprs, 2);
-    let arg_symbols = exprs[0].get_list()?;
+/// Return multiple values. Only `let-values` sees anything but the first.
+fn values(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    Ok(Expr::values(exprs))
+}
+
+/// (let-values (((q r) (div-mod 17 5))) body...)
+fn let_values(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let mut scope = symbol_table.clone();
+    for binding in exprs[0].get_list()? {
+        let pair = binding.get_list()?;
+        ensure!(
+            pair.len() == 2,
+            anyhow!(
+                "Error: let-values bindings look like ((a b) expr), but was given {}",
+                binding
+            )
+        );
+        let formals: Vec<Expr> = pair[0].get_list()?.iter().cloned().collect();
+        let producer = &pair[1];
+
+        let values = match producer.eval(&scope)? {
+            Expr::Values(values) => values,
+            value => Vector::unit(value),
+        };
+
+        let rest_index = formals.iter().position(|e| e.symbol_matches("&"));
+        let required = rest_index.unwrap_or(formals.len());
+        let arity_ok = match rest_index {
+            Some(_) => values.len() >= required,
+            None => values.len() == required,
+        };
+        ensure!(
+            arity_ok,
+            anyhow!(
+                "Error: let-values expected {} values for {}, but {} produced {}: {}",
+                required,
+                pair[0],
+                producer,
+                values.len(),
+                Expr::Tuple(values.clone())
+            )
+        );
+        scope = scope.with_locals(&formals, values)?;
+    }
+    exprs_do(exprs.clone().slice(1..), &scope)
+}
+
+pub(crate) fn func(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2, 3);
+    let (arg_symbols, param_types) = parse_params(&exprs[0].get_list()?)?;
+    // (fn (x :num) :num body) annotates the return type.
+    let ret = if exprs.len() == 3 {
+        let ret = exprs[1].get_symbol_string()?;
+        ensure!(
+            exprs[1].is_keyword(),
+            "fn expects a return type annotation like :num before the body, but was given {}",
+            ret
+        );
+        Some(ret[1..].to_string())
+    } else {
+        None
+    };
     let min_args = match arg_symbols.iter().position(|e| e.symbol_matches("&")) {
         Some(index) => index,
         None => arg_symbols.len(),
     };
-    let body = exprs[1].clone();
+    let body = exprs[exprs.len() - 1].clone();
     let f = Arc::new(move |_args: Vector<Expr>, sym: &SymbolTable| body.eval(sym));
     let f = Function::new_named_args(
         "AnonFn".to_string(),
@@ -411,31 +1257,44 @@ fn func(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
         f,
         arg_symbols.iter().cloned().collect(),
         true,
-    );
+    )
+    .with_annotations(Annotations::new(param_types, ret));
     Ok(Expr::Function(f))
 }
 
 fn defn(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
-    exact_len!(exprs, 3, 4);
-    let (name, doc, args, body) = if exprs.len() == 3 {
-        (exprs[0].clone(), None, exprs[1].clone(), exprs[2].clone())
-    } else {
-        (
-            exprs[0].clone(),
-            Some(exprs[1].clone().get_string()?),
-            exprs[2].clone(),
-            exprs[3].clone(),
-        )
+    exact_len!(exprs, 3, 4, 5);
+    let mut rest = exprs;
+    let name = rest.pop_front().unwrap();
+    let doc = match rest.front() {
+        Some(Expr::String(_)) => Some(rest.pop_front().unwrap().get_string()?),
+        _ => None,
     };
 
     let sym_name = name.get_symbol_string()?;
 
-    // Make a function
-    let func = func(vector![args, body], symbol_table)?.rename_function(sym_name.clone())?;
+    // Make a function. What's left is (args [return-type] body).
+    let func = func(rest, symbol_table)?.rename_function(sym_name.clone())?;
 
     // Add the function to the symbol table
     def(vector![name, func.clone()], symbol_table)?;
 
+    // Annotated functions show their signature in their docs.
+    let f = func.get_function()?;
+    let doc = if f.annotations().is_empty() {
+        doc
+    } else {
+        let signature = format!(
+            "{} : {}",
+            sym_name,
+            f.annotations().signature(f.named_args())
+        );
+        Some(match doc {
+            Some(doc) => format!("{}\n{}", signature, doc),
+            None => signature,
+        })
+    };
+
     // If given docs, add it to the symbol table
     if let Some(doc) = doc {
         symbol_table.push_canonical_doc_item(sym_name.clone());
@@ -446,6 +1305,27 @@ fn defn(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
     Ok(func)
 }
 
+/// Wrap an annotated function so its arguments and result are checked
+/// against its annotations on every call.
+fn check_types(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let f = exprs[0].get_function()?;
+    let annotations = f.annotations().clone();
+    annotations.ensure_known()?;
+    let name = format!("check-types({})", f.name());
+    let min_args = f.minimum_args();
+    let inner = f.clone();
+    let checked = move |args: Vector<Expr>, sym: &SymbolTable| {
+        annotations.check_args(inner.name(), inner.named_args(), &args)?;
+        let res = inner.call_with_values(args, sym)?;
+        annotations.check_return(inner.name(), &res)?;
+        Ok(res)
+    };
+    let checked = Function::new(name, min_args, Arc::new(checked), true)
+        .with_annotations(f.annotations().clone());
+    Ok(Expr::Function(checked))
+}
+
 // Dict
 
 fn make_dict(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
@@ -454,7 +1334,7 @@ fn make_dict(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Exp
     //     anyhow!("Error: dict requires an even list of expressions, but was given a list of length {}. List given was: {}", exprs.len(), exprs)
     // );
     ensure!(
-        exprs.len() % 2 == 0,
+        exprs.len().is_multiple_of(2),
         "Error: dict requires an even list of arguments."
     );
     let mut dict = im::HashMap::new();
@@ -472,7 +1352,11 @@ fn assoc(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
     Ok(Expr::Dict(dict))
 }
 
-fn remove(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+fn remove(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    // (remove dict key ...) removes keys, (remove pred coll) filters.
+    if !matches!(exprs[0].unmeta(), Expr::Dict(_)) {
+        return remove_matching(exprs, symbol_table);
+    }
     let mut dict = exprs[0].get_dict()?;
     for key in exprs.iter().skip(1) {
         dict.remove(key);
@@ -482,11 +1366,246 @@ fn remove(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr>
 
 fn get_dict(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
     exact_len!(exprs, 2);
-    let dict = exprs[0].get_dict()?;
-    let res = dict.get(&exprs[1]).cloned().unwrap_or(Expr::Nil);
+    Ok(exprs[0].dict_lookup(&exprs[1])?.unwrap_or(Expr::Nil))
+}
+
+fn contains(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    Ok(Expr::Bool(exprs[0].dict_lookup(&exprs[1])?.is_some()))
+}
+
+/// (get-in coll path [default])
+fn get_in(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    ensure!(
+        exprs.len() <= 3,
+        "get-in takes a collection, a path, and an optional default"
+    );
+    let default = exprs.get(2).cloned().unwrap_or(Expr::Nil);
+    let mut coll = exprs[0].clone();
+    for key in exprs[1].get_list()? {
+        let next = match coll.unmeta() {
+            Expr::List(l) | Expr::Tuple(l) => l.get(key.get_usize()?).cloned(),
+            Expr::Nil => None,
+            _ => coll.dict_lookup(&key)?,
+        };
+        match next {
+            Some(next) => coll = next,
+            None => return Ok(default),
+        }
+    }
+    Ok(coll)
+}
+
+fn deep_copy(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    deep_copy_expr(&exprs[0], symbol_table)
+}
+
+/// `expr` with every dict record in it made a plain dict, and every atom a
+/// new atom.
+fn deep_copy_expr(expr: &Expr, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let copy = |l: &Vector<Expr>| {
+        l.iter()
+            .map(|x| deep_copy_expr(x, symbol_table))
+            .collect::<LispResult<Vector<_>>>()
+    };
+    if let Some(atom) = AtomRecord::from_expr(expr) {
+        let value = atom.visit_acyclic(Walk::Copy, "deep-copy", |value| {
+            deep_copy_expr(value, symbol_table)
+        })?;
+        return AtomRecord::from_x7(Vector::unit(value), symbol_table);
+    }
+    Ok(match expr.unmeta() {
+        Expr::Record(r) => match r.as_dict() {
+            Some(dict) => dict.deep_copy()?,
+            None => expr.clone(),
+        },
+        Expr::List(l) => Expr::List(copy(l)?),
+        Expr::Tuple(l) => Expr::Tuple(copy(l)?),
+        Expr::Quote(l) => Expr::Quote(copy(l)?),
+        Expr::Dict(d) => Expr::Dict(
+            d.iter()
+                .map(|(k, v)| Ok((k.clone(), deep_copy_expr(v, symbol_table)?)))
+                .collect::<LispResult<_>>()?,
+        ),
+        _ => expr.clone(),
+    })
+}
+
+fn map_vals(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let f = &exprs[0];
+    let mut dict = exprs[1].dict_entries()?;
+    for (_, value) in dict.iter_mut() {
+        *value = f.call_with_values(Vector::unit(value.clone()), symbol_table)?;
+    }
+    Ok(Expr::Dict(dict))
+}
+
+fn map_keys(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let f = &exprs[0];
+    let dict = exprs[1].dict_entries()?;
+    let mut res = im::HashMap::new();
+    // New key -> the original keys mapped to it.
+    let mut sources: std::collections::HashMap<Expr, Vec<Expr>> = Default::default();
+    for (key, value) in dict.iter() {
+        let new_key = f.call_with_values(Vector::unit(key.clone()), symbol_table)?;
+        sources
+            .entry(new_key.clone())
+            .or_default()
+            .push(key.clone());
+        res.insert(new_key, value.clone());
+    }
+    let mut collisions: Vec<String> = sources
+        .iter()
+        .filter(|(_, keys)| keys.len() > 1)
+        .map(|(new_key, keys)| {
+            let mut keys: Vec<String> = keys.iter().map(|k| format!("{:?}", k)).collect();
+            keys.sort();
+            format!("{} all map to {:?}", keys.join(", "), new_key)
+        })
+        .collect();
+    collisions.sort();
+    ensure!(
+        collisions.is_empty(),
+        "map-keys would merge keys: {}",
+        collisions.join("; ")
+    );
+    Ok(Expr::Dict(res))
+}
+
+fn filter_kv(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let pred = &exprs[0];
+    let mut res = im::HashMap::new();
+    for (key, value) in exprs[1].dict_entries()? {
+        if pred
+            .call_with_values(vector![key.clone(), value.clone()], symbol_table)?
+            .get_bool()?
+        {
+            res.insert(key, value);
+        }
+    }
+    Ok(Expr::Dict(res))
+}
+
+fn reduce_kv(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 3);
+    let f = &exprs[0];
+    let mut acc = exprs[1].clone();
+    for key in exprs[2].dict_keys()? {
+        let value = exprs[2].dict_lookup(&key)?.unwrap_or(Expr::Nil);
+        acc = f.call_with_values(vector![acc, key, value], symbol_table)?;
+    }
+    Ok(acc)
+}
+
+fn select_keys(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let mut res = im::HashMap::new();
+    for key in exprs[1].get_list()? {
+        if let Some(value) = exprs[0].dict_lookup(&key)? {
+            res.insert(key, value);
+        }
+    }
+    Ok(Expr::Dict(res))
+}
+
+fn merge(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let mut res = im::HashMap::new();
+    for dict in exprs.iter() {
+        res.extend(dict.dict_entries()?);
+    }
+    Ok(Expr::Dict(res))
+}
+
+fn merge_with(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let f = &exprs[0];
+    let mut res = im::HashMap::new();
+    for dict in exprs.iter().skip(1) {
+        for (key, value) in dict.dict_entries()? {
+            let value = match res.get(&key) {
+                Some(old) => f
+                    .call_with_values(vector![Expr::clone(old), value], symbol_table)
+                    .with_context(|| format!("merge-with could not merge key {:?}", key))?,
+                None => value,
+            };
+            res.insert(key, value);
+        }
+    }
+    Ok(Expr::Dict(res))
+}
+
+/// The entries of `expr` if it's a dict or a record which reads like one.
+fn merged_entries(expr: &Expr) -> LispResult<Option<Dict>> {
+    match expr.unmeta() {
+        Expr::Dict(d) => Ok(Some(d.clone())),
+        Expr::Record(r) if r.as_dict().is_some() => expr.dict_entries().map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Merge `right` into `left`, recursing into dicts found at the same key.
+fn deep_merge_values(left: &Expr, right: &Expr, concat_lists: bool) -> LispResult<Expr> {
+    if let (Some(l), Some(r)) = (merged_entries(left)?, merged_entries(right)?) {
+        let mut res = l;
+        for (key, value) in r {
+            let merged = match res.get(&key) {
+                Some(old) => deep_merge_values(old, &value, concat_lists)?,
+                None => value,
+            };
+            res.insert(key, merged);
+        }
+        return Ok(Expr::Dict(res));
+    }
+    Ok(match (left, right) {
+        (Expr::List(l), Expr::List(r)) if concat_lists => Expr::List(l.clone() + r.clone()),
+        _ => right.clone(),
+    })
+}
+
+fn deep_merge(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let mut dicts = exprs;
+    let mut concat_lists = false;
+    if dicts.len() >= 2 && dicts[dicts.len() - 2].symbol_matches(":concat-lists") {
+        concat_lists = dicts.pop_back().unwrap().get_bool()?;
+        dicts.pop_back();
+    }
+    let mut res = Expr::Dict(im::HashMap::new());
+    for dict in dicts.iter() {
+        // Only the top level must be dicts, anything goes below.
+        dict.dict_entries()?;
+        res = deep_merge_values(&res, dict, concat_lists)?;
+    }
     Ok(res)
 }
 
+fn conform(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    crate::conform::conform(&exprs[0], &exprs[1])
+}
+
+fn template(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    crate::template::template(&exprs[0])
+}
+
+fn fill(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2, 4);
+    let mut allow_extra = false;
+    if exprs.len() == 4 {
+        if !exprs[2].symbol_matches(":allow-extra") {
+            bail!(
+                "fill only takes the option :allow-extra, but got {:?}",
+                exprs[2]
+            );
+        }
+        allow_extra = exprs[3].get_bool()?;
+    }
+    crate::template::fill(&exprs[0], &exprs[1], allow_extra)
+}
+
 // LISTS
 
 fn list(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
@@ -498,12 +1617,103 @@ fn tuple(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
 }
 
 fn nth(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
-    let index = exprs[0].get_usize()?;
-    exprs[1]
-        .get_list()?
-        .get(index)
-        .cloned()
-        .ok_or_else(|| anyhow::anyhow!(ProgramError::BadTypes))
+    exact_len!(exprs, 2);
+    let list = exprs[1].get_list()?;
+    let index = exprs[0].get_index_from_end("nth", list.len())?;
+    list.get(index).cloned().ok_or_else(|| {
+        anyhow!(ProgramError::BadTypes).context(format!(
+            "Error: nth index {} is out of range for length {}",
+            exprs[0],
+            list.len()
+        ))
+    })
+}
+
+/// Resolve `start` and `end` arguments of `func` into a range of `0..len`.
+/// Negative positions count from the end, and positions past the end clamp.
+fn slice_range(
+    start: &Expr,
+    end: Option<&Expr>,
+    len: usize,
+    func: &str,
+) -> LispResult<std::ops::Range<usize>> {
+    let start = start.get_clamped_index(func, len)?;
+    let end = match end {
+        Some(end) => end.get_clamped_index(func, len)?,
+        None => len,
+    };
+    Ok(start..end.max(start))
+}
+
+fn slice(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2, 3);
+    let list = exprs[0].get_list()?;
+    let range = slice_range(&exprs[1], exprs.get(2), list.len(), "slice")?;
+    Ok(Expr::List(list.clone().slice(range)))
+}
+
+/// (subvec list start end?)
+fn subvec(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2, 3);
+    let list = exprs[0].get_list()?;
+    let start = exprs[1].get_index("subvec")?;
+    let end = match exprs.get(2) {
+        Some(end) => end.get_index("subvec")?,
+        None => list.len(),
+    };
+    ensure!(
+        start <= end && end <= list.len(),
+        "subvec expects 0 <= start <= end <= {}, the length of the list, but was given {} and {}",
+        list.len(),
+        start,
+        end
+    );
+    Ok(Expr::List(list.clone().slice(start..end)))
+}
+
+/// (concat list...)
+fn concat(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let mut res = Vector::new();
+    for list in exprs.iter() {
+        let list = list.get_list()?;
+        let bytes = (res.len() + list.len()) * std::mem::size_of::<Expr>();
+        check_value_bytes("concat", bytes, symbol_table)?;
+        res.append(list);
+    }
+    Ok(Expr::List(res))
+}
+
+/// (window n list) or (window n step list)
+fn window(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2, 3);
+    let size = exprs[0].get_index("window")?;
+    let step = if exprs.len() == 3 {
+        exprs[1].get_index("window")?
+    } else {
+        1
+    };
+    ensure!(
+        size > 0 && step > 0,
+        "window expects a size and step of at least 1, but was given {} and {}",
+        size,
+        step
+    );
+    Window::lisp_res(size, step, exprs[exprs.len() - 1].get_list()?)
+}
+
+fn substring(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2, 3);
+    let chars: Vec<char> = exprs[0].get_string()?.chars().collect();
+    let range = slice_range(&exprs[1], exprs.get(2), chars.len(), "substring")?;
+    Ok(Expr::String(chars[range].iter().collect()))
+}
+
+fn drop(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let num = exprs[0].get_index("drop")?;
+    let list = exprs[1].get_list()?;
+    let num = num.min(list.len());
+    Ok(Expr::List(list.skip(num)))
 }
 
 fn cons(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
@@ -513,8 +1723,12 @@ fn cons(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
     Ok(Expr::List(list))
 }
 
-fn head(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+fn head(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
     exact_len!(exprs, 1);
+    if let Expr::Nil = exprs[0] {
+        nil_as_empty("head", symbol_table)?;
+        return Ok(Expr::Nil);
+    }
     if let Ok(list) = exprs[0].get_list() {
         if list.is_empty() {
             return Ok(Expr::Nil);
@@ -565,17 +1779,23 @@ fn zip(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
     ))
 }
 
-fn range(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+fn range(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
     if exprs.is_empty() {
         return NaturalNumbers::lisp_res();
     }
     exact_len!(exprs, 1, 2);
     let (mut start, end) = if exprs.len() == 1 {
-        use bigdecimal::Zero;
         (BigDecimal::zero(), exprs[0].get_num()?)
     } else {
         (exprs[0].get_num()?, exprs[1].get_num()?)
     };
+    // Casting saturates, so huge ranges still trip the limit.
+    let count = (&end - &start).to_f64().unwrap_or(0.0).max(0.0).ceil() as usize;
+    check_value_bytes(
+        "range",
+        count.saturating_mul(std::mem::size_of::<Expr>()),
+        symbol_table,
+    )?;
     let mut ret = Vector::new();
     while start < end {
         ret.push_back(Expr::Num(start.clone()));
@@ -586,7 +1806,11 @@ fn range(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
 
 fn take(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
     exact_len!(exprs, 2);
-    let num = exprs[0].get_usize()?;
+    let num = exprs[0].get_index("take")?;
+    if let Ok(list) = exprs[1].get_list() {
+        let num = num.min(list.len());
+        return Ok(Expr::List(list.take(num)));
+    }
     let iter = exprs[1].get_iterator()?;
     Take::lisp_res(num, iter)
 }
@@ -597,23 +1821,34 @@ fn doall(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
     exprs[0].get_iterator()?.eval(symbol_table)
 }
 
-fn shuffle(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+fn shuffle(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
     exact_len!(exprs, 1);
     let mut list: Vec<_> = exprs[0].get_list()?.iter().cloned().collect();
     use rand::seq::SliceRandom;
-    use rand::thread_rng;
-    list.shuffle(&mut thread_rng());
+    list.shuffle(symbol_table.host_mut().rng());
     Ok(Expr::List(list.into()))
 }
 
+fn random_seed(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let seed = exprs[0].get_num()?.to_u64().ok_or_else(|| {
+        anyhow!(
+            "random-seed needs a non-negative integer, but was given {}",
+            exprs[0]
+        )
+    })?;
+    symbol_table.host_mut().seed_rng(seed);
+    Ok(Expr::Nil)
+}
+
 // Records
 
-fn call_method(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+fn call_method(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
     let rec = exprs[0].get_record()?;
     let method = &exprs[1].get_string()?;
     let args = exprs.clone().slice(2..);
     use crate::records::Record;
-    rec.call_method(method, args)
+    rec.call_method_in(method, args, symbol_table)
 }
 
 fn doc_methods(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
@@ -627,54 +1862,270 @@ fn doc_methods(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Ex
     Ok(Expr::List(docs))
 }
 
-fn len(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+fn len(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
     exact_len!(exprs, 1);
+    if let Expr::Nil = exprs[0] {
+        nil_as_empty("len", symbol_table)?;
+        return Ok(num!(0));
+    }
     Ok(num!(exprs[0].len()?))
 }
 
 fn sort(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
     exact_len!(exprs, 1);
-    let mut list = exprs[0].full_order_list()?;
-    list.sort();
-    Ok(Expr::List(list))
+    let mut list: Vec<Expr> = exprs[0].get_list()?.into_iter().collect();
+    list.sort_by(sort_order);
+    Ok(Expr::List(list.into()))
 }
 
-use std::sync::Arc;
+/// (sort-by f coll)
+fn sort_by(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let f = &exprs[0];
+    let mut keyed = exprs[1]
+        .get_list()?
+        .into_iter()
+        .map(|item| {
+            let key = f.call_with_values(Vector::unit(item.clone()), symbol_table)?;
+            Ok((key, item))
+        })
+        .collect::<LispResult<Vec<_>>>()?;
+    // Stable, so items with equal keys keep their order.
+    keyed.sort_by(|(l, _), (r, _)| sort_order(l, r));
+    Ok(Expr::List(
+        keyed.into_iter().map(|(_, item)| item).collect(),
+    ))
+}
 
-macro_rules! make_stdlib_fns {
-	  ( $(($sym:literal, $minargs:expr, $func:expr, $eval_args:expr, $doc:literal)),* ) => {
-        {
-            let mut globals = Vec::new();
-            let mut docs = Vec::new();
-            $(
-                let f = Function::new($sym.into(), $minargs, Arc::new($func), $eval_args);
-                globals.push(($sym.into(), Expr::Function(f)));
-                docs.push(($sym.into(), $doc.into()));
-            )*
-            SymbolTable::with_globals(globals, docs)
-        }
-	  };
+/// (group-by f coll)
+fn group_by(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let f = &exprs[0];
+    let mut groups: im::HashMap<Expr, Vector<Expr>> = im::HashMap::new();
+    for item in exprs[1].get_list()? {
+        let key = f.call_with_values(Vector::unit(item.clone()), symbol_table)?;
+        groups.entry(key).or_default().push_back(item);
+    }
+    Ok(Expr::Dict(
+        groups
+            .into_iter()
+            .map(|(key, group)| (key, Expr::List(group)))
+            .collect(),
+    ))
 }
 
-macro_rules! document_records {
-	  ($sym:expr, $($rec:ident),*) => {
-        use crate::records::RecordDoc;
-		    $(
-            // Document the record itself.
-            $sym.add_doc_item($rec::name().into(), $rec::type_doc().into());
-            for (method, method_doc) in $rec::method_doc() {
-                $sym.add_doc_item(format!("{}.{}", $rec::name(), method), (*method_doc).into());
-            }
-        )*
-	  };
+/// (keys dict)
+fn keys(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let mut keys = exprs[0].dict_keys()?;
+    keys.sort_by(sort_order);
+    Ok(Expr::List(keys.into()))
+}
+
+fn reverse(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    Ok(Expr::List(exprs[0].get_list()?.into_iter().rev().collect()))
+}
+
+// These used to be written in x7 (see tests/fixtures/reference.x7), and
+// keep the same behaviour without recursing.
+
+fn not_eq_exprs(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    Ok(Expr::Bool(!eq_exprs(exprs, symbol_table)?.get_bool()?))
+}
+
+fn dot_product(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let (l, r) = (exprs[0].get_list()?, exprs[1].get_list()?);
+    let products = l
+        .into_iter()
+        .zip(r)
+        .map(|(l, r)| l * &r)
+        .collect::<LispResult<Vec<_>>>()?;
+    // Sum from the right, like the recursive definition did.
+    products
+        .into_iter()
+        .rev()
+        .try_fold(num!(0), |acc, product| product + &acc)
+}
+
+fn quicksort(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    enum Task {
+        Sort(Vector<Expr>),
+        Emit(Expr),
+    }
+    let mut res = Vector::new();
+    let mut tasks = vec![Task::Sort(exprs[0].get_list()?)];
+    while let Some(task) = tasks.pop() {
+        match task {
+            Task::Emit(expr) => res.push_back(expr),
+            Task::Sort(mut list) => {
+                let pivot = match list.pop_front() {
+                    Some(pivot) => pivot,
+                    None => continue,
+                };
+                let (le, ge): (Vector<Expr>, Vector<Expr>) =
+                    list.into_iter().partition(|x| x <= &pivot);
+                // ge isn't quite the complement, items incomparable to the pivot are dropped.
+                let ge = ge.into_iter().filter(|x| x > &pivot).collect();
+                // The stack is last in first out, so push in reverse.
+                tasks.push(Task::Sort(ge));
+                tasks.push(Task::Emit(pivot));
+                tasks.push(Task::Sort(le));
+            }
+        }
+    }
+    Ok(Expr::List(res))
+}
+
+fn assert_eq_exprs(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 3);
+    if exprs[0] != exprs[1] {
+        // The message stays the root cause, the diff is context.
+        return Err(anyhow!(exprs[2].to_string()))
+            .with_context(|| crate::diff::diff(&exprs[0], &exprs[1]));
+    }
+    Ok(Expr::List(Vector::new()))
+}
+
+/// (assert-matches pattern value [message])
+fn assert_matches(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2, 3);
+    let value = exprs[1].eval(symbol_table)?;
+    if let Some(failure) = crate::diff::match_pattern(&exprs[0], &value, symbol_table)? {
+        let message = match exprs.get(2) {
+            Some(message) => format!("{}: ", message.eval(symbol_table)?),
+            None => String::new(),
+        };
+        bail!("{}assert-matches failed at {}", message, failure);
+    }
+    Ok(Expr::List(Vector::new()))
+}
+
+use std::sync::Arc;
+
+// Everything registering builtins this way is behind a feature, so with none
+// of them on, these go unused.
+#[allow(dead_code)]
+pub(crate) type Builtin = fn(Vector<Expr>, &SymbolTable) -> LispResult<Expr>;
+
+/// (name, category, minimum args, function, eval args, doc)
+#[allow(dead_code)]
+pub(crate) type BuiltinEntry = (
+    &'static str,
+    &'static str,
+    usize,
+    Builtin,
+    bool,
+    &'static str,
+);
+
+/// Categories of builtins, in the order the builtins reference lists them.
+/// Every builtin is registered with one of these.
+pub(crate) const CATEGORIES: &[&str] = &[
+    "math",
+    "logic",
+    "strings",
+    "sequences",
+    "dicts",
+    "functions",
+    "control",
+    "data",
+    "records",
+    "testing",
+    "introspection",
+    "modules",
+    "paths",
+    "io",
+];
+
+/// Register builtins after the symbol table is made. Used for builtins behind
+/// cargo features, as make_stdlib_fns! can't cfg individual entries.
+#[allow(dead_code)]
+pub(crate) fn register_builtins(syms: &SymbolTable, builtins: &[BuiltinEntry]) {
+    for (sym, category, minargs, func, eval_args, doc) in builtins {
+        let f = Function::new((*sym).into(), *minargs, Arc::new(*func), *eval_args);
+        syms.add_global(sym, Expr::Function(f));
+        syms.add_doc_item((*sym).into(), (*doc).into());
+        syms.set_category(sym, category);
+    }
+}
+
+macro_rules! make_stdlib_fns {
+	  ( $(($sym:literal, $category:literal, $minargs:expr, $func:expr, $eval_args:expr, $doc:literal)),* ) => {
+        {
+            let mut globals = Vec::new();
+            let mut docs = Vec::new();
+            let mut categories = Vec::new();
+            $(
+                let f = Function::new($sym.into(), $minargs, Arc::new($func), $eval_args);
+                globals.push(($sym.into(), Expr::Function(f)));
+                docs.push(($sym.into(), $doc.into()));
+                categories.push(($sym, $category));
+            )*
+            let syms = SymbolTable::with_globals(globals, docs);
+            for (sym, category) in categories {
+                syms.set_category(sym, category);
+            }
+            syms
+        }
+	  };
+}
+
+macro_rules! document_records {
+	  ($sym:expr, $($rec:ident),*) => {
+        use crate::records::RecordDoc;
+		    $(
+            // Document the record itself.
+            $sym.add_doc_item($rec::name().into(), $rec::type_doc().into());
+            for (method, method_doc) in $rec::method_doc() {
+                $sym.add_doc_item(format!("{}.{}", $rec::name(), method), (*method_doc).into());
+            }
+        )*
+	  };
+}
+
+/// The scope of a fresh interpreter: the builtins, what the x7 stdlib
+/// defines at the top level, and their docs.
+///
+/// Building it makes every builtin and evaluates the x7 stdlib, which dominates
+/// startup, so it's done once per process and shared. The maps are persistent,
+/// so each interpreter's copy is O(1) and later defs stay local to it.
+/// This relies on the x7 stdlib not creating mutable values (like atoms) at load.
+static STDLIB: Lazy<(SymbolLookup, SymbolLookup, Doc)> =
+    Lazy::new(|| build_stdlib(&Options::default()).scope_and_docs());
+
+/// The category of the builtin `name`, without making an interpreter.
+pub(crate) fn builtin_category(name: &str) -> Option<&'static str> {
+    STDLIB.2.category(name)
 }
 
-#[allow(clippy::let_and_return)]
 pub fn create_stdlib_symbol_table(opts: &Options) -> SymbolTable {
+    let syms = if opts.show_loading_stdlib {
+        // Loading prints each stdlib form, so do it for real.
+        build_stdlib(opts)
+    } else {
+        let (globals, locals, docs) = STDLIB.clone();
+        SymbolTable::from_scope(globals, locals, docs)
+    };
+    syms.set_caches_enabled(!opts.no_caches);
+    syms.set_deny_warnings(opts.deny_warnings);
+    syms.set_deny_deprecated(opts.deny_deprecated);
+    syms.set_strict_nil(opts.strict_nil);
+    syms.set_frozen_globals(opts.frozen_globals);
+    syms.set_max_value_bytes(opts.max_value_bytes);
+    syms.set_terminal_info(TerminalInfo::detect());
+    syms
+}
+
+#[allow(clippy::let_and_return)]
+fn build_stdlib(opts: &Options) -> SymbolTable {
     let syms = make_stdlib_fns!(
         // ARITHMETIC
         (
             "+",
+            "math",
             1,
             add_exprs,
             true,
@@ -685,6 +2136,7 @@ Example: (+ \"Hello \" \"World\") ; \"Hello World\"
         ),
         (
             "-",
+            "math",
             1,
             sub_exprs,
             true,
@@ -694,6 +2146,7 @@ Example: (- 2 1 1) ; 0
         ),
         (
             "*",
+            "math",
             1,
             mult_exprs,
             true,
@@ -704,6 +2157,7 @@ Example: (* 1 2 3) ; 6
         ),
         (
             "%",
+            "math",
             2,
             rem_exprs,
             true,
@@ -712,6 +2166,7 @@ Example: (% 4 2) ; 0"
         ),
         (
             "/",
+            "math",
             2,
             div_exprs,
             true,
@@ -721,6 +2176,7 @@ Example: (/ 8 2 2 2) ; 1
         ),
         (
             "sqrt",
+            "math",
             1,
             sqrt_exprs,
             true,
@@ -730,6 +2186,7 @@ Example: (sqrt 9) ; 3
         ),
         (
             "=",
+            "logic",
             1,
             eq_exprs,
             true,
@@ -740,6 +2197,7 @@ Example: (= 1 1) ; true
         ),
         (
             "<",
+            "logic",
             2,
             lt_exprs,
             true,
@@ -748,6 +2206,7 @@ Example: (< 0 1 2) ; true"
         ),
         (
             "<=",
+            "logic",
             2,
             lte_exprs,
             true,
@@ -756,6 +2215,7 @@ Example: (<= 0 0 0.05 1) ; true"
         ),
         (
             ">",
+            "logic",
             2,
             gt_exprs,
             true,
@@ -764,30 +2224,135 @@ Example: (> 10 0 1 2 3 4) ; true"
         ),
         (
             ">=",
+            "logic",
             2,
             gte_exprs,
             true,
             "Test if the first item is greater than or equal to the rest.
 Example: (>= 10 10 5) ; true"
         ),
-        ("inc", 1, inc_exprs, true, "Increment the given number."),
-        ("int", 1, int, true, "Create an integer from the input.
+        ("div-mod", "math", 2, div_mod, true, "Floored division returning the quotient and remainder as multiple values.
+In a single value context only the quotient is returned.
+Example:
+(div-mod 17 5) ; 3
+(let-values (((q r) (div-mod 17 5))) (list q r)) ; (3 2)
+(let-values (((q r) (div-mod -17 5))) (list q r)) ; (-4 3)
+"),
+        ("inc", "math", 1, inc_exprs, true, "Increment the given number."),
+        ("int", "math", 1, int, true, "Create an integer from the input.
 
 Example:
 (int 3.2) ;; 3
+"),
+        ("min-of", "math", 1, min_of, true, "Return the smallest number in a collection, or nil if it's empty.
+Example:
+(min-of '(3 1 2)) ; 1
+(min-of '()) ; nil
+"),
+        ("max-of", "math", 1, max_of, true, "Return the largest number in a collection, or nil if it's empty.
+Example:
+(max-of '(3 1 2)) ; 3
+"),
+        ("extent", "math", 1, extent, true, "Return a tuple of the smallest and largest number in a collection, or nil if it's empty.
+Example:
+(extent '(3 1 2)) ; (tuple 1 3)
+"),
+        ("mean", "math", 1, mean, true, "Return the exact mean of a collection of numbers, or nil if it's empty.
+Example:
+(mean '(1 2 3 4)) ; 2.5
+(mean '(0.1 0.2 0.3)) ; 0.2
+"),
+        ("median", "math", 1, median, true, "Return the median of a collection of numbers, or nil if it's empty.
+For an even number of items, the median is the mean of the two middle items.
+Example:
+(median '(3 1 2)) ; 2
+(median '(4 1 3 2)) ; 2.5
+"),
+        ("variance", "math", 1, variance, true, "Return the population variance of a collection of numbers, or nil if it's empty.
+Example:
+(variance '(1 2 3 4)) ; 1.25
+"),
+        ("stddev", "math", 1, stddev, true, "Return the population standard deviation of a collection of numbers, or nil if it's empty.
+Example:
+(stddev '(2 4 4 4 5 5 7 9)) ; 2
+"),
+        ("clamp", "math", 3, clamp, true, "Restrict a number to the range [lo, hi].
+Example:
+(clamp 5 0 3) ; 3
+(clamp -1 0 3) ; 0
+(clamp 2 0 3) ; 2
+"),
+        ("int?", "math", 1, is_int, true, "Test if a number has no fractional part.
+Example:
+(int? 3) ; true
+(int? 3.0) ; true
+(int? (/ 4 2)) ; true
+(int? 1.5) ; false
+"),
+        ("zero?", "math", 1, is_zero, true, "Test if a number is zero.
+Example:
+(zero? 0) ; true
+(zero? 0.5) ; false
+"),
+        ("pos?", "math", 1, is_pos, true, "Test if a number is greater than zero.
+Example:
+(pos? 1) ; true
+(pos? 0) ; false
+"),
+        ("neg?", "math", 1, is_neg, true, "Test if a number is less than zero.
+Example:
+(neg? -1) ; true
+(neg? 0) ; false
+"),
+        ("even?", "math", 1, is_even, true, "Test if an integer is even. Errors on fractional numbers.
+Example:
+(even? 2) ; true
+(even? -3) ; false
+"),
+        ("odd?", "math", 1, is_odd, true, "Test if an integer is odd. Errors on fractional numbers.
+Example:
+(odd? 3) ; true
+(odd? 0) ; false
+"),
+        ("empty?", "sequences", 1, is_empty, true, "Test if a collection is empty. nil is empty, and anything else that
+isn't a collection, like a number, is not.
+Example:
+(empty? '()) ; true
+(empty? \"\") ; true
+(empty? (dict 1 2)) ; false
+(empty? 1) ; false
+"),
+        ("not-empty?", "sequences", 1, is_not_empty, true, "Test if a collection is not empty. Errors on values that aren't
+collections, like numbers.
+Example:
+(not-empty? '(1)) ; true
+(not-empty? \"\") ; false
+"),
+        ("between?", "math", 3, is_between, true, "Test if `x` is in the inclusive range [lo, hi].
+Example:
+(between? 2 1 3) ; true
+(between? 3 1 3) ; true
+(between? 4 1 3) ; false
+"),
+        ("parse-num", "math", 1, parse_num, true, "Parse a string into a number.
+Example:
+(parse-num \"1.5\") ; 1.5
+(parse-num \" 42 \") ; 42
 "),
         (
             "not",
+            "logic",
             1,
             not,
             true,
             "Invert the bool. true becomes false and vice-versa."
         ),
-        ("or", 1, or, true, "logical or."),
-        ("and", 1, and, true, "logical and."),
+        ("or", "logic", 1, or, true, "logical or."),
+        ("and", "logic", 1, and, true, "logical and."),
         // // MISC
         (
             "ident",
+            "control",
             0,
             ident,
             true,
@@ -795,13 +2360,36 @@ Example:
         ),
         (
             "quote",
+            "control",
             0,
             quote,
             false,
             "Transforms the given input into a quote. Usually you will want to use the '(1 2 3) syntax."
         ),
+        ("quote-form", "control", 1, quote_form, false, "Give back the one expression it's given, without evaluating it.
+'x reads as (quote-form x), for anything after the ' but a list.
+Example:
+(quote-form x) ; x
+'x ; x
+''(1 2) ; '(1 2)
+'^(1 2) ; (tuple 1 2)
+"),
+        ("data", "control", 1, data, false, "Read a form as data, without quoting: lists are lists, not calls, and symbols
+stay symbols. Inside it (tuple ...) and ^(...) make tuples, (dict ...) makes
+dicts, and ~expr evaluates expr where the data form is.
+Example:
+(def port 8080)
+(data (server (host \"x\") (port ~port)))  ; (server (host \"x\") (port 8080))
+(data (features ^(a b) (dict \"debug\" ~(= port 8080))))  ; (features (tuple a b) {\"debug\": true})
+"),
+        ("unquote", "control", 1, unquote, false, "Evaluate an expression inside data. Usually you will want to use the ~expr syntax.
+Example:
+(def n 2)
+(data (a (unquote n) ~(inc n)))  ; (a 2 3)
+"),
         (
             "print",
+            "io",
             1,
             print,
             true,
@@ -809,13 +2397,113 @@ Example:
         ),
         (
             "println",
+            "io",
             1,
             println,
             true,
             "Print the given argument WITH a newline."
         ),
+        ("pprint", "io", 1, pprint, true, "Print the full representation of a value, even if the REPL would elide it.
+For output which must not change between runs, like snapshot tests, `:sort-keys true` prints
+dict entries in key order. `:max-depth n` prints collections nested deeper than n as `…` and
+their size, and `:max-seq n` prints at most n elements of a sequence, eliding its middle.
+Example:
+(range 100000) ; (0 1 2 ... (99000 more elements, use (pprint *1) to see all))
+(pprint *1) ; prints every element
+(pprint (dict :b (range 10) :a 1) :sort-keys true :max-seq 4) ; {:a: 1, :b: (0 1 … 6 more … 8 9)}
+"),
+        ("benchmark", "functions", 1, bench::benchmark, true, "Call a function of no arguments repeatedly, returning how long the calls took in seconds
+as a dict of :min :max :mean :median :p95 and :total, with the :iterations and :warmup run.
+Options are :warmup calls which aren't timed (3), :iterations timed calls (100), and :max-seconds,
+which stops early once that long has passed, after at least one timed call.
+Example:
+(def result (benchmark (dict :warmup 1 :iterations 10) (fn () (reduce + 0 (range 100)))))
+(get result :iterations) ; 10
+"),
+        ("benchmark-compare", "io", 1, bench::benchmark_compare, true, "Benchmark each function in a dict of name to function, printing a table of their times
+with how many times slower than the fastest each is. Takes the same options as benchmark,
+and returns a dict of name to benchmark result.
+Example:
+(benchmark-compare (dict :loop (fn () (reduce + 0 (range 100))) :formula (fn () (/ (* 99 100) 2))))
+; name     | mean    | median  | p95     | relative
+; ---------+---------+---------+---------+---------
+; :formula | 1.10µs  | 1.05µs  | 1.40µs  | 1.00x
+; :loop    | 48.20µs | 47.90µs | 52.10µs | 43.82x
+"),
+        ("print-table", "io", 1, table::print_table, true, "Print a list of dicts, or of lists named by :header, as an aligned table.
+Numbers are right aligned and everything else left. Options are :columns, the keys to show,
+:max-width, past which cells are cut short with …, and :header. Missing keys are empty cells.
+Rows are printed as they're read, sizing the columns from the first 100. Returns the number of rows.
+Example:
+(print-table (list (dict :name \"Ada\" :age 36) (dict :name \"Grace\")))
+; age | name
+; ----+------
+;  36 | Ada
+;     | Grace
+(print-table (map (fn (x) (tuple x (* x x))) (range 3)) :header '(n square))
+"),
+        ("inspect", "introspection", 1, inspect, true, "Print a report on a value: its type and size, the types of the first few
+elements, and nested data as a tree limited in depth and width. Records show their
+methods, and functions their arguments and doc. The REPL's `:inspect expr` does the same.
+Example:
+(inspect (list 1 \"a\"))
+; list, 2 elements
+; element types: num, str
+;   0: 1
+;   1: \"a\"
+"),
+        ("with-location", "control", 4, with_location, false, "Evaluate an expression, citing the given file, line, and column in any error.
+Code generators can use this, or a `;#line 12 \"original.dsl\"` comment, so errors point at their input.
+Example:
+(with-location \"original.dsl\" 12 3 (+ 1 \"a\")) ; error ... at original.dsl:12:3
+"),
+        ("parse-duration", "strings", 1, units::parse_duration, true, "Read a duration like \"5m30s\" or \"1h 05m\" as seconds.
+Units are ns us µs ms s m h d w, each used at most once.
+Example:
+(parse-duration \"5m30s\") ; 330
+(parse-duration \"300ms\") ; 0.3
+"),
+        ("format-duration", "strings", 1, units::format_duration, true, "Write a number of seconds as a duration, which parse-duration reads back.
+Example:
+(format-duration 3903) ; \"1h 05m 03s\"
+(format-duration 0.3) ; \"300ms\"
+"),
+        ("parse-size", "strings", 1, units::parse_size, true, "Read a size like \"1.5GiB\" as a whole number of bytes.
+Units are B, KB MB GB TB PB in powers of 1000, and KiB MiB GiB TiB PiB in powers of 1024, in any case.
+Example:
+(parse-size \"1.5GiB\") ; 1610612736
+(parse-size \"2kb\") ; 2000
+"),
+        ("format-size", "strings", 1, units::format_size, true, "Write a number of bytes in the largest unit it has one of, to two decimals.
+Give :binary true for units in powers of 1024.
+Example:
+(format-size 1500) ; \"1.50 KB\"
+(format-size 1610612736 :binary true) ; \"1.50 GiB\"
+"),
+        ("format", "strings", 1, format_exprs, true, "Fill the {} placeholders of a string with the given values, in order.
+Placeholders can have a spec like {:+08,.2%}, where each part is optional:
+  +           always show the sign
+  0width      pad with zeros to the width (or just width to pad with spaces)
+  ,           separate thousands with commas
+  .precision  round to this many decimal places
+  % x b o     percentage, or hex / binary / octal for integers
+Use {{ and }} for literal braces.
+Example:
+(format \"{} costs ${:,.2}\" \"rent\" 1234.5) ; \"rent costs $1,234.50\"
+(format \"{:%} {:+} {:08.3} {:x}\" 0.125 5 3.14159 255) ; \"12.5% +5 0003.142 ff\"
+"),
+        ("str", "strings", 0, str_exprs, true, "Concatenate the printed form of the given items into a string.
+Example:
+(str \"a\" 1 '(2 3)) ; \"a1(2 3)\"
+(str inc) ; \"#<fn inc 1>\"
+"),
+        ("read-stdin", "io", 0, read_stdin, true, "Read the rest of stdin as a string.
+Example:
+(read-stdin) ; \"line one\\nline two\\n\"
+"),
         (
             "eval",
+            "control",
             1,
             eval,
             true,
@@ -828,6 +2516,7 @@ Example (in repl):
         ),
         (
             "def",
+            "control",
             1,
             def,
             false,
@@ -838,7 +2527,7 @@ Example:
 3
 "
         ),
-        ("cond", 2, cond, false, "Branching control flow construct. Given an even list of [pred then], if `pred` is true, return `then`.
+        ("cond", "control", 2, cond, false, "Branching control flow construct. Given an even list of [pred then], if `pred` is true, return `then`.
 Example:
 (def input 10)
 (cond
@@ -846,7 +2535,16 @@ Example:
   (= input 10) (print \"input is 10\")
   true         (print \"hit base case, input is: \" input))
 "),
-        ("match", 3, expr_match, false, "Branching control flow construct. Given an item and an even list of [value then], if `item` == `value`, return `then`.
+        ("cond-expand", "control", 1, features::cond_expand, false, "Evaluate the body of the first clause whose features this build of x7 has, skipping the others
+entirely, so they can use builtins this build doesn't have. Requirements are feature keywords,
+combined with (and ...), (or ...), and (not ...), and :else, which always holds.
+Example:
+(cond-expand
+  (:json (defn encode (x) (json-serialize x)))
+  (:else (defn encode (x) (str x))))
+(encode 1)
+"),
+        ("match", "control", 3, expr_match, false, "Branching control flow construct. Given an item and an even list of [value then], if `item` == `value`, return `then`.
 Example:
 (def input 10)
 (match input
@@ -855,7 +2553,7 @@ Example:
   _  (print \"hit base case, input is: \" input))
 "),
 
-        ("if", 3, if_gate, false, "Branching control flow construct. Given pred?, then, and else, if pred? is true, return then, otherwise, else.
+        ("if", "control", 3, if_gate, false, "Branching control flow construct. Given pred?, then, and else, if pred? is true, return then, otherwise, else.
 Note: Does not evaluate branches not taken.
 Example:
 (def input 10)
@@ -863,34 +2561,135 @@ Example:
   (print \"input is 10!\")
   (print \":[ input is not 10\"))
 "),
-        ("shuffle", 1, shuffle, true, "Shuffle (randomize) a given list.
+        ("shuffle", "math", 1, shuffle, true, "Shuffle (randomize) a given list.
 Example:
 >>> (shuffle (range 10))
 (6 3 2 9 4 0 1 8 5 7)
 "),
-        ("panic", 1, panic, true, "Abort the program printing the given message.
-
-Example: (panic \"goodbye\") ; kills program
+        ("random-seed", "math", 1, random_seed, true, "Seed the random number generator, so `shuffle`, `for-all`, and the like repeat their choices.
+Example:
+(random-seed 42)
+(shuffle (range 5)) ; the same order every run
+"),
+        ("panic", "control", 1, panic, true, "Abort the program printing the given message.
 
-Your console will print the following:
+Your console will print the following, and the interpreter will stop:
 
 thread 'main' panicked at 'goodbye', src/stdlib.rs:216:5
 note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace
 
-... and the interpreter will stop.
+Example: (panic \"goodbye\") ; kills program
 "),
-        ("type", 1, type_of, true, "Return the type of the argument as a string.
+        ("type", "introspection", 1, type_of, true, "Return the type of the argument as a string.
 Example: (type \"hello\") ; str"),
-        ("doc", 1, doc, false, "Return the documentation of a symbol as a string.
+        ("identical?", "logic", 2, identical, true, "Test if two values are the same object.
+Records (like atoms), iterators, and functions are identical only to themselves.
+Other values are immutable, so they are identical whenever they are equal.
+Example:
+(identical? (atom 1) (atom 1)) ; false
+(def a (atom 1))
+(identical? a a) ; true
+(identical? '(1 2) '(1 2)) ; true
+"),
+        ("object-id", "introspection", 1, object_id, true, "Return an integer id for a value, stable for the life of the process.
+Identical values always have the same id, so it can key identity-based caches.
+Example:
+(def a (atom 1))
+(= (object-id a) (object-id a)) ; true
+(= (object-id a) (object-id (atom 1))) ; false
+"),
+        ("doc", "introspection", 1, doc, false, "Return the documentation of a symbol as a string.
 Example: (doc doc) ; Return the documentation of a symbol as a..."),
-        ("err", 1, err, true, "Return an error with a message string.
+        ("err", "control", 1, err, true, "Return an error with a message string.
 Example: (err \"Something bad happened!\") ; return an error"),
-        ("all-symbols", 0, all_symbols, true, "Return all symbols defined in the interpreter."),
+        ("throw", "control", 1, throw::throw, true, "Fail with any value as the error. If nothing handles it, it's printed like the
+renderer from set-error-renderer! says, or with its record's display method.
+Example:
+(throw (dict :kind :validation :field :email)) ; Error: {:kind: :validation, :field: :email}
+"),
+        ("set-reader-tag!", "control", 2, set_reader_tag, true, "Read the tagged literal #tag\"literal\" in source read after this with a function,
+which is given the literal as a string. Whatever it returns takes the literal's place, even in quoted data.
+nil removes the tag. Reading a tag nothing is registered for is a parse error.
+Example:
+(set-reader-tag! \"twice\" (fn (s) (str s s))) ; now #twice\"ab\" reads as \"abab\"
+"),
+        ("set-error-renderer!", "control", 1, throw::set_error_renderer, true, "Render values thrown with throw which go uncaught, with a function taking the
+value and returning a string. nil removes it. If the renderer fails, the value is printed as is.
+Example:
+(set-error-renderer! (fn (e) (str \"invalid \" (get e :field))))
+"),
+        ("warn", "control", 2, warn, true, "Emit a warning with a keyword kind and a message. Warnings go to stderr,
+or are errors if warnings are denied (--deny-warnings).
+Example:
+(warn :custom \"this is deprecated\") ; prints Warning[:custom]: this is deprecated
+"),
+        ("with-warnings-collected", "control", 1, with_warnings_collected, false, "Evaluate an expression, collecting any warnings instead of reporting them.
+Returns a list of the result and the warnings, as dicts with a kind, message, and span.
+Example:
+(with-warnings-collected (do (warn :custom \"hi\") 1))
+; (1 ({\"kind\": :custom, \"message\": \"hi\", \"span\": nil}))
+"),
+        ("cache-stats", "introspection", 0, cache_stats, true, "Return hit / miss counts for the interpreter's internal caches.
+Example:
+(parse-num \"1\")
+(parse-num \"1\")
+(cache-stats) ; {\"enabled\": true, \"num-parse\": {\"hits\": 1, \"misses\": 1, ...}}
+"),
+        ("clear-caches!", "introspection", 0, clear_caches, true, "Empty the interpreter's internal caches and reset their statistics."),
+        ("feature?", "introspection", 1, features::is_feature, true, "Whether x7 was built with the cargo feature named by a keyword, like :io or :json.
+The core language is always built, while :io, :regex, :time, and :shell are on by default.
+Example:
+(feature? :json) ; false
+"),
+        ("features", "introspection", 0, features::features, true, "The cargo features x7 was built with, as keywords.
+Example:
+(features) ; (:io :regex :time :shell :json)
+"),
+        ("terminal-info", "introspection", 0, terminal::terminal_info, true, "What the interpreter prints to, as a dict: :stdout-tty and :stderr-tty,
+:width in columns (nil if unknown), :color (:none :ansi16 :ansi256 or :truecolor),
+and :unicode. Captured output is never a terminal.
+Example:
+(get (terminal-info) :color) ; :none
+"),
+        ("x7-version", "introspection", 0, x7_version, true, "The version of the interpreter, as a string, for scripts which need newer builtins.
+Example:
+(x7-version) ; \"0.1.0\"
+"),
+        ("deprecations", "introspection", 0, deprecated::deprecations, true, "List the old names of builtins which a program uses, as dicts of
+:name, :since (the x7 version), and :replacement. Looks at the program being run,
+or the source given, without running it. Calling an old name works, but warns the first time.
+Example:
+(deprecations \"(filter is-even? (list 1 2))\") ; ({:name \"is-even?\" :since \"0.1.0\" :replacement \"even?\"})
+"),
+        ("all-symbols", "introspection", 0, all_symbols, true, "Return all symbols defined in the interpreter."),
+        ("interpreter-config", "introspection", 0, config::interpreter_config, true, "The settings of this interpreter, as a dict keyed by :sandbox, :deny-warnings,
+:deny-deprecated, :strict-nil, :frozen-globals, :no-caches, :max-value-bytes (nil if unlimited), and :history.
+Example:
+(:strict-nil (interpreter-config)) ; false
+"),
+        ("env-diff", "introspection", 1, snapshot::env_diff, false, "Evaluate an expression, returning a dict of the top level bindings it added or changed, by name.
+Redefining a function counts as a change, even if the new one is written the same.
+Example:
+(def limit 10)
+(env-diff (do (def limit 20) (def step 2))) ; {\"limit\": 20, \"step\": 2}
+"),
+        ("symbols", "introspection", 0, symbols, true, "Return the documented symbols, or with :category, the builtins in a category.
+Categories are :math, :logic, :strings, :sequences, :dicts, :functions, :control,
+:data, :records, :testing, :introspection, :modules, :paths, and :io.
+Example:
+(symbols :category :strings) ; (format str substring ...)
+"),
+        ("examples", "introspection", 1, examples, false, "Return the example forms from the docs of a symbol, ready to eval.
+Example:
+(examples substring) ; ((substring \"hello\" 1 3) (substring \"hello\" -3))
+(map eval (examples substring)) ; (\"el\" \"llo\")
+"),
         // FUNC TOOLS
-        ("map", 1, map, true, "Apply a function to each element of a sequence and return a list.
+        ("map", "sequences", 1, map, true, "Apply a function to each element of a sequence and return a list.
+Mapping over nil returns nil, unless --strict-nil is on.
 Example: (map inc '(1 2 3)) ; (2 3 4)
 "),
-        ("foreach", 2, foreach, true, "Eagerly apply the given function to a sequence or list.
+        ("foreach", "sequences", 2, foreach, true, "Eagerly apply the given function to a sequence or list.
 Example:
 (foreach
   (fn (x) (println x))
@@ -900,34 +2699,86 @@ Example:
   (fn (x) (println x))
   (take 5 (map (fn (x) (* x x x x x x)) (range)))) ; prints 0, 1, 64, 729, 4096
 "),
-        ("filter", 1, filter, true, "Retain elements in a sequence according to a predicate.
+        ("dotimes", "sequences", 2, dotimes, false, "Evaluate the body once for each index from 0 up to the count. Returns nil.
+Example:
+(dotimes (i 3) (println i)) ; prints 0, 1, and 2
+"),
+        ("repeat", "sequences", 2, repeat, false, "Evaluate an expression the given number of times, returning a list of the results.
+Example:
+(def n (atom 0))
+(repeat 3 (.reset n (inc (.deref n)))) ; (1 2 3)
+"),
+        ("repeat-v", "sequences", 2, repeat_value, true, "Make a list of the given value repeated. The value is evaluated once.
+Example:
+(repeat-v 3 \"a\") ; (\"a\" \"a\" \"a\")
+"),
+        ("times", "sequences", 2, times, true, "Call a function with each index from 0 up to the count, returning a list of the results.
+Example:
+(times 3 (fn (i) (* i i))) ; (0 1 4)
+"),
+        ("filter", "sequences", 1, filter, true, "Retain elements in a sequence according to a predicate.
 Example:
 (defn is-odd (x) (= 1 (% x 2)))
 (filter is-odd (range 20)) ; outputs (1 3 5 7 9 11 13 15 17 19)
 "),
-        ("apply", 2, apply, true, "Apply a function to a given list.
+        ("remove", "sequences", 2, remove, true, "Drop elements of a sequence for which the predicate is true, the opposite of filter.
+Given a dict and keys instead, remove those keys from the dict.
+Example:
+(remove even? '(1 2 3 4)) ; (1 3)
+(remove (dict 1 2) 1) ; {}
+"),
+        ("keep", "sequences", 2, keep, true, "Apply a function to each element of a sequence, keeping the results which aren't nil.
+Example:
+(keep (fn (x) (get (dict 1 \"one\" 3 \"three\") x)) '(1 2 3)) ; (\"one\" \"three\")
+"),
+        ("some", "sequences", 2, some, true, "Return the first result of a function on the elements of a sequence which is not nil or false.
+Returns nil if there isn't one.
+Example:
+(some (fn (x) (if (> x 2) (* x 10) false)) '(1 2 3 4)) ; 30
+(some even? '(1 3)) ; nil
+"),
+        ("filter-nil", "sequences", 1, filter_nil, true, "Drop the nils from a sequence.
+Example:
+(filter-nil (list 1 (head '()) 2)) ; (1 2)
+"),
+        ("partition", "sequences", 2, partition, true, "Split a sequence by a predicate, returning the matching and non-matching items as multiple values.
+Example:
+(partition even? '(1 2 3 4)) ; (2 4)
+(let-values (((evens odds) (partition even? '(1 2 3 4)))) odds) ; (1 3)
+"),
+        ("apply", "functions", 2, apply, true, "Apply a function to a given list.
 (def my-list '(1 2 3))
 (apply + my-list) ; outputs 6
 "),
-        ("do", 1, exprs_do, false, "Evaluate a sequence of expressions and return the last one.
+        ("do", "control", 1, exprs_do, false, "Evaluate a sequence of expressions and return the last one.
 Example:
 (defn complex-fn (x)
   (do
     (print \"current state: \" x)
     (+ x x)))
 "),
-        ("comp", 1, comp, true, "Compose given functions and return a new function. NOT IMPLEMENTED YET!"),
-        ("reduce", 2, reduce, true, "Reduce (fold) a given sequence using the given function. Reduce is multi-arity, and will accept an `init` parameter.
+        ("partial", "functions", 1, partial, true, "Partially apply a function, returning a function which takes the remaining arguments.
+Example:
+(def add-one (partial + 1))
+(add-one 2) ; 3
+(add-one 2 3) ; 6
+"),
+        ("comp", "functions", 1, comp, true, "Compose given functions and return a new function. NOT IMPLEMENTED YET!"),
+        ("reduce", "sequences", 2, reduce, true, "Reduce (fold) a given sequence using the given function. Reduce is multi-arity, and will accept an `init` parameter.
 Example:
 (reduce + '(1 2 3)) ; 6
 (reduce + 100 '(1 2 3)) ; 106
 "),
         // Functions
-        ("fn", 0, func, false, "Create a anonymous function.
+        ("fn", "functions", 0, func, false, "Create a anonymous function.
+Parameters and the return value can be annotated with types, which are not checked
+unless the function is passed to check-types.
 Example:
-(fn (x) (* x 2)) ; Fn<AnonFn, 1, [ x ]>
+(fn (x) (* x 2)) ; #<fn AnonFn 1>
+(fn (x :num) :num (* x 2)) ; #<fn AnonFn 1>
 "),
-        ("defn", 3, defn, false, "Define a function and add it to the symbol table. Supports doc strings.
+        ("defn", "functions", 3, defn, false, "Define a function and add it to the symbol table. Supports doc strings,
+and type annotations as in fn, which doc shows.
 Example:
 (defn is-odd? (x) (= 1 (% x 2)))
 (defn get-odd-numbers
@@ -935,7 +2786,16 @@ Example:
   (x)
   (filter is-odd? x)) ; for fun, try (doc get-odd-numbers)
 "),
-        ("bind", 2, bind, false, "Bind symbol-value pairs, adding them to the symbol table.
+        ("check-types", "functions", 1, check_types, true, "Wrap a function so that each call checks its arguments and result
+against the type annotations it was defined with. Types are named like `(type x)`,
+plus :any.
+Example:
+(defn add (x :num y :num) :num (+ x y))
+(doc add) ; \"add : (num num) -> num\"
+(def checked-add (check-types add))
+(checked-add 1 \"2\") ; error: add expected y to be num, but got str \"2\"
+"),
+        ("bind", "control", 2, bind, false, "Bind symbol-value pairs while evaluating the body. Each value sees the names before it.
 Example:
 (defn quicksort
   \"Sort a list.\"
@@ -948,101 +2808,2266 @@ Example:
           le    (filter (fn (x) (<= x pivot)) rest)
           ge    (filter (fn (x) (> x pivot)) rest))
          (+ (quicksort le) (list pivot) (quicksort ge)))))
+"),
+        ("values", "functions", 0, values, true, "Return multiple values. Use let-values to bind them; everywhere else
+only the first value is seen.
+Example:
+(values 1 2) ; 1
+(let-values (((a b) (values 1 2))) (+ a b)) ; 3
+"),
+        ("let-values", "functions", 2, let_values, false, "Bind the multiple values returned by expressions, then evaluate the body.
+Supports & to capture excess values. Errors if the number of values doesn't match.
+Example:
+(let-values (((q r) (div-mod 17 5))
+             ((x & rest) (values 1 2 3)))
+  (list q r x rest)) ; (3 2 1 (2 3))
 "),
         // Iterators
-        ("take", 2, take, true, "Take the first `n` items from a list or sequence.
+        ("take", "sequences", 2, take, true, "Take the first `n` items from a list or sequence.
 Example:
 (take 2 '(1 2 3)) ; (1 2)
 (take 5 (range)) ; lazy seq of (0 1 2 3 4)
 (doall (take 5 (range))) ; (0 1 2 3 4)
 "),
-        ("doall", 1, doall, true, "Evaluate a sequence, collecting the results into a list.
+        ("generator", "sequences", 1, generator::generator, false, "Make an iterator of the values the body passes to `yield`.
+The body runs when items are asked for, and stops at each yield until the next one is.
+It runs on its own thread with a copy of the current bindings, so defs made in it aren't
+seen outside, but atoms are shared. Errors in the body are raised where the items are consumed.
+Example:
+(def evens (generator (foreach (fn (x) (if (even? x) (yield x) ())) (range))))
+(doall (take 3 evens)) ; (0 2 4)
+"),
+        ("yield", "sequences", 1, generator::yield_value, true, "Hand a value to whatever is consuming the enclosing generator, and wait
+until it asks for the next one. Errors outside of a generator.
+Example:
+(doall (generator (yield 1) (yield 2))) ; (1 2)
+"),
+        ("doall", "sequences", 1, doall, true, "Evaluate a sequence, collecting the results into a list.
 Example:
 (doall (take 5 (range))) ; (0 1 2 3 4)
 "),
         // Dicts
-        ("dict", 0, make_dict, true, "Create a dict from the given elements.
+        ("dict", "dicts", 0, make_dict, true, "Create a dict from the given elements.
 Example:
 (dict \"a\" 1 \"b\" 2) ;
 "),
-        ("assoc", 1, assoc, true, "Create a new dict from an old dict with the given elements.
+        ("assoc", "dicts", 1, assoc, true, "Create a new dict from an old dict with the given elements.
 Example:
 (assoc (dict) 1 2 3 4) ; {1: 2, 3: 4}
 "),
-        ("remove", 2, remove, true, "Remove a key-value pair from a dict.
-Example:
-(remove (dict 1 2) 1) ; {}
-"),
-        ("get", 2, get_dict, true, "Get a value from a dict by key.
+        ("get", "dicts", 2, get_dict, true, "Get a value from a dict by key.
 Example:
 (get (dict 1 2) 1) ; 2
 (get (dict) 1) ; nil
 "),
-        // Lists
-        ("list", 0, list, true, "Create a list from the given elements.
+        ("contains?", "dicts", 2, contains, true, "Whether a dict has a key.
 Example:
-(list 1 2 3) ; (1 2 3)
+(contains? (dict :a nil) :a) ; true
+(contains? (dict :a 1) :b) ; false
 "),
-        ("tuple", 0, tuple, true, "Create a list from the given elements.
-(tuple 1 2 3) ; (tuple 1 2 3)
-;; It's usually easier to use the tuple syntax:
-^(1 2 3) ; (tuple 1 2 3)
+        ("get-in", "dicts", 2, get_in, true, "Follow a path of keys into nested dicts, and indexes into lists.
+Gives the default, or nil, if anything on the path is missing.
+Example:
+(get-in (dict :a (dict :b '(1 2))) '(:a :b 1)) ; 2
+(get-in (dict :a 1) '(:b :c) 0) ; 0
 "),
-        ("nth", 2, nth, true, "Extract the nth item from a list or tuple. Throws error if this fails.
-Example
-(nth 0 ^(1 2 3)) ; 1
-(nth 1 '(1 2 3)) ; 2
+        ("deep-copy", "dicts", 1, deep_copy, true, "Copy a value, making read-only dict records in it plain dicts which can be changed.
+Atoms in it are copied into new atoms. Copying an atom which contains itself is a :cyclic error.
+Example:
+(deep-copy (list (dict :a 1))) ; ({:a: 1})
 "),
-        ("head", 1, head, true, "Get the first item in a list.
+        ("map-vals", "dicts", 2, map_vals, true, "Apply a function to each value of a dict, returning a dict.
 Example:
-(head ()) ; nil
-(head (1 2 3)) ; 1
+(map-vals inc (dict \"a\" 1 \"b\" 2)) ; {\"a\": 2, \"b\": 3}
 "),
-        ("tail", 1, tail, true, "Get all items after the first in a list or tuple.
-(tail '(1 2 3)) ; (2 3)
-(tail ^()) ; nil
+        ("map-keys", "dicts", 2, map_keys, true, "Apply a function to each key of a dict, returning a dict.
+Errors, listing the keys involved, if two keys map to the same new key.
+Example:
+(map-keys inc (dict 1 \"a\" 2 \"b\")) ; {2: \"a\", 3: \"b\"}
+(map-keys (fn (k) 0) (dict 1 \"a\" 2 \"b\")) ; error: 1, 2 all map to 0
 "),
-        ("cons", 2, cons, true, "Push an item to the front of a list.
+        ("filter-kv", "dicts", 2, filter_kv, true, "Keep the entries of a dict for which the predicate, given the key and value, is true.
 Example:
-(cons 1 '()) ; (1)
-(cons 1 '(2 3)) ; (1 2 3)
+(filter-kv (fn (k v) (> v 1)) (dict \"a\" 1 \"b\" 2)) ; {\"b\": 2}
 "),
-        ("range", 0, range, true, "Generate a range of numbers. It accepts 0, 1, or 2 arguments. No arguments
-yields an infinite range, one arg stops the range at that arg, and two args denote start..end.
+        ("reduce-kv", "dicts", 3, reduce_kv, true, "Reduce a dict with a function given the accumulator, key, and value.
+Dicts are unordered, so the function should not depend on the order of entries.
 Example:
-(range) ; infinite range
-(range 5) ; (0 1 2 3 4)
-(range 5 10); (5 6 7 8 9)
+(reduce-kv (fn (acc k v) (+ acc v)) 0 (dict \"a\" 1 \"b\" 2)) ; 3
 "),
-        ("len", 1, len, true, "Get the number of items in a list or tuple.
+        ("select-keys", "dicts", 2, select_keys, true, "Make a dict of only the given keys. Keys not in the dict are skipped.
 Example:
-(len '(0 0 0)) ; 3
-(len '()) ; 0
+(select-keys (dict :a 1 :b 2 :c 3) '(:a :b :d)) ; {:a: 1, :b: 2}
 "),
-        ("zip", 2, zip, true, "Zip two lists together into a list of tuples."),
-        ("len", 1, len, true, "Get the number of items in a list or tuple.
+        ("merge", "dicts", 0, merge, true, "Merge dicts. Later dicts win when keys collide.
 Example:
-(len '(0 0 0)) ; 3
-(len '()) ; 0
+(merge (dict :a 1 :b 2) (dict :b 3)) ; {:a: 1, :b: 3}
 "),
-
-        ("sort", 1, sort, true, "Sort a given homogeneously typed list in ascending order. Returns an error if types are all not the same.
+        ("merge-with", "dicts", 1, merge_with, true, "Merge dicts, resolving colliding keys by calling `f` with the old and new values.
 Example:
-(sort '(3 7 0 5 4 8 1 2 6 9)) ; (0 1 2 3 4 5 6 7 8 9)
+(merge-with + (dict :a 1 :b 2) (dict :b 3) (dict :b 4)) ; {:a: 1, :b: 9}
 "),
-        ("fs::open", 1, FileRecord::from_x7, true, "Open a file. Under construction."),
-        ("call_method", 2, call_method, true, "
-Call a method on a record.
-
+        ("deep-merge", "dicts", 0, deep_merge, true, "Merge dicts, recursing into dicts found under the same key.
+Anything else, including lists, is replaced by the later value, unless `:concat-lists true`
+is passed last, in which case lists are concatenated.
 Example:
-
-(def f (fs::open \"Hello.txt\"))
-(call_method f \"read_to_string\") ;; no args required
-(call_method f \"write\" \"hello world\") ;; pass it an arg
+(deep-merge (dict :db (dict :host \"a\" :port 1)) (dict :db (dict :port 2)))
+; {:db: {:host: \"a\", :port: 2}}
+(deep-merge (dict :xs '(1)) (dict :xs '(2)) :concat-lists true) ; {:xs: (1 2)}
 "),
-        ("methods", 1, doc_methods, false, "Grab all documentation for a record's methods")
-    );
-    load_x7_stdlib(opts, &syms).unwrap();
-    document_records!(syms, FileRecord);
-    syms
+        ("dict-from-pairs", "dicts", 1, dicts::dict_from_pairs, true, "Make a dict from a list of (key value) pairs. Later pairs win when a key repeats,
+unless `:on-duplicate :error` is passed last.
+Example:
+(dict-from-pairs '((:a 1) (:b 2))) ; {:a: 1, :b: 2}
+(dict-from-pairs '((:a 1) (:a 2))) ; {:a: 2}
+"),
+        ("zipmap", "dicts", 2, dicts::zipmap, true, "Make a dict from a list of keys and a list of values. Lists of different lengths
+are an error, unless `:on-mismatch :truncate` is passed last to drop the extras.
+Example:
+(zipmap '(:a :b) '(1 2)) ; {:a: 1, :b: 2}
+(zipmap '(:a :b) '(1 2 3) :on-mismatch :truncate) ; {:a: 1, :b: 2}
+"),
+        ("invert", "dicts", 1, dicts::invert, true, "Swap the keys and values of a dict. Values shared by several keys are an error,
+unless `:collect true` is passed last, making every value a list of its keys in sorted order.
+Example:
+(invert (dict :a 1 :b 2)) ; {1: :a, 2: :b}
+(invert (dict :a 1 :b 2 :c 1) :collect true) ; {1: (:a :c), 2: (:b)}
+"),
+        ("index-by", "dicts", 2, dicts::index_by, true, "Make a dict of the items of a list, keyed by calling a function on each. A keyword
+looks up that key of dicts, or that field of records. Later items win when keys repeat.
+Example:
+(index-by :id (list (dict :id 1 :name \"ann\") (dict :id 2 :name \"bo\"))) ; {1: {...}, 2: {...}}
+(index-by len '(\"a\" \"bb\")) ; {1: \"a\", 2: \"bb\"}
+"),
+        ("with-meta", "data", 2, meta::with_meta, true, "Attach a dict of metadata to a value, or remove it with nil. The value is
+otherwise unchanged: it's equal to, hashes like, and prints like the value without it.
+assoc, merge, merge-with, deep-merge, and cons keep the metadata of the collection they update.
+Anything else, like arithmetic or string functions, makes new values without metadata.
+That includes list, dict and the other builtins building collections: their elements lose it.
+Example:
+(meta (with-meta '(1 2) (dict :line 12))) ; {:line: 12}
+(= (with-meta 1 (dict :line 12)) 1) ; true
+(meta (assoc (with-meta (dict) (dict :line 12)) :a 1)) ; {:line: 12}
+(meta (first (list (with-meta 1 (dict :line 12))))) ; nil
+"),
+        ("meta", "data", 1, meta::meta, true, "The metadata of a value, from with-meta, or nil if it has none.
+Example:
+(meta (with-meta :k (dict :source \"config.x7\"))) ; {:source: \"config.x7\"}
+(meta 1) ; nil
+"),
+        ("canonical-hash", "data", 1, canonical::canonical_hash, true, "The sha256 of a value's canonical encoding, as hex, for keying caches across processes.
+Equal values hash the same, so numbers like 1.5 and 1.50 agree and dict order doesn't matter.
+Functions, lazy sequences, and records can't be hashed.
+Example:
+(= (canonical-hash 1.50) (canonical-hash 1.5)) ; true
+(= (canonical-hash (dict :a 1 :b 2)) (canonical-hash (dict :b 2 :a 1))) ; true
+(= (canonical-hash '(1 2)) (canonical-hash ^(1 2))) ; false
+"),
+        ("conform", "data", 2, conform, true, "Validate data against a schema, returning the data with defaults applied
+and strings converted to numbers where the schema asks for them.
+Errors list every violation with its path. Schemas are data:
+:any :string :bool :keyword    a value of that type
+:int :num                      a number, or a string which parses as one
+(:list schema)                 a list of items conforming to schema
+(:optional schema default)     a dict key which may be missing, using the default if given
+(dict :key schema ...)         a dict with these keys. Other keys are kept.
+Example:
+(conform (dict :port \"80\") (dict :port :int :debug '(:optional :bool false)))
+; {:port: 80, :debug: false}
+"),
+        ("template", "data", 1, template, true, "Check that quoted data can be used as a template for `fill`, and return it.
+Symbols like ?name are placeholders, and ?@name splices a list into the surrounding list.
+Example:
+(template '(query :table ?table :where ?cond)) ; (query :table ?table :where ?cond)
+"),
+        ("fill", "data", 2, fill, true, "Replace the placeholders in a template with values from a dict keyed by
+placeholder name. Unbound placeholders are errors, and so are unused keys
+unless `:allow-extra true` is passed last.
+Example:
+(fill (template '(query :table ?table :where ?cond)) (dict :table \"users\" :cond '(> age 18)))
+; (query :table \"users\" :where (> age 18))
+(fill '(+ ?@xs) (dict :xs '(1 2 3))) ; (+ 1 2 3)
+"),
+        ("parse-args", "data", 2, args::parse_args, true, "Parse command line arguments, like script-args, against a spec dict.
+The spec has :flags, a dict of flag keyword to options (:short :bool :takes-value
+:default :type :help), and :positional, a list of keywords for the required
+arguments. Returns a dict of every flag and positional, with the extra
+arguments under :rest. Unknown flags, missing arguments, and bad values are errors.
+Example:
+(def spec (dict :flags (dict :verbose (dict :short \"v\" :bool true)
+                             :output (dict :short \"o\" :takes-value true :default \"out.txt\"))
+                :positional '(:input)))
+(get (parse-args '(\"-v\" \"in.txt\") spec) :verbose) ; true
+(get (parse-args '(\"-vo\" \"x\" \"in.txt\" \"more\") spec) :rest) ; (\"more\")
+"),
+        ("usage", "data", 1, args::usage, true, "The help text for a parse-args spec, listing its flags with their :help.
+Example:
+(usage (dict :name \"greet\" :positional '(:name))) ; \"Usage: greet <name>\\n\"
+"),
+        // Lists
+        ("list", "sequences", 0, list, true, "Create a list from the given elements.
+Example:
+(list 1 2 3) ; (1 2 3)
+"),
+        ("tuple", "sequences", 0, tuple, true, "Create a list from the given elements.
+(tuple 1 2 3) ; (tuple 1 2 3)
+;; It's usually easier to use the tuple syntax:
+^(1 2 3) ; (tuple 1 2 3)
+"),
+        ("nth", "sequences", 2, nth, true, "Extract the nth item from a list or tuple. Throws error if this fails.
+Negative indices count from the end.
+Example
+(nth 0 ^(1 2 3)) ; 1
+(nth 1 '(1 2 3)) ; 2
+(nth -1 '(1 2 3)) ; 3
+"),
+        ("slice", "sequences", 2, slice, true, "Get the items of a list from `start` up to (not including) `end`.
+Negative positions count from the end, and positions past the end are clamped.
+Example:
+(slice '(1 2 3 4) 1 3) ; (2 3)
+(slice '(1 2 3 4) -2) ; (3 4)
+"),
+        ("subvec", "sequences", 2, subvec, true, "Get the items of a list from `start` up to (not including) `end`, or the end of the list.
+Unlike slice, positions out of range are errors. The result shares the list's structure,
+so taking it is O(log n) rather than a copy.
+Example:
+(subvec '(1 2 3 4) 1 3) ; (2 3)
+(subvec '(1 2 3 4) 2) ; (3 4)
+"),
+        ("concat", "sequences", 0, concat, true, "Join lists end to end. The result shares the structure of the lists,
+so joining two is O(log n) rather than a copy.
+Example:
+(concat '(1 2) '(3) '(4 5)) ; (1 2 3 4 5)
+"),
+        ("window", "sequences", 2, window, true, "A lazy sequence of the windows of n items over a list, each starting `step`
+items after the last, 1 by default. Only whole windows are made. Each window shares the
+list's structure, so making one is O(log n) however big it is.
+Example:
+(doall (window 2 '(1 2 3 4))) ; ((1 2) (2 3) (3 4))
+(doall (window 2 2 '(1 2 3 4 5))) ; ((1 2) (3 4))
+"),
+        ("digit?", "strings", 1, chars::is_digit, true, "Test if a one-character string is a digit, in any script.
+Example:
+(digit? \"7\") ; true
+(digit? \"x\") ; false
+"),
+        ("alpha?", "strings", 1, chars::is_alpha, true, "Test if a one-character string is a letter, in any script.
+Example:
+(alpha? \"é\") ; true
+(alpha? \"1\") ; false
+"),
+        ("alphanumeric?", "strings", 1, chars::is_alphanumeric, true, "Test if a one-character string is a letter or a digit.
+Example:
+(alphanumeric? \"a\") ; true
+(alphanumeric? \"-\") ; false
+"),
+        ("whitespace?", "strings", 1, chars::is_whitespace, true, "Test if a one-character string is whitespace.
+Example:
+(whitespace? \" \") ; true
+(whitespace? \"a\") ; false
+"),
+        ("upper?", "strings", 1, chars::is_upper, true, "Test if a one-character string is an uppercase letter.
+Example:
+(upper? \"A\") ; true
+(upper? \"a\") ; false
+"),
+        ("lower?", "strings", 1, chars::is_lower, true, "Test if a one-character string is a lowercase letter.
+Example:
+(lower? \"a\") ; true
+(lower? \"1\") ; false
+"),
+        ("char->digit", "strings", 1, chars::char_to_digit, true, "The value of a one-character string as a digit in the given radix, 10 by default,
+or nil if it isn't one. Only ASCII digits and letters are digits.
+Example:
+(char->digit \"7\") ; 7
+(char->digit \"f\" 16) ; 15
+(char->digit \"g\" 16) ; nil
+"),
+        ("digit->char", "strings", 1, chars::digit_to_char, true, "The one-character string for a digit in the given radix, 10 by default.
+Example:
+(digit->char 7) ; \"7\"
+(digit->char 15 16) ; \"f\"
+"),
+        ("substring", "strings", 2, substring, true, "Get the characters of a string from `start` up to (not including) `end`.
+Negative positions count from the end, and positions past the end are clamped.
+Example:
+(substring \"hello\" 1 3) ; \"el\"
+(substring \"hello\" -3) ; \"llo\"
+"),
+        ("lines", "strings", 1, text::lines, true, "Split a string into lines, on any of \\n, \\r\\n, and \\r.
+A final line ending doesn't start another line.
+Example:
+(lines \"GET /\nPOST /login\n\") ; (\"GET /\" \"POST /login\")
+"),
+        ("fields", "strings", 1, text::fields, true, "Split a line on runs of whitespace, ignoring whitespace at either end.
+Given a separator, a string or a regex, split on each occurrence of it instead.
+Example:
+(fields \"  GET /index 200 \") ; (\"GET\" \"/index\" \"200\")
+(fields \"a,,b\" \",\") ; (\"a\" \"\" \"b\")
+"),
+        ("col", "strings", 2, text::col, true, "Get the nth whitespace separated field of a line, counting from 1 like awk.
+Column 0 is the whole line, and missing columns are nil.
+Example:
+(col 2 \"GET /index 200\") ; \"/index\"
+(col 4 \"GET /index 200\") ; nil
+"),
+        ("grep", "strings", 2, text::grep, true, "Keep the lines containing a pattern, which is a string matched literally or a regex.
+Lazy when given a lazy sequence, like the lines of lines-gz.
+Example:
+(grep \"ERROR\" '(\"ERROR disk\" \"INFO ok\")) ; (\"ERROR disk\")
+(grep (regex \"^[0-9]+$\") '(\"12\" \"a1\")) ; (\"12\")
+"),
+        ("grep-v", "strings", 2, text::grep_v, true, "Keep the lines not containing a pattern, which is a string matched literally or a regex.
+Lazy when given a lazy sequence, like the lines of lines-gz.
+Example:
+(grep-v \"DEBUG\" '(\"DEBUG x\" \"INFO ok\")) ; (\"INFO ok\")
+"),
+        ("sub", "strings", 3, text::sub, true, "Replace the first match of a pattern in a line.
+Regex replacements can refer to groups with $1, $2, and so on.
+Example:
+(sub \"o\" \"0\" \"foo\") ; \"f0o\"
+(sub (regex \"([a-z]+)=([0-9]+)\") \"$2=$1\" \"x=1\") ; \"1=x\"
+"),
+        ("gsub", "strings", 3, text::gsub, true, "Replace every match of a pattern in a line.
+Regex replacements can refer to groups with $1, $2, and so on.
+Example:
+(gsub \"o\" \"0\" \"foo\") ; \"f00\"
+(gsub (regex \"[0-9]+\") \"N\" \"id 12 of 345\") ; \"id N of N\"
+"),
+        ("drop", "sequences", 2, drop, true, "Drop the first `n` items of a list.
+Example:
+(drop 2 '(1 2 3)) ; (3)
+"),
+        ("head", "sequences", 1, head, true, "Get the first item in a list.
+Example:
+(head ()) ; nil
+(head '(1 2 3)) ; 1
+"),
+        ("first", "sequences", 1, head, true, "Get the first item in a list. Same as head.
+Example:
+(first '(1 2 3)) ; 1
+"),
+        ("tail", "sequences", 1, tail, true, "Get all items after the first in a list or tuple.
+(tail '(1 2 3)) ; (2 3)
+(tail ^()) ; nil
+"),
+        ("cons", "sequences", 2, cons, true, "Push an item to the front of a list.
+Example:
+(cons 1 '()) ; (1)
+(cons 1 '(2 3)) ; (1 2 3)
+"),
+        ("range", "sequences", 0, range, true, "Generate a range of numbers. It accepts 0, 1, or 2 arguments. No arguments
+yields an infinite range, one arg stops the range at that arg, and two args denote start..end.
+Example:
+(range) ; infinite range
+(range 5) ; (0 1 2 3 4)
+(range 5 10); (5 6 7 8 9)
+"),
+        ("len", "sequences", 1, len, true, "Get the number of items in a list or tuple.
+Example:
+(len '(0 0 0)) ; 3
+(len '()) ; 0
+"),
+        ("zip", "sequences", 2, zip, true, "Zip two lists together into a list of tuples."),
+        ("count", "sequences", 1, len, true, "Get the number of items in a list or tuple. Same as len.
+Example:
+(count '(0 0 0)) ; 3
+"),
+
+        ("reverse", "sequences", 1, reverse, true, "Reverse a list.
+Example:
+(reverse '(1 2 3)) ; (3 2 1)
+"),
+        ("not=", "logic", 1, not_eq_exprs, true, "Test if a sequence is not equal to each other.
+Example:
+(not= 1 1 2) ; true
+"),
+        ("deep=", "logic", 1, deep_eq_exprs, true, "Test if all items are equal, comparing atoms by what they hold rather than
+by identity. Comparing atoms which contain themselves is a :cyclic error.
+Example:
+(deep= (atom '(1 2)) (atom '(1 2))) ; true
+(= (atom 1) (atom 1)) ; false
+"),
+        ("dot-product", "math", 2, dot_product, true, "Dot product two vectors.
+Example:
+(dot-product '(1 2 3) '(4 5 6)) ; 32
+"),
+        ("quicksort", "sequences", 1, quicksort, true, "Sort a list using quicksort.
+Example:
+(quicksort '(3 1 2)) ; (1 2 3)
+"),
+        ("assert-eq", "testing", 3, assert_eq_exprs, true, "Test if two items are equal, and panic otherwise.
+When they differ, the error lists the first few paths where they differ.
+Example:
+(assert-eq 1 1 \"1 should be 1\") ; ()
+(assert-eq (dict :a '(1 2)) (dict :a '(1 3)) \"lists\") ; error: lists ... :a 1: expected 2, got 3
+"),
+        ("assert-matches", "testing", 2, assert_matches, false, "Test if a value matches a pattern, and error otherwise.
+Patterns aren't evaluated. `_` matches anything, (dict :key pattern ...) matches dicts
+with at least those keys, (list ...), (tuple ...) and '(...) match items in order,
+and anything else is evaluated and compared with =. The error names the sub-pattern which failed.
+Example:
+(assert-matches (dict :status 200 :body _) (dict :status 200 :body \"ok\")) ; ()
+(assert-matches (dict :status 200) (dict :status 404)) ; error: ... at :status: expected 200, found num 404
+"),
+        ("gen-int", "testing", 2, property::gen_int, true, "Make a generator of integers from lo to hi, inclusive, for for-all.
+Example:
+(gen-int -10 10)
+"),
+        ("gen-string", "testing", 2, property::gen_string, true, "Make a generator of strings with a length in the range (min max), drawn from the characters of charset.
+Example:
+(gen-string '(0 8) \"abc123\")
+"),
+        ("gen-list", "testing", 2, property::gen_list, true, "Make a generator of lists of values from gen, with a length in the range (min max).
+Example:
+(gen-list (gen-int 0 9) '(1 5))
+"),
+        ("gen-dict", "testing", 2, property::gen_dict, true, "Make a generator of dicts with keys from keygen and values from valgen.
+Example:
+(gen-dict (gen-one-of '(:a :b :c)) (gen-int 0 9))
+"),
+        ("gen-one-of", "testing", 1, property::gen_one_of, true, "Make a generator which picks one of a list of generators. Other values are picked as they are.
+Example:
+(gen-one-of (list (gen-int 0 9) (gen-string '(0 3) \"ab\") :none))
+"),
+        ("for-all", "testing", 3, property::for_all, false, "Check a property against n cases drawn from generators, returning true if it always holds.
+The property fails when it returns false or errors. The failing case is shrunk to a simpler one,
+and the error reports it with the seed to replay the run with, as `:seed s`. See also random-seed.
+Example:
+(for-all ((gen-int 0 100) (gen-int 0 100)) 100 (fn (a b) (= (+ a b) (+ b a)))) ; true
+(for-all ((gen-int 0 1000)) 100 (fn (x) (< x 50))) ; error: ... Smallest failing case: (50), which returned false. Replay with :seed ...
+"),
+        ("sort", "sequences", 1, sort, true, "Sort a list in ascending order. Values of different types are grouped by type:
+nil < bools < nums < strings < keywords < symbols < lists < tuples < dicts < everything else.
+Lists and tuples compare element by element. Unlike <, this never errors on mixed types.
+Example:
+(sort '(3 7 0 5 4 8 1 2 6 9)) ; (0 1 2 3 4 5 6 7 8 9)
+(sort (list \"b\" :k 2 nil \"a\" 1)) ; (nil 1 2 \"a\" \"b\" :k)
+"),
+        ("sort-by", "sequences", 2, sort_by, true, "Sort a list by the result of calling a function on each item, in the order `sort` uses.
+Items with equal keys keep their order.
+Example:
+(sort-by len '(\"ccc\" \"a\" \"bb\")) ; (\"a\" \"bb\" \"ccc\")
+"),
+        ("group-by", "sequences", 2, group_by, true, "Group the items of a list into a dict, keyed by the result of calling a function
+on each item. Each group keeps the items in their original order.
+Example:
+(group-by even? '(1 2 3 4 5)) ; {false: (1 3 5), true: (2 4)}
+(keys (group-by len '(\"a\" \"bb\" \"c\"))) ; (1 2)
+"),
+        ("keys", "dicts", 1, keys, true, "The keys of a dict, in the order `sort` uses.
+Example:
+(keys (dict :b 1 \"a\" 2 :a 3)) ; (\"a\" :a :b)
+"),
+        ("require", "modules", 1, import, false, "Load a module, binding each of its definitions as module::name.
+Modules are found as `name.x7` in the current directory or the stdlib directory,
+or can be given as a path string. Modules are only evaluated once.
+Example:
+(require utils)
+(utils::helper 1)
+(require \"lib/parsing.x7\") ; binds parsing::...
+"),
+        ("import", "modules", 1, import, false, "Load a module and control how its definitions are bound.
+Options:
+  :as alias            bind definitions as alias::name
+  :only (a b)          bind only the given names, unprefixed
+  :rename ((a x))      bind `a` as `x`
+  :all                 bind every definition unprefixed, warning on shadowing
+Importing the same unprefixed name from two modules is an error.
+Example:
+(import utils :as u)
+(u::helper 1)
+(import utils :only (helper parse))
+(import utils :rename ((helper h)))
+(import utils :all)
+"),
+        ("require-string", "modules", 2, require_string, true, "Define a module from a string of source code, binding its definitions as name::definition.
+The module can then be imported by name.
+Example:
+(require-string \"gen\" \"(defn helper (x) (* x 2))\")
+(gen::helper 2) ; 4
+(import gen :only (helper))
+"),
+        ("require-url", "modules", 3, require_url, false, "Fetch a module over http(s), verify its sha256 digest, and load it like require.
+Fetched modules are cached on disk by digest. A digest mismatch is an error, and nothing is evaluated.
+Requires the `http` cargo feature, and is disabled in sandbox mode.
+Example:
+(require-url \"https://example.com/lib.x7\" :sha256 \"9f86d081884c7d65...\")
+(lib::helper 1)
+"),
+        ("source", "modules", 1, source, false, "Return the module an imported symbol came from, or nil.
+Example:
+(import utils :only (helper))
+(source helper) ; \"./utils.x7\"
+"),
+        // Paths
+        ("path-join", "paths", 1, paths::path_join, true, "Join path components with the platform's separator.
+A later absolute component replaces everything before it.
+Example:
+(path-join \"src\" \"lib\" \"main.x7\") ; \"src/lib/main.x7\" (\"src\\lib\\main.x7\" on Windows)
+"),
+        ("path-parent", "paths", 1, paths::path_parent, true, "Return the path without its last component, or nil for a root.
+Example:
+(path-parent \"src/lib/main.x7\") ; \"src/lib\"
+"),
+        ("path-filename", "paths", 1, paths::path_filename, true, "Return the last component of a path, or nil if there is none.
+Example:
+(path-filename \"src/lib/main.x7\") ; \"main.x7\"
+"),
+        ("path-extension", "paths", 1, paths::path_extension, true, "Return the extension of a path without the dot, or nil.
+Example:
+(path-extension \"src/lib/main.x7\") ; \"x7\"
+"),
+        ("path-absolute", "paths", 1, paths::path_absolute, true, "Make a path absolute by joining it to the current directory.
+The path doesn't have to exist. Use path-canonical to resolve links and `..`.
+Example:
+(path-absolute \"main.x7\") ; \"/home/me/project/main.x7\"
+"),
+        ("path-canonical", "paths", 1, paths::path_canonical, true, "Resolve a path to an absolute path without links or `..`.
+Errors if the path doesn't exist.
+Example:
+(path-canonical \"./src/../Cargo.toml\") ; \"/home/me/project/Cargo.toml\"
+"),
+        ("path-relative", "paths", 2, paths::path_relative, true, "Return the path which leads from base to path, using `..` to go up.
+Both must be absolute or both relative. The filesystem isn't consulted.
+Example:
+(path-relative \"/srv/app\" \"/srv/data/x.csv\") ; \"../data/x.csv\"
+"),
+        ("path-exists?", "paths", 1, paths::path_exists, true, "Test if a path exists.
+Example:
+(path-exists? \"Cargo.toml\") ; true
+"),
+        ("glob", "paths", 1, paths::glob, true, "Return the sorted paths matching a glob pattern. `**` matches any number of directories.
+Example:
+(glob \"src/**/*.x7\") ; (\"src/a.x7\" \"src/lib/b.x7\")
+"),
+        ("home-dir", "paths", 0, paths::home_dir, true, "Return the current user's home directory.
+Example:
+(home-dir) ; \"/home/me\"
+"),
+        ("temp-dir", "paths", 0, paths::temp_dir, true, "Return the directory for temporary files.
+Example:
+(temp-dir) ; \"/tmp\"
+"),
+        ("atom", "records", 1, AtomRecord::from_x7, true, "Create a mutable reference to a value. Atoms that contain themselves print as #<cycle>.
+Example:
+(def a (atom 1))
+(.reset a 2)
+(.deref a) ; 2
+"),
+        ("defrecord", "records", 2, user_record::defrecord, false, "Define a record type with fields and methods, binding its constructor and a
+predicate. Fields are written x, or (x default) to give a default, which is nil otherwise.
+Methods are (defn name (self args...) body), called like (.name instance args...).
+A method named call makes instances callable, so (instance args...) is (.call instance args...).
+Redefining a record makes a new version of it. Existing instances keep the fields and methods
+they were made with, and upgrade-record moves them to the latest version.
+Example:
+(defrecord Point (x (y 0))
+  (defn norm (self) (+ (.x self) (.y self))))
+(def p (Point 3 4))
+(.x p) ; 3
+(.norm p) ; 7
+(Point? p) ; true
+"),
+        ("upgrade-record", "records", 1, user_record::upgrade_record, true, "Make a copy of a record under the latest version of its type. Fields are matched
+by name, new fields get their defaults, and removed fields are dropped.
+Example:
+(defrecord Point (x y))
+(def p (Point 1 2))
+(defrecord Point (x y (z 0)))
+(.z (upgrade-record p)) ; 0
+"),
+        ("progress-bar", "io", 1, ProgressRecord::from_x7, true, "Make a record which reports progress towards total on stderr with .tick, .inc, .set-message, and .finish.
+Terminals get a bar redrawn at most 10 times a second, and anything else a log line every second.
+Example:
+(def p (progress-bar 3))
+(.set-message p \"copying\")
+(dotimes (i 3) (.tick p))
+(.finish p) ; copying done: 3/3 in 0.0s
+"),
+        ("with-open", "io", 2, with_open, false, "Bind records like files to names while evaluating a body, closing them afterwards, even on error.
+Later bindings can use earlier ones, and they're closed in reverse order.
+Example:
+(with-open (f (fs::open \"notes.txt\"))
+  (.write f \"hello\"))
+"),
+        ("with-progress", "io", 2, progress::with_progress, false, "Evaluate a body with a progress bar bound, finishing it afterwards, even on error.
+Example:
+(with-progress (p (len files))
+  (foreach (fn (f) (process f) (.tick p)) files))
+"),
+        ("call_method", "records", 2, call_method, true, "
+Call a method on a record.
+
+Example:
+
+(def f (fs::open \"Hello.txt\"))
+(call_method f \"read_to_string\") ;; no args required
+(call_method f \"write\" \"hello world\") ;; pass it an arg
+"),
+        ("methods", "records", 1, doc_methods, false, "Grab all documentation for a record's methods")
+    );
+    #[cfg(feature = "io")]
+    crate::files::register(&syms);
+    #[cfg(feature = "io")]
+    crate::tempfiles::register(&syms);
+    #[cfg(feature = "regex")]
+    crate::text::register(&syms);
+    #[cfg(feature = "time")]
+    crate::retry::register(&syms);
+    #[cfg(feature = "shell")]
+    crate::env::register(&syms);
+    #[cfg(feature = "compression")]
+    crate::compression::register(&syms);
+    #[cfg(feature = "json")]
+    crate::serde_formats::register(&syms);
+    #[cfg(feature = "watch")]
+    crate::watch::register(&syms);
+    // nil has no syntax of its own, so it's bound like a builtin.
+    syms.add_global("nil", Expr::Nil);
+    syms.add_doc_item(
+        "nil".into(),
+        "The absence of a value. Sequence builtins treat it as an empty list, unless --strict-nil is on.
+Example: (head '()) ; nil
+"
+        .into(),
+    );
+    syms.set_category("nil", "data");
+    load_x7_stdlib(opts, &syms).unwrap();
+    deprecated::register(&syms);
+    document_records!(syms, AtomRecord, ProgressRecord, GenRecord);
+    #[cfg(feature = "io")]
+    {
+        use crate::records::{FileRecord, KvRecord};
+        document_records!(syms, FileRecord, KvRecord);
+    }
+    #[cfg(feature = "time")]
+    {
+        use crate::records::RateLimiterRecord;
+        document_records!(syms, RateLimiterRecord);
+    }
+    #[cfg(feature = "regex")]
+    {
+        use crate::text::RegexRecord;
+        document_records!(syms, RegexRecord);
+    }
+    syms
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::read;
+
+    fn eval_str_with(prog: &str, no_caches: bool) -> LispResult<Expr> {
+        let opts = Options {
+            no_caches,
+            ..Default::default()
+        };
+        let sym_table = create_stdlib_symbol_table(&opts);
+        let mut res = Expr::Nil;
+        for expr in read(prog) {
+            res = expr?.eval(&sym_table)?;
+        }
+        Ok(res)
+    }
+
+    /// Evaluate `prog` with caches enabled and disabled, and check
+    /// that both modes agree.
+    ///
+    /// Values are compared rather than printed, and errors by their root
+    /// cause, as dicts print their entries in an order which varies from map
+    /// to map.
+    fn eval_str(prog: &str) -> LispResult<Expr> {
+        let cached = eval_str_with(prog, false);
+        let uncached = eval_str_with(prog, true);
+        match (&cached, &uncached) {
+            (Ok(l), Ok(r)) => {
+                assert_eq!(l, r, "cached and uncached evaluation differ for {}", prog)
+            }
+            (Err(l), Err(r)) => assert_eq!(
+                l.root_cause().to_string(),
+                r.root_cause().to_string(),
+                "cached and uncached evaluation differ for {}",
+                prog
+            ),
+            _ => panic!(
+                "cached and uncached evaluation differ for {}: {:?} vs {:?}",
+                prog,
+                cached.as_ref().map_err(|e| e.to_string()),
+                uncached.as_ref().map_err(|e| e.to_string())
+            ),
+        }
+        cached
+    }
+
+    macro_rules! assert_eval {
+        ($prog:expr, $expected:expr) => {
+            assert_eq!(
+                eval_str($prog).unwrap(),
+                eval_str($expected).unwrap(),
+                "{}",
+                $prog
+            );
+        };
+    }
+
+    #[test]
+    fn spread_args() {
+        assert_eval!("(def rest '(2 3)) (list 1 @rest 4)", "'(1 2 3 4)");
+        assert_eval!("(def rest '(2 3)) (list @rest 1 @rest)", "'(2 3 1 2 3)");
+        assert_eval!("(list 1 @() 2)", "'(1 2)");
+        // Only @ spreads, so spread is a name like any other.
+        assert_eval!("(defn spread (l) (len l)) (+ 1 (spread '(2 3)))", "3");
+        // Values passed along are never spread, even when they look like code.
+        assert_eval!("(apply list (list '(f @rest)))", "'((f @rest))");
+        assert_eval!("(map head '((f @rest)))", "'(f)");
+        // Spread happens before arity checking.
+        assert_eval!("(def args '(7 2)) (% @args)", "1");
+    }
+
+    #[test]
+    fn keywords_evaluate_to_themselves() {
+        assert_eq!(eval_str(":a").unwrap(), Expr::Symbol(":a".into()));
+        assert_eval!("(= :a :a)", "true");
+        assert_eval!("(type :a)", "\"symbol\"");
+        assert_eval!("(first (list :b :c))", ":b");
+        // Other symbols are still looked up, and a bare colon isn't a keyword.
+        assert!(eval_str("undefined-thing").is_err());
+        assert!(eval_str(":").is_err());
+    }
+
+    #[test]
+    fn spread_args_before_keywords() {
+        // Spread lists are spliced in place, so keywords keep their positions after them.
+        assert_eval!(
+            "(defn f (& args) args) (def rest '(1 2)) (f @rest :k 3)",
+            "'(1 2 :k 3)"
+        );
+        assert_eval!(
+            "(defn f (& args) args) (def opts '(:k 3)) (f 1 @opts)",
+            "'(1 :k 3)"
+        );
+    }
+
+    #[test]
+    fn spread_non_list_is_an_error() {
+        let err = eval_str("(list 1 @2)").unwrap_err();
+        assert!(format!("{:?}", err).contains("Cannot spread 2 into the arguments of #<fn list 0>"));
+        assert!(eval_str("(def a @'(1))").is_err());
+    }
+
+    #[test]
+    fn data_forms() {
+        assert_eval!(
+            "(data ((server (host \"x\") (port 8080)) (features (a b c))))",
+            "'((server (host \"x\") (port 8080)) (features (a b c)))"
+        );
+        assert_eval!("(data ())", "'()");
+        assert_eval!("(data :k)", ":k");
+        // Unquotes evaluate where the data form is.
+        assert_eval!(
+            "(def port 80) (data (port ~port ~(+ port 1)))",
+            "'(port 80 81)"
+        );
+        assert_eval!("((fn (p) (data (port ~p))) 9)", "'(port 9)");
+        assert_eval!(
+            "(def port 80) (data (\"a\" ^(1 ~port) (dict \"k\" (\"c\" ~port) :on true)))",
+            "(list \"a\" ^(1 80) (dict \"k\" (list \"c\" 80) :on true))"
+        );
+        // Nesting data changes nothing.
+        assert_eval!("(data (data (a b)))", "(data (a b))");
+        assert_eval!("(def n 1) (data (x (data (y ~n))))", "'(x (y 1))");
+
+        let err = eval_str("(def n 1) (list ~n)").unwrap_err();
+        assert!(err.to_string().contains("only valid inside data"));
+        assert!(eval_str("(data (dict \"odd\"))").is_err());
+    }
+
+    #[test]
+    fn parse_num_cache_stats() {
+        assert_eval!("(parse-num \"1.5\")", "1.5");
+        assert!(eval_str("(parse-num \"one\")").is_err());
+        let stats = eval_str_with(
+            "(parse-num \"1\") (parse-num \"1\") (parse-num \"2\") (get (cache-stats) \"num-parse\")",
+            false,
+        )
+        .unwrap();
+        let stats = stats.get_dict().unwrap();
+        assert_eq!(stats.get(&Expr::String("hits".into())), Some(&num!(1)));
+        assert_eq!(stats.get(&Expr::String("misses".into())), Some(&num!(2)));
+        assert_eval!(
+            "(parse-num \"1\") (clear-caches!) (get (get (cache-stats) \"num-parse\") \"size\")",
+            "0"
+        );
+    }
+
+    #[test]
+    fn multiple_values() {
+        assert_eval!("(values 1 2)", "1");
+        assert_eval!("(+ 1 (div-mod 17 5))", "4");
+        assert_eval!("(let-values (((q r) (div-mod 17 5))) (list q r))", "'(3 2)");
+        assert_eval!(
+            "(let-values (((q r) (div-mod -17 5))) (list q r))",
+            "'(-4 3)"
+        );
+        assert_eval!(
+            "(let-values (((q r) (div-mod 17 -5))) (list q r))",
+            "'(-4 -3)"
+        );
+        assert_eval!(
+            "(let-values (((a) 1) ((b & rest) (values a 2 3))) (list a b rest))",
+            "'(1 1 (2 3))"
+        );
+        assert_eval!(
+            "(defn f () (values 1 2)) (let-values (((a b) (f))) (+ a b))",
+            "3"
+        );
+        assert_eval!(
+            "(let-values (((evens odds) (partition even? '(1 2 3 4)))) odds)",
+            "'(1 3)"
+        );
+        // Items are passed to the predicate as they are, not evaluated again.
+        assert_eval!(
+            "(let-values (((pairs rest) (partition (fn (l) (= 2 (len l))) '((1 2) (3))))) rest)",
+            "'((3))"
+        );
+        // Values are only seen where they're returned.
+        assert_eval!("(let-values (((a) (do (values 1 2) 5))) a)", "5");
+        assert_eval!("(let-values (((q) (+ 0 (div-mod 17 5)))) q)", "3");
+        assert!(eval_str("(let-values (((q r) (+ 0 (div-mod 17 5)))) q)").is_err());
+        assert!(eval_str("(let-values (((a b) (do (values 1 2) 1))) a)").is_err());
+        assert_eval!("(def v (values 1 2)) (let-values (((a) v)) a)", "1");
+        assert_eval!("(let-values ((() (values))) 1)", "1");
+        assert_eval!("(list (values 1 2) (values))", "(list 1 nil)");
+    }
+
+    #[test]
+    fn let_values_arity_mismatch() {
+        let err = eval_str("(let-values (((a b c) (values 1 2))) a)").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("let-values expected 3 values for (a b c)"));
+        assert!(eval_str("(let-values (((a) (values 1 2))) a)").is_err());
+    }
+
+    #[test]
+    fn import_forms() {
+        let utils = "\"tests/fixtures/modules/utils.x7\"";
+        assert_eval!(&format!("(require {}) (utils::helper 2)", utils), "5");
+        assert_eval!(&format!("(import {} :as u) (u::helper 2)", utils), "5");
+        assert_eval!(
+            &format!("(import {} :only (helper)) (helper 2)", utils),
+            "5"
+        );
+        assert_eval!(
+            &format!("(import {} :rename ((helper h))) (h 2)", utils),
+            "5"
+        );
+        assert_eval!(&format!("(import {} :all) (offset 2)", utils), "3");
+        assert_eval!(
+            &format!("(import {} :only (helper)) (source helper)", utils),
+            "\"tests/fixtures/modules/utils.x7\""
+        );
+        assert!(eval_str(&format!("(import {} :only (missing))", utils)).is_err());
+        assert_eval!(
+            &format!(
+                "(require {}) (import \"tests/fixtures/modules/nested/utils.x7\" :as n) (n::twice 2)",
+                utils
+            ),
+            "4"
+        );
+        // Modules sharing a name in different directories are kept apart,
+        // whichever is loaded first.
+        assert_eval!(
+            &format!(
+                "(import \"tests/fixtures/modules/nested/utils.x7\" :as n) (import {} :as u)
+                 (list (n::twice 2) (u::helper 2) (source u::helper))",
+                utils
+            ),
+            "(list 4 5 \"tests/fixtures/modules/utils.x7\")"
+        );
+        assert_eval!(
+            "(require-string \"gen\" \"(defn helper () 1) (def names '(helper))\") gen::names",
+            "'(helper)"
+        );
+        assert_eval!(
+            "(require-string \"gen\" \"(defn helper () 1) (def name 'helper)\") gen::name",
+            "'helper"
+        );
+    }
+
+    #[test]
+    fn conflicting_imports_error() {
+        let err = eval_str(
+            "(import \"tests/fixtures/modules/utils.x7\" :only (helper))
+             (import \"tests/fixtures/modules/other.x7\" :only (helper))",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot import helper from tests/fixtures/modules/other.x7, \
+             it was already imported from tests/fixtures/modules/utils.x7"
+        );
+    }
+
+    #[test]
+    fn numeric_equality_across_scales() {
+        assert_eval!("(= 1 1.0 (/ 2 2) 1.000)", "true");
+        assert_eval!("(int? (/ 4 2))", "true");
+        assert_eval!("(int? 0.5)", "false");
+        assert_eval!("(get (dict 1 \"one\") 1.0)", "\"one\"");
+        assert_eval!("(get (dict 0.50 \"half\") (/ 1 2))", "\"half\"");
+    }
+
+    #[test]
+    fn function_printing_is_stable() {
+        assert_eval!("(str +)", "\"#<fn + 1>\"");
+        assert_eval!("(defn add (x y) (+ x y)) (str add)", "\"#<fn add 2>\"");
+        assert_eval!("(str (fn (x) x))", "\"#<fn AnonFn 1>\"");
+        assert_eval!(
+            "(defn add (x y) (+ x y)) (str (partial add 1))",
+            "\"#<fn partial(add) 1>\""
+        );
+        assert_eval!("((partial + 1 2) 3)", "6");
+        assert_eval!("((partial concat '(1 2)) '(3))", "'(1 2 3)");
+        assert_eval!(
+            "(map str ((partial list (head '(a))) (head '(b))))",
+            "'(\"a\" \"b\")"
+        );
+        let err = eval_str("(defn add (x y) (+ x y)) (add 1)").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Too few args supplied for #<fn add 2>."));
+    }
+
+    #[test]
+    fn require_string_modules() {
+        assert_eval!(
+            "(require-string \"gen\" \"(def k 3) (defn helper (x) (* x k))\") (gen::helper 2)",
+            "6"
+        );
+        assert_eval!(
+            "(require-string \"gen\" \"(defn helper (x) (* x 2))\")
+             (import gen :only (helper))
+             (list (helper 2) (source helper))",
+            "(list 4 \"<string gen>\")"
+        );
+        assert!(eval_str("(require-string \"bad\" \"(defn oops\")").is_err());
+        assert_eval!(
+            "(require-string \"gen\" \";#line 3 \\\"gen.dsl\\\"\n(defn helper (x) (* x 2))\")
+             (gen::helper 2)",
+            "4"
+        );
+    }
+
+    #[cfg(feature = "http")]
+    fn serve_once(body: &'static str) -> String {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf);
+            let resp = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(resp.as_bytes()).unwrap();
+        });
+        format!("http://{}/remote.x7", addr)
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn require_url_checks_digest() {
+        use sha2::{Digest, Sha256};
+        let body = "(defn helper (x) (+ x 100))";
+        let digest = format!("{:x}", Sha256::digest(body.as_bytes()));
+
+        let url = serve_once(body);
+        let prog = format!(
+            "(require-url \"{}\" :sha256 \"{}\") (remote::helper 1)",
+            url, digest
+        );
+        assert_eq!(eval_str_with(&prog, false).unwrap(), num!(101));
+
+        let url = serve_once("(def evaluated true)");
+        let prog = format!("(require-url \"{}\" :sha256 \"{}\")", url, "0".repeat(64));
+        let err = eval_str_with(&format!("{} remote::evaluated", prog), false).unwrap_err();
+        assert!(err.to_string().contains("Refusing to load"));
+
+        for digest in &["abc", "../../etc/passwd", &"g".repeat(64)] {
+            let prog = format!("(require-url \"{}\" :sha256 \"{}\")", url, digest);
+            let err = eval_str_with(&prog, false).unwrap_err();
+            assert!(err.to_string().contains("64 hex digits"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn aggregations() {
+        assert_eval!("(min-of '(3 1 2))", "1");
+        assert_eval!("(max-of ^(3 1 2))", "3");
+        assert_eval!("(extent '(3 -1 2))", "^(-1 3)");
+        assert_eval!("(mean '(1 2 3 4))", "2.5");
+        assert_eval!("(mean '(0.1 0.2 0.3))", "0.2");
+        assert_eval!("(mean '(0.1 0.7))", "0.4");
+        assert_eval!("(median '(3 1 2))", "2");
+        assert_eval!("(median '(4 1 3 2))", "2.5");
+        assert_eval!("(variance '(1 2 3 4))", "1.25");
+        assert_eval!("(stddev '(2 4 4 4 5 5 7 9))", "2");
+        assert_eval!("(clamp 5 0 3)", "3");
+        assert_eval!("(clamp -1 0 3)", "0");
+        assert_eval!("(clamp 1.5 0 3)", "1.5");
+        assert!(eval_str("(clamp 1 3 0)").is_err());
+        for f in &[
+            "min-of", "max-of", "extent", "mean", "median", "variance", "stddev",
+        ] {
+            assert_eq!(eval_str(&format!("({} '())", f)).unwrap(), Expr::Nil);
+        }
+        let err = eval_str("(mean '(1 2 \"three\"))").unwrap_err();
+        assert!(format!("{:?}", err)
+            .contains("mean requires a collection of nums, but item 2 is \"three\""));
+    }
+
+    #[test]
+    fn cyclic_atoms_terminate() {
+        assert_eval!("(def a (atom 1)) (.reset a 2) (.deref a)", "2");
+        assert_eval!(
+            "(def a (atom 1)) (.reset a (list 1 a)) (str a)",
+            "\"#<Record AtomRecord Atom<(1 #<Record AtomRecord #<cycle>>)>>\""
+        );
+        // Atoms compare by identity, so comparing cycles can't recurse.
+        assert_eval!(
+            "(def a (atom 1)) (.reset a (list a)) (= (.deref a) (.deref a))",
+            "true"
+        );
+    }
+
+    #[test]
+    fn cyclic_atoms_are_errors_where_there_is_no_answer() {
+        let cycles = "(def a (atom 1)) (.reset a (list 1 a))
+                      (def b (atom 1)) (.reset b (list 1 b))";
+        let cyclic = |prog: &str| {
+            let err = eval_str(&format!("{} {}", cycles, prog)).unwrap_err();
+            assert_eq!(crate::symbols::error_kind(&err), "Cyclic", "{}", prog);
+            format!("{:#}", err)
+        };
+        assert!(cyclic("(deep= a b)").contains("Cannot compare an atom which contains itself"));
+        assert!(cyclic("(deep-copy a)").contains("Cannot deep-copy an atom"));
+        assert!(cyclic("(deep-copy (list 2 a))").contains("Cannot deep-copy an atom"));
+
+        // Identical atoms, and atoms without cycles, still have answers.
+        assert_eval!(&format!("{} (deep= a a)", cycles), "true");
+        assert_eval!(
+            "(deep= (atom (list 1 (atom 2))) (atom (list 1 (atom 2))))",
+            "true"
+        );
+        assert_eval!("(deep= (atom 1) (atom 2))", "false");
+        // q is on both sides, which isn't a cycle.
+        assert_eval!(
+            "(def s (atom 1)) (def q (atom s)) (deep= (atom q) q)",
+            "false"
+        );
+        assert_eval!(
+            "(def s (atom 1)) (def q (atom s)) (deep= (list q (atom q)) (list q (atom q)))",
+            "true"
+        );
+        assert_eval!("(deep= (dict :a (atom 1)) (dict :a (atom 1)))", "true");
+        assert_eval!(
+            "(def a (atom 1)) (def b (deep-copy a)) (.reset b 2) (list (.deref a) (.deref b))",
+            "'(1 2)"
+        );
+    }
+
+    #[test]
+    fn interpreters_share_stdlib_without_leaking() {
+        let opts = Options::default();
+        let first = create_stdlib_symbol_table(&opts);
+        for expr in read("(def inc 5) (defn new-fn (x) x)") {
+            expr.unwrap().eval(&first).unwrap();
+        }
+        let second = create_stdlib_symbol_table(&opts);
+        let eval_in = |prog: &str| read(prog).next().unwrap().unwrap().eval(&second);
+        assert_eq!(eval_in("(inc 1)").unwrap(), num!(2));
+        assert!(eval_in("(new-fn 1)").is_err());
+        // What the x7 stdlib defines is there too.
+        let built = build_stdlib(&opts);
+        for prog in &["(map fib (range 10))", "(float-eq 1.0 1.05 0.1)"] {
+            let expected = read(prog).next().unwrap().unwrap().eval(&built).unwrap();
+            assert_eq!(eval_in(prog).unwrap(), expected, "{}", prog);
+        }
+        assert_eq!(
+            second.get_canonical_doc_order(),
+            build_stdlib(&opts).get_canonical_doc_order()
+        );
+    }
+
+    #[test]
+    fn warnings() {
+        assert_eval!(
+            "(def res (with-warnings-collected (do (warn :custom \"hi\") 1)))
+             (list (nth 0 res) (map (fn (w) (list (get w \"kind\") (get w \"message\"))) (nth 1 res)))",
+            "(list 1 (list (list :custom \"hi\")))"
+        );
+        assert_eval!("(with-warnings-collected 1)", "(list 1 (list))");
+        assert!(eval_str("(warn custom \"no keyword\")").is_err());
+
+        // Warnings go to stderr by default.
+        let opts = Options::default();
+        let syms = create_stdlib_symbol_table(&opts);
+        syms.host_mut().capture_output();
+        let prog = "(warn :custom \"to stderr\")";
+        read(prog).next().unwrap().unwrap().eval(&syms).unwrap();
+        let (_, stderr) = syms.host_mut().take_captured_output();
+        assert_eq!(stderr, "Warning[:custom]: to stderr\n");
+
+        // Deny mode makes them errors, even when collecting.
+        let opts = Options {
+            deny_warnings: true,
+            ..Default::default()
+        };
+        let syms = create_stdlib_symbol_table(&opts);
+        let prog = "(with-warnings-collected (warn :custom \"denied\"))";
+        let err = read(prog).next().unwrap().unwrap().eval(&syms).unwrap_err();
+        assert!(err.chain().any(|e| e.to_string().contains("denied")));
+        assert!(err
+            .chain()
+            .any(|e| e.downcast_ref::<ProgramError>() == Some(&ProgramError::DeniedWarning)));
+    }
+
+    #[test]
+    fn synthetic_spans_reach_errors() {
+        use crate::symbols::Span;
+        let span = Span {
+            file: "original.dsl".into(),
+            line: 12,
+            col: 3,
+        };
+        let failing = read("(+ 1 \"a\")")
+            .next()
+            .unwrap()
+            .unwrap()
+            .with_span(&span);
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let err = failing.eval(&syms).unwrap_err();
+        assert_eq!(err.to_string(), "at original.dsl:12:3");
+        assert!(err
+            .chain()
+            .any(|e| e.downcast_ref::<ProgramError>().is_some()));
+
+        let outcome = crate::run_source(
+            "(def x 1)\n;#line 40 \"gen.dsl\"\n(defn f () (+ 1 \"a\"))\n(f)",
+            crate::RunOptions::default(),
+        );
+        let error = outcome.error.unwrap();
+        assert!(
+            error.stacktrace.contains(&"at gen.dsl:41:1".to_string()),
+            "{:?}",
+            error
+        );
+    }
+
+    #[test]
+    fn format_strings() {
+        assert_eval!(
+            "(format \"{} costs ${:,.2}\" \"rent\" 1234.5)",
+            "\"rent costs $1,234.50\""
+        );
+        assert_eval!(
+            "(format \"{:%} {:+} {:08.3} {:x}\" 0.125 5 3.14159 255)",
+            "\"12.5% +5 0003.142 ff\""
+        );
+        let err = eval_str("(format \"{:,.2z}\" 1)").unwrap_err();
+        assert!(format!("{:?}", err).contains("at \"z\""));
+    }
+
+    #[test]
+    fn dict_transforms() {
+        assert_eval!(
+            "(map-vals inc (dict \"a\" 1 \"b\" 2))",
+            "(dict \"a\" 2 \"b\" 3)"
+        );
+        assert_eval!(
+            "(map-keys inc (dict 1 \"a\" 2 \"b\"))",
+            "(dict 2 \"a\" 3 \"b\")"
+        );
+        assert_eval!(
+            "(filter-kv (fn (k v) (> v k)) (dict 1 2 3 1 4 5))",
+            "(dict 1 2 4 5)"
+        );
+        assert_eval!(
+            "(reduce-kv (fn (acc k v) (+ acc (* k v))) 0 (dict 1 2 3 4))",
+            "14"
+        );
+        assert_eval!(
+            "(select-keys (dict :a 1 :b 2 :c 3) '(:a :b :d))",
+            "(dict :a 1 :b 2)"
+        );
+        let err = eval_str("(map-keys (fn (k) (% k 2)) (dict 1 0 2 0 3 0 4 0))").unwrap_err();
+        assert!(format!("{:?}", err)
+            .contains("map-keys would merge keys: 1, 3 all map to 1; 2, 4 all map to 0"));
+        // Empty dicts.
+        for prog in &[
+            "(map-vals inc (dict))",
+            "(map-keys inc (dict))",
+            "(filter-kv (fn (k v) true) (dict))",
+            "(select-keys (dict) '(:a))",
+        ] {
+            assert_eval!(prog, "(dict)");
+        }
+        assert_eval!("(reduce-kv (fn (acc k v) (+ acc v)) 0 (dict))", "0");
+        // Keys, values, and the accumulator are passed as they are, not
+        // evaluated again.
+        assert_eval!("(map-vals len (dict :a '(1 2)))", "(dict :a 2)");
+        assert_eval!(
+            "(map-vals type (dict :a (head '(zed))))",
+            "(dict :a \"symbol\")"
+        );
+        assert_eval!("(map-keys len (dict '(1 2) :a))", "(dict 2 :a)");
+        assert_eval!(
+            "(filter-kv (fn (k v) (= 2 (len v))) (dict :a '(1 2) :b '(1)))",
+            "(dict :a '(1 2))"
+        );
+        assert_eval!(
+            "(reduce-kv (fn (acc k v) (cons v acc)) '() (dict :a '(1 2)))",
+            "(list '(1 2))"
+        );
+        assert_eval!(
+            "(len (reduce-kv (fn (acc k v) (cons v acc)) '() (dict :a 1 :b 2)))",
+            "2"
+        );
+    }
+
+    #[test]
+    fn sort_mixed_types() {
+        let sorted = eval_str(
+            "(sort (list ^(1) \"b\" :k '(1 2) 2 (head '(zed)) nil true '(1) \"a\" false 1 :a (dict)))",
+        )
+        .unwrap();
+        assert_eq!(
+            format!("{:?}", sorted),
+            "(nil false true 1 2 \"a\" \"b\" :a :k zed (1) (1 2) (tuple 1) {})"
+        );
+        assert_eval!("(sort '(3 1 2))", "'(1 2 3)");
+        // Keywords come before symbols, even ones that print before them.
+        assert_eval!("(sort '(+ :a))", "'(:a +)");
+        assert_eval!("(keys (dict :b 1 \"a\" 2 :a 3))", "(list \"a\" :a :b)");
+        assert_eval!(
+            "(sort-by len '(\"ccc\" \"a\" \"bb\" \"d\"))",
+            "'(\"a\" \"d\" \"bb\" \"ccc\")"
+        );
+        assert_eval!("(get (group-by even? '(1 2 3 4 5)) false)", "'(1 3 5)");
+        assert_eval!(
+            "(keys (group-by type (list 1 \"a\" :k)))",
+            "'(\"num\" \"str\" \"symbol\")"
+        );
+        // Items are passed as they are, not evaluated again.
+        assert_eval!("(sort-by len '((1 2) (3)))", "'((3) (1 2))");
+        assert_eval!(
+            "(sort-by type (list '(1) (head '(zed))))",
+            "(list '(1) (head '(zed)))"
+        );
+        assert_eval!(
+            "(get (group-by len '((1 2) (3) (4 5))) 2)",
+            "'((1 2) (4 5))"
+        );
+        assert_eval!("(keys (group-by type '(zed :k)))", "'(\"symbol\")");
+        // The comparison operators still don't order mixed types.
+        assert_eval!("(< 1 \"a\")", "false");
+    }
+
+    #[test]
+    fn former_x7_functions() {
+        assert_eval!("(reverse '(1 2 3))", "'(3 2 1)");
+        assert_eval!("(reverse '())", "'()");
+        assert_eval!("(quicksort '(3 1 2 1))", "'(1 1 2 3)");
+        assert_eval!("(dot-product '(1 2 3) '(4 5 6 7))", "32");
+        assert_eval!("(not= 1 1 2)", "true");
+        assert_eval!("(assert-eq 1 1 \"same\")", "'()");
+        let err = format!("{:?}", eval_str("(assert-eq 1 2 \"differ\")").unwrap_err());
+        assert!(err.contains("differ"), "{}", err);
+    }
+
+    #[test]
+    fn assert_eq_reports_differences() {
+        let err = eval_str("(assert-eq (dict :a '(1 2) :b 3) (dict :a '(1 4) :b 3) \"nested\")")
+            .unwrap_err();
+        assert_eq!(err.root_cause().to_string(), "nested");
+        // Debug indents the lines of each cause, so look at the causes.
+        let causes = |err: anyhow::Error| err.chain().map(|e| e.to_string()).join("\n");
+        let err = causes(err);
+        assert!(
+            err.contains("expected and actual differ in 1 place(s):\n  :a 1: expected 2, got 4"),
+            "{}",
+            err
+        );
+        let err = causes(eval_str("(assert-eq (range 20) (range 1 21) \"ranges\")").unwrap_err());
+        assert!(err.contains("differ in 20 place(s)"), "{}", err);
+        assert!(
+            err.contains("  4: expected 4, got 5\n  ... and 15 more"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn assert_matches_patterns() {
+        for prog in &[
+            "(assert-matches _ 1)",
+            "(assert-matches (dict :status 200 :body _) (dict :status 200 :body \"ok\" :id 1))",
+            "(assert-matches (list 1 _ (+ 1 2)) '(1 2 3))",
+            "(assert-matches '(1 _ (dict :a _)) (list 1 \"x\" (dict :a 2)))",
+            "(assert-matches (tuple :ok _) ^(:ok 5))",
+        ] {
+            assert_eval!(prog, "'()");
+        }
+        let failure = |prog: &str| eval_str(prog).unwrap_err().to_string();
+        assert_eq!(
+            failure("(assert-matches (dict :status 200 :body _) (dict :status 404 :body \"\"))"),
+            "assert-matches failed at :status: expected 200, found num 404"
+        );
+        assert_eq!(
+            failure("(assert-matches (dict :body (dict :items (list _ 2))) (dict :body (dict :items '(1 3))))"),
+            "assert-matches failed at :body :items 1: expected 2, found num 3"
+        );
+        assert_eq!(
+            failure("(assert-matches (dict :body _) (dict :status 200) \"response\")"),
+            "response: assert-matches failed at :body: missing key, found {:status: 200}"
+        );
+        assert_eq!(
+            failure("(assert-matches (list _ _) '(1))"),
+            "assert-matches failed at top level: expected a list of 2 items, found list (1)"
+        );
+        assert_eq!(
+            failure("(assert-matches (dict :a _) '(1))"),
+            "assert-matches failed at top level: expected a dict, found list (1)"
+        );
+    }
+
+    #[test]
+    fn deep_inputs_do_not_recurse() {
+        assert_eval!("(len (reverse (range 1000000)))", "1000000");
+        assert_eval!("(head (reverse (range 1000000)))", "999999");
+        assert_eval!("(len (quicksort (range 3000)))", "3000");
+        assert_eval!(
+            "(dot-product (range 100000) (range 100000))",
+            "333328333350000"
+        );
+    }
+
+    #[test]
+    fn merging() {
+        assert_eval!(
+            "(merge (dict :a 1 :b 2) (dict :b 3 :c 4) (dict :c 5))",
+            "(dict :a 1 :b 3 :c 5)"
+        );
+        assert_eval!("(merge)", "(dict)");
+        assert_eval!(
+            "(merge-with + (dict :a 1 :b 2) (dict :b 3) (dict :a 10 :b 4))",
+            "(dict :a 11 :b 9)"
+        );
+        assert_eval!(
+            "(merge-with (fn (old new) old) (dict :a 1) (dict :a 2 :b 3))",
+            "(dict :a 1 :b 3)"
+        );
+        let err = format!(
+            "{:?}",
+            eval_str("(merge-with (fn (old new) (err \"clash\")) (dict :a 1) (dict :a 2))")
+                .unwrap_err()
+        );
+        assert!(err.contains("merge-with could not merge key :a"), "{}", err);
+        assert!(err.contains("clash"), "{}", err);
+        // Colliding values are passed as they are, not evaluated again.
+        assert_eval!(
+            "(merge-with concat (dict :a '(1 2)) (dict :a '(3)) (dict :a '(4)))",
+            "(dict :a '(1 2 3 4))"
+        );
+
+        let config = "(def defaults (dict :name \"app\"
+                                      :db (dict :host \"localhost\" :port 5432 :opts (dict :ssl false))
+                                      :tags '(\"a\")))
+                      (def overrides (dict :db (dict :port 6543 :opts (dict :ssl true))
+                                       :tags '(\"b\")))";
+        assert_eval!(
+            &format!("{} (deep-merge defaults overrides)", config),
+            "(dict :name \"app\"
+                   :db (dict :host \"localhost\" :port 6543 :opts (dict :ssl true))
+                   :tags '(\"b\"))"
+        );
+        assert_eval!(
+            &format!(
+                "{} (get (deep-merge defaults overrides :concat-lists true) :tags)",
+                config
+            ),
+            "'(\"a\" \"b\")"
+        );
+        // A dict and a scalar at the same path: the right-hand value wins.
+        assert_eval!(
+            "(deep-merge (dict :a (dict :b 1)) (dict :a 2) (dict :c 3))",
+            "(dict :a 2 :c 3)"
+        );
+        assert_eval!(
+            "(deep-merge (dict :a 2) (dict :a (dict :b 1)))",
+            "(dict :a (dict :b 1))"
+        );
+        // Options can be spread in like any other trailing arguments.
+        assert_eval!(
+            "(deep-merge (dict :a (list 1)) (dict :a (list 2)) @'(:concat-lists true))",
+            "(dict :a (list 1 2))"
+        );
+        assert!(eval_str("(deep-merge (dict) 1)").is_err());
+    }
+
+    #[test]
+    fn sequence_helpers() {
+        assert_eval!("(remove even? '(1 2 3 4))", "'(1 3)");
+        assert_eval!("(remove (dict 1 2 3 4) 1)", "(dict 3 4)");
+        assert_eval!(
+            "(keep (fn (x) (get (dict 1 \"one\" 3 \"three\") x)) '(1 2 3))",
+            "'(\"one\" \"three\")"
+        );
+        assert_eval!(
+            "(some (fn (x) (if (> x 2) (* x 10) false)) '(1 2 3 4))",
+            "30"
+        );
+        assert_eval!("(some even? '(1 3))", "(head '())");
+        // Items are passed as they are, not evaluated again.
+        assert_eval!("(remove (fn (l) (= 1 (len l))) '((1) (2 3)))", "'((2 3))");
+        assert_eval!("(keep head '((1) (2 3)))", "'(1 2)");
+        assert_eval!(
+            "(some (fn (l) (if (= 2 (len l)) l nil)) '((1) (2 3)))",
+            "'(2 3)"
+        );
+        assert_eval!("(filter-nil (list 1 (head '()) 2))", "'(1 2)");
+        assert_eval!("(first '(1 2))", "1");
+        assert_eval!("(count '(1 2))", "2");
+    }
+
+    #[test]
+    fn windows_share_the_list() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.eval_source("(def big (range 0 1000000))").unwrap();
+        // Copying the windows would clone a hundred million items, so this
+        // only finishes if they share the list.
+        syms.eval_source(
+            "(def chunks (doall (window 1000 1000 big)))
+             (def sliding (doall (take 1000 (window 100000 big))))
+             (def joined (concat (subvec big 500000) (subvec big 0 500000)))",
+        )
+        .unwrap();
+        assert_eq!(
+            syms.eval_source(
+                "(list (len chunks) (head (nth -1 chunks)) (len (nth -1 sliding))
+                       (head (nth -1 sliding)) (len joined) (head joined))"
+            )
+            .unwrap(),
+            syms.eval_source("'(1000 999000 100000 999 1000000 500000)")
+                .unwrap()
+        );
+        assert!(syms.eval_source("(subvec big 5 1000001)").is_err());
+        assert!(syms.eval_source("(window 0 big)").is_err());
+    }
+
+    #[test]
+    fn nil_punning() {
+        let strict = |prog: &str| {
+            let opts = Options {
+                strict_nil: true,
+                ..Default::default()
+            };
+            let sym_table = create_stdlib_symbol_table(&opts);
+            let mut res = Ok(Expr::Nil);
+            for expr in read(prog) {
+                res = expr.and_then(|e| e.eval(&sym_table));
+                if res.is_err() {
+                    break;
+                }
+            }
+            res
+        };
+        // (program, result when nil is an empty list)
+        let table = [
+            ("(head none)", "none"),
+            ("(first none)", "none"),
+            ("(len none)", "0"),
+            ("(count none)", "0"),
+            ("(map inc none)", "none"),
+            ("(foreach inc none)", "none"),
+            ("(filter even? none)", "none"),
+            ("(remove even? none)", "none"),
+            ("(keep inc none)", "none"),
+            ("(some inc none)", "none"),
+            ("(filter-nil none)", "none"),
+        ];
+        let none = "(def none (head '()))";
+        for (prog, lenient) in table.iter() {
+            let prog = format!("{} {}", none, prog);
+            assert_eval!(&prog, &format!("{} {}", none, lenient));
+            let err = strict(&prog).unwrap_err();
+            assert!(
+                err.root_cause().to_string().contains("but was given nil"),
+                "{}: {:?}",
+                prog,
+                err
+            );
+            // Lists behave the same either way.
+            let with_list = prog.replace(" none)", " '(1 2))");
+            assert_eq!(
+                strict(&with_list).unwrap(),
+                eval_str(&with_list).unwrap(),
+                "{}",
+                with_list
+            );
+        }
+    }
+
+    #[test]
+    fn path_builtins() {
+        use std::path::{Path, PathBuf};
+        // Build the expected paths with std::path, so this holds for either separator.
+        let string = |p: PathBuf| Expr::String(p.to_str().unwrap().into());
+        assert_eq!(
+            eval_str("(path-join \"src\" \"lib\" \"main.x7\")").unwrap(),
+            string(Path::new("src").join("lib").join("main.x7"))
+        );
+        // Paths built in x7 too, as literals with backslashes would need escaping.
+        let p = "(path-join \"src\" \"lib\" \"main.x7\")";
+        assert_eq!(
+            eval_str(&format!("(path-parent {})", p)).unwrap(),
+            string(Path::new("src").join("lib"))
+        );
+        assert_eq!(
+            eval_str(&format!("(path-filename {})", p)).unwrap(),
+            Expr::String("main.x7".into())
+        );
+        assert_eq!(
+            eval_str(&format!("(path-extension {})", p)).unwrap(),
+            Expr::String("x7".into())
+        );
+        assert_eq!(
+            eval_str("(path-extension \"Makefile\")").unwrap(),
+            Expr::Nil
+        );
+
+        assert_eq!(
+            eval_str("(temp-dir)").unwrap(),
+            string(std::env::temp_dir())
+        );
+        assert_eq!(
+            eval_str(
+                "(path-relative (path-join (temp-dir) \"srv\" \"app\")
+                                (path-join (temp-dir) \"srv\" \"data\"))"
+            )
+            .unwrap(),
+            string(Path::new("..").join("data"))
+        );
+        assert_eq!(
+            eval_str("(path-absolute \"Cargo.toml\")").unwrap(),
+            string(std::env::current_dir().unwrap().join("Cargo.toml"))
+        );
+        assert_eq!(
+            eval_str("(path-canonical \"src/../Cargo.toml\")").unwrap(),
+            string(std::fs::canonicalize("Cargo.toml").unwrap())
+        );
+        assert!(eval_str("(path-canonical \"does-not-exist\")").is_err());
+        assert_eval!("(path-exists? \"Cargo.toml\")", "true");
+        assert_eval!("(path-exists? \"does-not-exist\")", "false");
+
+        let found = eval_str("(glob \"tests/fixtures/**/*.x7\")").unwrap();
+        let found = found.get_list().unwrap();
+        assert!(found.contains(&string(
+            Path::new("tests").join("fixtures").join("reference.x7")
+        )));
+        assert!(eval_str("(glob \"[\")").is_err());
+    }
+
+    #[test]
+    fn type_annotations() {
+        let add = "(defn add \"Add numbers.\" (x :num y :num) :num (+ x y))";
+        assert_eval!(&format!("{} (add 1 2)", add), "3");
+        // Annotations aren't checked unless asked for.
+        assert_eval!(&format!("{} (add \"a\" \"b\")", add), "\"ab\"");
+        assert_eq!(
+            eval_str(&format!("{} (doc add)", add)).unwrap(),
+            Expr::String("add : (num num) -> num\nAdd numbers.".into())
+        );
+        assert_eval!(
+            "(defn join (sep :str & parts) (str sep)) (doc join)",
+            "\"join : (str & any) -> any\""
+        );
+        assert_eval!("((fn (x :num) :num (* x 2)) 4)", "8");
+        assert_eval!(
+            "(defn plain (x) x) (doc plain)",
+            "\"No documentation for plain\""
+        );
+        assert!(eval_str("(fn (:num x) x)").is_err());
+        assert!(eval_str("(fn (x :num :str) x)").is_err());
+    }
+
+    #[test]
+    fn comment_docs() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let prog = ";; Add numbers.
+;; Any numbers.
+(defn add (x :num y :num) :num (+ x y))
+;; Ignored, there is a docstring.
+(defn sub \"Subtract numbers.\" (x y) (- x y))
+;; Ignored, separated by a blank line.
+
+(defn mul (x y) (* x y))
+(doc add)";
+        assert_eq!(
+            syms.eval_source(prog).unwrap(),
+            Expr::String("add : (num num) -> num\nAdd numbers.\nAny numbers.".into())
+        );
+        assert_eq!(
+            syms.eval_source("(doc sub)").unwrap(),
+            Expr::String("Subtract numbers.".into())
+        );
+        assert_eq!(
+            syms.eval_source("(doc mul)").unwrap(),
+            Expr::String("No documentation for mul".into())
+        );
+    }
+
+    #[test]
+    fn check_types_wrapper() {
+        let add = "(defn add (x :num y :num) :num (+ x y)) (def checked (check-types add))";
+        assert_eval!(&format!("{} (checked 1 2)", add), "3");
+        let err = eval_str(&format!("{} (checked 1 \"2\")", add)).unwrap_err();
+        assert_eq!(
+            err.root_cause().to_string(),
+            "add expected y to be num, but got str \"2\""
+        );
+        let bad_return = "(defn bad (x :num) :str x) ((check-types bad) 1)";
+        let err = eval_str(bad_return).unwrap_err();
+        assert_eq!(
+            err.root_cause().to_string(),
+            "bad expected to return str, but returned num 1"
+        );
+        assert_eval!(
+            "(defn total (& xs :list) :num (len xs)) ((check-types total) 1 2 3)",
+            "3"
+        );
+        // Arguments are checked and passed on as the values they are.
+        assert_eval!(
+            "(defn first-of (l :list) (head l)) ((check-types first-of) '(1 2))",
+            "1"
+        );
+        assert!(eval_str("(defn f (x :number) x) (check-types f)").is_err());
+    }
+
+    #[test]
+    fn higher_order_errors_propagate() {
+        // (program, how many times boom should have run before failing)
+        let cases = [
+            ("(map boom (list 1 2 3 4))", 2),
+            ("(doall (map boom (take 4 (range))))", 3),
+            ("(foreach boom (list 1 2 3 4))", 2),
+            ("(foreach boom (take 4 (range)))", 3),
+            ("(filter (fn (x) (do (boom x) true)) (list 1 2 3 4))", 2),
+            ("(partition (fn (x) (do (boom x) true)) (list 1 2 3 4))", 2),
+            ("(reduce (fn (acc x) (boom x)) 0 (list 1 2 3 4))", 2),
+            ("(reduce (fn (acc x) (boom x)) (list 1 2 3 4))", 1),
+            ("(times 4 boom)", 3),
+            ("(apply boom (list 2))", 1),
+            ("((partial (fn (a x) (boom x)) 1) 2)", 1),
+            ("((comp boom) 2)", 1),
+            ("(map-vals boom (dict :a 2 :b 2))", 1),
+            ("(map-keys boom (dict 2 :a))", 1),
+            (
+                "(filter-kv (fn (k v) (do (boom v) true)) (dict :a 2 :b 2))",
+                1,
+            ),
+            ("(reduce-kv (fn (acc k v) (boom v)) 0 (dict :a 2 :b 2))", 1),
+            (
+                "(merge-with (fn (a b) (boom b)) (dict :a 1) (dict :a 2))",
+                1,
+            ),
+        ];
+        let setup = "(def calls (atom 0))
+                     (defn boom (x)
+                       (do (.reset calls (+ 1 (.deref calls)))
+                           (if (= x 2) (err \"boom at 2\") x)))";
+        for (prog, calls) in cases.iter() {
+            let syms = create_stdlib_symbol_table(&Options::default());
+            for expr in read(setup) {
+                expr.unwrap().eval(&syms).unwrap();
+            }
+            let err = read(prog).next().unwrap().unwrap().eval(&syms).unwrap_err();
+            assert_eq!(err.root_cause().to_string(), "boom at 2", "{}", prog);
+            let made = read("(.deref calls)")
+                .next()
+                .unwrap()
+                .unwrap()
+                .eval(&syms)
+                .unwrap();
+            assert_eq!(made, num!(*calls), "{}", prog);
+        }
+    }
+
+    #[test]
+    fn higher_order_builtins_pass_items_as_values() {
+        // Evaluating the items again would call (3 4), and look up b.
+        let setup = "(defn boom (x) (if (or (= x '(3 4)) (= x 'b)) (err \"boom at \" x) x))";
+        let cases = [
+            ("(map boom '((1 2) (3 4)))", "boom at (3 4)"),
+            ("(map boom '(a b))", "boom at b"),
+            (
+                "(filter (fn (x) (do (boom x) true)) '((1 2) (3 4)))",
+                "boom at (3 4)",
+            ),
+            ("(filter (fn (x) (do (boom x) true)) '(a b))", "boom at b"),
+            (
+                "(reduce (fn (acc x) (boom x)) '() '((1 2) (3 4)))",
+                "boom at (3 4)",
+            ),
+            ("(reduce (fn (acc x) (boom x)) '(a b))", "boom at b"),
+        ];
+        for (prog, cause) in cases.iter() {
+            let err = eval_str(&format!("{} {}", setup, prog)).unwrap_err();
+            assert_eq!(err.root_cause().to_string(), *cause, "{}", prog);
+        }
+        assert_eval!(&format!("{} (map boom '((1 2) a))", setup), "'((1 2) a)");
+        // Special forms see the items as values too.
+        assert_eval!("(map do '(a (1 2) :k))", "'(a (1 2) :k)");
+        assert_eval!("(apply if (list true 'a '(1 2)))", "'a");
+    }
+
+    #[test]
+    fn templates() {
+        let query = "(def query (template '(query :table ?table :where ?cond)))";
+        assert_eval!(
+            &format!(
+                "{} (fill query (dict :table \"users\" :cond '(> age 18)))",
+                query
+            ),
+            "'(query :table \"users\" :where (> age 18))"
+        );
+        // Placeholders in nested lists and dict-building forms.
+        assert_eval!(
+            "(fill '(let (x ?x) (dict :y ?y :z (+ ?x 1))) (dict :x 1 :y \"y\"))",
+            "'(let (x 1) (dict :y \"y\" :z (+ 1 1)))"
+        );
+        assert_eval!("(eval (fill '(+ ?a (* ?a 2)) (dict :a 3)))", "9");
+        // Splicing, including at the start and end and an empty list.
+        assert_eval!("(fill '(+ ?@xs) (dict :xs '(1 2 3)))", "'(+ 1 2 3)");
+        assert_eval!(
+            "(fill '(?@xs (?@ys) ?z) (dict :xs '(1) :ys '() :z 2))",
+            "'(1 () 2)"
+        );
+        assert!(eval_str("(fill '(+ ?@xs) (dict :xs 1))").is_err());
+        assert!(eval_str("(template '?@xs)").is_err());
+        // A filled-in template keeps its own placeholders for a later fill.
+        assert_eval!(
+            "(def inner (template '(> ?col ?n)))
+             (def outer (fill '(where ?cond) (dict :cond inner)))
+             (fill outer (dict :col \"age\" :n 18))",
+            "'(where (> \"age\" 18))"
+        );
+        // Every unbound placeholder is reported, and unused keys are errors.
+        let err = format!(
+            "{:?}",
+            eval_str("(fill '(?a (?b ?a) ?@c) (dict))").unwrap_err()
+        );
+        assert!(err.contains("?a, ?b, ?c"), "{}", err);
+        let err = format!(
+            "{:?}",
+            eval_str("(fill '(?a) (dict :a 1 :b 2 :c 3))").unwrap_err()
+        );
+        assert!(err.contains(":b, :c"), "{}", err);
+        assert_eval!("(fill '(?a) (dict :a 1 :b 2) :allow-extra true)", "'(1)");
+    }
+
+    #[test]
+    fn conforming() {
+        let schema = "(def schema (dict :port :int
+                                    :host :string
+                                    :tags '(:list :string)
+                                    :debug '(:optional :bool false)
+                                    :timeout '(:optional :num)
+                                    :db (dict :name :string :pool '(:optional :int 4))))";
+        assert_eval!(
+            &format!(
+                "{} (conform (dict :port \"8080\" :host \"h\" :tags '(\"a\") :db (dict :name \"x\") :extra 1) schema)",
+                schema
+            ),
+            "(dict :port 8080 :host \"h\" :tags '(\"a\") :debug false :db (dict :name \"x\" :pool 4) :extra 1)"
+        );
+        assert_eval!(
+            &format!(
+                "{} (get (conform (dict :port 1 :host \"h\" :tags '() :db (dict :name \"x\") :timeout \"1.5\") schema) :timeout)",
+                schema
+            ),
+            "1.5"
+        );
+
+        // Every violation is reported, with its path.
+        let prog = format!(
+            "{} (conform (dict :port \"eighty\" :tags '(\"a\" 2) :debug 1 :db (dict :pool 1.5)) schema)",
+            schema
+        );
+        let err = format!("{:?}", eval_str(&prog).unwrap_err());
+        for violation in [
+            "6 violation(s)",
+            ":db :name: missing required key",
+            ":db :pool: expected int, but got num 1.5",
+            ":debug: expected bool, but got num 1",
+            ":host: missing required key",
+            ":port: expected int, but could not convert \"eighty\"",
+            ":tags 1: expected string, but got num 2",
+        ]
+        .iter()
+        {
+            assert!(err.contains(violation), "missing {} in {}", violation, err);
+        }
+
+        assert_eval!("(conform '(\"1\" \"2\") '(:list :int))", "'(1 2)");
+        let err = format!("{:?}", eval_str("(conform \"1.5\" :int)").unwrap_err());
+        assert!(
+            err.contains("top level: expected int, but could not convert \"1.5\""),
+            "{}",
+            err
+        );
+        assert!(eval_str("(conform 1 :integer)").is_err());
+        assert!(eval_str("(conform 1 '(:list))").is_err());
+    }
+
+    #[test]
+    fn repetition() {
+        assert_eval!(
+            "(def n (atom 0)) (dotimes (i 5) (.reset n (+ i (.deref n)))) (.deref n)",
+            "10"
+        );
+        assert_eq!(eval_str("(dotimes (i 3) i)").unwrap(), Expr::Nil);
+        assert_eval!("(dotimes (i 0) (err \"never\")) 1", "1");
+        // The index is scoped to the body.
+        assert!(eval_str("(dotimes (i 2) i) i").is_err());
+        assert_eval!(
+            "(def n (atom 0)) (list (repeat 3 (.reset n (inc (.deref n)))) (.deref n))",
+            "(list (list 1 2 3) 3)"
+        );
+        assert_eval!(
+            "(def n (atom 0)) (list (repeat-v 3 (.reset n (inc (.deref n)))) (.deref n))",
+            "(list (list 1 1 1) 1)"
+        );
+        assert_eval!("(repeat-v 0 1)", "(list)");
+        assert_eval!("(times 4 (fn (i) (* i i)))", "(list 0 1 4 9)");
+        let bad_counts = [
+            "(dotimes (i -1) i)",
+            "(repeat 1.5 1)",
+            "(repeat-v -2 1)",
+            "(times \"3\" inc)",
+        ];
+        for prog in bad_counts.iter() {
+            let err = format!("{:?}", eval_str(prog).unwrap_err());
+            assert!(
+                err.contains("non-negative integer count"),
+                "{}: {}",
+                prog,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn predicates() {
+        assert_eval!(
+            "(list (zero? 0) (zero? 0.0) (zero? 1))",
+            "(list true true false)"
+        );
+        assert_eval!(
+            "(list (pos? 2) (pos? 0) (neg? -0.5) (neg? 0))",
+            "(list true false true false)"
+        );
+        assert_eval!(
+            "(list (even? 4) (even? -3) (odd? -3) (odd? 0))",
+            "(list true false true false)"
+        );
+        assert_eval!(
+            "(list (empty? (median (list))) (empty? (dict)) (empty? (tuple 1)))",
+            "(list true true false)"
+        );
+        assert_eval!(
+            "(list (not-empty? \"a\") (non-empty? '()))",
+            "(list true false)"
+        );
+        assert_eval!(
+            "(list (between? 1 1 2) (between? 0 1 2) (between? \"b\" \"a\" \"c\"))",
+            "(list true false true)"
+        );
+        assert!(eval_str("(even? 1.5)").is_err());
+        assert!(eval_str("(not-empty? 1)").is_err());
+        // The older names never error on these.
+        assert_eval!(
+            "(list (empty? 1) (non-empty? 1) (is-even? 1.5) (is-even? 4))",
+            "(list false true false true)"
+        );
+        assert_eval!("(empty? (with-meta nil (dict :a 1)))", "true");
+    }
+
+    /// Every predicate in the stdlib, with representative valid arguments.
+    const PREDICATE_EXAMPLES: &[(&str, &[&str])] = &[
+        ("int?", &["1", "1.5"]),
+        ("zero?", &["0", "1"]),
+        ("pos?", &["1", "-1"]),
+        ("neg?", &["-1", "1"]),
+        ("even?", &["2", "3"]),
+        ("odd?", &["2", "3"]),
+        ("empty?", &["'()", "\"a\""]),
+        ("not-empty?", &["'()", "\"a\""]),
+        ("non-empty?", &["'()", "(list 1)"]),
+        ("is-even?", &["2", "3"]),
+        ("between?", &["2 1 3", "0 1 3"]),
+        ("contains?", &["(dict :a nil) :a", "(dict :a 1) :b"]),
+        ("identical?", &["1 1", "(atom 1) (atom 1)"]),
+        ("path-exists?", &["\"Cargo.toml\"", "\"does-not-exist\""]),
+        ("digit?", &["\"1\"", "\"a\""]),
+        ("alpha?", &["\"a\"", "\"1\""]),
+        ("alphanumeric?", &["\"a\"", "\"-\""]),
+        ("whitespace?", &["\" \"", "\"a\""]),
+        ("upper?", &["\"A\"", "\"a\""]),
+        ("lower?", &["\"a\"", "\"A\""]),
+        ("feature?", &[":json", ":http"]),
+    ];
+
+    #[test]
+    fn predicates_return_bools() {
+        let symbols = eval_str("(all-symbols)").unwrap().get_list().unwrap();
+        let predicates: Vec<String> = symbols
+            .iter()
+            .map(|sym| sym.get_symbol_string().unwrap())
+            // Record methods like FileRecord.closed? are listed too, but
+            // they aren't builtins.
+            .filter(|sym| sym.ends_with('?') && !sym.contains('.'))
+            .collect();
+        assert!(predicates.len() >= PREDICATE_EXAMPLES.len());
+        for predicate in predicates.iter() {
+            let (_, examples) = PREDICATE_EXAMPLES
+                .iter()
+                .find(|(name, _)| name == predicate)
+                .unwrap_or_else(|| panic!("add examples for {} to PREDICATE_EXAMPLES", predicate));
+            for args in examples.iter() {
+                let prog = format!("({} {})", predicate, args);
+                match eval_str(&prog) {
+                    Ok(Expr::Bool(_)) => {}
+                    res => panic!("{} should return a bool, got {:?}", prog, res),
+                }
+            }
+        }
+    }
+
+    /// Builtins whose examples aren't run here, as they fail or panic on
+    /// purpose, or need things from outside the interpreter.
+    const EXAMPLES_NOT_RUN: &[&str] = &[
+        "panic",
+        "err",
+        "throw",
+        "with-location",
+        "with-retries",
+        "check-types",
+        "map-keys",
+        "assert-eq",
+        "assert-matches",
+        "for-all",
+        "call_method",
+    ];
+
+    /// Categories whose examples use files, modules, or stdin.
+    const CATEGORIES_NOT_RUN: &[&str] = &["io", "paths", "modules"];
+
+    /// Builtins whose examples use builtins from an optional feature, and
+    /// whether this build has it.
+    const EXAMPLES_NEEDING_FEATURES: &[(&str, bool)] = &[
+        ("grep", cfg!(feature = "regex")),
+        ("sub", cfg!(feature = "regex")),
+        ("gsub", cfg!(feature = "regex")),
+    ];
+
+    #[test]
+    fn examples_run() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let mut ran = 0;
+        for sym in syms.get_canonical_doc_order() {
+            let category = match syms.get_category(&sym) {
+                Some(category) => category,
+                None => continue,
+            };
+            assert!(CATEGORIES.contains(&category), "{}: {}", sym, category);
+            // Every example parses, even the ones that don't run.
+            let forms = examples(vector![Expr::Symbol(sym.clone())], &syms)
+                .unwrap()
+                .get_list()
+                .unwrap();
+            if CATEGORIES_NOT_RUN.contains(&category) || EXAMPLES_NOT_RUN.contains(&sym.as_str()) {
+                continue;
+            }
+            if EXAMPLES_NEEDING_FEATURES.contains(&(sym.as_str(), false)) {
+                continue;
+            }
+            // The examples of a builtin run in order, in their own interpreter.
+            let env = create_stdlib_symbol_table(&Options::default());
+            env.host_mut().capture_output();
+            let mut results = Vec::new();
+            for form in forms.iter() {
+                match form.eval(&env) {
+                    Ok(value) => results.push(value),
+                    Err(e) => panic!("The example {} of {} failed: {:?}", form, sym, e),
+                }
+            }
+            // They run the same with calls to builtins resolved ahead of time,
+            // unless they redefine a builtin.
+            let frozen = create_stdlib_symbol_table(&Options {
+                frozen_globals: true,
+                ..Default::default()
+            });
+            frozen.host_mut().capture_output();
+            for form in forms.iter() {
+                match frozen.eval_form(form) {
+                    Err(e) if e.to_string().starts_with("Cannot redefine the builtin") => break,
+                    Err(e) => panic!(
+                        "The example {} of {} failed when frozen: {:?}",
+                        form, sym, e
+                    ),
+                    Ok(_) => {}
+                }
+            }
+            // They give the same results when every other value evaluated has
+            // metadata. Some examples are random, so only results which
+            // repeat are compared, as values since dicts print in any order.
+            let again = create_stdlib_symbol_table(&Options::default());
+            let wrapped = create_stdlib_symbol_table(&Options::default());
+            again.host_mut().capture_output();
+            wrapped.host_mut().capture_output();
+            wrapped.host_mut().set_wraps_values(true);
+            for (form, result) in forms.iter().zip(results.iter()) {
+                let repeated = form.eval(&again);
+                match form.eval(&wrapped) {
+                    Err(e) => panic!(
+                        "The example {} of {} failed with metadata: {:?}",
+                        form, sym, e
+                    ),
+                    Ok(value) if repeated.as_ref().ok() == Some(result) => {
+                        assert_eq!(&value, result, "{} of {}", form, sym)
+                    }
+                    Ok(_) => {}
+                }
+            }
+            ran += 1;
+        }
+        assert!(ran > 100, "only ran the examples of {} builtins", ran);
+    }
+
+    #[test]
+    fn symbols_by_category() {
+        let strings = eval_str("(symbols :category :strings)")
+            .unwrap()
+            .get_list()
+            .unwrap();
+        assert!(strings.contains(&Expr::Symbol("substring".into())));
+        assert!(!strings.contains(&Expr::Symbol("map".into())));
+        assert_eval!("(len (symbols))", "(len (all-symbols))");
+        assert!(eval_str("(symbols :category :nope)").is_err());
+        assert!(eval_str("(symbols :strings)").is_err());
+        assert_eval!("(map eval (examples substring))", "(list \"el\" \"llo\")");
+        assert_eval!("(examples inc)", "(list)");
+        assert_eq!(example_source("No examples."), None);
+        assert_eq!(
+            example_source("Doc.\nExample:\n>>> (def a 3)\n>>> a\n3").unwrap(),
+            " (def a 3)\n a"
+        );
+    }
+
+    #[test]
+    fn index_arguments() {
+        assert_eval!("(nth -1 '(1 2 3))", "3");
+        assert_eval!("(nth -3 '(1 2 3))", "1");
+        assert_eval!("(take 2 '(1 2 3))", "(list 1 2)");
+        assert_eval!("(take 5 '(1 2 3))", "(list 1 2 3)");
+        assert_eval!("(drop 2 '(1 2 3))", "(list 3)");
+        assert_eval!("(drop 5 '(1 2 3))", "(list)");
+        assert_eval!("(slice '(1 2 3 4) 1 3)", "(list 2 3)");
+        assert_eval!("(slice '(1 2 3 4) -2)", "(list 3 4)");
+        assert_eval!("(slice '(1 2 3 4) 3 1)", "(list)");
+        assert_eval!("(slice '(1 2 3 4) 2 100)", "(list 3 4)");
+        assert_eval!("(substring \"hello\" 1 3)", "\"el\"");
+        assert_eval!("(substring \"héllo\" -4 -2)", "\"él\"");
+        assert_eval!("(slice '(1 2 3 4) -10 2)", "(list 1 2)");
+        assert_eval!("(substring \"hello\" -10 -3)", "\"he\"");
+        assert_eval!("(* \"ab\" 2)", "\"abab\"");
+
+        // Every index-taking builtin, with each kind of bad index.
+        let calls = [
+            ("nth", "(nth {} '(1 2 3))"),
+            ("take", "(take {} '(1 2 3))"),
+            ("drop", "(drop {} '(1 2 3))"),
+            ("slice", "(slice '(1 2 3) {})"),
+            ("substring", "(substring \"abc\" {})"),
+            ("subvec", "(subvec '(1 2 3) {})"),
+            ("window", "(window {} '(1 2 3))"),
+        ];
+        for (func, template) in calls.iter() {
+            for index in ["2.5", "1e20", "-100000000000000000000"].iter() {
+                let prog = template.replace("{}", index);
+                let err = format!("{:?}", eval_str(&prog).unwrap_err());
+                assert!(err.contains(func), "{}: {}", prog, err);
+            }
+        }
+        for prog in ["(take -1 '(1 2 3))", "(drop -1 '(1 2 3))", "(* \"a\" -1)"].iter() {
+            let err = format!("{:?}", eval_str(prog).unwrap_err());
+            assert!(
+                err.contains("does not accept negative indices"),
+                "{}: {}",
+                prog,
+                err
+            );
+        }
+        let err = format!("{:?}", eval_str("(nth 3 '(1 2 3))").unwrap_err());
+        assert!(
+            err.contains("nth index 3 is out of range for length 3"),
+            "{}",
+            err
+        );
+        let err = format!("{:?}", eval_str("(nth -4 '(1 2 3))").unwrap_err());
+        assert!(
+            err.contains("nth index -4 is out of range for length 3"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn identity() {
+        // Structurally equal but distinct atoms.
+        assert_eval!(
+            "(def a (atom 1)) (def b (atom 1)) (identical? a b)",
+            "false"
+        );
+        assert_eval!("(def a (atom 1)) (def b (atom 1)) (= a b)", "false");
+        assert_eval!("(def a (atom 1)) (def b a) (identical? a b)", "true");
+        assert_eval!("(def a (atom 1)) (= a a)", "true");
+        assert_eval!(
+            "(def a (atom 1)) (def b (atom 1)) (= (object-id a) (object-id b))",
+            "false"
+        );
+        assert_eval!("(def a (atom 1)) (= (object-id a) (object-id a))", "true");
+        // Functions
+        assert_eval!("(identical? inc inc)", "true");
+        assert_eval!("(identical? inc +)", "false");
+        assert_eval!("(defn f (x) x) (identical? f f)", "true");
+        assert_eval!("(identical? (fn (x) x) (fn (x) x))", "false");
+        assert_eval!("(= (object-id inc) (object-id inc))", "true");
+        // Iterators
+        assert_eval!("(def r (range)) (identical? r r)", "true");
+        assert_eval!("(identical? (range) (range))", "false");
+        // Immutable values are identical when equal.
+        assert_eval!("(identical? 1 1.0)", "true");
+        assert_eval!("(= (object-id 1) (object-id 1.0))", "true");
+        assert_eval!("(= (object-id 1) (object-id 2))", "false");
+        assert_eval!("(= (object-id '(1 2)) (object-id '(1 3)))", "false");
+        assert_eval!("(= (object-id inc) (object-id +))", "false");
+        assert_eval!("(identical? \"a\" \"a\")", "true");
+        assert_eval!("(identical? 'a 'a)", "true");
+        assert_eval!("(identical? 'a 'b)", "false");
+        assert_eval!("(identical? '(1 2) '(1 2))", "true");
+        assert_eval!("(identical? (dict 1 2) (dict 1 2))", "true");
+        assert_eval!("(identical? true false)", "false");
+        // Collections compare their elements by identity.
+        assert_eval!("(def a (atom 1)) (identical? (list a) (list a))", "true");
+        assert_eval!("(identical? (list (atom 1)) (list (atom 1)))", "false");
+        assert_eval!("(def r (range)) (identical? (list r) (list r))", "true");
+    }
+
+    #[cfg(feature = "compression")]
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("x7-{}-{}", rand::random::<u64>(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn gzip_round_trip() {
+        assert_eval!(
+            "(bytes->string (gzip-decompress (gzip-compress \"hello\")))",
+            "\"hello\""
+        );
+        assert_eval!("(string->bytes \"hi\")", "'(104 105)");
+        assert!(eval_str("(gzip-decompress '(1 2 3))").is_err());
+        assert!(eval_str("(gzip-compress '(1 256))").is_err());
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn lines_gz_streams_large_files() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let path = temp_path("lines.gz");
+        let file = std::fs::File::create(&path).unwrap();
+        let mut encoder = GzEncoder::new(file, flate2::Compression::default());
+        let lines = 100_000;
+        for i in 0..lines {
+            writeln!(encoder, "line {} of a multi megabyte gzipped file", i).unwrap();
+        }
+        encoder.finish().unwrap();
+
+        let prog = format!(
+            "(def n (atom 0)) (foreach (fn (l) (.reset n (inc (.deref n)))) (lines-gz \"{}\")) (.deref n)",
+            path
+        );
+        let res = eval_str_with(&prog, false);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(res.unwrap(), num!(lines));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn lines_gz_copies_read_independently() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let path = temp_path("copies.gz");
+        let file = std::fs::File::create(&path).unwrap();
+        let mut encoder = GzEncoder::new(file, flate2::Compression::default());
+        write!(encoder, "a\nb\nc\n").unwrap();
+        encoder.finish().unwrap();
+
+        let prog = format!(
+            "(def l (lines-gz \"{}\")) (list (doall (take 2 l)) (doall (take 3 l)))",
+            path
+        );
+        let res = eval_str_with(&prog, false);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(
+            res.unwrap(),
+            eval_str("(list (list \"a\" \"b\") (list \"a\" \"b\" \"c\"))").unwrap()
+        );
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn zip_archives() {
+        let path = temp_path("bundle.zip");
+        let prog = format!(
+            "(zip-create \"{0}\" (dict \"b.txt\" \"world\" \"a.txt\" \"hello\"))
+             (list (map (fn (e) (get e \"name\")) (zip-entries \"{0}\"))
+                   (bytes->string (zip-read \"{0}\" \"b.txt\")))",
+            path
+        );
+        let res = eval_str_with(&prog, false);
+        let missing = eval_str_with(&format!("(zip-read \"{}\" \"c.txt\")", path), false);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(
+            res.unwrap(),
+            eval_str("(list (list \"a.txt\" \"b.txt\") \"world\")").unwrap()
+        );
+        let err = format!("{:?}", missing.unwrap_err());
+        assert!(err.contains("c.txt"), "{}", err);
+        assert!(eval_str("(zip-entries \"Cargo.toml\")").is_err());
+    }
 }
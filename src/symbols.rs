@@ -1,14 +1,29 @@
+use crate::access::{Decision, IoOp};
+use crate::annotations::Annotations;
+use crate::cache::Caches;
+use crate::compiled::CompiledExpr;
+use crate::config::InterpreterConfig;
+use crate::history::Evaluation;
+use crate::host::{DetachedHost, Host, Warning};
 use crate::iterators::IterType;
+use crate::metrics::ProgramLimits;
+use crate::modules::Imports;
+use crate::parser::{quote_value, SPREAD_FORM};
 use crate::records::RecordType;
-use anyhow::{anyhow, bail, Context};
+use crate::resources::{ResourceReport, Resources};
+use crate::snapshot::GlobalsSnapshot;
+use crate::terminal::TerminalInfo;
+use anyhow::{anyhow, bail, ensure, Context};
 use bigdecimal::{BigDecimal, ToPrimitive, Zero};
-use core::cell::RefCell;
+use core::cell::{Ref, RefCell, RefMut};
 use core::cmp::Ordering;
 use im::Vector;
 use itertools::Itertools;
-use std::collections::HashMap;
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering as AtomicOrdering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 macro_rules! bad_types {
     ($custom:expr) => {
@@ -28,12 +43,13 @@ macro_rules! bad_types {
 }
 
 pub type Num = BigDecimal;
-pub(crate) type Dict = im::HashMap<Expr, Expr>;
+pub type Dict = im::HashMap<Expr, Expr>;
 pub(crate) type Symbol = String;
 
-#[allow(clippy::derive_hash_xor_eq)] // It's probably OK.
-#[derive(Clone, Hash)]
-pub(crate) enum Expr {
+/// An x7 value. Lists, dicts, and friends are persistent `im` collections,
+/// so cloning is cheap.
+#[derive(Clone)]
+pub enum Expr {
     Num(Num),
     Symbol(Symbol),
     List(Vector<Expr>),
@@ -46,11 +62,20 @@ pub(crate) enum Expr {
     LazyIter(IterType),
     Dict(Dict),
     Record(crate::records::RecordType),
+    /// A value with metadata, from `with-meta`. It's otherwise the value.
+    WithMeta(Box<Expr>, Dict),
+    /// Multiple values, from `values`, for `let-values` to bind. It's
+    /// otherwise the first value, or nil if there are none.
+    Values(Vector<Expr>),
 }
 
 impl PartialEq for Expr {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
+            (Expr::WithMeta(l, _), r) => l.as_ref().eq(r),
+            (l, Expr::WithMeta(r, _)) => l.eq(r.as_ref()),
+            (Expr::Values(_), r) => self.unmeta().eq(r),
+            (l, Expr::Values(_)) => l.eq(other.unmeta()),
             (Expr::Num(l), Expr::Num(r)) => l.eq(r),
             (Expr::Symbol(l), Expr::Symbol(r)) => l.eq(r),
             (Expr::String(l), Expr::String(r)) => l.eq(r),
@@ -68,6 +93,144 @@ impl PartialEq for Expr {
     }
 }
 
+/// Nums are compared by value, so `1`, `1.0`, and `(/ 4 4)` are all equal.
+/// They need to hash the same too, otherwise dict lookups break, and print
+/// the same, so printing and reading a num back is a fixed point.
+pub(crate) fn normalized_num_string(n: &Num) -> String {
+    // Zero keeps its scale, so `0e5` would print with trailing zeros.
+    if n.is_zero() {
+        return "0".into();
+    }
+    let s = n.to_string();
+    let trimmed = if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s.as_str()
+    };
+    trimmed.into()
+}
+
+/// Nums are printed with every digit, so exponents beyond this are refused
+/// rather than read into values too long to print.
+const MAX_NUM_EXPONENT: i64 = 1_000_000;
+
+/// Longest wait builtins accept, to keep time arithmetic from overflowing.
+const MAX_SECONDS: f64 = 1e9;
+
+/// Read a decimal like `-001.50e3`, the way the parser and `parse-num` do.
+pub(crate) fn read_num(s: &str) -> LispResult<Num> {
+    if let Some(at) = s.find(['e', 'E']) {
+        let exponent = s[at + 1..].parse::<i64>().ok();
+        ensure!(
+            exponent.is_some_and(|e| e.abs() <= MAX_NUM_EXPONENT),
+            "Could not parse \"{}\" as a num: its exponent is beyond ±{}",
+            s,
+            MAX_NUM_EXPONENT
+        );
+    }
+    s.parse::<Num>()
+        .map_err(|e| anyhow!("Could not parse \"{}\" as a num: {}", s, e))
+}
+
+impl Hash for Expr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        if matches!(self, Expr::WithMeta(..) | Expr::Values(_)) {
+            return self.unmeta().hash(state);
+        }
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Expr::Num(n) => normalized_num_string(n).hash(state),
+            Expr::Symbol(s) => s.hash(state),
+            Expr::List(l) | Expr::Quote(l) | Expr::Tuple(l) => l.hash(state),
+            Expr::Function(f) => f.hash(state),
+            Expr::Nil => {}
+            Expr::String(s) => s.hash(state),
+            Expr::Bool(b) => b.hash(state),
+            Expr::LazyIter(i) => i.hash(state),
+            Expr::Dict(d) => d.hash(state),
+            Expr::Record(r) => r.hash(state),
+            Expr::WithMeta(..) | Expr::Values(_) => unreachable!(),
+        }
+    }
+}
+
+/// A position in the source an expression came from. Code generators can
+/// point this at their own input, so x7 errors cite it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub file: String,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Expr {
+    /// Wrap this expression so errors from evaluating it cite `span`.
+    pub fn with_span(self, span: &Span) -> Expr {
+        Expr::List(im::vector![
+            Expr::Symbol("with-location".into()),
+            Expr::String(span.file.clone()),
+            Expr::Num((span.line as u64).into()),
+            Expr::Num((span.col as u64).into()),
+            self
+        ])
+    }
+}
+
+impl From<i64> for Expr {
+    fn from(n: i64) -> Self {
+        Expr::Num(n.into())
+    }
+}
+
+impl From<bool> for Expr {
+    fn from(b: bool) -> Self {
+        Expr::Bool(b)
+    }
+}
+
+impl From<&str> for Expr {
+    fn from(s: &str) -> Self {
+        Expr::String(s.into())
+    }
+}
+
+impl From<String> for Expr {
+    fn from(s: String) -> Self {
+        Expr::String(s)
+    }
+}
+
+impl From<Num> for Expr {
+    fn from(n: Num) -> Self {
+        Expr::Num(n)
+    }
+}
+
+impl From<Vector<Expr>> for Expr {
+    fn from(l: Vector<Expr>) -> Self {
+        Expr::List(l)
+    }
+}
+
+impl From<Dict> for Expr {
+    fn from(d: Dict) -> Self {
+        Expr::Dict(d)
+    }
+}
+
+/// Functions returning nothing return nil.
+impl From<()> for Expr {
+    fn from(_: ()) -> Self {
+        Expr::Nil
+    }
+}
+
+impl<T: Into<Expr>> From<Option<T>> for Expr {
+    fn from(o: Option<T>) -> Self {
+        o.map_or(Expr::Nil, Into::into)
+    }
+}
+
 fn debug_join(exprs: &Vector<Expr>) -> String {
     exprs
         .iter()
@@ -81,23 +244,28 @@ impl fmt::Debug for Expr {
         match self {
             Expr::Nil => write!(f, "nil"),
             Expr::String(s) => write!(f, "\"{}\"", s),
-            Expr::Num(n) => write!(f, "{}", n),
+            Expr::Num(n) => write!(f, "{}", normalized_num_string(n)),
             Expr::Symbol(s) => write!(f, "{}", s),
             Expr::Function(ff) => write!(f, "{}", ff),
             Expr::LazyIter(i) => write!(f, "{}", i),
             Expr::Quote(l) => write!(f, "'({})", debug_join(l)),
             Expr::Bool(b) => write!(f, "{}", b),
-            Expr::List(l) => write!(f, "({})", debug_join(l)),
+            Expr::List(l) => match self.get_spread_target() {
+                Some(target) => write!(f, "@{:?}", target),
+                None => write!(f, "({})", debug_join(l)),
+            },
             Expr::Tuple(l) => write!(f, "(tuple {})", debug_join(l)),
             Expr::Dict(l) => write!(f, "{:?}", l),
             Expr::Record(l) => write!(f, "{:?}", l),
+            Expr::WithMeta(inner, _) => write!(f, "{:?}", inner),
+            Expr::Values(_) => write!(f, "{:?}", self.unmeta()),
         }
     }
 }
 
 impl fmt::Display for Expr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
+        match self.unmeta() {
             Expr::String(s) => write!(f, "{}", s),
             rest => write!(f, "{:?}", rest),
         }
@@ -105,26 +273,7 @@ impl fmt::Display for Expr {
 }
 
 impl Expr {
-    pub(crate) fn full_order_list(&self) -> LispResult<Vector<Expr>> {
-        let list = self.get_list()?;
-        if list.is_empty() {
-            Ok(list)
-        } else {
-            let head = &list[0];
-            if !list.iter().skip(1).all(|e| match (head, e) {
-                (Expr::Num(_), Expr::Num(_)) => true,
-                (Expr::String(_), Expr::String(_)) => true,
-                _ => false,
-            }) {
-                // only floats (sorta) + strings are totally ordered
-                bad_types!("list of identically typed, ordered elements", &self)
-            } else {
-                Ok(list)
-            }
-        }
-    }
-
-    pub(crate) fn get_type_str(&self) -> &'static str {
+    pub fn get_type_str(&self) -> &'static str {
         match self {
             Expr::Num(_) => "num",
             Expr::String(_) => "str",
@@ -138,26 +287,76 @@ impl Expr {
             Expr::Tuple(_) => "tuple",
             Expr::Dict(_) => "map",
             Expr::Record(_) => "record",
+            Expr::WithMeta(inner, _) => inner.get_type_str(),
+            Expr::Values(_) => self.unmeta().get_type_str(),
         }
     }
 
-    pub(crate) fn get_num(&self) -> LispResult<Num> {
-        if let Expr::Num(n) = self {
+    pub fn get_num(&self) -> LispResult<Num> {
+        if let Expr::Num(n) = self.unmeta() {
             Ok(n.clone())
         } else {
             bad_types!("num", &self)
         }
     }
 
-    pub(crate) fn get_record(&self) -> LispResult<RecordType> {
-        if let Expr::Record(r) = self {
+    pub fn is_int(&self) -> LispResult<bool> {
+        let n = self.get_num()?;
+        Ok(n.with_scale(0) == n)
+    }
+
+    /// Whether two values are the same object.
+    ///
+    /// Records, iterators, and functions are compared by reference. Everything
+    /// else is an immutable value, so it is identical to anything equal to it,
+    /// and collections are identical when their elements are.
+    pub(crate) fn is_identical(&self, other: &Expr) -> bool {
+        let all_identical = |l: &Vector<Expr>, r: &Vector<Expr>| {
+            l.len() == r.len() && l.iter().zip(r.iter()).all(|(l, r)| l.is_identical(r))
+        };
+        match (self.unmeta(), other.unmeta()) {
+            (Expr::Record(l), Expr::Record(r)) => l.id() == r.id(),
+            (Expr::LazyIter(l), Expr::LazyIter(r)) => l.id() == r.id(),
+            (Expr::Function(l), Expr::Function(r)) => l.id() == r.id(),
+            (Expr::List(l), Expr::List(r))
+            | (Expr::Tuple(l), Expr::Tuple(r))
+            | (Expr::Quote(l), Expr::Quote(r)) => all_identical(l, r),
+            (Expr::Dict(l), Expr::Dict(r)) => {
+                l.len() == r.len()
+                    && l.iter()
+                        .all(|(k, v)| r.get(k).is_some_and(|rv| v.is_identical(rv)))
+            }
+            _ => self == other,
+        }
+    }
+
+    /// An id that is stable for the life of the process. Identical values
+    /// always have the same id. Records, iterators, and functions carry their
+    /// own, from `next_object_id`. Immediates, like numbers, strings, and
+    /// lists, have no identity of their own and are identified by value: their
+    /// id is their hash with the top bit set, which no counted id has.
+    pub(crate) fn object_id(&self) -> u64 {
+        match self.unmeta() {
+            Expr::Record(r) => r.id(),
+            Expr::LazyIter(i) => i.id(),
+            Expr::Function(f) => f.id(),
+            value => {
+                let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                value.hash(&mut hasher);
+                hasher.finish() | VALUE_ID_BIT
+            }
+        }
+    }
+
+    pub fn get_record(&self) -> LispResult<RecordType> {
+        if let Expr::Record(r) = self.unmeta() {
             Ok(r.clone())
         } else {
             bad_types!("record", &self)
         }
     }
 
-    pub(crate) fn get_usize(&self) -> LispResult<usize> {
+    pub fn get_usize(&self) -> LispResult<usize> {
         let res = self.get_num()?.to_usize().ok_or(anyhow!(
             "Cannot represent {} as it needs to fit in a usize",
             self.get_num()?
@@ -165,19 +364,135 @@ impl Expr {
         Ok(res)
     }
 
-    pub(crate) fn get_string(&self) -> LispResult<String> {
-        if let Expr::String(s) = self {
+    /// Convert an index (or count) argument of `func`. Fractional, negative,
+    /// and out of range indices are errors naming `func` and the value.
+    pub(crate) fn get_index(&self, func: &str) -> LispResult<usize> {
+        let n = self.get_num()?;
+        if n < BigDecimal::zero() {
+            bad_types!(format!(
+                "Error: {} does not accept negative indices, but was given {}",
+                func, n
+            ))
+        } else {
+            self.get_abs_index(func)
+        }
+    }
+
+    /// Like `get_index`, but negative indices count back from the end of a
+    /// collection of length `len`, so -1 is the last element.
+    pub(crate) fn get_index_from_end(&self, func: &str, len: usize) -> LispResult<usize> {
+        let index = self.get_abs_index(func)?;
+        if self.get_num()? >= BigDecimal::zero() {
+            return Ok(index);
+        }
+        match len.checked_sub(index) {
+            Some(index) => Ok(index),
+            None => bad_types!(format!(
+                "Error: {} index {} is out of range for length {}",
+                func, self, len
+            )),
+        }
+    }
+
+    /// Like `get_index_from_end`, but positions before the start or past the
+    /// end of a collection of length `len` are clamped to it.
+    pub(crate) fn get_clamped_index(&self, func: &str, len: usize) -> LispResult<usize> {
+        let index = self.get_abs_index(func)?;
+        if self.get_num()? >= BigDecimal::zero() {
+            Ok(index.min(len))
+        } else {
+            Ok(len.saturating_sub(index))
+        }
+    }
+
+    /// Convert a number of seconds, `what`, to a Duration. Negative,
+    /// non-finite, and absurdly long durations are errors.
+    pub(crate) fn get_seconds(&self, what: &str) -> LispResult<Duration> {
+        let secs = self.get_num()?.to_f64().unwrap_or(f64::NAN);
+        ensure!(
+            secs.is_finite() && (0.0..=MAX_SECONDS).contains(&secs),
+            "{} must be between 0 and {} seconds, but was given {}",
+            what,
+            MAX_SECONDS,
+            self
+        );
+        Ok(Duration::from_secs_f64(secs))
+    }
+
+    fn get_abs_index(&self, func: &str) -> LispResult<usize> {
+        let n = self.get_num()?;
+        if !self.is_int()? {
+            return bad_types!(format!(
+                "Error: {} expects an integer index, but was given {}",
+                func, n
+            ));
+        }
+        match n.abs().to_usize() {
+            Some(index) => Ok(index),
+            None => bad_types!(format!("Error: {} index {} is too large", func, n)),
+        }
+    }
+
+    pub fn get_string(&self) -> LispResult<String> {
+        if let Expr::String(s) = self.unmeta() {
             Ok(s.clone())
         } else {
             bad_types!("string", &self)
         }
     }
 
-    pub(crate) fn get_dict(&self) -> LispResult<Dict> {
-        if let Expr::Dict(d) = self {
-            Ok(d.clone())
-        } else {
-            bad_types!("dict", &self)
+    pub fn get_dict(&self) -> LispResult<Dict> {
+        match self.unmeta() {
+            Expr::Dict(d) => Ok(d.clone()),
+            Expr::Record(r) if r.as_dict().is_some() => bad_types!(format!(
+                "Error: {:?} is read-only, (deep-copy ...) it for a dict to change",
+                self
+            )),
+            _ => bad_types!("dict", &self),
+        }
+    }
+
+    /// The entries of a dict or a record which reads like one, for builtins
+    /// which only read them. Builtins which change a dict use `get_dict`.
+    pub(crate) fn dict_entries(&self) -> LispResult<Dict> {
+        match self.unmeta() {
+            Expr::Dict(d) => Ok(d.clone()),
+            Expr::Record(r) => match r.as_dict() {
+                Some(dict) => dict
+                    .keys()?
+                    .into_iter()
+                    .map(|key| {
+                        let value = dict.get(&key)?.unwrap_or(Expr::Nil);
+                        Ok((key, value))
+                    })
+                    .collect(),
+                None => bad_types!("dict", &self),
+            },
+            _ => bad_types!("dict", &self),
+        }
+    }
+
+    /// The value of `key` in a dict or a record which reads like one.
+    pub(crate) fn dict_lookup(&self, key: &Expr) -> LispResult<Option<Expr>> {
+        match self.unmeta() {
+            Expr::Dict(d) => Ok(d.get(key).cloned()),
+            Expr::Record(r) => match r.as_dict() {
+                Some(dict) => dict.get(key),
+                None => bad_types!("dict", &self),
+            },
+            _ => bad_types!("dict", &self),
+        }
+    }
+
+    /// The keys of a dict or a record which reads like one, unordered.
+    pub(crate) fn dict_keys(&self) -> LispResult<Vec<Expr>> {
+        match self.unmeta() {
+            Expr::Dict(d) => Ok(d.keys().cloned().collect()),
+            Expr::Record(r) => match r.as_dict() {
+                Some(dict) => dict.keys(),
+                None => bad_types!("dict", &self),
+            },
+            _ => bad_types!("dict", &self),
         }
     }
 
@@ -189,7 +504,7 @@ impl Expr {
     }
 
     pub(crate) fn is_bool_true(&self) -> LispResult<bool> {
-        if let Expr::Bool(b) = self {
+        if let Expr::Bool(b) = self.unmeta() {
             Ok(*b)
         } else {
             bad_types!("bool", &self)
@@ -197,11 +512,12 @@ impl Expr {
     }
 
     pub(crate) fn len(&self) -> LispResult<usize> {
-        let len = match self {
+        let len = match self.unmeta() {
             Expr::List(l) => l.len(),
             Expr::Tuple(l) => l.len(),
             Expr::Quote(l) => l.len(),
             Expr::Dict(m) => m.len(),
+            Expr::Record(r) if r.as_dict().is_some() => self.dict_keys()?.len(),
             Expr::String(s) => s.len(),
             Expr::Symbol(s) => s.len(),
             _ => return bad_types!("collection", &self),
@@ -210,23 +526,22 @@ impl Expr {
     }
 
     pub(crate) fn is_tuple(&self) -> bool {
-        if let Expr::Tuple(_) = self {
-            true
-        } else {
-            false
-        }
+        matches!(self, Expr::Tuple(_))
+    }
+
+    /// Whether this is a keyword: a symbol starting with `:`, like `:name`.
+    /// Keywords evaluate to themselves, so they can be used as dict keys,
+    /// option names and tags without quoting.
+    pub(crate) fn is_keyword(&self) -> bool {
+        matches!(self.unmeta(), Expr::Symbol(s) if s.starts_with(':') && s.len() > 1)
     }
 
     pub(crate) fn is_symbol(&self) -> bool {
-        if let Expr::Symbol(_) = self {
-            true
-        } else {
-            false
-        }
+        matches!(self, Expr::Symbol(_))
     }
 
-    pub(crate) fn get_function(&self) -> LispResult<Function> {
-        if let Expr::Function(f) = self {
+    pub fn get_function(&self) -> LispResult<Function> {
+        if let Expr::Function(f) = self.unmeta() {
             Ok(f.clone())
         } else {
             bad_types!("func", &self)
@@ -234,15 +549,15 @@ impl Expr {
     }
 
     pub(crate) fn get_iterator(&self) -> LispResult<IterType> {
-        if let Expr::LazyIter(l) = self {
+        if let Expr::LazyIter(l) = self.unmeta() {
             Ok(l.clone())
         } else {
             bad_types!("iterator", &self)
         }
     }
 
-    pub(crate) fn get_bool(&self) -> LispResult<bool> {
-        if let Expr::Bool(b) = self {
+    pub fn get_bool(&self) -> LispResult<bool> {
+        if let Expr::Bool(b) = self.unmeta() {
             Ok(*b)
         } else {
             bad_types!("bool", &self)
@@ -251,19 +566,20 @@ impl Expr {
     }
 
     pub(crate) fn get_quote(&self) -> LispResult<Vector<Expr>> {
-        if let Expr::Quote(l) = self {
+        if let Expr::Quote(l) = self.unmeta() {
             Ok(l.clone())
         } else {
             bad_types!("quote", &self)
         }
     }
 
-    pub(crate) fn get_list(&self) -> LispResult<Vector<Expr>> {
-        if let Expr::List(l) = self {
+    pub fn get_list(&self) -> LispResult<Vector<Expr>> {
+        let inner = self.unmeta();
+        if let Expr::List(l) = inner {
             Ok(l.clone())
-        } else if let Expr::Nil = self {
+        } else if let Expr::Nil = inner {
             Ok(Vector::new())
-        } else if let Expr::Tuple(l) = self {
+        } else if let Expr::Tuple(l) = inner {
             Ok(l.clone())
         } else {
             bad_types!("list", &self)
@@ -278,8 +594,16 @@ impl Expr {
         }
     }
 
-    pub(crate) fn get_symbol_string(&self) -> LispResult<String> {
-        if let Expr::Symbol(s) = self {
+    /// If this expression is a spread argument, `@x`, return `x`.
+    pub(crate) fn get_spread_target(&self) -> Option<&Expr> {
+        match self {
+            Expr::List(l) if l.len() == 2 && l[0].symbol_matches(SPREAD_FORM) => Some(&l[1]),
+            _ => None,
+        }
+    }
+
+    pub fn get_symbol_string(&self) -> LispResult<String> {
+        if let Expr::Symbol(s) = self.unmeta() {
             Ok(s.clone())
         } else {
             bad_types!("symbol", &self)
@@ -296,20 +620,20 @@ impl Expr {
     }
 }
 
-pub(crate) type X7FunctionPtr =
-    Arc<dyn Fn(Vector<Expr>, &SymbolTable) -> LispResult<Expr> + Sync + Send>;
+pub type X7FunctionPtr = Arc<dyn Fn(Vector<Expr>, &SymbolTable) -> LispResult<Expr> + Sync + Send>;
 
 #[derive(Clone)]
-pub(crate) struct Function {
+pub struct Function {
     symbol: String,
     minimum_args: usize,
     f: X7FunctionPtr,
     named_args: Vec<Expr>, // Expr::Symbol
     eval_args: bool,
+    annotations: Annotations,
+    // Shared by clones, for `identical?` and `object-id`.
+    id: u64,
 }
 
-use std::hash::{Hash, Hasher};
-
 impl Hash for Function {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.symbol.hash(state);
@@ -323,19 +647,14 @@ impl PartialEq for Function {
     fn eq(&self, other: &Self) -> bool {
         // TODO: See if this is an issue. This should only appear in
         // one code generation unit (i.e. this crate), so it should be safe.
-        #[allow(clippy::vtable_address_comparisons)]
+        #[allow(ambiguous_wide_pointer_comparisons)]
         Arc::ptr_eq(&self.f, &other.f)
     }
 }
 
 impl fmt::Debug for Function {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Fn<{}, {}, [ ", self.symbol, self.minimum_args)?;
-        for arg in &self.named_args {
-            let sym = arg.get_symbol_string().unwrap_or_else(|_| "??".into());
-            write!(f, "{} ", sym)?;
-        }
-        write!(f, "]>")
+        write!(f, "#<fn {} {}>", self.symbol, self.minimum_args)
     }
 }
 
@@ -345,6 +664,17 @@ impl fmt::Display for Function {
     }
 }
 
+/// Set in the ids of immediates, see `Expr::object_id`.
+const VALUE_ID_BIT: u64 = 1 << 63;
+
+static NEXT_OBJECT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A new id for a function, record, or iterator, never given out before in
+/// this process. Ids are counted from one, shared by every kind of object.
+pub(crate) fn next_object_id() -> u64 {
+    NEXT_OBJECT_ID.fetch_add(1, AtomicOrdering::Relaxed)
+}
+
 impl Function {
     pub fn new(symbol: String, minimum_args: usize, f: X7FunctionPtr, eval_args: bool) -> Self {
         Self {
@@ -353,9 +683,29 @@ impl Function {
             f,
             named_args: Vec::with_capacity(0),
             eval_args,
+            annotations: Annotations::default(),
+            id: next_object_id(),
         }
     }
 
+    pub(crate) fn name(&self) -> &str {
+        &self.symbol
+    }
+
+    /// Counted when the function is made, and shared by all its clones.
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub(crate) fn minimum_args(&self) -> usize {
+        self.minimum_args
+    }
+
+    /// The parameter names of a function defined in x7, empty for builtins.
+    pub(crate) fn named_args(&self) -> &[Expr] {
+        &self.named_args
+    }
+
     pub fn new_named_args(
         symbol: String,
         minimum_args: usize,
@@ -369,15 +719,27 @@ impl Function {
             f,
             named_args,
             eval_args,
+            annotations: Annotations::default(),
+            id: next_object_id(),
         }
     }
 
-    // TODO: Refactor this into something cleaner.
-    pub(crate) fn call_fn(
-        &self,
-        args: Vector<Expr>,
-        symbol_table: &SymbolTable,
-    ) -> LispResult<Expr> {
+    /// Attach the type annotations this function was defined with.
+    pub(crate) fn with_annotations(mut self, annotations: Annotations) -> Self {
+        self.annotations = annotations;
+        self
+    }
+
+    pub(crate) fn annotations(&self) -> &Annotations {
+        &self.annotations
+    }
+
+    /// Whether arguments are evaluated before the function sees them.
+    pub(crate) fn eval_args(&self) -> bool {
+        self.eval_args
+    }
+
+    pub(crate) fn check_arity(&self, args: &Vector<Expr>) -> LispResult<()> {
         if self.minimum_args > args.len() {
             bail!(anyhow!(
                 "Too few args supplied for {}. Expected {}, was given {} of length {}",
@@ -387,26 +749,68 @@ impl Function {
                 args.len()
             ));
         }
+        Ok(())
+    }
+
+    // TODO: Refactor this into something cleaner.
+    pub(crate) fn call_fn(
+        &self,
+        args: Vector<Expr>,
+        symbol_table: &SymbolTable,
+    ) -> LispResult<Expr> {
+        if symbol_table.is_interrupted() {
+            bail!(ProgramError::Interrupted);
+        }
+        // Files changed since the last call run their watch-path callbacks here.
+        #[cfg(feature = "watch")]
+        crate::watch::deliver(symbol_table)?;
+
+        // Spread arguments need to be expanded before we can check arity.
+        let args = if self.eval_args {
+            eval_spread_args(self, args, symbol_table)?
+        } else {
+            args
+        };
+        self.apply(args, symbol_table)
+    }
+
+    /// Call the function with `args` which are already values, like the
+    /// items `map` passes along, so they aren't evaluated a second time.
+    pub(crate) fn call_with_values(
+        &self,
+        args: Vector<Expr>,
+        symbol_table: &SymbolTable,
+    ) -> LispResult<Expr> {
+        if !self.eval_args {
+            // Special forms see their arguments as code, so quote values
+            // which don't evaluate to themselves, like lists and symbols.
+            let args = args.into_iter().map(quote_value).collect();
+            return self.call_fn(args, symbol_table);
+        }
+        if symbol_table.is_interrupted() {
+            bail!(ProgramError::Interrupted);
+        }
+        #[cfg(feature = "watch")]
+        crate::watch::deliver(symbol_table)?;
+        self.apply(args, symbol_table)
+    }
+
+    /// Call the function with its arguments evaluated, if it evaluates them.
+    fn apply(&self, args: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+        self.check_arity(&args)?;
 
         if self.named_args.is_empty() {
             if self.eval_args {
-                let args: Vector<_> = args.iter().map(|e| e.eval(symbol_table)).try_collect()?;
-                // let args = args?;
-                return (self.f)(args.clone(), symbol_table).with_context(|| {
-                    format!("Error in {}, with args {}", &self, format_args(&args))
+                return crate::meta::call_builtin(&self.symbol, args, |args| {
+                    (self.f)(args.clone(), symbol_table).with_context(|| {
+                        format!("Error in {}, with args {}", &self, format_args(&args))
+                    })
                 });
             } else {
                 return (self.f)(args, symbol_table);
             }
         }
 
-        let args = if self.eval_args {
-            let args: Vector<_> = args.iter().map(|e| e.eval(symbol_table)).try_collect()?;
-            args
-        } else {
-            args
-        };
-
         // Add local variables to symbol table
         let new_sym = symbol_table.with_locals(&self.named_args, args.clone())?;
 
@@ -416,6 +820,39 @@ impl Function {
     }
 }
 
+/// Evaluate the given arguments, splicing any `@x` arguments into the
+/// surrounding argument list. Only calls written in the program have
+/// spreads, so values passed along by `apply` and `call_with_values` are
+/// never spliced.
+///
+/// Arguments are evaluated one at a time, left to right, and each sees what
+/// the ones before it did: their output comes first, and their `def`s are
+/// visible. Scripts rely on this, so it has to stay a sequential loop.
+pub(crate) fn eval_spread_args(
+    callee: &dyn fmt::Display,
+    args: Vector<Expr>,
+    symbol_table: &SymbolTable,
+) -> LispResult<Vector<Expr>> {
+    let mut evaled = Vector::new();
+    for arg in args.iter() {
+        match arg.get_spread_target() {
+            Some(target) => {
+                let value = target.eval(symbol_table)?;
+                let spliced = value.get_list().with_context(|| {
+                    format!(
+                        "Cannot spread {:?} into the arguments of {}, as it is not a list",
+                        target, callee
+                    )
+                })?;
+                evaled.append(spliced);
+            }
+            // Arguments only see the first of multiple values.
+            None => evaled.push_back(arg.eval(symbol_table)?.first_value()),
+        }
+    }
+    Ok(evaled)
+}
+
 impl std::error::Error for ProgramError {}
 
 // use thiserror::Error;
@@ -439,22 +876,86 @@ pub(crate) enum ProgramError {
     // UnexpectedEOF,
     WrongNumberOfArgs(usize),
     FailedToParse(String),
+    Interrupted,
+    Io,            // context
+    DeniedWarning, // context
+    Permission,    // context
+    Resource,      // context
+    Closed,        // context
+    Cyclic,        // context
+    UserThrown(Expr),
     // Custom(String),
 }
 
+impl ProgramError {
+    /// Every `kind`, for builtins which are told kinds of errors to match.
+    #[cfg(feature = "time")]
+    pub(crate) const KINDS: &'static [&'static str] = &[
+        "BadTypes",
+        "CannotLookupNonSymbol",
+        "CondNoExecutionPath",
+        "CondBadConditionNotEven",
+        "DivisionByZero",
+        "NotAFunction",
+        "ExpectedRestSymbol",
+        "WrongNumberOfArgs",
+        "FailedToParse",
+        "Interrupted",
+        "Io",
+        "DeniedWarning",
+        "Permission",
+        "Cyclic",
+        "Resource",
+        "Closed",
+        "UserThrown",
+    ];
+
+    /// Name of the error variant, for embedders matching on error kinds.
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            ProgramError::BadTypes => "BadTypes",
+            ProgramError::CannotLookupNonSymbol => "CannotLookupNonSymbol",
+            ProgramError::CondNoExecutionPath => "CondNoExecutionPath",
+            ProgramError::CondBadConditionNotEven => "CondBadConditionNotEven",
+            ProgramError::DivisionByZero => "DivisionByZero",
+            ProgramError::NotAFunction(_) => "NotAFunction",
+            ProgramError::ExpectedRestSymbol => "ExpectedRestSymbol",
+            ProgramError::WrongNumberOfArgs(_) => "WrongNumberOfArgs",
+            ProgramError::FailedToParse(_) => "FailedToParse",
+            ProgramError::Interrupted => "Interrupted",
+            ProgramError::Io => "Io",
+            ProgramError::DeniedWarning => "DeniedWarning",
+            ProgramError::Permission => "Permission",
+            ProgramError::Resource => "Resource",
+            ProgramError::Closed => "Closed",
+            ProgramError::Cyclic => "Cyclic",
+            ProgramError::UserThrown(_) => "UserThrown",
+        }
+    }
+}
+
+/// The kind of the first `ProgramError` in `err`'s chain, or "Error" for
+/// custom errors.
+pub(crate) fn error_kind(err: &anyhow::Error) -> &'static str {
+    err.chain()
+        .find_map(|e| e.downcast_ref::<ProgramError>())
+        .map(|e| e.kind())
+        .unwrap_or("Error")
+}
+
 impl fmt::Display for ProgramError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{:?}", self)
     }
 }
 
-pub(crate) type LispResult<T> = anyhow::Result<T>;
+pub type LispResult<T> = anyhow::Result<T>;
 
 impl std::ops::Rem<&Expr> for Expr {
     type Output = LispResult<Expr>;
     fn rem(self, other: &Expr) -> LispResult<Expr> {
         match (&self, &other) {
-            (Expr::Num(l), Expr::Num(r)) => (Ok(Expr::Num(l % r))),
+            (Expr::Num(l), Expr::Num(r)) => Ok(Expr::Num(l % r)),
             _ => bad_types!(format!(
                 "Remainder requires left and right are num types, was given {:?} % {:?}",
                 &self, &other
@@ -467,7 +968,7 @@ impl std::ops::Add<&Expr> for Expr {
     type Output = LispResult<Expr>;
     fn add(self, other: &Expr) -> LispResult<Expr> {
         match (&self, &other) {
-            (Expr::Num(l), Expr::Num(r)) => (Ok(Expr::Num(l + r))),
+            (Expr::Num(l), Expr::Num(r)) => Ok(Expr::Num(l + r)),
             (Expr::String(l), Expr::String(r)) => Ok(Expr::String(l.to_string() + r)),
             (Expr::List(l), Expr::List(r)) => {
                 let mut res = l.clone();
@@ -494,7 +995,7 @@ impl std::ops::Sub<&Expr> for Expr {
     type Output = LispResult<Expr>;
     fn sub(self, other: &Expr) -> LispResult<Expr> {
         match (&self, &other) {
-            (Expr::Num(l), Expr::Num(r)) => (Ok(Expr::Num(l - r))),
+            (Expr::Num(l), Expr::Num(r)) => Ok(Expr::Num(l - r)),
             _ => bad_types!(format!(
                 "Subtraction between these types doesn't make sense: {} - {}",
                 &self, other
@@ -507,19 +1008,8 @@ impl std::ops::Mul<&Expr> for Expr {
     type Output = LispResult<Expr>;
     fn mul(self, other: &Expr) -> LispResult<Expr> {
         match (&self, &other) {
-            (Expr::Num(l), Expr::Num(r)) => (Ok(Expr::Num(l * r))),
-            (Expr::String(l), Expr::Num(r)) => {
-                if *r >= BigDecimal::zero() {
-                    Ok(Expr::String(
-                        l.to_string().repeat(Expr::Num(r.clone()).get_usize()?),
-                    ))
-                } else {
-                    bad_types!(format!(
-                        "Repeating a string negative times doesn't make sense: {} * {}",
-                        &self, other
-                    ))
-                }
-            }
+            (Expr::Num(l), Expr::Num(r)) => Ok(Expr::Num(l * r)),
+            (Expr::String(l), Expr::Num(_)) => Ok(Expr::String(l.repeat(other.get_index("*")?))),
             _ => bad_types!(format!(
                 "Multiplication between these types doesn't make sense: {} * {}",
                 &self, other
@@ -547,9 +1037,12 @@ impl std::ops::Div<&Expr> for Expr {
     }
 }
 
+// Only nums and strings are ordered, and `<` on anything else is false rather
+// than the arbitrary `Less` of `cmp`.
+#[allow(clippy::non_canonical_partial_ord_impl)]
 impl PartialOrd for Expr {
     fn partial_cmp(&self, other: &Expr) -> Option<Ordering> {
-        match (self, other) {
+        match (self.unmeta(), other.unmeta()) {
             (Expr::Num(l), Expr::Num(r)) => l.partial_cmp(r),
             (Expr::String(l), Expr::String(r)) => l.partial_cmp(r),
             _ => None,
@@ -557,11 +1050,59 @@ impl PartialOrd for Expr {
     }
 }
 
+/// Where `expr`'s type comes in `sort_order`.
+fn sort_rank(expr: &Expr) -> u8 {
+    match expr {
+        Expr::Nil => 0,
+        Expr::Bool(_) => 1,
+        Expr::Num(_) => 2,
+        Expr::String(_) => 3,
+        Expr::Symbol(s) if s.starts_with(':') => 4,
+        Expr::Symbol(_) => 5,
+        Expr::List(_) | Expr::Quote(_) => 6,
+        Expr::Tuple(_) => 7,
+        Expr::Dict(_) => 8,
+        Expr::Function(_) | Expr::LazyIter(_) | Expr::Record(_) => 9,
+        Expr::WithMeta(inner, _) => sort_rank(inner),
+        Expr::Values(_) => sort_rank(expr.unmeta()),
+    }
+}
+
+/// The order `sort` and friends use. Unlike `<`, it orders values of
+/// different types, by type: nil < bools < nums < strings < keywords <
+/// symbols < lists < tuples < dicts < everything else. Lists and tuples
+/// compare element by element, and values with no order of their own,
+/// like functions, compare equal.
+pub(crate) fn sort_order(l: &Expr, r: &Expr) -> Ordering {
+    let by_rank = sort_rank(l).cmp(&sort_rank(r));
+    if by_rank != Ordering::Equal {
+        return by_rank;
+    }
+    match (l.unmeta(), r.unmeta()) {
+        (Expr::Bool(l), Expr::Bool(r)) => l.cmp(r),
+        (Expr::Num(l), Expr::Num(r)) => l.cmp(r),
+        (Expr::String(l), Expr::String(r)) => l.cmp(r),
+        (Expr::Symbol(l), Expr::Symbol(r)) => l.cmp(r),
+        (Expr::List(l), Expr::List(r))
+        | (Expr::List(l), Expr::Quote(r))
+        | (Expr::Quote(l), Expr::List(r))
+        | (Expr::Quote(l), Expr::Quote(r))
+        | (Expr::Tuple(l), Expr::Tuple(r)) => l
+            .iter()
+            .zip(r.iter())
+            .map(|(l, r)| sort_order(l, r))
+            .find(|o| *o != Ordering::Equal)
+            .unwrap_or_else(|| l.len().cmp(&r.len())),
+        (Expr::Dict(l), Expr::Dict(r)) => l.len().cmp(&r.len()),
+        _ => Ordering::Equal,
+    }
+}
+
 impl Eq for Expr {}
 
 impl Ord for Expr {
     fn cmp(&self, other: &Self) -> Ordering {
-        match (self, other) {
+        match (self.unmeta(), other.unmeta()) {
             (Expr::Num(l), Expr::Num(r)) => l.cmp(r),
             (Expr::String(l), Expr::String(r)) => l.cmp(r),
             _ => Ordering::Less,
@@ -570,19 +1111,38 @@ impl Ord for Expr {
 }
 
 impl Expr {
-    pub(crate) fn call_fn(
+    pub fn call_fn(&self, args: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+        match self.unmeta() {
+            Expr::Function(f) => f.call_fn(args, symbol_table),
+            _ => crate::callable::call_value(self, args, symbol_table),
+        }
+    }
+
+    /// Like `call_fn`, with `args` which are already values and aren't
+    /// evaluated again.
+    pub(crate) fn call_with_values(
         &self,
         args: Vector<Expr>,
         symbol_table: &SymbolTable,
     ) -> LispResult<Expr> {
-        if let Expr::Function(f) = self {
-            f.call_fn(args, symbol_table)
-        } else {
-            bail!(ProgramError::NotAFunction(self.clone()));
+        match self.unmeta() {
+            Expr::Function(f) => f.call_with_values(args, symbol_table),
+            _ => crate::callable::call_value_with_values(self, args, symbol_table),
         }
     }
 
-    pub(crate) fn eval(&self, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    pub fn eval(&self, symbol_table: &SymbolTable) -> LispResult<Expr> {
+        let value = self.eval_unwrapped(symbol_table)?;
+        Ok(crate::meta::wrap_evaluated(value, symbol_table))
+    }
+
+    fn eval_unwrapped(&self, symbol_table: &SymbolTable) -> LispResult<Expr> {
+        // Values with metadata, and multiple values, were already evaluated.
+
+        if matches!(self, Expr::WithMeta(..) | Expr::Values(_)) {
+            return Ok(self.clone());
+        }
+
         // Tuple bypass
 
         if self.is_tuple() {
@@ -596,10 +1156,23 @@ impl Expr {
                 return Ok(Expr::List(Vector::new()));
             }
 
+            if let Some(target) = self.get_spread_target() {
+                bail!(
+                    "Cannot spread {:?} here, spread (@) is only valid as an argument to a function call",
+                    target
+                );
+            }
+
             let head = list.pop_front().unwrap();
             let tail = list;
 
-            return head.eval(&symbol_table)?.call_fn(tail, symbol_table);
+            // Calls resolved ahead of time (see resolve.rs) hold the function itself.
+            if let Expr::Function(f) = &head {
+                return f.call_fn(tail, symbol_table);
+            }
+
+            // The function is evaluated before any of its arguments.
+            return head.eval(symbol_table)?.call_fn(tail, symbol_table);
         }
 
         // Eval quote
@@ -610,45 +1183,71 @@ impl Expr {
 
         // Resolve Symbol
 
+        if self.is_keyword() {
+            return Ok(self.clone());
+        }
         if self.is_symbol() {
-            return symbol_table.lookup(&self);
+            return symbol_table.lookup(self);
         }
 
         Ok(self.clone())
     }
 }
 
-type SymbolLookup = HashMap<String, Expr>;
+// Persistent maps, so copying a whole scope (e.g. the shared stdlib) is cheap.
+pub(crate) type SymbolLookup = im::HashMap<String, Expr>;
 use std::rc::Rc;
 
 #[derive(Debug, Clone, Default)]
-struct Doc {
-    docs: HashMap<String, String>,
-    order: Vec<String>,
+pub(crate) struct Doc {
+    docs: im::HashMap<String, String>,
+    order: Vector<String>,
+    // Builtin -> its category, like "math". See stdlib::CATEGORIES.
+    categories: im::HashMap<String, &'static str>,
 }
 
 impl Doc {
     fn with_globals(v: Vec<(String, String)>) -> Self {
-        let mut docs = HashMap::new();
+        let mut docs = im::HashMap::new();
         for (name, doc) in v.iter().cloned() {
             docs.insert(name, doc);
         }
         let order = v.into_iter().map(|(name, _)| name).collect();
-        Doc { docs, order }
+        Doc {
+            docs,
+            order,
+            categories: im::HashMap::new(),
+        }
+    }
+
+    /// The category of the builtin `symbol`, like "math".
+    pub(crate) fn category(&self, symbol: &str) -> Option<&'static str> {
+        self.categories.get(symbol).copied()
     }
 
     fn add(&mut self, name: String, doc: String) {
         self.docs.insert(name.clone(), doc);
-        self.order.push(name)
+        self.order.push_back(name)
     }
 }
 
+/// An interpreter: its scopes, docs, loaded modules, and host.
+///
+/// Clones share state, so a clone is a handle to the same interpreter.
 // TODO: Debug should include stdlib
 #[derive(Clone, Debug, Default)]
 pub struct SymbolTable {
     globals: Rc<RefCell<SymbolLookup>>,
     locals: Rc<RefCell<SymbolLookup>>,
     docs: Rc<RefCell<Doc>>,
+    caches: Rc<RefCell<Caches>>,
+    imports: Rc<RefCell<Imports>>,
+    host: Rc<RefCell<Host>>,
+    resources: Rc<RefCell<Resources>>,
+    #[cfg(feature = "watch")]
+    watches: Rc<RefCell<crate::watch::Watches>>,
+    // Set from another thread to stop evaluation at the next function call.
+    interrupt: Arc<AtomicBool>,
     // TODO: Should functions be magic like this?
     // Future Dave: magic means we special case adding
     // symbols to the table whether or not a function is calling.
@@ -660,11 +1259,32 @@ impl SymbolTable {
     pub(crate) fn with_globals(
         globals: Vec<(String, Expr)>,
         doc_order: Vec<(String, String)>,
+    ) -> SymbolTable {
+        SymbolTable::from_globals(globals.into_iter().collect(), Doc::with_globals(doc_order))
+    }
+
+    /// Make a fresh interpreter whose global scope starts as a copy of `globals`.
+    pub(crate) fn from_globals(globals: SymbolLookup, docs: Doc) -> SymbolTable {
+        SymbolTable::from_scope(globals, Default::default(), docs)
+    }
+
+    /// Like `from_globals`, with `locals` already defined at the top level.
+    pub(crate) fn from_scope(
+        globals: SymbolLookup,
+        locals: SymbolLookup,
+        docs: Doc,
     ) -> SymbolTable {
         SymbolTable {
-            globals: Rc::new(RefCell::new(globals.into_iter().collect())),
-            locals: Default::default(),
-            docs: Rc::new(RefCell::new(Doc::with_globals(doc_order))),
+            globals: Rc::new(RefCell::new(globals)),
+            locals: Rc::new(RefCell::new(locals)),
+            docs: Rc::new(RefCell::new(docs)),
+            caches: Default::default(),
+            imports: Default::default(),
+            host: Default::default(),
+            resources: Default::default(),
+            #[cfg(feature = "watch")]
+            watches: Default::default(),
+            interrupt: Default::default(),
             func_locals: Default::default(),
         }
     }
@@ -686,15 +1306,74 @@ impl SymbolTable {
             .borrow()
             .get(symbol)
             .cloned()
-            .ok_or_else(|| anyhow!("Unknown Symbol {}", symbol.to_string()))
+            .ok_or_else(|| anyhow!("Unknown Symbol {}", symbol))
+    }
+
+    /// Copy the global scope and docs, e.g. to seed other interpreters with `from_globals`.
+    pub(crate) fn globals_and_docs(&self) -> (SymbolLookup, Doc) {
+        (self.globals.borrow().clone(), self.docs.borrow().clone())
+    }
+
+    /// Copy the global scope, what's been defined at the top level, and docs,
+    /// e.g. to seed other interpreters with `from_scope`.
+    pub(crate) fn scope_and_docs(&self) -> (SymbolLookup, SymbolLookup, Doc) {
+        let (globals, docs) = self.globals_and_docs();
+        (globals, self.locals.borrow().clone(), docs)
+    }
+
+    /// The bindings at the top level right now, builtins included, to compare
+    /// with a later snapshot using `diff_globals`. Taking one is cheap.
+    pub fn globals_snapshot(&self) -> GlobalsSnapshot {
+        GlobalsSnapshot::new(self.globals.borrow().clone(), self.locals.borrow().clone())
+    }
+
+    /// Snapshot the bindings visible here, to evaluate in on another thread.
+    /// Later defs on either side aren't seen by the other, but mutable
+    /// values like atoms are shared.
+    pub(crate) fn detach(&self) -> DetachedScope {
+        let bindings = self
+            .func_locals
+            .clone()
+            .union(self.locals.borrow().clone())
+            .union(self.globals.borrow().clone());
+        DetachedScope {
+            bindings,
+            docs: self.docs.borrow().clone(),
+            interrupt: self.interrupt.clone(),
+            host: self.host_mut().detach(),
+        }
+    }
+
+    /// Bind `symbol` to `value` in the global scope.
+    pub fn add_global(&self, symbol: &str, value: Expr) {
+        self.globals.borrow_mut().insert(symbol.into(), value);
     }
 
     pub(crate) fn add_local(&self, symbol: &Expr, value: &Expr) -> LispResult<Expr> {
-        let mut locals = self.locals.borrow_mut();
-        locals.insert(symbol.get_symbol_string()?, value.clone());
+        let symbol = symbol.get_symbol_string()?;
+        // Calls to it may already be resolved to the builtin.
+        ensure!(
+            !(self.host().is_frozen_globals() && self.builtin(&symbol).is_some()),
+            "Cannot redefine the builtin {} while globals are frozen",
+            symbol
+        );
+        self.locals
+            .borrow_mut()
+            .insert(symbol, value.clone().first_value());
         Ok(Expr::Nil)
     }
 
+    /// The global function `symbol` is bound to, unless a local binding hides it.
+    pub(crate) fn builtin(&self, symbol: &str) -> Option<Function> {
+        if self.func_locals.contains_key(symbol) || self.locals.borrow().contains_key(symbol) {
+            return None;
+        }
+        match self.globals.borrow().get(symbol) {
+            Some(Expr::Function(f)) => Some(f.clone()),
+            _ => None,
+        }
+    }
+
     pub(crate) fn add_doc_item(&self, symbol: String, doc: String) {
         self.docs.borrow_mut().add(symbol, doc);
     }
@@ -703,6 +1382,17 @@ impl SymbolTable {
         self.docs.borrow().docs.get(symbol).cloned()
     }
 
+    pub(crate) fn set_category(&self, symbol: &str, category: &'static str) {
+        self.docs
+            .borrow_mut()
+            .categories
+            .insert(symbol.into(), category);
+    }
+
+    pub(crate) fn get_category(&self, symbol: &str) -> Option<&'static str> {
+        self.docs.borrow().category(symbol)
+    }
+
     pub(crate) fn get_doc_methods(&self, sym: &str) -> Vec<(String, String)> {
         self.docs
             .borrow()
@@ -733,9 +1423,7 @@ impl SymbolTable {
                 } else {
                     bail!(ProgramError::ExpectedRestSymbol);
                 };
-                copy.locals
-                    .borrow_mut()
-                    .insert(rest_sym, Expr::List(values_iter.collect()));
+                new_func_locals.insert(rest_sym, Expr::List(values_iter.collect()));
                 break;
             }
 
@@ -745,12 +1433,405 @@ impl SymbolTable {
         Ok(copy)
     }
 
+    /// Evaluate `source` in a temporary child scope with `bindings` pre-bound.
+    ///
+    /// The child starts from a copy of the local scope, so `def` in the
+    /// snippet binds in the temporary scope and is gone afterwards, along
+    /// with the bindings. Scopes are persistent maps, so the copy is cheap.
+    pub fn eval_with_bindings(&self, source: &str, bindings: &[(&str, Expr)]) -> LispResult<Expr> {
+        self.with_bindings(bindings).eval_source(source)
+    }
+
+    /// A child scope of this one with `bindings` bound, for `eval_with_bindings`.
+    pub(crate) fn with_bindings(&self, bindings: &[(&str, Expr)]) -> SymbolTable {
+        let mut locals = self.locals.borrow().clone();
+        for (symbol, value) in bindings {
+            locals.insert((*symbol).into(), value.clone());
+        }
+        let mut child = self.clone();
+        child.locals = Rc::new(RefCell::new(locals));
+        child
+    }
+
+    /// Parse `source` once, to evaluate it many times with
+    /// `CompiledExpr::eval_with`. With frozen globals its forms are
+    /// resolved now too, instead of on every evaluation.
+    pub fn compile(&self, source: &str) -> LispResult<CompiledExpr> {
+        crate::compiled::compile(source, self)
+    }
+
+    /// Serve a REPL on `addr`, a localhost address, for `x7 attach` to
+    /// connect to with the server's token. What clients send is only
+    /// evaluated when the returned server's `serve_pending` or `serve_for`
+    /// is called with this interpreter.
+    #[cfg(feature = "remote-repl")]
+    pub fn serve_repl(&self, addr: &str) -> LispResult<crate::remote::ReplServer> {
+        crate::remote::serve(addr)
+    }
+
+    /// Evaluate every form in `source`, returning the value of the last one.
+    pub fn eval_source(&self, source: &str) -> LispResult<Expr> {
+        self.check_program(source)?;
+        let mut res = Expr::Nil;
+        for expr in crate::parser::read_with_comments(source).with_reader_tags(self) {
+            let expr = expr?;
+            self.host_mut().reset_built_bytes();
+            res = self.eval_form(&expr)?;
+        }
+        Ok(res)
+    }
+
+    /// Like `eval_source`, but for a whole script read from `file`, warning
+    /// when a top level form's value is thrown away. The warnings cite
+    /// `file`, unless a `;#line` directive says otherwise.
+    pub fn eval_script(&self, source: &str, file: &str) -> LispResult<Expr> {
+        self.check_program(source)?;
+        let mut forms = crate::parser::read_with_comments(source).with_reader_tags(self);
+        let mut res = Expr::Nil;
+        let mut last: Option<(Expr, Span)> = None;
+        while let Some(expr) = forms.next() {
+            if let Some((form, span)) = last.take() {
+                self.warn_if_discarded(&form, &res, &span)?;
+            }
+            let expr = expr?;
+            self.host_mut().reset_built_bytes();
+            res = self.eval_form(&expr)?;
+            last = Some((expr, forms.last_span(file)));
+        }
+        Ok(res)
+    }
+
+    /// Fail with a Resource error if `source` is over the limits set with
+    /// `set_max_program_metrics`.
+    pub(crate) fn check_program(&self, source: &str) -> LispResult<()> {
+        let limits = self.host().max_program_metrics().cloned();
+        match limits {
+            Some(limits) => crate::metrics::check_source(source, &limits),
+            None => Ok(()),
+        }
+    }
+
+    /// Evaluate a top level form, resolving it first if globals are frozen.
+    pub(crate) fn eval_form(&self, form: &Expr) -> LispResult<Expr> {
+        if !self.host().history().is_enabled() {
+            return self.eval_top_level(form);
+        }
+        let start = Instant::now();
+        let res = self.eval_top_level(form);
+        self.host_mut()
+            .history_mut()
+            .record(form, start.elapsed(), res.is_ok());
+        res
+    }
+
+    fn eval_top_level(&self, form: &Expr) -> LispResult<Expr> {
+        if self.host().is_frozen_globals() {
+            crate::resolve::resolve(form, self)?.eval(self)
+        } else {
+            form.eval(self)
+        }
+    }
+
+    /// Warn that the value of a top level `form` is thrown away, if it is a
+    /// function, or `form` is a bare symbol. Both usually mean a call is
+    /// missing its parentheses, like `map inc xs`.
+    pub(crate) fn warn_if_discarded(
+        &self,
+        form: &Expr,
+        value: &Expr,
+        span: &Span,
+    ) -> LispResult<()> {
+        let form = match form {
+            // Forms after a `;#line` directive are wrapped to carry their location.
+            Expr::List(l) if l.len() == 5 && l[0].symbol_matches("with-location") => &l[4],
+            form => form,
+        };
+        let definition = match form {
+            Expr::List(l) => l
+                .front()
+                .is_some_and(|head| matches!(head, Expr::Symbol(s) if s.starts_with("def"))),
+            _ => false,
+        };
+        let bare_symbol = matches!(form, Expr::Symbol(s) if !s.starts_with(':'));
+        if definition || !(bare_symbol || matches!(value, Expr::Function(_))) {
+            return Ok(());
+        }
+        self.warn(Warning {
+            kind: ":discarded".into(),
+            message: format!(
+                "{} evaluates to a {} which is never used, did you mean to call it? wrap the form in parentheses",
+                form,
+                value.get_type_str()
+            ),
+            span: Some(format!("{}:{}:{}", span.file, span.line, span.col)),
+        })
+    }
+
+    /// Report a warning, like `Host::warn`, calling the embedder's handler
+    /// once the host is no longer borrowed.
+    pub(crate) fn warn(&self, warning: Warning) -> LispResult<()> {
+        let pending = self.host_mut().warn(warning)?;
+        if let Some(pending) = pending {
+            pending.deliver();
+        }
+        Ok(())
+    }
+
+    /// Define a global function `name` implemented in Rust. Its arguments
+    /// are evaluated before `f` is called.
+    pub fn add_function<F>(&self, name: &str, minimum_args: usize, doc: &str, f: F)
+    where
+        F: Fn(Vector<Expr>, &SymbolTable) -> LispResult<Expr> + Sync + Send + 'static,
+    {
+        let f = Function::new(name.into(), minimum_args, Arc::new(f), true);
+        self.add_global(name, Expr::Function(f));
+        self.add_doc_item(name.into(), doc.into());
+    }
+
+    /// Call the global function `name` with already evaluated `args`.
+    pub fn call_function(&self, name: &str, args: Vector<Expr>) -> LispResult<Expr> {
+        let f = self.lookup(&Expr::Symbol(name.into()))?;
+        self.call_function_value(&f, args)
+    }
+
+    /// Call the function value `f`, like a function passed to a builtin,
+    /// with already evaluated `args`. Lists and symbols in them, however
+    /// deep, arrive as data rather than being evaluated again.
+    pub fn call_function_value(&self, f: &Expr, args: Vector<Expr>) -> LispResult<Expr> {
+        f.call_with_values(args, self)
+    }
+
+    /// A stdlib interpreter set up as `config` says.
+    pub fn from_config(config: &InterpreterConfig) -> SymbolTable {
+        crate::config::build(config)
+    }
+
+    /// The settings this interpreter has now, whether they came from a
+    /// config or were set one at a time.
+    pub fn config(&self) -> InterpreterConfig {
+        crate::config::effective(self)
+    }
+
+    // The embedding API: every setting of `InterpreterConfig`, and the hooks
+    // and limits which can't be plain data, set on a live interpreter. They
+    // take effect from the next form evaluated.
+
+    /// Deny file access (`fs::open`, loading modules from files) from now on.
+    pub fn set_sandboxed(&self, sandboxed: bool) {
+        self.host.borrow_mut().set_sandboxed(sandboxed);
+    }
+
+    /// Make warnings errors, like `--deny-warnings`. They never reach the
+    /// `on_warning` handler.
+    pub fn set_deny_warnings(&self, deny: bool) {
+        self.host.borrow_mut().set_deny_warnings(deny);
+    }
+
+    /// Make calling builtins by deprecated names errors, like `--deny-deprecated`.
+    pub fn set_deny_deprecated(&self, deny: bool) {
+        self.host.borrow_mut().set_deny_deprecated(deny);
+    }
+
+    /// Make sequence builtins error on nil instead of treating it as an
+    /// empty list, like `--strict-nil`.
+    pub fn set_strict_nil(&self, strict: bool) {
+        self.host.borrow_mut().set_strict_nil(strict);
+    }
+
+    /// Render progress bars, tables, and the like for `terminal` instead of
+    /// what was detected when the interpreter was made.
+    pub fn set_terminal_info(&self, terminal: TerminalInfo) {
+        self.host.borrow_mut().set_terminal(terminal);
+    }
+
+    /// Resolve calls to builtins as each top level form is read, instead of
+    /// looking them up on every call. Redefining a builtin becomes an error,
+    /// and parameters only hide builtins within the body of their function.
+    pub fn set_frozen_globals(&self, frozen: bool) {
+        self.host.borrow_mut().set_frozen_globals(frozen);
+    }
+
+    /// Error with a `Resource` error when a builtin like `repeat`, `range`,
+    /// or `str` would make a value bigger than roughly this many bytes.
+    /// Values are unlimited by default.
+    pub fn set_max_value_bytes(&self, max: Option<usize>) {
+        self.host.borrow_mut().set_max_value_bytes(max);
+    }
+
+    /// Reject source given to `eval_source`, `eval_script` or `compile`
+    /// with a `Resource` error, before evaluating any of it, when it's over
+    /// any of `limits`. See `analyze`. Programs are unlimited by default.
+    pub fn set_max_program_metrics(&self, limits: Option<ProgramLimits>) {
+        self.host.borrow_mut().set_max_program_metrics(limits);
+    }
+
+    /// Keep the last `size` top level forms evaluated, with how long each
+    /// took and whether it failed, for `recent_evaluations`. 0, the default,
+    /// keeps none.
+    pub fn set_history_size(&self, size: usize) {
+        self.host.borrow_mut().history_mut().set_capacity(size);
+    }
+
+    /// The top level forms evaluated most recently, oldest first, if
+    /// `set_history_size` asked to keep them.
+    pub fn recent_evaluations(&self) -> Vec<Evaluation> {
+        self.host().history().entries()
+    }
+
+    /// Ask `hook` before every file open, directory listing, network connection,
+    /// and shell command a program makes. Everything is allowed by default.
+    /// The sandbox, if enabled, still denies everything. `hook` can't be
+    /// called from generator bodies, which run on their own threads, so
+    /// they're denied everything instead.
+    pub fn on_io_access<F: Fn(&IoOp) -> Decision + 'static>(&self, hook: F) {
+        self.host.borrow_mut().set_io_hook(hook);
+    }
+
+    /// Send warnings to `handler` instead of stderr, e.g. to show them in an
+    /// editor. Warnings denied with `--deny-warnings` are errors and never
+    /// reach it. Generator bodies, which run on their own threads, still warn
+    /// to stderr.
+    pub fn on_warning<F: Fn(&Warning) + 'static>(&self, handler: F) {
+        self.host.borrow_mut().on_warning(handler);
+    }
+
+    /// Consult `resolver` for the source of modules before looking on disk.
+    ///
+    /// `resolver` is given the module name, e.g. `"utils"` for `(require utils)`,
+    /// and returns `None` to fall back to the filesystem. Modules it provides
+    /// load even when sandboxed.
+    pub fn set_module_resolver<F: Fn(&str) -> Option<String> + 'static>(&self, resolver: F) {
+        self.imports.borrow_mut().set_resolver(resolver);
+    }
+
+    /// Memoize number parsing, regexes, and method lookups, which is the
+    /// default. Disabling the caches empties them, like `--no-caches`.
+    pub fn set_caches_enabled(&self, enabled: bool) {
+        self.caches.borrow_mut().set_enabled(enabled);
+    }
+
+    pub(crate) fn parse_num(&self, s: &str) -> LispResult<Num> {
+        self.caches.borrow_mut().parse_num(s)
+    }
+
+    #[cfg(feature = "regex")]
+    pub(crate) fn compile_regex(&self, pattern: &str) -> LispResult<regex::Regex> {
+        self.caches.borrow_mut().compile_regex(pattern)
+    }
+
+    pub(crate) fn caches_enabled(&self) -> bool {
+        self.caches.borrow().is_enabled()
+    }
+
+    pub(crate) fn count_method_lookup(&self, hit: bool) {
+        self.caches.borrow_mut().count_method_lookup(hit);
+    }
+
+    pub(crate) fn cache_stats(&self) -> Expr {
+        self.caches.borrow().stats()
+    }
+
+    pub(crate) fn clear_caches(&self) {
+        self.caches.borrow_mut().clear();
+    }
+
+    pub(crate) fn imports(&self) -> Ref<'_, Imports> {
+        self.imports.borrow()
+    }
+
+    pub(crate) fn imports_mut(&self) -> RefMut<'_, Imports> {
+        self.imports.borrow_mut()
+    }
+
+    pub(crate) fn host(&self) -> Ref<'_, Host> {
+        self.host.borrow()
+    }
+
+    pub(crate) fn host_mut(&self) -> RefMut<'_, Host> {
+        self.host.borrow_mut()
+    }
+
+    /// Whether to give the value just evaluated metadata, in tests checking
+    /// that it changes nothing. See `meta::wrap_evaluated`.
+    #[cfg(test)]
+    pub(crate) fn wraps_next_value(&self) -> bool {
+        // Values evaluated while the host is in use are left alone.
+        self.host
+            .try_borrow()
+            .is_ok_and(|host| host.wraps_next_value())
+    }
+
+    pub(crate) fn resources_mut(&self) -> RefMut<'_, Resources> {
+        self.resources.borrow_mut()
+    }
+
+    /// What the program still holds: files it opened and never closed,
+    /// atoms still bound somewhere, and threads it started, like generators
+    /// and watches, which are still running. Embedders can check this after
+    /// a run to find leaks; it doesn't close anything.
+    pub fn resource_report(&self) -> ResourceReport {
+        self.resources.borrow_mut().report()
+    }
+
+    /// Close every file the program left open and stop its threads, waiting
+    /// a few seconds at most for them to exit, returning what was still held.
+    /// Bound records stay bound, but their handles are gone, so later calls
+    /// on them fail. Call this before dropping an interpreter whose program
+    /// may have leaked files.
+    pub fn shutdown(&self) -> ResourceReport {
+        #[cfg(feature = "watch")]
+        self.watches.borrow_mut().clear();
+        self.resources.borrow_mut().shutdown()
+    }
+
+    #[cfg(feature = "watch")]
+    pub(crate) fn watches_mut(&self) -> RefMut<'_, crate::watch::Watches> {
+        self.watches.borrow_mut()
+    }
+
+    pub(crate) fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    /// Stop evaluation whenever `interrupt` is set, instead of the interpreter's own flag.
+    pub(crate) fn set_interrupt_handle(&mut self, interrupt: Arc<AtomicBool>) {
+        self.interrupt = interrupt;
+    }
+
+    pub(crate) fn reset_interrupt(&self) {
+        self.interrupt.store(false, AtomicOrdering::SeqCst);
+    }
+
+    pub(crate) fn is_interrupted(&self) -> bool {
+        self.interrupt.load(AtomicOrdering::SeqCst)
+    }
+
     pub(crate) fn push_canonical_doc_item(&self, item: String) {
-        self.docs.borrow_mut().order.push(item);
+        self.docs.borrow_mut().order.push_back(item);
     }
 
     pub(crate) fn get_canonical_doc_order(&self) -> Vec<String> {
-        self.docs.borrow().order.clone()
+        self.docs.borrow().order.iter().cloned().collect()
+    }
+}
+
+/// A copy of a scope which can be sent to another thread, made with `detach`.
+pub(crate) struct DetachedScope {
+    bindings: SymbolLookup,
+    docs: Doc,
+    interrupt: Arc<AtomicBool>,
+    host: DetachedHost,
+}
+
+impl DetachedScope {
+    /// Make an interpreter with the copied bindings, and the original's
+    /// host settings and IO restrictions. Interrupting the original
+    /// interrupts it too.
+    pub(crate) fn attach(self) -> SymbolTable {
+        let mut symbol_table = SymbolTable::from_globals(self.bindings, self.docs);
+        symbol_table.interrupt = self.interrupt;
+        *symbol_table.host_mut() = self.host.attach();
+        symbol_table
     }
 }
 
@@ -758,13 +1839,123 @@ impl SymbolTable {
 // (foo 1 2 3 4) // x: 1, rest: '(2 3 4)
 
 fn get_symbol(sym: Option<Expr>) -> Option<LispResult<String>> {
-    match sym {
-        Some(rest_sym) => Some(rest_sym.get_symbol_string()),
-        None => None,
-    }
+    sym.map(|rest_sym| rest_sym.get_symbol_string())
 }
 
-fn format_args(args: &Vector<Expr>) -> String {
+pub(crate) fn format_args(args: &Vector<Expr>) -> String {
     // let mut res = String::new();
     format!("{}{}{}", "(", debug_join(args), ")")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::hash_map::DefaultHasher;
+
+    fn hash_of(e: &Expr) -> u64 {
+        let mut h = DefaultHasher::new();
+        e.hash(&mut h);
+        h.finish()
+    }
+
+    /// Random nums with a mix of scales, including integers written as decimals.
+    fn random_num(rng: &mut StdRng) -> Num {
+        let whole: i64 = rng.gen_range(-1000, 1000);
+        let s = match rng.gen_range(0, 3) {
+            0 => format!("{}", whole),
+            1 => format!("{}.{:03}", whole, rng.gen_range(0, 1000)),
+            _ => format!("{}.00", whole),
+        };
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn equal_nums_hash_equally() {
+        let pairs = [
+            ("1", "1.0"),
+            ("1", "1.000"),
+            ("-0", "0.00"),
+            ("10", "10.0"),
+            ("0.5", "0.50"),
+        ];
+        for (l, r) in pairs.iter() {
+            let l = Expr::Num(l.parse().unwrap());
+            let r = Expr::Num(r.parse().unwrap());
+            assert_eq!(l, r);
+            assert_eq!(hash_of(&l), hash_of(&r));
+        }
+        assert_ne!(
+            hash_of(&Expr::Num("1".parse().unwrap())),
+            hash_of(&Expr::Num("10".parse().unwrap()))
+        );
+    }
+
+    #[test]
+    fn arithmetic_properties_over_mixed_scales() {
+        let mut rng = StdRng::seed_from_u64(205);
+        for _ in 0..500 {
+            let (a, b, c) = (
+                Expr::Num(random_num(&mut rng)),
+                Expr::Num(random_num(&mut rng)),
+                Expr::Num(random_num(&mut rng)),
+            );
+            let ab = (a.clone() + &b).unwrap();
+            assert_eq!(ab, (b.clone() + &a).unwrap());
+            assert_eq!(
+                (ab + &c).unwrap(),
+                (a.clone() + &(b.clone() + &c).unwrap()).unwrap()
+            );
+            let ab = (a.clone() * &b).unwrap();
+            assert_eq!(ab, (b.clone() * &a).unwrap());
+            assert_eq!(
+                (ab.clone() * &c).unwrap(),
+                (a.clone() * &(b.clone() * &c).unwrap()).unwrap()
+            );
+            // Results that are equal must be usable as the same dict key.
+            let n = a.get_num().unwrap();
+            let rescaled = Expr::Num(n.with_scale(6));
+            assert_eq!(a, rescaled);
+            assert_eq!(hash_of(&a), hash_of(&rescaled));
+        }
+    }
+
+    #[test]
+    fn eval_with_bindings_is_isolated() {
+        use crate::cli::Options;
+        use crate::stdlib::create_stdlib_symbol_table;
+        use im::vector;
+
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.eval_with_bindings("(def greeting \"hi\")", &[])
+            .unwrap();
+        // That def was temporary too.
+        assert!(syms.lookup(&Expr::Symbol("greeting".into())).is_err());
+        crate::parser::read("(def prefix \"user-\")")
+            .for_each(|e| drop(e.unwrap().eval(&syms).unwrap()));
+
+        let admin = [("user_id", Expr::from(42)), ("role", Expr::from("admin"))];
+        let guest = [("user_id", Expr::from(7)), ("role", Expr::from("guest"))];
+        let prog = "(def seen (+ prefix role)) (list user_id seen)";
+        let expected = |id: i64, seen: &str| Expr::List(vector![Expr::from(id), Expr::from(seen)]);
+        // Interleave the calls to check one's bindings never show up in the other.
+        for _ in 0..2 {
+            let res = syms.eval_with_bindings(prog, &admin).unwrap();
+            assert_eq!(res, expected(42, "user-admin"));
+            let res = syms.eval_with_bindings(prog, &guest).unwrap();
+            assert_eq!(res, expected(7, "user-guest"));
+        }
+        for sym in &["user_id", "role", "seen"] {
+            assert!(
+                syms.lookup(&Expr::Symbol((*sym).into())).is_err(),
+                "{} leaked",
+                sym
+            );
+        }
+        assert_eq!(
+            syms.lookup(&Expr::Symbol("prefix".into())).unwrap(),
+            Expr::from("user-")
+        );
+    }
+}
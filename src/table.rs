@@ -0,0 +1,349 @@
+use crate::symbols::{Expr, LispResult, SymbolTable};
+use crate::terminal::TerminalInfo;
+use anyhow::{bail, ensure};
+use im::Vector;
+use itertools::Itertools;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+// (print-table rows [:columns '(:name :age)] [:max-width 20] [:header '("name" "age")])
+//
+// Rows are dicts, or lists / tuples named by :header. Rows are printed as
+// they're read, so lazy sequences aren't realized: column widths come from
+// the first SAMPLE_ROWS rows, and wider cells after them push the rest of
+// their line right. Widths are in terminal columns, so CJK text lines up.
+// On a terminal, lines are also cut to its width rather than wrapping.
+
+/// Rows read before printing anything, to size the columns.
+const SAMPLE_ROWS: usize = 100;
+
+/// Where a column's cells come from.
+enum Selector {
+    Key(Expr),
+    Index(usize),
+}
+
+struct Column {
+    label: String,
+    selector: Selector,
+    width: usize,
+}
+
+#[derive(Default)]
+struct TableOptions {
+    columns: Option<Vector<Expr>>,
+    max_width: Option<usize>,
+    header: Option<Vector<Expr>>,
+}
+
+impl TableOptions {
+    fn parse(args: &Vector<Expr>) -> LispResult<Self> {
+        ensure!(
+            args.len().is_multiple_of(2),
+            "print-table takes options as :option value pairs, but was given {}",
+            Expr::List(args.clone())
+        );
+        let mut options = TableOptions::default();
+        for (key, value) in args.iter().tuples() {
+            match key.get_symbol_string()?.as_str() {
+                ":columns" => options.columns = Some(value.get_list()?),
+                ":max-width" => {
+                    let width = value.get_usize()?;
+                    ensure!(width > 0, "print-table's :max-width must be at least 1");
+                    options.max_width = Some(width);
+                }
+                ":header" => options.header = Some(value.get_list()?),
+                other => bail!(
+                    "print-table has no option {}, it knows :columns :max-width :header",
+                    other
+                ),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// How a key or header names its column, e.g. `:age` is "age".
+fn label(expr: &Expr) -> String {
+    match expr {
+        Expr::Symbol(s) if s.starts_with(':') => s[1..].into(),
+        other => cell_text(other),
+    }
+}
+
+fn cell_text(expr: &Expr) -> String {
+    let text = match expr.unmeta() {
+        Expr::String(s) => s.clone(),
+        Expr::Nil => String::new(),
+        other => format!("{}", other),
+    };
+    // Keep each row on one line.
+    text.replace('\n', "\\n").replace('\t', "\\t")
+}
+
+/// `s`, cut down to `width` columns with a trailing `ellipsis` if it's wider.
+fn truncate(s: &str, width: usize, ellipsis: &str) -> String {
+    if s.width() <= width {
+        return s.into();
+    }
+    // Too narrow to say it was cut, so just cut it.
+    let ellipsis = if ellipsis.width() > width {
+        ""
+    } else {
+        ellipsis
+    };
+    let mut out = String::new();
+    let mut used = 0;
+    for c in s.chars() {
+        let w = c.width().unwrap_or(0);
+        if used + w + ellipsis.width() > width {
+            break;
+        }
+        used += w;
+        out.push(c);
+    }
+    out.push_str(ellipsis);
+    out
+}
+
+/// `line` as printed to `terminal`, which cuts it to fit on a terminal.
+fn fit(line: &str, terminal: &TerminalInfo) -> String {
+    match terminal.width {
+        Some(width) if terminal.stdout_tty => truncate(line, width, terminal.ellipsis()),
+        _ => line.into(),
+    }
+}
+
+fn cell(row: &Expr, selector: &Selector) -> LispResult<Expr> {
+    Ok(match (row, selector) {
+        (Expr::Dict(d), Selector::Key(key)) => d.get(key).cloned().unwrap_or(Expr::Nil),
+        (Expr::List(l), Selector::Index(i)) | (Expr::Tuple(l), Selector::Index(i)) => {
+            l.get(*i).cloned().unwrap_or(Expr::Nil)
+        }
+        (Expr::Dict(_), _) => bail!("print-table can't mix dict rows with list rows"),
+        (Expr::List(_), _) | (Expr::Tuple(_), _) => {
+            bail!("print-table needs :header to name the columns of list rows")
+        }
+        (other, _) => bail!(
+            "print-table expects rows to be dicts, lists, or tuples, but was given {}",
+            other
+        ),
+    })
+}
+
+/// Pick the columns to print, in order.
+fn columns(options: &TableOptions, sample: &[Expr]) -> LispResult<Vec<Column>> {
+    let column = |label: String, selector: Selector| Column {
+        width: label.width(),
+        label,
+        selector,
+    };
+    let dict_rows = matches!(sample.first(), Some(Expr::Dict(_)));
+    if let Some(header) = &options.header {
+        let labels: Vec<String> = header.iter().map(label).collect();
+        return Ok(match &options.columns {
+            Some(wanted) => wanted
+                .iter()
+                .map(|c| match labels.iter().position(|l| *l == label(c)) {
+                    Some(i) => Ok(column(label(c), Selector::Index(i))),
+                    None => bail!("print-table has no column {} in its :header", c),
+                })
+                .collect::<LispResult<_>>()?,
+            None => labels
+                .into_iter()
+                .enumerate()
+                .map(|(i, l)| column(l, Selector::Index(i)))
+                .collect(),
+        });
+    }
+    if let Some(wanted) = &options.columns {
+        return Ok(wanted
+            .iter()
+            .map(|key| column(label(key), Selector::Key(key.clone())))
+            .collect());
+    }
+    if !dict_rows && !sample.is_empty() {
+        bail!("print-table needs :header to name the columns of list rows");
+    }
+    // Every key seen in the sample, alphabetically since dicts are unordered.
+    let keys = sample
+        .iter()
+        .filter_map(|row| match row {
+            Expr::Dict(d) => Some(d.keys().cloned().collect::<Vec<_>>()),
+            _ => None,
+        })
+        .flatten()
+        .unique()
+        .sorted_by_key(|key| format!("{}", key));
+    Ok(keys
+        .map(|key| column(label(&key), Selector::Key(key)))
+        .collect())
+}
+
+/// One line of the table. Numbers are right aligned, everything else left.
+fn render_row(
+    row: &Expr,
+    columns: &[Column],
+    max_width: usize,
+    terminal: &TerminalInfo,
+) -> LispResult<String> {
+    let cells = columns
+        .iter()
+        .map(|column| {
+            let value = cell(row, &column.selector)?;
+            let text = truncate(&cell_text(&value), max_width, terminal.ellipsis());
+            let pad = " ".repeat(column.width.saturating_sub(text.width()));
+            Ok(match value.unmeta() {
+                Expr::Num(_) => format!("{}{}", pad, text),
+                _ => format!("{}{}", text, pad),
+            })
+        })
+        .collect::<LispResult<Vec<_>>>()?;
+    Ok(format!("{}\n", fit(cells.join(" | ").trim_end(), terminal)))
+}
+
+/// (print-table rows [:columns '(:name :age)] [:max-width 20] [:header '("name" "age")])
+pub(crate) fn print_table(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let options = TableOptions::parse(&exprs.clone().slice(1..))?;
+    let max_width = options.max_width.unwrap_or(usize::MAX);
+    let terminal = symbol_table.host_mut().terminal();
+    let mut rows: Box<dyn FnMut() -> Option<LispResult<Expr>> + '_> = match &exprs[0] {
+        Expr::LazyIter(iter) => {
+            let iter = iter.clone();
+            Box::new(move || iter.next(symbol_table))
+        }
+        rows => {
+            let mut rows = rows.get_list()?.into_iter();
+            Box::new(move || rows.next().map(Ok))
+        }
+    };
+
+    let mut sample = Vec::new();
+    while sample.len() < SAMPLE_ROWS {
+        match rows() {
+            Some(row) => sample.push(row?),
+            None => break,
+        }
+    }
+    let mut columns = columns(&options, &sample)?;
+    for column in columns.iter_mut() {
+        for row in sample.iter() {
+            let text = cell_text(&cell(row, &column.selector)?);
+            column.width = column.width.max(text.width());
+        }
+        column.width = column.width.min(max_width).max(1);
+    }
+
+    let header = columns
+        .iter()
+        .map(|c| {
+            let text = truncate(&c.label, max_width, terminal.ellipsis());
+            format!(
+                "{}{}",
+                text,
+                " ".repeat(c.width.saturating_sub(text.width()))
+            )
+        })
+        .join(" | ");
+    let separator = columns.iter().map(|c| "-".repeat(c.width)).join("-+-");
+    symbol_table.host_mut().write_stdout(&format!(
+        "{}\n{}\n",
+        fit(header.trim_end(), &terminal),
+        fit(&separator, &terminal)
+    ));
+
+    let mut printed = 0;
+    for row in sample.iter() {
+        let line = render_row(row, &columns, max_width, &terminal)?;
+        symbol_table.host_mut().write_stdout(&line);
+        printed += 1;
+    }
+    while let Some(row) = rows() {
+        let line = render_row(&row?, &columns, max_width, &terminal)?;
+        symbol_table.host_mut().write_stdout(&line);
+        printed += 1;
+    }
+    Ok(Expr::from(printed as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Options;
+    use crate::stdlib::create_stdlib_symbol_table;
+
+    fn printed(prog: &str) -> (Expr, String) {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.host_mut().capture_output();
+        let res = syms.eval_source(prog).unwrap();
+        let (stdout, _) = syms.host_mut().take_captured_output();
+        (res, stdout)
+    }
+
+    #[test]
+    fn cells_truncate_by_display_width() {
+        assert_eq!(truncate("hello", 5, "…"), "hello");
+        assert_eq!(truncate("hello world", 5, "…"), "hell…");
+        assert_eq!(truncate("hello world", 5, "..."), "he...");
+        assert_eq!(truncate("hello world", 2, "..."), "he");
+        // Each of these is two columns wide.
+        assert_eq!(truncate("東京都庁", 5, "…"), "東京…");
+        assert_eq!(cell_text(&Expr::String("a\nb".into())), "a\\nb");
+    }
+
+    #[test]
+    fn rendering_fits_the_terminal() {
+        let tty = TerminalInfo {
+            stdout_tty: true,
+            width: Some(12),
+            ..TerminalInfo::default()
+        };
+        assert_eq!(fit("name | email", &tty), "name | email");
+        assert_eq!(fit("name | email address", &tty), "name | emai…");
+        let ascii = TerminalInfo {
+            unicode: false,
+            ..tty.clone()
+        };
+        assert_eq!(fit("name | email address", &ascii), "name | em...");
+        // Pipes and files get whole lines.
+        let piped = TerminalInfo {
+            stdout_tty: false,
+            ..tty
+        };
+        assert_eq!(fit("name | email address", &piped), "name | email address");
+
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.set_terminal_info(ascii);
+        syms.host_mut().capture_output();
+        let prog = "(print-table (list (dict :name \"Marguerite\")) :max-width 6)";
+        syms.eval_source(prog).unwrap();
+        let out = syms.host_mut().take_captured_output().0;
+        assert_eq!(out, "name\n------\nMar...\n");
+    }
+
+    #[test]
+    fn lazy_rows_stream_past_the_sample() {
+        let (res, out) = printed("(print-table (take 150 (map (fn (x) (dict :n x)) (range))))");
+        assert_eq!(res, Expr::from(150));
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 152);
+        assert_eq!(&lines[..3], &["n", "--", " 0"]);
+        // Sized for the first 100 rows, so later ones overflow instead of truncating.
+        assert_eq!(lines[101], "99");
+        assert_eq!(lines[122], "120");
+    }
+
+    #[test]
+    fn options_are_checked() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.host_mut().capture_output();
+        for prog in &[
+            "(print-table (list (dict :a 1)) :width 3)",
+            "(print-table (list (dict :a 1)) :max-width 0)",
+            "(print-table (list ^(1 2)))",
+            "(print-table (list ^(1 2)) :header '(\"a\") :columns '(\"b\"))",
+            "(print-table (list 1 2))",
+        ] {
+            assert!(syms.eval_source(prog).is_err(), "{}", prog);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use crate::access;
+use crate::host::Warning;
+use crate::records::{FileRecord, Record};
+use crate::stdlib::register_builtins;
+use crate::symbols::{Expr, LispResult, SymbolTable};
+use anyhow::{bail, ensure};
+use im::Vector;
+use itertools::Itertools;
+
+// Temporary files and directories which live as long as a block. The path
+// is made unique under the system temp dir, bound for the body, and removed
+// with everything under it once the body is done, whether it returned or
+// failed. Failing to remove it only warns, so the body's own result or error
+// is what the caller sees.
+
+struct TempOptions {
+    suffix: String,
+    keep: bool,
+}
+
+/// The name and options of a binding like (f :suffix ".json").
+fn parse_binding(
+    what: &str,
+    binding: &Expr,
+    symbol_table: &SymbolTable,
+) -> LispResult<(Expr, TempOptions)> {
+    let items = binding.get_list()?;
+    ensure!(
+        items.len() % 2 == 1 && items[0].is_symbol(),
+        "{} expects a binding like (path :suffix \".txt\"), but was given {}",
+        what,
+        binding
+    );
+    let mut opts = TempOptions {
+        suffix: String::new(),
+        keep: false,
+    };
+    for (key, value) in items.iter().skip(1).tuples() {
+        let value = value.eval(symbol_table)?;
+        match key.get_symbol_string()?.as_str() {
+            ":suffix" => opts.suffix = value.get_string()?,
+            ":keep" => opts.keep = value.get_bool()?,
+            other => bail!("{} has no option {}, it knows :suffix :keep", what, other),
+        }
+    }
+    ensure!(
+        !opts.suffix.contains(std::path::is_separator),
+        "{}'s :suffix can't contain path separators, but was given {:?}",
+        what,
+        opts.suffix
+    );
+    Ok((items[0].clone(), opts))
+}
+
+fn with_temp(
+    what: &str,
+    exprs: Vector<Expr>,
+    symbol_table: &SymbolTable,
+    dir: bool,
+) -> LispResult<Expr> {
+    let (name, opts) = parse_binding(what, &exprs[0], symbol_table)?;
+    let path = access::create_temp(symbol_table, what, &opts.suffix, dir)?;
+    let path_expr = Expr::String(path.to_string_lossy().into_owned());
+    let bound = if dir {
+        Ok(path_expr)
+    } else {
+        FileRecord::from_x7(Vector::unit(path_expr), symbol_table)
+    };
+    let res = bound.and_then(|value| {
+        let scope = symbol_table.with_locals(&[name], Vector::unit(value.clone()))?;
+        let res = exprs
+            .iter()
+            .skip(1)
+            .try_fold(Expr::Nil, |_, expr| expr.eval(&scope));
+        let closed = match &value {
+            Expr::Record(file) => file.close_in(symbol_table),
+            _ => Ok(Expr::Nil),
+        };
+        let res = res?;
+        closed?;
+        Ok(res)
+    });
+    if opts.keep {
+        return res;
+    }
+    let warned = match access::remove_temp(&path) {
+        Ok(()) => Ok(()),
+        Err(e) => symbol_table.warn(Warning {
+            kind: ":temp-cleanup".into(),
+            message: format!("{} left it behind. {}", what, e),
+            span: None,
+        }),
+    };
+    let res = res?;
+    warned?;
+    Ok(res)
+}
+
+/// (with-temp-dir (d [:suffix s] [:keep true]) body...)
+pub(crate) fn with_temp_dir(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    with_temp("with-temp-dir", exprs, symbol_table, true)
+}
+
+/// (with-temp-file (f [:suffix s] [:keep true]) body...)
+pub(crate) fn with_temp_file(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    with_temp("with-temp-file", exprs, symbol_table, false)
+}
+
+pub(crate) fn register(syms: &SymbolTable) {
+    register_builtins(
+        syms,
+        &[
+            (
+                "with-temp-dir",
+                "io",
+                2,
+                with_temp_dir,
+                false,
+                "Bind a new, empty directory under the system temp dir while evaluating a body, removing it
+and everything in it afterwards, even on error. Options follow the name: :suffix for the end of
+its name, and :keep true to leave it behind for debugging. Failing to remove it is a :temp-cleanup warning.
+Example:
+(with-temp-dir (d)
+  (write-file (path-join d \"config.toml\") \"debug = true\")
+  (load-config d))
+",
+            ),
+            (
+                "with-temp-file",
+                "io",
+                2,
+                with_temp_file,
+                false,
+                "Bind an open file record for a new, empty file under the system temp dir while evaluating
+a body, closing and removing it afterwards, even on error. Takes the same options as with-temp-dir.
+Example:
+(with-temp-file (f :suffix \".json\")
+  (.write f \"{}\")
+  (.read_to_string f))
+",
+            ),
+        ],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::Options;
+    use crate::records::Record;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use crate::symbols::{Expr, SymbolTable};
+    use std::path::Path;
+
+    #[test]
+    fn temp_dirs_are_removed_on_the_way_out() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let prog = "(with-temp-dir (d)
+          (write-file (path-join d \"sub\" \"data.txt\") \"x\" :create-dirs true)
+          d)";
+        let dir = syms.eval_source(prog).unwrap().get_string().unwrap();
+        assert!(dir.starts_with(&*std::env::temp_dir().to_string_lossy()));
+        assert!(!Path::new(&dir).exists());
+
+        let prog = "(def kept (atom nil))
+          (with-temp-dir (d) (.reset kept d) (write-file (path-join d \"a\") \"x\") (err \"boom\"))";
+        assert!(syms.eval_source(prog).is_err());
+        let dir = syms.eval_source("(.deref kept)").unwrap();
+        assert!(!Path::new(&dir.get_string().unwrap()).exists());
+
+        let prog =
+            "(with-temp-dir (a) (with-temp-dir (b) (list a b (path-exists? a) (path-exists? b))))";
+        let res = syms.eval_source(prog).unwrap().get_list().unwrap();
+        assert_ne!(res[0], res[1]);
+        assert_eq!((&res[2], &res[3]), (&Expr::Bool(true), &Expr::Bool(true)));
+        assert!(!Path::new(&res[0].get_string().unwrap()).exists());
+    }
+
+    /// The path of the file record in the atom `kept`.
+    fn kept_path(syms: &SymbolTable) -> String {
+        let file = syms
+            .eval_source("(.deref kept)")
+            .unwrap()
+            .get_record()
+            .unwrap();
+        assert!(file.is_closed());
+        let display = file.display();
+        display["File<".len()..display.len() - 1].into()
+    }
+
+    #[test]
+    fn temp_files_are_open_records() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let prog = "(def kept (atom nil))
+          (with-temp-file (f :suffix \".json\") (.reset kept f) (.write f \"{}\") (.read_to_string f))";
+        assert_eq!(syms.eval_source(prog).unwrap(), Expr::from("{}"));
+        let path = kept_path(&syms);
+        assert!(path.ends_with(".json"));
+        assert!(!Path::new(&path).exists());
+        assert!(syms.resource_report().open_files.is_empty());
+
+        let prog = "(with-temp-file (f) (.reset kept f) (err \"boom\"))";
+        assert!(syms.eval_source(prog).is_err());
+        assert!(!Path::new(&kept_path(&syms)).exists());
+    }
+
+    #[test]
+    fn options_and_policy() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let dir = syms
+            .eval_source("(with-temp-dir (d :keep true :suffix \"-debug\") d)")
+            .unwrap()
+            .get_string()
+            .unwrap();
+        assert!(dir.ends_with("-debug"));
+        assert!(Path::new(&dir).is_dir());
+        std::fs::remove_dir(&dir).unwrap();
+
+        for prog in &[
+            "(with-temp-dir (d :color \"red\") d)",
+            "(with-temp-dir (d :suffix \"/..\") d)",
+            "(with-temp-dir (d :keep) d)",
+            "(with-temp-file (\"f\") 1)",
+        ] {
+            assert!(syms.eval_source(prog).is_err(), "{}", prog);
+        }
+
+        syms.set_sandboxed(true);
+        let err = syms.eval_source("(with-temp-dir (d) d)").unwrap_err();
+        assert!(format!("{:#}", err).contains("with-temp-dir is not allowed in sandbox mode"));
+    }
+}
@@ -0,0 +1,159 @@
+use crate::symbols::{Expr, LispResult};
+use anyhow::{anyhow, bail};
+use itertools::Itertools;
+use std::collections::HashSet;
+
+// Templates for `template` and `fill` are quoted data with placeholders:
+//
+//   ?name     replaced by the value bound to :name
+//   ?@name    spliced into the surrounding list. The value must be a list.
+//
+// Placeholders are found anywhere in the structure, including nested lists
+// and dict keys and values. Substituted values are inserted as they are and
+// not searched for placeholders, so a template can be filled into another
+// template and filled later.
+
+/// The placeholder name of `?name` or `?@name`, and whether it splices.
+fn placeholder(expr: &Expr) -> Option<(&str, bool)> {
+    let s = match expr {
+        Expr::Symbol(s) => s,
+        _ => return None,
+    };
+    if let Some(name) = s.strip_prefix("?@") {
+        if !name.is_empty() {
+            return Some((name, true));
+        }
+    }
+    match s.strip_prefix('?') {
+        Some(name) if !name.is_empty() => Some((name, false)),
+        _ => None,
+    }
+}
+
+/// The name a binding key fills in: `:name` or `"name"`.
+fn binding_name(key: &Expr) -> LispResult<String> {
+    match key {
+        Expr::Symbol(s) if s.starts_with(':') && s.len() > 1 => Ok(s[1..].into()),
+        Expr::String(s) => Ok(s.clone()),
+        rest => bail!(
+            "Template bindings must be keywords or strings, but got {:?}",
+            rest
+        ),
+    }
+}
+
+struct Filler<'a> {
+    bindings: &'a im::HashMap<String, Expr>,
+    used: HashSet<String>,
+    // In order of first appearance, for stable errors.
+    unbound: Vec<String>,
+}
+
+impl<'a> Filler<'a> {
+    fn lookup(&mut self, name: &str) -> Option<Expr> {
+        match self.bindings.get(name) {
+            Some(value) => {
+                self.used.insert(name.into());
+                Some(value.clone())
+            }
+            None => {
+                if !self.unbound.iter().any(|n| n == name) {
+                    self.unbound.push(name.into());
+                }
+                None
+            }
+        }
+    }
+
+    fn fill(&mut self, expr: &Expr) -> LispResult<Expr> {
+        let res = match expr {
+            Expr::List(items) => Expr::List(self.fill_items(items)?),
+            Expr::Quote(items) => Expr::Quote(self.fill_items(items)?),
+            Expr::Tuple(items) => Expr::Tuple(self.fill_items(items)?),
+            Expr::Dict(dict) => {
+                let mut res = im::HashMap::new();
+                for (key, value) in dict.iter() {
+                    res.insert(self.fill(key)?, self.fill(value)?);
+                }
+                Expr::Dict(res)
+            }
+            _ => match placeholder(expr) {
+                Some((name, true)) => {
+                    bail!("?@{} can only be spliced into a list", name)
+                }
+                Some((name, false)) => self.lookup(name).unwrap_or_else(|| expr.clone()),
+                None => expr.clone(),
+            },
+        };
+        Ok(res)
+    }
+
+    fn fill_items(&mut self, items: &im::Vector<Expr>) -> LispResult<im::Vector<Expr>> {
+        let mut res = im::Vector::new();
+        for item in items.iter() {
+            match placeholder(item) {
+                Some((name, true)) => match self.lookup(name) {
+                    Some(Expr::List(l)) | Some(Expr::Quote(l)) | Some(Expr::Tuple(l)) => {
+                        res.append(l)
+                    }
+                    Some(Expr::Nil) => {}
+                    Some(rest) => bail!(
+                        "?@{} must be bound to a list, but got {} {:?}",
+                        name,
+                        rest.get_type_str(),
+                        rest
+                    ),
+                    None => res.push_back(item.clone()),
+                },
+                _ => res.push_back(self.fill(item)?),
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// Check that `body` can be used as a template, and return it.
+pub(crate) fn template(body: &Expr) -> LispResult<Expr> {
+    if let Some((name, true)) = placeholder(body) {
+        bail!("?@{} can only be spliced into a list", name);
+    }
+    Ok(body.clone())
+}
+
+/// Replace the placeholders in `template` with values from `bindings`.
+/// Every placeholder must be bound, and every binding used unless
+/// `allow_extra` is set.
+pub(crate) fn fill(template: &Expr, bindings: &Expr, allow_extra: bool) -> LispResult<Expr> {
+    let bindings = bindings
+        .get_dict()?
+        .iter()
+        .map(|(k, v)| Ok((binding_name(k)?, v.clone())))
+        .collect::<LispResult<im::HashMap<_, _>>>()?;
+    let mut filler = Filler {
+        bindings: &bindings,
+        used: HashSet::new(),
+        unbound: Vec::new(),
+    };
+    let res = filler.fill(template)?;
+    if !filler.unbound.is_empty() {
+        return Err(anyhow!(
+            "Unbound template placeholder(s): {}",
+            filler.unbound.iter().map(|n| format!("?{}", n)).join(", ")
+        ));
+    }
+    if !allow_extra {
+        let extra = bindings
+            .keys()
+            .filter(|name| !filler.used.contains(*name))
+            .sorted()
+            .map(|name| format!(":{}", name))
+            .join(", ");
+        if !extra.is_empty() {
+            bail!(
+                "Template binding(s) not used by any placeholder: {}. Pass `:allow-extra true` to allow them",
+                extra
+            );
+        }
+    }
+    Ok(res)
+}
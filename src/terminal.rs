@@ -0,0 +1,270 @@
+use crate::exact_len;
+use crate::symbols::{Expr, LispResult, SymbolTable};
+use im::Vector;
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// What the terminal x7 prints to can do, detected once when an interpreter
+// is made and kept on its host. Builtins which draw, like progress bars and
+// tables, render from this rather than probing for themselves, so output
+// which is redirected or captured falls back to plain text, and tests can
+// hand renderers any terminal they like.
+
+/// How many colors the terminal shows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorDepth {
+    /// No colors, e.g. output isn't a terminal, or NO_COLOR is set.
+    None,
+    /// The 16 standard ANSI colors.
+    Ansi16,
+    Ansi256,
+    /// 24 bit color.
+    TrueColor,
+}
+
+impl ColorDepth {
+    fn keyword(self) -> &'static str {
+        match self {
+            ColorDepth::None => ":none",
+            ColorDepth::Ansi16 => ":ansi16",
+            ColorDepth::Ansi256 => ":ansi256",
+            ColorDepth::TrueColor => ":truecolor",
+        }
+    }
+}
+
+/// The capabilities of the terminal, from `TerminalInfo::detect`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TerminalInfo {
+    pub stdout_tty: bool,
+    pub stderr_tty: bool,
+    /// Columns, when known.
+    pub width: Option<usize>,
+    /// Colors of stdout, which is `None` unless it's a terminal.
+    pub color: ColorDepth,
+    /// Whether the locale is UTF-8, so characters like … can be drawn.
+    pub unicode: bool,
+}
+
+impl Default for TerminalInfo {
+    /// Not a terminal, like a pipe or a file.
+    fn default() -> Self {
+        TerminalInfo {
+            stdout_tty: false,
+            stderr_tty: false,
+            width: None,
+            color: ColorDepth::None,
+            unicode: true,
+        }
+    }
+}
+
+/// Set by SIGWINCH, so widths are measured again after the window changes.
+static RESIZED: Lazy<Arc<AtomicBool>> = Lazy::new(|| {
+    let resized = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    {
+        // Without the handler widths are just never updated.
+        let _ = signal_hook::flag::register(signal_hook::SIGWINCH, resized.clone());
+    }
+    resized
+});
+
+fn tty_width() -> Option<usize> {
+    terminal_size::terminal_size().map(|(terminal_size::Width(w), _)| w as usize)
+}
+
+static DETECTED: Lazy<TerminalInfo> = Lazy::new(|| {
+    Lazy::force(&RESIZED);
+    TerminalInfo::probe()
+});
+
+impl TerminalInfo {
+    /// The capabilities of the process's stdout and stderr. They're probed
+    /// the first time this is called, and only widths change after that.
+    pub fn detect() -> TerminalInfo {
+        let mut info = DETECTED.clone();
+        info.refresh_width();
+        info
+    }
+
+    fn probe() -> TerminalInfo {
+        let stdout_tty = atty::is(atty::Stream::Stdout);
+        let stderr_tty = atty::is(atty::Stream::Stderr);
+        let width = if stdout_tty || stderr_tty {
+            tty_width()
+        } else {
+            None
+        };
+        TerminalInfo::from_env(|var| std::env::var(var).ok(), stdout_tty, stderr_tty, width)
+    }
+
+    /// Capabilities from environment variables, given what the streams are.
+    /// COLUMNS stands in for the width when it can't be measured.
+    fn from_env(
+        var: impl Fn(&str) -> Option<String>,
+        stdout_tty: bool,
+        stderr_tty: bool,
+        measured_width: Option<usize>,
+    ) -> TerminalInfo {
+        let width = measured_width
+            .or_else(|| var("COLUMNS")?.trim().parse().ok())
+            .filter(|w| *w > 0);
+        let term = var("TERM").unwrap_or_default();
+        let color = if !stdout_tty || var("NO_COLOR").is_some() || term == "dumb" {
+            ColorDepth::None
+        } else if matches!(
+            var("COLORTERM").as_deref(),
+            Some("truecolor") | Some("24bit")
+        ) {
+            ColorDepth::TrueColor
+        } else if term.contains("256color") {
+            ColorDepth::Ansi256
+        } else {
+            ColorDepth::Ansi16
+        };
+        // The first of these which is set decides the locale.
+        let locale = ["LC_ALL", "LC_CTYPE", "LANG"]
+            .iter()
+            .find_map(|v| var(v).filter(|l| !l.is_empty()));
+        let unicode = match locale {
+            Some(locale) => {
+                let locale = locale.to_lowercase();
+                locale.contains("utf-8") || locale.contains("utf8")
+            }
+            // Windows terminals don't say, but handle UTF-8.
+            None => cfg!(windows),
+        };
+        TerminalInfo {
+            stdout_tty,
+            stderr_tty,
+            width,
+            color,
+            unicode,
+        }
+    }
+
+    /// Measure the width again if the window changed size since last time.
+    pub(crate) fn refresh_width(&mut self) {
+        if (self.stdout_tty || self.stderr_tty) && RESIZED.swap(false, Ordering::Relaxed) {
+            if let Some(width) = tty_width() {
+                self.width = Some(width);
+            }
+        }
+    }
+
+    /// These capabilities for output that's captured rather than printed.
+    pub(crate) fn captured(&self) -> TerminalInfo {
+        TerminalInfo {
+            stdout_tty: false,
+            stderr_tty: false,
+            color: ColorDepth::None,
+            ..self.clone()
+        }
+    }
+
+    /// `…` if the terminal can draw it, otherwise `...`.
+    pub(crate) fn ellipsis(&self) -> &'static str {
+        if self.unicode {
+            "…"
+        } else {
+            "..."
+        }
+    }
+}
+
+/// (terminal-info)
+pub(crate) fn terminal_info(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 0);
+    let info = symbol_table.host_mut().terminal();
+    let mut dict = im::HashMap::new();
+    let mut set = |key: &str, value: Expr| dict.insert(Expr::Symbol(key.into()), value);
+    set(":stdout-tty", Expr::Bool(info.stdout_tty));
+    set(":stderr-tty", Expr::Bool(info.stderr_tty));
+    set(
+        ":width",
+        info.width.map_or(Expr::Nil, |w| Expr::from(w as i64)),
+    );
+    set(":color", Expr::Symbol(info.color.keyword().into()));
+    set(":unicode", Expr::Bool(info.unicode));
+    Ok(Expr::Dict(dict))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn info(env: &[(&str, &str)], tty: bool, measured: Option<usize>) -> TerminalInfo {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        TerminalInfo::from_env(|var| env.get(var).cloned(), tty, tty, measured)
+    }
+
+    #[test]
+    fn capabilities_come_from_the_environment() {
+        let term = info(
+            &[("TERM", "xterm-256color"), ("LANG", "en_CA.UTF-8")],
+            true,
+            Some(120),
+        );
+        assert_eq!(term.color, ColorDepth::Ansi256);
+        assert_eq!(term.width, Some(120));
+        assert!(term.unicode);
+
+        let term = info(&[("COLORTERM", "truecolor"), ("COLUMNS", "90")], true, None);
+        assert_eq!(term.color, ColorDepth::TrueColor);
+        assert_eq!(term.width, Some(90));
+
+        let term = info(&[("NO_COLOR", "1"), ("TERM", "xterm-256color")], true, None);
+        assert_eq!(term.color, ColorDepth::None);
+        assert_eq!(term.width, None);
+        assert_eq!(
+            info(&[("TERM", "dumb")], true, None).color,
+            ColorDepth::None
+        );
+        assert_eq!(
+            info(&[("TERM", "xterm")], true, None).color,
+            ColorDepth::Ansi16
+        );
+
+        // Redirected output has no colors, whatever the environment says.
+        let term = info(&[("COLORTERM", "truecolor"), ("COLUMNS", "x")], false, None);
+        assert_eq!(term.color, ColorDepth::None);
+        assert_eq!(term.width, None);
+
+        // LC_ALL wins over LANG.
+        let term = info(&[("LC_ALL", "C"), ("LANG", "en_CA.utf8")], true, None);
+        assert!(!term.unicode);
+        assert_eq!(term.ellipsis(), "...");
+    }
+
+    #[test]
+    fn scripts_see_the_terminal() {
+        use crate::cli::Options;
+        use crate::stdlib::create_stdlib_symbol_table;
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.set_terminal_info(TerminalInfo {
+            stdout_tty: true,
+            stderr_tty: false,
+            width: Some(100),
+            color: ColorDepth::Ansi256,
+            unicode: false,
+        });
+        let eval = |prog: &str| syms.eval_source(prog).unwrap();
+        assert_eq!(eval("(get (terminal-info) :width)"), Expr::from(100));
+        assert_eq!(
+            eval("(get (terminal-info) :color)"),
+            Expr::Symbol(":ansi256".into())
+        );
+        assert_eq!(eval("(get (terminal-info) :unicode)"), Expr::Bool(false));
+
+        // Captured output isn't a terminal.
+        syms.host_mut().capture_output();
+        assert_eq!(eval("(get (terminal-info) :stdout-tty)"), Expr::Bool(false));
+        assert_eq!(eval("(get (terminal-info) :width)"), Expr::from(100));
+    }
+}
@@ -0,0 +1,467 @@
+use crate::exact_len;
+use crate::iterators::{IterType, LazyIter};
+#[cfg(feature = "regex")]
+use crate::records::{Record, RecordDoc, RecordType};
+#[cfg(feature = "regex")]
+use crate::stdlib::register_builtins;
+use crate::symbols::{next_object_id, Expr, LispResult, SymbolTable};
+#[cfg(feature = "regex")]
+use crate::{record, unknown_method};
+use anyhow::{anyhow, bail, ensure};
+use im::Vector;
+#[cfg(feature = "regex")]
+use regex::Regex;
+use std::fmt;
+
+// Line-oriented helpers for munging text the way one would with awk: split
+// input into lines and fields, keep the lines matching a pattern, and
+// substitute within them. Patterns are plain strings, matched literally, or
+// records made with `regex`. grep and grep-v over a lazy sequence, like the
+// lines of `lines-gz`, are lazy too, so pipelines over huge logs stream.
+
+/// A compiled regular expression, from `(regex "pattern")`.
+#[cfg(feature = "regex")]
+#[derive(Clone, Debug)]
+pub(crate) struct RegexRecord {
+    regex: Regex,
+    id: u64,
+}
+
+#[cfg(feature = "regex")]
+impl RegexRecord {
+    pub(crate) fn from_x7(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+        exact_len!(exprs, 1);
+        let regex = symbol_table.compile_regex(&exprs[0].get_string()?)?;
+        record!(RegexRecord {
+            regex,
+            id: next_object_id()
+        })
+    }
+
+    fn is_match(&self, args: Vector<Expr>) -> LispResult<Expr> {
+        exact_len!(args, 1);
+        Ok(Expr::Bool(self.regex.is_match(&args[0].get_string()?)))
+    }
+}
+
+#[cfg(feature = "regex")]
+impl Record for RegexRecord {
+    fn call_method(&self, sym: &str, args: Vector<Expr>) -> LispResult<Expr> {
+        match sym {
+            "matches?" => self.is_match(args),
+            _ => unknown_method!(self, sym),
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        "Regex"
+    }
+
+    fn display(&self) -> String {
+        format!("Regex<{}>", self.regex.as_str())
+    }
+
+    fn debug(&self) -> String {
+        self.display()
+    }
+
+    fn clone(&self) -> RecordType {
+        Box::new(Clone::clone(self))
+    }
+
+    fn methods(&self) -> Vec<&'static str> {
+        RegexRecord::method_doc().iter().map(|(l, _)| *l).collect()
+    }
+
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn as_any(&self) -> Option<&dyn std::any::Any> {
+        Some(self)
+    }
+}
+
+#[cfg(feature = "regex")]
+impl RecordDoc for RegexRecord {
+    fn name() -> &'static str {
+        "Regex"
+    }
+
+    fn type_doc() -> &'static str {
+        "A regular expression, for grep, grep-v, fields, sub, and gsub.
+x7 strings can't hold backslashes, so use classes like [0-9] and [[:space:]].
+Example:
+(def number (regex \"[0-9]+\"))
+(.matches? number \"abc 123\") ; true
+"
+    }
+
+    fn method_doc() -> &'static [(&'static str, &'static str)] {
+        &[(
+            "matches?",
+            "Whether the regex matches anywhere in the string.
+Example:
+(.matches? (regex \"^a\") \"abc\") ; true
+",
+        )]
+    }
+}
+
+/// What grep and friends search for.
+#[derive(Clone, Debug)]
+enum Pattern {
+    Literal(String),
+    #[cfg(feature = "regex")]
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn from_expr(what: &str, expr: &Expr) -> LispResult<Pattern> {
+        if let Expr::String(s) = expr {
+            return Ok(Pattern::Literal(s.clone()));
+        }
+        #[cfg(feature = "regex")]
+        {
+            let regex = match expr {
+                Expr::Record(r) => r
+                    .as_any()
+                    .and_then(|any| any.downcast_ref::<RegexRecord>())
+                    .map(|r| r.regex.clone()),
+                _ => None,
+            };
+            if let Some(regex) = regex {
+                return Ok(Pattern::Regex(regex));
+            }
+        }
+        bail!(
+            "{} expects a string or a regex as its pattern, but was given {}",
+            what,
+            expr
+        )
+    }
+
+    fn is_match(&self, line: &str) -> bool {
+        match self {
+            Pattern::Literal(s) => line.contains(s.as_str()),
+            #[cfg(feature = "regex")]
+            Pattern::Regex(r) => r.is_match(line),
+        }
+    }
+
+    fn replace(&self, line: &str, replacement: &str, limit: usize) -> String {
+        match self {
+            // 0 replaces every match, like Regex::replacen.
+            Pattern::Literal(s) if limit == 0 => line.replace(s.as_str(), replacement),
+            Pattern::Literal(s) => line.replacen(s.as_str(), replacement, limit),
+            #[cfg(feature = "regex")]
+            Pattern::Regex(r) => r.replacen(line, limit, replacement).into_owned(),
+        }
+    }
+}
+
+/// `s` split on any of \n, \r\n, and \r. A final line ending doesn't start
+/// another line.
+fn split_lines(s: &str) -> Vec<&str> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    let mut chars = s.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\n' => {}
+            '\r' => {
+                if let Some((_, '\n')) = chars.peek() {
+                    chars.next();
+                }
+            }
+            _ => continue,
+        }
+        lines.push(&s[start..i]);
+        start = match chars.peek() {
+            Some((next, _)) => *next,
+            None => s.len(),
+        };
+    }
+    if start < s.len() {
+        lines.push(&s[start..]);
+    }
+    lines
+}
+
+fn strings<'a>(parts: impl Iterator<Item = &'a str>) -> Expr {
+    Expr::List(parts.map(|p| Expr::String(p.into())).collect())
+}
+
+/// (lines s)
+pub(crate) fn lines(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    Ok(strings(split_lines(&exprs[0].get_string()?).into_iter()))
+}
+
+/// (fields line) or (fields line sep)
+pub(crate) fn fields(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1, 2);
+    let line = exprs[0].get_string()?;
+    let sep = match exprs.get(1) {
+        Some(sep) => Pattern::from_expr("fields", sep)?,
+        None => return Ok(strings(line.split_whitespace())),
+    };
+    match sep {
+        Pattern::Literal(sep) => {
+            ensure!(!sep.is_empty(), "fields can't split on an empty separator");
+            Ok(strings(line.split(sep.as_str())))
+        }
+        #[cfg(feature = "regex")]
+        Pattern::Regex(sep) => Ok(strings(sep.split(&line))),
+    }
+}
+
+/// (col n line)
+pub(crate) fn col(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let n = exprs[0].get_usize()?;
+    let line = exprs[1].get_string()?;
+    // Like awk's $0, column 0 is the whole line.
+    if n == 0 {
+        return Ok(Expr::String(line));
+    }
+    Ok(line
+        .split_whitespace()
+        .nth(n - 1)
+        .map_or(Expr::Nil, |field| Expr::String(field.into())))
+}
+
+/// The lines of a lazy sequence which do, or don't, match a pattern.
+#[derive(Clone)]
+struct Grep {
+    inner: IterType,
+    pattern: Pattern,
+    keep: bool,
+    id: u64,
+}
+
+impl Grep {
+    fn matches(&self, line: &Expr) -> LispResult<bool> {
+        Ok(self.pattern.is_match(&line.get_string()?) == self.keep)
+    }
+}
+
+impl LazyIter for Grep {
+    fn next(&self, symbol_table: &SymbolTable) -> Option<LispResult<Expr>> {
+        loop {
+            let line = match self.inner.next(symbol_table)? {
+                Ok(line) => line,
+                err => return Some(err),
+            };
+            match self.matches(&line) {
+                Ok(true) => return Some(Ok(line)),
+                Ok(false) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        if self.keep {
+            "Grep"
+        } else {
+            "GrepV"
+        }
+    }
+
+    fn clone(&self) -> IterType {
+        Box::new(Clone::clone(self))
+    }
+
+    fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl fmt::Debug for Grep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}<{}>", self.name(), self.inner)
+    }
+}
+
+impl fmt::Display for Grep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+fn grep_lines(what: &str, exprs: Vector<Expr>, keep: bool) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let pattern = Pattern::from_expr(what, &exprs[0])?;
+    if let Ok(inner) = exprs[1].get_iterator() {
+        return Ok(Expr::LazyIter(Box::new(Grep {
+            inner,
+            pattern,
+            keep,
+            id: next_object_id(),
+        })));
+    }
+    let mut res = Vector::new();
+    for line in exprs[1].get_list()? {
+        if pattern.is_match(&line.get_string()?) == keep {
+            res.push_back(line);
+        }
+    }
+    Ok(Expr::List(res))
+}
+
+/// (grep pat lines)
+pub(crate) fn grep(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    grep_lines("grep", exprs, true)
+}
+
+/// (grep-v pat lines)
+pub(crate) fn grep_v(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    grep_lines("grep-v", exprs, false)
+}
+
+fn substitute(what: &str, exprs: Vector<Expr>, limit: usize) -> LispResult<Expr> {
+    exact_len!(exprs, 3);
+    let pattern = Pattern::from_expr(what, &exprs[0])?;
+    let replacement = exprs[1].get_string()?;
+    let line = exprs[2].get_string()?;
+    Ok(Expr::String(pattern.replace(&line, &replacement, limit)))
+}
+
+/// (sub pat repl line)
+pub(crate) fn sub(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    substitute("sub", exprs, 1)
+}
+
+/// (gsub pat repl line)
+pub(crate) fn gsub(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    substitute("gsub", exprs, 0)
+}
+
+#[cfg(feature = "regex")]
+pub(crate) fn register(syms: &SymbolTable) {
+    register_builtins(
+        syms,
+        &[(
+            "regex",
+            "strings",
+            1,
+            RegexRecord::from_x7,
+            true,
+            "Compile a regular expression, for grep, grep-v, fields, sub, and gsub.
+x7 strings can't hold backslashes, so use classes like [0-9] and [[:space:]].
+Example:
+(def number (regex \"[0-9]+\"))
+(.matches? number \"abc 123\") ; true
+",
+        )],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_lines;
+    use crate::cli::Options;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use crate::symbols::Expr;
+
+    fn strings(parts: &[&str]) -> Expr {
+        Expr::List(parts.iter().map(|p| Expr::from(*p)).collect())
+    }
+
+    #[test]
+    fn lines_split_on_any_newline() {
+        assert_eq!(split_lines("a\r\nb\nc\rd\r\n"), vec!["a", "b", "c", "d"]);
+        assert_eq!(split_lines("a\n\nb"), vec!["a", "", "b"]);
+        assert_eq!(split_lines("\r\n"), vec![""]);
+        assert!(split_lines("").is_empty());
+
+        let syms = create_stdlib_symbol_table(&Options::default());
+        assert_eq!(
+            syms.eval_source("(lines \"GET /\r\nPOST /login\r\n\")")
+                .unwrap(),
+            strings(&["GET /", "POST /login"])
+        );
+    }
+
+    #[test]
+    fn fields_and_columns() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let eval = |prog: &str| syms.eval_source(prog).unwrap();
+        assert_eq!(
+            eval("(fields \"  GET\t/index  200 \")"),
+            strings(&["GET", "/index", "200"])
+        );
+        assert_eq!(eval("(fields \"   \")"), Expr::List(Default::default()));
+        assert_eq!(eval("(fields \"a,,b\" \",\")"), strings(&["a", "", "b"]));
+        #[cfg(feature = "regex")]
+        assert_eq!(
+            eval("(fields \"a, b ,c\" (regex \" *, *\"))"),
+            strings(&["a", "b", "c"])
+        );
+        assert_eq!(eval("(col 2 \"  GET /index 200\")"), Expr::from("/index"));
+        assert_eq!(eval("(col 4 \"GET /index 200\")"), Expr::Nil);
+        assert_eq!(eval("(col 0 \" GET \")"), Expr::from(" GET "));
+        assert!(syms.eval_source("(fields \"a\" \"\")").is_err());
+        assert!(syms.eval_source("(col -1 \"a\")").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "regex")]
+    fn patterns_are_literal_unless_regexes() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let eval = |prog: &str| syms.eval_source(prog).unwrap();
+        let lines = "'(\"a.c\" \"abc\" \"xyz\")";
+        assert_eq!(
+            eval(&format!("(grep \"a.c\" {})", lines)),
+            strings(&["a.c"])
+        );
+        assert_eq!(
+            eval(&format!("(grep (regex \"a.c\") {})", lines)),
+            strings(&["a.c", "abc"])
+        );
+        assert_eq!(
+            eval(&format!("(grep-v (regex \"^a\") {})", lines)),
+            strings(&["xyz"])
+        );
+        assert_eq!(eval("(sub \".\" \"!\" \"a.b.c\")"), Expr::from("a!b.c"));
+        assert_eq!(eval("(gsub \".\" \"!\" \"a.b.c\")"), Expr::from("a!b!c"));
+        assert_eq!(
+            eval("(gsub (regex \"[0-9]+\") \"N\" \"id 12 of 345\")"),
+            Expr::from("id N of N")
+        );
+        assert_eq!(
+            eval("(sub (regex \"([a-z]+)@([a-z]+)\") \"$2 at $1\" \"mail bob@home now\")"),
+            Expr::from("mail home at bob now")
+        );
+        // Replacements for literal patterns are taken as is.
+        assert_eq!(eval("(sub \"@\" \"$1\" \"a@b\")"), Expr::from("a$1b"));
+        assert!(syms.eval_source("(grep 1 '(\"1\"))").is_err());
+        assert!(syms.eval_source("(regex \"(\")").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "regex")]
+    fn regexes_come_from_the_cache() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let prog = "(regex \"[0-9]+\") (regex \"[0-9]+\") (regex \"[0-9]+\")
+                    (get (cache-stats) \"regex\")";
+        let stats = syms.eval_source(prog).unwrap();
+        let stat = |name: &str| stats.get_dict().unwrap()[&Expr::from(name)].clone();
+        assert_eq!(stat("hits"), Expr::from(2));
+        assert_eq!(stat("misses"), Expr::from(1));
+        assert_eq!(stat("size"), Expr::from(1));
+    }
+
+    #[test]
+    #[cfg(feature = "regex")]
+    fn grep_streams_lazy_sequences() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        // An endless sequence, so this only finishes if grep is lazy.
+        let prog = "(doall (take 3 (grep-v \"1\" (grep (regex \"^[0-9]+7$\") (map str (range))))))";
+        assert_eq!(
+            syms.eval_source(prog).unwrap(),
+            strings(&["27", "37", "47"])
+        );
+    }
+}
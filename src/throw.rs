@@ -0,0 +1,131 @@
+use crate::exact_len;
+use crate::records::Record;
+use crate::symbols::{Expr, LispResult, ProgramError, SymbolTable};
+use anyhow::{anyhow, bail};
+use im::Vector;
+
+// Errors carrying any x7 value, made with (throw value). When one goes
+// uncaught, what's printed comes from the renderer installed with
+// `set-error-renderer!`, else from a `display` method of a record thrown,
+// else from the value's printed form. A renderer which fails falls back to
+// the printed form, noting why.
+
+/// (throw value)
+pub(crate) fn throw(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    Err(anyhow!(ProgramError::UserThrown(exprs[0].clone())))
+}
+
+/// (set-error-renderer! f)
+pub(crate) fn set_error_renderer(
+    exprs: Vector<Expr>,
+    symbol_table: &SymbolTable,
+) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let renderer = match &exprs[0] {
+        Expr::Nil => None,
+        Expr::Function(_) => Some(exprs[0].clone()),
+        other => bail!(
+            "set-error-renderer! expects a function, or nil to remove it, but was given {}",
+            other
+        ),
+    };
+    symbol_table.host_mut().set_error_renderer(renderer);
+    Ok(Expr::Nil)
+}
+
+fn render(value: &Expr, symbol_table: &SymbolTable) -> LispResult<String> {
+    // Don't hold the host while the renderer runs.
+    let renderer = symbol_table.host().error_renderer();
+    let rendered = match (renderer, value) {
+        (Some(renderer), _) => {
+            renderer.call_with_values(Vector::unit(value.clone()), symbol_table)?
+        }
+        (None, Expr::Record(r)) if r.methods().contains(&"display") => {
+            r.call_method_in("display", Vector::new(), symbol_table)?
+        }
+        _ => return Ok(format!("{:?}", value)),
+    };
+    match rendered {
+        Expr::String(s) => Ok(s),
+        other => bail!("it returned {:?}, not a string", other),
+    }
+}
+
+/// The first line of an uncaught error's report: the innermost message,
+/// with thrown values rendered when there's an interpreter to do it.
+pub(crate) fn headline(err: &anyhow::Error, symbol_table: Option<&SymbolTable>) -> String {
+    let root = err.root_cause();
+    let value = match root.downcast_ref::<ProgramError>() {
+        Some(ProgramError::UserThrown(value)) => value,
+        _ => return root.to_string(),
+    };
+    match symbol_table.map(|symbol_table| render(value, symbol_table)) {
+        Some(Ok(rendered)) => rendered,
+        Some(Err(e)) => format!("{:?} (rendering it failed: {:#})", value, e),
+        None => format!("{:?}", value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::{format_error, Options};
+    use crate::stdlib::create_stdlib_symbol_table;
+    use crate::symbols::SymbolTable;
+
+    fn uncaught(syms: &SymbolTable, prog: &str) -> String {
+        let err = syms.eval_source(prog).unwrap_err();
+        format_error(&err, Some(syms))
+    }
+
+    #[test]
+    fn thrown_values_print_as_themselves() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let report = uncaught(&syms, "(throw '(:validation :email))");
+        assert!(
+            report.starts_with("Error: (:validation :email)\n\nStacktrace:\n"),
+            "{}",
+            report
+        );
+        // Errors which weren't thrown are untouched.
+        assert!(uncaught(&syms, "(err \"boom\")").starts_with("Error: boom\n"));
+    }
+
+    #[test]
+    fn renderers_describe_thrown_values() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.eval_source(
+            "(set-error-renderer! (fn (e) (str \"invalid \" (get e :field))))
+             (defrecord NotFound (path)
+               (defn display (self) (str \"no such file \" (.path self))))",
+        )
+        .unwrap();
+        let prog = "(throw (dict :kind :validation :field :email))";
+        assert!(uncaught(&syms, prog).starts_with("Error: invalid :email\n"));
+        // Thrown values are passed as they are, not evaluated again.
+        let prog = "(throw (dict :field '(1 2)))";
+        let report = uncaught(&syms, prog);
+        assert!(report.starts_with("Error: invalid (1 2)\n"), "{}", report);
+        let prog = "(throw (dict :field (head '(zed))))";
+        let report = uncaught(&syms, prog);
+        assert!(report.starts_with("Error: invalid zed\n"), "{}", report);
+
+        // A renderer which fails falls back to the value, saying why.
+        let report = uncaught(&syms, "(throw 5)");
+        assert!(
+            report.starts_with("Error: 5 (rendering it failed: "),
+            "{}",
+            report
+        );
+
+        // Without a renderer, records are asked to display themselves.
+        syms.eval_source("(set-error-renderer! nil)").unwrap();
+        let report = uncaught(&syms, "(throw (NotFound \"a.txt\"))");
+        assert!(
+            report.starts_with("Error: no such file a.txt\n"),
+            "{}",
+            report
+        );
+        assert!(syms.eval_source("(set-error-renderer! 1)").is_err());
+    }
+}
@@ -0,0 +1,345 @@
+use crate::exact_len;
+use crate::format::round;
+use crate::symbols::{Expr, LispResult, Num, SymbolTable};
+use anyhow::{anyhow, bail, ensure};
+use bigdecimal::{ToPrimitive, Zero};
+use im::Vector;
+use itertools::Itertools;
+
+// Durations and sizes written the way people write them: "5m30s", "300ms",
+// "1.5GiB". A duration is any number of <number><unit> terms, each unit at
+// most once, optionally separated by spaces. A size is a single term. Units
+// are looked up in the tables below, and a term without one is an error
+// rather than a guess.
+
+/// Duration units and how many nanoseconds each is.
+const DURATION_UNITS: &[(&str, u64)] = &[
+    ("ns", 1),
+    ("us", 1_000),
+    ("µs", 1_000),
+    ("ms", 1_000_000),
+    ("s", 1_000_000_000),
+    ("m", 60 * 1_000_000_000),
+    ("h", 3_600 * 1_000_000_000),
+    ("d", 86_400 * 1_000_000_000),
+    ("w", 604_800 * 1_000_000_000),
+];
+
+/// Size units, as printed, and how many bytes each is. Read case-insensitively.
+const SIZE_UNITS: &[(&str, u64)] = &[
+    ("B", 1),
+    ("KB", 1_000),
+    ("MB", 1_000_000),
+    ("GB", 1_000_000_000),
+    ("TB", 1_000_000_000_000),
+    ("PB", 1_000_000_000_000_000),
+    ("KiB", 1 << 10),
+    ("MiB", 1 << 20),
+    ("GiB", 1 << 30),
+    ("TiB", 1 << 40),
+    ("PiB", 1 << 50),
+];
+
+/// One <number><unit> term at the start of `s`, and what follows it.
+fn term<'a>(what: &str, s: &'a str, whole: &str) -> LispResult<(Num, &'a str, &'a str)> {
+    let digits = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, rest) = s.split_at(digits);
+    let number: Num = number
+        .parse()
+        .map_err(|_| anyhow!("{} doesn't understand {:?} in {:?}", what, s, whole))?;
+    let rest = rest.trim_start();
+    let unit_len = rest
+        .find(|c: char| c.is_ascii_digit() || c == '.' || c.is_whitespace())
+        .unwrap_or(rest.len());
+    let (unit, rest) = rest.split_at(unit_len);
+    ensure!(
+        !unit.is_empty(),
+        "{} needs a unit after {} in {:?}",
+        what,
+        number,
+        whole
+    );
+    Ok((number, unit, rest.trim_start()))
+}
+
+/// Parse a duration like "1h 05m 03s" into seconds.
+pub(crate) fn duration_seconds(s: &str) -> LispResult<Num> {
+    let mut rest = s.trim();
+    ensure!(!rest.is_empty(), "parse-duration was given an empty string");
+    let mut nanos = Num::zero();
+    let mut seen = Vec::new();
+    while !rest.is_empty() {
+        let (number, unit, after) = term("parse-duration", rest, s)?;
+        let factor = match DURATION_UNITS.iter().find(|(name, _)| *name == unit) {
+            Some((_, factor)) => *factor,
+            None => bail!(
+                "parse-duration doesn't know the unit {:?} in {:?}, it knows {}",
+                unit,
+                s,
+                DURATION_UNITS.iter().map(|(name, _)| name).join(" ")
+            ),
+        };
+        ensure!(
+            !seen.contains(&unit),
+            "parse-duration was given the unit {:?} twice in {:?}",
+            unit,
+            s
+        );
+        seen.push(unit);
+        nanos += number * Num::from(factor);
+        rest = after;
+    }
+    Ok(nanos / Num::from(1_000_000_000u64))
+}
+
+/// Parse a size like "1.5GiB" into a whole number of bytes.
+pub(crate) fn size_bytes(s: &str) -> LispResult<Num> {
+    let trimmed = s.trim();
+    ensure!(!trimmed.is_empty(), "parse-size was given an empty string");
+    let (number, unit, rest) = term("parse-size", trimmed, s)?;
+    ensure!(
+        rest.is_empty(),
+        "parse-size doesn't understand {:?} in {:?}, it takes one size",
+        rest,
+        s
+    );
+    let factor = match SIZE_UNITS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+    {
+        Some((_, factor)) => *factor,
+        None => bail!(
+            "parse-size doesn't know the unit {:?} in {:?}, it knows {}",
+            unit,
+            s,
+            SIZE_UNITS.iter().map(|(name, _)| name).join(" ")
+        ),
+    };
+    let bytes = number * Num::from(factor);
+    ensure!(
+        bytes.with_scale(0) == bytes,
+        "parse-size was given {:?}, which isn't a whole number of bytes",
+        s
+    );
+    Ok(bytes.with_scale(0))
+}
+
+/// `n` printed without trailing zeros after the point.
+fn trimmed(n: &Num) -> String {
+    let s = n.to_string();
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.').into()
+    } else {
+        s
+    }
+}
+
+/// Format `secs` like "1h 05m 03s", or "300ms" under a second.
+pub(crate) fn format_seconds(secs: &Num) -> LispResult<String> {
+    ensure!(
+        *secs >= Num::zero(),
+        "format-duration was given {} seconds, which is negative",
+        secs
+    );
+    if secs.is_zero() {
+        return Ok("0s".into());
+    }
+    if *secs < Num::from(1) {
+        let (scale, unit) = if *secs >= Num::from(1) / Num::from(1_000) {
+            (1_000, "ms")
+        } else if *secs >= Num::from(1) / Num::from(1_000_000) {
+            (1_000_000, "µs")
+        } else {
+            (1_000_000_000, "ns")
+        };
+        let value = round(&(secs.clone() * Num::from(scale)), 3);
+        return Ok(format!("{}{}", trimmed(&value), unit));
+    }
+    let secs = round(secs, 3);
+    let whole = secs.with_scale(0);
+    let fraction = trimmed(&(&secs - &whole));
+    let mut left = whole.to_u64().ok_or_else(|| {
+        anyhow!(
+            "format-duration was given {} seconds, which is too long",
+            secs
+        )
+    })?;
+    let mut parts = Vec::new();
+    for (unit, size) in &[("d", 86_400u64), ("h", 3_600), ("m", 60), ("s", 1)] {
+        let count = left / size;
+        left %= size;
+        // Show units from the first one needed down, all but the first padded.
+        if parts.is_empty() && count == 0 && *unit != "s" {
+            continue;
+        }
+        let fraction = if *unit == "s" {
+            fraction.trim_start_matches('0')
+        } else {
+            ""
+        };
+        parts.push(if parts.is_empty() {
+            format!("{}{}{}", count, fraction, unit)
+        } else {
+            format!("{:02}{}{}", count, fraction, unit)
+        });
+    }
+    Ok(parts.join(" "))
+}
+
+/// Format `bytes` in the largest unit it has at least one of, to two decimals.
+pub(crate) fn format_bytes(bytes: &Num, binary: bool) -> LispResult<String> {
+    ensure!(
+        *bytes >= Num::zero() && bytes.with_scale(0) == *bytes,
+        "format-size takes a whole number of bytes, but was given {}",
+        bytes
+    );
+    let units: Vec<(&str, u64)> = SIZE_UNITS
+        .iter()
+        .filter(|(name, factor)| *factor == 1 || name.contains('i') == binary)
+        .copied()
+        .collect();
+    let mut chosen = 0;
+    for (i, (_, factor)) in units.iter().enumerate() {
+        if *bytes >= Num::from(*factor) {
+            chosen = i;
+        }
+    }
+    let value = |i: usize| round(&(bytes.clone() / Num::from(units[i].1)), 2);
+    // Rounding can carry into the next unit, like 999999 bytes to 1000.00 KB.
+    if chosen + 1 < units.len() && value(chosen) >= Num::from(units[chosen + 1].1 / units[chosen].1)
+    {
+        chosen += 1;
+    }
+    if chosen == 0 {
+        return Ok(format!("{} B", trimmed(bytes)));
+    }
+    Ok(format!("{} {}", value(chosen), units[chosen].0))
+}
+
+/// (parse-duration "5m30s")
+pub(crate) fn parse_duration(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    Ok(Expr::Num(duration_seconds(&exprs[0].get_string()?)?))
+}
+
+/// (format-duration secs)
+pub(crate) fn format_duration(
+    exprs: Vector<Expr>,
+    _symbol_table: &SymbolTable,
+) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    Ok(Expr::String(format_seconds(&exprs[0].get_num()?)?))
+}
+
+/// (parse-size "1.5GiB")
+pub(crate) fn parse_size(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    Ok(Expr::Num(size_bytes(&exprs[0].get_string()?)?))
+}
+
+/// (format-size bytes [:binary true])
+pub(crate) fn format_size(exprs: Vector<Expr>, _symbol_table: &SymbolTable) -> LispResult<Expr> {
+    let mut binary = false;
+    let options = exprs.clone().slice(1..);
+    ensure!(
+        options.len().is_multiple_of(2),
+        "format-size takes options as :option value pairs, but was given {}",
+        Expr::List(options)
+    );
+    for (key, value) in options.iter().tuples() {
+        match key.get_symbol_string()?.as_str() {
+            ":binary" => binary = value.get_bool()?,
+            other => bail!("format-size has no option {}, it knows :binary", other),
+        }
+    }
+    Ok(Expr::String(format_bytes(&exprs[0].get_num()?, binary)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(s: &str) -> Num {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn every_unit_parses() {
+        for (unit, nanos) in DURATION_UNITS {
+            let secs = duration_seconds(&format!("2{}", unit)).unwrap();
+            assert_eq!(
+                secs,
+                Num::from(2 * nanos) / Num::from(1_000_000_000u64),
+                "{}",
+                unit
+            );
+        }
+        for (unit, bytes) in SIZE_UNITS {
+            assert_eq!(
+                size_bytes(&format!("3{}", unit)).unwrap(),
+                Num::from(3 * bytes)
+            );
+            let lower = format!("3 {}", unit.to_lowercase());
+            assert_eq!(
+                size_bytes(&lower).unwrap(),
+                Num::from(3 * bytes),
+                "{}",
+                lower
+            );
+        }
+    }
+
+    #[test]
+    fn combined_and_fractional_terms() {
+        assert_eq!(duration_seconds("5m30s").unwrap(), num("330"));
+        assert_eq!(duration_seconds("1h 05m 03s").unwrap(), num("3903"));
+        assert_eq!(duration_seconds("1.5h").unwrap(), num("5400"));
+        assert_eq!(duration_seconds("300ms").unwrap(), num("0.3"));
+        assert_eq!(duration_seconds("1s 500ms").unwrap(), num("1.5"));
+        assert_eq!(size_bytes("1.5GiB").unwrap(), num("1610612736"));
+        assert_eq!(size_bytes("1.5kb").unwrap(), num("1500"));
+    }
+
+    #[test]
+    fn bad_input_names_the_fragment() {
+        let err = |r: LispResult<Num>| r.unwrap_err().to_string();
+        assert!(err(duration_seconds("5x")).contains("\"x\""));
+        assert!(err(duration_seconds("5m5m")).contains("twice"));
+        assert!(err(duration_seconds("30")).contains("needs a unit"));
+        assert!(err(duration_seconds("-5s")).contains("\"-5s\""));
+        assert!(err(duration_seconds("")).contains("empty"));
+        assert!(err(size_bytes("5 furlongs")).contains("\"furlongs\""));
+        assert!(err(size_bytes("1.0001KB")).contains("whole number"));
+        assert!(err(size_bytes("1KB 2B")).contains("\"2B\""));
+    }
+
+    #[test]
+    fn formatting_round_trips() {
+        assert_eq!(format_seconds(&num("3903")).unwrap(), "1h 05m 03s");
+        assert_eq!(format_seconds(&num("45")).unwrap(), "45s");
+        assert_eq!(format_seconds(&num("90.5")).unwrap(), "1m 30.5s");
+        assert_eq!(format_seconds(&num("90061")).unwrap(), "1d 01h 01m 01s");
+        assert_eq!(format_seconds(&num("0.3")).unwrap(), "300ms");
+        assert_eq!(format_seconds(&num("0")).unwrap(), "0s");
+        for secs in &["3903", "45", "90.5", "90061", "0.3", "0.0000025", "0"] {
+            let text = format_seconds(&num(secs)).unwrap();
+            assert_eq!(duration_seconds(&text).unwrap(), num(secs), "{}", text);
+        }
+
+        assert_eq!(format_bytes(&num("999"), false).unwrap(), "999 B");
+        assert_eq!(format_bytes(&num("1500"), false).unwrap(), "1.50 KB");
+        assert_eq!(format_bytes(&num("999999"), false).unwrap(), "1.00 MB");
+        assert_eq!(format_bytes(&num("1610612736"), true).unwrap(), "1.50 GiB");
+        for (bytes, binary) in &[
+            ("1500", false),
+            ("1536", true),
+            ("1610612736", true),
+            ("12", true),
+        ] {
+            let text = format_bytes(&num(bytes), *binary).unwrap();
+            assert_eq!(size_bytes(&text).unwrap(), num(bytes), "{}", text);
+        }
+    }
+}
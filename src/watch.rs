@@ -0,0 +1,259 @@
+use crate::access;
+use crate::exact_len;
+use crate::stdlib::register_builtins;
+use crate::symbols::{Expr, LispResult, SymbolTable};
+use anyhow::Context;
+use im::{vector, Vector};
+use notify::{DebouncedEvent, RecommendedWatcher};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// (watch-path path f) calls (f event path) when path changes, where event is
+// :created, :modified, or :removed. Each watch has a thread queueing its
+// events, and the interpreter runs the callbacks itself at its next function
+// call, where it also checks for interrupts. So callbacks never run
+// concurrently with the program, or with each other.
+
+/// How long a path has to be quiet before its changes are reported, so
+/// saving a file reports one :modified instead of a burst.
+const DEBOUNCE: Duration = Duration::from_millis(50);
+
+#[derive(Default)]
+struct Queue {
+    // Set when events are waiting, so the check per function call is one load.
+    pending: AtomicBool,
+    events: Mutex<Vec<(u64, &'static str, String)>>,
+}
+
+/// The paths an interpreter is watching.
+#[derive(Default)]
+pub(crate) struct Watches {
+    next_id: u64,
+    watching: HashMap<u64, (RecommendedWatcher, Expr)>,
+    queue: Arc<Queue>,
+    delivering: bool,
+}
+
+impl fmt::Debug for Watches {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Watches({})", self.watching.len())
+    }
+}
+
+impl Watches {
+    /// Stop watching everything.
+    pub(crate) fn clear(&mut self) {
+        self.watching.clear();
+    }
+}
+
+fn event_kind(event: DebouncedEvent) -> Vec<(&'static str, String)> {
+    let path = |p: std::path::PathBuf| p.to_string_lossy().into_owned();
+    match event {
+        DebouncedEvent::Create(p) => vec![(":created", path(p))],
+        DebouncedEvent::Write(p) | DebouncedEvent::Chmod(p) => vec![(":modified", path(p))],
+        DebouncedEvent::Remove(p) => vec![(":removed", path(p))],
+        DebouncedEvent::Rename(from, to) => vec![(":removed", path(from)), (":created", path(to))],
+        // Notices come before the debounced event, and errors have nowhere to go.
+        _ => Vec::new(),
+    }
+}
+
+/// (watch-path path f)
+pub(crate) fn watch_path(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 2);
+    let path = exprs[0].get_string()?;
+    let callback = exprs[1].clone();
+    callback.get_function()?;
+    let (tx, rx) = mpsc::channel();
+    let watcher = access::watch(symbol_table, "watch-path", &path, tx, DEBOUNCE)?;
+
+    let mut watches = symbol_table.watches_mut();
+    let id = watches.next_id;
+    watches.next_id += 1;
+    let queue = watches.queue.clone();
+    let stop: Arc<AtomicBool> = Arc::default();
+    let thread_stop = stop.clone();
+    // Ends when the watcher, and with it the sender, is dropped, or on shutdown.
+    let handle = thread::spawn(move || loop {
+        let event = match rx.recv_timeout(DEBOUNCE) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) if !thread_stop.load(Ordering::SeqCst) => continue,
+            Err(_) => return,
+        };
+        let events = event_kind(event);
+        if events.is_empty() {
+            continue;
+        }
+        queue
+            .events
+            .lock()
+            .extend(events.into_iter().map(|(kind, path)| (id, kind, path)));
+        queue.pending.store(true, Ordering::SeqCst);
+    });
+    watches.watching.insert(id, (watcher, callback));
+    symbol_table
+        .resources_mut()
+        .register_worker(format!("watch-path {}", path), stop, handle);
+    Ok(Expr::from(id as i64))
+}
+
+/// (unwatch handle)
+pub(crate) fn unwatch(exprs: Vector<Expr>, symbol_table: &SymbolTable) -> LispResult<Expr> {
+    exact_len!(exprs, 1);
+    let id = exprs[0].get_usize()? as u64;
+    let removed = symbol_table.watches_mut().watching.remove(&id);
+    Ok(Expr::Bool(removed.is_some()))
+}
+
+/// Run the callbacks for changes seen since the last call.
+pub(crate) fn deliver(symbol_table: &SymbolTable) -> LispResult<()> {
+    let events = {
+        let mut watches = symbol_table.watches_mut();
+        // Callbacks make function calls too, which mustn't start delivering again.
+        if watches.delivering || !watches.queue.pending.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        watches.delivering = true;
+        let events = mem::take(&mut *watches.queue.events.lock());
+        events
+    };
+    let res = events.into_iter().try_for_each(|(id, kind, path)| {
+        let callback = match symbol_table.watches_mut().watching.get(&id) {
+            Some((_, callback)) => callback.clone(),
+            // Unwatched since.
+            None => return Ok(()),
+        };
+        let args = vector![Expr::Symbol(kind.into()), Expr::String(path.clone())];
+        callback
+            .call_with_values(args, symbol_table)
+            .map(drop)
+            .with_context(|| format!("Error in the watch-path callback for {} {}", kind, path))
+    });
+    symbol_table.watches_mut().delivering = false;
+    res
+}
+
+pub(crate) fn register(syms: &SymbolTable) {
+    register_builtins(
+        syms,
+        &[
+            (
+                "watch-path",
+                "io",
+                2,
+                watch_path,
+                true,
+                "Call (f event path) whenever the file or directory at path changes, returning a
+handle for unwatch. event is :created, :modified, or :removed. Changes are reported once
+they've been quiet for 50ms, and callbacks run between the program's function calls.
+Example:
+(def handle (watch-path \"config.json\" (fn (event path) (println event \" \" path))))
+",
+            ),
+            (
+                "unwatch",
+                "io",
+                1,
+                unwatch,
+                true,
+                "Stop watching, given a handle from watch-path. Returns whether it was watching.
+Example:
+(unwatch handle) ; true
+",
+            ),
+        ],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::Options;
+    use crate::stdlib::create_stdlib_symbol_table;
+    use crate::symbols::{Expr, SymbolTable};
+    use std::fs;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Evaluate `prog` until it isn't nil, or give up after a few seconds.
+    fn eventually(syms: &SymbolTable, prog: &str) -> Expr {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let res = syms.eval_source(prog).unwrap();
+            if res != Expr::Nil || Instant::now() > deadline {
+                return res;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn callbacks_see_modifications() {
+        let path = std::env::temp_dir().join(format!("x7-watch-{}", rand::random::<u64>()));
+        fs::write(&path, "before").unwrap();
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let prog = format!(
+            "(def seen (atom nil)) (def handle (watch-path {:?} (fn (event path) (.reset seen event))))",
+            path.to_string_lossy()
+        );
+        syms.eval_source(&prog).unwrap();
+        // Let the watch settle, so the write isn't mistaken for the create.
+        thread::sleep(Duration::from_millis(100));
+        fs::write(&path, "after").unwrap();
+        assert_eq!(
+            eventually(&syms, "(.deref seen)"),
+            Expr::Symbol(":modified".into())
+        );
+
+        assert_eq!(
+            syms.eval_source("(unwatch handle)").unwrap(),
+            Expr::Bool(true)
+        );
+        syms.eval_source("(.reset seen nil)").unwrap();
+        fs::remove_file(&path).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(syms.eval_source("(.deref seen)").unwrap(), Expr::Nil);
+        assert_eq!(
+            syms.eval_source("(unwatch handle)").unwrap(),
+            Expr::Bool(false)
+        );
+    }
+
+    #[test]
+    fn shutdown_stops_watch_threads() {
+        let path = std::env::temp_dir().join(format!("x7-watch-{}", rand::random::<u64>()));
+        fs::write(&path, "watched").unwrap();
+        let syms = create_stdlib_symbol_table(&Options::default());
+        let prog = format!(
+            "(watch-path {:?} (fn (event path) nil))",
+            path.to_string_lossy()
+        );
+        syms.eval_source(&prog).unwrap();
+        let watching = vec![format!("watch-path {}", path.to_string_lossy())];
+        assert_eq!(syms.resource_report().running_tasks, watching);
+        assert_eq!(syms.shutdown().running_tasks, watching);
+        // Only threads which are still running are reported.
+        assert!(syms.resource_report().running_tasks.is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sandbox_denies_watching() {
+        let syms = create_stdlib_symbol_table(&Options::default());
+        syms.set_sandboxed(true);
+        let err = syms
+            .eval_source("(watch-path \".\" (fn (event path) nil))")
+            .unwrap_err();
+        assert_eq!(
+            err.root_cause().to_string(),
+            "watch-path is not allowed in sandbox mode"
+        );
+    }
+}
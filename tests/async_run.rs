@@ -0,0 +1,34 @@
+use std::time::Duration;
+use x7::{run_source_async, RunOptions};
+
+#[tokio::test]
+async fn awaits_scripts_without_blocking() {
+    let outcome = run_source_async("(+ 1 2)", RunOptions::default()).await;
+    assert_eq!(outcome.value.as_deref(), Some("3"));
+
+    // The test runtime has one thread, so the timer can only fire while the
+    // endless script is pending if awaiting it leaves the executor free.
+    let endless = run_source_async("(foreach ident (range))", RunOptions::default());
+    tokio::select! {
+        _ = endless => panic!("The script can't finish"),
+        _ = tokio::time::delay_for(Duration::from_millis(10)) => {}
+    }
+}
+
+#[tokio::test]
+async fn timeouts_interrupt_the_script() {
+    // Dropping the future when the timeout expires interrupts the script.
+    let slow = run_source_async("(foreach ident (range))", RunOptions::default());
+    let res = tokio::time::timeout(Duration::from_millis(100), slow).await;
+    assert!(res.is_err());
+
+    let outcome = run_source_async(
+        "(foreach ident (range))",
+        RunOptions {
+            timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(outcome.error.unwrap().kind, "Interrupted");
+}
@@ -0,0 +1,60 @@
+use std::process::Command;
+
+/// Run `x7 check` with `args`, returning the exit code and stdout.
+fn check(args: &[&str]) -> (i32, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_x7"))
+        .arg("check")
+        .args(args)
+        .output()
+        .unwrap();
+    (
+        output.status.code().unwrap(),
+        String::from_utf8(output.stdout).unwrap(),
+    )
+}
+
+#[test]
+fn clean_files_pass() {
+    assert_eq!(
+        check(&["tests/fixtures/check/clean.x7"]),
+        (0, String::new())
+    );
+}
+
+#[test]
+fn every_error_is_reported_without_running_anything() {
+    let (status, stdout) = check(&["tests/fixtures/check"]);
+    assert_eq!(status, 1);
+    assert_eq!(
+        stdout,
+        "tests/fixtures/check/broken.x7:2:11: error: unexpected `)`, there's no `(` to close\n\
+         tests/fixtures/check/broken.x7:6:1: error: this `(` is never closed\n"
+    );
+    // Globs are expanded by x7 itself.
+    assert_eq!(check(&["tests/fixtures/check/*.x7"]), (status, stdout));
+}
+
+#[test]
+fn json_diagnostics() {
+    let (status, stdout) = check(&["--format", "json", "tests/fixtures/check"]);
+    assert_eq!(status, 1);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 4, "{}", stdout);
+    assert_eq!((lines[0], lines[3]), ("[", "]"));
+    assert_eq!(
+        lines[1],
+        "  {\"path\": \"tests/fixtures/check/broken.x7\", \"line\": 2, \"col\": 11, \
+         \"message\": \"unexpected `)`, there's no `(` to close\", \"severity\": \"error\"},"
+    );
+    assert!(lines[2].contains("\"line\": 6, \"col\": 1,"));
+
+    let (status, stdout) = check(&["--format", "json", "tests/fixtures/check/clean.x7"]);
+    assert_eq!((status, stdout.as_str()), (0, "[]\n"));
+}
+
+#[test]
+fn no_paths_is_an_error() {
+    let (status, stdout) = check(&[]);
+    assert_ne!(status, 0);
+    assert_eq!(stdout, "");
+}
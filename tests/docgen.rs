@@ -0,0 +1,43 @@
+use std::fs;
+use std::process::Command;
+use x7::cli::Options;
+use x7::docgen::{builtins_reference, docgen};
+use x7::stdlib::create_stdlib_symbol_table;
+
+/// The Markdown for the fixture file matches the snapshot next to it.
+#[test]
+fn docgen_snapshot() {
+    let markdown = docgen(&["tests/fixtures/docgen".to_string()]).unwrap();
+    let expected = fs::read_to_string("tests/fixtures/docgen/math.md").unwrap();
+    assert_eq!(markdown, expected);
+}
+
+/// Builtins are listed under their category, with their examples.
+#[test]
+fn builtins_by_category() {
+    let markdown = builtins_reference(&create_stdlib_symbol_table(&Options::default()));
+    let math = markdown.find("\n## math\n").unwrap();
+    let strings = markdown.find("\n## strings\n").unwrap();
+    let io = markdown.find("\n## io\n").unwrap();
+    let substring = markdown.find("\n### `substring`\n").unwrap();
+    assert!(math < strings && strings < substring && substring < io);
+    assert!(markdown.contains(
+        "### `clamp`\n\nTakes at least 3 arguments.\n\nRestrict a number to the range [lo, hi].\n\n```\n(clamp 5 0 3) ; 3"
+    ));
+}
+
+/// `x7 docgen` needs something to document.
+#[test]
+fn paths_or_builtins_are_required() {
+    let docgen = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_x7"))
+            .arg("docgen")
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    let output = docgen(&[]);
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(docgen(&["--builtins"]).status.success());
+}
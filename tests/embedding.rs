@@ -0,0 +1,437 @@
+//! The assertions from examples/, so the embedding API they show can't rot.
+use im::{vector, Vector};
+use parking_lot::Mutex;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use x7::cli::Options;
+use x7::stdlib::create_stdlib_symbol_table;
+use x7::{
+    run_source, Expr, InterpreterConfig, LispResult, Record, RecordType, RunOptions, Span,
+    SymbolTable, Warning,
+};
+#[cfg(feature = "io")]
+use x7::{Decision, IoKind};
+
+fn interpreter() -> SymbolTable {
+    create_stdlib_symbol_table(&Options::default())
+}
+
+#[test]
+fn register_fn() {
+    let interpreter = interpreter();
+    interpreter.add_function("shout", 1, "Uppercase a string.", |args, _| {
+        Ok(Expr::String(args[0].get_string()?.to_uppercase()))
+    });
+    assert_eq!(
+        interpreter.eval_source("(shout \"hello\")").unwrap(),
+        Expr::from("HELLO")
+    );
+    assert_eq!(
+        interpreter
+            .eval_source("(map shout (list \"a\" \"b\"))")
+            .unwrap(),
+        Expr::List(vector![Expr::from("A"), Expr::from("B")])
+    );
+    assert!(interpreter.eval_source("(shout 1)").is_err());
+    assert!(interpreter.eval_source("(shout)").is_err());
+}
+
+#[test]
+fn call_lisp() {
+    let interpreter = interpreter();
+    interpreter
+        .eval_source("(defn area (w h) (* w h)) (defn total (l) (reduce + 0 l))")
+        .unwrap();
+    let area = interpreter
+        .call_function("area", vector![Expr::from(3), Expr::from(4)])
+        .unwrap();
+    assert_eq!(area, Expr::from(12));
+    let items = Expr::List(vector![Expr::from(1), Expr::from(2), Expr::from(3)]);
+    let total = interpreter.call_function("total", vector![items]).unwrap();
+    assert_eq!(total, Expr::from(6));
+    let res = interpreter
+        .eval_with_bindings("(area w h)", &[("w", 5.into()), ("h", 6.into())])
+        .unwrap();
+    assert_eq!(res, Expr::from(30));
+    assert!(interpreter.call_function("missing", Vector::new()).is_err());
+
+    // Arguments are values, so lists and symbols in them aren't evaluated.
+    interpreter.eval_source("(defn pass (x) x)").unwrap();
+    let nested = Expr::List(vector![
+        Expr::List(vector![Expr::Symbol("f".into()), Expr::from(1)]),
+        Expr::Symbol("undefined".into())
+    ]);
+    let passed = interpreter.call_function("pass", vector![nested.clone()]);
+    assert_eq!(passed.unwrap(), nested);
+    let symbol = Expr::Symbol("undefined".into());
+    let passed = interpreter.call_function("pass", vector![symbol.clone()]);
+    assert_eq!(passed.unwrap(), symbol);
+    let first = interpreter.eval_source("first").unwrap();
+    let res = interpreter.call_function_value(&first, vector![nested]);
+    assert_eq!(
+        res.unwrap(),
+        Expr::List(vector![Expr::Symbol("f".into()), Expr::from(1)])
+    );
+}
+
+#[test]
+fn sandbox() {
+    let untrusted = || RunOptions {
+        sandbox: true,
+        timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let outcome = run_source("(+ 1 2)", untrusted());
+    assert_eq!(outcome.value.as_deref(), Some("3"));
+    let outcome = run_source("(fs::open \"/etc/passwd\")", untrusted());
+    assert!(!outcome.success());
+    let outcome = run_source("(foreach ident (range))", untrusted());
+    assert_eq!(outcome.error.unwrap().kind, "Interrupted");
+
+    let interpreter = interpreter();
+    interpreter.set_sandboxed(true);
+    assert!(interpreter
+        .eval_source("(fs::open \"/etc/passwd\")")
+        .is_err());
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn json_bridge() {
+    use serde_json::json;
+    let order = json!({"item": "tea", "quantity": 2, "price": 3.5});
+    let res = interpreter()
+        .eval_with_bindings(
+            "(assoc order \"total\" (* (get order \"quantity\") (get order \"price\")))",
+            &[("order", Expr::from_json(&order))],
+        )
+        .unwrap();
+    let res = res.to_json().unwrap();
+    assert_eq!(res["total"], json!(7));
+    assert_eq!(res["item"], json!("tea"));
+}
+
+#[derive(Clone, Default)]
+struct KvStore {
+    entries: Arc<Mutex<HashMap<String, Expr>>>,
+}
+
+impl Record for KvStore {
+    fn call_method(&self, sym: &str, args: Vector<Expr>) -> LispResult<Expr> {
+        match sym {
+            "get" => {
+                let key = args[0].get_string()?;
+                Ok(self.entries.lock().get(&key).cloned().unwrap_or(Expr::Nil))
+            }
+            "set" => {
+                self.entries
+                    .lock()
+                    .insert(args[0].get_string()?, args[1].clone());
+                Ok(args[1].clone())
+            }
+            _ => Err(anyhow::anyhow!("Unknown method {}", sym)),
+        }
+    }
+
+    fn id(&self) -> u64 {
+        Arc::as_ptr(&self.entries) as u64
+    }
+
+    fn display(&self) -> String {
+        format!("KvStore<{} entries>", self.entries.lock().len())
+    }
+
+    fn debug(&self) -> String {
+        self.display()
+    }
+
+    fn clone(&self) -> RecordType {
+        Box::new(Clone::clone(self))
+    }
+
+    fn methods(&self) -> Vec<&'static str> {
+        vec!["get", "set"]
+    }
+
+    fn type_name(&self) -> &'static str {
+        "KvStore"
+    }
+}
+
+#[test]
+fn custom_record() {
+    let interpreter = interpreter();
+    let store = KvStore::default();
+    interpreter.add_global("store", Expr::Record(Box::new(Clone::clone(&store))));
+    interpreter.eval_source("(.set store \"count\" 2)").unwrap();
+    assert_eq!(store.entries.lock().get("count"), Some(&Expr::from(2)));
+    store
+        .entries
+        .lock()
+        .insert("from-rust".into(), Expr::Bool(true));
+    assert_eq!(
+        interpreter
+            .eval_source("(.get store \"from-rust\")")
+            .unwrap(),
+        Expr::Bool(true)
+    );
+    assert!(interpreter
+        .eval_source("(.delete store \"count\")")
+        .is_err());
+}
+
+#[test]
+fn module_resolver() {
+    let interpreter = interpreter();
+    interpreter.set_module_resolver(|name| match name {
+        "geometry" => Some("(defn square (x) (* x x))".into()),
+        "utils" => Some("(defn which () :resolved)".into()),
+        _ => None,
+    });
+    // A file module which shares the name doesn't stand in for it.
+    let prog = "(import \"tests/fixtures/modules/nested/utils.x7\" :as n)
+                (import utils :as u)
+                (u::which)";
+    assert_eq!(
+        interpreter.eval_source(prog).unwrap(),
+        Expr::Symbol(":resolved".into())
+    );
+    interpreter.set_sandboxed(true);
+    assert_eq!(
+        interpreter
+            .eval_source("(require geometry) (geometry::square 7)")
+            .unwrap(),
+        Expr::from(49)
+    );
+    assert!(interpreter.eval_source("(require missing)").is_err());
+}
+
+#[cfg(feature = "io")]
+#[test]
+fn io_access_hook() {
+    let path = std::env::temp_dir().join("x7-io-access-hook.txt");
+    let path = path.to_str().unwrap().to_string();
+    let interpreter = interpreter();
+    let allowed = path.clone();
+    interpreter.on_io_access(move |op| {
+        if op.kind == IoKind::OpenFile && op.target == allowed {
+            Decision::Allow
+        } else {
+            Decision::Deny
+        }
+    });
+
+    let prog = format!(
+        "(def f (fs::open {:?})) (.write f \"hi\") (.read_to_string f)",
+        path
+    );
+    assert_eq!(interpreter.eval_source(&prog).unwrap(), Expr::from("hi"));
+
+    let err = interpreter
+        .eval_source("(fs::open \"/etc/passwd\")")
+        .unwrap_err();
+    let err = format!("{:?}", err);
+    assert!(err.contains("Permission denied"), "{}", err);
+    assert!(err.contains("open-file \"/etc/passwd\""), "{}", err);
+
+    let err = interpreter
+        .eval_source("(require \"tests/fixtures/modules/utils.x7\")")
+        .unwrap_err();
+    assert!(format!("{:?}", err).contains("Permission denied"));
+    // Even looking for a module's file is checked.
+    let err = interpreter.eval_source("(require utils)").unwrap_err();
+    let err = format!("{:?}", err);
+    assert!(err.contains("open-file \"./utils.x7\""), "{}", err);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn export_module() {
+    fn user_count() -> i64 {
+        3
+    }
+    fn get_user(id: usize) -> Option<String> {
+        ["ada", "grace", "barbara"].get(id).map(|s| s.to_string())
+    }
+    fn greet(name: String, excited: bool) -> String {
+        format!("hello {}{}", name, if excited { "!" } else { "" })
+    }
+    fn save(id: i64, fields: x7::Dict) -> LispResult<()> {
+        if id < 0 || fields.is_empty() {
+            return Err(anyhow::anyhow!("can't save user {}", id));
+        }
+        Ok(())
+    }
+
+    let interpreter = interpreter();
+    x7::export_module!(interpreter, "app", {
+        "user-count" => 0 => user_count,
+        /// The name of the user with `id`, or nil.
+        "get-user" => 1 => get_user,
+        "greet" => 2 => greet,
+        "save!" => 2 => save,
+    });
+    let eval = |prog| interpreter.eval_source(prog);
+    assert_eq!(
+        eval("(app/greet (app/get-user 1) true)").unwrap(),
+        Expr::from("hello grace!")
+    );
+    assert_eq!(eval("(app/get-user (app/user-count))").unwrap(), Expr::Nil);
+    assert_eq!(
+        eval("(app/save! 1 (dict :name \"ada\"))").unwrap(),
+        Expr::Nil
+    );
+    assert_eq!(
+        eval("(map app/get-user (list 0 2))").unwrap(),
+        Expr::List(vector![Expr::from("ada"), Expr::from("barbara")])
+    );
+    assert_eq!(
+        eval("(doc app/get-user)").unwrap(),
+        Expr::from("The name of the user with `id`, or nil.")
+    );
+
+    let err = |prog| format!("{:#}", eval(prog).unwrap_err());
+    assert!(err("(app/get-user -1)").contains("in app/get-user: argument 1"));
+    assert!(err("(app/greet \"ada\" 1)").contains("argument 2"));
+    assert!(err("(app/save! -1 (dict :a 1))").contains("can't save user -1"));
+    assert!(err("(app/greet \"ada\")").contains("Too few args"));
+    assert!(err("(app/user-count 1)").contains("WrongNumberOfArgs"));
+}
+
+#[cfg(feature = "io")]
+#[test]
+fn resource_report_and_shutdown() {
+    let path = std::env::temp_dir().join("x7-embedding-resources.txt");
+    std::fs::write(&path, "leaked").unwrap();
+    let path = path.canonicalize().unwrap().to_str().unwrap().to_string();
+    let interpreter = interpreter();
+    assert!(interpreter.resource_report().is_empty());
+
+    let prog = format!("(def f (fs::open {:?})) (def a (atom 1))", path);
+    interpreter.eval_source(&prog).unwrap();
+    let report = interpreter.resource_report();
+    assert_eq!(report.open_files, vec![path.clone()]);
+    assert_eq!(report.live_atoms, 1);
+
+    assert_eq!(interpreter.shutdown().open_files, vec![path.clone()]);
+    assert!(interpreter.resource_report().open_files.is_empty());
+    assert!(interpreter.eval_source("(.read_to_string f)").is_err());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn generated_code_spans() {
+    let interpreter = interpreter();
+    let span = Span {
+        file: "rules.dsl".into(),
+        line: 7,
+        col: 2,
+    };
+    let generated = Expr::List(vector![
+        Expr::Symbol("+".into()),
+        Expr::from(1),
+        Expr::from("a")
+    ])
+    .with_span(&span);
+    let err = generated.eval(&interpreter).unwrap_err();
+    assert_eq!(err.to_string(), "at rules.dsl:7:2");
+}
+
+#[test]
+fn warning_handler() {
+    let interpreter = interpreter();
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let sink = seen.clone();
+    interpreter.on_warning(move |w: &Warning| sink.borrow_mut().push(w.clone()));
+    assert_eq!(
+        interpreter
+            .eval_source("(do (warn :custom \"careful\") 1)")
+            .unwrap(),
+        Expr::from(1)
+    );
+    let seen = seen.borrow();
+    assert_eq!(seen.len(), 1);
+    assert_eq!(seen[0].kind, ":custom");
+    assert_eq!(seen[0].message, "careful");
+
+    // Handlers may use the interpreter, which isn't borrowed while they run.
+    let handle = interpreter.clone();
+    interpreter.on_warning(move |w: &Warning| {
+        handle.set_sandboxed(false);
+        handle.add_global("last-warning", Expr::from(w.message.as_str()));
+    });
+    interpreter.eval_source("(warn :custom \"again\")").unwrap();
+    assert_eq!(
+        interpreter.eval_source("last-warning").unwrap(),
+        Expr::from("again")
+    );
+    interpreter.eval_source("(is-even? 2)").unwrap();
+    assert_eq!(
+        interpreter.eval_source("last-warning").unwrap(),
+        Expr::from("is-even? is deprecated since x7 0.1.0, use even? instead")
+    );
+}
+
+#[test]
+fn every_setting_has_a_setter() {
+    let interpreter = interpreter();
+    interpreter.set_sandboxed(true);
+    interpreter.set_deny_warnings(true);
+    interpreter.set_deny_deprecated(true);
+    interpreter.set_strict_nil(true);
+    interpreter.set_frozen_globals(true);
+    interpreter.set_caches_enabled(false);
+    interpreter.set_max_value_bytes(Some(1 << 20));
+    interpreter.set_history_size(4);
+    let all_set = InterpreterConfig {
+        sandbox: true,
+        deny_warnings: true,
+        deny_deprecated: true,
+        strict_nil: true,
+        frozen_globals: true,
+        no_caches: true,
+        max_value_bytes: Some(1 << 20),
+        history: 4,
+    };
+    assert_eq!(interpreter.config(), all_set);
+    assert_eq!(SymbolTable::from_config(&all_set).config(), all_set);
+    assert!(interpreter
+        .eval_source("(warn :custom \"careful\")")
+        .is_err());
+    assert!(interpreter.eval_source("(len (head '()))").is_err());
+}
+
+#[test]
+fn discarded_values_warn_in_scripts() {
+    let interpreter = interpreter();
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let sink = seen.clone();
+    interpreter.on_warning(move |w: &Warning| sink.borrow_mut().push(w.clone()));
+    // Snippets from an embedder are free to evaluate a name for nothing.
+    interpreter.eval_source("inc 1").unwrap();
+    assert!(seen.borrow().is_empty());
+    interpreter.eval_script("inc 1", "rules.x7").unwrap();
+    let seen = seen.borrow();
+    assert_eq!(seen.len(), 1);
+    assert_eq!(seen[0].span.as_deref(), Some("rules.x7:1:1"));
+}
+
+#[test]
+fn recent_evaluations() {
+    let interpreter = interpreter();
+    assert!(interpreter.eval_source("(def a 1)").is_ok());
+    assert!(interpreter.recent_evaluations().is_empty());
+
+    interpreter.set_history_size(2);
+    interpreter.eval_source("(def b (+ a 1))").unwrap();
+    interpreter.eval_source("(map inc (list a b))").unwrap();
+    assert!(interpreter.eval_source("(err \"boom\")").is_err());
+    let recent = interpreter.recent_evaluations();
+    let sources: Vec<_> = recent.iter().map(|e| e.source.as_str()).collect();
+    assert_eq!(sources, vec!["(map inc (list a b))", "(err \"boom\")"]);
+    assert!(recent[0].success);
+    assert!(!recent[1].success);
+}
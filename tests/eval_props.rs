@@ -0,0 +1,500 @@
+//! Property tests for evaluation. Each generator builds a small well-typed
+//! program alongside a Rust oracle for its result, and we check the
+//! interpreter agrees.
+//!
+//! Runs with a fixed seed so CI is deterministic. Set X7_PROPTEST_RANDOM=1
+//! to use a random seed locally; the seed is printed so failures can be
+//! reproduced with X7_PROPTEST_SEED=<hex>. X7_PROPTEST_CASES=<n> runs more
+//! cases than the 128 CI does, for a longer search.
+use bigdecimal::{BigDecimal, Zero};
+use proptest::prelude::*;
+use proptest::test_runner::{Config, RngAlgorithm, TestRng, TestRunner};
+use std::collections::BTreeMap;
+use x7::{run_source, RunOptions};
+
+const DEFAULT_SEED: [u8; 32] = *b"x7 evaluator property test seed!";
+
+fn parse_seed(hex: &str) -> [u8; 32] {
+    let mut seed = [0; 32];
+    for (i, byte) in seed.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).expect("bad X7_PROPTEST_SEED");
+    }
+    seed
+}
+
+fn runner(name: &str) -> TestRunner {
+    let seed = if let Ok(hex) = std::env::var("X7_PROPTEST_SEED") {
+        parse_seed(&hex)
+    } else if std::env::var("X7_PROPTEST_RANDOM").is_ok() {
+        rand::random()
+    } else {
+        DEFAULT_SEED
+    };
+    let hex: String = seed.iter().map(|b| format!("{:02x}", b)).collect();
+    eprintln!("{}: X7_PROPTEST_SEED={}", name, hex);
+    let cases = std::env::var("X7_PROPTEST_CASES")
+        .map(|n| n.parse().expect("bad X7_PROPTEST_CASES"))
+        .unwrap_or(128);
+    let config = Config {
+        cases,
+        failure_persistence: None,
+        ..Config::default()
+    };
+    TestRunner::new_with_rng(config, TestRng::from_seed(RngAlgorithm::ChaCha, &seed))
+}
+
+/// Evaluate `prog` and return the printed result, panicking on errors.
+fn eval(prog: &str) -> String {
+    let outcome = run_source(prog, RunOptions::default());
+    match outcome.value {
+        Some(value) => value,
+        None => panic!("{} failed: {:?}", prog, outcome.error),
+    }
+}
+
+fn list_src(items: &[i64]) -> String {
+    let items: Vec<String> = items.iter().map(|i| i.to_string()).collect();
+    format!("(list {})", items.join(" "))
+}
+
+fn list_repr(items: &[i64]) -> String {
+    let items: Vec<String> = items.iter().map(|i| i.to_string()).collect();
+    format!("({})", items.join(" "))
+}
+
+// ARITHMETIC
+
+#[derive(Debug, Clone)]
+enum Arith {
+    Lit(i64),
+    Add(Vec<Arith>),
+    Sub(Vec<Arith>),
+    Mul(Vec<Arith>),
+}
+
+impl Arith {
+    fn source(&self) -> String {
+        let call = |op: &str, args: &[Arith]| {
+            let args: Vec<String> = args.iter().map(Arith::source).collect();
+            format!("({} {})", op, args.join(" "))
+        };
+        match self {
+            Arith::Lit(n) => n.to_string(),
+            Arith::Add(args) => call("+", args),
+            Arith::Sub(args) => call("-", args),
+            Arith::Mul(args) => call("*", args),
+        }
+    }
+
+    /// The oracle. None on overflow, in which case the case is skipped.
+    fn value(&self) -> Option<i128> {
+        let values = |args: &[Arith]| args.iter().map(Arith::value).collect::<Option<Vec<_>>>();
+        match self {
+            Arith::Lit(n) => Some(*n as i128),
+            Arith::Add(args) => values(args)?.into_iter().try_fold(0i128, i128::checked_add),
+            Arith::Sub(args) => {
+                let values = values(args)?;
+                values[1..]
+                    .iter()
+                    .try_fold(values[0], |l, r| l.checked_sub(*r))
+            }
+            Arith::Mul(args) => values(args)?.into_iter().try_fold(1i128, i128::checked_mul),
+        }
+    }
+}
+
+fn arith() -> impl Strategy<Value = Arith> {
+    let leaf = (-50i64..50).prop_map(Arith::Lit);
+    leaf.prop_recursive(4, 32, 3, |inner| {
+        let args = prop::collection::vec(inner, 2..=3);
+        prop_oneof![
+            args.clone().prop_map(Arith::Add),
+            args.clone().prop_map(Arith::Sub),
+            args.prop_map(Arith::Mul),
+        ]
+    })
+}
+
+#[test]
+fn arithmetic_matches_oracle() {
+    runner("arithmetic")
+        .run(&arith(), |expr| {
+            if let Some(expected) = expr.value() {
+                prop_assert_eq!(eval(&expr.source()), expected.to_string());
+            }
+            Ok(())
+        })
+        .unwrap();
+}
+
+// LIST PIPELINES
+
+#[derive(Debug, Clone)]
+enum Step {
+    Inc,
+    Double,
+    KeepEven,
+    KeepAbove(i64),
+}
+
+impl Step {
+    fn wrap(&self, inner: String) -> String {
+        match self {
+            Step::Inc => format!("(map inc {})", inner),
+            Step::Double => format!("(map (fn (x) (* x 2)) {})", inner),
+            Step::KeepEven => format!("(filter (fn (x) (= 0 (% x 2))) {})", inner),
+            Step::KeepAbove(k) => format!("(filter (fn (x) (> x {})) {})", k, inner),
+        }
+    }
+
+    fn apply(&self, items: Vec<i64>) -> Vec<i64> {
+        match self {
+            Step::Inc => items.into_iter().map(|x| x + 1).collect(),
+            Step::Double => items.into_iter().map(|x| x * 2).collect(),
+            Step::KeepEven => items.into_iter().filter(|x| x % 2 == 0).collect(),
+            Step::KeepAbove(k) => items.into_iter().filter(|x| x > k).collect(),
+        }
+    }
+}
+
+fn step() -> impl Strategy<Value = Step> {
+    prop_oneof![
+        Just(Step::Inc),
+        Just(Step::Double),
+        Just(Step::KeepEven),
+        (-20i64..20).prop_map(Step::KeepAbove),
+    ]
+}
+
+#[test]
+fn list_pipelines_match_oracle() {
+    let strategy = (
+        prop::collection::vec(-100i64..100, 0..12),
+        prop::collection::vec(step(), 0..5),
+        any::<bool>(),
+    );
+    runner("list pipelines")
+        .run(&strategy, |(data, steps, sum)| {
+            let mut src = list_src(&data);
+            let mut expected = data.clone();
+            for step in steps.iter() {
+                src = step.wrap(src);
+                expected = step.apply(expected);
+            }
+            if sum {
+                src = format!("(reduce + 0 {})", src);
+                prop_assert_eq!(eval(&src), expected.iter().sum::<i64>().to_string());
+            } else {
+                prop_assert_eq!(eval(&src), list_repr(&expected));
+            }
+            Ok(())
+        })
+        .unwrap();
+}
+
+// STRINGS
+
+#[test]
+fn string_operations_match_oracle() {
+    let strategy = (
+        prop::collection::vec("[a-z ]{0,6}", 1..4),
+        0usize..4,
+        -100i64..100,
+    );
+    runner("strings")
+        .run(&strategy, |(parts, repeat, n)| {
+            let quoted: Vec<String> = parts.iter().map(|p| format!("\"{}\"", p)).collect();
+            let joined = parts.concat();
+
+            let src = format!("(+ {})", quoted.join(" "));
+            prop_assert_eq!(eval(&src), format!("\"{}\"", joined));
+
+            let src = format!("(* (+ {}) {})", quoted.join(" "), repeat);
+            prop_assert_eq!(eval(&src), format!("\"{}\"", joined.repeat(repeat)));
+
+            let src = format!("(str {} {})", quoted.join(" "), n);
+            prop_assert_eq!(eval(&src), format!("\"{}{}\"", joined, n));
+            Ok(())
+        })
+        .unwrap();
+}
+
+// REFERENCE IMPLEMENTATIONS
+
+/// Run `prog` after loading the x7 reference definitions of builtins,
+/// returning the value or error message.
+fn outcome_with_reference(prog: &str) -> Result<String, String> {
+    let reference = std::fs::read_to_string("tests/fixtures/reference.x7").unwrap();
+    let outcome = run_source(&format!("{}\n{}", reference, prog), RunOptions::default());
+    match outcome.value {
+        Some(value) => Ok(value),
+        None => Err(outcome.error.unwrap().message),
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Item {
+    Num(i64),
+    Str(String),
+}
+
+impl Item {
+    fn source(&self) -> String {
+        match self {
+            Item::Num(n) => n.to_string(),
+            Item::Str(s) => format!("\"{}\"", s),
+        }
+    }
+}
+
+fn items_src(items: &[Item]) -> String {
+    let items: Vec<String> = items.iter().map(Item::source).collect();
+    format!("(list {})", items.join(" "))
+}
+
+#[test]
+fn builtins_match_reference_definitions() {
+    let item = prop_oneof![
+        4 => (-20i64..20).prop_map(Item::Num),
+        1 => "[a-c]{0,2}".prop_map(Item::Str),
+    ];
+    let strategy = (
+        prop::collection::vec(item.clone(), 0..12),
+        prop::collection::vec(item, 1..12),
+    );
+    runner("reference definitions")
+        .run(&strategy, |(l, r)| {
+            let (l, r) = (items_src(&l), items_src(&r));
+            let calls = [
+                format!("(quicksort {})", l),
+                format!("(dot-product {} {})", l, r),
+                format!("(not= (head {}) 1 (head {}))", r, r),
+                format!("(assert-eq {} {} \"differ\")", l, r),
+                format!("(assert-eq {} {} \"differ\")", l, l),
+            ];
+            for call in calls.iter() {
+                let reference = call.replacen('(', "(ref-", 1);
+                prop_assert_eq!(
+                    outcome_with_reference(call),
+                    outcome_with_reference(&reference),
+                    "{} vs {}",
+                    call,
+                    reference
+                );
+            }
+            Ok(())
+        })
+        .unwrap();
+}
+
+// CANONICAL ENCODING
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Int(i64),
+    Str(String),
+    Keyword(String),
+    List(Vec<Value>),
+    Tuple(Vec<Value>),
+    Dict(BTreeMap<String, Value>),
+}
+
+impl Value {
+    /// The value's source. Respelled, numbers are written with decimals
+    /// and dict entries are added in the opposite order.
+    fn source(&self, respell: bool) -> String {
+        let items = |items: &[Value]| -> String {
+            let items: Vec<String> = items.iter().map(|v| v.source(respell)).collect();
+            items.join(" ")
+        };
+        match self {
+            Value::Int(n) if respell => format!("{}.0", n),
+            Value::Int(n) => n.to_string(),
+            Value::Str(s) => format!("\"{}\"", s),
+            Value::Keyword(k) => format!(":{}", k),
+            Value::List(l) => format!("(list {})", items(l)),
+            Value::Tuple(l) => format!("(tuple {})", items(l)),
+            Value::Dict(d) => {
+                let mut entries: Vec<String> = d
+                    .iter()
+                    .map(|(k, v)| format!(":{} {}", k, v.source(respell)))
+                    .collect();
+                if respell {
+                    entries.reverse();
+                }
+                format!("(dict {})", entries.join(" "))
+            }
+        }
+    }
+}
+
+fn value() -> impl Strategy<Value = Value> {
+    let leaf = prop_oneof![
+        (-3i64..3).prop_map(Value::Int),
+        "[ab ]{0,2}".prop_map(Value::Str),
+        "[ab]".prop_map(Value::Keyword),
+    ];
+    leaf.prop_recursive(3, 16, 3, |inner| {
+        prop_oneof![
+            prop::collection::vec(inner.clone(), 0..3).prop_map(Value::List),
+            prop::collection::vec(inner.clone(), 0..3).prop_map(Value::Tuple),
+            prop::collection::btree_map("[abc]", inner, 0..3).prop_map(Value::Dict),
+        ]
+    })
+}
+
+#[test]
+fn canonical_hashes_agree_with_equality() {
+    // Small alphabets, so the corpus has plenty of equal values.
+    let strategy = prop::collection::vec((value(), any::<bool>()), 2..8);
+    runner("canonical hashes")
+        .run(&strategy, |corpus| {
+            let hashes: Vec<String> = corpus
+                .iter()
+                .map(|(v, respell)| format!("(canonical-hash {})", v.source(*respell)))
+                .collect();
+            let printed = eval(&format!("(list {})", hashes.join(" ")));
+            let hashes: Vec<&str> = printed
+                .trim_matches(|c| c == '(' || c == ')')
+                .split(' ')
+                .collect();
+            prop_assert_eq!(hashes.len(), corpus.len());
+            for (i, (l, _)) in corpus.iter().enumerate() {
+                for (j, (r, _)) in corpus.iter().enumerate() {
+                    prop_assert_eq!(l == r, hashes[i] == hashes[j], "{:?} vs {:?}", l, r);
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+}
+
+// NUMS
+
+/// A decimal as someone might write it, like `-007.250e-3`.
+#[derive(Debug, Clone)]
+struct Decimal {
+    sign: &'static str,
+    leading_zeros: usize,
+    int: String,
+    frac: Option<String>,
+    exponent: Option<(char, i64)>,
+}
+
+impl Decimal {
+    fn source(&self) -> String {
+        let mut s = format!(
+            "{}{}{}",
+            self.sign,
+            "0".repeat(self.leading_zeros),
+            self.int
+        );
+        if let Some(frac) = &self.frac {
+            s.push('.');
+            s.push_str(frac);
+        }
+        if let Some((e, exponent)) = self.exponent {
+            s.push_str(&format!("{}{}", e, exponent));
+        }
+        s
+    }
+
+    fn value(&self) -> BigDecimal {
+        self.source().parse().unwrap()
+    }
+}
+
+fn decimal() -> impl Strategy<Value = Decimal> {
+    (
+        prop_oneof![Just(""), Just("-"), Just("+")],
+        0usize..3,
+        "[0-9]{1,12}",
+        prop::option::of("[0-9]{0,10}"),
+        prop::option::of((prop_oneof![Just('e'), Just('E')], -30i64..30)),
+    )
+        .prop_map(|(sign, leading_zeros, int, frac, exponent)| Decimal {
+            sign,
+            leading_zeros,
+            int,
+            frac,
+            exponent,
+        })
+}
+
+/// The nums printed in a list of them.
+fn nums(printed: &str) -> Vec<BigDecimal> {
+    printed
+        .trim_matches(|c| c == '(' || c == ')')
+        .split(' ')
+        .map(|n| n.trim_matches('"').parse().unwrap())
+        .collect()
+}
+
+/// Nums print in one way: no exponent, no trailing zeros, and no `-0`.
+fn is_canonical(printed: &str) -> bool {
+    let digits = printed.strip_prefix('-').unwrap_or(printed);
+    !printed.contains(['e', 'E', '+'])
+        && !(printed.contains('.') && (printed.ends_with('0') || printed.ends_with('.')))
+        && printed != "-0"
+        && (digits == "0" || !digits.starts_with('0') || digits.starts_with("0."))
+}
+
+#[test]
+fn printing_and_reading_nums_is_a_fixed_point() {
+    runner("num printing")
+        .run(&decimal(), |x| {
+            let printed = eval(&x.source());
+            prop_assert!(
+                is_canonical(&printed),
+                "{} printed as {}",
+                x.source(),
+                printed
+            );
+            prop_assert_eq!(printed.parse::<BigDecimal>().unwrap(), x.value());
+            prop_assert_eq!(eval(&printed), printed.clone());
+            let prog = format!("(parse-num (str {}))", x.source());
+            prop_assert_eq!(eval(&prog), printed);
+            Ok(())
+        })
+        .unwrap();
+}
+
+#[test]
+fn num_arithmetic_matches_bigdecimal() {
+    runner("num arithmetic")
+        .run(&(decimal(), decimal()), |(x, y)| {
+            let (xv, yv) = (x.value(), y.value());
+            let (x, y) = (x.source(), y.source());
+            let prog = format!(
+                "(list (+ {x} 0) (* {x} 1) (+ {x} {y}) (- {x} {y}) (* {x} {y}))",
+                x = x,
+                y = y
+            );
+            let expected = [xv.clone(), xv.clone(), &xv + &yv, &xv - &yv, &xv * &yv];
+            prop_assert_eq!(nums(&eval(&prog)), expected);
+            if yv.is_zero() {
+                return Ok(());
+            }
+            // Dividing a product by one of its factors is exact, otherwise
+            // quotients are rounded, so multiplying back only comes close.
+            let prog = format!(
+                "(list (/ (* {x} {y}) {y}) (* (/ {x} {y}) {y}))",
+                x = x,
+                y = y
+            );
+            let results = nums(&eval(&prog));
+            prop_assert_eq!(&results[0], &xv);
+            let error = (&results[1] - &xv).abs();
+            let tolerance = xv.abs() * "1e-90".parse::<BigDecimal>().unwrap();
+            prop_assert!(
+                error <= tolerance,
+                "({} / {}) * {} is off by {}",
+                x,
+                y,
+                y,
+                error
+            );
+            Ok(())
+        })
+        .unwrap();
+}
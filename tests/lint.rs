@@ -0,0 +1,87 @@
+use std::fs;
+use std::process::Command;
+
+/// Run `x7 lint` with `args`, returning the exit code and stdout.
+fn lint(args: &[&str]) -> (i32, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_x7"))
+        .arg("lint")
+        .args(args)
+        .output()
+        .unwrap();
+    (
+        output.status.code().unwrap(),
+        String::from_utf8(output.stdout).unwrap(),
+    )
+}
+
+#[test]
+fn each_lint_fires_only_where_it_should() {
+    // Each fixture has forms which should fire its lint, then forms which shouldn't.
+    let mut fixtures: Vec<_> = fs::read_dir("tests/fixtures/lint")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "x7"))
+        .collect();
+    fixtures.sort();
+    assert_eq!(fixtures.len(), 7);
+    for fixture in fixtures {
+        let expected = fs::read_to_string(fixture.with_extension("out")).unwrap();
+        let (status, stdout) = lint(&[fixture.to_str().unwrap()]);
+        assert_eq!(stdout, expected, "{}", fixture.display());
+        assert_eq!(status, 1);
+    }
+}
+
+#[test]
+fn fix_applies_the_safe_suggestions() {
+    let path = std::env::temp_dir().join(format!("x7-lint-fix-{}.x7", std::process::id()));
+    fs::copy("tests/fixtures/lint-fix/input.x7", &path).unwrap();
+    let path_str = path.to_str().unwrap();
+    let (status, stdout) = lint(&["--fix", path_str]);
+    let fixed = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(
+        fixed,
+        "; Safe fixes are applied, and what's left is reported.\n\
+         (defn positive-sign (x) (if (> x 0) 1))\n\
+         \"b\"\n\
+         (print ''x)\n"
+    );
+    // Adding an else and quoting once change what the code does, so
+    // they're only suggested, and still reported.
+    assert_eq!(status, 1);
+    assert_eq!(
+        stdout,
+        format!(
+            "{0}:2:25: warning: `if` has no else branch, which it needs when it runs [missing-else]\n  \
+             suggestion: (if (> x 0) 1 nil)\n\
+             {0}:4:8: warning: `''x` is quoted twice, so its value is still quoted [double-quote]\n  \
+             suggestion: 'x\n",
+            path_str
+        )
+    );
+}
+
+#[test]
+fn clean_files_and_json() {
+    assert_eq!(lint(&["tests/fixtures/check/clean.x7"]), (0, String::new()));
+    let (status, stdout) = lint(&[
+        "--format",
+        "json",
+        "tests/fixtures/lint/single-arg-equals.x7",
+    ]);
+    assert_eq!(status, 1);
+    assert_eq!(
+        stdout,
+        "[\n  {\"path\": \"tests/fixtures/lint/single-arg-equals.x7\", \"line\": 3, \"col\": 1, \
+         \"message\": \"`(= x)` compares x with nothing, so it's always true\", \
+         \"severity\": \"warning\", \"lint\": \"single-arg-equals\", \"suggestion\": null}\n]\n"
+    );
+}
+
+#[test]
+fn no_paths_is_an_error() {
+    let (status, stdout) = lint(&[]);
+    assert_ne!(status, 0);
+    assert_eq!(stdout, "");
+}
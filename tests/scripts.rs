@@ -0,0 +1,200 @@
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use x7::{run_script, run_source, ProgramLimits, RunOptions};
+
+/// Run every script in tests/fixtures/scripts in parallel, checking its
+/// output against the `.out` file next to it, and its error kind against
+/// the `.err` file if there is one. Each script runs with and without
+/// frozen globals, which must not change the outcome.
+#[test]
+fn fixture_scripts() {
+    let mut handles = Vec::new();
+    for entry in fs::read_dir("tests/fixtures/scripts").unwrap() {
+        let path: PathBuf = entry.unwrap().path();
+        if path.extension().map(|ext| ext != "x7").unwrap_or(true) {
+            continue;
+        }
+        for &frozen_globals in &[false, true] {
+            let path = path.clone();
+            handles.push(thread::spawn(move || {
+                let opts = RunOptions {
+                    frozen_globals,
+                    ..Default::default()
+                };
+                let outcome = run_script(&path, opts);
+                (path, outcome)
+            }));
+        }
+    }
+    assert!(!handles.is_empty());
+    for handle in handles {
+        let (path, outcome) = handle.join().unwrap();
+        let expected = fs::read_to_string(path.with_extension("out")).unwrap();
+        assert_eq!(outcome.stdout, expected, "stdout of {}", path.display());
+        match fs::read_to_string(path.with_extension("err")) {
+            Ok(kind) => {
+                assert_eq!(outcome.exit_status(), 1);
+                let error = outcome.error.expect("script should have failed");
+                assert_eq!(error.kind, kind.trim(), "error of {}", path.display());
+            }
+            Err(_) => assert!(outcome.success(), "{:?}", outcome.error),
+        }
+    }
+}
+
+#[test]
+fn args_and_stdin() {
+    let outcome = run_script(
+        "tests/fixtures/misc/echo.x7",
+        RunOptions {
+            args: vec!["first".into(), "second".into()],
+            stdin: Some("from stdin\n".into()),
+            ..Default::default()
+        },
+    );
+    assert_eq!(outcome.stdout, "first\nfrom stdin\n");
+    assert_eq!(outcome.value, Some("2".into()));
+}
+
+#[test]
+fn timeout_interrupts_script() {
+    let outcome = run_script(
+        "tests/fixtures/misc/forever.x7",
+        RunOptions {
+            timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        },
+    );
+    assert_eq!(outcome.error.unwrap().kind, "Interrupted");
+}
+
+#[cfg(feature = "io")]
+#[test]
+fn sandbox_denies_files() {
+    let outcome = run_script(
+        "tests/fixtures/misc/open-file.x7",
+        RunOptions {
+            sandbox: true,
+            ..Default::default()
+        },
+    );
+    let error = outcome.error.unwrap();
+    assert_eq!(error.message, "fs::open is not allowed in sandbox mode");
+}
+
+#[test]
+fn program_limits_are_checked_before_running() {
+    let limited = |max_depth| RunOptions {
+        max_program_metrics: Some(ProgramLimits {
+            max_depth: Some(max_depth),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    // Three levels deep.
+    let source = "(println \"ran\") (+ 1 (* 2 (- 3 1)))";
+    let outcome = run_source(source, limited(3));
+    assert!(outcome.success());
+    assert_eq!(outcome.value.unwrap(), "5");
+
+    let outcome = run_source(source, limited(2));
+    assert_eq!(outcome.stdout, "");
+    let error = outcome.error.unwrap();
+    assert_eq!(error.kind, "Resource");
+    assert_eq!(
+        error.stacktrace,
+        vec!["The program has at least 3 levels of nesting, over the limit of 2"]
+    );
+}
+
+#[test]
+fn warnings_are_reported_or_denied() {
+    let outcome = run_script("tests/fixtures/misc/warn.x7", RunOptions::default());
+    assert!(outcome.success());
+    assert_eq!(outcome.stdout, "done\n");
+    assert_eq!(outcome.stderr, "");
+    let warnings: Vec<_> = outcome
+        .warnings
+        .iter()
+        .map(|w| (w.kind.as_str(), w.message.as_str()))
+        .collect();
+    assert_eq!(
+        warnings,
+        vec![(":custom", "first"), (":deprecated", "second")]
+    );
+
+    let outcome = run_script(
+        "tests/fixtures/misc/warn.x7",
+        RunOptions {
+            deny_warnings: true,
+            ..Default::default()
+        },
+    );
+    assert_eq!(outcome.stdout, "");
+    assert_eq!(outcome.error.unwrap().kind, "DeniedWarning");
+}
+
+#[test]
+fn discarded_functions_warn() {
+    let outcome = run_script("tests/fixtures/misc/discarded.x7", RunOptions::default());
+    assert!(outcome.success());
+    assert_eq!(outcome.stdout, "done\n");
+    let warnings: Vec<_> = outcome
+        .warnings
+        .iter()
+        .map(|w| (w.kind.as_str(), w.span.as_deref().unwrap_or("")))
+        .collect();
+    // Only the unparenthesized call warns, not the definitions.
+    assert_eq!(
+        warnings,
+        vec![
+            (":discarded", "tests/fixtures/misc/discarded.x7:4:1"),
+            (":discarded", "tests/fixtures/misc/discarded.x7:4:5"),
+            (":discarded", "tests/fixtures/misc/discarded.x7:4:12"),
+        ]
+    );
+    assert_eq!(
+        outcome.warnings[0].message,
+        "map evaluates to a func which is never used, did you mean to call it? wrap the form in parentheses"
+    );
+}
+
+#[test]
+fn tables_align_wide_characters() {
+    let outcome = run_script("tests/fixtures/misc/table.x7", RunOptions::default());
+    assert!(outcome.success(), "{:?}", outcome.error);
+    let expected = fs::read_to_string("tests/fixtures/misc/table.out").unwrap();
+    assert_eq!(outcome.stdout, expected);
+}
+
+#[test]
+fn errors_carry_the_forms_before_them() {
+    let source = "(def total 0)\n(def total (+ total 1))\n(println total)\n(err \"boom\")\n(println \"never\")";
+    let outcome = run_source(
+        source,
+        RunOptions {
+            history: 3,
+            ..Default::default()
+        },
+    );
+    let error = outcome.error.unwrap();
+    let recent: Vec<_> = error
+        .recent
+        .iter()
+        .map(|e| (e.source.as_str(), e.success))
+        .collect();
+    assert_eq!(
+        recent,
+        vec![
+            ("(def total (+ total 1))", true),
+            ("(println total)", true),
+            ("(err \"boom\")", false),
+        ]
+    );
+
+    // Nothing is kept by default.
+    let outcome = run_source(source, RunOptions::default());
+    assert!(outcome.error.unwrap().recent.is_empty());
+}